@@ -10,6 +10,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         data_dir: "./api_data".to_string(),
         encryption_key: None,
         max_file_size: 1024 * 1024,
+        ..Config::default()
     };
 
     // 创建数据库实例
@@ -1,5 +1,4 @@
-use simpledb::{Config, SimpleDB, Value};
-use std::collections::HashMap;
+use simpledb::{Config, IndexMap, SimpleDB, Value};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -10,15 +9,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         data_dir: "./example_data".to_string(),
         encryption_key: None, // 不使用加密
         max_file_size: 1024 * 1024,
+        ..Config::default()
     };
 
     // 创建数据库实例
-    let mut db = SimpleDB::new(config)?;
+    let db = SimpleDB::new(config)?;
     
     println!("1. 插入用户数据");
     
     // 插入第一个用户
-    let mut user1 = HashMap::new();
+    let mut user1 = IndexMap::new();
     user1.insert("name".to_string(), Value::String("张三".to_string()));
     user1.insert("age".to_string(), Value::Int(25));
     user1.insert("email".to_string(), Value::String("zhangsan@example.com".to_string()));
@@ -28,7 +28,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("  插入用户1，ID: {}", user1_id);
     
     // 插入第二个用户
-    let mut user2 = HashMap::new();
+    let mut user2 = IndexMap::new();
     user2.insert("name".to_string(), Value::String("李四".to_string()));
     user2.insert("age".to_string(), Value::Int(30));
     user2.insert("email".to_string(), Value::String("lisi@example.com".to_string()));
@@ -64,7 +64,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("\n3. 更新数据");
     
     // 更新用户1的年龄
-    let mut update_data = HashMap::new();
+    let mut update_data = IndexMap::new();
     update_data.insert("age".to_string(), Value::Int(26));
     update_data.insert("name".to_string(), Value::String("张三".to_string()));
     update_data.insert("email".to_string(), Value::String("zhangsan@example.com".to_string()));
@@ -102,7 +102,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     println!("\n5. 插入产品数据");
     
-    let mut product1 = HashMap::new();
+    let mut product1 = IndexMap::new();
     product1.insert("name".to_string(), Value::String("笔记本电脑".to_string()));
     product1.insert("price".to_string(), Value::Float(5999.99));
     product1.insert("category".to_string(), Value::String("电子产品".to_string()));
@@ -1,6 +1,5 @@
-use simpledb::{Config, SimpleDB, Value};
+use simpledb::{Config, IndexMap, SimpleDB, Value};
 use simpledb::crypto::Crypto;
-use std::collections::HashMap;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -15,15 +14,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         data_dir: "./encrypted_data".to_string(),
         encryption_key: Some(encryption_key.clone()),
         max_file_size: 1024 * 1024,
+        ..Config::default()
     };
 
     // 创建数据库实例
-    let mut db = SimpleDB::new(config)?;
+    let db = SimpleDB::new(config)?;
     
     println!("\n1. 插入敏感数据");
     
     // 插入敏感用户数据
-    let mut sensitive_user = HashMap::new();
+    let mut sensitive_user = IndexMap::new();
     sensitive_user.insert("name".to_string(), Value::String("机密用户".to_string()));
     sensitive_user.insert("ssn".to_string(), Value::String("123-45-6789".to_string()));
     sensitive_user.insert("credit_card".to_string(), Value::String("4111-1111-1111-1111".to_string()));
@@ -34,7 +34,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("  插入敏感用户数据，ID: {}", user_id);
     
     // 插入银行账户信息
-    let mut account = HashMap::new();
+    let mut account = IndexMap::new();
     account.insert("account_number".to_string(), Value::String("1234567890".to_string()));
     account.insert("balance".to_string(), Value::Float(50000.75));
     account.insert("owner_id".to_string(), Value::String(user_id.clone()));
@@ -77,6 +77,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         data_dir: "./encrypted_data".to_string(),
         encryption_key: Some(encryption_key.clone()),
         max_file_size: 1024 * 1024,
+        ..Config::default()
     };
     
     let db2 = SimpleDB::new(config2)?;
@@ -102,6 +103,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         data_dir: "./encrypted_data".to_string(),
         encryption_key: Some(wrong_key),
         max_file_size: 1024 * 1024,
+        ..Config::default()
     };
     
     match SimpleDB::new(wrong_config) {
@@ -1,31 +1,145 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use indexmap::IndexMap;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::sync::{Arc, Mutex};
-use tokio::net::TcpListener;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio::io::{AsyncRead, AsyncWriteExt, BufWriter};
 use base64::Engine;
 
+use crate::audit::{AccessEntry, AccessLog, ACCESS_LOG_TABLE};
+use crate::changes::{ChangeEvent, ChangeFeed};
+use crate::counters::Counters;
 use crate::database::SimpleDB;
+use crate::datetime;
 use crate::error::{DatabaseError, Result};
-use crate::storage::Value;
+use crate::http::{self, BodyReader, ContentEncoding, HttpRequest, StreamEncoder};
+use crate::idempotency::{Attempt, IdempotencyCache, DEFAULT_IDEMPOTENCY_WINDOW};
+use crate::index::IndexKind;
+use crate::lock::Recover;
+use crate::mapping::FieldMapping;
+use crate::pipeline::{Accumulator, Pipeline, Stage};
+use crate::policy::Caller;
+use crate::query::{Condition, Cursor, Operator, Query, SortOrder};
+use crate::schema::{FieldType, TableMode};
+use crate::schedule::Job;
+use crate::sql::Aggregate;
+use crate::session::{TransactionSessions, DEFAULT_TRANSACTION_TIMEOUT};
+use crate::storage::{Record, Value};
+use crate::sync::{Digest, SyncEntry};
+use crate::telemetry::{SpanKind, TraceContext};
+use crate::update::{PopEnd, UpdateOp};
 
 /// HTTP请求结构
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct ApiRequest {
+    #[serde(default)]
     pub method: String,
     pub table: String,
     pub id: Option<String>,
-    pub data: Option<HashMap<String, serde_json::Value>>,
-    pub query: Option<HashMap<String, serde_json::Value>>,
+    pub data: Option<IndexMap<String, serde_json::Value>>,
+    pub query: Option<IndexMap<String, serde_json::Value>>,
+    /// 排序字段，如`[["category", "asc"], ["price", "desc"]]`
+    pub order_by: Option<Vec<(String, String)>>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    /// 为true时只返回执行计划
+    pub explain: Option<bool>,
+    /// 索引提示：字段名表示使用该字段上的索引，`false`表示强制全表扫描
+    pub index: Option<serde_json::Value>,
+    /// 为true时以分块传输的JSONL逐条返回记录
+    pub stream: Option<bool>,
+    /// 上一页响应中的`next_token`，从该位置继续分页
+    pub next_token: Option<String>,
+    /// 为true时插入后返回完整的记录，也可以用请求头`Prefer: return=representation`
+    pub returning: Option<bool>,
+}
+
+/// 添加定时任务请求
+#[derive(Debug, Deserialize)]
+struct ScheduleRequest {
+    cron: String,
+    job: Job,
+}
+
+/// 声明字段类型请求，值为null的字段取消声明
+#[derive(Debug, Deserialize)]
+struct FieldTypesRequest {
+    #[serde(default)]
+    types: BTreeMap<String, Option<FieldType>>,
+    /// 同时切换表的结构模式
+    mode: Option<TableMode>,
+}
+
+/// 创建索引请求
+#[derive(Debug, Deserialize)]
+struct IndexRequest {
+    /// 索引的字段，多字段唯一索引用逗号连接字段名
+    field: String,
+    #[serde(default)]
+    kind: IndexKind,
+    /// 部分索引的过滤条件，格式与查询条件相同，只索引满足条件的记录
+    #[serde(default)]
+    filter: Option<IndexMap<String, serde_json::Value>>,
+    /// 在后台创建等值索引，立即返回，进度见统计信息接口
+    #[serde(default)]
+    background: bool,
+}
+
+/// SQL查询请求
+#[derive(Debug, Deserialize)]
+struct SqlRequest {
+    query: String,
+}
+
+/// 聚合管道请求
+#[derive(Debug, Deserialize)]
+struct AggregateRequest {
+    table: String,
+    /// 阶段列表，如`[{"$match": {...}}, {"$group": {...}}, {"$sort": {...}}, {"$limit": 10}]`
+    pipeline: Vec<serde_json::Value>,
+}
+
+/// 同步第一步的请求：客户端所有记录的版本
+#[derive(Debug, Deserialize)]
+struct SyncRequest {
+    versions: Vec<Digest>,
+}
+
+/// 同步第二步的请求：服务器没有或较旧的记录
+#[derive(Debug, Deserialize)]
+struct SyncPushRequest {
+    entries: Vec<SyncEntry>,
 }
 
 /// HTTP响应结构
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ApiResponse {
     pub success: bool,
     pub data: Option<serde_json::Value>,
     pub error: Option<String>,
     pub message: Option<String>,
+    /// 分页查询还有后续结果时，用于获取下一页的令牌
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_token: Option<String>,
+    /// 本次返回的结果条数
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+    /// 分页之前满足条件的记录总数
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<usize>,
+    /// 按`offset`和`limit`分页时的页码，从1开始；按`next_token`翻页时页码未知
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<usize>,
+    /// 处理查询所用的毫秒数
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elapsed_ms: Option<f64>,
 }
 
 impl ApiResponse {
@@ -35,6 +149,11 @@ impl ApiResponse {
             data: Some(data),
             error: None,
             message: None,
+            next_token: None,
+            count: None,
+            total: None,
+            page: None,
+            elapsed_ms: None,
         }
     }
 
@@ -44,6 +163,11 @@ impl ApiResponse {
             data: None,
             error: Some(message),
             message: None,
+            next_token: None,
+            count: None,
+            total: None,
+            page: None,
+            elapsed_ms: None,
         }
     }
 
@@ -53,254 +177,2764 @@ impl ApiResponse {
             data: None,
             error: None,
             message: Some(msg),
+            next_token: None,
+            count: None,
+            total: None,
+            page: None,
+            elapsed_ms: None,
+        }
+    }
+
+    pub fn with_next_token(mut self, token: Option<String>) -> Self {
+        self.next_token = token;
+        self
+    }
+
+    /// 附加结果条数、分页前的总数和页码
+    pub fn with_counts(mut self, count: usize, total: Option<usize>, page: Option<usize>) -> Self {
+        self.count = Some(count);
+        self.total = total;
+        self.page = page;
+        self
+    }
+
+    /// 附加从`started`起经过的毫秒数
+    pub fn with_elapsed(mut self, started: std::time::Instant) -> Self {
+        self.elapsed_ms = Some(started.elapsed().as_secs_f64() * 1000.0);
+        self
+    }
+}
+
+/// 响应体
+#[derive(Clone)]
+enum ReplyBody {
+    /// 单个JSON响应体
+    Json(ApiResponse),
+    /// 以`Transfer-Encoding: chunked`逐条输出的记录流
+    Stream(Vec<Arc<Record>>, StreamFormat),
+    /// Server-Sent Events变更流，`after`为客户端已收到的最后一个事件序号
+    Events {
+        table: String,
+        after: Option<u64>,
+        feed: Arc<ChangeFeed>,
+    },
+    /// 二进制响应体及其Content-Type
+    Bytes(Vec<u8>, &'static str),
+    /// 无响应体（如304）
+    Empty,
+}
+
+/// 变更流空闲时发送保活注释的间隔
+const SSE_KEEPALIVE: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// 停止服务时默认等待进行中的请求完成的时间
+pub const DEFAULT_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// 流式响应的记录格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamFormat {
+    /// 每行一条JSON记录
+    Jsonl,
+    /// 首行为列名，列为`id`、`created_at`、`updated_at`及所有出现过的字段
+    Csv,
+}
+
+/// 处理器的返回结果：状态码、附加响应头和响应体
+#[derive(Clone)]
+pub struct HttpReply {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: ReplyBody,
+}
+
+impl HttpReply {
+    fn stream(records: Vec<Arc<Record>>, format: StreamFormat) -> Self {
+        Self {
+            status: 200,
+            headers: Vec::new(),
+            body: ReplyBody::Stream(records, format),
+        }
+    }
+
+    /// 二进制响应体及其Content-Type
+    pub fn bytes(data: Vec<u8>, content_type: &'static str) -> Self {
+        Self {
+            status: 200,
+            headers: Vec::new(),
+            body: ReplyBody::Bytes(data, content_type),
+        }
+    }
+
+    /// 没有响应体的响应
+    pub fn empty(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: ReplyBody::Empty,
+        }
+    }
+
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn set_status(&mut self, status: u16) {
+        self.status = status;
+    }
+
+    /// 按名称（不区分大小写）获取处理器附加的响应头
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// 设置响应头，替换同名的响应头
+    pub fn set_header(&mut self, name: &'static str, value: String) {
+        self.headers.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.headers.push((name, value));
+    }
+}
+
+impl From<ApiResponse> for HttpReply {
+    fn from(response: ApiResponse) -> Self {
+        Self {
+            status: 200,
+            headers: Vec::new(),
+            body: ReplyBody::Json(response),
+        }
+    }
+}
+
+/// 把处理请求时的panic转换为500响应，而不是直接断开连接
+struct CatchUnwind<F>(Pin<Box<F>>);
+
+impl<F: Future<Output = HttpReply>> Future for CatchUnwind<F> {
+    type Output = HttpReply;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<HttpReply> {
+        let handler = self.0.as_mut();
+        match std::panic::catch_unwind(AssertUnwindSafe(|| handler.poll(cx))) {
+            Ok(poll) => poll,
+            Err(_) => Poll::Ready(HttpReply::from(ApiResponse::error("内部错误".to_string())).with_status(500)),
+        }
+    }
+}
+
+/// 批量导入失败的行
+#[derive(Debug, Serialize)]
+struct ImportFailure {
+    line: usize,
+    error: String,
+}
+
+/// 批量导入结果汇总
+#[derive(Debug, Default, Serialize)]
+struct ImportSummary {
+    inserted: usize,
+    failed: usize,
+    /// 失败原因，最多保留`MAX_IMPORT_FAILURES`条
+    failures: Vec<ImportFailure>,
+    #[serde(skip)]
+    line: usize,
+    /// 客户端接受NDJSON时逐行的结果（`{"line", "id"}`或`{"line", "error"}`），作为响应体返回
+    #[serde(skip)]
+    results: Option<Vec<u8>>,
+}
+
+/// NDJSON（每行一个JSON值）的媒体类型
+const NDJSON: &str = "application/x-ndjson";
+
+/// 表结构接口默认的抽样记录数
+const DEFAULT_SCHEMA_SAMPLE: usize = 1000;
+
+/// 导入汇总中保留的失败原因条数上限
+const MAX_IMPORT_FAILURES: usize = 100;
+
+/// 批量导入请求体的解析方式
+struct ImportParser {
+    /// CSV的列名，JSONL导入时为None，还没有读到首行时为空
+    columns: Option<Vec<String>>,
+    mapping: Option<FieldMapping>,
+    /// 目标表中声明的字段类型，按映射后的字段名转换
+    types: BTreeMap<String, FieldType>,
+}
+
+impl ImportParser {
+    /// 按`format`（`jsonl`或`csv`）和`mapping`（JSON字段映射）参数创建
+    fn from_request(request: &HttpRequest, types: BTreeMap<String, FieldType>) -> std::result::Result<Self, String> {
+        let columns = match request.query_param("format").unwrap_or("jsonl") {
+            "jsonl" | "ndjson" => None,
+            "csv" => Some(Vec::new()),
+            other => return Err(format!("不支持的导入格式: {}", other)),
+        };
+        let mapping = request.query_param("mapping").map(FieldMapping::parse).transpose().map_err(|e| e.to_string())?;
+        Ok(Self { columns, mapping, types })
+    }
+
+    /// 请求体中一行的结束位置；CSV引号中的换行属于字段
+    fn line_end(&self, data: &[u8]) -> Option<usize> {
+        if self.columns.is_none() {
+            return data.iter().position(|&b| b == b'\n');
+        }
+        let mut quoted = false;
+        data.iter().position(|&b| {
+            if b == b'"' {
+                quoted = !quoted;
+            }
+            b == b'\n' && !quoted
+        })
+    }
+
+    /// 解析一行记录的数据，CSV的首行返回None；空的CSV单元格视为缺失的字段
+    fn parse(&mut self, line: &[u8]) -> std::result::Result<Option<IndexMap<String, Value>>, String> {
+        let data = match &mut self.columns {
+            None => serde_json::from_slice::<IndexMap<String, serde_json::Value>>(line)
+                .map(|json| DatabaseServer::convert_json_to_value(json, &BTreeMap::new()))
+                .map_err(|e| format!("JSON解析错误: {}", e))?,
+            Some(columns) if columns.is_empty() => {
+                *columns = DatabaseServer::csv_fields(&String::from_utf8_lossy(line))?;
+                return Ok(None);
+            }
+            Some(columns) => {
+                let fields = DatabaseServer::csv_fields(&String::from_utf8_lossy(line))?;
+                if fields.len() != columns.len() {
+                    return Err(format!("有 {} 列，与首行的 {} 列不一致", fields.len(), columns.len()));
+                }
+                columns
+                    .iter()
+                    .zip(fields)
+                    .filter(|(_, field)| !field.is_empty())
+                    .map(|(column, field)| (column.clone(), Value::String(field)))
+                    .collect()
+            }
+        };
+        let data = match &self.mapping {
+            Some(mapping) => mapping.apply(data).map_err(|e| e.to_string())?,
+            None => data,
+        };
+        Ok(Some(DatabaseServer::convert_types(data, &self.types)))
+    }
+}
+
+impl ImportSummary {
+    /// 解析并插入一行，空行忽略
+    fn import_line(
+        &mut self,
+        line: &[u8],
+        parser: &mut ImportParser,
+        insert: impl FnOnce(IndexMap<String, Value>) -> Result<String>,
+    ) {
+        self.line += 1;
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return;
+        }
+        let result = parser
+            .parse(line)
+            .and_then(|data| data.map(|data| insert(data).map_err(|e| format!("插入失败: {}", e))).transpose());
+        let outcome = match result {
+            Ok(None) => return,
+            Ok(Some(id)) => {
+                self.inserted += 1;
+                serde_json::json!({"line": self.line, "id": id})
+            }
+            Err(error) => {
+                self.failed += 1;
+                let outcome = serde_json::json!({"line": self.line, "error": error});
+                if self.failures.len() < MAX_IMPORT_FAILURES {
+                    self.failures.push(ImportFailure { line: self.line, error });
+                }
+                outcome
+            }
+        };
+        if let Some(results) = &mut self.results {
+            results.extend(outcome.to_string().bytes());
+            results.push(b'\n');
         }
     }
 }
 
+/// 服务器的请求中间件，用于认证、日志、租户路由、请求ID等扩展而不需要修改路由
+///
+/// `on_request`按注册顺序在读取请求头之后、路由之前调用，此时请求体还没有读取；
+/// `on_response`按注册的相反顺序在写回响应之前调用，被中间件拦截的请求同样经过所有`on_response`。
+pub trait Middleware: Send + Sync + 'static {
+    /// 检查或改写请求；返回`Some`时拦截请求，不再调用后面的中间件和处理器，直接以它作为响应
+    fn on_request(&self, _request: &mut HttpRequest) -> Option<HttpReply> {
+        None
+    }
+
+    /// 检查或修改响应的状态码和响应头
+    fn on_response(&self, _request: &HttpRequest, _reply: &mut HttpReply) {}
+}
+
+/// 服务器启动后各连接共用的状态和设置
+struct Connections {
+    sessions: Arc<TransactionSessions>,
+    idempotency: Arc<IdempotencyCache<HttpReply>>,
+    access_log: Option<Arc<AccessLog>>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    max_body_size: usize,
+}
+
 /// 数据库API服务器
 pub struct DatabaseServer {
-    db: Arc<Mutex<SimpleDB>>,
+    db: Arc<SimpleDB>,
     port: u16,
+    sessions: Arc<TransactionSessions>,
+    idempotency: Arc<IdempotencyCache<HttpReply>>,
+    access_log: Option<Arc<AccessLog>>,
+    /// `with_listener`设置的监听套接字，启动时取出
+    listener: Mutex<Option<std::net::TcpListener>>,
+    drain_timeout: std::time::Duration,
+    max_body_size: usize,
+    middleware: Vec<Arc<dyn Middleware>>,
 }
 
 impl DatabaseServer {
     pub fn new(db: SimpleDB, port: u16) -> Self {
+        Self::with_shared(Arc::new(db), port)
+    }
+
+    /// 使用与其他前端共享的数据库实例
+    pub fn with_shared(db: Arc<SimpleDB>, port: u16) -> Self {
         Self {
-            db: Arc::new(Mutex::new(db)),
+            db,
             port,
+            sessions: Arc::new(TransactionSessions::new(DEFAULT_TRANSACTION_TIMEOUT)),
+            idempotency: Arc::new(IdempotencyCache::new(DEFAULT_IDEMPOTENCY_WINDOW)),
+            access_log: None,
+            listener: Mutex::new(None),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            max_body_size: http::DEFAULT_MAX_BODY_SIZE,
+            middleware: Vec::new(),
         }
     }
 
+    /// 设置幂等键的保留时长
+    pub fn with_idempotency_window(mut self, window: std::time::Duration) -> Self {
+        self.idempotency = Arc::new(IdempotencyCache::new(window));
+        self
+    }
+
+    /// 设置HTTP事务的空闲超时
+    pub fn with_transaction_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.sessions = Arc::new(TransactionSessions::new(timeout));
+        self
+    }
+
+    /// 审计每个API请求：调用方、端点、表、结果和耗时
+    pub fn with_access_log(mut self, log: AccessLog) -> Self {
+        self.access_log = Some(Arc::new(log));
+        self
+    }
+
+    /// 使用已经绑定的监听套接字而不是按端口绑定，如平滑升级时从上一个进程继承的套接字
+    pub fn with_listener(self, listener: std::net::TcpListener) -> Self {
+        *self.listener.lock().recover() = Some(listener);
+        self
+    }
+
+    /// 设置停止服务时等待进行中的请求完成的最长时间
+    pub fn with_drain_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// 设置请求体的最大字节数，超过时返回413；流式导入不受限制
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// 添加请求中间件，见`Middleware`
+    pub fn with_middleware(mut self, middleware: impl Middleware) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
     /// 启动服务器
     pub async fn start(&self) -> Result<()> {
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", self.port))
-            .await
-            .map_err(|e| DatabaseError::Io(e))?;
+        self.serve_until(std::future::pending()).await.map(|_| ())
+    }
+
+    /// 启动服务器，直到`shutdown`完成：停止接受新连接，等待进行中的请求完成后保存所有表，返回监听套接字
+    ///
+    /// 返回的套接字仍在监听，这期间到达的连接在内核中排队而不会被重置，
+    /// 可以交给`upgrade::spawn_successor`启动的新进程继续接受。超过`drain_timeout`仍未结束的连接被断开。
+    pub async fn serve_until(&self, shutdown: impl Future<Output = ()>) -> Result<std::net::TcpListener> {
+        let inherited = self.listener.lock().recover().take();
+        let listener = match inherited {
+            Some(listener) => {
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)?
+            }
+            None => TcpListener::bind(format!("127.0.0.1:{}", self.port)).await?,
+        };
+        let port = listener.local_addr()?.port();
 
-        println!("数据库服务器启动，监听端口: {}", self.port);
+        println!("数据库服务器启动，监听端口: {}", port);
         println!("API文档:");
         println!("  POST /api/insert   - 插入记录");
         println!("  GET  /api/find     - 查询记录");
         println!("  PUT  /api/update   - 更新记录");
         println!("  DELETE /api/delete - 删除记录");
+        println!("  POST /api/aggregate - 聚合管道");
+        println!("  POST /api/query/{{name}} - 绑定参数执行预备查询");
+        println!("  POST /api/sync - 同步：交换记录版本");
+        println!("  POST /api/sync/push - 同步：接收客户端的记录");
         println!("  GET  /api/tables   - 列出所有表");
+        println!("  POST /api/tables/{{table}}/import - 批量导入JSONL或CSV");
+        println!("  GET  /api/tables/{{table}}/export - 导出为JSONL或CSV");
+        println!("  GET  /api/tables/{{table}}/schema - 查看表结构");
+        println!("  PUT  /api/tables/{{table}}/schema - 声明字段类型");
+        println!("  GET  /api/tables/{{table}}/indexes - 列出索引");
+        println!("  POST /api/tables/{{table}}/indexes - 创建索引");
+        println!("  DELETE /api/tables/{{table}}/indexes/{{field}} - 删除索引");
+        println!("  GET  /api/stream/{{table}} - 订阅变更（SSE）");
+        println!("  POST /api/tx/begin - 开始事务");
+        println!("  POST /api/tx/{{id}}/commit|rollback - 提交或回滚事务");
+        println!("  POST /api/admin/backup - 在线备份");
+        println!("  GET  /api/admin/schedules - 列出定时任务");
+        println!("  PUT|DELETE /api/admin/schedules/{{name}} - 添加或删除定时任务");
+        println!("  GET  /api/cluster - 集群节点状态");
+        println!("  GET  /metrics - 各表的读写计数（Prometheus格式）");
+        #[cfg(feature = "admin-ui")]
+        println!("  GET  /ui - 管理界面");
 
+        let shared = Arc::new(Connections {
+            sessions: Arc::clone(&self.sessions),
+            idempotency: Arc::clone(&self.idempotency),
+            access_log: self.access_log.clone(),
+            middleware: self.middleware.clone(),
+            max_body_size: self.max_body_size,
+        });
+        let mut shutdown = std::pin::pin!(shutdown);
+        let mut connections = JoinSet::new();
         loop {
-            match listener.accept().await {
-                Ok((mut stream, _)) => {
-                    let db = Arc::clone(&self.db);
-                    tokio::spawn(async move {
-                        let mut buffer = [0; 1024];
-                        
-                        match stream.read(&mut buffer).await {
-                            Ok(n) if n > 0 => {
-                                let request = String::from_utf8_lossy(&buffer[..n]);
-                                let response = Self::handle_request(&db, &request).await;
-                                let response_json = serde_json::to_string(&response).unwrap();
-                                
-                                let http_response = format!(
-                                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
-                                    response_json.len(),
-                                    response_json
-                                );
-                                
-                                let _ = stream.write_all(http_response.as_bytes()).await;
-                            }
-                            _ => {}
-                        }
-                    });
+            tokio::select! {
+                _ = &mut shutdown => break,
+                // 回收已结束的连接
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                accepted = listener.accept() => {
+                    let Ok((stream, client)) = accepted else {
+                        continue;
+                    };
+                    connections.spawn(Self::serve_connection(Arc::clone(&self.db), Arc::clone(&shared), stream, client));
                 }
-                Err(_) => continue,
             }
         }
-    }
-
-    /// 处理HTTP请求
-    async fn handle_request(db: &Arc<Mutex<SimpleDB>>, request: &str) -> ApiResponse {
-        // 简单的HTTP请求解析
-        let lines: Vec<&str> = request.lines().collect();
-        if lines.is_empty() {
-            return ApiResponse::error("无效的请求".to_string());
-        }
 
-        let request_line = lines[0];
-        let parts: Vec<&str> = request_line.split_whitespace().collect();
-        if parts.len() < 2 {
-            return ApiResponse::error("无效的请求格式".to_string());
+        // 不再接受新连接，但套接字保持监听，新到达的连接在内核中排队
+        let drained = tokio::time::timeout(self.drain_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            // 超时仍未结束的连接（如变更订阅）直接断开
+            connections.shutdown().await;
         }
+        self.db.save_all()?;
+        listener.into_std().map_err(DatabaseError::Io)
+    }
 
-        let method = parts[0];
-        let path = parts[1];
-
-        // 查找请求体
-        let mut body = "";
-        for (i, line) in lines.iter().enumerate() {
-            if line.is_empty() && i + 1 < lines.len() {
-                body = lines[i + 1];
-                break;
+    /// 处理一个连接上的请求
+    async fn serve_connection(db: Arc<SimpleDB>, shared: Arc<Connections>, mut stream: TcpStream, client: std::net::SocketAddr) {
+        let Connections { sessions, idempotency, access_log, middleware, max_body_size } = &*shared;
+        let started = std::time::SystemTime::now();
+        let reply = match http::read_head(&mut stream).await {
+            Ok(Some((mut request, buffered))) => {
+                let intercepted = middleware.iter().find_map(|m| m.on_request(&mut request));
+                let encoding = ContentEncoding::negotiate(request.header("accept-encoding"));
+                // 从调用方的traceparent请求头继续追踪，请求中的数据库操作成为这个span的子span
+                let span = db.tracer().map(|tracer| {
+                    let parent = request.header("traceparent").and_then(TraceContext::parse_traceparent);
+                    let name = format!("{} {}", request.method, request.path);
+                    tracer.start_with_parent(name, SpanKind::Server, parent)
+                });
+                let handle = CatchUnwind(Box::pin(async {
+                    if let Some(reply) = intercepted {
+                        return reply;
+                    }
+                    if let Some(reply) = Self::leader_redirect(&db, &request) {
+                        return reply;
+                    }
+                    let mut body = BodyReader::new(&request, &mut stream, buffered);
+                    match Self::import_target(&request) {
+                        // 导入请求边接收边处理，不缓存整个请求体
+                        Some(table) => match Self::authenticate(&db, &request) {
+                            // 受行级安全策略限制的调用方可以以NDJSON插入，按记录检查策略
+                            Ok(caller) if caller.is_none() || (request.path == "/api/insert" && table != ACCESS_LOG_TABLE) => {
+                                Self::handle_import(&db, caller.as_ref(), &table, &request, &mut body).await
+                            }
+                            Ok(_) => Self::admin_required(),
+                            Err(e) => Self::unauthorized(e),
+                        },
+                        None => match body.read_to_end(*max_body_size).await {
+                            Ok(bytes) => {
+                                request.body = bytes;
+                                Self::handle_request(&db, sessions, idempotency, &request).await
+                            }
+                            Err(e) if http::is_too_large(&e) => HttpReply::from(ApiResponse::error(e.to_string())).with_status(413),
+                            Err(e) => ApiResponse::error(format!("无效的请求: {}", e)).into(),
+                        },
+                    }
+                }));
+                let mut reply = match span {
+                    Some(mut span) => {
+                        let reply = span.context.in_scope(handle).await;
+                        span.set("http.request.method", request.method.as_str());
+                        span.set("url.path", request.path.as_str());
+                        span.set("http.response.status_code", reply.status);
+                        if reply.status >= 500 {
+                            span.error = Some(http::status_text(reply.status).to_string());
+                        }
+                        if let Some(tracer) = db.tracer() {
+                            tracer.finish(span);
+                        }
+                        reply
+                    }
+                    None => handle.await,
+                };
+                for m in middleware.iter().rev() {
+                    m.on_response(&request, &mut reply);
+                }
+                // 集群节点之间的心跳和日志复制不记录
+                if let Some(log) = access_log.as_ref().filter(|_| !request.path.starts_with("/api/raft/")) {
+                    let entry = Self::access_entry(&db, &request, client, started, reply.status);
+                    let _ = log.record(&db, &entry);
+                }
+                (reply, encoding)
             }
-        }
+            Ok(None) => return,
+            Err(e) => (
+                ApiResponse::error(format!("无效的请求: {}", e)).into(),
+                ContentEncoding::Identity,
+            ),
+        };
+        let _ = Self::write_reply(&mut stream, reply.0, reply.1).await;
+    }
 
-        // 路由处理
-        match (method, path) {
-            ("POST", "/api/insert") => Self::handle_insert(db, body).await,
-            ("GET", "/api/find") => Self::handle_find(db, body).await,
-            ("PUT", "/api/update") => Self::handle_update(db, body).await,
-            ("DELETE", "/api/delete") => Self::handle_delete(db, body).await,
-            ("GET", "/api/tables") => Self::handle_list_tables(db).await,
-            _ => ApiResponse::error("不支持的API端点".to_string()),
+    /// 将处理结果写回客户端
+    ///
+    /// 客户端接受压缩时，超过阈值的JSON响应和所有流式响应都会按协商的编码压缩。
+    async fn write_reply(stream: &mut TcpStream, reply: HttpReply, encoding: ContentEncoding) -> std::io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", reply.status, http::status_text(reply.status));
+        for (name, value) in &reply.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
         }
-    }
 
-    /// 处理插入请求
-    async fn handle_insert(db: &Arc<Mutex<SimpleDB>>, body: &str) -> ApiResponse {
-        match serde_json::from_str::<ApiRequest>(body) {
-            Ok(req) => {
-                if let Some(data) = req.data {
-                    let converted_data = Self::convert_json_to_value(data);
-                    match db.lock().unwrap().insert(&req.table, converted_data) {
-                        Ok(id) => ApiResponse::success(serde_json::json!({"id": id})),
-                        Err(e) => ApiResponse::error(format!("插入失败: {}", e)),
-                    }
+        match reply.body {
+            ReplyBody::Json(response) => {
+                let response_json = serde_json::to_vec(&response).unwrap();
+
+                let (body, encoding) = if response_json.len() >= http::COMPRESSION_THRESHOLD {
+                    (encoding.compress(&response_json)?, encoding)
                 } else {
-                    ApiResponse::error("缺少数据字段".to_string())
+                    (response_json, ContentEncoding::Identity)
+                };
+
+                head.push_str(&format!(
+                    "Content-Type: application/json\r\nContent-Length: {}\r\nVary: Accept-Encoding\r\n",
+                    body.len()
+                ));
+                if let Some(value) = encoding.header_value() {
+                    head.push_str(&format!("Content-Encoding: {}\r\n", value));
                 }
+                head.push_str("\r\n");
+
+                stream.write_all(head.as_bytes()).await?;
+                stream.write_all(&body).await
             }
-            Err(e) => ApiResponse::error(format!("JSON解析错误: {}", e)),
-        }
-    }
+            ReplyBody::Stream(records, format) => {
+                let mut writer = BufWriter::new(stream);
+                let content_type = match format {
+                    StreamFormat::Jsonl => NDJSON,
+                    StreamFormat::Csv => "text/csv; charset=utf-8",
+                };
+                head.push_str(&format!(
+                    "Content-Type: {}\r\nTransfer-Encoding: chunked\r\nVary: Accept-Encoding\r\n",
+                    content_type
+                ));
+                if let Some(value) = encoding.header_value() {
+                    head.push_str(&format!("Content-Encoding: {}\r\n", value));
+                }
+                head.push_str("\r\n");
+                writer.write_all(head.as_bytes()).await?;
 
-    /// 处理查询请求
-    async fn handle_find(db: &Arc<Mutex<SimpleDB>>, body: &str) -> ApiResponse {
-        match serde_json::from_str::<ApiRequest>(body) {
-            Ok(req) => {
-                let db_guard = db.lock().unwrap();
-                if let Some(id) = req.id {
-                    // 根据ID查询
-                    match db_guard.find_by_id(&req.table, &id) {
-                        Ok(Some(record)) => {
-                            let json_record = Self::convert_record_to_json(record);
-                            ApiResponse::success(json_record)
-                        }
-                        Ok(None) => ApiResponse::error("记录不存在".to_string()),
-                        Err(e) => ApiResponse::error(format!("查询失败: {}", e)),
+                // 每条记录单独序列化并压缩为一个分块，内存占用与结果集大小无关
+                let mut encoder = StreamEncoder::new(encoding);
+                let columns = match format {
+                    StreamFormat::Jsonl => Vec::new(),
+                    StreamFormat::Csv => {
+                        let columns = Self::csv_columns(&records);
+                        let header = Self::csv_line(["id", "created_at", "updated_at"].into_iter().chain(columns.iter().map(String::as_str)));
+                        Self::write_chunk(&mut writer, &encoder.write(header.as_bytes())?).await?;
+                        columns
                     }
-                } else {
-                    // 查询所有记录
-                    match db_guard.find_all(&req.table) {
-                        Ok(records) => {
-                            let json_records: Vec<_> = records
-                                .iter()
-                                .map(|r| Self::convert_record_to_json(r))
-                                .collect();
-                            ApiResponse::success(serde_json::json!(json_records))
+                };
+                for record in records {
+                    let line = match format {
+                        StreamFormat::Jsonl => {
+                            let mut line = Self::convert_record_to_json(&record).to_string();
+                            line.push('\n');
+                            line
                         }
-                        Err(e) => ApiResponse::error(format!("查询失败: {}", e)),
+                        StreamFormat::Csv => Self::csv_record(&record, &columns),
+                    };
+                    Self::write_chunk(&mut writer, &encoder.write(line.as_bytes())?).await?;
+                }
+                Self::write_chunk(&mut writer, &encoder.finish()?).await?;
+                writer.write_all(b"0\r\n\r\n").await?;
+                writer.flush().await
+            }
+            ReplyBody::Bytes(body, content_type) => {
+                head.push_str(&format!("Content-Type: {}\r\nContent-Length: {}\r\n\r\n", content_type, body.len()));
+                stream.write_all(head.as_bytes()).await?;
+                stream.write_all(&body).await
+            }
+            ReplyBody::Events { table, after, feed } => {
+                head.push_str("Content-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n");
+                stream.write_all(head.as_bytes()).await?;
+
+                let mut subscription = feed.subscribe(after);
+                if subscription.truncated {
+                    // 断点之后的事件已不在日志中，客户端需要重新加载全量数据
+                    stream.write_all(b"event: resync\ndata: {}\n\n").await?;
+                }
+                for event in subscription.backlog.iter().filter(|e| e.table == table) {
+                    Self::write_event(stream, event).await?;
+                }
+                loop {
+                    match tokio::time::timeout(SSE_KEEPALIVE, subscription.receiver.recv()).await {
+                        Ok(Ok(event)) if event.table == table => Self::write_event(stream, &event).await?,
+                        Ok(Ok(_)) => {}
+                        Err(_) => stream.write_all(b": keepalive\n\n").await?,
+                        // 落后过多或数据库关闭时断开，客户端带`Last-Event-ID`重连后从日志补发
+                        Ok(Err(_)) => return Ok(()),
                     }
                 }
             }
-            Err(e) => ApiResponse::error(format!("JSON解析错误: {}", e)),
+            ReplyBody::Empty => {
+                head.push_str("Content-Length: 0\r\n\r\n");
+                stream.write_all(head.as_bytes()).await
+            }
         }
     }
 
-    /// 处理更新请求
-    async fn handle_update(db: &Arc<Mutex<SimpleDB>>, body: &str) -> ApiResponse {
-        match serde_json::from_str::<ApiRequest>(body) {
-            Ok(req) => {
-                if let (Some(id), Some(data)) = (req.id, req.data) {
-                    let converted_data = Self::convert_json_to_value(data);
-                    match db.lock().unwrap().update(&req.table, &id, converted_data) {
-                        Ok(_) => ApiResponse::message("更新成功".to_string()),
-                        Err(e) => ApiResponse::error(format!("更新失败: {}", e)),
-                    }
-                } else {
-                    ApiResponse::error("缺少ID或数据字段".to_string())
+    /// 写出一条SSE事件
+    async fn write_event(stream: &mut TcpStream, event: &ChangeEvent) -> std::io::Result<()> {
+        let data = Self::event_to_json(event);
+        let message = format!("id: {}\nevent: {}\ndata: {}\n\n", event.seq, event.kind.as_str(), data);
+        stream.write_all(message.as_bytes()).await
+    }
+
+    /// 变更事件的JSON表示，供SSE和CDC使用
+    pub(crate) fn event_to_json(event: &ChangeEvent) -> serde_json::Value {
+        serde_json::json!({
+            "seq": event.seq,
+            "table": event.table,
+            "kind": event.kind,
+            "id": event.id,
+            "record": event.record.as_ref().map(|r| Self::convert_record_to_json(r)),
+        })
+    }
+
+    /// 写出一个分块，空数据不输出（空分块表示响应结束）
+    async fn write_chunk(writer: &mut BufWriter<&mut TcpStream>, data: &[u8]) -> std::io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        writer.write_all(format!("{:x}\r\n", data.len()).as_bytes()).await?;
+        writer.write_all(data).await?;
+        writer.write_all(b"\r\n").await
+    }
+
+    /// 处理HTTP请求
+    async fn handle_request(
+        db: &Arc<SimpleDB>,
+        sessions: &TransactionSessions,
+        idempotency: &IdempotencyCache<HttpReply>,
+        request: &HttpRequest,
+    ) -> HttpReply {
+        let body = request.body_str();
+        let body = body.as_ref();
+
+        // 管理界面是不含数据的静态页面，不需要认证；页面中的请求各自带令牌
+        #[cfg(feature = "admin-ui")]
+        if request.method == "GET" && crate::ui::is_ui_path(&request.path) {
+            return HttpReply::bytes(crate::ui::INDEX_HTML.as_bytes().to_vec(), "text/html; charset=utf-8");
+        }
+
+        let caller = match Self::authenticate(db, request) {
+            Ok(caller) => caller,
+            Err(e) => return Self::unauthorized(e),
+        };
+        let caller = caller.as_ref();
+        // 受行级安全策略限制的调用方只能使用按记录检查策略的接口
+        if caller.is_some() {
+            let allowed = matches!(
+                (request.method.as_str(), request.path.as_str()),
+                ("POST", "/api/insert") | ("GET", "/api/find") | ("PUT", "/api/update") | ("DELETE", "/api/delete") | ("GET", "/api/tables")
+            ) || (request.method == "POST" && request.path.starts_with("/api/query/"));
+            // 访问日志只对管理员开放，普通调用方不能读取或伪造
+            let audit = Self::request_table(request).is_some_and(|table| table == ACCESS_LOG_TABLE);
+            if !allowed || audit || Self::transaction_id(request).is_some() {
+                return Self::admin_required();
+            }
+        }
+
+        // 路由处理
+        match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/api/insert") => {
+                Self::idempotent(idempotency, request, Self::handle_insert(db, caller, sessions, request)).await
+            }
+            ("GET", "/api/find") => Self::handle_find(db, caller, request).await,
+            ("PUT", "/api/update") => {
+                Self::idempotent(idempotency, request, Self::handle_update(db, caller, sessions, request)).await
+            }
+            ("DELETE", "/api/delete") => Self::handle_delete(db, caller, sessions, request).await,
+            ("GET", "/api/tables") => Self::handle_list_tables(db).await.into(),
+            ("POST", "/api/sql") => Self::handle_sql(db, body).await.into(),
+            ("POST", "/api/aggregate") => Self::handle_aggregate(db, body).await.into(),
+            ("POST", "/api/sync") => Self::handle_sync(db, body).await.into(),
+            ("POST", "/api/sync/push") => Self::handle_sync_push(db, body).await.into(),
+            ("POST", "/api/tx/begin") => Self::handle_begin(sessions).await.into(),
+            ("POST", "/api/admin/backup") => Self::handle_backup(db).await,
+            ("GET", "/api/admin/schedules") => match db.scheduled_jobs() {
+                Ok(jobs) => ApiResponse::success(serde_json::json!(jobs)).into(),
+                Err(e) => ApiResponse::error(format!("读取定时任务失败: {}", e)).into(),
+            },
+            (method, path) if path.starts_with("/api/admin/schedules/") => {
+                Self::handle_schedule(db, method, &http::percent_decode(&path["/api/admin/schedules/".len()..]), body)
+                    .await
+                    .into()
+            }
+            ("GET", "/api/cluster") => Self::handle_cluster_status(db).await.into(),
+            ("GET", "/metrics") => Self::handle_metrics(db).await,
+            ("POST", path) if path.starts_with("/api/raft/") => {
+                Self::handle_raft(db, &path["/api/raft/".len()..], body).await
+            }
+            ("GET", path) if path.starts_with("/api/stream/") => {
+                Self::handle_stream(db, &path["/api/stream/".len()..], request).await
+            }
+            ("POST", path) if path.starts_with("/api/query/") => {
+                Self::handle_prepared(db, caller, &http::percent_decode(&path["/api/query/".len()..]), body).await.into()
+            }
+            ("POST", path) if path.starts_with("/api/tx/") => {
+                match path["/api/tx/".len()..].split_once('/') {
+                    Some((id, "commit")) => Self::handle_commit(db, sessions, id).await,
+                    Some((id, "rollback")) => Self::handle_rollback(sessions, id).await,
+                    _ => ApiResponse::error("不支持的API端点".to_string()).into(),
                 }
             }
-            Err(e) => ApiResponse::error(format!("JSON解析错误: {}", e)),
+            (method, path) => match (method, Self::table_route(path)) {
+                ("GET", Some((table, "export"))) => Self::handle_export(db, table, request).await,
+                ("GET", Some((table, "schema"))) => Self::handle_schema(db, table, request).await.into(),
+                ("PUT", Some((table, "schema"))) => Self::handle_set_field_types(db, table, body).await,
+                ("GET", Some((table, "indexes"))) => Self::handle_list_indexes(db, table).await.into(),
+                ("GET", Some((table, "info"))) => Self::handle_table_info(db, table).await.into(),
+                ("GET", Some((table, "stats"))) => Self::handle_stats(db, table, false).await.into(),
+                ("POST", Some((table, "analyze"))) => Self::handle_stats(db, table, true).await.into(),
+                ("POST", Some((table, "indexes"))) => Self::handle_create_index(db, table, body).await.into(),
+                ("DELETE", Some((table, action))) if action.starts_with("indexes/") => {
+                    let field = http::percent_decode(&action["indexes/".len()..]);
+                    Self::handle_drop_index(db, table, &field).await.into()
+                }
+                _ => ApiResponse::error("不支持的API端点".to_string()).into(),
+            },
         }
     }
 
-    /// 处理删除请求
-    async fn handle_delete(db: &Arc<Mutex<SimpleDB>>, body: &str) -> ApiResponse {
-        match serde_json::from_str::<ApiRequest>(body) {
-            Ok(req) => {
-                if let Some(id) = req.id {
-                    match db.lock().unwrap().delete(&req.table, &id) {
-                        Ok(_) => ApiResponse::message("删除成功".to_string()),
-                        Err(e) => ApiResponse::error(format!("删除失败: {}", e)),
-                    }
-                } else {
-                    ApiResponse::error("缺少ID字段".to_string())
-                }
+    /// 集群模式下跟随者把单条写入和导入请求重定向到主节点；不在集群模式、本节点是主节点或主节点未知时为None
+    ///
+    /// 事务中的写操作先缓存在接收请求的节点上，不重定向，提交时才复制。
+    fn leader_redirect(db: &SimpleDB, request: &HttpRequest) -> Option<HttpReply> {
+        let cluster = db.cluster()?;
+        let write = matches!(
+            (request.method.as_str(), request.path.as_str()),
+            ("POST", "/api/insert") | ("PUT", "/api/update") | ("DELETE", "/api/delete")
+        ) || Self::import_target(request).is_some();
+        if !write || Self::transaction_id(request).is_some() {
+            return None;
+        }
+        let leader = cluster.leader_url()?;
+        let mut location = format!("{}{}", leader.trim_end_matches('/'), request.path);
+        // 导入的格式和字段映射在查询参数中
+        for (i, (name, value)) in request.query.iter().enumerate() {
+            location.push(if i == 0 { '?' } else { '&' });
+            location.push_str(&format!("{}={}", http::percent_encode(name), http::percent_encode(value)));
+        }
+        Some(HttpReply::empty(307).with_header("Location", location))
+    }
+
+    /// 处理集群状态请求
+    async fn handle_cluster_status(db: &Arc<SimpleDB>) -> ApiResponse {
+        match db.cluster() {
+            Some(cluster) => ApiResponse::success(serde_json::json!(cluster.status())),
+            None => ApiResponse::error("数据库没有运行在集群模式".to_string()),
+        }
+    }
+
+    /// 处理其他集群节点发来的Raft请求（`vote`或`append`）
+    async fn handle_raft(db: &Arc<SimpleDB>, kind: &str, body: &str) -> HttpReply {
+        let Some(cluster) = db.cluster().cloned() else {
+            return ApiResponse::error("数据库没有运行在集群模式".to_string()).into();
+        };
+        let body = match serde_json::from_str::<serde_json::Value>(body) {
+            Ok(body) => body,
+            Err(e) => return ApiResponse::error(format!("JSON解析错误: {}", e)).into(),
+        };
+        let kind = kind.to_string();
+        // 处理时要写RAFT文件
+        match tokio::task::spawn_blocking(move || cluster.handle_rpc(&kind, body)).await {
+            Ok(Ok(reply)) => ApiResponse::success(reply).into(),
+            Ok(Err(e)) => Self::error_reply("集群请求失败", e),
+            Err(e) => ApiResponse::error(format!("集群请求失败: {}", e)).into(),
+        }
+    }
+
+    /// 处理在线备份请求
+    ///
+    /// 配置了`backup_dir`时把备份写入该目录并返回文件路径，否则直接以响应体返回备份内容。
+    async fn handle_backup(db: &Arc<SimpleDB>) -> HttpReply {
+        let db = Arc::clone(db);
+        // 编码和写文件可能较慢，放到阻塞线程池中执行，不占用处理请求的线程
+        let result = tokio::task::spawn_blocking(move || match &db.config().backup_dir {
+            Some(dir) => {
+                let timestamp = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                let path = Path::new(dir).join(format!("backup-{}.sdbbak", timestamp));
+                std::fs::create_dir_all(dir)?;
+                let files = db.backup(&path)?;
+                Ok(HttpReply::from(ApiResponse::success(serde_json::json!({
+                    "path": path.to_string_lossy(),
+                    "files": files,
+                }))))
             }
-            Err(e) => ApiResponse::error(format!("JSON解析错误: {}", e)),
+            None => Ok(HttpReply::bytes(db.backup_bytes()?, "application/octet-stream")
+                .with_header("Content-Disposition", "attachment; filename=\"backup.sdbbak\"".to_string())),
+        })
+        .await;
+        match result {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(e)) => Self::error_reply("备份失败", e),
+            Err(e) => ApiResponse::error(format!("备份失败: {}", e)).into(),
         }
     }
 
-    /// 处理列出表请求
-    async fn handle_list_tables(db: &Arc<Mutex<SimpleDB>>) -> ApiResponse {
-        let tables = db.lock().unwrap().list_tables();
-        ApiResponse::success(serde_json::json!(tables))
+    /// 处理表结构请求：字段定义，以及索引定义
+    ///
+    /// 严格模式的表有声明的结构，直接返回声明的字段和类型（`source`为`declared`）；
+    /// 其他表抽样推断字段类型与覆盖率（`source`为`sampled`），`sample`参数指定抽样记录数，默认为`DEFAULT_SCHEMA_SAMPLE`。
+    /// 带`sample`参数时总是抽样，可用来检查严格模式的表中的实际数据。
+    async fn handle_schema(db: &Arc<SimpleDB>, table: &str, request: &HttpRequest) -> ApiResponse {
+        let sample_size = match request.query_param("sample").map(str::parse::<usize>) {
+            Some(Ok(n)) => Some(n),
+            Some(Err(_)) => return ApiResponse::error("sample 参数必须是非负整数".to_string()),
+            None => None,
+        };
+        match Self::table_schema(db, table, sample_size) {
+            Ok(schema) => ApiResponse::success(schema),
+            Err(e) => ApiResponse::error(format!("查询失败: {}", e)),
+        }
     }
 
-    /// 将JSON值转换为内部Value类型
-    fn convert_json_to_value(json_map: HashMap<String, serde_json::Value>) -> HashMap<String, Value> {
-        json_map
-            .into_iter()
-            .map(|(k, v)| {
-                let value = match v {
-                    serde_json::Value::Null => Value::Null,
-                    serde_json::Value::Bool(b) => Value::Bool(b),
-                    serde_json::Value::Number(n) => {
-                        if let Some(i) = n.as_i64() {
-                            Value::Int(i)
-                        } else if let Some(f) = n.as_f64() {
-                            Value::Float(f)
-                        } else {
-                            Value::Null
-                        }
-                    }
-                    serde_json::Value::String(s) => Value::String(s),
-                    serde_json::Value::Array(arr) => {
-                        Value::Array(arr.into_iter().map(|v| Value::String(v.to_string())).collect())
-                    }
-                    serde_json::Value::Object(_) => Value::String(v.to_string()),
-                };
-                (k, value)
+    fn table_schema(db: &SimpleDB, table: &str, sample_size: Option<usize>) -> Result<serde_json::Value> {
+        let types = db.field_types(table)?;
+        let mode = db.table_mode(table)?;
+        let mut schema = serde_json::json!({
+            "table": table,
+            "indexes": db.list_indexes(table)?,
+            "types": types,
+            "mode": mode,
+        });
+        if mode == TableMode::Strict && sample_size.is_none() {
+            // 严格模式下null总是允许，其他值必须符合声明的类型
+            let fields: Vec<serde_json::Value> = types
+                .iter()
+                .map(|(name, field_type)| serde_json::json!({"name": name, "type": field_type, "nullable": true}))
+                .collect();
+            schema["source"] = "declared".into();
+            schema["total"] = db.count(table)?.into();
+            schema["fields"] = fields.into();
+        } else {
+            let sampled = db.sample_schema(table, sample_size.unwrap_or(DEFAULT_SCHEMA_SAMPLE))?;
+            schema["source"] = "sampled".into();
+            schema["sampled"] = sampled.sampled.into();
+            schema["total"] = sampled.total.into();
+            schema["fields"] = serde_json::json!(sampled.fields);
+        }
+        Ok(schema)
+    }
+
+    /// 处理声明字段类型请求，请求体为`{"types": {"ordered_at": "datetime", "note": null}, "mode": "strict"}`，
+    /// null取消声明
+    ///
+    /// 表不存在时先创建。声明和模式在同一把写锁内一起修改：修改后为严格模式时先检查已有记录，
+    /// 有不符合的记录时声明和模式都不修改，返回422，`data.errors`列出不符合的记录和字段。
+    async fn handle_set_field_types(db: &Arc<SimpleDB>, table: &str, body: &str) -> HttpReply {
+        let req = match serde_json::from_str::<FieldTypesRequest>(body) {
+            Ok(req) => req,
+            Err(e) => return ApiResponse::error(format!("JSON解析错误: {}", e)).into(),
+        };
+        let result = db
+            .create_table(table)
+            .and_then(|_| db.set_table_schema(table, &req.types, req.mode))
+            .and_then(|_| Ok((db.field_types(table)?, db.table_mode(table)?)));
+        match result {
+            Ok((types, mode)) => ApiResponse::success(serde_json::json!({"table": table, "types": types, "mode": mode})).into(),
+            Err(e) => Self::error_reply("声明字段类型失败", e),
+        }
+    }
+
+    /// 处理列出索引请求
+    /// 处理统计信息请求，`analyze`为true或还没有统计过时先重新统计
+    async fn handle_stats(db: &Arc<SimpleDB>, table: &str, analyze: bool) -> ApiResponse {
+        let index_builds = match db.index_builds(table) {
+            Ok(builds) => builds,
+            Err(e) => return ApiResponse::error(format!("查询失败: {}", e)),
+        };
+        let result = match db.table_stats(table) {
+            Ok(Some(stats)) if !analyze => Ok(stats),
+            Ok(_) => db.analyze(table),
+            Err(e) => Err(e),
+        };
+        let stats = match result {
+            Ok(stats) => stats,
+            Err(e) => return ApiResponse::error(format!("查询失败: {}", e)),
+        };
+        let fields: serde_json::Map<String, serde_json::Value> = stats
+            .fields
+            .iter()
+            .map(|(name, field)| {
+                let most_common: Vec<serde_json::Value> = field
+                    .most_common
+                    .iter()
+                    .map(|(value, count)| serde_json::json!({"value": Self::value_to_json(value), "count": count}))
+                    .collect();
+                let field = serde_json::json!({
+                    "present": field.present,
+                    "nulls": field.nulls,
+                    "null_ratio": field.null_ratio(),
+                    "distinct": field.distinct,
+                    "min": field.min.as_ref().map(Self::value_to_json),
+                    "max": field.max.as_ref().map(Self::value_to_json),
+                    "most_common": most_common,
+                    "histogram": field.histogram,
+                });
+                (name.clone(), field)
             })
-            .collect()
+            .collect();
+        ApiResponse::success(serde_json::json!({
+            "table": table,
+            "rows": stats.rows,
+            "analyzed_at": stats.analyzed_at,
+            "fields": fields,
+            "index_builds": index_builds,
+            "counters": db.table_counters(table).unwrap_or_default(),
+        }))
     }
 
-    /// 将记录转换为JSON
-    fn convert_record_to_json(record: &crate::storage::Record) -> serde_json::Value {
-        let mut json_map = serde_json::Map::new();
-        json_map.insert("id".to_string(), serde_json::Value::String(record.id.clone()));
-        json_map.insert("created_at".to_string(), serde_json::Value::Number(record.created_at.into()));
-        json_map.insert("updated_at".to_string(), serde_json::Value::Number(record.updated_at.into()));
+    /// 以Prometheus文本格式输出各表的读写计数
+    async fn handle_metrics(db: &Arc<SimpleDB>) -> HttpReply {
+        let counters = db.counters();
+        let mut text = String::new();
+        for (i, (name, help, _)) in Counters::default().metrics().into_iter().enumerate() {
+            text.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n", name, help, name));
+            for (table, counters) in &counters {
+                let table = table.replace('\\', "\\\\").replace('"', "\\\"");
+                text.push_str(&format!("{}{{table=\"{}\"}} {}\n", name, table, counters.metrics()[i].2));
+            }
+        }
+        HttpReply::bytes(text.into_bytes(), "text/plain; version=0.0.4")
+    }
 
-        let mut data_map = serde_json::Map::new();
-        for (key, value) in &record.data {
-            let json_value = match value {
-                Value::Null => serde_json::Value::Null,
-                Value::Bool(b) => serde_json::Value::Bool(*b),
-                Value::Int(i) => serde_json::Value::Number((*i).into()),
-                Value::Float(f) => serde_json::Value::Number(
-                    serde_json::Number::from_f64(*f).unwrap_or(serde_json::Number::from(0)),
-                ),
-                Value::String(s) => serde_json::Value::String(s.clone()),
-                Value::Bytes(b) => serde_json::Value::String(base64::engine::general_purpose::STANDARD.encode(b)),
-                Value::Array(arr) => serde_json::Value::Array(
-                    arr.iter()
-                        .map(|v| serde_json::Value::String(format!("{:?}", v)))
-                        .collect(),
-                ),
-                Value::Object(_) => serde_json::Value::String(format!("{:?}", value)),
-            };
-            data_map.insert(key.clone(), json_value);
+    async fn handle_list_indexes(db: &Arc<SimpleDB>, table: &str) -> ApiResponse {
+        match db.list_indexes(table) {
+            Ok(indexes) => ApiResponse::success(serde_json::json!(indexes)),
+            Err(e) => ApiResponse::error(format!("查询失败: {}", e)),
         }
-        json_map.insert("data".to_string(), serde_json::Value::Object(data_map));
+    }
 
-        serde_json::Value::Object(json_map)
+    /// 表的修改与保存状态，时间为UTC的ISO-8601字符串
+    async fn handle_table_info(db: &Arc<SimpleDB>, table: &str) -> ApiResponse {
+        let info = match db.table_info(table) {
+            Ok(info) => info,
+            Err(e) => return ApiResponse::error(format!("查询失败: {}", e)),
+        };
+        let time = |time: Option<std::time::SystemTime>| {
+            time.and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|since| datetime::format(since.as_millis() as i64))
+        };
+        ApiResponse::success(serde_json::json!({
+            "table": info.name,
+            "records": info.records,
+            "modified_at": time(info.modified_at),
+            "saved_at": time(info.saved_at),
+            "dirty": info.dirty,
+            "pending_changes": info.pending_changes,
+        }))
+    }
+
+    /// 处理创建索引请求，请求体为`{"field": "age", "kind": "hash"}`，`kind`可省略或为`geo`
+    async fn handle_create_index(db: &Arc<SimpleDB>, table: &str, body: &str) -> ApiResponse {
+        let req = match serde_json::from_str::<IndexRequest>(body) {
+            Ok(req) => req,
+            Err(e) => return ApiResponse::error(format!("JSON解析错误: {}", e)),
+        };
+        let types = Self::field_types(db, table);
+        let filter = match req.filter.as_ref().map(|filter| Self::build_conditions(filter, &types)).transpose() {
+            Ok(filter) => filter.unwrap_or_default(),
+            Err(e) => return ApiResponse::error(e),
+        };
+        let result = match req.kind {
+            IndexKind::Hash if req.background => match db.create_index_background(table, &req.field, filter) {
+                Ok(_) => return ApiResponse::message(format!("已开始在后台创建字段 {} 上的索引", req.field)),
+                Err(e) => Err(e),
+            },
+            IndexKind::Hash => db.create_partial_index(table, &req.field, filter),
+            IndexKind::Geo if filter.is_empty() => db.create_geo_index(table, &req.field),
+            IndexKind::Unique if filter.is_empty() => {
+                let fields: Vec<&str> = req.field.split(',').map(str::trim).collect();
+                db.create_unique_index(table, &fields)
+            }
+            IndexKind::Geo | IndexKind::Unique => {
+                return ApiResponse::error("只有等值索引支持过滤条件".to_string())
+            }
+        };
+        match result {
+            Ok(()) => ApiResponse::message(format!("已在字段 {} 上创建索引", req.field)),
+            Err(e) => ApiResponse::error(format!("创建索引失败: {}", e)),
+        }
     }
-} 
\ No newline at end of file
+
+    /// 处理定时任务请求：`PUT`添加或替换任务，`DELETE`删除任务
+    async fn handle_schedule(db: &Arc<SimpleDB>, method: &str, name: &str, body: &str) -> ApiResponse {
+        let result = match method {
+            "PUT" => match serde_json::from_str::<ScheduleRequest>(body) {
+                Ok(req) => db.schedule(name, &req.cron, req.job).map(|()| format!("已添加定时任务 {}", name)),
+                Err(e) => return ApiResponse::error(format!("JSON解析错误: {}", e)),
+            },
+            "DELETE" => match db.unschedule(name) {
+                Ok(true) => Ok(format!("已删除定时任务 {}", name)),
+                Ok(false) => return ApiResponse::error(format!("定时任务 {} 不存在", name)),
+                Err(e) => Err(e),
+            },
+            _ => return ApiResponse::error("不支持的API端点".to_string()),
+        };
+        match result {
+            Ok(message) => ApiResponse::message(message),
+            Err(e) => ApiResponse::error(format!("修改定时任务失败: {}", e)),
+        }
+    }
+
+    /// 处理删除索引请求
+    async fn handle_drop_index(db: &Arc<SimpleDB>, table: &str, field: &str) -> ApiResponse {
+        match db.drop_index(table, field) {
+            Ok(true) => ApiResponse::message(format!("已删除字段 {} 上的索引", field)),
+            Ok(false) => ApiResponse::error(format!("字段 {} 上没有索引", field)),
+            Err(e) => ApiResponse::error(format!("删除索引失败: {}", e)),
+        }
+    }
+
+    /// 处理导出请求，以流式响应返回整张表或满足`query`参数（JSON过滤条件）的记录
+    ///
+    /// `format`参数为`jsonl`（默认）或`csv`，`mapping`参数为改写记录数据的字段映射。
+    async fn handle_export(db: &Arc<SimpleDB>, table: &str, request: &HttpRequest) -> HttpReply {
+        let format = match request.query_param("format").unwrap_or("jsonl") {
+            "jsonl" | "ndjson" => StreamFormat::Jsonl,
+            "csv" => StreamFormat::Csv,
+            other => return ApiResponse::error(format!("不支持的导出格式: {}", other)).into(),
+        };
+        let filters = match request.query_param("query").map(serde_json::from_str) {
+            Some(Ok(filters)) => Some(filters),
+            Some(Err(e)) => return ApiResponse::error(format!("查询条件无效: {}", e)).into(),
+            None => None,
+        };
+        let req = ApiRequest {
+            table: table.to_string(),
+            query: filters,
+            ..Default::default()
+        };
+        let query = match Self::build_query(&req, &Self::field_types(db, table)) {
+            Ok(query) => query,
+            Err(e) => return ApiResponse::error(format!("查询条件无效: {}", e)).into(),
+        };
+        let mapping = match request.query_param("mapping").map(FieldMapping::parse).transpose() {
+            Ok(mapping) => mapping,
+            Err(e) => return ApiResponse::error(e.to_string()).into(),
+        };
+        let records = db.query(table, &query).and_then(|records| match &mapping {
+            Some(mapping) => records
+                .into_iter()
+                .map(|record| {
+                    let mut record = (*record).clone();
+                    record.data = mapping.apply(std::mem::take(&mut record.data))?;
+                    Ok(Arc::new(record))
+                })
+                .collect(),
+            None => Ok(records),
+        });
+        match records {
+            Ok(records) => HttpReply::stream(records, format),
+            Err(e) => ApiResponse::error(format!("导出失败: {}", e)).into(),
+        }
+    }
+
+    /// 拆分`/api/tables/{table}/{action}`形式的路径
+    fn table_route(path: &str) -> Option<(&str, &str)> {
+        let (table, action) = path.strip_prefix("/api/tables/")?.split_once('/')?;
+        (!table.is_empty()).then_some((table, action))
+    }
+
+    /// 请求访问的表：取自路径、查询参数或JSON请求体中的`table`
+    fn request_table(request: &HttpRequest) -> Option<String> {
+        if let Some((table, _)) = Self::table_route(&request.path) {
+            return Some(table.to_string());
+        }
+        if let Some(table) = request.path.strip_prefix("/api/stream/") {
+            return Some(table.to_string());
+        }
+        if let Some(table) = request.query.get("table") {
+            return Some(table.clone());
+        }
+        serde_json::from_slice::<serde_json::Value>(&request.body)
+            .ok()?
+            .get("table")?
+            .as_str()
+            .map(str::to_string)
+    }
+
+    /// 处理完的请求的审计记录
+    fn access_entry(
+        db: &SimpleDB,
+        request: &HttpRequest,
+        client: std::net::SocketAddr,
+        started: std::time::SystemTime,
+        status: u16,
+    ) -> AccessEntry {
+        let caller = request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| db.verify_token(token).ok())
+            .and_then(|claims| match claims.get("sub") {
+                Some(Value::String(sub)) => Some(sub.clone()),
+                _ => None,
+            });
+        AccessEntry {
+            time: started
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as i64),
+            client: Some(client.ip().to_string()),
+            caller,
+            method: request.method.clone(),
+            path: request.path.clone(),
+            table: Self::request_table(request),
+            status,
+            outcome: AccessEntry::outcome_of(status),
+            latency_us: started.elapsed().map_or(0, |d| d.as_micros() as u64),
+        }
+    }
+
+    /// 匹配`POST /api/tables/{table}/import`和请求体为NDJSON的`POST /api/insert?table=...`，返回表名
+    fn import_target(request: &HttpRequest) -> Option<String> {
+        match (request.method.as_str(), Self::table_route(&request.path)) {
+            ("POST", Some((table, "import"))) => Some(table.to_string()),
+            ("POST", _) if request.path == "/api/insert" && http::is_media_type(request.header("content-type"), NDJSON) => {
+                Some(request.query_param("table").unwrap_or_default().to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// 客户端是否接受NDJSON响应
+    fn accepts_ndjson(request: &HttpRequest) -> bool {
+        request
+            .header("accept")
+            .is_some_and(|accept| accept.split(',').any(|media| http::is_media_type(Some(media), NDJSON)))
+    }
+
+    /// 处理JSONL或CSV批量导入和NDJSON插入，边接收边插入
+    ///
+    /// JSONL每行是一条记录的数据对象，CSV首行为列名、值都是字符串；`mapping`参数为字段映射。
+    /// 空行忽略，某行失败不影响其他行。客户端接受NDJSON时逐行返回插入的ID或失败原因，否则返回汇总。
+    async fn handle_import<R: AsyncRead + Unpin>(
+        db: &Arc<SimpleDB>,
+        caller: Option<&Caller<'_>>,
+        table: &str,
+        request: &HttpRequest,
+        body: &mut BodyReader<'_, R>,
+    ) -> HttpReply {
+        if table.is_empty() {
+            return ApiResponse::error("以NDJSON插入时需要table参数".to_string()).into();
+        }
+        if Self::transaction_id(request).is_some() {
+            return ApiResponse::error("以NDJSON插入不支持事务".to_string()).into();
+        }
+        let mut parser = match ImportParser::from_request(request, Self::field_types(db, table)) {
+            Ok(parser) => parser,
+            Err(e) => return ApiResponse::error(e).into(),
+        };
+        let insert = |data| match caller {
+            Some(caller) => caller.insert(table, data),
+            None => db.insert(table, data),
+        };
+        let mut summary = ImportSummary {
+            results: Self::accepts_ndjson(request).then(Vec::new),
+            ..Default::default()
+        };
+        let mut pending = Vec::new();
+        loop {
+            let data = match body.next_chunk().await {
+                Ok(Some(data)) => data,
+                Ok(None) => break,
+                Err(e) => {
+                    let mut response = ApiResponse::error(format!("读取请求体失败: {}", e));
+                    response.data = Some(serde_json::json!(summary));
+                    return response.into();
+                }
+            };
+            pending.extend(data);
+
+            let mut start = 0;
+            while let Some(pos) = parser.line_end(&pending[start..]) {
+                summary.import_line(&pending[start..start + pos], &mut parser, insert);
+                start += pos + 1;
+            }
+            pending.drain(..start);
+        }
+        if !pending.is_empty() {
+            summary.import_line(&pending, &mut parser, insert);
+        }
+        match summary.results.take() {
+            Some(results) => HttpReply::bytes(results, NDJSON),
+            None => ApiResponse::success(serde_json::json!(summary)).into(),
+        }
+    }
+
+    /// 按`Idempotency-Key`头处理写请求：首次请求正常执行并记录结果，
+    /// 重试时直接返回原始结果，不会重复写入
+    async fn idempotent(
+        cache: &IdempotencyCache<HttpReply>,
+        request: &HttpRequest,
+        handler: impl std::future::Future<Output = HttpReply>,
+    ) -> HttpReply {
+        let Some(key) = request.header("idempotency-key") else {
+            return handler.await;
+        };
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        (&request.method, &request.path, &request.body, request.header("x-transaction-id")).hash(&mut hasher);
+        let fingerprint = hasher.finish();
+
+        match cache.begin(key, fingerprint) {
+            // 处理时panic或连接中断，占用随future一起被丢弃，重试会重新执行
+            Attempt::New(reservation) => {
+                let reply = handler.await;
+                reservation.complete(reply.clone());
+                reply
+            }
+            Attempt::Replay(reply) => reply.with_header("Idempotent-Replayed", "true".to_string()),
+            Attempt::InProgress => HttpReply::from(ApiResponse::error("相同幂等键的请求正在处理中".to_string())).with_status(409),
+            Attempt::Mismatch => HttpReply::from(ApiResponse::error("幂等键已用于不同的请求".to_string())).with_status(409),
+        }
+    }
+
+    /// 处理插入请求
+    ///
+    /// 带`X-Transaction-Id`头时只加入事务，返回提交后记录将使用的ID。
+    async fn handle_insert(
+        db: &Arc<SimpleDB>,
+        caller: Option<&Caller<'_>>,
+        sessions: &TransactionSessions,
+        request: &HttpRequest,
+    ) -> HttpReply {
+        match serde_json::from_str::<ApiRequest>(&request.body_str()) {
+            Ok(req) => {
+                if let Some(data) = req.data {
+                    let converted_data = Self::convert_json_to_value(data, &Self::field_types(db, &req.table));
+                    if let Some(tx_id) = Self::transaction_id(request) {
+                        return match sessions.with(tx_id, |tx| tx.insert(&req.table, converted_data)) {
+                            Some(id) => ApiResponse::success(serde_json::json!({"id": id})).into(),
+                            None => Self::transaction_not_found(),
+                        };
+                    }
+                    let returning = req.returning.unwrap_or(false)
+                        || request.header("prefer").is_some_and(|prefer| prefer.contains("return=representation"));
+                    if returning {
+                        let result = match caller {
+                            Some(caller) => caller.insert_returning(&req.table, converted_data),
+                            None => db.insert_returning(&req.table, converted_data),
+                        };
+                        return match result {
+                            Ok(record) => ApiResponse::success(Self::convert_record_to_json(&record)).into(),
+                            Err(e) => Self::error_reply("插入失败", e),
+                        };
+                    }
+                    let result = match caller {
+                        Some(caller) => caller.insert(&req.table, converted_data),
+                        None => db.insert(&req.table, converted_data),
+                    };
+                    match result {
+                        Ok(id) => ApiResponse::success(serde_json::json!({"id": id})).into(),
+                        Err(e) => Self::error_reply("插入失败", e),
+                    }
+                } else {
+                    ApiResponse::error("缺少数据字段".to_string()).into()
+                }
+            }
+            Err(e) => ApiResponse::error(format!("JSON解析错误: {}", e)).into(),
+        }
+    }
+
+    /// 处理查询请求
+    ///
+    /// 按ID查询时返回ETag，`If-None-Match`命中时返回304。
+    async fn handle_find(db: &Arc<SimpleDB>, caller: Option<&Caller<'_>>, request: &HttpRequest) -> HttpReply {
+        let started = std::time::Instant::now();
+        let req = match serde_json::from_str::<ApiRequest>(&request.body_str()) {
+            Ok(req) => req,
+            Err(e) => return ApiResponse::error(format!("JSON解析错误: {}", e)).into(),
+        };
+
+        if let Some(id) = &req.id {
+            // 根据ID查询
+            let record = match caller {
+                Some(caller) => caller.find_by_id(&req.table, id),
+                None => db.find_by_id(&req.table, id),
+            };
+            return match record {
+                Ok(Some(record)) => {
+                    let etag = record.etag();
+                    let not_modified = request
+                        .header("if-none-match")
+                        .is_some_and(|header| http::etag_matches(header, &etag));
+                    let reply = if not_modified {
+                        HttpReply::empty(304)
+                    } else if Self::accepts_ndjson(request) {
+                        HttpReply::stream(vec![record], StreamFormat::Jsonl)
+                    } else {
+                        ApiResponse::success(Self::convert_record_to_json(&record)).into()
+                    };
+                    reply.with_header("ETag", etag)
+                }
+                Ok(None) => ApiResponse::error("记录不存在".to_string()).into(),
+                Err(e) => ApiResponse::error(format!("查询失败: {}", e)).into(),
+            };
+        }
+
+        let mut next_token = None;
+        let (mut total, mut page) = (None, None);
+        let records = if req.query.is_some()
+            || req.order_by.is_some()
+            || req.limit.is_some()
+            || req.offset.is_some()
+            || req.explain.is_some()
+            || req.index.is_some()
+            || req.next_token.is_some()
+        {
+            // 条件查询
+            let query = match Self::build_query(&req, &Self::field_types(db, &req.table)) {
+                Ok(query) => query,
+                Err(e) => return ApiResponse::error(format!("查询条件无效: {}", e)).into(),
+            };
+            if req.explain == Some(true) {
+                let plan = match caller {
+                    Some(caller) => caller.explain(&req.table, &query),
+                    None => db.explain(&req.table, &query),
+                };
+                return match plan {
+                    Ok(plan) => ApiResponse::success(serde_json::json!(plan)),
+                    Err(e) => ApiResponse::error(format!("查询失败: {}", e)),
+                }
+                .into();
+            }
+            // 分页时在同一次扫描中统计满足条件的记录数，不分页时就是返回的条数
+            let paged = query.limit.is_some() || query.offset > 0 || query.after.is_some();
+            let records = if paged {
+                let counted = match caller {
+                    Some(caller) => caller.query_with_total(&req.table, &query),
+                    None => db.query_with_total(&req.table, &query),
+                };
+                counted.map(|(records, all)| {
+                    total = Some(all);
+                    records
+                })
+            } else {
+                match caller {
+                    Some(caller) => caller.query(&req.table, &query),
+                    None => db.query(&req.table, &query),
+                }
+            };
+            if let Ok(records) = &records {
+                // 取满一页时返回下一页令牌
+                if let Some(limit) = query.limit.filter(|&limit| limit > 0) {
+                    if records.len() == limit {
+                        next_token = records.last().map(|r| query.cursor_for(r).encode());
+                    }
+                    if query.after.is_none() {
+                        page = Some(query.offset / limit + 1);
+                    }
+                }
+                total = total.or(Some(records.len()));
+            }
+            records
+        } else {
+            // 查询所有记录
+            let records = match caller {
+                Some(caller) => caller.find_all(&req.table),
+                None => db.find_all(&req.table),
+            };
+            total = records.as_ref().ok().map(Vec::len);
+            records
+        };
+
+        match records {
+            Ok(records) if req.stream == Some(true) || Self::accepts_ndjson(request) => {
+                let mut reply = HttpReply::stream(records, StreamFormat::Jsonl);
+                if let Some(total) = total {
+                    reply = reply.with_header("X-Total-Count", total.to_string());
+                }
+                match next_token {
+                    Some(token) => reply.with_header("X-Next-Token", token),
+                    None => reply,
+                }
+            }
+            Ok(records) => {
+                let json_records: Vec<_> = records
+                    .iter()
+                    .map(|r| Self::convert_record_to_json(r))
+                    .collect();
+                ApiResponse::success(serde_json::json!(json_records))
+                    .with_next_token(next_token)
+                    .with_counts(records.len(), total, page)
+                    .with_elapsed(started)
+                    .into()
+            }
+            Err(e) => ApiResponse::error(format!("查询失败: {}", e)).into(),
+        }
+    }
+
+    /// 处理更新请求
+    ///
+    /// 带`If-Match`头时仅在ETag一致时更新，否则返回412。
+    /// 带`X-Transaction-Id`头时只加入事务，`If-Match`在提交时校验。
+    async fn handle_update(
+        db: &Arc<SimpleDB>,
+        caller: Option<&Caller<'_>>,
+        sessions: &TransactionSessions,
+        request: &HttpRequest,
+    ) -> HttpReply {
+        let req = match serde_json::from_str::<ApiRequest>(&request.body_str()) {
+            Ok(req) => req,
+            Err(e) => return ApiResponse::error(format!("JSON解析错误: {}", e)).into(),
+        };
+        let (id, data) = match (req.id, req.data) {
+            (Some(id), Some(data)) => (id, data),
+            _ => return ApiResponse::error("缺少ID或数据字段".to_string()).into(),
+        };
+        // `If-Match: *`只要求记录存在，普通更新已保证这一点
+        let if_match = request.header("if-match").filter(|v| v.trim() != "*");
+
+        let types = Self::field_types(db, &req.table);
+        if let Some(tx_id) = Self::transaction_id(request) {
+            let ops = match Self::parse_update_ops(&data, &types) {
+                Some(Ok(ops)) => Some(ops),
+                Some(Err(e)) => return ApiResponse::error(format!("更新操作无效: {}", e)).into(),
+                None => None,
+            };
+            let queued = sessions.with(tx_id, |tx| {
+                if let Some(etag) = if_match {
+                    tx.require_etag(&req.table, &id, etag);
+                }
+                match ops {
+                    Some(ops) => tx.patch(&req.table, &id, ops),
+                    None => tx.update(&req.table, &id, Self::convert_json_to_value(data, &types)),
+                }
+            });
+            return match queued {
+                Some(()) => ApiResponse::message("已加入事务".to_string()).into(),
+                None => Self::transaction_not_found(),
+            };
+        }
+
+        let result = match Self::parse_update_ops(&data, &types) {
+            Some(Ok(ops)) => match (caller, if_match) {
+                (Some(caller), _) => caller.patch(&req.table, &id, &ops, if_match),
+                (None, Some(etag)) => db.patch_if_match(&req.table, &id, etag, &ops),
+                (None, None) => db.patch(&req.table, &id, &ops),
+            },
+            Some(Err(e)) => return ApiResponse::error(format!("更新操作无效: {}", e)).into(),
+            None => {
+                let converted_data = Self::convert_json_to_value(data, &types);
+                match (caller, if_match) {
+                    (Some(caller), _) => caller.update(&req.table, &id, converted_data, if_match),
+                    (None, Some(etag)) => db.update_if_match(&req.table, &id, etag, converted_data),
+                    (None, None) => db.update(&req.table, &id, converted_data),
+                }
+            }
+        };
+        match result {
+            Ok(_) => {
+                let reply: HttpReply = ApiResponse::message("更新成功".to_string()).into();
+                match db.find_by_id(&req.table, &id) {
+                    Ok(Some(record)) => reply.with_header("ETag", record.etag()),
+                    _ => reply,
+                }
+            }
+            Err(e) => Self::error_reply("更新失败", e),
+        }
+    }
+
+    /// 处理删除请求
+    ///
+    /// 带`If-Match`头时仅在ETag一致时删除，否则返回412。
+    /// 带`X-Transaction-Id`头时只加入事务。
+    async fn handle_delete(
+        db: &Arc<SimpleDB>,
+        caller: Option<&Caller<'_>>,
+        sessions: &TransactionSessions,
+        request: &HttpRequest,
+    ) -> HttpReply {
+        let req = match serde_json::from_str::<ApiRequest>(&request.body_str()) {
+            Ok(req) => req,
+            Err(e) => return ApiResponse::error(format!("JSON解析错误: {}", e)).into(),
+        };
+        let id = match req.id {
+            Some(id) => id,
+            None => return ApiResponse::error("缺少ID字段".to_string()).into(),
+        };
+
+        let if_match = request.header("if-match").filter(|v| v.trim() != "*");
+
+        if let Some(tx_id) = Self::transaction_id(request) {
+            let queued = sessions.with(tx_id, |tx| {
+                if let Some(etag) = if_match {
+                    tx.require_etag(&req.table, &id, etag);
+                }
+                tx.delete(&req.table, &id);
+            });
+            return match queued {
+                Some(()) => ApiResponse::message("已加入事务".to_string()).into(),
+                None => Self::transaction_not_found(),
+            };
+        }
+
+        let result = match (caller, if_match) {
+            (Some(caller), _) => caller.delete(&req.table, &id, if_match),
+            (None, Some(etag)) => db.delete_if_match(&req.table, &id, etag),
+            (None, None) => db.delete(&req.table, &id),
+        };
+        match result {
+            Ok(_) => ApiResponse::message("删除成功".to_string()).into(),
+            Err(e) => Self::error_reply("删除失败", e),
+        }
+    }
+
+    /// 处理变更流请求，以Server-Sent Events推送表的插入、更新和删除
+    ///
+    /// 重连时按`Last-Event-ID`头（或`last_event_id`参数）补发断线期间的事件。
+    async fn handle_stream(db: &Arc<SimpleDB>, table: &str, request: &HttpRequest) -> HttpReply {
+        if let Err(e) = db.count(table) {
+            return ApiResponse::error(format!("订阅失败: {}", e)).into();
+        }
+        let after = match request
+            .header("last-event-id")
+            .or_else(|| request.query_param("last_event_id"))
+            .map(|v| v.trim().parse::<u64>())
+        {
+            Some(Ok(seq)) => Some(seq),
+            Some(Err(_)) => return ApiResponse::error("无效的Last-Event-ID".to_string()).into(),
+            None => None,
+        };
+        HttpReply {
+            status: 200,
+            headers: Vec::new(),
+            body: ReplyBody::Events {
+                table: table.to_string(),
+                after,
+                feed: db.change_feed(),
+            },
+        }
+    }
+
+    /// 处理开始事务请求
+    async fn handle_begin(sessions: &TransactionSessions) -> ApiResponse {
+        let id = sessions.begin();
+        ApiResponse::success(serde_json::json!({
+            "transaction_id": id,
+            "timeout_secs": sessions.timeout().as_secs(),
+        }))
+    }
+
+    /// 处理提交事务请求，事务中的操作原子地生效
+    async fn handle_commit(db: &Arc<SimpleDB>, sessions: &TransactionSessions, id: &str) -> HttpReply {
+        let tx = match sessions.take(id) {
+            Some(tx) => tx,
+            None => return Self::transaction_not_found(),
+        };
+        let operations = tx.len();
+        // 提交可能等待表锁或集群复制，不占用异步运行时的工作线程
+        let db = Arc::clone(db);
+        let context = TraceContext::current();
+        let committed = tokio::task::spawn_blocking(move || match context {
+            Some(context) => context.scope(|| db.commit(tx)),
+            None => db.commit(tx),
+        })
+            .await
+            .unwrap_or_else(|e| Err(DatabaseError::Internal(format!("提交事务时发生panic: {}", e))));
+        match committed {
+            Ok(()) => {
+                let mut response = ApiResponse::message("事务已提交".to_string());
+                response.data = Some(serde_json::json!({"operations": operations}));
+                response.into()
+            }
+            Err(e) => Self::error_reply("提交失败，事务已回滚", e),
+        }
+    }
+
+    /// 处理回滚事务请求
+    async fn handle_rollback(sessions: &TransactionSessions, id: &str) -> HttpReply {
+        match sessions.take(id) {
+            Some(_) => ApiResponse::message("事务已回滚".to_string()).into(),
+            None => Self::transaction_not_found(),
+        }
+    }
+
+    /// 请求所属的事务ID
+    fn transaction_id(request: &HttpRequest) -> Option<&str> {
+        request.header("x-transaction-id").map(str::trim)
+    }
+
+    /// 事务不存在或已超时
+    fn transaction_not_found() -> HttpReply {
+        HttpReply::from(ApiResponse::error("事务不存在或已超时".to_string())).with_status(404)
+    }
+
+    /// 配置了令牌签名密钥时按`Authorization: Bearer <令牌>`识别调用方
+    ///
+    /// 返回受行级安全策略限制的调用方；没有配置密钥或调用方是管理员时为None。
+    fn authenticate<'a>(db: &'a SimpleDB, request: &HttpRequest) -> Result<Option<Caller<'a>>> {
+        if db.config().auth_key.is_none() {
+            return Ok(None);
+        }
+        let token = request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| DatabaseError::NotPermitted("缺少令牌".to_string()))?;
+        let caller = db.as_caller(db.verify_token(token)?);
+        Ok((!caller.is_admin()).then_some(caller))
+    }
+
+    /// 缺少令牌或令牌无效时的401响应
+    fn unauthorized(error: DatabaseError) -> HttpReply {
+        HttpReply::from(ApiResponse::error(error.to_string()))
+            .with_status(401)
+            .with_header("WWW-Authenticate", "Bearer".to_string())
+    }
+
+    fn admin_required() -> HttpReply {
+        HttpReply::from(ApiResponse::error("该接口需要管理员令牌".to_string())).with_status(403)
+    }
+
+    /// 将数据库错误转换为响应，前置条件失败时使用412状态码，不允许的操作使用403
+    fn error_reply(context: &str, error: DatabaseError) -> HttpReply {
+        let status = match error {
+            DatabaseError::PreconditionFailed(_) => 412,
+            DatabaseError::NotPermitted(_) => 403,
+            DatabaseError::QuotaExceeded(_) => 507,
+            // 放弃等待锁的事务没有任何效果，可以重试
+            DatabaseError::Deadlock(_) => 409,
+            DatabaseError::LockTimeout(_) => 503,
+            // 集群正在选举主节点，稍后重试
+            DatabaseError::NotLeader(_) => 503,
+            DatabaseError::Internal(_) => 500,
+            DatabaseError::Validation(_) => 422,
+            _ => 200,
+        };
+        let mut response = ApiResponse::error(format!("{}: {}", context, error));
+        // 校验失败时逐个列出字段错误，客户端可以标注到对应的输入上
+        if let DatabaseError::Validation(errors) = &error {
+            response.data = Some(serde_json::json!({"errors": errors}));
+        }
+        HttpReply::from(response).with_status(status)
+    }
+
+    /// 处理列出表请求
+    async fn handle_list_tables(db: &Arc<SimpleDB>) -> ApiResponse {
+        let tables = db.list_tables();
+        ApiResponse::success(serde_json::json!(tables))
+    }
+
+    /// 处理SQL查询请求，请求体为`{"query": "SELECT ..."}`
+    async fn handle_sql(db: &Arc<SimpleDB>, body: &str) -> ApiResponse {
+        let req = match serde_json::from_str::<SqlRequest>(body) {
+            Ok(req) => req,
+            Err(e) => return ApiResponse::error(format!("JSON解析错误: {}", e)),
+        };
+        let started = std::time::Instant::now();
+        match db.sql(&req.query) {
+            Ok(result) => {
+                let rows: Vec<Vec<serde_json::Value>> = result
+                    .rows
+                    .iter()
+                    .map(|row| row.iter().map(Self::value_to_json).collect())
+                    .collect();
+                let count = rows.len();
+                ApiResponse::success(serde_json::json!({
+                    "columns": result.columns,
+                    "rows": rows,
+                }))
+                .with_counts(count, None, None)
+                .with_elapsed(started)
+            }
+            Err(e) => ApiResponse::error(format!("查询失败: {}", e)),
+        }
+    }
+
+    /// 处理预备查询请求，请求体为参数绑定，如`{"min": 18}`
+    async fn handle_prepared(db: &Arc<SimpleDB>, caller: Option<&Caller<'_>>, name: &str, body: &str) -> ApiResponse {
+        let body = if body.trim().is_empty() { "{}" } else { body };
+        let bindings = match serde_json::from_str::<IndexMap<String, serde_json::Value>>(body) {
+            Ok(bindings) => bindings,
+            Err(e) => return ApiResponse::error(format!("JSON解析错误: {}", e)),
+        };
+        let bindings = bindings
+            .into_iter()
+            .map(|(name, value)| (name, Self::convert_json_value(value)))
+            .collect();
+        let started = std::time::Instant::now();
+        let records = match caller {
+            Some(caller) => caller.execute_prepared(name, &bindings),
+            None => db.execute_prepared(name, &bindings),
+        };
+        match records {
+            Ok(records) => {
+                let json_records: Vec<_> = records.iter().map(|r| Self::convert_record_to_json(r)).collect();
+                ApiResponse::success(serde_json::json!(json_records))
+                    .with_counts(records.len(), None, None)
+                    .with_elapsed(started)
+            }
+            Err(e) => ApiResponse::error(format!("查询失败: {}", e)),
+        }
+    }
+
+    /// 处理聚合管道请求
+    async fn handle_aggregate(db: &Arc<SimpleDB>, body: &str) -> ApiResponse {
+        let req = match serde_json::from_str::<AggregateRequest>(body) {
+            Ok(req) => req,
+            Err(e) => return ApiResponse::error(format!("JSON解析错误: {}", e)),
+        };
+        let pipeline = match Self::build_pipeline(&req.pipeline, &Self::field_types(db, &req.table)) {
+            Ok(pipeline) => pipeline,
+            Err(e) => return ApiResponse::error(format!("聚合管道无效: {}", e)),
+        };
+        let started = std::time::Instant::now();
+        match db.aggregate(&req.table, &pipeline) {
+            Ok(documents) => {
+                let documents: Vec<serde_json::Value> = documents
+                    .iter()
+                    .map(|doc| {
+                        let fields: serde_json::Map<String, serde_json::Value> =
+                            doc.iter().map(|(k, v)| (k.clone(), Self::value_to_json(v))).collect();
+                        serde_json::Value::Object(fields)
+                    })
+                    .collect();
+                let count = documents.len();
+                ApiResponse::success(serde_json::json!(documents))
+                    .with_counts(count, None, None)
+                    .with_elapsed(started)
+            }
+            Err(e) => ApiResponse::error(format!("查询失败: {}", e)),
+        }
+    }
+
+    /// 处理同步请求：返回服务器上所有记录的版本和客户端需要的记录
+    async fn handle_sync(db: &Arc<SimpleDB>, body: &str) -> ApiResponse {
+        let req = match serde_json::from_str::<SyncRequest>(body) {
+            Ok(req) => req,
+            Err(e) => return ApiResponse::error(format!("JSON解析错误: {}", e)),
+        };
+        let db = Arc::clone(db);
+        match tokio::task::spawn_blocking(move || db.sync_offer(&req.versions)).await {
+            Ok(Ok(offer)) => ApiResponse::success(serde_json::json!(offer)),
+            Ok(Err(e)) => ApiResponse::error(format!("同步失败: {}", e)),
+            Err(e) => ApiResponse::error(format!("同步失败: {}", e)),
+        }
+    }
+
+    /// 处理同步推送：写入客户端发来的记录
+    async fn handle_sync_push(db: &Arc<SimpleDB>, body: &str) -> ApiResponse {
+        let req = match serde_json::from_str::<SyncPushRequest>(body) {
+            Ok(req) => req,
+            Err(e) => return ApiResponse::error(format!("JSON解析错误: {}", e)),
+        };
+        let db = Arc::clone(db);
+        match tokio::task::spawn_blocking(move || db.sync_accept(req.entries)).await {
+            Ok(Ok(applied)) => ApiResponse::success(serde_json::json!({"applied": applied})),
+            Ok(Err(e)) => ApiResponse::error(format!("同步失败: {}", e)),
+            Err(e) => ApiResponse::error(format!("同步失败: {}", e)),
+        }
+    }
+
+    /// 解析Mongo风格的聚合管道
+    ///
+    /// 字段引用可以带`$`前缀；`$group`的`_id`为分组字段（字符串、数组或null），
+    /// 其余键为`{"$sum": "amount"}`、`{"$count": {}}`形式的聚合，分组字段以原名出现在输出中。
+    fn build_pipeline(
+        stages: &[serde_json::Value],
+        types: &BTreeMap<String, FieldType>,
+    ) -> std::result::Result<Pipeline, String> {
+        let field_ref = |value: &serde_json::Value| -> std::result::Result<String, String> {
+            match value.as_str() {
+                Some(field) => Ok(field.strip_prefix('$').unwrap_or(field).to_string()),
+                None => Err(format!("字段引用必须是字符串: {}", value)),
+            }
+        };
+        let mut pipeline = Pipeline::new();
+        for stage in stages {
+            let (name, spec) = match stage.as_object().map(|o| o.iter().collect::<Vec<_>>()).as_deref() {
+                Some([(name, spec)]) => (name.as_str(), *spec),
+                _ => return Err(format!("每个阶段必须是只有一个键的对象: {}", stage)),
+            };
+            let object = || spec.as_object().ok_or_else(|| format!("{} 的参数必须是对象", name));
+            let count = || spec.as_u64().map(|n| n as usize).ok_or_else(|| format!("{} 的参数必须是非负整数", name));
+            let stage = match name {
+                "$match" => {
+                    let filters: IndexMap<String, serde_json::Value> = object()?.clone().into_iter().collect();
+                    Stage::Match(Self::build_conditions(&filters, types)?)
+                }
+                "$project" => {
+                    let mut fields = Vec::new();
+                    for (output, source) in object()? {
+                        // 1或true保留字段，0或false不输出，字符串为来源字段
+                        let keep = match source {
+                            serde_json::Value::Bool(keep) => Some(*keep),
+                            serde_json::Value::Number(n) => Some(n.as_f64() != Some(0.0)),
+                            _ => None,
+                        };
+                        match keep {
+                            Some(true) => fields.push((output.clone(), output.clone())),
+                            Some(false) => {}
+                            None => fields.push((output.clone(), field_ref(source)?)),
+                        }
+                    }
+                    Stage::Project(fields)
+                }
+                "$group" => {
+                    let mut by = Vec::new();
+                    let mut accumulators = Vec::new();
+                    for (output, spec) in object()? {
+                        if output == "_id" {
+                            by = match spec {
+                                serde_json::Value::Null => Vec::new(),
+                                serde_json::Value::Array(fields) => fields.iter().map(field_ref).collect::<std::result::Result<_, _>>()?,
+                                field => vec![field_ref(field)?],
+                            };
+                            continue;
+                        }
+                        let (op, field) = match spec.as_object().map(|o| o.iter().collect::<Vec<_>>()).as_deref() {
+                            Some([(op, field)]) => (op.as_str(), *field),
+                            _ => return Err(format!("聚合 {} 必须是只有一个键的对象", output)),
+                        };
+                        let func = op
+                            .strip_prefix('$')
+                            .and_then(Aggregate::from_name)
+                            .ok_or_else(|| format!("不支持的聚合: {}", op))?;
+                        accumulators.push(match (func, field) {
+                            (Aggregate::Count, serde_json::Value::Object(_)) => Accumulator::count(output),
+                            (func, field) => Accumulator::new(output, func, &field_ref(field)?),
+                        });
+                    }
+                    Stage::Group { by, accumulators }
+                }
+                "$sort" => {
+                    // 对象按键的书写顺序排序，也可以写成[["total", -1], ["name", 1]]
+                    let keys: Vec<(String, &serde_json::Value)> = match spec {
+                        serde_json::Value::Array(pairs) => pairs
+                            .iter()
+                            .map(|pair| match pair.as_array().map(Vec::as_slice) {
+                                Some([field, order]) => Ok((field_ref(field)?, order)),
+                                _ => Err(format!("排序项必须是[字段, 方向]: {}", pair)),
+                            })
+                            .collect::<std::result::Result<_, _>>()?,
+                        _ => object()?.iter().map(|(field, order)| (field.clone(), order)).collect(),
+                    };
+                    let mut order_by = Vec::new();
+                    for (field, order) in keys {
+                        let order = match order.as_i64() {
+                            Some(1) => SortOrder::Asc,
+                            Some(-1) => SortOrder::Desc,
+                            _ => return Err(format!("字段 {} 的排序方向必须是1或-1", field)),
+                        };
+                        order_by.push((field, order));
+                    }
+                    Stage::Sort(order_by)
+                }
+                "$skip" => Stage::Skip(count()?),
+                "$limit" => Stage::Limit(count()?),
+                other => return Err(format!("不支持的阶段: {}", other)),
+            };
+            pipeline = pipeline.stage(stage);
+        }
+        Ok(pipeline)
+    }
+
+    /// 由JSON过滤条件构建查询条件
+    ///
+    /// 每个字段对应一个值（相等）或`{"$gt": 18, "$lte": 60}`形式的比较。
+    fn build_conditions(
+        filters: &IndexMap<String, serde_json::Value>,
+        types: &BTreeMap<String, FieldType>,
+    ) -> std::result::Result<Vec<Condition>, String> {
+        let mut conditions = Vec::new();
+        for (field, spec) in filters {
+            match spec {
+                serde_json::Value::Object(ops) if ops.keys().all(|k| k.starts_with('$')) && !ops.is_empty() => {
+                    for (op, value) in ops {
+                        let op = match op.as_str() {
+                            "$eq" => Operator::Eq,
+                            "$ne" => Operator::Ne,
+                            "$gt" => Operator::Gt,
+                            "$gte" => Operator::Gte,
+                            "$lt" => Operator::Lt,
+                            "$lte" => Operator::Lte,
+                            other => return Err(format!("不支持的比较操作符: {}", other)),
+                        };
+                        conditions.push(Condition::new(field, op, Self::convert_field(types, field, value.clone())));
+                    }
+                }
+                value => conditions.push(Condition::eq(field, Self::convert_field(types, field, value.clone()))),
+            }
+        }
+        Ok(conditions)
+    }
+
+    /// 由请求构建查询
+    fn build_query(req: &ApiRequest, types: &BTreeMap<String, FieldType>) -> std::result::Result<Query, String> {
+        let mut query = Query::new();
+        if let Some(filters) = &req.query {
+            query.conditions = Self::build_conditions(filters, types)?;
+        }
+        if let Some(order_by) = &req.order_by {
+            let mut fields = Vec::with_capacity(order_by.len());
+            for (field, order) in order_by {
+                let order = match order.to_lowercase().as_str() {
+                    "asc" => SortOrder::Asc,
+                    "desc" => SortOrder::Desc,
+                    other => return Err(format!("无效的排序方向: {}", other)),
+                };
+                fields.push((field.clone(), order));
+            }
+            query = query.order_by(fields);
+        }
+        if let Some(offset) = req.offset {
+            query = query.offset(offset);
+        }
+        if let Some(limit) = req.limit {
+            query = query.limit(limit);
+        }
+        if let Some(token) = &req.next_token {
+            let cursor = Cursor::decode(token, &query).map_err(|e| e.to_string())?;
+            query = query.after(cursor);
+        }
+        match &req.index {
+            None => {}
+            Some(serde_json::Value::String(field)) => query = query.use_index(field),
+            Some(serde_json::Value::Bool(false)) => query = query.force_scan(),
+            Some(other) => return Err(format!("index 必须是字段名或false: {}", other)),
+        }
+        Ok(query)
+    }
+
+    /// 将JSON对象转换为内部数据表示，`types`中声明了类型的字段按声明转换，如ISO-8601字符串转换为时间戳
+    fn convert_json_to_value(
+        json_map: IndexMap<String, serde_json::Value>,
+        types: &BTreeMap<String, FieldType>,
+    ) -> IndexMap<String, Value> {
+        json_map
+            .into_iter()
+            .map(|(k, v)| {
+                let value = Self::convert_field(types, &k, v);
+                (k, value)
+            })
+            .collect()
+    }
+
+    /// 转换一个字段的JSON值，字段声明了类型时按声明转换
+    fn convert_field(types: &BTreeMap<String, FieldType>, field: &str, v: serde_json::Value) -> Value {
+        let value = Self::convert_json_value(v);
+        match types.get(field) {
+            Some(field_type) => field_type.convert(value),
+            None => value,
+        }
+    }
+
+    /// 按声明的字段类型转换已解析的数据
+    fn convert_types(data: IndexMap<String, Value>, types: &BTreeMap<String, FieldType>) -> IndexMap<String, Value> {
+        if types.is_empty() {
+            return data;
+        }
+        data.into_iter()
+            .map(|(k, v)| match types.get(&k) {
+                Some(field_type) => {
+                    let value = field_type.convert(v);
+                    (k, value)
+                }
+                None => (k, v),
+            })
+            .collect()
+    }
+
+    /// 表中声明的字段类型，表不存在时为空
+    fn field_types(db: &SimpleDB, table: &str) -> BTreeMap<String, FieldType> {
+        db.field_types(table).unwrap_or_default()
+    }
+
+    /// 将单个JSON值转换为内部Value类型
+    pub(crate) fn convert_json_value(v: serde_json::Value) -> Value {
+        match v {
+            serde_json::Value::Null => Value::Null,
+            serde_json::Value::Bool(b) => Value::Bool(b),
+            serde_json::Value::Number(n) => {
+                if let Some(i) = n.as_i64() {
+                    Value::Int(i)
+                } else if let Some(f) = n.as_f64() {
+                    Value::Float(f)
+                } else {
+                    Value::Null
+                }
+            }
+            serde_json::Value::String(s) => Value::String(s),
+            serde_json::Value::Array(arr) => {
+                Value::Array(arr.into_iter().map(Self::convert_json_value).collect())
+            }
+            serde_json::Value::Object(ref obj) => Self::parse_geo_point(obj)
+                .or_else(|| Self::parse_vector(obj))
+                .or_else(|| Self::parse_ref(obj))
+                .unwrap_or_else(|| Value::String(v.to_string())),
+        }
+    }
+
+    /// 将`{"$push": {"tags": "rust"}, ...}`形式的更新体解析为更新操作
+    ///
+    /// 不含`$`前缀键的更新体返回None，按整体替换处理。操作按请求体中操作符和字段出现的顺序应用。
+    fn parse_update_ops(
+        data: &IndexMap<String, serde_json::Value>,
+        types: &BTreeMap<String, FieldType>,
+    ) -> Option<std::result::Result<Vec<UpdateOp>, String>> {
+        if !data.keys().any(|k| k.starts_with('$')) {
+            return None;
+        }
+        Some(Self::collect_update_ops(data, types))
+    }
+
+    fn collect_update_ops(
+        data: &IndexMap<String, serde_json::Value>,
+        types: &BTreeMap<String, FieldType>,
+    ) -> std::result::Result<Vec<UpdateOp>, String> {
+        let mut ops = Vec::new();
+        for (operator, fields) in data {
+            let fields = fields
+                .as_object()
+                .ok_or_else(|| format!("{} 的参数必须是对象", operator))?;
+            for (field, value) in fields {
+                let convert = || Self::convert_field(types, field, value.clone());
+                let field = field.clone();
+                let op = match operator.as_str() {
+                    "$set" => UpdateOp::Set(field, convert()),
+                    "$push" => UpdateOp::Push(field, convert()),
+                    "$pull" => UpdateOp::Pull(field, convert()),
+                    "$addToSet" => UpdateOp::AddToSet(field, convert()),
+                    "$unset" => UpdateOp::Unset(field),
+                    "$rename" => match value.as_str() {
+                        Some(to) => UpdateOp::Rename(field, to.to_string()),
+                        None => return Err("$rename 的目标字段名必须是字符串".to_string()),
+                    },
+                    "$pop" => match value.as_i64() {
+                        Some(-1) => UpdateOp::Pop(field, PopEnd::First),
+                        Some(1) => UpdateOp::Pop(field, PopEnd::Last),
+                        _ => return Err("$pop 的值必须是 1 或 -1".to_string()),
+                    },
+                    other => return Err(format!("不支持的更新操作符: {}", other)),
+                };
+                ops.push(op);
+            }
+        }
+        Ok(ops)
+    }
+
+    /// 识别`{"lat": .., "lon": ..}`形式的地理坐标
+    fn parse_geo_point(obj: &serde_json::Map<String, serde_json::Value>) -> Option<Value> {
+        if obj.len() != 2 {
+            return None;
+        }
+        let lat = obj.get("lat")?.as_f64()?;
+        let lon = obj.get("lon")?.as_f64()?;
+        Some(Value::GeoPoint { lat, lon })
+    }
+
+    /// 识别`{"$vector": [..]}`形式的向量
+    fn parse_vector(obj: &serde_json::Map<String, serde_json::Value>) -> Option<Value> {
+        if obj.len() != 1 {
+            return None;
+        }
+        let items = obj.get("$vector")?.as_array()?;
+        let vector = items
+            .iter()
+            .map(|v| v.as_f64().map(|f| f as f32))
+            .collect::<Option<Vec<f32>>>()?;
+        Some(Value::Vector(vector))
+    }
+
+    /// 识别`{"$ref": "users", "$id": ".."}`形式的记录引用
+    fn parse_ref(obj: &serde_json::Map<String, serde_json::Value>) -> Option<Value> {
+        if obj.len() != 2 {
+            return None;
+        }
+        Some(Value::reference(obj.get("$ref")?.as_str()?, obj.get("$id")?.as_str()?))
+    }
+
+    /// 将记录转换为JSON
+    pub(crate) fn convert_record_to_json(record: &crate::storage::Record) -> serde_json::Value {
+        let mut json_map = serde_json::Map::new();
+        json_map.insert("id".to_string(), serde_json::Value::String(record.id.clone()));
+        json_map.insert("created_at".to_string(), serde_json::Value::Number(record.created_at.into()));
+        json_map.insert("updated_at".to_string(), serde_json::Value::Number(record.updated_at.into()));
+
+        let mut data_map = serde_json::Map::new();
+        for (key, value) in &record.data {
+            data_map.insert(key.clone(), Self::value_to_json(value));
+        }
+        json_map.insert("data".to_string(), serde_json::Value::Object(data_map));
+
+        serde_json::Value::Object(json_map)
+    }
+
+    /// CSV的数据列：所有记录中出现过的字段，按首次出现的顺序
+    pub(crate) fn csv_columns(records: &[Arc<Record>]) -> Vec<String> {
+        let columns: indexmap::IndexSet<&String> = records.iter().flat_map(|r| r.data.keys()).collect();
+        columns.into_iter().cloned().collect()
+    }
+
+    /// 将记录转换为一行CSV，缺失的字段留空
+    ///
+    /// 时间戳、二进制等以字符串表示的值写入字符串本身而不是带引号的JSON，导入时才能按声明的类型读回。
+    pub(crate) fn csv_record(record: &Record, columns: &[String]) -> String {
+        let values: Vec<String> = columns
+            .iter()
+            .map(|column| match record.data.get(column) {
+                None | Some(Value::Null) => String::new(),
+                Some(value) => match Self::value_to_json(value) {
+                    serde_json::Value::String(s) => s,
+                    json => json.to_string(),
+                },
+            })
+            .collect();
+        let created_at = record.created_at.to_string();
+        let updated_at = record.updated_at.to_string();
+        Self::csv_line(
+            [record.id.as_str(), created_at.as_str(), updated_at.as_str()]
+                .into_iter()
+                .chain(values.iter().map(String::as_str)),
+        )
+    }
+
+    /// 拼接一行CSV，含逗号、引号或换行的字段加引号转义
+    pub(crate) fn csv_line<'a>(fields: impl Iterator<Item = &'a str>) -> String {
+        let mut line = fields
+            .map(|field| {
+                if field.contains([',', '"', '\n', '\r']) {
+                    format!("\"{}\"", field.replace('"', "\"\""))
+                } else {
+                    field.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        line.push_str("\r\n");
+        line
+    }
+
+    /// 拆分一行CSV，与`csv_line`的转义规则相同
+    pub(crate) fn csv_fields(line: &str) -> std::result::Result<Vec<String>, String> {
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut chars = line.chars().peekable();
+        let mut quoted = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' if quoted => quoted = false,
+                '"' if field.is_empty() => quoted = true,
+                ',' if !quoted => fields.push(std::mem::take(&mut field)),
+                c => field.push(c),
+            }
+        }
+        if quoted {
+            return Err("CSV引号没有闭合".to_string());
+        }
+        fields.push(field);
+        Ok(fields)
+    }
+
+    /// 将内部Value转换为JSON值
+    pub(crate) fn value_to_json(value: &Value) -> serde_json::Value {
+        match value {
+            Value::Null => serde_json::Value::Null,
+            Value::Bool(b) => serde_json::Value::Bool(*b),
+            Value::Int(i) => serde_json::Value::Number((*i).into()),
+            Value::Float(f) => serde_json::Value::Number(
+                serde_json::Number::from_f64(*f).unwrap_or(serde_json::Number::from(0)),
+            ),
+            Value::String(s) => serde_json::Value::String(s.clone()),
+            Value::Bytes(b) => serde_json::Value::String(base64::engine::general_purpose::STANDARD.encode(b)),
+            Value::Array(arr) => serde_json::Value::Array(arr.iter().map(Self::value_to_json).collect()),
+            Value::Object(_) => serde_json::Value::String(format!("{:?}", value)),
+            Value::GeoPoint { lat, lon } => serde_json::json!({"lat": lat, "lon": lon}),
+            Value::Vector(v) => serde_json::json!({"$vector": v}),
+            // 不输出密文，只标明所属主体
+            Value::Sealed { subject, .. } => serde_json::json!({"$sealed": subject}),
+            Value::Deterministic { domain, .. } => serde_json::json!({"$deterministic": domain}),
+            Value::Ref { table, id } => serde_json::json!({"$ref": table, "$id": id}),
+            Value::Param(name) => serde_json::json!({"$param": name}),
+            // 表返回的记录已解压、已换回blob的内容，只有无法读出的值会出现在这里
+            Value::Compressed { binary, data } => serde_json::json!({"$compressed": data.len(), "$binary": binary}),
+            Value::Blob { id, size } => serde_json::json!({"$blob": id, "$size": size}),
+            Value::Timestamp(millis) => serde_json::Value::String(datetime::format(*millis)),
+        }
+    }
+} 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config_in, json_body, open, open_with, request, serve, serve_with, temp_dir};
+    use crate::Config;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    #[tokio::test]
+    async fn test_query_metadata() {
+        let (_dir, db) = open("metadata");
+        let db = Arc::new(db);
+        for age in 0..25 {
+            db.insert("users", IndexMap::from([("age".to_string(), Value::Int(age))])).unwrap();
+        }
+        let (address, serving) = serve(Arc::clone(&db));
+        let find = |body: String| async move { json_body(&request(address, "GET /api/find HTTP/1.1", &body).await) };
+
+        let scans = db.table_counters("users").unwrap().scans;
+        let response = find(r#"{"table": "users", "query": {"age": {"$gte": 5}}, "limit": 10, "offset": 10}"#.to_string()).await;
+        assert_eq!((&response["count"], &response["total"], &response["page"]), (&10.into(), &20.into(), &2.into()));
+        assert!(response["elapsed_ms"].as_f64().is_some());
+        // 总数在取这一页的同一次扫描中统计
+        assert_eq!(db.table_counters("users").unwrap().scans, scans + 1);
+        // 按令牌翻页时页码未知
+        let next = response["next_token"].as_str().unwrap().to_string();
+        let body = format!(r#"{{"table": "users", "query": {{"age": {{"$gte": 5}}}}, "limit": 10, "next_token": "{}"}}"#, next);
+        let response = find(body).await;
+        assert_eq!((&response["count"], &response["total"]), (&0.into(), &20.into()));
+        assert!(response.get("page").is_none());
+
+        let response = find(r#"{"table": "users"}"#.to_string()).await;
+        assert_eq!((&response["count"], &response["total"]), (&25.into(), &25.into()));
+        serving.abort();
+        let _ = serving.await;
+    }
+
+    #[tokio::test]
+    async fn test_insert_returning() {
+        let (_dir, db) = open("returning");
+        let db = Arc::new(db);
+        let (address, serving) = serve(Arc::clone(&db));
+        let insert = |head: &'static str, body: &'static str| async move { json_body(&request(address, head, body).await) };
+
+        let response = insert("POST /api/insert HTTP/1.1", r#"{"table": "users", "data": {"name": "alice"}, "returning": true}"#).await;
+        let id = response["data"]["id"].as_str().unwrap();
+        let record = db.find_by_id("users", id).unwrap().unwrap();
+        assert_eq!(response["data"], DatabaseServer::convert_record_to_json(&record));
+        assert_eq!(response["data"]["data"]["name"], "alice");
+
+        let response = insert("POST /api/insert HTTP/1.1\r\nPrefer: return=representation", r#"{"table": "users", "data": {"name": "bob"}}"#).await;
+        assert!(response["data"]["created_at"].as_u64().is_some());
+        // 默认只返回ID
+        let response = insert("POST /api/insert HTTP/1.1", r#"{"table": "users", "data": {"name": "carol"}}"#).await;
+        assert!(response["data"].get("created_at").is_none() && response["data"]["id"].is_string());
+        serving.abort();
+        let _ = serving.await;
+    }
+
+    /// 要求`X-Api-Key`，并为每个响应加上请求ID
+    struct ApiKey;
+
+    impl Middleware for ApiKey {
+        fn on_request(&self, request: &mut HttpRequest) -> Option<HttpReply> {
+            match request.header("x-api-key") {
+                Some("secret") => None,
+                _ => Some(HttpReply::from(ApiResponse::error("缺少API密钥".to_string())).with_status(401)),
+            }
+        }
+
+        fn on_response(&self, request: &HttpRequest, reply: &mut HttpReply) {
+            let id = request.header("x-request-id").unwrap_or("generated").to_string();
+            reply.set_header("X-Request-Id", id);
+        }
+    }
+
+    /// 把`/v2/`开头的路径改写为`/api/`
+    struct Rewrite;
+
+    impl Middleware for Rewrite {
+        fn on_request(&self, request: &mut HttpRequest) -> Option<HttpReply> {
+            if let Some(rest) = request.path.strip_prefix("/v2/") {
+                request.path = format!("/api/{}", rest);
+            }
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middleware() {
+        let (_dir, db) = open("middleware");
+        db.create_table("users").unwrap();
+        let server = DatabaseServer::new(db, 0).with_middleware(ApiKey).with_middleware(Rewrite);
+        let (address, serving) = serve_with(server);
+
+        let rejected = request(address, "GET /api/tables HTTP/1.1", "").await;
+        assert!(rejected.starts_with("HTTP/1.1 401"), "{}", rejected);
+        assert!(rejected.contains("X-Request-Id: generated"), "{}", rejected);
+        let listed = request(address, "GET /v2/tables HTTP/1.1\r\nX-Api-Key: secret\r\nX-Request-Id: r-1", "").await;
+        assert!(listed.starts_with("HTTP/1.1 200") && listed.contains("users"), "{}", listed);
+        assert!(listed.contains("X-Request-Id: r-1"), "{}", listed);
+        serving.abort();
+        let _ = serving.await;
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let record = Record::new(IndexMap::from([
+            ("ordered_at".to_string(), Value::Timestamp(datetime::parse("2024-05-01T12:00:00Z").unwrap())),
+            ("note".to_string(), Value::String("说\"你好\", 再见".to_string())),
+            ("raw".to_string(), Value::Bytes(vec![1, 2, 3])),
+        ]));
+        let columns = DatabaseServer::csv_columns(&[Arc::new(record.clone())]);
+        let line = DatabaseServer::csv_record(&record, &columns);
+        assert!(line.contains(",2024-05-01T12:00:00Z,") && line.trim_end().ends_with(",AQID"), "{}", line);
+
+        let mut parser = ImportParser {
+            columns: Some(["id", "created_at", "updated_at"].into_iter().map(String::from).chain(columns).collect()),
+            mapping: None,
+            types: BTreeMap::from([("ordered_at".to_string(), FieldType::DateTime)]),
+        };
+        let imported = parser.parse(line.trim_end().as_bytes()).unwrap().unwrap();
+        assert_eq!((&imported["ordered_at"], &imported["note"]), (&record.data["ordered_at"], &record.data["note"]));
+        assert_eq!(imported["raw"], Value::String("AQID".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_explain() {
+        let (_dir, db) = open("explain");
+        let db = Arc::new(db);
+        for n in 0..100 {
+            let data = IndexMap::from([("team".to_string(), Value::Int(n % 10)), ("age".to_string(), Value::Int(n))]);
+            db.insert("users", data).unwrap();
+        }
+        db.create_index("users", "team").unwrap();
+        let (address, serving) = serve(Arc::clone(&db));
+        let explain = |body: &'static str| async move { json_body(&request(address, "GET /api/find HTTP/1.1", body).await)["data"].clone() };
+
+        // 只返回执行计划，不执行查询
+        let scans = db.table_counters("users").unwrap().scans;
+        let plan = explain(r#"{"table": "users", "query": {"team": 3}, "order_by": [["age", "desc"]], "limit": 5, "explain": true}"#).await;
+        assert_eq!(plan["index"], "team");
+        assert_eq!((&plan["estimated_scanned"], &plan["total_records"]), (&10.into(), &100.into()));
+        assert_eq!(plan["sort"], serde_json::json!({"TopK": 5}));
+        assert_eq!(db.table_counters("users").unwrap().scans, scans);
+
+        let plan = explain(r#"{"table": "users", "query": {"age": {"$gte": 50}}, "explain": true}"#).await;
+        assert_eq!((&plan["index"], &plan["estimated_scanned"], &plan["sort"]), (&serde_json::Value::Null, &100.into(), &"None".into()));
+        let plan = explain(r#"{"table": "users", "query": {"team": 3}, "order_by": [["age", "asc"]], "index": false, "explain": true}"#).await;
+        assert_eq!((&plan["index"], &plan["sort"]), (&serde_json::Value::Null, &"InMemory".into()));
+        serving.abort();
+        let _ = serving.await;
+    }
+
+    /// 拆分分块传输的响应，返回响应头和各个分块
+    fn dechunk(response: &str) -> (&str, Vec<&str>) {
+        let (head, mut body) = response.split_once("\r\n\r\n").unwrap();
+        let mut chunks = Vec::new();
+        loop {
+            let (size, rest) = body.split_once("\r\n").unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+            if size == 0 {
+                return (head, chunks);
+            }
+            chunks.push(&rest[..size]);
+            body = rest[size..].strip_prefix("\r\n").unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streamed_find() {
+        let (_dir, db) = open("streamed");
+        let db = Arc::new(db);
+        for n in 0..120 {
+            db.insert("users", IndexMap::from([("n".to_string(), Value::Int(n))])).unwrap();
+        }
+        let (address, serving) = serve(Arc::clone(&db));
+
+        let response = request(
+            address,
+            "GET /api/find HTTP/1.1",
+            r#"{"table": "users", "query": {"n": {"$gte": 20}}, "order_by": [["n", "asc"]], "stream": true}"#,
+        )
+        .await;
+        let (head, chunks) = dechunk(&response);
+        assert!(head.contains("Transfer-Encoding: chunked") && head.contains("Content-Type: application/x-ndjson"), "{}", head);
+        assert!(head.contains("X-Total-Count: 100") && !head.contains("Content-Length"), "{}", head);
+        // 每条记录是单独的一个分块、一行JSON，按查询的顺序
+        assert_eq!(chunks.len(), 100);
+        for (n, chunk) in (20..).zip(&chunks) {
+            let record: serde_json::Value = serde_json::from_str(chunk.strip_suffix('\n').unwrap()).unwrap();
+            assert_eq!(record["data"]["n"], n);
+        }
+
+        let response = request(address, "GET /api/find HTTP/1.1", r#"{"table": "users", "limit": 10, "stream": true}"#).await;
+        let (head, chunks) = dechunk(&response);
+        assert!(head.contains("X-Next-Token: ") && head.contains("X-Total-Count: 120"), "{}", head);
+        assert_eq!(chunks.len(), 10);
+        // 空结果只有结束分块
+        let response = request(address, "GET /api/find HTTP/1.1", r#"{"table": "users", "query": {"n": -1}, "stream": true}"#).await;
+        assert!(dechunk(&response).1.is_empty());
+        serving.abort();
+        let _ = serving.await;
+    }
+
+    #[tokio::test]
+    async fn test_export_formats() {
+        let (_dir, db) = open("export");
+        let db = Arc::new(db);
+        for (name, age) in [("张三", 25), ("李, 四", 30), ("王五", 35)] {
+            let data = IndexMap::from([("name".to_string(), Value::String(name.to_string())), ("age".to_string(), Value::Int(age))]);
+            db.insert("users", data).unwrap();
+        }
+        let (address, serving) = serve(Arc::clone(&db));
+
+        // 默认导出为JSONL
+        let response = request(address, "GET /api/tables/users/export HTTP/1.1", "").await;
+        let (head, chunks) = dechunk(&response);
+        assert!(head.contains("Content-Type: application/x-ndjson"), "{}", head);
+        let mut ages: Vec<i64> = chunks
+            .iter()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["data"]["age"].as_i64().unwrap())
+            .collect();
+        ages.sort();
+        assert_eq!(ages, [25, 30, 35]);
+
+        // CSV先写表头，含逗号的字段加引号
+        let query = crate::http::percent_encode(r#"{"age": {"$gte": 30}}"#);
+        let response = request(address, &format!("GET /api/tables/users/export?format=csv&query={} HTTP/1.1", query), "").await;
+        let (head, chunks) = dechunk(&response);
+        assert!(head.contains("Content-Type: text/csv"), "{}", head);
+        let csv = chunks.concat();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "id,created_at,updated_at,name,age");
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().any(|line| line.ends_with(",\"李, 四\",30")), "{}", csv);
+        assert!(lines.iter().any(|line| line.ends_with(",王五,35")), "{}", csv);
+
+        for (path, message) in [
+            ("/api/tables/users/export?format=xml", "不支持的导出格式: xml"),
+            ("/api/tables/users/export?query=%7B", "查询条件无效"),
+        ] {
+            let response = json_body(&request(address, &format!("GET {} HTTP/1.1", path), "").await);
+            assert_eq!(response["success"], false);
+            assert!(response["error"].as_str().unwrap().contains(message), "{}", response);
+        }
+        serving.abort();
+        let _ = serving.await;
+    }
+
+    #[tokio::test]
+    async fn test_index_endpoints() {
+        let (_dir, db) = open("index_endpoints");
+        let db = Arc::new(db);
+        for n in 0..100 {
+            let data = IndexMap::from([
+                ("team".to_string(), Value::Int(n % 10)),
+                ("email".to_string(), Value::String(format!("u{}@example.com", n))),
+            ]);
+            db.insert("users", data).unwrap();
+        }
+        let (address, serving) = serve(Arc::clone(&db));
+        let call = |head: &'static str, body: &'static str| async move { json_body(&request(address, head, body).await) };
+        let fields = |indexes: serde_json::Value| -> Vec<(String, String)> {
+            indexes["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|index| (index["field"].as_str().unwrap().to_string(), index["kind"].as_str().unwrap().to_string()))
+                .collect()
+        };
+
+        let response = call("POST /api/tables/users/indexes HTTP/1.1", r#"{"field": "team"}"#).await;
+        assert_eq!(response["success"], true, "{}", response);
+        let response = call("POST /api/tables/users/indexes HTTP/1.1", r#"{"field": "email", "kind": "unique"}"#).await;
+        assert_eq!(response["success"], true, "{}", response);
+        let indexes = call("GET /api/tables/users/indexes HTTP/1.1", "").await;
+        let mut listed = fields(indexes);
+        listed.sort();
+        assert_eq!(listed, [("email".to_string(), "unique".to_string()), ("team".to_string(), "hash".to_string())]);
+        // 新建的索引立即用于查询，唯一索引拒绝重复的值
+        assert_eq!(db.explain("users", &Query::new().filter(Condition::eq("team", Value::Int(1)))).unwrap().index.as_deref(), Some("team"));
+        let duplicate = IndexMap::from([("email".to_string(), Value::String("u1@example.com".to_string()))]);
+        assert!(db.insert("users", duplicate).is_err());
+
+        for (head, body) in [
+            ("POST /api/tables/users/indexes HTTP/1.1", r#"{"field": "location", "kind": "geo", "filter": {"team": 1}}"#),
+            ("POST /api/tables/users/indexes HTTP/1.1", r#"{"kind": "hash"}"#),
+            ("POST /api/tables/missing/indexes HTTP/1.1", r#"{"field": "team"}"#),
+        ] {
+            assert_eq!(call(head, body).await["success"], false, "{}", body);
+        }
+
+        let response = call("DELETE /api/tables/users/indexes/team HTTP/1.1", "").await;
+        assert_eq!(response["success"], true, "{}", response);
+        let response = call("DELETE /api/tables/users/indexes/team HTTP/1.1", "").await;
+        assert_eq!((&response["success"], &response["error"]), (&false.into(), &"字段 team 上没有索引".into()));
+        let indexes = call("GET /api/tables/users/indexes HTTP/1.1", "").await;
+        assert_eq!(fields(indexes), [("email".to_string(), "unique".to_string())]);
+        assert_eq!(db.explain("users", &Query::new().filter(Condition::eq("team", Value::Int(1)))).unwrap().index, None);
+        serving.abort();
+        let _ = serving.await;
+    }
+
+    #[tokio::test]
+    async fn test_hot_backup_during_writes() {
+        let backups = temp_dir("hot_backups");
+        let (_dir, db) = open_with("hot_backup", Config {
+            backup_dir: Some(backups.path().to_string_lossy().into_owned()),
+            ..Config::default()
+        });
+        let db = Arc::new(db);
+        let balance = |n: i64| IndexMap::from([("balance".to_string(), Value::Int(n))]);
+        let checking = db.insert("checking", balance(1000)).unwrap();
+        let savings = db.insert("savings", balance(0)).unwrap();
+        let (address, serving) = serve(Arc::clone(&db));
+
+        // 每个事务从一张表转出1、转入另一张表并记一笔流水
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let writer = std::thread::spawn({
+            let (db, stop, checking, savings) = (Arc::clone(&db), Arc::clone(&stop), checking.clone(), savings.clone());
+            move || {
+                let mut moved = 0;
+                while !stop.load(std::sync::atomic::Ordering::Relaxed) && moved < 100_000 {
+                    moved += 1;
+                    let mut tx = crate::Transaction::new();
+                    tx.update("checking", &checking, balance(1000 - moved));
+                    tx.update("savings", &savings, balance(moved));
+                    tx.insert("ledger", IndexMap::from([("n".to_string(), Value::Int(moved))]));
+                    db.commit(tx).unwrap();
+                }
+                moved
+            }
+        });
+        while db.count("ledger").unwrap_or(0) == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // 每个备份都是所有表同一时刻的快照：两张表的余额和流水条数一致，且不早于上一个备份
+        let mut last = 0;
+        for _ in 0..5 {
+            let response = json_body(&request(address, "POST /api/admin/backup HTTP/1.1", "").await);
+            assert_eq!(response["success"], true, "{}", response);
+            let restored = temp_dir("hot_restore");
+            let config = Config {
+                allow_unsigned_backup: true,
+                ..config_in(&restored)
+            };
+            SimpleDB::restore(&config, Path::new(response["data"]["path"].as_str().unwrap())).unwrap();
+            let copy = SimpleDB::new(config).unwrap();
+            let moved = copy.count("ledger").unwrap() as i64;
+            assert_eq!(copy.find_by_id("checking", &checking).unwrap().unwrap().data, balance(1000 - moved));
+            assert_eq!(copy.find_by_id("savings", &savings).unwrap().unwrap().data, balance(moved));
+            assert!(moved >= last);
+            last = moved;
+        }
+        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        let moved = writer.join().unwrap();
+        assert!(last <= moved && db.count("ledger").unwrap() as i64 == moved);
+        serving.abort();
+        let _ = serving.await;
+    }
+
+    /// 订阅变更流，返回读完响应头的连接
+    async fn subscribe(address: SocketAddr, head: &str) -> BufReader<TcpStream> {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(format!("{}\r\n\r\n", head).as_bytes()).await.unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("HTTP/1.1 200"), "{}", line);
+        while !line.trim().is_empty() {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+        }
+        reader
+    }
+
+    /// 读取下一条SSE事件的类型、ID和数据，跳过注释行
+    async fn next_event(reader: &mut BufReader<TcpStream>) -> (String, Option<u64>, serde_json::Value) {
+        let (mut kind, mut id, mut data) = (String::new(), None, serde_json::Value::Null);
+        loop {
+            let mut line = String::new();
+            let read = tokio::time::timeout(Duration::from_secs(10), reader.read_line(&mut line)).await.unwrap().unwrap();
+            assert!(read > 0, "变更流已断开");
+            match line.trim_end().split_once(": ") {
+                Some(("event", value)) => kind = value.to_string(),
+                Some(("id", value)) => id = Some(value.parse().unwrap()),
+                Some(("data", value)) => data = serde_json::from_str(value).unwrap(),
+                _ if line.trim().is_empty() && !kind.is_empty() => return (kind, id, data),
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_change_stream() {
+        let (_dir, db) = open_with("stream", Config {
+            change_log_size: 3,
+            ..Config::default()
+        });
+        let db = Arc::new(db);
+        let user = |n: i64| IndexMap::from([("n".to_string(), Value::Int(n))]);
+        for n in 0..4 {
+            db.insert("users", user(n)).unwrap();
+        }
+        db.insert("orders", IndexMap::new()).unwrap();
+        let last = db.insert("users", user(4)).unwrap();
+        // 日志中只剩序号4到6的事件
+        let (address, serving) = serve(Arc::clone(&db));
+
+        // 从日志补发断点之后本表的事件，再接着推送新事件
+        let mut stream = subscribe(address, "GET /api/stream/users HTTP/1.1\r\nLast-Event-ID: 3").await;
+        let (kind, id, data) = next_event(&mut stream).await;
+        assert_eq!((kind.as_str(), id), ("insert", Some(4)));
+        assert_eq!(data["record"]["data"]["n"], 3);
+        assert_eq!(next_event(&mut stream).await.1, Some(6));
+        db.insert("orders", IndexMap::new()).unwrap();
+        db.update("users", &last, user(5)).unwrap();
+        db.delete("users", &last).unwrap();
+        let (kind, id, data) = next_event(&mut stream).await;
+        assert_eq!((kind.as_str(), id, &data["id"]), ("update", Some(8), &last.as_str().into()));
+        assert_eq!(data["record"]["data"]["n"], 5);
+        let (kind, id, data) = next_event(&mut stream).await;
+        assert_eq!((kind.as_str(), id, &data["record"]), ("delete", Some(9), &serde_json::Value::Null));
+
+        // 不带断点时不补发，只推送订阅之后的事件；服务器发出响应头后随即订阅
+        let mut live = subscribe(address, "GET /api/stream/users HTTP/1.1").await;
+        db.insert("users", user(6)).unwrap();
+        let (kind, id, data) = next_event(&mut live).await;
+        assert_eq!((kind.as_str(), id, &data["record"]["data"]["n"]), ("insert", Some(10), &6.into()));
+
+        // 断点早于日志、或晚于当前序号（如服务重启后）时先发送resync，再补发日志中仍有的事件
+        let current = db.change_feed().since(0, usize::MAX).0.last().unwrap().seq;
+        for after in [1, current + 100] {
+            let mut stream = subscribe(address, &format!("GET /api/stream/users HTTP/1.1\r\nLast-Event-ID: {}", after)).await;
+            assert_eq!(next_event(&mut stream).await, ("resync".to_string(), None, serde_json::json!({})));
+            if after == 1 {
+                assert!(next_event(&mut stream).await.1.unwrap() > current - 3);
+            }
+        }
+        let mut resumed = subscribe(address, &format!("GET /api/stream/users?last_event_id={} HTTP/1.1", current)).await;
+        db.insert("users", user(0)).unwrap();
+        assert_eq!(next_event(&mut resumed).await.1, Some(current + 1));
+
+        let invalid = request(address, "GET /api/stream/users HTTP/1.1\r\nLast-Event-ID: abc", "").await;
+        assert!(invalid.contains("无效的Last-Event-ID"), "{}", invalid);
+        let missing = request(address, "GET /api/stream/missing HTTP/1.1", "").await;
+        assert!(missing.contains("订阅失败"), "{}", missing);
+        serving.abort();
+        let _ = serving.await;
+    }
+
+    #[test]
+    fn test_sort_stage_keeps_key_order() {
+        let stage = |sort: serde_json::Value| {
+            DatabaseServer::build_pipeline(&[serde_json::json!({ "$sort": sort })], &BTreeMap::new()).unwrap()
+        };
+        let expected = Pipeline::new().stage(Stage::Sort(vec![
+            ("total".to_string(), SortOrder::Desc),
+            ("name".to_string(), SortOrder::Asc),
+        ]));
+        assert_eq!(stage(serde_json::json!({"total": -1, "name": 1})), expected);
+        assert_eq!(stage(serde_json::json!([["total", -1], ["name", 1]])), expected);
+    }
+
+    #[test]
+    fn test_vector_json_round_trip() {
+        let vector = Value::Vector(vec![0.5, -1.0]);
+        let json = DatabaseServer::value_to_json(&vector);
+        assert_eq!(json, serde_json::json!({"$vector": [0.5, -1.0]}));
+        assert_eq!(DatabaseServer::convert_json_value(json), vector);
+    }
+
+    #[test]
+    fn test_update_ops_keep_request_order() {
+        let ops = |body: &str| {
+            let data: IndexMap<String, serde_json::Value> = serde_json::from_str(body).unwrap();
+            DatabaseServer::parse_update_ops(&data, &BTreeMap::new()).unwrap().unwrap()
+        };
+        let set = UpdateOp::Set("a".to_string(), Value::Int(1));
+        let unset = UpdateOp::Unset("a".to_string());
+        assert_eq!(ops(r#"{"$unset": {"a": true}, "$set": {"a": 1}}"#), vec![unset.clone(), set.clone()]);
+        assert_eq!(ops(r#"{"$set": {"a": 1}, "$unset": {"a": true}}"#), vec![set, unset]);
+        // 字段按名称排序后会颠倒的顺序也保持不变
+        assert_eq!(
+            ops(r#"{"$push": {"z": 1}, "$pull": {"y": 2}}"#),
+            vec![UpdateOp::Push("z".to_string(), Value::Int(1)), UpdateOp::Pull("y".to_string(), Value::Int(2))]
+        );
+    }
+
+    #[test]
+    fn test_declared_schema() {
+        let (_dir, db) = open("declared");
+        db.insert("orders", IndexMap::from([("qty".to_string(), Value::Int(2))])).unwrap();
+        let schema = DatabaseServer::table_schema(&db, "orders", None).unwrap();
+        assert_eq!(schema["source"], "sampled");
+        assert_eq!(schema["fields"][0]["name"], "qty");
+
+        db.set_field_type("orders", "qty", Some(FieldType::Int)).unwrap();
+        db.set_field_type("orders", "note", Some(FieldType::String)).unwrap();
+        db.set_table_mode("orders", TableMode::Strict).unwrap();
+        let schema = DatabaseServer::table_schema(&db, "orders", None).unwrap();
+        assert_eq!(schema["source"], "declared");
+        assert_eq!(schema["total"], 1);
+        assert_eq!(schema["fields"][0], serde_json::json!({"name": "note", "type": "string", "nullable": true}));
+        assert_eq!(schema["fields"][1]["type"], "int");
+        // 指定sample时仍然抽样
+        assert_eq!(DatabaseServer::table_schema(&db, "orders", Some(10)).unwrap()["source"], "sampled");
+
+        drop(db);
+    }
+}
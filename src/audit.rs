@@ -0,0 +1,239 @@
+use serde::Serialize;
+use indexmap::IndexMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::database::SimpleDB;
+use crate::error::Result;
+use crate::lock::Recover;
+use crate::storage::Value;
+
+/// 记录API访问的表
+pub const ACCESS_LOG_TABLE: &str = "__access_log";
+
+/// 一次API请求的访问记录
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccessEntry {
+    /// 请求开始的Unix毫秒时间
+    pub time: i64,
+    /// 客户端地址
+    pub client: Option<String>,
+    /// 令牌中的`sub`声明，没有配置令牌或令牌无效时为None
+    pub caller: Option<String>,
+    pub method: String,
+    pub path: String,
+    /// 请求访问的表，无法确定时为None
+    pub table: Option<String>,
+    pub status: u16,
+    /// `ok`、`denied`（401/403）或`error`
+    pub outcome: &'static str,
+    /// 处理耗时（微秒）
+    pub latency_us: u64,
+}
+
+impl AccessEntry {
+    /// 按状态码归类的结果
+    pub fn outcome_of(status: u16) -> &'static str {
+        match status {
+            401 | 403 => "denied",
+            s if s >= 400 => "error",
+            _ => "ok",
+        }
+    }
+
+    pub fn latency(&self) -> Duration {
+        Duration::from_micros(self.latency_us)
+    }
+
+    fn to_record(&self) -> IndexMap<String, Value> {
+        let optional = |value: &Option<String>| value.clone().map_or(Value::Null, Value::String);
+        IndexMap::from([
+            ("time".to_string(), Value::Int(self.time)),
+            ("client".to_string(), optional(&self.client)),
+            ("caller".to_string(), optional(&self.caller)),
+            ("method".to_string(), Value::String(self.method.clone())),
+            ("path".to_string(), Value::String(self.path.clone())),
+            ("table".to_string(), optional(&self.table)),
+            ("status".to_string(), Value::Int(self.status.into())),
+            ("outcome".to_string(), Value::String(self.outcome.to_string())),
+            ("latency_us".to_string(), Value::Int(i64::try_from(self.latency_us).unwrap_or(i64::MAX))),
+        ])
+    }
+}
+
+/// 按大小轮转的JSON Lines日志文件：写满后`access.log`改名为`access.log.1`，原来的`.1`改名为`.2`，依此类推
+#[derive(Debug)]
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    /// 保留的轮转文件个数，不含当前文件
+    keep: usize,
+    file: Option<File>,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.file = None;
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                if self.rotated(n).exists() {
+                    std::fs::rename(self.rotated(n), self.rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn append(&mut self, line: &[u8]) -> Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+                self.size = file.metadata()?.len();
+                self.file.insert(file)
+            }
+        };
+        file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// API访问审计：把请求记录写入轮转的日志文件和可查询的`__access_log`表
+///
+/// 成功的请求按`sample_rate`抽样记录，被拒绝和失败的请求总是记录。写审计记录失败不影响请求。
+#[derive(Debug)]
+pub struct AccessLog {
+    file: Option<Mutex<RotatingFile>>,
+    table: bool,
+    sample_rate: f64,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccessLog {
+    /// 记录全部请求到`__access_log`表
+    pub fn new() -> Self {
+        Self {
+            file: None,
+            table: true,
+            sample_rate: 1.0,
+        }
+    }
+
+    /// 同时写入日志文件，超过`max_size`字节时轮转，保留`keep`个旧文件
+    pub fn with_file(mut self, path: impl AsRef<Path>, max_size: u64, keep: usize) -> Self {
+        self.file = Some(Mutex::new(RotatingFile {
+            path: path.as_ref().to_path_buf(),
+            max_size,
+            keep,
+            file: None,
+            size: 0,
+        }));
+        self
+    }
+
+    /// 是否写入`__access_log`表
+    pub fn with_table(mut self, table: bool) -> Self {
+        self.table = table;
+        self
+    }
+
+    /// 成功请求的抽样比例，0到1之间
+    pub fn with_sample_rate(mut self, rate: f64) -> Self {
+        self.sample_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// 该请求是否需要记录
+    fn sampled(&self, entry: &AccessEntry) -> bool {
+        entry.outcome != "ok" || self.sample_rate >= 1.0 || rand::random::<f64>() < self.sample_rate
+    }
+
+    /// 记录一次请求，返回是否被抽中
+    pub fn record(&self, db: &SimpleDB, entry: &AccessEntry) -> Result<bool> {
+        if !self.sampled(entry) {
+            return Ok(false);
+        }
+        if let Some(file) = &self.file {
+            let mut line = serde_json::to_vec(entry).unwrap_or_default();
+            line.push(b'\n');
+            file.lock().recover().append(&line)?;
+        }
+        if self.table {
+            db.insert(ACCESS_LOG_TABLE, entry.to_record())?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+    use crate::Config;
+
+    #[test]
+    fn test_access_log() {
+        let dir = temp_dir("audit");
+        let db = SimpleDB::new(Config {
+            data_dir: dir.path().join("data").to_string_lossy().into_owned(),
+            ..Config::default()
+        })
+        .unwrap();
+        let log_path = dir.path().join("access.log");
+        let log = AccessLog::new().with_file(&log_path, 400, 2).with_sample_rate(0.0);
+        let entry = |status| AccessEntry {
+            time: 1_700_000_000_000,
+            client: Some("127.0.0.1".to_string()),
+            caller: Some("alice".to_string()),
+            method: "GET".to_string(),
+            path: "/api/find".to_string(),
+            table: Some("users".to_string()),
+            status,
+            outcome: AccessEntry::outcome_of(status),
+            latency_us: 120,
+        };
+
+        // 抽样比例为0时只记录被拒绝和失败的请求
+        assert!(!log.record(&db, &entry(200)).unwrap());
+        for _ in 0..4 {
+            assert!(log.record(&db, &entry(403)).unwrap());
+        }
+        let denied = db.find_all(ACCESS_LOG_TABLE).unwrap();
+        assert_eq!(denied.len(), 4);
+        assert_eq!(denied[0].data["caller"], Value::String("alice".to_string()));
+        assert_eq!(denied[0].data["outcome"], Value::String("denied".to_string()));
+
+        // 每行约200字节，超过400字节时轮转
+        assert!(dir.path().join("access.log.1").exists());
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&log_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.last().unwrap()["status"], 403);
+        drop(db);
+    }
+}
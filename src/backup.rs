@@ -0,0 +1,133 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey, SIGNATURE_LENGTH};
+use std::path::Path;
+
+use crate::error::{DatabaseError, Result};
+
+/// 备份文件开头的魔数
+const MAGIC: &[u8; 8] = b"SDBBAK01";
+
+/// 标志位：文件末尾带有Ed25519签名
+const FLAG_SIGNED: u8 = 1;
+
+/// 备份中的一个文件：(相对数据目录的文件名, 内容)
+pub type ArchiveFile = (String, Vec<u8>);
+
+/// 由32字节种子创建备份签名密钥
+pub fn signing_key(seed: &[u8]) -> Result<SigningKey> {
+    let seed: [u8; 32] = seed
+        .try_into()
+        .map_err(|_| DatabaseError::Config("备份签名密钥必须为32字节".to_string()))?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+/// 由32字节公钥创建恢复时校验备份签名的公钥
+pub fn verifying_key(bytes: &[u8]) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| DatabaseError::Config("备份校验公钥必须为32字节".to_string()))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| DatabaseError::Config(format!("无效的备份校验公钥: {}", e)))
+}
+
+/// 签名密钥种子对应的公钥，恢复时只需要公钥
+pub fn public_key(seed: &[u8]) -> Result<Vec<u8>> {
+    Ok(signing_key(seed)?.verifying_key().to_bytes().to_vec())
+}
+
+/// 生成新的备份签名密钥种子
+pub fn generate_signing_key() -> Vec<u8> {
+    use rand::RngCore;
+    let mut seed = vec![0u8; 32];
+    rand::thread_rng().fill_bytes(&mut seed);
+    seed
+}
+
+/// 编码备份内容：魔数、标志、文件列表，配置了密钥时在末尾附上对前面所有内容的签名
+pub fn encode_archive(files: &[ArchiveFile], key: Option<&SigningKey>) -> Result<Vec<u8>> {
+    let mut content = MAGIC.to_vec();
+    content.push(if key.is_some() { FLAG_SIGNED } else { 0 });
+    content.extend(bincode::serialize(files)?);
+    if let Some(key) = key {
+        let signature = key.sign(&content);
+        content.extend_from_slice(&signature.to_bytes());
+    }
+    Ok(content)
+}
+
+/// 写入备份文件
+pub fn write_archive(path: &Path, files: &[ArchiveFile], key: Option<&SigningKey>) -> Result<()> {
+    let content = encode_archive(files, key)?;
+    // 先写临时文件再改名，中途失败不会留下半个备份
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// 读取并校验备份文件
+///
+/// 提供了公钥时备份必须带有有效签名；未提供公钥时拒绝已签名的备份，避免跳过校验；
+/// 未签名的备份只有`allow_unsigned`时才接受，不会因为忘记配置公钥而恢复被篡改的备份。
+pub fn read_archive(path: &Path, key: Option<&VerifyingKey>, allow_unsigned: bool) -> Result<Vec<ArchiveFile>> {
+    let content = std::fs::read(path)?;
+    let invalid = |reason: &str| DatabaseError::DataFormat(format!("无效的备份文件 {}: {}", path.display(), reason));
+    if content.len() < MAGIC.len() + 1 || &content[..MAGIC.len()] != MAGIC {
+        return Err(invalid("文件头不正确"));
+    }
+    let signed = content[MAGIC.len()] & FLAG_SIGNED != 0;
+
+    let body = match (signed, key) {
+        (true, Some(key)) => {
+            if content.len() < MAGIC.len() + 1 + SIGNATURE_LENGTH {
+                return Err(invalid("文件被截断"));
+            }
+            let (body, signature) = content.split_at(content.len() - SIGNATURE_LENGTH);
+            let signature = Signature::from_slice(signature).map_err(|_| invalid("签名格式错误"))?;
+            key.verify(body, &signature)
+                .map_err(|_| invalid("签名校验失败，备份可能被篡改或截断"))?;
+            body
+        }
+        (false, Some(_)) => return Err(invalid("备份未签名，但配置了签名密钥")),
+        (true, None) => return Err(invalid("备份已签名，需要配置签名密钥才能校验")),
+        (false, None) if allow_unsigned => &content[..],
+        (false, None) => return Err(invalid("备份未签名，需要配置校验公钥或明确允许恢复未签名的备份")),
+    };
+    bincode::deserialize(&body[MAGIC.len() + 1..]).map_err(|e| invalid(&e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_rejects_tampered_archive() {
+        let dir = temp_dir("backup");
+        let path = dir.path().join("backup.bak");
+        let key = signing_key(&generate_signing_key()).unwrap();
+        let files = vec![("users.db".to_string(), b"records".to_vec())];
+
+        write_archive(&path, &files, Some(&key)).unwrap();
+        let public = verifying_key(&public_key(key.as_bytes()).unwrap()).unwrap();
+        assert_eq!(read_archive(&path, Some(&public), false).unwrap(), files);
+        assert!(read_archive(&path, None, true).is_err());
+        let other = signing_key(&generate_signing_key()).unwrap();
+        assert!(read_archive(&path, Some(&other.verifying_key()), false).is_err());
+
+        // 修改内容或截断都会使签名失效
+        let mut content = std::fs::read(&path).unwrap();
+        content[12] ^= 1;
+        std::fs::write(&path, &content).unwrap();
+        assert!(read_archive(&path, Some(&public), false).is_err());
+        content[12] ^= 1;
+        std::fs::write(&path, &content[..content.len() - 10]).unwrap();
+        assert!(read_archive(&path, Some(&public), false).is_err());
+
+        // 未签名的备份只有明确允许时才能恢复，配置了公钥时总是拒绝
+        write_archive(&path, &files, None).unwrap();
+        assert!(read_archive(&path, None, false).is_err());
+        assert!(read_archive(&path, Some(&public), true).is_err());
+        assert_eq!(read_archive(&path, None, true).unwrap(), files);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
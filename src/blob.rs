@@ -0,0 +1,236 @@
+use serde::Serialize;
+use indexmap::IndexMap;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::crypto::Crypto;
+use crate::error::Result;
+use crate::lock::Recover;
+use crate::siv::Siv;
+use crate::storage::{Record, Value};
+
+/// 共享blob文件的扩展名，文件名为内容的哈希
+pub const BLOB_EXTENSION: &str = "blob";
+
+/// 派生blob哈希密钥的域
+const BLOB_DOMAIN: &[u8] = b"simpledb-blob";
+
+/// blob存储的用量，由`SimpleDB::blob_usage`生成
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct BlobUsage {
+    /// 被引用的不同内容数，即磁盘上的blob文件数
+    pub blobs: usize,
+    /// 所有表中的引用总数
+    pub references: usize,
+    /// 每份内容只计一次的字节数
+    pub bytes: u64,
+}
+
+#[derive(Debug, Default)]
+struct BlobEntry {
+    references: usize,
+    size: u64,
+    /// 读取过的内容，引用数降为0时释放
+    data: Option<Arc<Vec<u8>>>,
+}
+
+/// 数据库中所有表共享的内容寻址blob存储：相同的较大字节串只在数据目录中保存一份`<哈希>.blob`
+///
+/// 表中保存`Value::Blob`引用，读取时换回字节串。引用数按内存中的记录统计，打开数据库时由加载的表重新计算；
+/// blob文件在写入引用它的表文件之前写出，不再被引用的文件在所有表保存之后（`save_all`）才删除，
+/// 崩溃时只会留下多余的文件。配置了主密钥时blob文件加密，文件名为带密钥的哈希，不能由内容推测。
+#[derive(Debug)]
+pub(crate) struct BlobStore {
+    dir: PathBuf,
+    crypto: Option<Crypto>,
+    /// 哈希前缀的密钥，没有主密钥时为空
+    salt: Vec<u8>,
+    /// 不小于该字节数的字节串存入blob存储，None表示不再存入新的blob
+    threshold: Option<usize>,
+    entries: Mutex<HashMap<String, BlobEntry>>,
+}
+
+impl BlobStore {
+    pub(crate) fn new(dir: &Path, crypto: Option<Crypto>, siv: Option<&Siv>, threshold: Option<usize>) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            crypto,
+            salt: siv.map(|siv| siv.mac(BLOB_DOMAIN, b"").to_vec()).unwrap_or_default(),
+            threshold,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// blob文件是否加密
+    pub(crate) fn is_encrypted(&self) -> bool {
+        self.crypto.is_some()
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", id, BLOB_EXTENSION))
+    }
+
+    /// 把较大的字节串换成blob引用并计数，必要时写出blob文件；结果应存入表中
+    ///
+    /// 持有锁写出文件，不会与`collect_garbage`交错。写不出blob文件的值保持原样，仍保存在表文件中。
+    pub(crate) fn store(&self, mut data: IndexMap<String, Value>) -> IndexMap<String, Value> {
+        let Some(threshold) = self.threshold else {
+            return data;
+        };
+        let mut entries = self.entries.lock().recover();
+        for value in data.values_mut() {
+            let Value::Bytes(bytes) = value else {
+                continue;
+            };
+            if bytes.len() < threshold {
+                continue;
+            }
+            let mut hasher = Sha256::new();
+            hasher.update(&self.salt);
+            hasher.update(&bytes);
+            let id = hex::encode(hasher.finalize());
+            let path = self.path(&id);
+            if !entries.contains_key(&id) && !path.exists() && self.write(&path, bytes).is_err() {
+                continue;
+            }
+            let entry = entries.entry(id.clone()).or_default();
+            entry.references += 1;
+            entry.size = bytes.len() as u64;
+            *value = Value::Blob {
+                id,
+                size: bytes.len() as u64,
+            };
+        }
+        data
+    }
+
+    fn write(&self, path: &Path, bytes: &[u8]) -> Result<()> {
+        let content = match &self.crypto {
+            Some(crypto) => crypto.encrypt(bytes)?,
+            None => bytes.to_vec(),
+        };
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// 记录中的blob引用
+    fn references(record: &Record) -> impl Iterator<Item = (&str, u64)> {
+        record.data.values().filter_map(|value| match value {
+            Value::Blob { id, size } => Some((id.as_str(), *size)),
+            _ => None,
+        })
+    }
+
+    /// 为从表文件加载的记录中的引用计数
+    pub(crate) fn retain(&self, record: &Record) {
+        let mut entries = self.entries.lock().recover();
+        for (id, size) in Self::references(record) {
+            let entry = entries.entry(id.to_string()).or_default();
+            entry.references += 1;
+            entry.size = size;
+        }
+    }
+
+    /// 记录移出表时减少引用数
+    pub(crate) fn release(&self, record: &Record) {
+        let mut entries = self.entries.lock().recover();
+        for (id, _) in Self::references(record) {
+            if let Some(entry) = entries.get_mut(id) {
+                entry.references = entry.references.saturating_sub(1);
+                if entry.references == 0 {
+                    entries.remove(id);
+                }
+            }
+        }
+    }
+
+    fn read(&self, id: &str) -> Option<Arc<Vec<u8>>> {
+        let mut entries = self.entries.lock().recover();
+        let entry = entries.get_mut(id)?;
+        if let Some(data) = &entry.data {
+            return Some(Arc::clone(data));
+        }
+        let content = std::fs::read(self.path(id)).ok()?;
+        let content = match &self.crypto {
+            Some(crypto) => crypto.decrypt(&content).ok()?,
+            None => content,
+        };
+        Some(Arc::clone(entry.data.insert(Arc::new(content))))
+    }
+
+    /// 把记录中的blob引用换回字节串，没有引用时不复制；blob文件丢失的引用保持原样
+    pub(crate) fn resolve<'a>(&self, record: Cow<'a, Record>) -> Cow<'a, Record> {
+        if Self::references(&record).next().is_none() {
+            return record;
+        }
+        let mut record = record.into_owned();
+        for value in record.data.values_mut() {
+            if let Value::Blob { id, .. } = value {
+                if let Some(data) = self.read(id) {
+                    *value = Value::Bytes(data.as_ref().clone());
+                }
+            }
+        }
+        Cow::Owned(record)
+    }
+
+    pub(crate) fn usage(&self) -> BlobUsage {
+        let entries = self.entries.lock().recover();
+        BlobUsage {
+            blobs: entries.len(),
+            references: entries.values().map(|entry| entry.references).sum(),
+            bytes: entries.values().map(|entry| entry.size).sum(),
+        }
+    }
+
+    /// 删除不再被任何记录引用的blob文件，返回删除的文件数；只应在所有表都已保存时调用
+    pub(crate) fn collect_garbage(&self) -> Result<usize> {
+        let entries = self.entries.lock().recover();
+        let mut removed = 0;
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != BLOB_EXTENSION) {
+                continue;
+            }
+            let id = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+            if !entries.contains_key(id) {
+                std::fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_blob_references() {
+        let dir = temp_dir("blob");
+        let store = BlobStore::new(dir.path(), None, None, Some(16));
+        let attachment = Value::Bytes(vec![7; 100]);
+        let records: Vec<Record> = (0..3)
+            .map(|_| {
+                let data = IndexMap::from([("file".to_string(), attachment.clone())]);
+                Record::new(store.store(data))
+            })
+            .collect();
+        assert!(matches!(records[0].data["file"], Value::Blob { size: 100, .. }));
+        assert_eq!(store.usage(), BlobUsage { blobs: 1, references: 3, bytes: 100 });
+        assert_eq!(store.resolve(Cow::Borrowed(&records[0])).data["file"], attachment);
+
+        store.release(&records[0]);
+        store.release(&records[1]);
+        assert_eq!(store.collect_garbage().unwrap(), 0);
+        store.release(&records[2]);
+        assert_eq!(store.collect_garbage().unwrap(), 1);
+    }
+}
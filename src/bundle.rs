@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::backup::ArchiveFile;
+use crate::error::{DatabaseError, Result};
+
+/// 单文件数据库开头的魔数
+pub const MAGIC: &[u8; 8] = b"SDBUNDLE";
+
+/// 当前的单文件格式版本
+const VERSION: u16 = 1;
+
+/// 页大小：文件头占第一页，每个段和末尾的目录都从页边界开始
+pub const PAGE_SIZE: u64 = 4096;
+
+/// 文件头：魔数、版本（u16）、页大小（u32）、目录偏移（u64）、目录长度（u64）、目录的CRC32（u32），均为小端
+const HEADER_LEN: usize = 34;
+
+/// 单文件中的一个段，保存数据目录中的一个文件
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    pub name: String,
+    /// 段在文件中的起始偏移，是页大小的整数倍
+    pub offset: u64,
+    pub len: u64,
+    pub crc32: u32,
+}
+
+fn pad_to_page(content: &mut Vec<u8>) {
+    let padded = (content.len() as u64).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    content.resize(padded as usize, 0);
+}
+
+/// 把文件写成单文件数据库：文件头之后每个文件占连续的若干页，最后是段目录
+///
+/// 先写临时文件再改名，写到一半时原来的文件保持完整。
+pub fn write_bundle(path: &Path, files: &[ArchiveFile]) -> Result<()> {
+    let mut content = vec![0u8; PAGE_SIZE as usize];
+    let mut segments = Vec::with_capacity(files.len());
+    for (name, data) in files {
+        segments.push(Segment {
+            name: name.clone(),
+            offset: content.len() as u64,
+            len: data.len() as u64,
+            crc32: crc32fast::hash(data),
+        });
+        content.extend_from_slice(data);
+        pad_to_page(&mut content);
+    }
+
+    let directory = bincode::serialize(&segments)?;
+    let directory_offset = content.len() as u64;
+    content.extend_from_slice(&directory);
+
+    let header = &mut content[..HEADER_LEN];
+    header[..8].copy_from_slice(MAGIC);
+    header[8..10].copy_from_slice(&VERSION.to_le_bytes());
+    header[10..14].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+    header[14..22].copy_from_slice(&directory_offset.to_le_bytes());
+    header[22..30].copy_from_slice(&(directory.len() as u64).to_le_bytes());
+    header[30..34].copy_from_slice(&crc32fast::hash(&directory).to_le_bytes());
+
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+fn format_error(message: &str) -> DatabaseError {
+    DatabaseError::DataFormat(format!("单文件数据库无效: {}", message))
+}
+
+/// 读取段目录，不读取段的内容
+pub fn read_segments(path: &Path) -> Result<Vec<Segment>> {
+    read_directory(&mut File::open(path)?)
+}
+
+fn read_directory(file: &mut File) -> Result<Vec<Segment>> {
+    let mut header = [0u8; HEADER_LEN];
+    file.read_exact(&mut header).map_err(|_| format_error("文件过短"))?;
+    if &header[..8] != MAGIC {
+        return Err(format_error("缺少文件头"));
+    }
+    let version = u16::from_le_bytes([header[8], header[9]]);
+    if version != VERSION {
+        return Err(format_error(&format!("不支持的版本 {}", version)));
+    }
+    let le_u64 = |range: std::ops::Range<usize>| u64::from_le_bytes(header[range].try_into().unwrap());
+    let (offset, len) = (le_u64(14..22), le_u64(22..30));
+    let crc = u32::from_le_bytes(header[30..34].try_into().unwrap());
+
+    let size = file.metadata()?.len();
+    if offset.checked_add(len).is_none_or(|end| end > size) {
+        return Err(format_error("目录超出文件末尾"));
+    }
+    let mut directory = vec![0u8; len as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut directory)?;
+    if crc32fast::hash(&directory) != crc {
+        return Err(format_error("目录校验值不符"));
+    }
+    Ok(bincode::deserialize(&directory)?)
+}
+
+/// 读取单文件数据库中的所有文件，逐段校验CRC32
+pub fn read_bundle(path: &Path) -> Result<Vec<ArchiveFile>> {
+    let mut file = File::open(path)?;
+    let segments = read_directory(&mut file)?;
+    let size = file.metadata()?.len();
+    let mut files = Vec::with_capacity(segments.len());
+    for segment in segments {
+        if segment.offset.checked_add(segment.len).is_none_or(|end| end > size) {
+            return Err(format_error(&format!("段 {} 超出文件末尾", segment.name)));
+        }
+        let mut data = vec![0u8; segment.len as usize];
+        file.seek(SeekFrom::Start(segment.offset))?;
+        file.read_exact(&mut data)?;
+        if crc32fast::hash(&data) != segment.crc32 {
+            return Err(format_error(&format!("段 {} 校验值不符", segment.name)));
+        }
+        files.push((segment.name, data));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_bundle_round_trip() {
+        let dir = temp_dir("bundle");
+        let path = dir.path().join("bundle.sdb");
+        let files = vec![
+            ("MANIFEST".to_string(), vec![1, 2, 3]),
+            ("empty.db".to_string(), Vec::new()),
+            ("users.db".to_string(), vec![7; PAGE_SIZE as usize + 1]),
+        ];
+        write_bundle(&path, &files).unwrap();
+
+        let segments = read_segments(&path).unwrap();
+        assert!(segments.iter().all(|s| s.offset % PAGE_SIZE == 0));
+        assert_eq!(segments[2].offset, 2 * PAGE_SIZE);
+        assert_eq!(read_bundle(&path).unwrap(), files);
+
+        // 段内容被改动时读取失败
+        let mut content = std::fs::read(&path).unwrap();
+        content[PAGE_SIZE as usize] ^= 1;
+        std::fs::write(&path, content).unwrap();
+        assert!(read_bundle(&path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::query::Query;
+
+/// 查询结果缓存，保存查询命中的记录ID
+///
+/// 表的任何写入都会清空整张表的缓存，因此缓存中的ID总是与当前数据一致。
+#[derive(Debug)]
+pub struct QueryCache {
+    capacity: usize,
+    entries: HashMap<String, Vec<String>>,
+    order: VecDeque<String>,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// 生成规范化的缓存键：条件之间是逻辑与，与书写顺序无关
+    pub fn key(query: &Query) -> String {
+        let mut conditions: Vec<String> = query.conditions.iter().map(|c| format!("{:?}", c)).collect();
+        conditions.sort();
+        format!(
+            "{:?}|{:?}|{}|{:?}|{:?}",
+            conditions, query.order_by, query.offset, query.limit, query.after
+        )
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<&Vec<String>> {
+        if self.entries.contains_key(key) {
+            // 命中的键移到队尾，淘汰时从队首开始
+            self.order.retain(|k| k != key);
+            self.order.push_back(key.to_string());
+        }
+        self.entries.get(key)
+    }
+
+    pub fn put(&mut self, key: String, ids: Vec<String>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.insert(key.clone(), ids).is_none() {
+            self.order.push_back(key);
+        }
+        while self.entries.len() > self.capacity {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    self.entries.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
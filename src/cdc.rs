@@ -0,0 +1,915 @@
+//! 变更数据捕获（CDC）：把变更流中的每个事件发布到Kafka或NATS，下游管道可以像消费其他数据源一样消费
+//!
+//! 后台线程按序号读取变更日志（`Config::change_log_size`），按表发布到各自的主题，
+//! 消息为与SSE相同的JSON事件，Kafka消息的键为记录ID。一批事件被服务器确认后才把最后的序号持久地写入数据目录的`CDC`文件，
+//! 发布失败时退避重试同一批，因此每个事件至少发布一次，消费者应按`seq`去重。
+//!
+//! 启动后变更日志持久化到数据目录的`CHANGES`文件，表文件保存之前先fsync其中的事件，
+//! 未确认的事件在确认前不移出日志，发布中断再久也不会丢失。重新打开数据库时从该文件恢复未确认的事件和序号，
+//! 序号接着上次递增，重启后从上次确认的序号之后继续发布。崩溃前未保存的修改的事件也可能被发布。
+//! 启动前已被移出变更日志的事件只能跳过，计入`lost`并记在`last_error`中。
+
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::api::DatabaseServer;
+use crate::changes::{ChangeEvent, ChangeFeed};
+use crate::crypto::Crypto;
+use crate::database::SimpleDB;
+use crate::error::{DatabaseError, Result};
+use crate::lock::Recover;
+use crate::statefile;
+
+/// 保存已确认序号的文件名
+pub const CDC_FILE: &str = "CDC";
+
+/// `CDC`文件开头的魔数
+pub const CDC_MAGIC: &[u8; 8] = b"SDBCDCO1";
+
+/// 连接和读写超时
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 发布失败后的最长重试间隔
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// 发布的目标
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CdcTarget {
+    /// Kafka代理地址（`主机:端口`），事件写入每个主题的0号分区，保持表内的顺序
+    Kafka { brokers: Vec<String> },
+    /// NATS服务器地址（`主机:端口`），主题即NATS的subject
+    Nats { address: String },
+}
+
+/// CDC配置
+#[derive(Debug, Clone)]
+pub struct CdcConfig {
+    pub target: CdcTarget,
+    /// 主题名模板，`{table}`替换为表名
+    pub topic: String,
+    /// 按表指定的主题，优先于模板
+    pub topics: HashMap<String, String>,
+    /// 一次最多发布的事件数
+    pub batch_size: usize,
+    /// 没有新事件时检查变更日志的间隔
+    pub interval: Duration,
+}
+
+impl CdcConfig {
+    /// 发布到`target`，主题为`simpledb.<表名>`
+    pub fn new(target: CdcTarget) -> Self {
+        Self {
+            target,
+            topic: "simpledb.{table}".to_string(),
+            topics: HashMap::new(),
+            batch_size: 500,
+            interval: Duration::from_millis(100),
+        }
+    }
+
+    /// 表的事件发布到的主题
+    pub fn topic_of(&self, table: &str) -> String {
+        match self.topics.get(table) {
+            Some(topic) => topic.clone(),
+            None => self.topic.replace("{table}", table),
+        }
+    }
+}
+
+/// 发布进度，由`CdcSink::status`生成
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CdcStatus {
+    /// 已被服务器确认的最后一个事件的序号
+    pub offset: u64,
+    /// 本次启动以来发布的事件数
+    pub published: u64,
+    /// 发布前已被移出变更日志而跳过的事件数
+    pub lost: u64,
+    /// 最近一次发布失败或跳过事件的原因，之后的一批发布成功时清除
+    pub last_error: Option<String>,
+}
+
+/// 待发布的一条消息
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Message {
+    topic: String,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+trait Publisher: Send {
+    /// 发布一批消息，返回时服务器已确认全部消息
+    fn publish(&mut self, messages: &[Message]) -> Result<()>;
+}
+
+fn publish_error(message: impl Into<String>) -> DatabaseError {
+    DatabaseError::Publish(message.into())
+}
+
+fn connect(address: &str) -> Result<TcpStream> {
+    let socket_address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| publish_error(format!("无法解析地址: {}", address)))?;
+    let stream = TcpStream::connect_timeout(&socket_address, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+/// NATS核心协议的发布者：每批消息之后发送PING，收到PONG说明服务器已处理之前的所有消息
+struct Nats {
+    address: String,
+    connection: Option<BufReader<TcpStream>>,
+}
+
+impl Nats {
+    fn connection(&mut self) -> Result<&mut BufReader<TcpStream>> {
+        if self.connection.is_none() {
+            let mut reader = BufReader::new(connect(&self.address)?);
+            let mut info = String::new();
+            reader.read_line(&mut info)?;
+            if !info.starts_with("INFO") {
+                return Err(publish_error(format!("不是NATS服务器: {}", info.trim())));
+            }
+            let connect = format!(
+                "CONNECT {{\"verbose\":false,\"pedantic\":false,\"name\":\"simpledb\",\"lang\":\"rust\",\"version\":\"{}\"}}\r\n",
+                env!("CARGO_PKG_VERSION")
+            );
+            reader.get_mut().write_all(connect.as_bytes())?;
+            self.connection = Some(reader);
+        }
+        Ok(self.connection.as_mut().expect("连接已建立"))
+    }
+
+    fn publish_batch(&mut self, messages: &[Message]) -> Result<()> {
+        let mut buffer = Vec::new();
+        for message in messages {
+            buffer.extend_from_slice(format!("PUB {} {}\r\n", message.topic, message.value.len()).as_bytes());
+            buffer.extend_from_slice(&message.value);
+            buffer.extend_from_slice(b"\r\n");
+        }
+        buffer.extend_from_slice(b"PING\r\n");
+        let reader = self.connection()?;
+        reader.get_mut().write_all(&buffer)?;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(publish_error("NATS服务器关闭了连接"));
+            }
+            match line.trim_end() {
+                "PONG" => return Ok(()),
+                "PING" => reader.get_mut().write_all(b"PONG\r\n")?,
+                error if error.starts_with("-ERR") => return Err(publish_error(error.to_string())),
+                // +OK和INFO
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Publisher for Nats {
+    fn publish(&mut self, messages: &[Message]) -> Result<()> {
+        let result = self.publish_batch(messages);
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+}
+
+/// Kafka请求的编码
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn i8(&mut self, value: i8) -> &mut Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn i16(&mut self, value: i16) -> &mut Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn i32(&mut self, value: i32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn i64(&mut self, value: i64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn string(&mut self, value: &str) -> &mut Self {
+        self.i16(value.len() as i16);
+        self.raw(value.as_bytes())
+    }
+
+    fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.i32(value.len() as i32);
+        self.raw(value)
+    }
+
+    /// zigzag编码的变长整数，用于记录批次中的字段
+    fn varint(&mut self, value: i64) -> &mut Self {
+        let mut value = ((value << 1) ^ (value >> 63)) as u64;
+        while value >= 0x80 {
+            self.0.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+        self
+    }
+
+    fn raw(&mut self, value: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(value);
+        self
+    }
+}
+
+/// Kafka响应的解码
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(publish_error("Kafka响应不完整"));
+        }
+        let (head, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn i16(&mut self) -> Result<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().expect("长度为2")))
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().expect("长度为4")))
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().expect("长度为8")))
+    }
+
+    /// 可为null的字符串，null返回空串
+    fn string(&mut self) -> Result<String> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(String::new());
+        }
+        Ok(String::from_utf8_lossy(self.take(len as usize)?).into_owned())
+    }
+
+    fn count(&mut self) -> Result<usize> {
+        Ok(self.i32()?.max(0) as usize)
+    }
+}
+
+/// CRC-32C（Castagnoli），Kafka记录批次的校验和
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// 编码第2版（magic为2）的记录批次，所有记录使用同一个时间戳
+fn record_batch(messages: &[&Message], timestamp: i64) -> Vec<u8> {
+    let mut records = Encoder::default();
+    for (i, message) in messages.iter().enumerate() {
+        let mut record = Encoder::default();
+        record
+            .i8(0)
+            .varint(0)
+            .varint(i as i64)
+            .varint(message.key.len() as i64)
+            .raw(&message.key)
+            .varint(message.value.len() as i64)
+            .raw(&message.value)
+            .varint(0);
+        records.varint(record.0.len() as i64).raw(&record.0);
+    }
+    // 校验和覆盖从attributes开始的部分
+    let mut checked = Encoder::default();
+    checked
+        .i16(0)
+        .i32(messages.len() as i32 - 1)
+        .i64(timestamp)
+        .i64(timestamp)
+        .i64(-1)
+        .i16(-1)
+        .i32(-1)
+        .i32(messages.len() as i32)
+        .raw(&records.0);
+    let mut batch = Encoder::default();
+    batch
+        .i64(0)
+        .i32((4 + 1 + 4 + checked.0.len()) as i32)
+        .i32(-1)
+        .i8(2)
+        .raw(&crc32c(&checked.0).to_be_bytes())
+        .raw(&checked.0);
+    batch.0
+}
+
+/// 最小的Kafka生产者：用Metadata（v1）找到每个主题0号分区的leader，用Produce（v3，acks=all）写入
+struct Kafka {
+    brokers: Vec<String>,
+    correlation: i32,
+    connections: HashMap<String, TcpStream>,
+    /// 主题 → 0号分区leader的地址
+    leaders: HashMap<String, String>,
+}
+
+impl Kafka {
+    const PRODUCE: i16 = 0;
+    const METADATA: i16 = 3;
+
+    fn request(&mut self, address: &str, api_key: i16, version: i16, body: &[u8]) -> Result<Vec<u8>> {
+        self.correlation = self.correlation.wrapping_add(1);
+        let mut frame = Encoder::default();
+        frame.i16(api_key).i16(version).i32(self.correlation).string("simpledb").raw(body);
+        let mut message = Encoder::default();
+        message.bytes(&frame.0);
+
+        let result = (|| {
+            let stream = match self.connections.entry(address.to_string()) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => entry.insert(connect(address)?),
+            };
+            stream.write_all(&message.0)?;
+            let mut size = [0; 4];
+            stream.read_exact(&mut size)?;
+            let mut response = vec![0; i32::from_be_bytes(size).max(0) as usize];
+            stream.read_exact(&mut response)?;
+            Ok::<_, DatabaseError>(response)
+        })();
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                self.connections.remove(address);
+                return Err(e);
+            }
+        };
+        let mut decoder = Decoder { data: &response };
+        if decoder.i32()? != self.correlation {
+            self.connections.remove(address);
+            return Err(publish_error("Kafka响应与请求不对应"));
+        }
+        Ok(decoder.data.to_vec())
+    }
+
+    /// 主题0号分区的leader地址
+    fn leader(&mut self, topic: &str) -> Result<String> {
+        if let Some(leader) = self.leaders.get(topic) {
+            return Ok(leader.clone());
+        }
+        let mut body = Encoder::default();
+        body.i32(1).string(topic);
+        let mut last_error = publish_error("没有配置Kafka代理");
+        for broker in self.brokers.clone() {
+            match self.request(&broker, Self::METADATA, 1, &body.0).and_then(|response| Self::parse_leader(&response, topic)) {
+                Ok(leader) => {
+                    self.leaders.insert(topic.to_string(), leader.clone());
+                    return Ok(leader);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn parse_leader(response: &[u8], topic: &str) -> Result<String> {
+        let mut decoder = Decoder { data: response };
+        let mut brokers = HashMap::new();
+        for _ in 0..decoder.count()? {
+            let node = decoder.i32()?;
+            let host = decoder.string()?;
+            let port = decoder.i32()?;
+            decoder.string()?;
+            brokers.insert(node, format!("{}:{}", host, port));
+        }
+        decoder.i32()?;
+        for _ in 0..decoder.count()? {
+            let error = decoder.i16()?;
+            let name = decoder.string()?;
+            decoder.take(1)?;
+            let mut leader = None;
+            for _ in 0..decoder.count()? {
+                decoder.i16()?;
+                let partition = decoder.i32()?;
+                let node = decoder.i32()?;
+                let replicas = decoder.count()?;
+                decoder.take(replicas * 4)?;
+                let isr = decoder.count()?;
+                decoder.take(isr * 4)?;
+                if partition == 0 {
+                    leader = Some(node);
+                }
+            }
+            if name != topic {
+                continue;
+            }
+            if error != 0 {
+                // 自动创建主题时第一次请求返回LEADER_NOT_AVAILABLE，稍后重试即可
+                return Err(publish_error(format!("Kafka主题 {} 不可用，错误码 {}", topic, error)));
+            }
+            return leader
+                .and_then(|node| brokers.get(&node).cloned())
+                .ok_or_else(|| publish_error(format!("Kafka主题 {} 的0号分区没有leader", topic)));
+        }
+        Err(publish_error(format!("Kafka没有返回主题 {}", topic)))
+    }
+
+    fn produce(&mut self, topic: &str, messages: &[&Message]) -> Result<()> {
+        let leader = self.leader(topic)?;
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        let mut body = Encoder::default();
+        body.i16(-1)
+            .i16(-1)
+            .i32(TIMEOUT.as_millis() as i32)
+            .i32(1)
+            .string(topic)
+            .i32(1)
+            .i32(0)
+            .bytes(&record_batch(messages, timestamp));
+        let response = self.request(&leader, Self::PRODUCE, 3, &body.0)?;
+        let mut decoder = Decoder { data: &response };
+        for _ in 0..decoder.count()? {
+            decoder.string()?;
+            for _ in 0..decoder.count()? {
+                decoder.i32()?;
+                let error = decoder.i16()?;
+                decoder.i64()?;
+                decoder.i64()?;
+                if error != 0 {
+                    // leader可能已经变化，下次重新查询
+                    self.leaders.remove(topic);
+                    return Err(publish_error(format!("写入Kafka主题 {} 失败，错误码 {}", topic, error)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Publisher for Kafka {
+    fn publish(&mut self, messages: &[Message]) -> Result<()> {
+        let mut by_topic: BTreeMap<&str, Vec<&Message>> = BTreeMap::new();
+        for message in messages {
+            by_topic.entry(message.topic.as_str()).or_default().push(message);
+        }
+        for (topic, messages) in by_topic {
+            self.produce(topic, &messages)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    status: Mutex<CdcStatus>,
+    stopped: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl Shared {
+    /// 等待`timeout`，返回是否已要求停止
+    fn wait(&self, timeout: Duration) -> bool {
+        let stopped = self.stopped.lock().recover();
+        if *stopped {
+            return true;
+        }
+        *self.wake.wait_timeout(stopped, timeout).recover().0
+    }
+}
+
+/// 发布变更事件的后台任务，由`CdcSink::start`启动，释放时发布完剩余的事件后停止
+#[derive(Debug)]
+pub struct CdcSink {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CdcSink {
+    /// 开始把`db`的变更发布到`config.target`，应在打开数据库后、写入之前调用，之前的写入的事件才不会被移出变更日志
+    pub fn start(db: &SimpleDB, config: CdcConfig) -> Result<CdcSink> {
+        if db.config().change_log_size == 0 {
+            return Err(DatabaseError::Config("CDC需要变更日志，change_log_size不能为0".to_string()));
+        }
+        let publisher: Box<dyn Publisher> = match &config.target {
+            CdcTarget::Kafka { brokers } => Box::new(Kafka {
+                brokers: brokers.clone(),
+                correlation: 0,
+                connections: HashMap::new(),
+                leaders: HashMap::new(),
+            }),
+            CdcTarget::Nats { address } => Box::new(Nats {
+                address: address.clone(),
+                connection: None,
+            }),
+        };
+        let offsets = Offsets {
+            path: Path::new(&db.config().data_dir).join(CDC_FILE),
+            crypto: db.master_crypto(),
+        };
+        let feed = db.change_feed();
+        let offset = offsets.load()?;
+        feed.retain_after(offset);
+        feed.persist()?;
+        let shared = Arc::new(Shared::default());
+        shared.status.lock().recover().offset = offset;
+        let worker = Worker {
+            shared: Arc::clone(&shared),
+            feed,
+            offsets,
+            config,
+            publisher,
+        };
+        let thread = std::thread::Builder::new()
+            .name("simpledb-cdc".to_string())
+            .spawn(move || worker.run(offset))?;
+        Ok(CdcSink {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn status(&self) -> CdcStatus {
+        self.shared.status.lock().recover().clone()
+    }
+}
+
+impl Drop for CdcSink {
+    fn drop(&mut self) {
+        *self.shared.stopped.lock().recover() = true;
+        self.shared.wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// `CDC`文件：已确认的序号
+struct Offsets {
+    path: PathBuf,
+    crypto: Option<Crypto>,
+}
+
+/// 数据目录中CDC已确认的序号，没有启动过CDC时为0
+pub(crate) fn saved_offset(data_dir: &Path, crypto: Option<Crypto>) -> Result<u64> {
+    Offsets {
+        path: data_dir.join(CDC_FILE),
+        crypto,
+    }
+    .load()
+}
+
+impl Offsets {
+    fn load(&self) -> Result<u64> {
+        Ok(statefile::load(&self.path, CDC_MAGIC, self.crypto.as_ref())?.unwrap_or(0))
+    }
+
+    fn save(&self, offset: u64) -> Result<()> {
+        statefile::store(&self.path, CDC_MAGIC, &offset, self.crypto.as_ref())
+    }
+}
+
+struct Worker {
+    shared: Arc<Shared>,
+    feed: Arc<ChangeFeed>,
+    offsets: Offsets,
+    config: CdcConfig,
+    publisher: Box<dyn Publisher>,
+}
+
+impl Worker {
+    fn message(&self, event: &ChangeEvent) -> Message {
+        Message {
+            topic: self.config.topic_of(&event.table),
+            key: event.id.as_bytes().to_vec(),
+            value: serde_json::to_vec(&DatabaseServer::event_to_json(event)).unwrap_or_default(),
+        }
+    }
+
+    fn run(mut self, mut offset: u64) {
+        let mut backoff = self.config.interval;
+        loop {
+            let (events, lost) = self.feed.since(offset, self.config.batch_size.max(1));
+            let mut skipped = None;
+            if lost > 0 {
+                offset += lost;
+                let message = format!("{} 个变更事件在发布前已被移出变更日志", lost);
+                let mut status = self.shared.status.lock().recover();
+                status.lost += lost;
+                status.last_error = Some(message.clone());
+                skipped = Some(message);
+            }
+            let Some(last) = events.last().map(|event| event.seq) else {
+                // 停止前发布完剩余的事件
+                if self.shared.wait(self.config.interval) && self.feed.since(offset, 1).0.is_empty() {
+                    return;
+                }
+                continue;
+            };
+            let messages: Vec<Message> = events.iter().map(|event| self.message(event)).collect();
+            match self.publisher.publish(&messages) {
+                Ok(()) => {
+                    offset = last;
+                    backoff = self.config.interval;
+                    let saved = self.offsets.save(offset);
+                    // 序号持久化之后才允许移出已确认的事件，写入失败时重启后重新发布
+                    if saved.is_ok() {
+                        self.feed.retain_after(offset);
+                    }
+                    let mut status = self.shared.status.lock().recover();
+                    status.offset = offset;
+                    status.published += events.len() as u64;
+                    status.last_error = saved.err().map(|e| e.to_string()).or(skipped);
+                }
+                Err(e) => {
+                    self.shared.status.lock().recover().last_error = Some(e.to_string());
+                    if self.shared.wait(backoff) {
+                        return;
+                    }
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_config;
+    use indexmap::IndexMap;
+    use crate::storage::Value;
+    use crate::Config;
+    use std::net::TcpListener;
+    use std::sync::mpsc::Sender;
+
+    /// 只处理PUB和PING的NATS服务器，收到的消息按subject发送到通道
+    fn fake_nats(listener: TcpListener, received: std::sync::mpsc::Sender<(String, serde_json::Value)>) {
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                stream.write_all(b"INFO {}\r\n").unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                    let parts: Vec<&str> = line.split_whitespace().collect();
+                    match parts.first().copied() {
+                        Some("PUB") => {
+                            let mut payload = vec![0; parts[2].parse::<usize>().unwrap() + 2];
+                            reader.read_exact(&mut payload).unwrap();
+                            let event = serde_json::from_slice(&payload[..payload.len() - 2]).unwrap();
+                            received.send((parts[1].to_string(), event)).unwrap();
+                        }
+                        Some("PING") => stream.write_all(b"PONG\r\n").unwrap(),
+                        _ => {}
+                    }
+                    line.clear();
+                }
+            }
+        });
+    }
+
+    /// 解码zigzag变长整数
+    fn varint(decoder: &mut Decoder) -> i64 {
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let byte = decoder.take(1).unwrap()[0];
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte < 0x80 {
+                return ((value >> 1) as i64) ^ -((value & 1) as i64);
+            }
+            shift += 7;
+        }
+    }
+
+    /// 解码记录批次并校验长度和CRC，返回每条记录的键和值
+    fn decode_batch(batch: &[u8]) -> Vec<(String, serde_json::Value)> {
+        let mut decoder = Decoder { data: batch };
+        decoder.i64().unwrap();
+        let length = decoder.i32().unwrap() as usize;
+        assert_eq!(length, decoder.data.len());
+        decoder.i32().unwrap();
+        assert_eq!(decoder.take(1).unwrap(), [2]);
+        let crc = u32::from_be_bytes(decoder.take(4).unwrap().try_into().unwrap());
+        assert_eq!(crc32c(decoder.data), crc);
+        // attributes、偏移差、两个时间戳、生产者ID、epoch和起始序号
+        decoder.take(2 + 4 + 8 + 8 + 8 + 2 + 4).unwrap();
+        (0..decoder.count().unwrap())
+            .map(|_| {
+                varint(&mut decoder);
+                decoder.take(1).unwrap();
+                varint(&mut decoder);
+                varint(&mut decoder);
+                let key_length = varint(&mut decoder) as usize;
+                let key = String::from_utf8(decoder.take(key_length).unwrap().to_vec()).unwrap();
+                let value_length = varint(&mut decoder) as usize;
+                let value = serde_json::from_slice(decoder.take(value_length).unwrap()).unwrap();
+                assert_eq!(varint(&mut decoder), 0);
+                (key, value)
+            })
+            .collect()
+    }
+
+    /// 只处理Metadata（v1）和Produce（v3）的Kafka代理，自己就是所有主题0号分区的leader
+    ///
+    /// 解码收到的记录批次，按主题发送到通道；每种请求的前`failures`次返回错误码：
+    /// Metadata为5（LEADER_NOT_AVAILABLE），Produce为6（NOT_LEADER_FOR_PARTITION）。返回收到的请求类型。
+    fn fake_kafka(
+        listener: TcpListener,
+        failures: usize,
+        received: Sender<(String, Vec<(String, serde_json::Value)>)>,
+    ) -> Arc<Mutex<Vec<i16>>> {
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut size = [0; 4];
+                while stream.read_exact(&mut size).is_ok() {
+                    let mut request = vec![0; i32::from_be_bytes(size) as usize];
+                    stream.read_exact(&mut request).unwrap();
+                    let mut decoder = Decoder { data: &request };
+                    let (api_key, version, correlation) = (decoder.i16().unwrap(), decoder.i16().unwrap(), decoder.i32().unwrap());
+                    assert_eq!(decoder.string().unwrap(), "simpledb");
+                    let failed = {
+                        let mut log = log.lock().unwrap();
+                        log.push(api_key);
+                        log.iter().filter(|&&key| key == api_key).count() <= failures
+                    };
+                    let mut response = Encoder::default();
+                    response.i32(correlation);
+                    match (api_key, version) {
+                        (Kafka::METADATA, 1) => {
+                            assert_eq!(decoder.count().unwrap(), 1);
+                            let topic = decoder.string().unwrap();
+                            response.i32(1).i32(7).string("127.0.0.1").i32(port.into()).i16(-1).i32(7);
+                            response.i32(1).i16(if failed { 5 } else { 0 }).string(&topic).i8(0);
+                            response.i32(1).i16(0).i32(0).i32(7).i32(1).i32(7).i32(1).i32(7);
+                        }
+                        (Kafka::PRODUCE, 3) => {
+                            // 没有事务ID，acks为all
+                            assert_eq!((decoder.i16().unwrap(), decoder.i16().unwrap()), (-1, -1));
+                            decoder.i32().unwrap();
+                            assert_eq!(decoder.count().unwrap(), 1);
+                            let topic = decoder.string().unwrap();
+                            assert_eq!((decoder.count().unwrap(), decoder.i32().unwrap()), (1, 0));
+                            let length = decoder.count().unwrap();
+                            received.send((topic.clone(), decode_batch(decoder.take(length).unwrap()))).unwrap();
+                            response.i32(1).string(&topic).i32(1).i32(0).i16(if failed { 6 } else { 0 }).i64(0).i64(-1).i32(0);
+                        }
+                        other => panic!("未预期的请求: {:?}", other),
+                    }
+                    let mut frame = Encoder::default();
+                    frame.bytes(&response.0);
+                    stream.write_all(&frame.0).unwrap();
+                }
+            }
+        });
+        requests
+    }
+
+    #[test]
+    fn test_produce_to_kafka() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker = listener.local_addr().unwrap().to_string();
+        let (sender, received) = std::sync::mpsc::channel();
+        let requests = fake_kafka(listener, 1, sender);
+        let mut kafka = Kafka {
+            brokers: vec![broker],
+            correlation: 0,
+            connections: HashMap::new(),
+            leaders: HashMap::new(),
+        };
+        let message = |topic: &str, key: &str, n: i64| Message {
+            topic: topic.to_string(),
+            key: key.as_bytes().to_vec(),
+            value: serde_json::to_vec(&serde_json::json!({ "n": n })).unwrap(),
+        };
+        let messages = [message("simpledb.users", "a", 1), message("simpledb.users", "b", 2), message("shop.orders", "c", 3)];
+        let next = || received.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        // 第一次查询主题的leader返回LEADER_NOT_AVAILABLE
+        let error = kafka.publish(&messages).unwrap_err();
+        assert!(error.to_string().contains("错误码 5"), "{}", error);
+        // 代理拒绝写入时发布失败，下次重新查询leader
+        let error = kafka.publish(&messages).unwrap_err();
+        assert!(error.to_string().contains("错误码 6"), "{}", error);
+        assert_eq!(next(), ("shop.orders".to_string(), vec![("c".to_string(), serde_json::json!({ "n": 3 }))]));
+        assert!(kafka.leaders.is_empty());
+
+        kafka.publish(&messages).unwrap();
+        assert_eq!(next().0, "shop.orders");
+        // 同一主题的消息在一个批次中按顺序写入，键为记录ID
+        let (topic, records) = next();
+        assert_eq!(topic, "simpledb.users");
+        assert_eq!(records, vec![
+            ("a".to_string(), serde_json::json!({ "n": 1 })),
+            ("b".to_string(), serde_json::json!({ "n": 2 })),
+        ]);
+        let (metadata, produce) = (Kafka::METADATA, Kafka::PRODUCE);
+        assert_eq!(*requests.lock().unwrap(), [metadata, metadata, produce, metadata, produce, metadata, produce]);
+    }
+
+    #[test]
+    fn test_publish_to_nats() {
+        // RFC 3720中的CRC-32C测试向量
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+
+        let (_dir, config) = temp_config("cdc", Config::default());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = CdcTarget::Nats {
+            address: listener.local_addr().unwrap().to_string(),
+        };
+        let (sender, received) = std::sync::mpsc::channel();
+        fake_nats(listener, sender);
+        let mut cdc = CdcConfig::new(target);
+        cdc.topics.insert("orders".to_string(), "shop.orders".to_string());
+        cdc.interval = Duration::from_millis(10);
+        let next = || received.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        let db = SimpleDB::new(config.clone()).unwrap();
+        let sink = CdcSink::start(&db, cdc.clone()).unwrap();
+        let id = db.insert("users", IndexMap::from([("name".to_string(), Value::String("张三".to_string()))])).unwrap();
+        db.insert("orders", IndexMap::new()).unwrap();
+        let (subject, event) = next();
+        assert_eq!(subject, "simpledb.users");
+        assert_eq!((event["seq"].as_u64(), event["kind"].as_str()), (Some(1), Some("insert")));
+        assert_eq!(event["record"]["data"]["name"], "张三");
+        assert_eq!(next().0, "shop.orders");
+        drop(sink);
+        drop(db);
+
+        // 重启后序号接着上次的序号继续，启动CDC之前的写入也一样
+        let db = SimpleDB::new(config.clone()).unwrap();
+        db.delete("users", &id).unwrap();
+        let sink = CdcSink::start(&db, cdc.clone()).unwrap();
+        let (_, event) = next();
+        assert_eq!((event["seq"].as_u64(), event["kind"].as_str()), (Some(3), Some("delete")));
+        drop(sink);
+        drop(db);
+
+        // 发布失败期间保存的修改的事件在重启后发布，不受变更日志大小限制
+        let unreachable = TcpListener::bind("127.0.0.1:0").unwrap();
+        let down = CdcConfig {
+            target: CdcTarget::Nats {
+                address: unreachable.local_addr().unwrap().to_string(),
+            },
+            ..cdc.clone()
+        };
+        drop(unreachable);
+        let small = Config {
+            change_log_size: 2,
+            ..config.clone()
+        };
+        let db = SimpleDB::new(small.clone()).unwrap();
+        let sink = CdcSink::start(&db, down).unwrap();
+        for n in 0..5 {
+            db.insert("users", IndexMap::from([("n".to_string(), Value::Int(n))])).unwrap();
+        }
+        db.save_all().unwrap();
+        while sink.status().last_error.is_none() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(sink.status().offset, 3);
+        drop(sink);
+        drop(db);
+
+        let db = SimpleDB::new(small).unwrap();
+        let sink = CdcSink::start(&db, cdc).unwrap();
+        let seqs: Vec<_> = (0..5).map(|_| next().1["seq"].as_u64().unwrap()).collect();
+        assert_eq!(seqs, vec![4, 5, 6, 7, 8]);
+        db.insert("users", IndexMap::new()).unwrap();
+        assert_eq!(next().1["seq"].as_u64(), Some(9));
+        while sink.status().offset < 9 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(sink.status().lost, 0);
+        drop(sink);
+        drop(db);
+    }
+}
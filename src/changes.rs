@@ -0,0 +1,385 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+use crate::crypto::Crypto;
+use crate::error::{DatabaseError, Result};
+use crate::lock::Recover;
+use crate::statefile;
+use crate::storage::Record;
+
+/// 订阅者未及时读取时，广播通道最多缓冲的事件数
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// 持久的变更日志文件名，启动过CDC后存在
+pub const CHANGES_FILE: &str = "CHANGES";
+
+/// `CHANGES`文件开头的魔数
+pub const CHANGES_MAGIC: &[u8; 8] = b"SDBCHNG1";
+
+/// 变更类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete,
+    /// 清空整张表，事件的ID为空
+    Truncate,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Insert => "insert",
+            ChangeKind::Update => "update",
+            ChangeKind::Delete => "delete",
+            ChangeKind::Truncate => "truncate",
+        }
+    }
+}
+
+/// 一次记录变更
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    /// 全局递增的序号，可用于断点续传
+    pub seq: u64,
+    pub table: String,
+    pub kind: ChangeKind,
+    pub id: String,
+    /// 变更后的记录，删除时为None
+    pub record: Option<Arc<Record>>,
+}
+
+/// 从某个序号之后订阅的结果
+pub struct Subscription {
+    /// 日志中仍保留的、序号大于起点的事件
+    pub backlog: Vec<Arc<ChangeEvent>>,
+    /// 起点之后有事件已被移出日志，`backlog`不完整
+    pub truncated: bool,
+    /// 之后的新事件
+    pub receiver: broadcast::Receiver<Arc<ChangeEvent>>,
+}
+
+/// `CHANGES`文件：魔数、之后的起始序号（8字节小端），然后是逐条追加的事件，
+/// 每条为4字节小端的长度和bincode编码（配置了密钥时加密）的事件
+struct Journal {
+    path: PathBuf,
+    crypto: Option<Crypto>,
+    /// 追加写入的文件，启用前为None
+    file: Option<File>,
+    /// 文件中的事件数
+    entries: usize,
+    /// 追加失败过，文件中缺少事件，下次`sync`时整个重写
+    broken: bool,
+}
+
+impl std::fmt::Debug for Journal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Journal")
+            .field("path", &self.path)
+            .field("entries", &self.entries)
+            .field("broken", &self.broken)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Journal {
+    fn frame(&self, event: &ChangeEvent) -> Result<Vec<u8>> {
+        let mut content = bincode::serialize(event)?;
+        if let Some(crypto) = &self.crypto {
+            content = crypto.encrypt(&content)?;
+        }
+        Ok(statefile::frame(&content))
+    }
+
+    /// 读出文件中的起始序号和完整的事件；末尾写到一半的事件（写入时崩溃）被忽略，下次重写时去掉
+    fn read(path: &Path, crypto: Option<&Crypto>) -> Result<(u64, Vec<ChangeEvent>)> {
+        let (next_seq, frames, _) = statefile::read_frames(path, CHANGES_MAGIC)?;
+        let mut events = Vec::new();
+        for frame in frames {
+            let content = match crypto {
+                Some(crypto) => match crypto.decrypt(&frame) {
+                    Ok(content) => content,
+                    Err(_) => break,
+                },
+                None => frame,
+            };
+            let Ok(event) = bincode::deserialize(&content) else { break };
+            events.push(event);
+        }
+        Ok((next_seq, events))
+    }
+
+    /// 用`log`中的事件持久地替换整个文件，之后继续追加
+    fn rewrite(&mut self, next_seq: u64, log: &VecDeque<Arc<ChangeEvent>>) -> Result<()> {
+        // 记下当前的下一个序号，日志为空时重启后序号也接着递增
+        let mut content = statefile::frames_header(CHANGES_MAGIC, next_seq);
+        for event in log {
+            content.extend_from_slice(&self.frame(event)?);
+        }
+        self.file = None;
+        statefile::write_durable(&self.path, &content)?;
+        self.file = Some(OpenOptions::new().append(true).open(&self.path)?);
+        self.entries = log.len();
+        self.broken = false;
+        Ok(())
+    }
+
+    fn append(&mut self, event: &ChangeEvent) -> Result<()> {
+        let frame = self.frame(event)?;
+        match &mut self.file {
+            Some(file) => file.write_all(&frame)?,
+            None => return Ok(()),
+        }
+        self.entries += 1;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct FeedState {
+    next_seq: u64,
+    log: VecDeque<Arc<ChangeEvent>>,
+    /// 序号大于此值的事件在确认前不移出日志，由CDC设置
+    retain_after: Option<u64>,
+    journal: Option<Journal>,
+}
+
+/// 数据库的变更流
+///
+/// 最近的事件保存在有界日志中，订阅时可以从指定序号之后补发；
+/// 事务回滚会以补偿事件的形式出现在流中。
+/// 启用持久化（`persist`）后事件同时追加到数据目录的`CHANGES`文件，
+/// 表文件保存之前先fsync该文件，重新打开数据库时从中恢复日志和序号。
+#[derive(Debug)]
+pub struct ChangeFeed {
+    capacity: usize,
+    state: Mutex<FeedState>,
+    sender: broadcast::Sender<Arc<ChangeEvent>>,
+}
+
+impl ChangeFeed {
+    /// `capacity`为日志保留的事件数
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+        Self {
+            capacity,
+            state: Mutex::new(FeedState {
+                next_seq: 1,
+                log: VecDeque::new(),
+                retain_after: None,
+                journal: None,
+            }),
+            sender,
+        }
+    }
+
+    /// 打开数据目录`data_dir`的变更流，存在`CHANGES`文件时恢复其中的事件和序号并继续持久化；
+    /// `read_only`时只读取不写入
+    pub(crate) fn open(data_dir: &Path, capacity: usize, crypto: Option<Crypto>, read_only: bool) -> Result<Self> {
+        let feed = Self::new(capacity);
+        let path = data_dir.join(CHANGES_FILE);
+        let mut journal = Journal {
+            path,
+            crypto,
+            file: None,
+            entries: 0,
+            broken: false,
+        };
+        {
+            let mut state = feed.state.lock().recover();
+            if journal.path.exists() {
+                let (next_seq, events) = Journal::read(&journal.path, journal.crypto.as_ref())?;
+                state.next_seq = events.last().map_or(next_seq, |event| event.seq + 1).max(next_seq);
+                state.log = events.into_iter().map(Arc::new).collect();
+                if !read_only {
+                    journal.rewrite(state.next_seq, &state.log)?;
+                }
+            }
+            if !read_only {
+                state.journal = Some(journal);
+            }
+        }
+        Ok(feed)
+    }
+
+    /// 发布一次变更
+    ///
+    /// 追加到`CHANGES`文件失败时记下，之后的`sync`重写整个文件或返回错误，使表文件不会在事件之前保存。
+    pub fn publish(&self, table: &str, kind: ChangeKind, id: &str, record: Option<Arc<Record>>) {
+        let mut state = self.state.lock().recover();
+        let event = Arc::new(ChangeEvent {
+            seq: state.next_seq,
+            table: table.to_string(),
+            kind,
+            id: id.to_string(),
+            record,
+        });
+        state.next_seq += 1;
+        if self.capacity > 0 || state.retain_after.is_some() {
+            state.log.push_back(Arc::clone(&event));
+            let retain_after = state.retain_after;
+            while state.log.len() > self.capacity && state.log.front().is_some_and(|e| retain_after.is_none_or(|seq| e.seq <= seq)) {
+                state.log.pop_front();
+            }
+        }
+        let state = &mut *state;
+        if let Some(journal) = state.journal.as_mut().filter(|journal| journal.file.is_some() && !journal.broken) {
+            // 文件中的事件远多于日志时重写，去掉已移出日志的事件
+            let result = if journal.entries >= 2 * state.log.len().max(self.capacity).max(1) {
+                journal.rewrite(state.next_seq, &state.log)
+            } else {
+                journal.append(&event)
+            };
+            journal.broken = result.is_err();
+        }
+        // 没有订阅者时发送失败，忽略即可
+        let _ = self.sender.send(event);
+    }
+
+    /// 启用持久化：把日志中现有的事件写入`CHANGES`文件，之后的事件追加到文件中
+    pub(crate) fn persist(&self) -> Result<()> {
+        let state = &mut *self.state.lock().recover();
+        match state.journal.as_mut() {
+            Some(journal) if journal.file.is_none() => journal.rewrite(state.next_seq, &state.log),
+            Some(_) => Ok(()),
+            None => Err(DatabaseError::Config("只读打开的数据库不能持久化变更日志".to_string())),
+        }
+    }
+
+    /// 把已追加的事件fsync到磁盘；表文件保存之前调用，保证已保存的修改的事件不会因崩溃丢失
+    pub(crate) fn sync(&self) -> Result<()> {
+        let state = &mut *self.state.lock().recover();
+        let Some(journal) = state.journal.as_mut().filter(|journal| journal.file.is_some()) else {
+            return Ok(());
+        };
+        if journal.broken {
+            return journal.rewrite(state.next_seq, &state.log);
+        }
+        match &journal.file {
+            Some(file) => Ok(file.sync_data()?),
+            None => Ok(()),
+        }
+    }
+
+    /// 序号大于`seq`的事件在再次调用之前不移出日志，供CDC在确认之前保留事件
+    pub(crate) fn retain_after(&self, seq: u64) {
+        self.state.lock().recover().retain_after = Some(seq);
+    }
+
+    /// 日志中序号大于`after`的前`limit`个事件，以及其前已被移出日志、无法再取得的事件数
+    pub fn since(&self, after: u64, limit: usize) -> (Vec<Arc<ChangeEvent>>, u64) {
+        let state = self.state.lock().recover();
+        let events: Vec<_> = state.log.iter().filter(|e| e.seq > after).take(limit).cloned().collect();
+        let oldest = events.first().map_or(state.next_seq, |e| e.seq);
+        (events, oldest.saturating_sub(after + 1))
+    }
+
+    /// 让之后的序号至少从`seq + 1`开始，打开数据库时用CDC已确认的序号保证序号不回退
+    pub(crate) fn resume_after(&self, seq: u64) {
+        let mut state = self.state.lock().recover();
+        state.next_seq = state.next_seq.max(seq + 1);
+    }
+
+    /// 订阅序号大于`after`的事件，None表示只接收新事件
+    pub fn subscribe(&self, after: Option<u64>) -> Subscription {
+        // 持锁期间订阅，保证补发的事件与新事件之间没有遗漏或重复
+        let state = self.state.lock().recover();
+        let receiver = self.sender.subscribe();
+        let (backlog, truncated) = match after {
+            Some(after) => {
+                let backlog: Vec<_> = state.log.iter().filter(|e| e.seq > after).cloned().collect();
+                let oldest = backlog.first().map_or(state.next_seq, |e| e.seq);
+                // 起点早于日志或晚于当前序号（如服务重启后）都无法完整续传
+                (backlog, oldest != after + 1)
+            }
+            None => (Vec::new(), false),
+        };
+        Subscription {
+            backlog,
+            truncated,
+            receiver,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_resume_from_log() {
+        let feed = ChangeFeed::new(2);
+        for id in ["a", "b", "c"] {
+            feed.publish("t", ChangeKind::Insert, id, None);
+        }
+
+        let resumed = feed.subscribe(Some(1));
+        let ids: Vec<&str> = resumed.backlog.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, vec!["b", "c"]);
+        assert!(!resumed.truncated);
+
+        // 序号1的事件已被移出日志
+        assert!(feed.subscribe(Some(0)).truncated);
+        assert!(!feed.subscribe(Some(3)).truncated);
+        assert!(feed.subscribe(Some(10)).truncated);
+    }
+
+    #[test]
+    fn test_journal() {
+        let dir = temp_dir("changes");
+        let crypto = Crypto::new(&Crypto::generate_key()).unwrap();
+        let path = dir.path().join(CHANGES_FILE);
+
+        // 没有启用持久化时不写文件
+        let feed = ChangeFeed::open(dir.path(), 2, Some(crypto.clone()), false).unwrap();
+        feed.publish("t", ChangeKind::Insert, "a", None);
+        feed.sync().unwrap();
+        assert!(!path.exists());
+        feed.persist().unwrap();
+        feed.retain_after(0);
+        for id in ["b", "c"] {
+            feed.publish("t", ChangeKind::Insert, id, None);
+        }
+        feed.sync().unwrap();
+        assert!(statefile::is_state_file(&path).unwrap());
+        assert!(statefile::frames_encrypted(&path, CHANGES_MAGIC, Some(&crypto)));
+
+        // 写到一半的事件被忽略，未确认的事件和序号都恢复
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[200, 0, 0, 0, 1]).unwrap();
+        drop(file);
+        let feed = ChangeFeed::open(dir.path(), 2, Some(crypto.clone()), false).unwrap();
+        let ids: Vec<String> = feed.since(0, 10).0.iter().map(|e| e.id.clone()).collect();
+        assert_eq!(ids, vec!["a", "b", "c"]);
+        feed.publish("t", ChangeKind::Delete, "a", None);
+        assert_eq!(feed.since(3, 10).0[0].seq, 4);
+
+        // 未确认的事件超出日志大小也保留，确认后移出，文件只保留日志中的事件
+        feed.retain_after(4);
+        for _ in 0..8 {
+            feed.publish("t", ChangeKind::Truncate, "", None);
+        }
+        assert_eq!(feed.since(4, 100).0.len(), 8);
+        feed.retain_after(12);
+        for _ in 0..4 {
+            feed.publish("t", ChangeKind::Truncate, "", None);
+        }
+        feed.sync().unwrap();
+        let (_, events) = Journal::read(&path, Some(&crypto)).unwrap();
+        let seqs: Vec<u64> = events.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![12, 13, 14, 15, 16]);
+
+        // 只读打开时恢复但不写入
+        let feed = ChangeFeed::open(dir.path(), 2, Some(crypto), true).unwrap();
+        feed.publish("t", ChangeKind::Insert, "d", None);
+        assert_eq!(feed.since(16, 10).0[0].seq, 17);
+        assert!(feed.persist().is_err());
+    }
+}
@@ -0,0 +1,1265 @@
+//! 集群模式：多个节点用Raft协议复制写操作，选出的主节点处理写入，所有节点都可以读
+//!
+//! 每次写入（单条写操作或事务）作为一条日志由主节点追加并复制到其他节点，写入多数节点后提交，
+//! 各节点按日志顺序把提交的写操作应用到自己的表中。日志中带有主节点接受写入的时间，
+//! 所有节点上的记录时间戳和ETag因此一致。跟随者上的数据可能略落后于主节点。
+//!
+//! 任期和投票保存在数据目录的`RAFT`文件中，日志逐条追加到`RAFTLOG`文件，每次修改都fsync后才回复。
+//! 应用的日志涉及的表立即保存，因此表中的数据就是已应用日志的快照：已应用的日志每`ClusterConfig::snapshot_interval`条
+//! 压缩一次，从日志文件中删除。跟随者需要的日志已被压缩时（如落后太多或数据目录被清空），主节点发送全部表的记录代替这些日志。
+//!
+//! 限制：节点成员在启动时固定，不支持在运行中增删节点。只复制记录的写入，建表、索引、过期时间、严格模式等表设置
+//! 不经过复制，需要在每个节点上分别执行。
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::crypto::Crypto;
+use crate::database::SimpleDB;
+use crate::error::{DatabaseError, Result};
+use crate::lock::Recover;
+use crate::statefile;
+use crate::storage::{Record, Value};
+use crate::sync;
+use crate::transaction::WriteOp;
+
+/// 保存任期、投票和快照位置的文件名
+pub const RAFT_FILE: &str = "RAFT";
+
+/// `RAFT`文件开头的魔数
+pub const RAFT_MAGIC: &[u8; 8] = b"SDBRAFT2";
+
+/// 旧版本的`RAFT`文件的魔数，其中还保存着全部日志，启动时迁移
+pub const LEGACY_RAFT_MAGIC: &[u8; 8] = b"SDBRAFT1";
+
+/// 追加写入日志的文件名
+pub const RAFT_LOG_FILE: &str = "RAFTLOG";
+
+/// `RAFTLOG`文件开头的魔数
+pub const RAFT_LOG_MAGIC: &[u8; 8] = b"SDBRLOG1";
+
+/// 一次复制请求最多携带的日志条数
+const MAX_BATCH: usize = 64;
+
+/// 等待写入提交的最长时间
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 集群配置
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// 本节点的ID，集群内唯一
+    pub node_id: String,
+    /// 其他节点的ID和API地址（`http://主机:端口`）
+    pub peers: BTreeMap<String, String>,
+    /// 主节点发送心跳的间隔
+    pub heartbeat_interval: Duration,
+    /// 跟随者超过这段时间收不到主节点的消息就发起选举，实际在该值和它的两倍之间随机
+    pub election_timeout: Duration,
+    /// 已应用的日志每积累这么多条压缩一次
+    pub snapshot_interval: u64,
+}
+
+impl ClusterConfig {
+    pub fn new(node_id: impl Into<String>, peers: BTreeMap<String, String>) -> Self {
+        Self {
+            node_id: node_id.into(),
+            peers,
+            heartbeat_interval: Duration::from_millis(100),
+            election_timeout: Duration::from_secs(1),
+            snapshot_interval: 1000,
+        }
+    }
+}
+
+/// 节点在集群中的角色
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// 节点的状态，由`Cluster::status`生成
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClusterStatus {
+    pub node_id: String,
+    pub role: Role,
+    pub term: u64,
+    /// 当前主节点的ID，选举期间为None
+    pub leader: Option<String>,
+    /// 最后一条日志的序号
+    pub last_index: u64,
+    /// 已提交到多数节点的日志序号
+    pub commit_index: u64,
+    /// 已应用到表中的日志序号
+    pub applied: u64,
+    /// 已压缩的最后一条日志的序号
+    pub snapshot_index: u64,
+    /// 后台线程最近一次写入`RAFT`或`RAFTLOG`文件失败的原因
+    pub last_error: Option<String>,
+}
+
+/// 一条日志：一次原子写入
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    term: u64,
+    /// 主节点接受写入时的Unix秒，应用时作为记录的时间戳
+    time: u64,
+    /// 为空表示主节点上任时追加的空日志
+    ops: Vec<WriteOp>,
+}
+
+/// 保存在`RAFT`文件中的状态
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Persistent {
+    term: u64,
+    voted_for: Option<String>,
+    /// 已应用且涉及的表已保存的最后一条日志的序号
+    applied: u64,
+    /// 已压缩的最后一条日志的序号和任期，没有压缩过时为0
+    snapshot_index: u64,
+    snapshot_term: u64,
+}
+
+/// 旧版本的`RAFT`文件：状态和全部日志
+#[derive(Debug, Default, Deserialize)]
+struct LegacyPersistent {
+    term: u64,
+    voted_for: Option<String>,
+    log: Vec<Entry>,
+    applied: u64,
+}
+
+/// `RAFTLOG`文件中快照之后的日志：魔数、快照的最后序号，之后逐条追加（配置了密钥时加密）的日志
+struct Log {
+    path: PathBuf,
+    crypto: Option<Crypto>,
+    file: File,
+    /// 快照的最后序号，第一条日志的序号为它加一
+    base: u64,
+    entries: Vec<Entry>,
+    /// 每条日志在文件中的起始位置
+    offsets: Vec<u64>,
+    /// 文件中完整日志的结束位置
+    end: u64,
+}
+
+impl std::fmt::Debug for Log {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Log")
+            .field("path", &self.path)
+            .field("base", &self.base)
+            .field("entries", &self.entries.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Log {
+    /// 打开日志文件，跳过已压缩的日志，截掉末尾写到一半的日志；文件不存在时创建空日志
+    fn open(path: PathBuf, crypto: Option<Crypto>, base: u64) -> Result<Log> {
+        if !path.exists() {
+            return Log::create(path, crypto, base, &[]);
+        }
+        let (start, frames, end) = statefile::read_frames(&path, RAFT_LOG_MAGIC)?;
+        let mut offset = (statefile::MAGIC_LEN + 8) as u64;
+        let mut offsets = Vec::new();
+        let mut entries = Vec::new();
+        for (i, frame) in frames.iter().enumerate() {
+            let content = match &crypto {
+                Some(crypto) => crypto.decrypt(frame)?,
+                None => frame.clone(),
+            };
+            // 压缩时先更新`RAFT`文件再重写日志文件，中间崩溃时文件中还有已压缩的日志
+            if start + i as u64 + 1 > base {
+                offsets.push(offset);
+                entries.push(bincode::deserialize::<Entry>(&content)?);
+            }
+            offset += 4 + frame.len() as u64;
+        }
+        if start > base {
+            return Err(DatabaseError::DataFormat(format!(
+                "{} 从第 {} 条日志开始，缺少快照之后的日志",
+                path.display(),
+                start + 1
+            )));
+        }
+        let file = OpenOptions::new().append(true).open(&path)?;
+        if file.metadata()?.len() != end {
+            file.set_len(end)?;
+            file.sync_data()?;
+        }
+        Ok(Log {
+            path,
+            crypto,
+            file,
+            base,
+            entries,
+            offsets,
+            end,
+        })
+    }
+
+    /// 持久地写出只含`entries`的日志文件，第一条日志的序号为`base + 1`
+    fn create(path: PathBuf, crypto: Option<Crypto>, base: u64, entries: &[Entry]) -> Result<Log> {
+        let mut content = statefile::frames_header(RAFT_LOG_MAGIC, base);
+        let mut offsets = Vec::with_capacity(entries.len());
+        for entry in entries {
+            offsets.push(content.len() as u64);
+            content.extend_from_slice(&Self::encode(crypto.as_ref(), entry)?);
+        }
+        statefile::write_durable(&path, &content)?;
+        Ok(Log {
+            file: OpenOptions::new().append(true).open(&path)?,
+            path,
+            crypto,
+            base,
+            entries: entries.to_vec(),
+            offsets,
+            end: content.len() as u64,
+        })
+    }
+
+    fn encode(crypto: Option<&Crypto>, entry: &Entry) -> Result<Vec<u8>> {
+        // 日志中有表名和记录内容，与表数据一样加密
+        let mut content = bincode::serialize(entry)?;
+        if let Some(crypto) = crypto {
+            content = crypto.encrypt(&content)?;
+        }
+        Ok(statefile::frame(&content))
+    }
+
+    fn last_index(&self) -> u64 {
+        self.base + self.entries.len() as u64
+    }
+
+    /// 序号为`index`的日志，已压缩或不存在时为None
+    fn get(&self, index: u64) -> Option<&Entry> {
+        index.checked_sub(self.base + 1).and_then(|i| self.entries.get(i as usize))
+    }
+
+    /// 从序号`from`开始最多`limit`条日志
+    fn slice(&self, from: u64, limit: usize) -> &[Entry] {
+        let start = (from.saturating_sub(self.base + 1) as usize).min(self.entries.len());
+        &self.entries[start..self.entries.len().min(start + limit)]
+    }
+
+    /// 追加日志并fsync，返回后日志已落盘；写入失败时文件和内存中的日志都不变
+    fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        let mut content = Vec::new();
+        let mut offsets = Vec::with_capacity(entries.len());
+        for entry in entries {
+            offsets.push(self.end + content.len() as u64);
+            content.extend_from_slice(&Self::encode(self.crypto.as_ref(), entry)?);
+        }
+        let written = self.file.write_all(&content).and_then(|()| self.file.sync_data());
+        if let Err(e) = written {
+            // 去掉可能写了一部分的内容，失败时下次打开也会截掉
+            let _ = self.file.set_len(self.end);
+            return Err(e.into());
+        }
+        self.end += content.len() as u64;
+        self.offsets.extend(offsets);
+        self.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    /// 删除序号`index`及之后的日志
+    fn truncate(&mut self, index: u64) -> Result<()> {
+        let keep = (index.saturating_sub(self.base + 1) as usize).min(self.entries.len());
+        if keep == self.entries.len() {
+            return Ok(());
+        }
+        let end = self.offsets[keep];
+        self.file.set_len(end)?;
+        self.file.sync_data()?;
+        self.end = end;
+        self.entries.truncate(keep);
+        self.offsets.truncate(keep);
+        Ok(())
+    }
+
+    /// 去掉序号`base`及之前的日志，持久地重写文件；`base`超过最后一条日志时清空
+    fn compact(&mut self, base: u64) -> Result<()> {
+        let skip = (base.saturating_sub(self.base) as usize).min(self.entries.len());
+        *self = Log::create(self.path.clone(), self.crypto.clone(), base, &self.entries[skip..])?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VoteRequest {
+    term: u64,
+    candidate: String,
+    last_log_index: u64,
+    last_log_term: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VoteReply {
+    term: u64,
+    granted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AppendRequest {
+    term: u64,
+    leader: String,
+    prev_log_index: u64,
+    prev_log_term: u64,
+    /// bincode编码后再base64编码的日志
+    entries: String,
+    leader_commit: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AppendReply {
+    term: u64,
+    success: bool,
+    /// 跟随者最后一条日志的序号，失败时主节点据此回退
+    last_index: u64,
+}
+
+/// 主节点发送的快照：已应用到某条日志为止的全部表的记录，代替跟随者缺少的已压缩日志
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotRequest {
+    term: u64,
+    leader: String,
+    /// 快照包含的最后一条日志的序号和任期
+    last_index: u64,
+    last_term: u64,
+    /// bincode编码后再base64编码的各表记录
+    tables: String,
+}
+
+#[derive(Debug)]
+struct State {
+    persistent: Persistent,
+    log: Log,
+    role: Role,
+    leader: Option<String>,
+    commit_index: u64,
+    /// 到这个时刻还没有主节点的消息就发起选举
+    election_deadline: Instant,
+    /// 主节点上每个跟随者下一条要发送的日志序号
+    next_index: HashMap<String, u64>,
+    /// 主节点上每个跟随者已确认的日志序号
+    match_index: HashMap<String, u64>,
+    /// 本节点接受、等待应用结果的写入，按日志序号
+    pending: HashMap<u64, Option<Result<()>>>,
+    /// 后台线程最近一次写入状态失败的原因
+    last_error: Option<String>,
+    stopped: bool,
+}
+
+impl State {
+    fn last_index(&self) -> u64 {
+        self.log.last_index()
+    }
+
+    /// 序号为`index`的日志的任期，序号0为0，已压缩的日志中只知道最后一条的任期
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index == self.persistent.snapshot_index {
+            true => Some(self.persistent.snapshot_term),
+            false => self.log.get(index).map(|entry| entry.term),
+        }
+    }
+
+    fn become_follower(&mut self, election_timeout: Duration) {
+        self.role = Role::Follower;
+        self.election_deadline = Instant::now() + random_timeout(election_timeout);
+    }
+}
+
+/// 数据库所在的集群节点，由`Cluster::start`创建并附加到数据库
+///
+/// 附加后`SimpleDB`的单条写入和事务提交都经过复制：主节点上等到写入提交并应用后返回，
+/// 跟随者上返回`NotLeader`。其余修改记录的操作（批量更新、清空表、同步、合并等）无法复制，返回`NotPermitted`。
+/// `create_table`、`create_index`等修改表设置的操作只在本节点生效，要在每个节点上分别调用。
+#[derive(Debug)]
+pub struct Cluster {
+    db: Weak<SimpleDB>,
+    config: ClusterConfig,
+    path: PathBuf,
+    crypto: Option<Crypto>,
+    /// 配置了令牌签名密钥时节点之间请求使用的管理员令牌
+    token: Option<String>,
+    state: Mutex<State>,
+    changed: Condvar,
+    /// 应用日志、生成和安装快照时持有，表中的数据在此期间与`applied`一致；先于`state`加锁
+    applying: Mutex<()>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Cluster {
+    /// 以`config.node_id`节点的身份加入集群：读取`RAFT`和`RAFTLOG`文件，启动选举、复制和应用日志的线程
+    ///
+    /// 上次退出时已提交但还没有应用的日志在选出主节点后重新应用。
+    pub fn start(db: &Arc<SimpleDB>, config: ClusterConfig) -> Result<Arc<Cluster>> {
+        if config.peers.contains_key(&config.node_id) {
+            return Err(DatabaseError::Config(format!("节点 {} 不能把自己列为其他节点", config.node_id)));
+        }
+        let data_dir = Path::new(&db.config().data_dir);
+        let path = data_dir.join(RAFT_FILE);
+        let crypto = db.master_crypto();
+        let (persistent, log) = load(data_dir, crypto.as_ref())?;
+        let token = match db.config().auth_key {
+            Some(_) => {
+                let claims = HashMap::from([
+                    ("sub".to_string(), Value::String(config.node_id.clone())),
+                    ("admin".to_string(), Value::Bool(true)),
+                ]);
+                Some(db.issue_token(&claims)?)
+            }
+            None => None,
+        };
+        let election_deadline = Instant::now() + random_timeout(config.election_timeout);
+        let cluster = Arc::new(Cluster {
+            db: Arc::downgrade(db),
+            path,
+            crypto,
+            token,
+            state: Mutex::new(State {
+                commit_index: persistent.applied,
+                persistent,
+                log,
+                role: Role::Follower,
+                leader: None,
+                election_deadline,
+                next_index: HashMap::new(),
+                match_index: HashMap::new(),
+                pending: HashMap::new(),
+                last_error: None,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+            applying: Mutex::new(()),
+            threads: Mutex::new(Vec::new()),
+            config,
+        });
+        db.attach_cluster(Arc::clone(&cluster))?;
+        if cluster.config.peers.is_empty() {
+            // 单节点集群不必等待选举超时
+            cluster.campaign();
+        }
+
+        let mut threads = cluster.threads.lock().recover();
+        let elections = Arc::clone(&cluster);
+        threads.push(spawn("simpledb-raft-election", move || elections.run_elections())?);
+        let applier = Arc::clone(&cluster);
+        threads.push(spawn("simpledb-raft-apply", move || applier.apply_committed())?);
+        for (peer, url) in &cluster.config.peers {
+            let replicator = Arc::clone(&cluster);
+            let (peer, url) = (peer.clone(), url.clone());
+            threads.push(spawn("simpledb-raft-replicate", move || replicator.replicate_to(&peer, &url))?);
+        }
+        drop(threads);
+        Ok(cluster)
+    }
+
+    /// 停止后台线程，之后的写入返回错误；数据库关闭时自动调用
+    pub fn stop(&self) {
+        self.lock().stopped = true;
+        self.changed.notify_all();
+        let threads = std::mem::take(&mut *self.threads.lock().recover());
+        let current = std::thread::current().id();
+        for thread in threads {
+            // 最后一个数据库引用可能在后台线程中释放
+            if thread.thread().id() != current {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.config.node_id
+    }
+
+    pub fn status(&self) -> ClusterStatus {
+        let state = self.lock();
+        ClusterStatus {
+            node_id: self.config.node_id.clone(),
+            role: state.role,
+            term: state.persistent.term,
+            leader: state.leader.clone(),
+            last_index: state.last_index(),
+            commit_index: state.commit_index,
+            applied: state.persistent.applied,
+            snapshot_index: state.persistent.snapshot_index,
+            last_error: state.last_error.clone(),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.lock().role == Role::Leader
+    }
+
+    /// 其他节点是主节点时它的API地址
+    pub fn leader_url(&self) -> Option<String> {
+        let state = self.lock();
+        state.leader.as_ref().and_then(|leader| self.config.peers.get(leader).cloned())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().recover()
+    }
+
+    /// 多数节点的个数
+    fn majority(&self) -> usize {
+        let nodes = self.config.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    /// 持久地写`RAFT`文件（fsync后改名），返回后状态已落盘
+    fn persist(&self, persistent: &Persistent) -> Result<()> {
+        statefile::store(&self.path, RAFT_MAGIC, persistent, self.crypto.as_ref())
+    }
+
+    /// 修改`RAFT`文件中的状态，写入失败时恢复原来的状态并返回错误
+    fn update(&self, state: &mut State, change: impl FnOnce(&mut Persistent)) -> Result<()> {
+        let previous = state.persistent.clone();
+        change(&mut state.persistent);
+        let result = self.persist(&state.persistent);
+        if result.is_err() {
+            state.persistent = previous;
+        }
+        result
+    }
+
+    fn not_leader(&self, state: &State) -> DatabaseError {
+        let leader = state.leader.as_ref().and_then(|leader| self.config.peers.get(leader));
+        DatabaseError::NotLeader(match leader {
+            Some(url) => format!("写入应发往主节点 {}", url),
+            None => "正在选举主节点".to_string(),
+        })
+    }
+
+    /// 复制一组写操作，等到提交并在本节点应用后返回应用的结果
+    pub(crate) fn replicate(&self, ops: Vec<WriteOp>) -> Result<()> {
+        let mut state = self.lock();
+        if state.stopped {
+            return Err(DatabaseError::Cluster("节点已停止".to_string()));
+        }
+        if state.role != Role::Leader {
+            return Err(self.not_leader(&state));
+        }
+        let term = state.persistent.term;
+        state.log.append(&[Entry { term, time: unix_now(), ops }])?;
+        let index = state.last_index();
+        state.pending.insert(index, None);
+        self.advance_commit(&mut state);
+        self.changed.notify_all();
+
+        let deadline = Instant::now() + COMMIT_TIMEOUT;
+        loop {
+            if let Some(Some(_)) = state.pending.get(&index) {
+                return state.pending.remove(&index).flatten().unwrap_or(Ok(()));
+            }
+            // 日志被新的主节点覆盖后不会再提交
+            let replaced = state.term_at(index) != Some(term);
+            let now = Instant::now();
+            if replaced || state.stopped || now >= deadline {
+                state.pending.remove(&index);
+                return Err(match replaced {
+                    true => self.not_leader(&state),
+                    false => DatabaseError::Cluster("写入未能在时限内提交到多数节点，可能已经生效".to_string()),
+                });
+            }
+            state = self.changed.wait_timeout(state, deadline - now).recover().0;
+        }
+    }
+
+    /// 主节点上把已复制到多数节点的本任期日志标记为提交
+    fn advance_commit(&self, state: &mut State) {
+        if state.role != Role::Leader {
+            return;
+        }
+        let term = state.persistent.term;
+        for index in (state.commit_index + 1..=state.last_index()).rev() {
+            // 只按本任期的日志计数，之前任期的日志随之提交
+            if state.term_at(index) != Some(term) {
+                break;
+            }
+            let replicas = 1 + state.match_index.values().filter(|&&matched| matched >= index).count();
+            if replicas >= self.majority() {
+                state.commit_index = index;
+                self.changed.notify_all();
+                break;
+            }
+        }
+    }
+
+    /// 遇到更高的任期时成为跟随者；新的任期未能落盘时保持原来的任期并返回错误，不在未落盘的任期中投票或接受日志
+    fn step_down(&self, state: &mut State, term: u64) -> Result<()> {
+        if state.role != Role::Follower {
+            state.become_follower(self.config.election_timeout);
+            self.changed.notify_all();
+        }
+        if term > state.persistent.term {
+            state.leader = None;
+            self.update(state, |persistent| {
+                persistent.term = term;
+                persistent.voted_for = None;
+            })?;
+        }
+        Ok(())
+    }
+
+    /// 后台线程中无法返回的错误，记在`ClusterStatus::last_error`中
+    fn record(&self, state: &mut State, result: Result<()>) {
+        if let Err(e) = result {
+            state.last_error = Some(e.to_string());
+        }
+    }
+
+    fn run_elections(&self) {
+        loop {
+            let timed_out = {
+                let state = self.lock();
+                if state.stopped {
+                    return;
+                }
+                state.role != Role::Leader && Instant::now() >= state.election_deadline
+            };
+            if timed_out {
+                self.campaign();
+            }
+            std::thread::sleep(self.config.heartbeat_interval.min(Duration::from_millis(10)));
+        }
+    }
+
+    /// 发起选举：任期加一，投自己一票并向其他节点拉票，得到多数票后成为主节点
+    fn campaign(&self) {
+        let request = {
+            let mut state = self.lock();
+            state.election_deadline = Instant::now() + random_timeout(self.config.election_timeout);
+            let node_id = self.config.node_id.clone();
+            let result = self.update(&mut state, |persistent| {
+                persistent.term += 1;
+                persistent.voted_for = Some(node_id);
+            });
+            if result.is_err() {
+                self.record(&mut state, result);
+                return;
+            }
+            state.role = Role::Candidate;
+            state.leader = None;
+            VoteRequest {
+                term: state.persistent.term,
+                candidate: self.config.node_id.clone(),
+                last_log_index: state.last_index(),
+                last_log_term: state.term_at(state.last_index()).unwrap_or_default(),
+            }
+        };
+        let replies: Vec<Result<VoteReply>> = std::thread::scope(|scope| {
+            let request = &request;
+            let calls: Vec<_> = self
+                .config
+                .peers
+                .values()
+                .map(|url| scope.spawn(move || self.call(url, "vote", request)))
+                .collect();
+            calls.into_iter().filter_map(|call| call.join().ok()).collect()
+        });
+
+        let mut state = self.lock();
+        let mut votes = 1;
+        for reply in replies.into_iter().flatten() {
+            if reply.term > state.persistent.term {
+                let result = self.step_down(&mut state, reply.term);
+                self.record(&mut state, result);
+                return;
+            }
+            votes += usize::from(reply.granted);
+        }
+        if state.role == Role::Candidate && state.persistent.term == request.term && votes >= self.majority() {
+            self.become_leader(&mut state);
+        }
+    }
+
+    fn become_leader(&self, state: &mut State) {
+        // 追加一条空日志，提交它的同时提交之前任期留下的日志
+        let term = state.persistent.term;
+        let appended = state.log.append(&[Entry { term, time: unix_now(), ops: Vec::new() }]);
+        if appended.is_err() {
+            state.become_follower(self.config.election_timeout);
+            self.record(state, appended);
+            return;
+        }
+        state.role = Role::Leader;
+        state.leader = Some(self.config.node_id.clone());
+        let last = state.last_index();
+        for peer in self.config.peers.keys() {
+            state.next_index.insert(peer.clone(), last);
+            state.match_index.insert(peer.clone(), 0);
+        }
+        self.advance_commit(state);
+        self.changed.notify_all();
+    }
+
+    /// 主节点上向一个跟随者发送日志，没有新日志时按心跳间隔发送空请求；需要的日志已压缩时发送快照
+    fn replicate_to(&self, peer: &str, url: &str) {
+        let heartbeat = self.config.heartbeat_interval;
+        let mut last_sent: Option<Instant> = None;
+        loop {
+            let (request, sent) = {
+                let mut state = self.lock();
+                loop {
+                    if state.stopped {
+                        return;
+                    }
+                    if state.role == Role::Leader {
+                        let pending = state.next_index.get(peer).is_some_and(|&next| next <= state.last_index());
+                        if pending || last_sent.is_none_or(|sent| sent.elapsed() >= heartbeat) {
+                            break;
+                        }
+                    }
+                    state = self.changed.wait_timeout(state, heartbeat).recover().0;
+                }
+                let next = state.next_index.get(peer).copied().unwrap_or(1).max(1);
+                if next <= state.persistent.snapshot_index {
+                    drop(state);
+                    last_sent = Some(Instant::now());
+                    self.send_snapshot(peer, url);
+                    continue;
+                }
+                let prev = next - 1;
+                let entries = state.log.slice(next, MAX_BATCH);
+                let encoded = match bincode::serialize(entries) {
+                    Ok(encoded) => STANDARD.encode(encoded),
+                    Err(_) => return,
+                };
+                let request = AppendRequest {
+                    term: state.persistent.term,
+                    leader: self.config.node_id.clone(),
+                    prev_log_index: prev,
+                    prev_log_term: state.term_at(prev).unwrap_or_default(),
+                    entries: encoded,
+                    leader_commit: state.commit_index,
+                };
+                (request, entries.len() as u64)
+            };
+            last_sent = Some(Instant::now());
+            let reply: Result<AppendReply> = self.call(url, "append", &request);
+
+            let mut state = self.lock();
+            match reply {
+                Ok(reply) if reply.term > state.persistent.term => {
+                    let result = self.step_down(&mut state, reply.term);
+                    self.record(&mut state, result);
+                }
+                Ok(reply) if state.role == Role::Leader && state.persistent.term == request.term => {
+                    if reply.success {
+                        self.matched(&mut state, peer, request.prev_log_index + sent);
+                    } else {
+                        // 跟随者的日志与主节点不一致，向前回退直到找到一致的位置
+                        let next = request.prev_log_index.min(reply.last_index + 1).max(1);
+                        state.next_index.insert(peer.to_string(), next);
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    // 节点不可达，等一个心跳间隔再重试
+                    drop(state);
+                    std::thread::sleep(heartbeat);
+                }
+            }
+        }
+    }
+
+    /// 跟随者确认了到`matched`为止的日志
+    fn matched(&self, state: &mut State, peer: &str, matched: u64) {
+        let current = state.match_index.entry(peer.to_string()).or_default();
+        *current = (*current).max(matched);
+        state.next_index.insert(peer.to_string(), matched + 1);
+        self.advance_commit(state);
+    }
+
+    /// 生成快照：持有`applying`时读出所有表的记录，与已应用的最后一条日志一致
+    fn snapshot(&self) -> Result<SnapshotRequest> {
+        let db = self.db.upgrade().ok_or_else(|| DatabaseError::Cluster("数据库已关闭".to_string()))?;
+        let _applying = self.applying.lock().recover();
+        let (term, last_index, last_term) = {
+            let state = self.lock();
+            let applied = state.persistent.applied;
+            (state.persistent.term, applied, state.term_at(applied).unwrap_or_default())
+        };
+        let mut tables = Vec::new();
+        for name in db.list_tables() {
+            let records = db.find_all(&name)?;
+            tables.push((name, records));
+        }
+        Ok(SnapshotRequest {
+            term,
+            leader: self.config.node_id.clone(),
+            last_index,
+            last_term,
+            tables: STANDARD.encode(bincode::serialize(&tables)?),
+        })
+    }
+
+    /// 向跟随者发送快照，成功后从快照之后的日志继续复制
+    fn send_snapshot(&self, peer: &str, url: &str) {
+        let reply = self.snapshot().and_then(|request| {
+            // 快照可能很大，给足时间
+            let reply: AppendReply = self.call_with_timeout(url, "snapshot", &request, COMMIT_TIMEOUT)?;
+            Ok((request, reply))
+        });
+        let mut state = self.lock();
+        match reply {
+            Ok((_, reply)) if reply.term > state.persistent.term => {
+                let result = self.step_down(&mut state, reply.term);
+                self.record(&mut state, result);
+            }
+            Ok((request, reply)) if reply.success && state.role == Role::Leader && state.persistent.term == request.term => {
+                self.matched(&mut state, peer, request.last_index);
+            }
+            Ok(_) => {}
+            Err(_) => {
+                drop(state);
+                std::thread::sleep(self.config.heartbeat_interval);
+            }
+        }
+    }
+
+    /// 按顺序把已提交的日志应用到表中，每应用`snapshot_interval`条压缩一次日志
+    fn apply_committed(&self) {
+        loop {
+            {
+                let mut state = self.lock();
+                while !state.stopped && state.persistent.applied >= state.commit_index {
+                    state = self.changed.wait(state).recover();
+                }
+                if state.stopped {
+                    return;
+                }
+            }
+            let Some(db) = self.db.upgrade() else {
+                return;
+            };
+            let _applying = self.applying.lock().recover();
+            let (index, entry) = {
+                let state = self.lock();
+                // 等待期间可能安装了快照
+                if state.persistent.applied >= state.commit_index {
+                    continue;
+                }
+                let index = state.persistent.applied + 1;
+                (index, state.log.get(index).cloned().expect("未应用的日志不会被压缩"))
+            };
+            let result = if entry.ops.is_empty() { Ok(()) } else { db.apply_replicated(entry.ops, entry.time) };
+            drop(db);
+
+            let mut state = self.lock();
+            let persisted = self.update(&mut state, |persistent| persistent.applied = index);
+            // 没能记下已应用时内存中也视为已应用，重启后重新应用这条日志
+            state.persistent.applied = index;
+            self.record(&mut state, persisted);
+            if let Some(slot) = state.pending.get_mut(&index) {
+                *slot = Some(result);
+            }
+            if index - state.persistent.snapshot_index >= self.config.snapshot_interval.max(1) {
+                let compacted = self.compact(&mut state);
+                self.record(&mut state, compacted);
+            }
+            self.changed.notify_all();
+        }
+    }
+
+    /// 压缩已应用的日志：它们涉及的表都已保存，表中的数据就是这些日志的快照。
+    /// 先在`RAFT`文件中记下快照位置再重写日志文件，中间崩溃时打开日志文件会跳过已压缩的日志
+    fn compact(&self, state: &mut State) -> Result<()> {
+        let index = state.persistent.applied;
+        let term = state.term_at(index).unwrap_or_default();
+        self.update(state, |persistent| {
+            persistent.snapshot_index = index;
+            persistent.snapshot_term = term;
+        })?;
+        state.log.compact(index)
+    }
+
+    /// 处理其他节点发来的`vote`、`append`或`snapshot`请求
+    pub(crate) fn handle_rpc(&self, kind: &str, body: serde_json::Value) -> Result<serde_json::Value> {
+        if self.lock().stopped {
+            return Err(DatabaseError::Cluster("节点已停止".to_string()));
+        }
+        let invalid = |e: serde_json::Error| DatabaseError::DataFormat(format!("无效的集群请求: {}", e));
+        let reply = match kind {
+            "vote" => serde_json::to_value(self.handle_vote(serde_json::from_value(body).map_err(invalid)?)?),
+            "append" => serde_json::to_value(self.handle_append(serde_json::from_value(body).map_err(invalid)?)?),
+            "snapshot" => serde_json::to_value(self.handle_snapshot(serde_json::from_value(body).map_err(invalid)?)?),
+            _ => return Err(DatabaseError::InvalidQuery(format!("未知的集群请求: {}", kind))),
+        };
+        reply.map_err(invalid)
+    }
+
+    fn handle_vote(&self, request: VoteRequest) -> Result<VoteReply> {
+        let mut state = self.lock();
+        if request.term > state.persistent.term {
+            self.step_down(&mut state, request.term)?;
+        }
+        let last_index = state.last_index();
+        let last_term = state.term_at(last_index).unwrap_or_default();
+        // 只投给日志不比自己旧的候选者，保证新的主节点有全部已提交的日志
+        let up_to_date = (request.last_log_term, request.last_log_index) >= (last_term, last_index);
+        let available = state.persistent.voted_for.as_ref().is_none_or(|voted| *voted == request.candidate);
+        let granted = request.term == state.persistent.term && available && up_to_date;
+        if granted && state.persistent.voted_for.is_none() {
+            self.update(&mut state, |persistent| persistent.voted_for = Some(request.candidate))?;
+        }
+        if granted {
+            state.election_deadline = Instant::now() + random_timeout(self.config.election_timeout);
+        }
+        Ok(VoteReply {
+            term: state.persistent.term,
+            granted,
+        })
+    }
+
+    /// 收到主节点的请求：任期不旧时认主节点并推迟选举，返回是否接受
+    fn follow(&self, state: &mut State, term: u64, leader: &str) -> Result<bool> {
+        if term < state.persistent.term {
+            return Ok(false);
+        }
+        self.step_down(state, term)?;
+        state.leader = Some(leader.to_string());
+        state.election_deadline = Instant::now() + random_timeout(self.config.election_timeout);
+        Ok(true)
+    }
+
+    fn handle_append(&self, request: AppendRequest) -> Result<AppendReply> {
+        let entries: Vec<Entry> = bincode::deserialize(
+            &STANDARD
+                .decode(&request.entries)
+                .map_err(|e| DatabaseError::DataFormat(format!("无效的日志: {}", e)))?,
+        )?;
+        let received = entries.len() as u64;
+        let mut state = self.lock();
+        let reply = |state: &State, success| AppendReply {
+            term: state.persistent.term,
+            success,
+            last_index: state.last_index(),
+        };
+        if !self.follow(&mut state, request.term, &request.leader)? {
+            return Ok(reply(&state, false));
+        }
+        // 快照之前的日志都已提交，与主节点一致
+        let snapshot_index = state.persistent.snapshot_index;
+        if request.prev_log_index >= snapshot_index && state.term_at(request.prev_log_index) != Some(request.prev_log_term) {
+            return Ok(reply(&state, false));
+        }
+
+        let mut new = Vec::new();
+        for (offset, entry) in entries.into_iter().enumerate() {
+            let index = request.prev_log_index + 1 + offset as u64;
+            if index <= snapshot_index {
+                continue;
+            }
+            if new.is_empty() {
+                match state.term_at(index) {
+                    Some(term) if term == entry.term => continue,
+                    // 与主节点冲突的日志没有提交过，连同之后的日志一起丢弃
+                    Some(_) => state.log.truncate(index)?,
+                    None => {}
+                }
+            }
+            new.push(entry);
+        }
+        state.log.append(&new)?;
+        let commit = request.leader_commit.min(request.prev_log_index + received);
+        if commit > state.commit_index {
+            state.commit_index = commit;
+            self.changed.notify_all();
+        }
+        Ok(reply(&state, true))
+    }
+
+    /// 安装主节点的快照：替换各表的记录并保存，丢弃快照包含的日志
+    fn handle_snapshot(&self, request: SnapshotRequest) -> Result<AppendReply> {
+        let tables: Vec<(String, Vec<Arc<Record>>)> = bincode::deserialize(
+            &STANDARD
+                .decode(&request.tables)
+                .map_err(|e| DatabaseError::DataFormat(format!("无效的快照: {}", e)))?,
+        )?;
+        let db = self.db.upgrade().ok_or_else(|| DatabaseError::Cluster("数据库已关闭".to_string()))?;
+        let _applying = self.applying.lock().recover();
+        {
+            let mut state = self.lock();
+            let reply = |state: &State, success| AppendReply {
+                term: state.persistent.term,
+                success,
+                last_index: state.last_index(),
+            };
+            if !self.follow(&mut state, request.term, &request.leader)? {
+                return Ok(reply(&state, false));
+            }
+            // 已经应用到快照之后时不必安装
+            if request.last_index <= state.persistent.applied {
+                return Ok(reply(&state, true));
+            }
+        }
+        db.install_snapshot(tables)?;
+
+        let mut state = self.lock();
+        self.update(&mut state, |persistent| {
+            persistent.applied = request.last_index;
+            persistent.snapshot_index = request.last_index;
+            persistent.snapshot_term = request.last_term;
+        })?;
+        state.log.compact(request.last_index)?;
+        state.commit_index = state.commit_index.max(request.last_index);
+        self.changed.notify_all();
+        Ok(AppendReply {
+            term: state.persistent.term,
+            success: true,
+            last_index: state.last_index(),
+        })
+    }
+
+    fn call<T: DeserializeOwned>(&self, url: &str, kind: &str, request: &impl Serialize) -> Result<T> {
+        let timeout = self.config.election_timeout.max(Duration::from_millis(100));
+        self.call_with_timeout(url, kind, request, timeout)
+    }
+
+    fn call_with_timeout<T: DeserializeOwned>(&self, url: &str, kind: &str, request: &impl Serialize, timeout: Duration) -> Result<T> {
+        let body = serde_json::to_value(request).map_err(|e| DatabaseError::Cluster(e.to_string()))?;
+        let data = sync::post_with(url, &format!("/api/raft/{}", kind), &body, self.token.as_deref(), timeout)?;
+        serde_json::from_value(data).map_err(|e| DatabaseError::Cluster(format!("无效的集群响应: {}", e)))
+    }
+}
+
+/// 读取`RAFT`和`RAFTLOG`文件；旧版本的`RAFT`文件中的日志迁移到`RAFTLOG`文件
+fn load(data_dir: &Path, crypto: Option<&Crypto>) -> Result<(Persistent, Log)> {
+    let path = data_dir.join(RAFT_FILE);
+    let log_path = data_dir.join(RAFT_LOG_FILE);
+    if path.exists() && !statefile::has_magic(&path, RAFT_MAGIC)? {
+        let legacy: LegacyPersistent = statefile::load(&path, LEGACY_RAFT_MAGIC, crypto)?.unwrap_or_default();
+        let log = Log::create(log_path, crypto.cloned(), 0, &legacy.log)?;
+        let persistent = Persistent {
+            term: legacy.term,
+            voted_for: legacy.voted_for,
+            applied: legacy.applied,
+            ..Persistent::default()
+        };
+        statefile::store(&path, RAFT_MAGIC, &persistent, crypto)?;
+        return Ok((persistent, log));
+    }
+    let persistent: Persistent = statefile::load(&path, RAFT_MAGIC, crypto)?.unwrap_or_default();
+    let log = Log::open(log_path, crypto.cloned(), persistent.snapshot_index)?;
+    Ok((persistent, log))
+}
+
+/// 供`SimpleDB::repair`检查`RAFT`和`RAFTLOG`文件，打开日志时截掉末尾写到一半的日志；返回无法读取的文件和原因
+///
+/// `RAFT`文件无法读取时日志也无从使用，两个文件都返回；隔离后节点以空状态重新加入集群，由领导者发送快照。
+pub(crate) fn check_files(data_dir: &Path, crypto: Option<&Crypto>) -> Vec<(&'static str, DatabaseError)> {
+    let path = data_dir.join(RAFT_FILE);
+    let log_path = data_dir.join(RAFT_LOG_FILE);
+    let snapshot_index = if !path.exists() {
+        Ok(0)
+    } else {
+        statefile::has_magic(&path, RAFT_MAGIC).and_then(|current| match current {
+            true => statefile::load::<Persistent>(&path, RAFT_MAGIC, crypto).map(|p| p.unwrap_or_default().snapshot_index),
+            // 旧版本的`RAFT`文件中包含日志，打开时才迁移
+            false => statefile::load::<LegacyPersistent>(&path, LEGACY_RAFT_MAGIC, crypto).map(|_| 0),
+        })
+    };
+    let mut damaged = Vec::new();
+    match snapshot_index {
+        Ok(base) if log_path.exists() => {
+            if let Err(e) = Log::open(log_path, crypto.cloned(), base) {
+                damaged.push((RAFT_LOG_FILE, e));
+            }
+        }
+        Ok(_) => {}
+        Err(e) => {
+            if log_path.exists() {
+                damaged.push((RAFT_LOG_FILE, DatabaseError::DataFormat(format!("{} 无法读取", RAFT_FILE))));
+            }
+            damaged.push((RAFT_FILE, e));
+        }
+    }
+    damaged
+}
+
+fn spawn(name: &str, f: impl FnOnce() + Send + 'static) -> Result<JoinHandle<()>> {
+    Ok(std::thread::Builder::new().name(name.to_string()).spawn(f)?)
+}
+
+/// 在`timeout`和它的两倍之间随机的选举超时，避免多个节点同时发起选举
+fn random_timeout(timeout: Duration) -> Duration {
+    timeout + timeout.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+    use indexmap::IndexMap;
+    use crate::api::DatabaseServer;
+    use crate::Config;
+
+    #[test]
+    fn test_raft_log() {
+        let dir = temp_dir("raft-log");
+        let crypto = Crypto::new(&Crypto::generate_key()).unwrap();
+        let entry = |term| Entry { term, time: 0, ops: Vec::new() };
+        let path = dir.path().join(RAFT_LOG_FILE);
+        let open = |base| Log::open(path.clone(), Some(crypto.clone()), base).unwrap();
+        let terms = |log: &Log| log.entries.iter().map(|entry| entry.term).collect::<Vec<_>>();
+
+        // 追加、截断后重新打开，末尾写到一半的日志被截掉
+        let mut log = open(0);
+        log.append(&[entry(1), entry(1), entry(2)]).unwrap();
+        log.truncate(3).unwrap();
+        log.append(&[entry(3)]).unwrap();
+        std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(&[9, 0, 0, 0, 1]).unwrap();
+        let mut log = open(0);
+        assert_eq!((terms(&log), log.last_index()), (vec![1, 1, 3], 3));
+        assert!(statefile::frames_encrypted(&path, RAFT_LOG_MAGIC, Some(&crypto)));
+
+        // 压缩后只保留之后的日志；记下快照位置后、重写文件前崩溃时跳过已压缩的日志
+        log.append(&[entry(3)]).unwrap();
+        let log = open(2);
+        assert_eq!((terms(&log), log.get(2).is_none(), log.get(3).map(|e| e.term)), (vec![3, 3], true, Some(3)));
+        let mut log = open(0);
+        log.compact(2).unwrap();
+        assert_eq!((terms(&open(2)), open(2).slice(3, 1).len()), (vec![3, 3], 1));
+        assert!(Log::open(path.clone(), Some(crypto.clone()), 1).is_err());
+
+        // 旧版本的RAFT文件中的日志迁移到RAFTLOG文件
+        let legacy = dir.path().join("legacy");
+        std::fs::create_dir(&legacy).unwrap();
+        let old = (5u64, Some("b".to_string()), vec![entry(4), entry(5)], 1u64);
+        statefile::store(&legacy.join(RAFT_FILE), LEGACY_RAFT_MAGIC, &old, Some(&crypto)).unwrap();
+        let (persistent, log) = load(&legacy, Some(&crypto)).unwrap();
+        assert_eq!((persistent.term, persistent.voted_for.as_deref(), persistent.applied), (5, Some("b"), 1));
+        assert_eq!(terms(&log), vec![4, 5]);
+        assert!(statefile::has_magic(&legacy.join(RAFT_FILE), RAFT_MAGIC).unwrap());
+        assert_eq!(terms(&load(&legacy, Some(&crypto)).unwrap().1), vec![4, 5]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_replicated_cluster() {
+        let dir = temp_dir("cluster");
+        let ids = ["a", "b", "c"];
+        let listeners: Vec<_> = ids.iter().map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+        let urls: Vec<String> = listeners
+            .iter()
+            .map(|listener| format!("http://{}", listener.local_addr().unwrap()))
+            .collect();
+        let mut dbs = Vec::new();
+        let mut servers = Vec::new();
+        for (i, listener) in listeners.into_iter().enumerate() {
+            let db = Arc::new(
+                SimpleDB::new(Config {
+                    data_dir: dir.path().join(ids[i]).to_string_lossy().into_owned(),
+                    ..Config::default()
+                })
+                .unwrap(),
+            );
+            let server = DatabaseServer::with_shared(Arc::clone(&db), 0).with_listener(listener);
+            servers.push(tokio::spawn(async move { server.start().await }));
+            dbs.push(db);
+        }
+
+        let (dbs, clusters, stopped) = tokio::task::spawn_blocking(move || {
+            let start = |i: usize| {
+                let peers = (0..ids.len()).filter(|&j| j != i).map(|j| (ids[j].to_string(), urls[j].clone())).collect();
+                let mut config = ClusterConfig::new(ids[i], peers);
+                config.heartbeat_interval = Duration::from_millis(20);
+                config.election_timeout = Duration::from_millis(200);
+                config.snapshot_interval = 4;
+                Cluster::start(&dbs[i], config).unwrap()
+            };
+            // 第三个节点稍后加入，它需要的日志届时已被压缩，由主节点发送快照
+            let mut clusters: Vec<Arc<Cluster>> = vec![start(0), start(1)];
+            let wait = |done: &dyn Fn() -> bool| {
+                let deadline = Instant::now() + Duration::from_secs(10);
+                while !done() {
+                    assert!(Instant::now() < deadline, "集群未能在时限内达到预期状态");
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            };
+            wait(&|| clusters.iter().any(|cluster| cluster.is_leader()));
+            let first = clusters.iter().position(|cluster| cluster.is_leader()).unwrap();
+            for n in 0..10 {
+                dbs[first].insert("early", IndexMap::from([("n".to_string(), Value::Int(n))])).unwrap();
+            }
+            wait(&|| clusters.iter().all(|cluster| cluster.status().snapshot_index >= 8));
+            assert!(clusters[first].status().last_index - clusters[first].status().snapshot_index < 4);
+            clusters.push(start(2));
+            // 安装快照时先替换表再记下快照位置
+            wait(&|| clusters[2].status().snapshot_index >= 8 && dbs[2].count("early").is_ok_and(|count| count == 10));
+            assert_eq!(dbs[2].find_all("early").unwrap(), dbs[first].find_all("early").unwrap());
+
+            let leader_among = |nodes: &[usize]| {
+                let leaders: Vec<usize> = nodes.iter().copied().filter(|&i| clusters[i].is_leader()).collect();
+                let &[leader] = leaders.as_slice() else {
+                    return None;
+                };
+                let known = nodes.iter().all(|&i| clusters[i].status().leader.is_some_and(|l| l == ids[leader]));
+                known.then_some(leader)
+            };
+            wait(&|| leader_among(&[0, 1, 2]).is_some());
+            let leader = leader_among(&[0, 1, 2]).unwrap();
+            let follower = (leader + 1) % 3;
+
+            // 主节点上的写入复制到所有节点，记录的时间戳和ETag一致
+            let data = IndexMap::from([("n".to_string(), Value::Int(1))]);
+            let id = dbs[leader].insert("items", data.clone()).unwrap();
+            wait(&|| dbs.iter().all(|db| db.find_by_id("items", &id).is_ok_and(|r| r.is_some())));
+            let etag = dbs[leader].find_by_id("items", &id).unwrap().unwrap().etag();
+            for db in &dbs {
+                assert_eq!(db.find_by_id("items", &id).unwrap().unwrap().etag(), etag);
+            }
+
+            // 跟随者拒绝写入，无法复制的操作被拒绝
+            assert!(matches!(dbs[follower].insert("items", data.clone()), Err(DatabaseError::NotLeader(_))));
+            assert!(matches!(dbs[leader].patch_all("items", &[]), Err(DatabaseError::NotPermitted(_))));
+            // 事务在每个节点上原子地应用，ETag不一致时都不生效
+            assert!(matches!(
+                dbs[leader].delete_if_match("items", &id, "\"stale\""),
+                Err(DatabaseError::PreconditionFailed(_))
+            ));
+            assert_eq!(dbs[follower].count("items").unwrap(), 1);
+
+            // 主节点停止后其余两个节点选出新的主节点，继续接受写入
+            clusters[leader].stop();
+            let rest: Vec<usize> = (0..3).filter(|&i| i != leader).collect();
+            wait(&|| leader_among(&rest).is_some());
+            let next = leader_among(&rest).unwrap();
+            dbs[next].update("items", &id, IndexMap::from([("n".to_string(), Value::Int(2))])).unwrap();
+            wait(&|| rest.iter().all(|&i| dbs[i].find_by_id("items", &id).unwrap().unwrap().data["n"] == Value::Int(2)));
+            assert!(clusters[next].status().term > clusters[leader].status().term);
+            (dbs, clusters, leader)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(clusters[stopped].status().role, Role::Leader);
+        for server in servers {
+            server.abort();
+            let _ = server.await;
+        }
+        drop(clusters);
+        drop(dbs);
+    }
+}
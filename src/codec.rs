@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::error::{DatabaseError, Result};
+use crate::storage::Record;
+
+/// 表中的全部记录
+pub type Records = HashMap<String, Arc<Record>>;
+
+/// 记录的序列化方式
+///
+/// MessagePack和CBOR有其他语言的通用实现，写出的表文件（未加密时）可以直接被外部工具读取。
+pub trait Codec: Send + Sync {
+    /// 编码名称，用于错误信息
+    fn name(&self) -> &'static str;
+
+    fn encode(&self, records: &Records) -> Result<Vec<u8>>;
+
+    fn decode(&self, data: &[u8]) -> Result<Records>;
+
+    /// 编码单条记录，用于按记录分帧的表文件
+    fn encode_record(&self, record: &Record) -> Result<Vec<u8>>;
+
+    fn decode_record(&self, data: &[u8]) -> Result<Record>;
+}
+
+/// bincode，体积小、速度快，只适合Rust读取
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn name(&self) -> &'static str {
+        "bincode"
+    }
+
+    fn encode(&self, records: &Records) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(records)?)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Records> {
+        Ok(bincode::deserialize(data)?)
+    }
+
+    fn encode_record(&self, record: &Record) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(record)?)
+    }
+
+    fn decode_record(&self, data: &[u8]) -> Result<Record> {
+        Ok(bincode::deserialize(data)?)
+    }
+}
+
+/// MessagePack，结构体按字段名编码
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    fn name(&self) -> &'static str {
+        "messagepack"
+    }
+
+    fn encode(&self, records: &Records) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(records).map_err(|e| codec_error(self, e))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Records> {
+        rmp_serde::from_slice(data).map_err(|e| codec_error(self, e))
+    }
+
+    fn encode_record(&self, record: &Record) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(record).map_err(|e| codec_error(self, e))
+    }
+
+    fn decode_record(&self, data: &[u8]) -> Result<Record> {
+        rmp_serde::from_slice(data).map_err(|e| codec_error(self, e))
+    }
+}
+
+/// CBOR（RFC 8949）
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn name(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn encode(&self, records: &Records) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        ciborium::into_writer(records, &mut data).map_err(|e| codec_error(self, e))?;
+        Ok(data)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Records> {
+        ciborium::from_reader(data).map_err(|e| codec_error(self, e))
+    }
+
+    fn encode_record(&self, record: &Record) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        ciborium::into_writer(record, &mut data).map_err(|e| codec_error(self, e))?;
+        Ok(data)
+    }
+
+    fn decode_record(&self, data: &[u8]) -> Result<Record> {
+        ciborium::from_reader(data).map_err(|e| codec_error(self, e))
+    }
+}
+
+/// 格式化的JSON，便于查看和手工编辑，体积和速度都不如二进制编码
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, records: &Records) -> Result<Vec<u8>> {
+        // 按ID排序，手工编辑后再保存时差异更小
+        let sorted: std::collections::BTreeMap<_, _> = records.iter().collect();
+        let mut data = serde_json::to_vec_pretty(&sorted).map_err(|e| codec_error(self, e))?;
+        data.push(b'\n');
+        Ok(data)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Records> {
+        serde_json::from_slice(data).map_err(|e| codec_error(self, e))
+    }
+
+    fn encode_record(&self, record: &Record) -> Result<Vec<u8>> {
+        serde_json::to_vec(record).map_err(|e| codec_error(self, e))
+    }
+
+    fn decode_record(&self, data: &[u8]) -> Result<Record> {
+        serde_json::from_slice(data).map_err(|e| codec_error(self, e))
+    }
+}
+
+fn codec_error(codec: &dyn Codec, e: impl std::fmt::Display) -> DatabaseError {
+    DatabaseError::DataFormat(format!("{}编码错误: {}", codec.name(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::IndexMap;
+    use crate::storage::Value;
+
+    #[test]
+    fn test_codecs_round_trip() {
+        let mut record = Record::new(IndexMap::from([
+            ("name".to_string(), Value::String("张三".to_string())),
+            ("location".to_string(), Value::GeoPoint { lat: 39.9, lon: 116.4 }),
+            ("tags".to_string(), Value::Array(vec![Value::Int(-1), Value::Null, Value::Bytes(vec![0, 255])])),
+        ]));
+        record.id = "1".to_string();
+        let records = Records::from([("1".to_string(), Arc::new(record))]);
+
+        let codecs: [&dyn Codec; 4] = [&Bincode, &MessagePack, &Cbor, &Json];
+        for codec in codecs {
+            assert_eq!(codec.decode(&codec.encode(&records).unwrap()).unwrap(), records, "{}", codec.name());
+            let record = &records["1"];
+            assert_eq!(codec.decode_record(&codec.encode_record(record).unwrap()).unwrap(), **record, "{}", codec.name());
+        }
+    }
+}
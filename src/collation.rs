@@ -0,0 +1,97 @@
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// 字符串比较函数
+pub type Comparator = fn(&str, &str) -> Ordering;
+
+/// 字符串的排序规则，表的元数据中只保存名称
+#[derive(Debug, Clone)]
+pub struct Collation {
+    pub name: String,
+    pub compare: Comparator,
+}
+
+impl Collation {
+    pub fn new(name: &str, compare: Comparator) -> Self {
+        Self {
+            name: name.to_string(),
+            compare,
+        }
+    }
+
+    /// 内置的排序规则：`binary`按字节（默认）、`nocase`忽略大小写、`natural`其中的数字按数值比较
+    pub fn builtin(name: &str) -> Option<Self> {
+        let compare: Comparator = match name {
+            "binary" => binary,
+            "nocase" => nocase,
+            "natural" => natural,
+            _ => return None,
+        };
+        Some(Self::new(name, compare))
+    }
+}
+
+/// 按字节比较
+pub fn binary(a: &str, b: &str) -> Ordering {
+    a.cmp(b)
+}
+
+/// 按小写形式比较，只有大小写不同的字符串相等
+pub fn nocase(a: &str, b: &str) -> Ordering {
+    a.chars().flat_map(char::to_lowercase).cmp(b.chars().flat_map(char::to_lowercase))
+}
+
+/// 连续的数字按数值比较，使"file2"排在"file10"之前；数值相同时（如"01"和"1"）再按字节比较
+pub fn natural(a: &str, b: &str) -> Ordering {
+    let (mut x, mut y) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        let ordering = match (x.peek(), y.peek()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(c), Some(d)) if c.is_ascii_digit() && d.is_ascii_digit() => {
+                let (m, n) = (digits(&mut x), digits(&mut y));
+                m.len().cmp(&n.len()).then_with(|| m.cmp(&n))
+            }
+            (Some(c), Some(d)) => {
+                let ordering = c.cmp(d);
+                x.next();
+                y.next();
+                ordering
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// 取出连续的数字，去掉开头的零
+fn digits(chars: &mut Peekable<Chars>) -> String {
+    let mut digits = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        if !(digits.is_empty() && c == '0') {
+            digits.push(c);
+        }
+    }
+    digits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_collations() {
+        let mut files = vec!["file10", "file2", "File1", "file02b", "file2a"];
+        files.sort_by(|a, b| natural(a, b));
+        assert_eq!(files, ["File1", "file2", "file2a", "file02b", "file10"]);
+        assert_eq!(natural("v1.10", "v1.9"), Ordering::Greater);
+
+        assert_eq!(nocase("Apple", "apple"), Ordering::Equal);
+        assert_eq!(nocase("apple", "Banana"), Ordering::Less);
+        assert_eq!(binary("apple", "Banana"), Ordering::Greater);
+        assert!(Collation::builtin("icu").is_none());
+    }
+}
@@ -0,0 +1,163 @@
+use indexmap::IndexMap;
+use std::borrow::Cow;
+use std::fmt;
+
+use zstd::bulk::{Compressor, Decompressor};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+use crate::error::{DatabaseError, Result};
+use crate::storage::{Record, Value};
+
+/// 表的压缩字典文件的扩展名；扩展名不是`.db`，不会被当作表加载
+pub const DICTIONARY_EXTENSION: &str = "dict";
+
+/// zstd的压缩级别
+const LEVEL: i32 = 3;
+
+/// 训练出的字典的最大字节数
+const MAX_DICTIONARY_SIZE: usize = 16 * 1024;
+
+/// 表的值压缩：不小于阈值的字符串和字节串值以zstd压缩后保存在内存和表文件中，读取时解压
+///
+/// 可以使用从表中的值训练出的字典，短小而相似的值（如日志消息）用字典压缩效果好得多。
+/// 压缩后不比原值小的值保持原样。
+pub(crate) struct ValueCodec {
+    threshold: usize,
+    dictionary: Option<Vec<u8>>,
+    encoder: Option<EncoderDictionary<'static>>,
+    decoder: Option<DecoderDictionary<'static>>,
+}
+
+impl fmt::Debug for ValueCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValueCodec")
+            .field("threshold", &self.threshold)
+            .field("dictionary", &self.dictionary.as_ref().map(Vec::len))
+            .finish()
+    }
+}
+
+impl ValueCodec {
+    pub(crate) fn new(threshold: usize, dictionary: Option<Vec<u8>>) -> Self {
+        Self {
+            threshold,
+            encoder: dictionary.as_deref().map(|d| EncoderDictionary::copy(d, LEVEL)),
+            decoder: dictionary.as_deref().map(DecoderDictionary::copy),
+            dictionary,
+        }
+    }
+
+    pub(crate) fn dictionary(&self) -> Option<&[u8]> {
+        self.dictionary.as_deref()
+    }
+
+    fn compress_bytes(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut compressor = match &self.encoder {
+            Some(encoder) => Compressor::with_prepared_dictionary(encoder),
+            None => Compressor::new(LEVEL),
+        }
+        .ok()?;
+        compressor.compress(data).ok().filter(|compressed| compressed.len() < data.len())
+    }
+
+    fn decompress_bytes(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let size = zstd::zstd_safe::get_frame_content_size(data).ok()??;
+        let mut decompressor = match &self.decoder {
+            Some(decoder) => Decompressor::with_prepared_dictionary(decoder),
+            None => Decompressor::new(),
+        }
+        .ok()?;
+        decompressor.decompress(data, usize::try_from(size).ok()?).ok()
+    }
+
+    /// 压缩后的值，不需要或不值得压缩时为None
+    fn compress_value(&self, value: &Value) -> Option<Value> {
+        let (binary, data) = match value {
+            Value::String(s) if s.len() >= self.threshold => (false, s.as_bytes()),
+            Value::Bytes(b) if b.len() >= self.threshold => (true, b.as_slice()),
+            _ => return None,
+        };
+        Some(Value::Compressed {
+            binary,
+            data: self.compress_bytes(data)?,
+        })
+    }
+
+    /// 解压后的值，不是压缩值时为None；无法解压（如字典丢失）的值保持原样
+    fn expand_value(&self, value: &Value) -> Option<Value> {
+        let Value::Compressed { binary, data } = value else {
+            return None;
+        };
+        let data = self.decompress_bytes(data)?;
+        if *binary {
+            Some(Value::Bytes(data))
+        } else {
+            String::from_utf8(data).ok().map(Value::String)
+        }
+    }
+
+    /// 压缩记录数据中较大的值
+    pub(crate) fn compress(&self, mut data: IndexMap<String, Value>) -> IndexMap<String, Value> {
+        for value in data.values_mut() {
+            if let Some(compressed) = self.compress_value(value) {
+                *value = compressed;
+            }
+        }
+        data
+    }
+
+    /// 解压记录中的压缩值，没有压缩值时不复制记录
+    pub(crate) fn expand<'a>(&self, record: &'a Record) -> Cow<'a, Record> {
+        if !record.data.values().any(|value| matches!(value, Value::Compressed { .. })) {
+            return Cow::Borrowed(record);
+        }
+        let mut expanded = record.clone();
+        for value in expanded.data.values_mut() {
+            if let Some(plain) = self.expand_value(value) {
+                *value = plain;
+            }
+        }
+        Cow::Owned(expanded)
+    }
+
+}
+
+/// 用记录中不小于`threshold`字节的字符串和字节串值训练压缩字典
+pub(crate) fn train_dictionary<'a>(threshold: usize, records: impl Iterator<Item = &'a Record>) -> Result<Vec<u8>> {
+    let samples: Vec<&[u8]> = records
+        .flat_map(|record| record.data.values())
+        .filter_map(|value| match value {
+            Value::String(s) => Some(s.as_bytes()),
+            Value::Bytes(b) => Some(b.as_slice()),
+            _ => None,
+        })
+        .filter(|sample| sample.len() >= threshold)
+        .collect();
+    zstd::dict::from_samples(&samples, MAX_DICTIONARY_SIZE)
+        .map_err(|e| DatabaseError::InvalidQuery(format!("样本不足，无法训练压缩字典: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_values() {
+        let codec = ValueCodec::new(64, None);
+        let message = "connection reset by peer; retrying in 5 seconds ".repeat(8);
+        let record = Record::new(IndexMap::from([
+            ("message".to_string(), Value::String(message.clone())),
+            ("blob".to_string(), Value::Bytes(vec![0; 1000])),
+            ("level".to_string(), Value::String("warn".to_string())),
+        ]));
+        let compressed = Record {
+            data: codec.compress(record.data.clone()),
+            ..record.clone()
+        };
+        assert!(matches!(compressed.data["message"], Value::Compressed { binary: false, .. }));
+        assert!(matches!(compressed.data["blob"], Value::Compressed { binary: true, .. }));
+        assert_eq!(compressed.data["level"], Value::String("warn".to_string()));
+        assert_eq!(codec.expand(&compressed).into_owned(), record);
+        assert!(matches!(codec.expand(&record), Cow::Borrowed(_)));
+    }
+}
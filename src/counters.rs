@@ -0,0 +1,120 @@
+//! 表级的读写计数器
+//!
+//! 每张表自打开以来的读取、写入、全表扫描、索引命中与未命中次数和写入表文件的字节数，
+//! 用于找出热点表而不需要外部的性能分析工具。计数只保存在内存中，重启后从零开始。
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// 一张表的计数器，读操作只持有表的读锁，因此用原子整数累加
+#[derive(Debug, Default)]
+pub(crate) struct TableCounters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    scans: AtomicU64,
+    index_hits: AtomicU64,
+    index_misses: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl TableCounters {
+    pub(crate) fn read(&self) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn write(&self) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn scan(&self) {
+        self.scans.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn index_hit(&self) {
+        self.index_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn index_miss(&self) {
+        self.index_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn bytes_written(&self, bytes: usize) {
+        self.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Counters {
+        Counters {
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            scans: self.scans.load(Ordering::Relaxed),
+            index_hits: self.index_hits.load(Ordering::Relaxed),
+            index_misses: self.index_misses.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 计数器某一时刻的值，见`SimpleDB::table_counters`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Counters {
+    /// 按ID读取、查询等读操作的次数
+    pub reads: u64,
+    /// 插入、更新、删除等修改表的次数
+    pub writes: u64,
+    /// 遍历全部记录的次数
+    pub scans: u64,
+    /// 由等值索引取得候选记录的查询次数
+    pub index_hits: u64,
+    /// 带条件但没有使用索引、扫描了全表的查询次数
+    pub index_misses: u64,
+    /// 保存时写入表文件和段文件的字节数
+    pub bytes_written: u64,
+}
+
+impl Counters {
+    /// 各计数器在Prometheus文本格式中的名称、类型说明和值
+    pub fn metrics(&self) -> [(&'static str, &'static str, u64); 6] {
+        [
+            ("simpledb_table_reads_total", "Read operations served by the table", self.reads),
+            ("simpledb_table_writes_total", "Write operations applied to the table", self.writes),
+            ("simpledb_table_scans_total", "Full table scans", self.scans),
+            ("simpledb_table_index_hits_total", "Queries answered from an index", self.index_hits),
+            ("simpledb_table_index_misses_total", "Filtered queries that scanned the table", self.index_misses),
+            ("simpledb_table_bytes_written_total", "Bytes written to table files", self.bytes_written),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::Query;
+    use crate::storage::Value;
+    use crate::testing::{open, request, serve};
+    use crate::IndexMap;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_table_counters() {
+        let (_dir, db) = open("counters");
+        let db = Arc::new(db);
+        let user = |n: &str| IndexMap::from([("name".to_string(), Value::String(n.to_string()))]);
+        let id = db.insert("users", user("alice")).unwrap();
+        db.insert("users", user("bob")).unwrap();
+        db.find_by_id("users", &id).unwrap();
+        db.query("users", &Query::eq("name", Value::String("bob".to_string()))).unwrap();
+        db.create_index("users", "name").unwrap();
+        db.query("users", &Query::eq("name", Value::String("bob".to_string())).use_index("name")).unwrap();
+        db.save_all().unwrap();
+
+        let counters = db.table_counters("users").unwrap();
+        assert_eq!((counters.writes, counters.index_hits, counters.index_misses), (2, 1, 1));
+        assert!(counters.reads >= 3 && counters.scans >= 1 && counters.bytes_written > 0);
+
+        let (address, serving) = serve(Arc::clone(&db));
+        let response = request(address, "GET /metrics HTTP/1.1", "").await;
+        assert!(response.contains("# TYPE simpledb_table_writes_total counter"), "{}", response);
+        assert!(response.contains("simpledb_table_index_hits_total{table=\"users\"} 1"), "{}", response);
+        serving.abort();
+        let _ = serving.await;
+    }
+}
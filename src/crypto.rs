@@ -1,28 +1,76 @@
 use aes_gcm::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
     Aes256Gcm, Key, Nonce,
 };
+use aes_gcm_siv::Aes256GcmSiv;
+use argon2::{Algorithm, Argon2, Params, Version};
 use rand::RngCore;
+use std::path::Path;
+use std::str::FromStr;
 
 use crate::error::{DatabaseError, Result};
 
+/// 受口令保护的密钥文件开头的魔数
+pub const KEY_FILE_MAGIC: &[u8; 6] = b"SDBKEY";
+
+/// 受口令保护的密钥文件的格式版本
+const KEY_FILE_VERSION: u8 = 1;
+
+/// 魔数、版本、Argon2参数（内存KiB、迭代次数、并行度）和16字节盐
+const KEY_FILE_HEADER_LEN: usize = 6 + 1 + 12 + 16;
+
+/// 导入时接受的Argon2参数范围，依次为内存KiB、迭代次数和并行度；下限为`export_key`写入的默认值
+///
+/// 文件头要到派生出密钥之后才能认证，不限制时被篡改的密钥文件可以让导入耗尽内存或长时间占用CPU。
+const ARGON2_LIMITS: [(u32, u32); 3] = [(19 * 1024, 1024 * 1024), (2, 16), (1, 16)];
+
+/// 加密算法
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Cipher {
+    /// AES-256-GCM，同一密钥下重复使用nonce会泄露明文并允许伪造
+    #[default]
+    Aes256Gcm,
+    /// AES-256-GCM-SIV（RFC 8452），nonce重复时只泄露两段明文是否相同，适合加密次数极多的表
+    Aes256GcmSiv,
+}
+
+impl FromStr for Cipher {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "aes-gcm" => Ok(Cipher::Aes256Gcm),
+            "aes-gcm-siv" => Ok(Cipher::Aes256GcmSiv),
+            other => Err(format!("不支持的加密算法: {}（可选 aes-gcm、aes-gcm-siv）", other)),
+        }
+    }
+}
+
 /// 加密器，负责数据的加密和解密
 #[derive(Clone)]
 pub struct Crypto {
-    cipher: Aes256Gcm,
+    gcm: Aes256Gcm,
+    siv: Aes256GcmSiv,
+    /// 加密使用的算法；两种算法的密文都能解密
+    cipher: Cipher,
 }
 
 impl std::fmt::Debug for Crypto {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Crypto")
-            .field("cipher", &"<AES-GCM cipher>")
+            .field("cipher", &self.cipher)
             .finish()
     }
 }
 
 impl Crypto {
-    /// 从提供的密钥创建加密器
+    /// 从提供的密钥创建使用AES-256-GCM的加密器
     pub fn new(key: &[u8]) -> Result<Self> {
+        Self::with_cipher(key, Cipher::default())
+    }
+
+    /// 从提供的密钥创建使用指定算法加密的加密器
+    pub fn with_cipher(key: &[u8], cipher: Cipher) -> Result<Self> {
         if key.len() != 32 {
             return Err(DatabaseError::Encryption(
                 "密钥长度必须为32字节".to_string(),
@@ -30,9 +78,21 @@ impl Crypto {
         }
 
         let key = Key::<Aes256Gcm>::from_slice(key);
-        let cipher = Aes256Gcm::new(key);
+        Ok(Self {
+            gcm: Aes256Gcm::new(key),
+            siv: Aes256GcmSiv::new(key),
+            cipher,
+        })
+    }
 
-        Ok(Self { cipher })
+    /// 加密使用的算法
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// 从密钥文件创建加密器，文件内容为32字节原始密钥或其十六进制文本
+    pub fn from_key_file(path: &Path) -> Result<Self> {
+        Self::new(&read_key_file(path)?)
     }
 
     /// 生成随机密钥
@@ -42,16 +102,102 @@ impl Crypto {
         key
     }
 
+    /// 把密钥写入受口令保护的密钥文件：用Argon2id从口令派生加密密钥，再用AES-256-GCM加密原始密钥
+    ///
+    /// 文件头（含Argon2参数和盐）作为附加认证数据，被篡改时无法导入。
+    pub fn export_key(key: &[u8], path: &Path, passphrase: &str) -> Result<()> {
+        let mut header = Vec::with_capacity(KEY_FILE_HEADER_LEN);
+        header.extend_from_slice(KEY_FILE_MAGIC);
+        header.push(KEY_FILE_VERSION);
+        for n in [Params::DEFAULT_M_COST, Params::DEFAULT_T_COST, Params::DEFAULT_P_COST] {
+            header.extend_from_slice(&n.to_le_bytes());
+        }
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        header.extend_from_slice(&salt);
+
+        let kek = Self::derive_kek(&header, passphrase)?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = kek
+            .gcm
+            .encrypt(&nonce, Payload { msg: key, aad: &header })
+            .map_err(|e| DatabaseError::Encryption(format!("加密失败: {}", e)))?;
+
+        let mut content = header;
+        content.extend_from_slice(&nonce);
+        content.extend_from_slice(&ciphertext);
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// 读取`export_key`写出的密钥文件，口令错误或文件被篡改时返回`Encryption`错误
+    pub fn import_key(path: &Path, passphrase: &str) -> Result<Vec<u8>> {
+        let content = std::fs::read(path)?;
+        if !content.starts_with(KEY_FILE_MAGIC) {
+            return Err(DatabaseError::Encryption("不是受口令保护的密钥文件".to_string()));
+        }
+        if content.len() < KEY_FILE_HEADER_LEN + 12 {
+            return Err(DatabaseError::Encryption("密钥文件不完整".to_string()));
+        }
+        if content[KEY_FILE_MAGIC.len()] != KEY_FILE_VERSION {
+            return Err(DatabaseError::Encryption(format!(
+                "不支持的密钥文件版本: {}",
+                content[KEY_FILE_MAGIC.len()]
+            )));
+        }
+        let (header, rest) = content.split_at(KEY_FILE_HEADER_LEN);
+        let (nonce, ciphertext) = rest.split_at(12);
+        Self::derive_kek(header, passphrase)?
+            .gcm
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: header })
+            .map_err(|_| DatabaseError::Encryption("口令错误或密钥文件已损坏".to_string()))
+    }
+
+    /// 按文件头中的Argon2参数和盐从口令派生加密密钥的加密器
+    fn derive_kek(header: &[u8], passphrase: &str) -> Result<Self> {
+        let field = |i: usize| {
+            let start = KEY_FILE_MAGIC.len() + 1 + i * 4;
+            u32::from_le_bytes(header[start..start + 4].try_into().unwrap())
+        };
+        for (i, (name, (min, max))) in ["内存", "迭代次数", "并行度"].into_iter().zip(ARGON2_LIMITS).enumerate() {
+            if !(min..=max).contains(&field(i)) {
+                return Err(DatabaseError::Encryption(format!(
+                    "密钥文件的Argon2{} {} 超出允许的范围 {}..={}",
+                    name,
+                    field(i),
+                    min,
+                    max
+                )));
+            }
+        }
+        let params = Params::new(field(0), field(1), field(2), Some(32))
+            .map_err(|e| DatabaseError::Encryption(format!("无效的Argon2参数: {}", e)))?;
+        let mut kek = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &header[KEY_FILE_HEADER_LEN - 16..], &mut kek)
+            .map_err(|e| DatabaseError::Encryption(format!("派生密钥失败: {}", e)))?;
+        Self::new(&kek)
+    }
+
     /// 加密数据
     pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.encrypt_with_aad(data, &[])
+    }
+
+    /// 加密数据，`aad`作为附加认证数据参与认证但不包含在密文中，解密时必须提供相同的`aad`
+    pub fn encrypt_with_aad(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
         // 生成随机nonce
         let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
         
         // 加密数据
-        let ciphertext = self
-            .cipher
-            .encrypt(&nonce, data)
-            .map_err(|e| DatabaseError::Encryption(format!("加密失败: {}", e)))?;
+        let payload = Payload { msg: data, aad };
+        let ciphertext = match self.cipher {
+            Cipher::Aes256Gcm => self.gcm.encrypt(&nonce, payload),
+            Cipher::Aes256GcmSiv => self.siv.encrypt(&nonce, payload),
+        }
+        .map_err(|e| DatabaseError::Encryption(format!("加密失败: {}", e)))?;
 
         // 将nonce和密文组合
         let mut result = Vec::with_capacity(12 + ciphertext.len());
@@ -61,8 +207,23 @@ impl Crypto {
         Ok(result)
     }
 
-    /// 解密数据
+    /// 解密数据；先按加密使用的算法，失败时再按另一种算法，更换算法之前写出的数据仍可读取
     pub fn decrypt(&self, encrypted_data: &[u8]) -> Result<Vec<u8>> {
+        let other = match self.cipher {
+            Cipher::Aes256Gcm => Cipher::Aes256GcmSiv,
+            Cipher::Aes256GcmSiv => Cipher::Aes256Gcm,
+        };
+        self.decrypt_with(self.cipher, encrypted_data)
+            .or_else(|e| self.decrypt_with(other, encrypted_data).map_err(|_| e))
+    }
+
+    /// 按指定的算法解密数据，用于文件头记录了算法的表文件
+    pub fn decrypt_with(&self, cipher: Cipher, encrypted_data: &[u8]) -> Result<Vec<u8>> {
+        self.decrypt_with_aad(cipher, encrypted_data, &[])
+    }
+
+    /// 按指定的算法解密`encrypt_with_aad`加密的数据
+    pub fn decrypt_with_aad(&self, cipher: Cipher, encrypted_data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
         if encrypted_data.len() < 12 {
             return Err(DatabaseError::Encryption(
                 "加密数据太短，至少需要12字节".to_string(),
@@ -74,18 +235,42 @@ impl Crypto {
         let nonce = Nonce::from_slice(nonce_bytes);
 
         // 解密数据
-        let plaintext = self
-            .cipher
-            .decrypt(nonce, ciphertext)
-            .map_err(|e| DatabaseError::Encryption(format!("解密失败: {}", e)))?;
+        let payload = Payload { msg: ciphertext, aad };
+        let plaintext = match cipher {
+            Cipher::Aes256Gcm => self.gcm.decrypt(nonce, payload),
+            Cipher::Aes256GcmSiv => self.siv.decrypt(nonce, payload),
+        }
+        .map_err(|e| DatabaseError::Encryption(format!("解密失败: {}", e)))?;
 
         Ok(plaintext)
     }
 }
 
+/// 读取密钥文件，文件内容为32字节原始密钥或其十六进制文本
+///
+/// 受口令保护的密钥文件需要用`Crypto::import_key`读取。
+pub fn read_key_file(path: &Path) -> Result<Vec<u8>> {
+    let content = std::fs::read(path)?;
+    if content.starts_with(KEY_FILE_MAGIC) {
+        return Err(DatabaseError::Encryption(format!(
+            "密钥文件 {} 受口令保护，需要提供口令",
+            path.display()
+        )));
+    }
+    let text = String::from_utf8_lossy(&content);
+    let text = text.trim();
+    if text.len() == 64 {
+        if let Ok(key) = hex::decode(text) {
+            return Ok(key);
+        }
+    }
+    Ok(content)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::temp_dir;
 
     #[test]
     fn test_encrypt_decrypt() {
@@ -98,4 +283,50 @@ mod tests {
         
         assert_eq!(data, &decrypted[..]);
     }
+
+    #[test]
+    fn test_cipher_selection() {
+        let key = Crypto::generate_key();
+        let gcm = Crypto::new(&key).unwrap();
+        let siv = Crypto::with_cipher(&key, Cipher::Aes256GcmSiv).unwrap();
+
+        let encrypted = siv.encrypt(b"secret").unwrap();
+        assert!(siv.decrypt_with(Cipher::Aes256Gcm, &encrypted).is_err());
+        assert_eq!(siv.decrypt_with(Cipher::Aes256GcmSiv, &encrypted).unwrap(), b"secret");
+        // 换回AES-GCM之后仍能读取
+        assert_eq!(gcm.decrypt(&encrypted).unwrap(), b"secret");
+        assert_eq!(siv.decrypt(&gcm.encrypt(b"secret").unwrap()).unwrap(), b"secret");
+        assert!(Crypto::new(&Crypto::generate_key()).unwrap().decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn test_export_import_key() {
+        let dir = temp_dir("key");
+        let path = dir.path().join("keyNone");
+        let key = Crypto::generate_key();
+        Crypto::export_key(&key, &path, "correct horse").unwrap();
+
+        let content = std::fs::read(&path).unwrap();
+        assert!(!content.windows(key.len()).any(|w| w == key));
+        assert_eq!(Crypto::import_key(&path, "correct horse").unwrap(), key);
+        assert!(Crypto::import_key(&path, "wrong").is_err());
+        assert!(read_key_file(&path).is_err());
+
+        // 篡改文件头中的参数后无法导入
+        let mut tampered = content.clone();
+        tampered[KEY_FILE_MAGIC.len() + 5] ^= 1;
+        std::fs::write(&path, tampered).unwrap();
+        assert!(Crypto::import_key(&path, "correct horse").is_err());
+
+        // 参数超出范围时在派生密钥之前拒绝
+        for (i, value) in [(0, u32::MAX), (1, 1), (2, 255)] {
+            let mut tampered = content.clone();
+            let start = KEY_FILE_MAGIC.len() + 1 + i * 4;
+            tampered[start..start + 4].copy_from_slice(&value.to_le_bytes());
+            std::fs::write(&path, tampered).unwrap();
+            let error = Crypto::import_key(&path, "correct horse").unwrap_err().to_string();
+            assert!(error.contains("超出允许的范围"), "{}", error);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
 } 
\ No newline at end of file
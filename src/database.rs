@@ -1,45 +1,314 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use indexmap::IndexMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use crate::api::DatabaseServer;
+use crate::backup;
+use crate::blob::{BlobStore, BlobUsage, BLOB_EXTENSION};
+use crate::bundle;
+use crate::changes::{ChangeFeed, CHANGES_FILE, CHANGES_MAGIC};
+use crate::cdc::{self, CDC_FILE};
+use crate::cluster::{Cluster, RAFT_FILE, RAFT_LOG_FILE, RAFT_LOG_MAGIC};
+use crate::dirlock::{DirLock, LOCK_FILE};
+use crate::collation::Collation;
+use crate::compress::DICTIONARY_EXTENSION;
+use crate::counters::Counters;
 use crate::crypto::Crypto;
 use crate::error::{DatabaseError, Result};
-use crate::storage::{Record, Table, Value};
-use crate::Config;
+use crate::format::{self, Damage, FileOptions};
+use crate::graph::{self, Subgraph, Traversal};
+use crate::http;
+use crate::index::{FieldIndex, IndexBuildProgress, IndexInfo};
+use crate::keyring::{Keyring, KEYRING_FILE};
+use crate::lock::{Held, Recover, TableLocks, Wait};
+use crate::manifest::{Manifest, VerifyReport, MANIFEST_FILE};
+use crate::meta::{Cap, META_EXTENSION};
+use crate::plaintext::PLAINTEXT_EXTENSION;
+use crate::pipeline::{Document, Pipeline};
+use crate::policy::{self, Caller, Claims, Policy};
+use crate::prepared::{PreparedQueries, PreparedQuery, PREPARED_FILE};
+use crate::projection::{Projections, Reducer};
+use crate::query::{Condition, IndexHint, Query, QueryPlan};
+use crate::quota::Usage;
+use crate::repair::{self, RepairReport};
+use crate::schedule::{Job, ScheduledJob, Schedules, SCHEDULE_FILE};
+use crate::schema::{FieldType, SchemaSample, TableMode};
+use crate::security::{FileSecurity, SecurityReport, TableSecurity};
+use crate::siv::Siv;
+use crate::statefile;
+use crate::sql::{self, Aggregate, SqlColumn, SqlResult};
+use crate::stats::TableStats;
+use crate::telemetry::{SpanKind, TraceContext, Tracer};
+use crate::sync::{self as sync, Conflict, Digest, SyncEntry, SyncOffer, SyncReport, SyncState, SYNC_FILE};
+use crate::storage::{self, Record, Table, TableInfo, Value, PARTS_EXTENSION, PARTS_MAGIC};
+use crate::system;
+use crate::timeseries::{self, TimeSeries};
+use crate::transaction::{self, ReadTransaction, Transaction, WriteOp};
+use crate::update::UpdateOp;
+use crate::validate::{FieldError, Validator};
+use crate::vector::Metric;
+use crate::{Config, TableConfig};
+
+/// 数据目录中存放租户分区的子目录
+pub const TENANTS_DIR: &str = "tenants";
+
+/// 恢复时写入备份内容的暂存目录，全部落盘后改名为`RESTORE_DIR`即提交
+const RESTORE_STAGING_DIR: &str = "restore.tmp";
+
+/// 已提交、尚未替换到数据目录中的恢复内容：`files`子目录中的文件和列出备份全部文件名的`NAMES`
+const RESTORE_DIR: &str = "restore";
+
+/// 共享的表句柄，每张表有独立的读写锁
+type TableHandle = Arc<RwLock<Table>>;
+
+/// 撤销一项已应用的事务操作所需的信息
+enum Undo {
+    /// 删除事务中插入的记录
+    Remove(String),
+    /// 恢复被更新或删除的记录
+    Restore(Arc<Record>),
+    Nothing,
+}
+
+/// 合并数据目录时，同一ID的记录两边都存在且内容不同时的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// 保留更新时间较晚的记录
+    #[default]
+    Newest,
+    /// 保留已有的记录
+    Skip,
+    /// 报错，不写入任何记录
+    Error,
+}
+
+impl FromStr for MergeStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "newest" => Ok(MergeStrategy::Newest),
+            "skip" => Ok(MergeStrategy::Skip),
+            "error" => Ok(MergeStrategy::Error),
+            other => Err(format!("不支持的合并策略: {}（可选 newest、skip、error）", other)),
+        }
+    }
+}
+
+/// 合并结果统计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergeReport {
+    /// 新增的记录数
+    pub inserted: usize,
+    /// 被较新记录替换的记录数
+    pub replaced: usize,
+    /// 保留原记录（含内容完全相同）的记录数
+    pub skipped: usize,
+}
+
+/// 销毁数据库的结果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DestroyReport {
+    /// 被删除的文件名
+    pub removed: Vec<String>,
+    /// 不能确认属于数据库而保留的文件名
+    pub kept: Vec<String>,
+}
+
+/// 打开数据库时各表文件中因损坏而跳过的部分
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    /// 只列出有损坏的表
+    pub damaged: BTreeMap<String, Vec<Damage>>,
+}
+
+impl LoadReport {
+    pub fn is_clean(&self) -> bool {
+        self.damaged.is_empty()
+    }
+}
 
 /// 简单数据库
+///
+/// 所有操作只需`&self`：表目录由一把读写锁保护，每张表再各自加锁，
+/// 因此对不同表的读写可以并发进行。
 pub struct SimpleDB {
     config: Config,
-    tables: HashMap<String, Table>,
+    tables: RwLock<HashMap<String, TableHandle>>,
     crypto: Option<Crypto>,
+    /// `Config::table_keys`中的密钥，按密钥ID组织
+    table_keys: HashMap<String, Crypto>,
+    scan_pool: Option<Arc<ThreadPool>>,
+    changes: Arc<ChangeFeed>,
+    /// 数据目录的共享锁，打开期间其他进程不能修复数据目录
+    _dir_lock: DirLock,
+    /// 按主体的数据密钥，配置了主密钥时才有
+    keyring: Option<Keyring>,
+    /// 由主密钥派生的确定性加密密钥
+    siv: Option<Siv>,
+    /// 表文件清单
+    manifest: Arc<Manifest>,
+    /// 事件表投影的检查点
+    projections: Projections,
+    /// 与其他实例同步的状态，第一次同步时读取
+    sync: Mutex<Option<SyncState>>,
+    /// 定时任务，第一次使用时读取
+    schedules: Mutex<Option<Schedules>>,
+    /// 已注册的预备查询
+    prepared: PreparedQueries,
+    /// `Config::quota`的用量，所有表共享
+    usage: Option<Arc<Usage>>,
+    /// 已打开的租户分区，最近使用的排在最后
+    tenants: Mutex<IndexMap<String, Arc<SimpleDB>>>,
+    /// 所有表共享的blob存储
+    blobs: Arc<BlobStore>,
+    /// 配置了`Config::otlp_endpoint`时导出操作的span
+    tracer: Option<Arc<Tracer>>,
+    /// 只读打开时上次加载的各表文件，供`reload_changed`比较
+    fingerprints: Mutex<HashMap<String, Fingerprint>>,
+    /// `set_validator`注册的校验函数，按表名组织；表打开或重新加载时设置到表上
+    validators: RwLock<HashMap<String, Validator>>,
+    /// 由`Cluster::start`附加，之后的写入经过Raft复制
+    cluster: OnceLock<Arc<Cluster>>,
 }
 
+/// 一张表的各个文件的大小和修改时间，按文件名组织
+type Fingerprint = BTreeMap<String, (u64, SystemTime)>;
+
 impl SimpleDB {
+    /// 数据库的配置
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
     /// 创建新的数据库实例
-    pub fn new(config: Config) -> Result<Self> {
+    ///
+    /// 配置了`bundle`时从该文件读出所有文件到临时的工作目录，`data_dir`被替换为工作目录。
+    pub fn new(mut config: Config) -> Result<Self> {
+        if let Some(bundle) = &config.bundle {
+            let work_dir = std::env::temp_dir().join(format!("simpledb-bundle-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&work_dir)?;
+            if Path::new(bundle).exists() {
+                for (name, content) in bundle::read_bundle(Path::new(bundle))? {
+                    check_file_name(&name)?;
+                    std::fs::write(work_dir.join(name), content)?;
+                }
+            }
+            config.data_dir = work_dir.to_string_lossy().into_owned();
+        }
+
         // 创建数据目录
         std::fs::create_dir_all(&config.data_dir)?;
+        // 恢复在提交后、替换完之前中断时，先完成替换
+        if Path::new(&config.data_dir).join(RESTORE_DIR).exists() {
+            if config.read_only {
+                return Err(DatabaseError::NotPermitted("数据目录中有未完成的恢复，只读打开前要先以读写方式打开一次".to_string()));
+            }
+            let _lock = DirLock::exclusive(Path::new(&config.data_dir))?;
+            finish_restore(Path::new(&config.data_dir))?;
+        }
+        let dir_lock = DirLock::shared(Path::new(&config.data_dir))?;
 
         // 初始化加密器
         let crypto = if let Some(key) = &config.encryption_key {
-            Some(Crypto::new(key)?)
+            Some(Crypto::with_cipher(key, config.cipher)?)
         } else {
             None
         };
 
-        let mut db = Self {
+        let table_keys = config
+            .table_keys
+            .iter()
+            .map(|(id, key)| Ok((id.clone(), Crypto::with_cipher(key, config.cipher)?)))
+            .collect::<Result<HashMap<_, _>>>()?;
+        for (table, overrides) in &config.tables {
+            if let Some(id) = overrides.encryption_key_id.as_ref().filter(|id| !table_keys.contains_key(*id)) {
+                return Err(DatabaseError::Config(format!("表 {} 使用的密钥 {} 不存在", table, id)));
+            }
+        }
+
+        // 初始化并行扫描线程池
+        let scan_pool = if config.query_threads == 1 {
+            None
+        } else {
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(config.query_threads)
+                .thread_name(|i| format!("simpledb-scan-{}", i))
+                .build()
+                .map_err(|e| DatabaseError::Config(format!("无法创建查询线程池: {}", e)))?;
+            Some(Arc::new(pool))
+        };
+
+        let changes = Arc::new(ChangeFeed::open(
+            Path::new(&config.data_dir),
+            config.change_log_size,
+            crypto.clone(),
+            config.read_only,
+        )?);
+        // 变更日志文件丢失时序号也不回退到CDC已确认的序号之前
+        changes.resume_after(cdc::saved_offset(Path::new(&config.data_dir), crypto.clone())?);
+        let keyring = match &crypto {
+            Some(master) => Some(Keyring::open(Path::new(&config.data_dir), master.clone())?),
+            None => None,
+        };
+        let siv = config.encryption_key.as_deref().map(Siv::derive).transpose()?;
+        let manifest = Arc::new(Manifest::open(Path::new(&config.data_dir), siv.clone())?);
+        let prepared = PreparedQueries::open(Path::new(&config.data_dir), crypto.clone())?;
+        let usage = config.quota.map(|quota| Arc::new(Usage::new(quota)));
+        let blobs = Arc::new(BlobStore::new(
+            Path::new(&config.data_dir),
+            crypto.clone(),
+            siv.as_ref(),
+            config.blob_threshold,
+        ));
+        let tracer = config
+            .otlp_endpoint
+            .as_deref()
+            .map(|endpoint| Arc::new(Tracer::new(endpoint, &config.service_name)));
+        let db = Self {
+            _dir_lock: dir_lock,
             config,
-            tables: HashMap::new(),
+            tables: RwLock::new(HashMap::new()),
             crypto,
+            table_keys,
+            scan_pool,
+            changes,
+            keyring,
+            siv,
+            manifest,
+            projections: Projections::default(),
+            sync: Mutex::new(None),
+            schedules: Mutex::new(None),
+            prepared,
+            usage,
+            tenants: Mutex::new(IndexMap::new()),
+            blobs,
+            tracer,
+            fingerprints: Mutex::new(HashMap::new()),
+            validators: RwLock::new(HashMap::new()),
+            cluster: OnceLock::new(),
         };
 
         // 自动加载现有的表
         db.load_existing_tables()?;
+        // 所有表都已加载，没有被引用的blob文件是上次删除引用后来不及清理的；只读时blob文件可能属于写入进程刚保存的记录
+        if db.config.read_only {
+            *db.fingerprints.lock().recover() = db.table_fingerprints()?;
+        } else {
+            db.blobs.collect_garbage()?;
+        }
 
         Ok(db)
     }
 
     /// 加载现有的表文件
-    fn load_existing_tables(&mut self) -> Result<()> {
+    fn load_existing_tables(&self) -> Result<()> {
         let data_dir = PathBuf::from(&self.config.data_dir);
         
         // 检查数据目录是否存在
@@ -48,18 +317,24 @@ impl SimpleDB {
         }
 
         // 扫描.db文件
-        for entry in std::fs::read_dir(data_dir)? {
+        for entry in std::fs::read_dir(&data_dir)? {
             let entry = entry?;
             let path = entry.path();
             
+            // 时间序列表的段文件和按大小拆分出的文件随所属的表加载
+            let segment = path.file_name().and_then(|n| n.to_str()).is_some_and(|n| {
+                timeseries::parse_segment_file_name(n).is_some() || storage::is_part_file(&data_dir, n)
+            });
             if let Some(extension) = path.extension() {
-                if extension == "db" {
+                if extension == "db" && !segment {
                     if let Some(stem) = path.file_stem() {
                         if let Some(table_name) = stem.to_str() {
                             // 加载表
-                            let data_dir = PathBuf::from(&self.config.data_dir);
-                            let table = Table::new(table_name.to_string(), &data_dir, self.crypto.clone())?;
-                            self.tables.insert(table_name.to_string(), table);
+                            let table = self.open_table(table_name)?;
+                            self.tables
+                                .write()
+                                .recover()
+                                .insert(table_name.to_string(), Arc::new(RwLock::new(table)));
                         }
                     }
                 }
@@ -69,158 +344,4286 @@ impl SimpleDB {
         Ok(())
     }
 
+    /// 表对全局配置的覆盖设置，没有配置时各项均为None
+    pub fn table_config(&self, name: &str) -> TableConfig {
+        self.config.tables.get(name).cloned().unwrap_or_default()
+    }
+
+    /// 加密表文件使用的密钥：表指定的密钥，否则为主密钥
+    fn table_crypto(&self, name: &str) -> Option<Crypto> {
+        match &self.table_config(name).encryption_key_id {
+            Some(id) => self.table_keys.get(id).cloned(),
+            None => self.crypto.clone(),
+        }
+    }
+
+    /// 按名称找到排序规则：先找内置的排序规则，再找`Config::collations`中注册的
+    pub fn collation(&self, name: &str) -> Result<Collation> {
+        Collation::builtin(name)
+            .or_else(|| self.config.collations.get(name).map(|compare| Collation::new(name, *compare)))
+            .ok_or_else(|| DatabaseError::Config(format!("排序规则 {} 未注册", name)))
+    }
+
+    /// 设置表的字符串排序规则，None表示按字节比较；影响排序、分页游标和范围条件，等值条件始终要求完全相同
+    ///
+    /// 规则的名称保存在表的元数据中，重新打开后排序结果不变；自定义的规则要在每次打开数据库时注册。
+    pub fn set_collation(&self, table_name: &str, name: Option<&str>) -> Result<()> {
+        let collation = name.map(|name| self.collation(name)).transpose()?;
+        self.write_table(table_name, |table| table.set_collation(collation))
+    }
+
+    /// 写入表文件的选项
+    fn file_options(&self, overrides: &TableConfig) -> FileOptions {
+        FileOptions {
+            engine: self.config.engine,
+            compress: overrides.compress.unwrap_or(self.config.compress_tables),
+            append_only: false,
+        }
+    }
+
+    /// 按数据库配置和表的覆盖设置创建表对象，表文件存在时加载
+    fn open_table(&self, name: &str) -> Result<Table> {
+        if timeseries::parse_segment_file_name(&format!("{}.db", name)).is_some() {
+            return Err(DatabaseError::InvalidQuery(format!("表名 {} 不能以@加整数结尾", name)));
+        }
+        // 旧版本中这样命名的表与拆分出的部分文件无法区分，打开时拒绝，需要先改名
+        if storage::parse_part_file_name(&format!("{}.db", name)).is_some() {
+            return Err(DatabaseError::InvalidQuery(format!("表名 {} 不能以.数字.数字结尾", name)));
+        }
+        let data_dir = PathBuf::from(&self.config.data_dir);
+        let overrides = self.table_config(name);
+        let mut table = Table::new(name.to_string(), &data_dir, self.table_crypto(name))?;
+        if let Some(collation) = table.collation().map(str::to_string) {
+            let collation = self.collation(&collation).map_err(|_| {
+                DatabaseError::Config(format!("表 {} 使用的排序规则 {} 未注册", name, collation))
+            })?;
+            table.use_collation(collation.compare);
+        }
+        table.set_query_cache(self.config.query_cache_size);
+        table.set_scan_pool(self.scan_pool.clone());
+        table.set_change_feed(Some(Arc::clone(&self.changes)));
+        table.set_manifest(Some(Arc::clone(&self.manifest)));
+        table.set_file_options(self.file_options(&overrides));
+        table.set_autosave(overrides.autosave.unwrap_or_default());
+        table.set_max_file_size(overrides.max_file_size.unwrap_or(self.config.max_file_size));
+        table.set_sync_dir(self.config.sync_dir);
+        table.set_quota(overrides.quota);
+        table.set_usage(self.usage.clone());
+        table.set_plaintext_fields(overrides.plaintext_fields);
+        table.set_blob_store(Arc::clone(&self.blobs));
+        table.rebuild_indexes()?;
+        table.set_validator(self.validators.read().recover().get(name).cloned());
+        if self.config.read_only {
+            // 加载时的迁移等修改不写回
+            table.discard_changes();
+        }
+        Ok(table)
+    }
+
+    /// 只读打开时拒绝写入
+    fn check_writable(&self) -> Result<()> {
+        if self.config.read_only {
+            return Err(DatabaseError::NotPermitted("数据库以只读方式打开".to_string()));
+        }
+        Ok(())
+    }
+
+    /// 集群模式下拒绝无法复制的写操作
+    pub(crate) fn check_local_write(&self) -> Result<()> {
+        if self.cluster.get().is_some() {
+            return Err(DatabaseError::NotPermitted("集群模式下只能复制单条写入和事务，不支持该操作".to_string()));
+        }
+        Ok(())
+    }
+
+    /// 数据库所在的集群节点，没有调用`Cluster::start`时为None
+    pub fn cluster(&self) -> Option<&Arc<Cluster>> {
+        self.cluster.get()
+    }
+
+    pub(crate) fn attach_cluster(&self, cluster: Arc<Cluster>) -> Result<()> {
+        if self.config.read_only {
+            return Err(DatabaseError::Config("只读打开的数据库不能加入集群".to_string()));
+        }
+        self.cluster
+            .set(cluster)
+            .map_err(|_| DatabaseError::Config("数据库已经加入集群".to_string()))
+    }
+
+    /// 主密钥的加密器，用于加密表文件以外的状态文件
+    pub(crate) fn master_crypto(&self) -> Option<Crypto> {
+        self.crypto.clone()
+    }
+
+    /// 数据目录中每张表的文件（表文件、段文件和同名的旁路文件）的名称、大小和修改时间
+    fn table_fingerprints(&self) -> Result<HashMap<String, Fingerprint>> {
+        let mut files: Vec<(String, u64, SystemTime)> = Vec::new();
+        for entry in std::fs::read_dir(&self.config.data_dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if let (true, Some(name)) = (metadata.is_file(), entry.file_name().to_str()) {
+                files.push((name.to_string(), metadata.len(), metadata.modified()?));
+            }
+        }
+        let mut fingerprints: HashMap<String, Fingerprint> = files
+            .iter()
+            .filter(|(name, ..)| {
+                name.ends_with(".db")
+                    && timeseries::parse_segment_file_name(name).is_none()
+                    && !storage::is_part_file(Path::new(&self.config.data_dir), name)
+            })
+            .map(|(name, ..)| (timeseries::table_of_file(name).to_string(), Fingerprint::new()))
+            .collect();
+        for (name, size, modified) in files {
+            let table = if name.ends_with(".db") {
+                timeseries::table_of_file(&name)
+            } else {
+                match Path::new(&name).file_stem().and_then(|stem| stem.to_str()) {
+                    Some(stem) if !name.ends_with(".tmp") => stem,
+                    _ => continue,
+                }
+            };
+            if let Some(fingerprint) = fingerprints.get_mut(table) {
+                fingerprint.insert(name.clone(), (size, modified));
+            }
+        }
+        Ok(fingerprints)
+    }
+
+    /// 只读打开时重新加载其他进程保存过的表，返回重新加载、新加载或移除的表名
+    ///
+    /// 按表文件及其旁路文件的大小和修改时间判断是否变化。读到正在写入的不完整文件时保留原来的内容，下次调用时重试。
+    pub fn reload_changed(&self) -> Result<Vec<String>> {
+        if !self.config.read_only {
+            return Err(DatabaseError::Config("只有只读打开的数据库才能重新加载表文件".to_string()));
+        }
+        let current = self.table_fingerprints()?;
+        let mut fingerprints = self.fingerprints.lock().recover();
+        let mut changed = Vec::new();
+        for (name, fingerprint) in &current {
+            if fingerprints.get(name) == Some(fingerprint) {
+                continue;
+            }
+            let Ok(table) = self.open_table(name) else {
+                continue;
+            };
+            if !table.damage().is_empty() {
+                continue;
+            }
+            let previous = self.tables.write().recover().insert(name.clone(), Arc::new(RwLock::new(table)));
+            if let Some(previous) = previous {
+                previous.read().recover().release_blobs();
+            }
+            fingerprints.insert(name.clone(), fingerprint.clone());
+            changed.push(name.clone());
+        }
+        let removed: Vec<String> = fingerprints.keys().filter(|name| !current.contains_key(*name)).cloned().collect();
+        for name in removed {
+            fingerprints.remove(&name);
+            if let Some(previous) = self.tables.write().recover().remove(&name) {
+                previous.read().recover().release_blobs();
+            }
+            changed.push(name);
+        }
+        changed.sort();
+        Ok(changed)
+    }
+
     /// 创建表
-    pub fn create_table(&mut self, name: &str) -> Result<()> {
-        if self.tables.contains_key(name) {
+    pub fn create_table(&self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let mut tables = self.tables.write().recover();
+        if tables.contains_key(name) {
             return Ok(()); // 表已存在，直接返回
         }
 
-        let data_dir = PathBuf::from(&self.config.data_dir);
-        let table = Table::new(name.to_string(), &data_dir, self.crypto.clone())?;
-        self.tables.insert(name.to_string(), table);
+        let table = self.open_table(name)?;
+        tables.insert(name.to_string(), Arc::new(RwLock::new(table)));
+
+        Ok(())
+    }
+
+    /// 创建只能追加记录的事件表：事件按追加顺序获得序号作为ID，不能修改或删除，用`project`归约为当前状态
+    ///
+    /// 同名的事件表已存在时直接返回，同名的普通表已存在时返回`DuplicateKey`。
+    pub fn create_event_table(&self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let mut tables = self.tables.write().recover();
+        if let Some(table) = tables.get(name) {
+            if read_lock(name, table)?.is_append_only() {
+                return Ok(());
+            }
+            return Err(DatabaseError::DuplicateKey(format!("表已存在: {}", name)));
+        }
+
+        let mut table = self.open_table(name)?;
+        table.set_append_only(true);
+        // 立即写出带有事件表标志的文件，重新打开时仍是事件表
+        table.save()?;
+        tables.insert(name.to_string(), Arc::new(RwLock::new(table)));
+        Ok(())
+    }
+
+    /// 创建固定大小表：插入使记录数或字节数超出`cap`时，自动删除最早插入的记录，适合环形缓冲式的遥测数据
+    ///
+    /// 上限保存在表的元数据文件中，重新打开后仍然生效。同名的固定大小表已存在时改用新的上限，
+    /// 同名的普通表已存在时返回`DuplicateKey`。
+    pub fn create_capped_table(&self, name: &str, cap: Cap) -> Result<()> {
+        self.check_writable()?;
+        let mut tables = self.tables.write().recover();
+        if let Some(table) = tables.get(name) {
+            let mut table = write_lock(name, table)?;
+            if table.meta().capped.is_none() {
+                return Err(DatabaseError::DuplicateKey(format!("表已存在: {}", name)));
+            }
+            return table.set_capped(Some(cap));
+        }
+
+        let mut table = self.open_table(name)?;
+        table.set_capped(Some(cap))?;
+        table.save()?;
+        tables.insert(name.to_string(), Arc::new(RwLock::new(table)));
+        Ok(())
+    }
+
+    /// 创建时间序列表：每条记录必须有整数的时间戳字段`series.field`，按`series.bucket`的跨度分段保存
+    ///
+    /// 保存时只重写修改过的段，`expire_before`删除的整段直接删除段文件，适合持续写入的监控指标。
+    /// 同名的时间序列表已存在且设置相同时直接返回，否则返回`DuplicateKey`。
+    pub fn create_time_series_table(&self, name: &str, series: TimeSeries) -> Result<()> {
+        self.check_writable()?;
+        let mut tables = self.tables.write().recover();
+        if let Some(table) = tables.get(name) {
+            if read_lock(name, table)?.time_series() == Some(&series) {
+                return Ok(());
+            }
+            return Err(DatabaseError::DuplicateKey(format!("表已存在: {}", name)));
+        }
 
+        let mut table = self.open_table(name)?;
+        table.set_time_series(series)?;
+        table.save()?;
+        tables.insert(name.to_string(), Arc::new(RwLock::new(table)));
+        Ok(())
+    }
+
+    /// 按事件的先后顺序用`reducer`归约事件表，得到当前状态
+    ///
+    /// 结果作为检查点按表、状态类型和`reducer`缓存，再次投影时只归约之后追加的事件。
+    /// `reducer`须是函数或不捕获变量的闭包，状态只能由事件决定。
+    pub fn project<S>(&self, table_name: &str, reducer: Reducer<S>) -> Result<S>
+    where
+        S: Clone + Default + Send + Sync + 'static,
+    {
+        let checkpoint = self.projections.get(table_name, reducer);
+        let (after, folded, state) = checkpoint.unwrap_or_default();
+        let (restart, events) = self.read_table(table_name, |table| {
+            if !table.is_append_only() {
+                return Err(DatabaseError::NotPermitted(format!("{} 不是事件表", table_name)));
+            }
+            // 检查点之前的事件数不符时（如表被删除后重建）从头归约
+            let (earlier, events) = table.events_after(&after);
+            Ok(if earlier == folded { (false, events) } else { (true, table.events_after("").1) })
+        })??;
+
+        let (mut state, earlier) = if restart { (S::default(), 0) } else { (state, folded) };
+        let Some(last) = events.last().map(|e| e.id.clone()) else {
+            return Ok(state);
+        };
+        for event in &events {
+            state = reducer(state, event);
+        }
+        self.projections.put(table_name, reducer, last, earlier + events.len(), state.clone());
+        Ok(state)
+    }
+
+    /// 复制表：保存源表后直接复制表文件和统计信息，并复制内存中的索引，不逐条重新插入记录
+    ///
+    /// 目标表已存在时返回`DuplicateKey`。
+    pub fn clone_table(&self, src: &str, dst: &str) -> Result<()> {
+        self.check_writable()?;
+        self.check_local_write()?;
+        let mut tables = self.tables.write().recover();
+        if tables.contains_key(dst) {
+            return Err(DatabaseError::DuplicateKey(format!("表已存在: {}", dst)));
+        }
+        let source = tables
+            .get(src)
+            .cloned()
+            .ok_or_else(|| DatabaseError::TableNotFound(src.to_string()))?;
+        let mut source = write_lock(src, &source)?;
+        source.save()?;
+
+        let target = Path::new(&self.config.data_dir).join(format!("{}.db", dst));
+        for segment in source.segment_files()? {
+            let name = segment.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            if let Some((_, bucket)) = timeseries::parse_segment_file_name(name) {
+                let copy = timeseries::segment_file_name(dst, bucket);
+                std::fs::copy(&segment, target.with_file_name(&copy))?;
+                self.manifest.copy(name, &copy)?;
+            }
+        }
+        for part in source.part_files()? {
+            let name = part.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            if let Some((_, generation, number)) = storage::parse_part_file_name(name) {
+                let copy = storage::part_file_name(dst, generation, number);
+                std::fs::copy(&part, target.with_file_name(&copy))?;
+                self.manifest.copy(name, &copy)?;
+            }
+        }
+        if source.parts_path().exists() {
+            std::fs::copy(source.parts_path(), target.with_extension(PARTS_EXTENSION))?;
+        }
+        if source.file_path.exists() {
+            std::fs::copy(&source.file_path, &target)?;
+            self.manifest.copy(&source.file_name(), &format!("{}.db", dst))?;
+            if source.stats_path().exists() {
+                std::fs::copy(source.stats_path(), target.with_extension("stats"))?;
+            }
+            if source.meta_path().exists() {
+                std::fs::copy(source.meta_path(), target.with_extension(META_EXTENSION))?;
+            }
+            if source.plaintext_path().exists() {
+                std::fs::copy(source.plaintext_path(), target.with_extension(PLAINTEXT_EXTENSION))?;
+            }
+            if source.dictionary_path().exists() {
+                std::fs::copy(source.dictionary_path(), target.with_extension(DICTIONARY_EXTENSION))?;
+            }
+        }
+        let mut table = self.open_table(dst)?;
+        table.copy_indexes_from(&source)?;
+        tables.insert(dst.to_string(), Arc::new(RwLock::new(table)));
         Ok(())
     }
 
+    /// 清空表：删除所有记录但保留表和索引定义，直接把表文件重写为空表，返回删除的记录数
+    pub fn truncate(&self, table_name: &str) -> Result<usize> {
+        self.check_local_write()?;
+        self.write_table(table_name, |table| table.truncate())
+    }
+
     /// 删除表
-    pub fn drop_table(&mut self, name: &str) -> Result<()> {
-        if let Some(table) = self.tables.remove(name) {
+    pub fn drop_table(&self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        self.check_local_write()?;
+        let removed = self.tables.write().recover().remove(name);
+        if let Some(table) = removed {
             // 删除表文件
+            let mut table = table.write().recover();
+            table.discard_changes();
+            table.release_blobs();
             if table.file_path.exists() {
                 std::fs::remove_file(&table.file_path)?;
             }
+            if table.stats_path().exists() {
+                std::fs::remove_file(table.stats_path())?;
+            }
+            if table.meta_path().exists() {
+                std::fs::remove_file(table.meta_path())?;
+            }
+            if table.plaintext_path().exists() {
+                std::fs::remove_file(table.plaintext_path())?;
+            }
+            if table.dictionary_path().exists() {
+                std::fs::remove_file(table.dictionary_path())?;
+            }
+            if table.parts_path().exists() {
+                std::fs::remove_file(table.parts_path())?;
+            }
+            for segment in table.segment_files()?.into_iter().chain(table.part_files()?) {
+                std::fs::remove_file(&segment)?;
+                if let Some(name) = segment.file_name().and_then(|n| n.to_str()) {
+                    self.manifest.remove(name)?;
+                }
+            }
+            self.manifest.remove(&table.file_name())?;
+            self.projections.invalidate(name);
+            if let Some(usage) = &self.usage {
+                usage.remove(name);
+            }
+        }
+        Ok(())
+    }
+
+    /// 打开租户`name`的分区：数据保存在数据目录的`tenants/<name>`中，与其他租户和主数据库完全分开
+    ///
+    /// 返回的实例与主数据库使用相同的配置（密钥、按表设置等），`Config::tenant_quotas`中的配额限制该租户所有表的合计用量。
+    /// 同一租户的分区在缓存中只打开一次，之后返回同一个实例；缓存超过`Config::max_open_tenants`时
+    /// 关闭最久未用、且没有在别处使用的分区（保存未保存的修改），下次使用时重新打开。
+    pub fn with_tenant(&self, name: &str) -> Result<Arc<SimpleDB>> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(DatabaseError::InvalidQuery(format!("租户名 {} 只能包含字母、数字、-和_", name)));
+        }
+        if self.config.bundle.is_some() {
+            return Err(DatabaseError::NotPermitted("单文件模式不支持租户分区".to_string()));
+        }
+        let mut tenants = self.tenants.lock().recover();
+        if let Some(tenant) = tenants.shift_remove(name) {
+            tenants.insert(name.to_string(), Arc::clone(&tenant));
+            return Ok(tenant);
+        }
+        let config = Config {
+            data_dir: self.tenant_dir(name).to_string_lossy().into_owned(),
+            quota: self.config.tenant_quotas.get(name).copied(),
+            tenant_quotas: HashMap::new(),
+            ..self.config.clone()
+        };
+        let tenant = Arc::new(SimpleDB::new(config)?);
+        tenants.insert(name.to_string(), Arc::clone(&tenant));
+        // 仍在别处使用的分区不能关闭，否则再次打开时会有两个实例同时写同一目录；
+        // 在持有锁时关闭，保存完成之前同一租户不会被重新打开
+        let excess = tenants.len().saturating_sub(self.config.max_open_tenants);
+        let idle: Vec<String> = tenants
+            .iter()
+            .filter(|(_, tenant)| Arc::strong_count(tenant) == 1)
+            .map(|(name, _)| name.clone())
+            .take(excess)
+            .collect();
+        for name in idle {
+            tenants.shift_remove(&name);
+        }
+        Ok(tenant)
+    }
+
+    /// 缓存中已打开的租户分区，按最近使用的先后排列
+    pub fn open_tenants(&self) -> Vec<String> {
+        self.tenants.lock().recover().keys().cloned().collect()
+    }
+
+    /// 已有分区的租户，按名称排序
+    pub fn tenants(&self) -> Result<Vec<String>> {
+        let dir = Path::new(&self.config.data_dir).join(TENANTS_DIR);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut tenants = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                tenants.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        tenants.sort();
+        Ok(tenants)
+    }
+
+    /// 把租户的全部数据导出为备份文件，可以用`SimpleDB::restore`恢复到单独的数据目录；返回文件数
+    pub fn export_tenant(&self, name: &str, path: &Path) -> Result<usize> {
+        self.with_tenant(name)?.backup(path)
+    }
+
+    /// 删除租户的分区及其全部数据；租户的实例仍在别处使用时返回`NotPermitted`
+    pub fn drop_tenant(&self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let tenant = self.with_tenant(name)?;
+        let mut tenants = self.tenants.lock().recover();
+        // 这里和缓存各持有一个引用
+        if Arc::strong_count(&tenant) > 2 {
+            return Err(DatabaseError::NotPermitted(format!("租户 {} 的实例仍在使用", name)));
         }
+        tenants.shift_remove(name);
+        drop(tenant);
+        std::fs::remove_dir_all(self.tenant_dir(name))?;
         Ok(())
     }
 
-    /// 获取表
-    fn get_table(&self, name: &str) -> Result<&Table> {
+    /// 数据库（所有表合计）的记录数和字节数，没有配置`Config::quota`时为None
+    pub fn usage(&self) -> Option<(usize, usize)> {
+        self.usage.as_ref().map(|usage| usage.total())
+    }
+
+    fn tenant_dir(&self, name: &str) -> PathBuf {
+        Path::new(&self.config.data_dir).join(TENANTS_DIR).join(name)
+    }
+
+    /// 所有表共用的变更流
+    pub fn change_feed(&self) -> Arc<ChangeFeed> {
+        Arc::clone(&self.changes)
+    }
+
+    /// 获取表句柄
+    pub(crate) fn get_table(&self, name: &str) -> Result<TableHandle> {
         self.tables
+            .read()
+            .recover()
             .get(name)
+            .cloned()
             .ok_or_else(|| DatabaseError::TableNotFound(name.to_string()))
     }
 
-    /// 获取可变表
-    fn get_table_mut(&mut self, name: &str) -> Result<&mut Table> {
-        self.tables
-            .get_mut(name)
-            .ok_or_else(|| DatabaseError::TableNotFound(name.to_string()))
+    /// 在表的读锁内执行操作
+    pub(crate) fn read_table<T>(&self, name: &str, f: impl FnOnce(&Table) -> T) -> Result<T> {
+        let handle = self.get_table(name)?;
+        let table = read_lock(name, &handle)?;
+        Ok(f(&table))
+    }
+
+    /// 在表的写锁内执行操作，成功后按表的自动保存策略保存
+    pub(crate) fn write_table<T>(&self, name: &str, f: impl FnOnce(&mut Table) -> Result<T>) -> Result<T> {
+        self.check_writable()?;
+        let handle = self.get_table(name)?;
+        let mut table = write_lock(name, &handle)?;
+        let result = f(&mut table)?;
+        table.autosave()?;
+        Ok(result)
     }
 
     /// 插入记录
-    pub fn insert(&mut self, table_name: &str, data: HashMap<String, Value>) -> Result<String> {
+    pub fn insert(&self, table_name: &str, data: IndexMap<String, Value>) -> Result<String> {
         // 如果表不存在，自动创建
-        if !self.tables.contains_key(table_name) {
+        if self.get_table(table_name).is_err() {
             self.create_table(table_name)?;
         }
 
-        let record = Record::new(data);
-        let table = self.get_table_mut(table_name)?;
-        table.insert(record)
+        self.traced("insert", Some(table_name), |_| 1, || {
+            if let Some(cluster) = self.cluster.get() {
+                let mut tx = Transaction::new();
+                let id = tx.insert(table_name, data);
+                return cluster.replicate(tx.into_ops()).map(|_| id);
+            }
+            let record = Record::new(data);
+            self.write_table(table_name, |table| table.insert(record))
+        })
     }
 
-    /// 根据ID查找记录
-    pub fn find_by_id(&self, table_name: &str, id: &str) -> Result<Option<&Record>> {
-        let table = self.get_table(table_name)?;
-        Ok(table.find_by_id(id))
+    /// 插入记录并返回插入后的完整记录，包括分配的ID和创建时间，不需要再查询一次
+    pub fn insert_returning(&self, table_name: &str, data: IndexMap<String, Value>) -> Result<Arc<Record>> {
+        if self.get_table(table_name).is_err() {
+            self.create_table(table_name)?;
+        }
+
+        self.traced("insert", Some(table_name), |_| 1, || {
+            if let Some(cluster) = self.cluster.get() {
+                let mut tx = Transaction::new();
+                let id = tx.insert(table_name, data);
+                cluster.replicate(tx.into_ops())?;
+                return self.find_by_id(table_name, &id)?.ok_or(DatabaseError::RecordNotFound(id));
+            }
+            let record = Record::new(data);
+            self.write_table(table_name, |table| {
+                let id = table.insert(record)?;
+                table.find_by_id(&id).ok_or(DatabaseError::RecordNotFound(id))
+            })
+        })
     }
 
-    /// 更新记录
-    pub fn update(
-        &mut self,
-        table_name: &str,
-        id: &str,
-        data: HashMap<String, Value>,
-    ) -> Result<()> {
-        let table = self.get_table_mut(table_name)?;
-        table.update(id, data)
+    /// 配置了追踪时在span中执行`f`：记录操作名、表名、涉及的记录数、耗时和错误，`f`中的操作成为子span
+    fn traced<T>(
+        &self,
+        operation: &str,
+        table: Option<&str>,
+        rows: impl FnOnce(&T) -> usize,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let Some(tracer) = &self.tracer else {
+            return f();
+        };
+        let name = match table {
+            Some(table) => format!("{} {}", operation, table),
+            None => operation.to_string(),
+        };
+        let mut span = tracer.start(name, SpanKind::Internal);
+        span.set("db.system", "simpledb");
+        span.set("db.operation.name", operation);
+        if let Some(table) = table {
+            span.set("db.collection.name", table);
+        }
+        let result = span.context.scope(f);
+        match &result {
+            Ok(value) => span.set("db.response.returned_rows", rows(value)),
+            Err(e) => span.error = Some(e.to_string()),
+        }
+        tracer.finish(span);
+        result
     }
 
-    /// 删除记录
-    pub fn delete(&mut self, table_name: &str, id: &str) -> Result<()> {
-        let table = self.get_table_mut(table_name)?;
-        table.delete(id)
+    /// 追踪器，配置了`Config::otlp_endpoint`时才有；可用来为调用方自己的操作开始span
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_deref()
+    }
+
+    /// 在W3C `traceparent`（如上游请求头）所指的追踪中执行`f`，期间的数据库操作成为它的子span
+    ///
+    /// `traceparent`无效时返回`InvalidQuery`，不执行`f`。已有`TraceContext`时直接用`TraceContext::scope`。
+    pub fn in_trace<T>(&self, traceparent: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        traceparent.parse::<TraceContext>()?.scope(f)
+    }
+
+    /// 立即导出排队的span，返回导出的个数；没有配置追踪时什么也不做
+    ///
+    /// span平时由后台线程每5秒导出一次，关闭数据库时也会导出剩余的span。
+    pub fn flush_traces(&self) -> Result<usize> {
+        match &self.tracer {
+            Some(tracer) => tracer.flush(),
+            None => Ok(0),
+        }
     }
 
-    /// 查询所有记录
-    pub fn find_all(&self, table_name: &str) -> Result<Vec<&Record>> {
-        let table = self.get_table(table_name)?;
-        Ok(table.find_all())
+    /// 设置表的行级安全策略，None表示取消；策略保存在表的元数据中，只对`as_caller`的读写生效
+    pub fn set_policy(&self, table_name: &str, policy: Option<Policy>) -> Result<()> {
+        self.write_table(table_name, |table| table.set_policy(policy))
     }
 
-    /// 根据条件查询记录
-    pub fn find_where<F>(&self, table_name: &str, predicate: F) -> Result<Vec<&Record>>
+    /// 注册表的校验函数，插入、更新和局部更新写入前调用，返回的字段错误使写入以`Validation`失败
+    ///
+    /// 同一张表只有一个校验函数，再次注册时替换；表还不存在时在创建后生效。校验函数不保存，重新打开数据库后需要重新注册。
+    pub fn set_validator<F>(&self, table_name: &str, validate: F) -> Result<()>
     where
-        F: Fn(&Record) -> bool,
+        F: Fn(&IndexMap<String, Value>) -> std::result::Result<(), Vec<FieldError>> + Send + Sync + 'static,
     {
-        let table = self.get_table(table_name)?;
-        Ok(table.find_where(predicate))
+        self.replace_validator(table_name, Some(Validator::new(validate)))
+    }
+
+    /// 取消表的校验函数
+    pub fn clear_validator(&self, table_name: &str) -> Result<()> {
+        self.replace_validator(table_name, None)
     }
 
-    /// 保存所有表到磁盘
-    pub fn save_all(&mut self) -> Result<()> {
-        for table in self.tables.values_mut() {
-            table.save()?;
+    fn replace_validator(&self, table_name: &str, validator: Option<Validator>) -> Result<()> {
+        let mut validators = self.validators.write().recover();
+        match &validator {
+            Some(validator) => validators.insert(table_name.to_string(), validator.clone()),
+            None => validators.remove(table_name),
+        };
+        drop(validators);
+        if let Ok(handle) = self.get_table(table_name) {
+            write_lock(table_name, &handle)?.set_validator(validator);
         }
         Ok(())
     }
 
-    /// 获取表列表
-    pub fn list_tables(&self) -> Vec<String> {
-        self.tables.keys().cloned().collect()
+    /// 以持有`claims`的调用方身份访问数据库，读写受表的行级安全策略限制
+    pub fn as_caller(&self, claims: Claims) -> Caller<'_> {
+        Caller::new(self, claims)
     }
 
-    /// 获取表的记录数量
-    pub fn count(&self, table_name: &str) -> Result<usize> {
-        let table = self.get_table(table_name)?;
-        Ok(table.count())
+    /// 用`Config::auth_key`签发调用方令牌
+    pub fn issue_token(&self, claims: &Claims) -> Result<String> {
+        policy::issue_token(self.auth_key()?, claims)
     }
 
-    /// 创建包含示例数据的数据库
-    pub fn create_sample_db() -> Result<Self> {
-        let key = Crypto::generate_key();
-        let config = Config {
-            data_dir: "./sample_data".to_string(),
-            encryption_key: Some(key),
-            max_file_size: 1024 * 1024,
-        };
+    /// 用`Config::auth_key`校验调用方令牌，返回其中的声明
+    pub fn verify_token(&self, token: &str) -> Result<Claims> {
+        policy::verify_token(self.auth_key()?, token)
+    }
 
-        let mut db = Self::new(config)?;
+    fn auth_key(&self) -> Result<&[u8]> {
+        self.config
+            .auth_key
+            .as_deref()
+            .ok_or_else(|| DatabaseError::Config("没有配置令牌签名密钥".to_string()))
+    }
 
-        // 创建用户表
-        db.create_table("users")?;
-        
-        // 插入示例数据
-        let mut user1 = HashMap::new();
-        user1.insert("name".to_string(), Value::String("张三".to_string()));
-        user1.insert("age".to_string(), Value::Int(25));
-        user1.insert("email".to_string(), Value::String("zhangsan@example.com".to_string()));
-        
-        let mut user2 = HashMap::new();
-        user2.insert("name".to_string(), Value::String("李四".to_string()));
-        user2.insert("age".to_string(), Value::Int(30));
-        user2.insert("email".to_string(), Value::String("lisi@example.com".to_string()));
+    /// 设置表中保存记录过期时间（Unix秒）的字段，None表示取消；设置保存在表的元数据中
+    ///
+    /// 之后该字段为整数的记录到期后由`sweep_expired`删除，服务器每秒清理一次。
+    pub fn set_ttl_field(&self, table_name: &str, field: Option<&str>) -> Result<()> {
+        self.write_table(table_name, |table| table.set_ttl_field(field))
+    }
 
-        db.insert("users", user1)?;
-        db.insert("users", user2)?;
+    /// 共享blob存储的用量：不同内容的个数、引用总数和去重后的字节数
+    pub fn blob_usage(&self) -> BlobUsage {
+        self.blobs.usage()
+    }
 
-        // 创建产品表
-        db.create_table("products")?;
-        
-        let mut product1 = HashMap::new();
-        product1.insert("name".to_string(), Value::String("笔记本电脑".to_string()));
-        product1.insert("price".to_string(), Value::Float(5999.99));
-        product1.insert("category".to_string(), Value::String("电子产品".to_string()));
-        
-        db.insert("products", product1)?;
+    /// 设置表的值压缩阈值，None表示不压缩；设置保存在表的元数据中
+    ///
+    /// 不小于阈值的字符串和字节串值以zstd压缩后保存在内存和表文件中，读取时透明解压，
+    /// 适合日志消息这类较长的文本字段。现有记录随即重新压缩。
+    pub fn set_value_compression(&self, table_name: &str, threshold: Option<usize>) -> Result<()> {
+        self.write_table(table_name, |table| table.set_value_compression(threshold))
+    }
 
-        db.save_all()?;
-        Ok(db)
+    /// 用表中现有的较长值训练zstd字典并用它重新压缩，返回字典的字节数；需要先启用值压缩
+    ///
+    /// 字典保存在`<表名>.dict`中，加密的表的字典同样加密。
+    pub fn train_compression_dictionary(&self, table_name: &str) -> Result<usize> {
+        self.write_table(table_name, |table| table.train_compression_dictionary())
     }
-}
 
-impl Drop for SimpleDB {
-    fn drop(&mut self) {
-        let _ = self.save_all();
+    /// 插入在`ttl`之后过期的记录，过期时间写入表的过期时间字段
+    pub fn insert_with_ttl(&self, table_name: &str, mut data: IndexMap<String, Value>, ttl: Duration) -> Result<String> {
+        let field = self
+            .read_table(table_name, |table| table.ttl_field().map(str::to_string))?
+            .ok_or_else(|| DatabaseError::InvalidQuery(format!("表 {} 没有设置过期时间字段", table_name)))?;
+        let expires_at = unix_now().saturating_add(ttl.as_secs().try_into().unwrap_or(i64::MAX));
+        data.insert(field, Value::Int(expires_at));
+        self.insert(table_name, data)
+    }
+
+    /// 删除所有表中已过期的记录，返回删除的记录数
+    ///
+    /// 每张表按过期时间排列会过期的记录，清理只访问到期的记录；没有到期记录的表只短暂加读锁。
+    pub fn sweep_expired(&self) -> Result<usize> {
+        // 只读打开时由写入的进程清理
+        if self.config.read_only {
+            return Ok(0);
+        }
+        let now = unix_now();
+        let handles: Vec<(String, TableHandle)> = self
+            .tables
+            .read()
+            .recover()
+            .iter()
+            .map(|(name, handle)| (name.clone(), Arc::clone(handle)))
+            .collect();
+        let mut removed = 0;
+        for (name, handle) in handles {
+            if read_lock(&name, &handle)?.next_expiry().is_some_and(|expires_at| expires_at <= now) {
+                removed += self.write_table(&name, |table| table.sweep_expired(now))?;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// 根据ID查找记录
+    pub fn find_by_id(&self, table_name: &str, id: &str) -> Result<Option<Arc<Record>>> {
+        self.traced("find_by_id", Some(table_name), |record: &Option<Arc<Record>>| usize::from(record.is_some()), || {
+            self.read_table(table_name, |table| table.find_by_id(id))
+        })
     }
-} 
\ No newline at end of file
+
+    /// 更新记录
+    pub fn update(
+        &self,
+        table_name: &str,
+        id: &str,
+        data: IndexMap<String, Value>,
+    ) -> Result<()> {
+        self.traced("update", Some(table_name), |_| 1, || {
+            if let Some(cluster) = self.cluster.get() {
+                return replicate(cluster, |tx| tx.update(table_name, id, data));
+            }
+            self.write_table(table_name, |table| table.update(id, data))
+        })
+    }
+
+    /// 局部更新记录，按顺序应用`ops`中的操作
+    pub fn patch(&self, table_name: &str, id: &str, ops: &[UpdateOp]) -> Result<()> {
+        self.traced("patch", Some(table_name), |_| 1, || {
+            if let Some(cluster) = self.cluster.get() {
+                return replicate(cluster, |tx| tx.patch(table_name, id, ops.to_vec()));
+            }
+            self.write_table(table_name, |table| table.patch(id, ops))
+        })
+    }
+
+    /// 对表中所有记录应用更新操作，返回发生变化的记录数
+    pub fn patch_all(&self, table_name: &str, ops: &[UpdateOp]) -> Result<usize> {
+        self.check_local_write()?;
+        self.traced("patch_all", Some(table_name), |changed| *changed, || {
+            self.write_table(table_name, |table| table.patch_all(ops))
+        })
+    }
+
+    /// 在一次加锁中对每条记录应用映射函数，返回`Some`时替换记录的数据（`None`表示不变），返回发生变化的记录数
+    ///
+    /// 适合回填、规范化等批量修改；每条变更照常发布到变更流，违反唯一约束时整表保持不变。
+    pub fn transform<F>(&self, table_name: &str, f: F) -> Result<usize>
+    where
+        F: FnMut(&Record) -> Option<IndexMap<String, Value>>,
+    {
+        self.check_local_write()?;
+        self.write_table(table_name, |table| table.transform(f))
+    }
+
+    /// 在所有记录中重命名字段，返回发生变化的记录数
+    pub fn rename_field(&self, table_name: &str, from: &str, to: &str) -> Result<usize> {
+        self.patch_all(table_name, &[UpdateOp::Rename(from.to_string(), to.to_string())])
+    }
+
+    /// 从所有记录中删除字段，返回发生变化的记录数
+    pub fn remove_field(&self, table_name: &str, field: &str) -> Result<usize> {
+        self.patch_all(table_name, &[UpdateOp::Unset(field.to_string())])
+    }
+
+    /// 把`table`表中`key_field`等于`old`的键值改为`new`，同时把`referencing`中各(表, 字段)等于`old`的引用
+    /// 都改为`new`，返回修改的记录数
+    ///
+    /// 适合改名被大量记录引用的值，如把分类"数码"改为"电子产品"，同时修改所有商品和推荐位中的分类。
+    /// 所有修改作为一个事务提交，要么全部生效，要么全部不生效；集群模式下只复制一条日志。提交前有记录被
+    /// 并发修改时重新查找并重试，见`with_retry`。只比较字段的值本身，不查找数组中的元素。
+    /// `Value::Ref`按记录ID引用，键值改名后仍指向同一条记录，不需要列出；`old`和`new`本身是`Value::Ref`时
+    /// 按引用相等查找，可用于把引用改为指向另一条记录。
+    pub fn cascade_update_value(
+        &self,
+        table: &str,
+        key_field: &str,
+        old: &Value,
+        new: &Value,
+        referencing: &[(&str, &str)],
+    ) -> Result<usize> {
+        if old == new {
+            return Ok(0);
+        }
+        let fields: Vec<(&str, &str)> = std::iter::once((table, key_field)).chain(referencing.iter().copied()).collect();
+        self.with_retry(|tx| {
+            let mut changed = 0;
+            for &(table, field) in &fields {
+                let records = self.query(table, &Query::eq(field, old.clone()))?;
+                for record in &records {
+                    tx.require_field(table, &record.id, field, old.clone());
+                    tx.patch(table, &record.id, vec![UpdateOp::Set(field.to_string(), new.clone())]);
+                }
+                changed += records.len();
+            }
+            Ok(changed)
+        })
+    }
+
+    /// 仅当记录当前的ETag与`etag`一致时更新，否则返回`PreconditionFailed`
+    pub fn update_if_match(
+        &self,
+        table_name: &str,
+        id: &str,
+        etag: &str,
+        data: IndexMap<String, Value>,
+    ) -> Result<()> {
+        if let Some(cluster) = self.cluster.get() {
+            return replicate(cluster, |tx| {
+                tx.require_etag(table_name, id, etag);
+                tx.update(table_name, id, data);
+            });
+        }
+        self.write_table(table_name, |table| {
+            Self::check_etag(table, id, etag)?;
+            table.update(id, data)
+        })
+    }
+
+    /// 仅当ETag一致时应用局部更新
+    pub fn patch_if_match(&self, table_name: &str, id: &str, etag: &str, ops: &[UpdateOp]) -> Result<()> {
+        if let Some(cluster) = self.cluster.get() {
+            return replicate(cluster, |tx| {
+                tx.require_etag(table_name, id, etag);
+                tx.patch(table_name, id, ops.to_vec());
+            });
+        }
+        self.write_table(table_name, |table| {
+            Self::check_etag(table, id, etag)?;
+            table.patch(id, ops)
+        })
+    }
+
+    /// 仅当ETag一致时删除记录
+    pub fn delete_if_match(&self, table_name: &str, id: &str, etag: &str) -> Result<()> {
+        if let Some(cluster) = self.cluster.get() {
+            return replicate(cluster, |tx| {
+                tx.require_etag(table_name, id, etag);
+                tx.delete(table_name, id);
+            });
+        }
+        self.write_table(table_name, |table| {
+            Self::check_etag(table, id, etag)?;
+            table.delete(id)
+        })
+    }
+
+    /// 在写锁内校验记录的ETag，`etag`按`If-Match`头的规则比较（可以是多个ETag或`*`）
+    pub(crate) fn check_etag(table: &Table, id: &str, etag: &str) -> Result<()> {
+        let record = table
+            .find_by_id(id)
+            .ok_or_else(|| DatabaseError::RecordNotFound(id.to_string()))?;
+        let current = record.etag();
+        if !http::etag_matches(etag, &current) {
+            return Err(DatabaseError::PreconditionFailed(format!(
+                "记录 {} 已被修改，当前ETag为 {}",
+                id, current
+            )));
+        }
+        Ok(())
+    }
+
+    /// 仅当记录的`field`字段等于`expected`时更新，否则返回`PreconditionFailed`
+    ///
+    /// 比较和写入在同一把写锁内完成，适合“仅当状态为pending时才取消订单”这类状态转换。
+    pub fn update_if(
+        &self,
+        table_name: &str,
+        id: &str,
+        field: &str,
+        expected: &Value,
+        data: IndexMap<String, Value>,
+    ) -> Result<()> {
+        if let Some(cluster) = self.cluster.get() {
+            return replicate(cluster, |tx| {
+                tx.require_field(table_name, id, field, expected.clone());
+                tx.update(table_name, id, data);
+            });
+        }
+        self.write_table(table_name, |table| {
+            Self::check_field(table, id, field, expected)?;
+            table.update(id, data)
+        })
+    }
+
+    /// 仅当记录的`field`字段等于`expected`时应用局部更新
+    pub fn patch_if(&self, table_name: &str, id: &str, field: &str, expected: &Value, ops: &[UpdateOp]) -> Result<()> {
+        if let Some(cluster) = self.cluster.get() {
+            return replicate(cluster, |tx| {
+                tx.require_field(table_name, id, field, expected.clone());
+                tx.patch(table_name, id, ops.to_vec());
+            });
+        }
+        self.write_table(table_name, |table| {
+            Self::check_field(table, id, field, expected)?;
+            table.patch(id, ops)
+        })
+    }
+
+    /// 仅当记录的`field`字段等于`expected`时删除记录
+    pub fn delete_if(&self, table_name: &str, id: &str, field: &str, expected: &Value) -> Result<()> {
+        if let Some(cluster) = self.cluster.get() {
+            return replicate(cluster, |tx| {
+                tx.require_field(table_name, id, field, expected.clone());
+                tx.delete(table_name, id);
+            });
+        }
+        self.write_table(table_name, |table| {
+            Self::check_field(table, id, field, expected)?;
+            table.delete(id)
+        })
+    }
+
+    /// 在写锁内校验记录字段的当前值，系统字段同样可以比较
+    pub(crate) fn check_field(table: &Table, id: &str, field: &str, expected: &Value) -> Result<()> {
+        let record = table
+            .find_by_id(id)
+            .ok_or_else(|| DatabaseError::RecordNotFound(id.to_string()))?;
+        match record.field(field) {
+            Some(current) if *current == *expected => Ok(()),
+            Some(current) => Err(DatabaseError::PreconditionFailed(format!(
+                "记录 {} 的字段 {} 当前为 {}",
+                id,
+                field,
+                DatabaseServer::value_to_json(&current)
+            ))),
+            None => Err(DatabaseError::PreconditionFailed(format!("记录 {} 没有字段 {}", id, field))),
+        }
+    }
+
+    /// 原子地提交事务：所有操作要么全部生效，要么全部不生效
+    ///
+    /// 涉及的表按名称顺序加写锁并持有到提交结束，同时提交的事务之间不会互相等待成环。
+    /// 等待某张表的锁会形成循环等待时（如在持有该表锁的回调中提交）立即放弃已取得的锁，返回`Deadlock`；
+    /// 锁只是被其他操作占用时等待释放，`Config::lock_timeout`内仍取不到则返回`LockTimeout`。两种情况下都没有任何操作生效。任一操作失败时，已应用的操作按相反顺序撤销，并返回该操作的错误。
+    ///
+    /// 集群模式下事务作为一条日志复制，提交到多数节点后在每个节点上以同样的方式应用。
+    pub fn commit(&self, tx: Transaction) -> Result<()> {
+        self.check_writable()?;
+        match self.cluster.get() {
+            Some(cluster) => cluster.replicate(tx.into_ops()),
+            None => self.commit_local(tx.into_ops(), None),
+        }
+    }
+
+    /// 应用集群中提交的日志：记录的时间戳取主节点接受写入的时间`time`，之后立即保存涉及的表
+    pub(crate) fn apply_replicated(&self, ops: Vec<WriteOp>, time: u64) -> Result<()> {
+        let names: BTreeSet<String> = ops.iter().map(|op| op.table().to_string()).collect();
+        self.commit_local(ops, Some(time))?;
+        for name in names {
+            write_lock(&name, &self.get_table(&name)?)?.save()?;
+        }
+        Ok(())
+    }
+
+    /// 安装集群主节点发来的快照：用其中的记录替换各表的全部记录并保存，快照中没有的表不变
+    pub(crate) fn install_snapshot(&self, tables: Vec<(String, Vec<Arc<Record>>)>) -> Result<()> {
+        for (name, records) in tables {
+            self.create_table(&name)?;
+            self.write_table(&name, |table| {
+                table.truncate()?;
+                for record in records {
+                    table.restore(record);
+                }
+                table.save()
+            })?;
+        }
+        Ok(())
+    }
+
+    /// 在本地原子地应用一组写操作，`time`不为None时用它作为写入记录的时间戳
+    ///
+    /// 插入的表不存在时先创建；提交失败时移除这些表，前提是它们仍为空且没有其他操作正在使用。
+    fn commit_local(&self, ops: Vec<WriteOp>, time: Option<u64>) -> Result<()> {
+        let mut created = Vec::new();
+        let mut result = Ok(());
+        for op in &ops {
+            if let WriteOp::Insert { table, .. } = op {
+                if self.get_table(table).is_err() {
+                    result = self.create_table(table);
+                    if result.is_err() {
+                        break;
+                    }
+                    created.push(table.clone());
+                }
+            }
+        }
+        let result = result.and_then(|()| self.apply_locked(ops, time));
+        if result.is_err() {
+            self.discard_created(&created);
+        }
+        result
+    }
+
+    /// 移除提交失败的事务创建的表，仍有其他操作持有或已有记录的表保留
+    fn discard_created(&self, names: &[String]) {
+        let mut tables = self.tables.write().recover();
+        for name in names {
+            let unused = tables.get(name).is_some_and(|handle| {
+                Arc::strong_count(handle) == 1 && handle.try_read().is_ok_and(|table| table.count() == 0)
+            });
+            if unused {
+                tables.remove(name);
+            }
+        }
+    }
+
+    /// 按名称顺序给涉及的表加写锁，应用全部写操作；任一操作失败时撤销已应用的操作
+    ///
+    /// 变更事件在全部操作成功后才按顺序发布，失败的事务不产生事件。
+    fn apply_locked(&self, ops: Vec<WriteOp>, time: Option<u64>) -> Result<()> {
+        let names: BTreeSet<String> = ops.iter().map(|op| op.table().to_string()).collect();
+        let handles = names
+            .into_iter()
+            .map(|name| Ok((name.clone(), self.get_table(&name)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let deadline = Instant::now() + self.config.lock_timeout;
+        let mut guards: HashMap<&str, Held<RwLockWriteGuard<'_, Table>>> = HashMap::new();
+        for (name, handle) in &handles {
+            guards.insert(name.as_str(), lock_before(name, handle, deadline)?);
+        }
+        for table in guards.values_mut() {
+            table.defer_changes();
+        }
+
+        let mut applied: Vec<(String, Undo)> = Vec::with_capacity(ops.len());
+        let mut events = Vec::new();
+        for op in ops {
+            let name = op.table().to_string();
+            let table = guards.get_mut(name.as_str()).expect("事务涉及的表均已加锁");
+            match Self::apply_op(table, op, time) {
+                Ok(undo) => {
+                    events.extend(table.take_deferred(false).into_iter().map(|event| (name.clone(), event)));
+                    applied.push((name, undo));
+                }
+                Err(e) => {
+                    for (name, undo) in applied.into_iter().rev() {
+                        let table = guards.get_mut(name.as_str()).expect("事务涉及的表均已加锁");
+                        match undo {
+                            Undo::Remove(id) => {
+                                let _ = table.remove(&id);
+                            }
+                            Undo::Restore(record) => table.restore(record),
+                            Undo::Nothing => {}
+                        }
+                    }
+                    for table in guards.values_mut() {
+                        table.take_deferred(true);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        for table in guards.values_mut() {
+            table.take_deferred(true);
+        }
+        for (name, event) in events {
+            guards[name.as_str()].publish_change(event);
+        }
+        for table in guards.values_mut() {
+            table.autosave()?;
+        }
+        Ok(())
+    }
+
+    /// 用`f`构建事务并提交，遇到冲突（`DatabaseError::is_conflict`）时等待随机的退避时间后重新调用`f`
+    ///
+    /// 每次调用`f`都得到一个新的空事务，`f`应在其中重新读取数据并用`require_etag`声明依赖的记录版本。
+    /// `f`本身返回错误时不重试；重试`transaction::MAX_RETRIES`次后仍冲突则返回最后一次的错误。
+    pub fn with_retry<T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Transaction) -> Result<T>,
+    {
+        let mut attempt = 0;
+        loop {
+            let mut tx = Transaction::new();
+            let value = f(&mut tx)?;
+            match self.commit(tx) {
+                Ok(()) => return Ok(value),
+                Err(e) if e.is_conflict() && attempt < transaction::MAX_RETRIES => {
+                    std::thread::sleep(transaction::retry_delay(attempt));
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// 在已加锁的表上应用一项事务操作，返回撤销信息；`time`不为None时作为写入记录的时间戳
+    fn apply_op(table: &mut Table, op: WriteOp, time: Option<u64>) -> Result<Undo> {
+        let previous = |table: &Table, id: &str| {
+            table
+                .find_by_id(id)
+                .ok_or_else(|| DatabaseError::RecordNotFound(id.to_string()))
+        };
+        match op {
+            WriteOp::Insert { id, data, .. } => {
+                let mut record = Record::new(data);
+                record.id = id;
+                if let Some(time) = time {
+                    record.created_at = time;
+                    record.updated_at = time;
+                }
+                Ok(Undo::Remove(table.insert(record)?))
+            }
+            WriteOp::Update { id, data, .. } => {
+                let old = previous(table, &id)?;
+                table.update(&id, data)?;
+                if let Some(time) = time {
+                    table.set_updated_at(&id, time);
+                }
+                Ok(Undo::Restore(old))
+            }
+            WriteOp::Patch { id, ops, .. } => {
+                let old = previous(table, &id)?;
+                table.patch(&id, &ops)?;
+                if let Some(time) = time {
+                    table.set_updated_at(&id, time);
+                }
+                Ok(Undo::Restore(old))
+            }
+            WriteOp::Delete { id, .. } => {
+                let old = previous(table, &id)?;
+                table.delete(&id)?;
+                Ok(Undo::Restore(old))
+            }
+            WriteOp::CheckEtag { id, etag, .. } => {
+                Self::check_etag(table, &id, &etag)?;
+                Ok(Undo::Nothing)
+            }
+            WriteOp::CheckField { id, field, expected, .. } => {
+                Self::check_field(table, &id, &field, &expected)?;
+                Ok(Undo::Nothing)
+            }
+        }
+    }
+
+    /// 删除记录
+    pub fn delete(&self, table_name: &str, id: &str) -> Result<()> {
+        self.traced("delete", Some(table_name), |_| 1, || {
+            if let Some(cluster) = self.cluster.get() {
+                return replicate(cluster, |tx| tx.delete(table_name, id));
+            }
+            self.write_table(table_name, |table| table.delete(id))
+        })
+    }
+
+    /// 查询所有记录，按创建时间排列，创建时间相同（同一秒内插入）时按ID排列
+    pub fn find_all(&self, table_name: &str) -> Result<Vec<Arc<Record>>> {
+        self.traced("find_all", Some(table_name), Vec::len, || {
+            self.read_table(table_name, |table| table.find_all())
+        })
+    }
+
+    /// 加载表文件时跳过的损坏记录
+    ///
+    /// 损坏的表照常打开，其余记录可以正常读写；下次保存该表时损坏部分被丢弃，需要保留原文件时应先复制。
+    pub fn load_report(&self) -> LoadReport {
+        let tables = self.tables.read().recover();
+        let damaged = tables
+            .iter()
+            .filter_map(|(name, handle)| {
+                let damage = handle.read().recover().damage().to_vec();
+                (!damage.is_empty()).then(|| (name.clone(), damage))
+            })
+            .collect();
+        LoadReport { damaged }
+    }
+
+    /// 最近插入的`n`条记录，由新到旧排列
+    pub fn find_latest(&self, table_name: &str, n: usize) -> Result<Vec<Arc<Record>>> {
+        self.read_table(table_name, |table| table.find_latest(n))
+    }
+
+    /// 时间序列表中时间戳在`[from, to)`内的记录，按时间先后排列
+    pub fn find_between(&self, table_name: &str, from: i64, to: i64) -> Result<Vec<Arc<Record>>> {
+        self.read_time_series(table_name, |table| Ok(table.find_between(from, to)))
+    }
+
+    /// 对时间序列表降采样：`[from, to)`内的记录按`interval`分组，对每组的`field`求聚合
+    ///
+    /// 返回(组的起始时间, 聚合值)，按时间先后排列，没有记录的组不出现。
+    pub fn downsample(
+        &self,
+        table_name: &str,
+        from: i64,
+        to: i64,
+        interval: i64,
+        field: &str,
+        aggregate: Aggregate,
+    ) -> Result<Vec<(i64, Value)>> {
+        let overflow = self.config.aggregate_overflow;
+        self.read_time_series(table_name, |table| table.downsample(from, to, interval, field, aggregate, overflow))
+    }
+
+    /// 删除时间序列表中时间戳早于`before`的记录，返回删除的记录数
+    pub fn expire_before(&self, table_name: &str, before: i64) -> Result<usize> {
+        self.check_local_write()?;
+        self.write_table(table_name, |table| {
+            if table.time_series().is_none() {
+                return Err(not_time_series(table_name));
+            }
+            table.expire_before(before)
+        })
+    }
+
+    fn read_time_series<T>(&self, table_name: &str, f: impl FnOnce(&Table) -> Result<T>) -> Result<T> {
+        self.read_table(table_name, |table| {
+            if table.time_series().is_none() {
+                return Err(not_time_series(table_name));
+            }
+            f(table)
+        })?
+    }
+
+    /// 根据条件查询记录，顺序与`find_all`相同
+    pub fn find_where<F>(&self, table_name: &str, predicate: F) -> Result<Vec<Arc<Record>>>
+    where
+        F: Fn(&Record) -> bool,
+    {
+        self.read_table(table_name, |table| table.find_where(predicate))
+    }
+
+    /// 查询字段等于`value`或数组字段包含`value`的记录，字段上有索引时不扫描全表
+    pub fn find_by_field(&self, table_name: &str, field: &str, value: &Value) -> Result<Vec<Arc<Record>>> {
+        self.read_table(table_name, |table| table.find_by_field(field, value))
+    }
+
+    /// 返回记录的副本，其中的引用（包括数组和对象中的）替换为被引用记录的字段和`id`组成的对象
+    ///
+    /// 被引用的记录中的引用继续展开，最多`depth`层；已在当前展开路径上的记录不再展开，
+    /// 避免循环引用无限展开。超出深度、形成循环或被引用的记录不存在时保留原来的引用。
+    pub fn resolve(&self, record: &Record, depth: usize) -> Result<Record> {
+        let mut path = Vec::new();
+        let mut resolved = record.clone();
+        for value in resolved.data.values_mut() {
+            *value = self.resolve_value(value, depth, &mut path)?;
+        }
+        Ok(resolved)
+    }
+
+    fn resolve_value(&self, value: &Value, depth: usize, path: &mut Vec<(String, String)>) -> Result<Value> {
+        match value {
+            Value::Ref { table, id } if depth > 0 && !path.iter().any(|(t, i)| t == table && i == id) => {
+                let Some(target) = self.find_by_id(table, id)? else {
+                    return Ok(value.clone());
+                };
+                path.push((table.clone(), id.clone()));
+                let mut object = IndexMap::from([("id".to_string(), Value::String(id.clone()))]);
+                for (field, value) in &target.data {
+                    object.insert(field.clone(), self.resolve_value(value, depth - 1, path)?);
+                }
+                path.pop();
+                Ok(Value::Object(object))
+            }
+            Value::Array(items) => Ok(Value::Array(
+                items.iter().map(|v| self.resolve_value(v, depth, path)).collect::<Result<_>>()?,
+            )),
+            Value::Object(fields) => Ok(Value::Object(
+                fields
+                    .iter()
+                    .map(|(k, v)| Ok((k.clone(), self.resolve_value(v, depth, path)?)))
+                    .collect::<Result<_>>()?,
+            )),
+            _ => Ok(value.clone()),
+        }
+    }
+
+    /// 从记录出发广度优先遍历`edge_field`字段连接的记录，最多走`depth`步，返回可达的子图
+    ///
+    /// 边字段可以是引用、同一张表中的记录ID，或由它们组成的数组；适合组织架构、分类树等数据。
+    pub fn traverse(&self, table_name: &str, id: &str, edge_field: &str, depth: usize) -> Result<Subgraph> {
+        self.traverse_with(table_name, id, edge_field, depth, Traversal::BreadthFirst)
+    }
+
+    /// 按指定顺序遍历，见`traverse`
+    pub fn traverse_with(
+        &self,
+        table_name: &str,
+        id: &str,
+        edge_field: &str,
+        depth: usize,
+        order: Traversal,
+    ) -> Result<Subgraph> {
+        graph::traverse(self, table_name, id, edge_field, depth, order)
+    }
+
+    /// 在指定字段上创建空间索引
+    pub fn create_geo_index(&self, table_name: &str, field: &str) -> Result<()> {
+        system::check_indexable(field)?;
+        self.write_table(table_name, |table| table.create_geo_index(field))
+    }
+
+    /// 查询距离给定点`radius_m`米以内的记录，按距离由近到远排序
+    ///
+    /// `point`须为`Value::GeoPoint`，字段上有空间索引时使用索引，否则全表扫描。
+    pub fn find_near(
+        &self,
+        table_name: &str,
+        field: &str,
+        point: &Value,
+        radius_m: f64,
+    ) -> Result<Vec<Arc<Record>>> {
+        let (lat, lon) = point
+            .as_geo_point()
+            .ok_or_else(|| DatabaseError::DataFormat("查询点必须是GeoPoint类型".to_string()))?;
+        self.read_table(table_name, |table| {
+            table
+                .find_near(field, lat, lon, radius_m)
+                .into_iter()
+                .map(|(record, _)| record)
+                .collect()
+        })
+    }
+
+    /// 按余弦相似度返回与`query`最接近的`k`条记录及相似度
+    pub fn find_similar(
+        &self,
+        table_name: &str,
+        field: &str,
+        query: &[f32],
+        k: usize,
+    ) -> Result<Vec<(Arc<Record>, f32)>> {
+        self.find_similar_with(table_name, field, query, k, Metric::Cosine)
+    }
+
+    /// 使用指定相似度度量查询最接近的`k`条记录
+    pub fn find_similar_with(
+        &self,
+        table_name: &str,
+        field: &str,
+        query: &[f32],
+        k: usize,
+        metric: Metric,
+    ) -> Result<Vec<(Arc<Record>, f32)>> {
+        self.read_table(table_name, |table| table.find_similar(field, query, k, metric))
+    }
+
+    /// 执行查询，返回过滤、排序、分页后的记录
+    pub fn query(&self, table_name: &str, query: &Query) -> Result<Vec<Arc<Record>>> {
+        self.traced("query", Some(table_name), Vec::len, || {
+            query.validate()?;
+            self.read_table(table_name, |table| table.query(query))
+        })
+    }
+
+    /// 执行查询，同时返回分页前满足条件的记录数，见`Table::query_with_total`
+    pub fn query_with_total(&self, table_name: &str, query: &Query) -> Result<(Vec<Arc<Record>>, usize)> {
+        self.traced("query", Some(table_name), |(records, _): &(Vec<_>, _)| records.len(), || {
+            query.validate()?;
+            self.read_table(table_name, |table| table.query_with_total(query))
+        })
+    }
+
+    /// 在表上执行聚合管道（过滤、投影、分组、排序、分页）
+    pub fn aggregate(&self, table_name: &str, pipeline: &Pipeline) -> Result<Vec<Document>> {
+        self.traced("aggregate", Some(table_name), Vec::len, || pipeline.execute(self, table_name))
+    }
+
+    /// 返回查询的执行计划而不实际执行
+    pub fn explain(&self, table_name: &str, query: &Query) -> Result<QueryPlan> {
+        query.validate()?;
+        self.read_table(table_name, |table| table.plan(query))
+    }
+
+    /// 注册名为`name`的预备查询，同名的查询被替换；条件值可以是`param`参数占位符
+    ///
+    /// 查询在注册时校验并选定执行计划：选中了索引时固定为该索引，之后执行不再重新规划。
+    /// 预备查询保存在数据目录中，重新打开数据库后仍然可用。
+    pub fn prepare(&self, name: &str, table_name: &str, query: Query) -> Result<PreparedQuery> {
+        self.check_writable()?;
+        query.validate_template()?;
+        let mut query = query;
+        if query.hint == IndexHint::Auto {
+            if let Some(field) = self.read_table(table_name, |table| table.plan(&query))?.index {
+                query.hint = IndexHint::Use(field);
+            }
+        }
+        let prepared = PreparedQuery {
+            table: table_name.to_string(),
+            params: query.params().map(str::to_string).collect(),
+            query,
+        };
+        self.prepared.insert(name, prepared.clone())?;
+        Ok(prepared)
+    }
+
+    /// 绑定参数执行预备查询；查询不存在、缺少参数或有未知参数时返回`InvalidQuery`
+    pub fn execute_prepared(&self, name: &str, bindings: &IndexMap<String, Value>) -> Result<Vec<Arc<Record>>> {
+        let prepared = self.prepared_query(name)?;
+        let query = prepared.bind(bindings)?;
+        self.read_table(&prepared.table, |table| table.query(&query))
+    }
+
+    /// 名为`name`的预备查询
+    pub fn prepared_query(&self, name: &str) -> Result<PreparedQuery> {
+        self.prepared
+            .get(name)
+            .ok_or_else(|| DatabaseError::InvalidQuery(format!("预备查询 {} 不存在", name)))
+    }
+
+    /// 所有预备查询的名称，按名称排序
+    pub fn prepared_queries(&self) -> Vec<String> {
+        self.prepared.names()
+    }
+
+    /// 删除预备查询，返回该查询是否存在
+    pub fn unprepare(&self, name: &str) -> Result<bool> {
+        self.check_writable()?;
+        self.prepared.remove(name)
+    }
+
+    /// 在指定字段上创建等值索引
+    pub fn create_index(&self, table_name: &str, field: &str) -> Result<()> {
+        system::check_indexable(field)?;
+        self.write_table(table_name, |table| table.create_index(field))
+    }
+
+    /// 在指定字段上创建部分索引，只收录满足`filter`中全部条件的记录
+    pub fn create_partial_index(&self, table_name: &str, field: &str, filter: Vec<Condition>) -> Result<()> {
+        system::check_indexable(field)?;
+        self.write_table(table_name, |table| table.create_partial_index(field, filter))
+    }
+
+    /// 在后台线程中创建等值索引（`filter`不为空时为部分索引），不阻塞表上的其他操作
+    ///
+    /// 索引在开始时的记录快照上构建，完成后在写锁内补上构建期间被修改的记录再启用；
+    /// 进度见`index_builds`。返回的句柄可用于等待创建完成。
+    pub fn create_index_background(
+        &self,
+        table_name: &str,
+        field: &str,
+        filter: Vec<Condition>,
+    ) -> Result<std::thread::JoinHandle<Result<()>>> {
+        self.check_writable()?;
+        system::check_indexable(field)?;
+        let handle = self.get_table(table_name)?;
+        let (snapshot, processed) = write_lock(table_name, &handle)?.begin_index_build(field)?;
+        let field = field.to_string();
+        Ok(std::thread::spawn(move || {
+            let mut index = FieldIndex::partial(filter);
+            for record in snapshot.values() {
+                index.add_record(&field, record);
+                processed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+            handle.write().recover().finish_index_build(&field, index, &snapshot)
+        }))
+    }
+
+    /// 后台创建中的索引及其进度
+    pub fn index_builds(&self, table_name: &str) -> Result<Vec<IndexBuildProgress>> {
+        self.read_table(table_name, |table| table.index_builds())
+    }
+
+    /// 在一个或多个字段上创建唯一索引，例如订单的`(user_id, product_id)`
+    ///
+    /// 缺少任一字段或值为null的记录不受约束；现有记录已有重复时返回`DuplicateKey`。
+    pub fn create_unique_index(&self, table_name: &str, fields: &[&str]) -> Result<()> {
+        fields.iter().try_for_each(|field| system::check_indexable(field))?;
+        self.write_table(table_name, |table| {
+            table.create_unique_index(fields.iter().map(|f| f.to_string()).collect())
+        })
+    }
+
+    /// 删除字段上的索引（包括地理位置索引和以逗号连接字段名命名的唯一索引），返回索引是否存在
+    pub fn drop_index(&self, table_name: &str, field: &str) -> Result<bool> {
+        self.write_table(table_name, |table| table.drop_index(field))
+    }
+
+    /// 列出表上的所有索引
+    pub fn list_indexes(&self, table_name: &str) -> Result<Vec<IndexInfo>> {
+        self.read_table(table_name, |table| table.indexes())
+    }
+
+    /// 取最多`sample_size`条记录推断表结构
+    pub fn sample_schema(&self, table_name: &str, sample_size: usize) -> Result<SchemaSample> {
+        self.read_table(table_name, |table| table.sample_schema(sample_size))
+    }
+
+    /// 重新收集表的统计信息（字段基数、最值、null比例、直方图），供查询计划估计结果规模
+    ///
+    /// 保存表时如果自上次统计以来修改的记录超过10%，也会自动重新统计。
+    pub fn analyze(&self, table_name: &str) -> Result<TableStats> {
+        self.write_table(table_name, |table| table.analyze().cloned())
+    }
+
+    /// 表的统计信息，还没有统计过时为None
+    pub fn table_stats(&self, table_name: &str) -> Result<Option<TableStats>> {
+        self.read_table(table_name, |table| table.stats().cloned())
+    }
+
+    /// 表的修改与保存状态：最近修改和保存的时间、是否有未保存的修改、未保存的记录修改数
+    pub fn table_info(&self, table_name: &str) -> Result<TableInfo> {
+        self.read_table(table_name, |table| table.info())
+    }
+
+    /// 表自打开以来的读写计数：读取、写入、全表扫描、索引命中与未命中和写入的字节数
+    pub fn table_counters(&self, table_name: &str) -> Result<Counters> {
+        self.read_table(table_name, |table| table.counters())
+    }
+
+    /// 所有表的读写计数，按表名排列
+    pub fn counters(&self) -> Vec<(String, Counters)> {
+        self.with_all_tables(|table| table.counters())
+    }
+
+    /// 扫描全部记录推断表结构：出现过的字段、类型分布、覆盖率和是否可为空
+    pub fn infer_schema(&self, table_name: &str) -> Result<SchemaSample> {
+        self.sample_schema(table_name, usize::MAX)
+    }
+
+    /// 声明字段的类型，None表示取消声明；声明保存在表的元数据中
+    ///
+    /// 声明为`FieldType::DateTime`的字段，HTTP API和命令行收到的ISO-8601字符串保存为时间戳，
+    /// 范围查询和排序按时间先后进行。已有的记录不会转换；严格模式的表中已有记录不符合修改后的声明时返回`Validation`错误。
+    pub fn set_field_type(&self, table_name: &str, field: &str, field_type: Option<FieldType>) -> Result<()> {
+        self.write_table(table_name, |table| table.set_field_type(field, field_type))
+    }
+
+    /// 按字段声明的类型
+    pub fn field_types(&self, table_name: &str) -> Result<BTreeMap<String, FieldType>> {
+        self.read_table(table_name, |table| table.field_types().clone())
+    }
+
+    /// 切换表的结构模式，模式保存在表的元数据中
+    ///
+    /// 严格模式的表只能写入声明了类型的字段，值必须符合声明的类型，否则写入返回`Validation`错误。
+    /// 切换为严格模式时检查所有已有的记录，有不符合的记录时返回列出这些记录的`Validation`错误，表保持原来的模式；
+    /// 可以先补充字段声明或修正记录再切换。切换回宽松模式总是成功。
+    pub fn set_table_mode(&self, table_name: &str, mode: TableMode) -> Result<()> {
+        self.write_table(table_name, |table| table.set_mode(mode))
+    }
+
+    /// 在同一把写锁内一起修改多个字段声明（None取消声明）和结构模式（None不变）
+    ///
+    /// 修改后为严格模式时先检查所有已有的记录，有不符合的记录时什么也不修改，返回`Validation`错误。
+    pub fn set_table_schema(
+        &self,
+        table_name: &str,
+        types: &BTreeMap<String, Option<FieldType>>,
+        mode: Option<TableMode>,
+    ) -> Result<()> {
+        self.write_table(table_name, |table| table.set_schema(types, mode))
+    }
+
+    /// 表的结构模式
+    pub fn table_mode(&self, table_name: &str) -> Result<TableMode> {
+        self.read_table(table_name, |table| table.mode())
+    }
+
+    /// 执行只读的SQL SELECT语句，支持投影、条件、等值连接、分组聚合、排序和分页
+    pub fn sql(&self, sql: &str) -> Result<SqlResult> {
+        self.traced("sql", None, |result: &SqlResult| result.rows.len(), || sql::parse(sql)?.execute(self))
+    }
+
+    /// 解析并规划SQL SELECT语句、不执行，返回结果的列，见`Select::describe`
+    pub fn describe_sql(&self, sql: &str) -> Result<Vec<SqlColumn>> {
+        sql::parse(sql)?.describe(self)
+    }
+
+    fn keyring(&self) -> Result<&Keyring> {
+        self.keyring
+            .as_ref()
+            .ok_or_else(|| DatabaseError::Config("加密字段需要配置主密钥".to_string()))
+    }
+
+    /// 用主体（如用户ID）的数据密钥加密一个值，首次使用时为该主体生成密钥
+    ///
+    /// 返回的`Value::Sealed`可以像普通值一样写入记录，读取时用`reveal`或`unseal`解密。
+    pub fn seal(&self, subject: &str, value: &Value) -> Result<Value> {
+        let key = self.keyring()?.key_for(subject)?;
+        Ok(Value::Sealed {
+            subject: subject.to_string(),
+            data: key.encrypt(&bincode::serialize(value)?)?,
+        })
+    }
+
+    /// 确定性地加密一个值，`domain`通常为字段名
+    ///
+    /// 同一域中相同的明文总是得到相同的密文，因此加密后仍可以做等值查询、建索引和唯一性检查。
+    /// 代价是泄露了哪些记录的值相等（以及各个值出现的频率），只应用于需要按值查找的字段；
+    /// 密文的顺序与明文无关，对这类值的范围查询会被`Query::validate`拒绝。
+    /// 包含对象的值没有唯一的编码，不能确定性加密。
+    pub fn seal_deterministic(&self, domain: &str, value: &Value) -> Result<Value> {
+        fn has_object(value: &Value) -> bool {
+            match value {
+                Value::Object(_) => true,
+                Value::Array(items) => items.iter().any(has_object),
+                _ => false,
+            }
+        }
+        if has_object(value) {
+            return Err(DatabaseError::DataFormat("对象类型的值不能确定性加密".to_string()));
+        }
+        let siv = self.deterministic_cipher()?;
+        Ok(Value::Deterministic {
+            domain: domain.to_string(),
+            data: siv.encrypt(&[domain.as_bytes()], &bincode::serialize(value)?),
+        })
+    }
+
+    fn deterministic_cipher(&self) -> Result<&Siv> {
+        self.siv
+            .as_ref()
+            .ok_or_else(|| DatabaseError::Config("加密字段需要配置主密钥".to_string()))
+    }
+
+    /// 解密`Value::Sealed`或`Value::Deterministic`，主体已被遗忘时返回None；其他值原样返回
+    pub fn unseal(&self, value: &Value) -> Result<Option<Value>> {
+        match value {
+            Value::Sealed { subject, data } => match self.keyring()?.existing_key(subject)? {
+                Some(key) => Ok(Some(bincode::deserialize(&key.decrypt(data)?)?)),
+                None => Ok(None),
+            },
+            Value::Deterministic { domain, data } => {
+                let plaintext = self.deterministic_cipher()?.decrypt(&[domain.as_bytes()], data)?;
+                Ok(Some(bincode::deserialize(&plaintext)?))
+            }
+            other => Ok(Some(other.clone())),
+        }
+    }
+
+    /// 返回解密后的记录副本，包括数组和对象中的加密值；已被遗忘的值为`Null`
+    pub fn reveal(&self, record: &Record) -> Result<Record> {
+        let mut revealed = record.clone();
+        for value in revealed.data.values_mut() {
+            *value = self.reveal_value(value)?;
+        }
+        Ok(revealed)
+    }
+
+    fn reveal_value(&self, value: &Value) -> Result<Value> {
+        match value {
+            Value::Sealed { .. } | Value::Deterministic { .. } => Ok(self.unseal(value)?.unwrap_or(Value::Null)),
+            Value::Array(items) => items.iter().map(|v| self.reveal_value(v)).collect::<Result<_>>().map(Value::Array),
+            Value::Object(fields) => fields
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), self.reveal_value(v)?)))
+                .collect::<Result<_>>()
+                .map(Value::Object),
+            other => Ok(other.clone()),
+        }
+    }
+
+    /// 销毁主体的数据密钥（加密擦除），之后该主体所有加密字段都无法再解密
+    ///
+    /// 只改写密钥文件，不需要改写任何表文件。返回该主体是否有密钥。
+    pub fn forget(&self, subject: &str) -> Result<bool> {
+        self.check_writable()?;
+        self.keyring()?.forget(subject)
+    }
+
+    /// 报告静态加密的覆盖情况：每张表使用的密钥和含有字段级加密值的字段，数据目录中每个文件是否加密，
+    /// 以及磁盘上仍是旧加密状态、等待重新保存的文件
+    ///
+    /// 按已保存的文件判断，尚未保存的修改不计入。租户分区有各自的报告。
+    pub fn security_report(&self) -> Result<SecurityReport> {
+        let mut report = SecurityReport::default();
+        let mut tables = self.list_tables();
+        tables.sort();
+        for name in tables {
+            let (sealed_fields, deterministic_fields) = self.read_table(&name, |table| {
+                let (mut sealed, mut deterministic) = (BTreeSet::new(), BTreeSet::new());
+                for record in table.find_all() {
+                    for (field, value) in &record.data {
+                        match value {
+                            Value::Sealed { .. } => sealed.insert(field.clone()),
+                            Value::Deterministic { .. } => deterministic.insert(field.clone()),
+                            _ => false,
+                        };
+                    }
+                }
+                (sealed, deterministic)
+            })?;
+            let overrides = self.table_config(&name);
+            let encrypted = self.table_crypto(&name).is_some();
+            report.tables.push(TableSecurity {
+                encrypted,
+                key_id: overrides.encryption_key_id,
+                plaintext_fields: if encrypted { overrides.plaintext_fields } else { Vec::new() },
+                table: name,
+                sealed_fields,
+                deterministic_fields,
+                unmigrated_files: Vec::new(),
+            });
+        }
+
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&self.config.data_dir)? {
+            let entry = entry?;
+            // 锁文件中只有魔数
+            if entry.file_type()?.is_file() && entry.file_name() != LOCK_FILE {
+                files.push((entry.file_name().to_string_lossy().into_owned(), entry.path()));
+            }
+        }
+        files.sort();
+        let decrypts = |crypto: Option<Crypto>, content: &[u8]| crypto.is_some_and(|c| c.decrypt(content).is_ok());
+        for (name, path) in files {
+            // 带文件头的表文件只读取文件头
+            let header = match name.ends_with(".db") {
+                true => format::read_header(&path).ok().flatten(),
+                false => None,
+            };
+            let content = match header {
+                Some(_) => Vec::new(),
+                None => std::fs::read(&path)?,
+            };
+            // 表文件、统计信息文件和压缩字典文件所属的表
+            let table = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("db") => Some(timeseries::table_of_file(&name)),
+                Some("stats") => name.strip_suffix(".stats"),
+                Some(DICTIONARY_EXTENSION) => name.strip_suffix(".dict"),
+                _ => None,
+            };
+            let encrypted = match table {
+                Some(table) if name.ends_with(".db") => match &header {
+                    Some(header) => header.is_encrypted(),
+                    // 没有文件头的旧版本文件只能尝试解密
+                    _ => decrypts(self.table_crypto(table), &content),
+                },
+                Some(table) => decrypts(self.table_crypto(table), &content),
+                None if name == PREPARED_FILE || name == SYNC_FILE || name == RAFT_FILE || name == CDC_FILE || name == SCHEDULE_FILE || path.extension().is_some_and(|ext| ext == BLOB_EXTENSION) => {
+                    decrypts(self.crypto.clone(), statefile::strip_magic(&content))
+                }
+                None if name == RAFT_LOG_FILE => statefile::frames_encrypted(&path, RAFT_LOG_MAGIC, self.crypto.as_ref()),
+                None if name == CHANGES_FILE => statefile::frames_encrypted(&path, CHANGES_MAGIC, self.crypto.as_ref()),
+                None => name == KEYRING_FILE,
+            };
+            if let Some(table) = table.and_then(|table| report.tables.iter_mut().find(|t| t.table == table)) {
+                if table.encrypted != encrypted {
+                    table.unmigrated_files.push(name.clone());
+                }
+            }
+            report.files.push(FileSecurity { name, encrypted });
+        }
+        Ok(report)
+    }
+
+    /// 对照清单检查所有表文件，发现在数据库之外被修改、删除或新增的文件
+    ///
+    /// `deep`为true时还会解密（校验AES-GCM认证标签）并反序列化每个文件。
+    pub fn verify(&self, deep: bool) -> Result<VerifyReport> {
+        self.manifest
+            .verify(Path::new(&self.config.data_dir), &|file| self.table_crypto(timeseries::table_of_file(file)), deep)
+    }
+
+    /// 对所有表同时加读锁，在同一时刻对每张表调用`f`，结果按表名排列
+    fn with_all_tables<T>(&self, f: impl Fn(&Table) -> T) -> Vec<(String, T)> {
+        let tables = self.tables.read().recover();
+        let mut names: Vec<&String> = tables.keys().collect();
+        // 按固定顺序加锁，避免与其他同时锁多张表的操作死锁
+        names.sort();
+        let guards: Vec<_> = names.iter().map(|name| tables[*name].read().recover()).collect();
+        names.iter().zip(&guards).map(|(name, table)| (name.to_string(), f(table))).collect()
+    }
+
+    /// 开始只读事务：取所有表同一时刻的快照，之后的多次查询看到同一份数据
+    ///
+    /// 只在复制记录指针时持有所有表的读锁，之后的读写照常进行，不受只读事务影响。
+    pub fn read_transaction(&self) -> ReadTransaction {
+        ReadTransaction::new(self.with_all_tables(|table| table.view()).into_iter().collect())
+    }
+
+    /// 在所有表的一致快照上收集备份的文件：表文件、清单和数据目录中的其他文件（统计信息、主体密钥等）
+    ///
+    /// 只在复制记录指针时持有所有表的读锁，编码表文件时读写照常进行；未保存的修改也包含在内。
+    fn snapshot_files(&self) -> Result<Vec<backup::ArchiveFile>> {
+        let snapshots = self.with_all_tables(|table| table.snapshot());
+
+        let mut files = Vec::new();
+        let mut part_sets = Vec::new();
+        for (table, snapshot) in snapshots {
+            for (name, records) in snapshot.files {
+                files.push((name, format::encode(&records, snapshot.crypto.as_ref(), snapshot.options)?));
+            }
+            if let Some(set) = snapshot.parts {
+                let name = format!("{}.{}", table, PARTS_EXTENSION);
+                part_sets.push((name, statefile::encode(PARTS_MAGIC, &bincode::serialize(&set)?)));
+            }
+        }
+        let table_files = files.len();
+        files.extend(part_sets);
+        let snapshotted: BTreeSet<String> = files.iter().map(|(name, _)| name.clone()).collect();
+        let mut stale = Vec::new();
+        for entry in std::fs::read_dir(&self.config.data_dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if !entry.file_type()?.is_file() || path.extension().is_some_and(|ext| ext == "tmp") {
+                continue;
+            }
+            if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                // 快照中没有的段文件是已清空、尚未删除的段；拆分出的文件和部分清单以快照中的为准，
+                // 磁盘上的是上次保存时的版本
+                let stale_segment = timeseries::parse_segment_file_name(name)
+                    .map(|(table, _)| table)
+                    .or_else(|| storage::parse_part_file_name(name).map(|(table, ..)| table))
+                    .or_else(|| name.strip_suffix(&format!(".{}", PARTS_EXTENSION)))
+                    .is_some_and(|table| snapshotted.contains(&format!("{}.db", table)));
+                if stale_segment && !snapshotted.contains(name) {
+                    stale.push(name.to_string());
+                } else if name != MANIFEST_FILE && name != LOCK_FILE && !snapshotted.contains(name) {
+                    files.push((name.to_string(), std::fs::read(&path)?));
+                }
+            }
+        }
+        // 清单按快照中的表文件内容登记、去掉没有备份的旧文件，恢复后校验能通过
+        let manifest = self.manifest.encode_with(&files[..table_files], &stale)?;
+        files.push((MANIFEST_FILE.to_string(), manifest));
+        files.sort();
+        Ok(files)
+    }
+
+    fn backup_key(&self) -> Result<Option<ed25519_dalek::SigningKey>> {
+        self.config.backup_signing_key.as_deref().map(backup::signing_key).transpose()
+    }
+
+    /// 把数据目录中的所有文件（表文件、清单和主体密钥）写入备份文件，返回备份的文件数
+    ///
+    /// 备份取自所有表同一时刻的快照，期间服务器照常读写；配置了`backup_signing_key`时备份带有Ed25519签名。
+    pub fn backup(&self, path: &Path) -> Result<usize> {
+        let files = self.snapshot_files()?;
+        backup::write_archive(path, &files, self.backup_key()?.as_ref())?;
+        Ok(files.len())
+    }
+
+    /// 生成与`backup`相同的备份内容，直接返回而不写入文件
+    pub fn backup_bytes(&self) -> Result<Vec<u8>> {
+        backup::encode_archive(&self.snapshot_files()?, self.backup_key()?.as_ref())
+    }
+
+    /// 删除数据目录中确认属于数据库的文件：带文件头的表文件及其统计信息，以及以魔数开头的状态文件
+    /// （清单、主体密钥、预备查询、定时任务、同步、CDC和集群状态，见`statefile`），只读取文件头判断
+    ///
+    /// 必须在打开数据库之前调用。其他文件（包括无法识别的旧版本表文件和没有魔数的旧版本状态文件）保留并在结果中列出，
+    /// 数据目录为空时一并删除，因此在共享目录中清理测试数据库不会误删无关文件。
+    pub fn destroy(config: &Config) -> Result<DestroyReport> {
+        let data_dir = Path::new(&config.data_dir);
+        let mut report = DestroyReport::default();
+        if !data_dir.exists() {
+            return Ok(report);
+        }
+
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(data_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_file() {
+                files.push((name, entry.path()));
+            } else {
+                report.kept.push(name);
+            }
+        }
+        files.sort();
+
+        // 先识别表文件，统计信息文件只随可识别的表文件删除
+        let mut tables = BTreeSet::new();
+        for (_, path) in &files {
+            if path.extension().is_some_and(|ext| ext == "db") && format::is_table_file(path)? {
+                tables.insert(path.with_extension(""));
+            }
+        }
+        for (name, path) in files {
+            let owned = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("db") | Some("stats") | Some(META_EXTENSION) | Some(PLAINTEXT_EXTENSION) | Some(DICTIONARY_EXTENSION) => {
+                    tables.contains(&path.with_extension(""))
+                }
+                _ if statefile::is_state_file(&path)? => true,
+                // blob文件名是64位十六进制的哈希
+                Some(BLOB_EXTENSION) => name.len() == 64 + 1 + BLOB_EXTENSION.len() && name[..64].bytes().all(|b| b.is_ascii_hexdigit()),
+                _ => false,
+            };
+            if owned {
+                std::fs::remove_file(&path)?;
+                report.removed.push(name);
+            } else {
+                report.kept.push(name);
+            }
+        }
+        report.kept.sort();
+
+        if report.kept.is_empty() {
+            std::fs::remove_dir(data_dir)?;
+        }
+        Ok(report)
+    }
+
+    /// 修复数据目录：跳过损坏的记录后写回表文件，无法读取的文件移入隔离目录，并重建清单；数据库必须未打开
+    pub fn repair(config: &Config) -> Result<RepairReport> {
+        repair::repair(config)
+    }
+
+    /// 校验备份文件后用其中的文件替换数据目录的内容，返回恢复的文件数
+    ///
+    /// 必须在打开数据库之前调用，恢复期间持有数据目录的排他锁。用`backup_verifying_key`校验签名，
+    /// 未签名的备份只有`allow_unsigned_backup`时才接受；签名或格式校验失败时不会修改数据目录。
+    /// 备份内容先完整写入暂存目录并落盘，提交后才替换数据目录中的文件，中途崩溃时下次打开数据库会完成替换。
+    /// 备份中没有的表文件会被删除，使恢复后的表与备份时一致。
+    pub fn restore(config: &Config, path: &Path) -> Result<usize> {
+        let key = config.backup_verifying_key.as_deref().map(backup::verifying_key).transpose()?;
+        let files = backup::read_archive(path, key.as_ref(), config.allow_unsigned_backup)?;
+        for (name, _) in &files {
+            check_file_name(name)?;
+        }
+
+        let data_dir = Path::new(&config.data_dir);
+        std::fs::create_dir_all(data_dir)?;
+        let _lock = DirLock::exclusive(data_dir)?;
+        finish_restore(data_dir)?;
+        stage_restore(data_dir, &files)?;
+        finish_restore(data_dir)?;
+        Ok(files.len())
+    }
+
+    /// 按记录ID把另一个数据库的所有表合并进来，保留记录原有的ID和时间戳
+    ///
+    /// 合并进来的记录与插入一样检查数据（严格模式、校验函数），有不符合的记录时不做任何修改，返回`Validation`错误；
+    /// `MergeStrategy::Error`也会先检查所有表，存在冲突时不做任何修改。
+    pub fn merge_from(&self, other: &SimpleDB, strategy: MergeStrategy) -> Result<MergeReport> {
+        self.check_local_write()?;
+        let mut tables = other.list_tables();
+        tables.sort();
+
+        for name in &tables {
+            let Ok(handle) = self.get_table(name) else { continue };
+            let existing = read_lock(name, &handle)?;
+            for record in other.find_all(name)? {
+                if strategy == MergeStrategy::Error && existing.find_by_id(&record.id).is_some_and(|current| current != record) {
+                    return Err(DatabaseError::DuplicateKey(format!("表 {} 中的记录 {}", name, record.id)));
+                }
+                existing.check_incoming(&record.data).map_err(|e| match e {
+                    DatabaseError::Validation(errors) => DatabaseError::Validation(
+                        errors
+                            .into_iter()
+                            .map(|e| FieldError::new(e.field, format!("表 {} 记录 {}: {}", name, record.id, e.message)))
+                            .collect(),
+                    ),
+                    e => e,
+                })?;
+            }
+        }
+
+        let mut report = MergeReport::default();
+        for name in &tables {
+            let records = other.find_all(name)?;
+            self.create_table(name)?;
+            self.write_table(name, |table| {
+                for record in records {
+                    match table.find_by_id(&record.id) {
+                        None => {
+                            table.restore_checked(record)?;
+                            report.inserted += 1;
+                        }
+                        Some(current) if current == record => report.skipped += 1,
+                        Some(current) => match strategy {
+                            MergeStrategy::Newest if record.updated_at > current.updated_at => {
+                                table.restore_checked(record)?;
+                                report.replaced += 1;
+                            }
+                            MergeStrategy::Newest | MergeStrategy::Skip => report.skipped += 1,
+                            MergeStrategy::Error => {
+                                return Err(DatabaseError::DuplicateKey(format!("表 {} 中的记录 {}", name, record.id)))
+                            }
+                        },
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        Ok(report)
+    }
+
+    /// 本实例在同步中的节点ID，第一次使用时生成并保存在数据目录中
+    pub fn node_id(&self) -> Result<String> {
+        self.with_sync(|state| Ok(state.node().to_string()))
+    }
+
+    /// 与`remote_url`（如`http://192.168.1.10:8080`）上运行的服务器双向同步，并发修改按后写者胜出处理
+    pub fn sync_with(&self, remote_url: &str) -> Result<SyncReport> {
+        self.sync_with_resolver(remote_url, Conflict::last_writer_wins)
+    }
+
+    /// 与远端服务器双向同步，两边并发修改同一条记录时由`resolve`给出合并结果，返回None表示删除
+    ///
+    /// 每条记录带有版本向量：只交换对方没有或比对方新的记录，离线期间的修改在下次同步时合并。
+    /// 事件表不参与同步。
+    pub fn sync_with_resolver<F>(&self, remote_url: &str, resolve: F) -> Result<SyncReport>
+    where
+        F: FnMut(&Conflict) -> Option<Record>,
+    {
+        self.with_sync(|state| state.sync(self, |path, body| sync::post(remote_url, path, &body), resolve))
+    }
+
+    /// 服务器端的同步第一步：根据客户端的记录版本返回客户端需要的记录
+    pub fn sync_offer(&self, versions: &[Digest]) -> Result<SyncOffer> {
+        self.with_sync(|state| state.offer(self, versions))
+    }
+
+    /// 服务器端的同步第二步：写入客户端发来的记录，并发修改按后写者胜出处理；返回写入的记录数
+    pub fn sync_accept(&self, entries: Vec<SyncEntry>) -> Result<usize> {
+        self.with_sync(|state| Ok(state.accept(self, entries, Conflict::last_writer_wins)?.0))
+    }
+
+    fn with_sync<T>(&self, f: impl FnOnce(&mut SyncState) -> Result<T>) -> Result<T> {
+        let mut guard = self.sync.lock().recover();
+        let state = match guard.as_mut() {
+            Some(state) => state,
+            None => guard.insert(SyncState::open(Path::new(&self.config.data_dir), self.crypto.clone())?),
+        };
+        f(state)
+    }
+
+    /// 按cron表达式（UTC时间的分、时、日、月、周）定期运行任务，替换同名的任务
+    ///
+    /// 计划保存在数据目录中，重启后继续生效；任务由`run_due_jobs`运行。
+    pub fn schedule(&self, name: &str, cron: &str, job: Job) -> Result<()> {
+        if self.config.read_only {
+            return Err(DatabaseError::NotPermitted("只读打开的数据库不能添加定时任务".to_string()));
+        }
+        self.with_schedules(|schedules| schedules.insert(name, cron, job, unix_now()))
+    }
+
+    /// 删除定时任务，返回任务是否存在
+    pub fn unschedule(&self, name: &str) -> Result<bool> {
+        if self.config.read_only {
+            return Err(DatabaseError::NotPermitted("只读打开的数据库不能删除定时任务".to_string()));
+        }
+        self.with_schedules(|schedules| schedules.remove(name))
+    }
+
+    /// 所有定时任务，按名称排列
+    pub fn scheduled_jobs(&self) -> Result<Vec<ScheduledJob>> {
+        self.with_schedules(|schedules| Ok(schedules.jobs()))
+    }
+
+    /// 注册`Job::Custom(name)`的处理函数；处理函数不保存，每次打开数据库后要重新注册
+    pub fn on_job(&self, name: &str, handler: impl Fn(&SimpleDB) -> Result<()> + Send + Sync + 'static) {
+        let _ = self.with_schedules(|schedules| {
+            schedules.set_handler(name, Arc::new(handler));
+            Ok(())
+        });
+    }
+
+    /// 运行所有到期的定时任务，返回运行的任务名和结果；服务器每秒调用一次，嵌入使用时应定期调用
+    ///
+    /// 任务依次在当前线程中运行，失败的原因记录在`ScheduledJob::last_error`中。只读打开时不运行任何任务。
+    pub fn run_due_jobs(&self) -> Result<Vec<(String, Result<()>)>> {
+        self.run_jobs_due_at(unix_now())
+    }
+
+    pub(crate) fn run_jobs_due_at(&self, now: i64) -> Result<Vec<(String, Result<()>)>> {
+        if self.config.read_only {
+            return Ok(Vec::new());
+        }
+        // 任务可能运行很久，期间不持有锁
+        let due = self.with_schedules(|schedules| schedules.take_due(now))?;
+        let mut results = Vec::with_capacity(due.len());
+        for (name, job, handler) in due {
+            let result = job.run(self, handler);
+            let error = result.as_ref().err().map(|e| e.to_string());
+            self.with_schedules(|schedules| schedules.finish(&name, now, error))?;
+            results.push((name, result));
+        }
+        Ok(results)
+    }
+
+    fn with_schedules<T>(&self, f: impl FnOnce(&mut Schedules) -> Result<T>) -> Result<T> {
+        let mut guard = self.schedules.lock().recover();
+        let schedules = match guard.as_mut() {
+            Some(schedules) => schedules,
+            None => guard.insert(Schedules::open(Path::new(&self.config.data_dir), self.crypto.clone())?),
+        };
+        f(schedules)
+    }
+
+    /// 参与同步的表：事件表以外的所有表
+    pub(crate) fn sync_tables(&self) -> Vec<String> {
+        let handles: Vec<(String, TableHandle)> =
+            self.tables.read().recover().iter().map(|(name, handle)| (name.clone(), Arc::clone(handle))).collect();
+        handles
+            .into_iter()
+            .filter(|(_, handle)| !handle.read().recover().is_append_only())
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// 写入同步得到的记录，保留其ID和时间戳；None表示删除
+    ///
+    /// 写入的记录与插入一样检查数据，严格模式的表拒绝不符合声明的记录。
+    pub(crate) fn apply_synced(&self, table_name: &str, id: &str, record: Option<Record>) -> Result<()> {
+        self.check_local_write()?;
+        self.create_table(table_name)?;
+        self.write_table(table_name, |table| {
+            match record {
+                Some(record) if table.find_by_id(id).as_deref() != Some(&record) => table.restore_checked(Arc::new(record))?,
+                Some(_) => {}
+                None if table.find_by_id(id).is_some() => table.remove(id)?,
+                None => {}
+            }
+            Ok(())
+        })
+    }
+
+    /// 把所有文件写成一个单文件数据库（`.sdb`），返回写入的文件数
+    ///
+    /// 与`backup`一样取自所有表同一时刻的快照；用`Config::bundle`可以直接打开写出的文件。
+    pub fn export_bundle(&self, path: &Path) -> Result<usize> {
+        let files = self.snapshot_files()?;
+        bundle::write_bundle(path, &files)?;
+        Ok(files.len())
+    }
+
+    /// 按当前的压缩和加密设置完整重写所有表文件并清理不再引用的blob文件，返回重写的表数
+    pub fn compact(&self) -> Result<usize> {
+        if self.config.read_only {
+            return Ok(0);
+        }
+        let handles: Vec<(String, TableHandle)> =
+            self.tables.read().recover().iter().map(|(name, handle)| (name.clone(), Arc::clone(handle))).collect();
+        for (name, handle) in &handles {
+            write_lock(name, handle)?.rewrite()?;
+        }
+        self.blobs.collect_garbage()?;
+        if let Some(path) = &self.config.bundle {
+            self.export_bundle(Path::new(path))?;
+        }
+        Ok(handles.len())
+    }
+
+    /// 保存所有表到磁盘，单文件模式下同时重写单文件
+    pub fn save_all(&self) -> Result<()> {
+        if self.config.read_only {
+            return Ok(());
+        }
+        let handles: Vec<(String, TableHandle)> =
+            self.tables.read().recover().iter().map(|(name, handle)| (name.clone(), Arc::clone(handle))).collect();
+        // 修改时发生panic的表不写回磁盘，保留上次保存的内容，其余的表照常保存
+        let mut poisoned = None;
+        for (name, handle) in &handles {
+            match write_lock(name, handle) {
+                Ok(mut table) => table.save()?,
+                Err(e) => poisoned = poisoned.or(Some(e)),
+            }
+        }
+        if let Some(e) = poisoned {
+            return Err(e);
+        }
+        self.blobs.collect_garbage()?;
+        if let Some(path) = &self.config.bundle {
+            self.export_bundle(Path::new(path))?;
+        }
+        Ok(())
+    }
+
+    /// 获取表列表
+    pub fn list_tables(&self) -> Vec<String> {
+        self.tables.read().recover().keys().cloned().collect()
+    }
+
+    /// 获取表的记录数量
+    pub fn count(&self, table_name: &str) -> Result<usize> {
+        self.read_table(table_name, |table| table.count())
+    }
+
+    /// 创建包含示例数据的数据库
+    pub fn create_sample_db() -> Result<Self> {
+        let key = Crypto::generate_key();
+        let config = Config {
+            data_dir: "./sample_data".to_string(),
+            encryption_key: Some(key),
+            max_file_size: 1024 * 1024,
+            ..Config::default()
+        };
+
+        let db = Self::new(config)?;
+
+        // 创建用户表
+        db.create_table("users")?;
+        
+        // 插入示例数据
+        let mut user1 = IndexMap::new();
+        user1.insert("name".to_string(), Value::String("张三".to_string()));
+        user1.insert("age".to_string(), Value::Int(25));
+        user1.insert("email".to_string(), Value::String("zhangsan@example.com".to_string()));
+        
+        let mut user2 = IndexMap::new();
+        user2.insert("name".to_string(), Value::String("李四".to_string()));
+        user2.insert("age".to_string(), Value::Int(30));
+        user2.insert("email".to_string(), Value::String("lisi@example.com".to_string()));
+
+        db.insert("users", user1)?;
+        db.insert("users", user2)?;
+
+        // 创建产品表
+        db.create_table("products")?;
+        
+        let mut product1 = IndexMap::new();
+        product1.insert("name".to_string(), Value::String("笔记本电脑".to_string()));
+        product1.insert("price".to_string(), Value::Float(5999.99));
+        product1.insert("category".to_string(), Value::String("电子产品".to_string()));
+        
+        db.insert("products", product1)?;
+
+        db.save_all()?;
+        Ok(db)
+    }
+}
+
+impl Drop for SimpleDB {
+    fn drop(&mut self) {
+        if let Some(cluster) = self.cluster.get() {
+            cluster.stop();
+        }
+        let saved = self.save_all();
+        // 单文件模式的工作目录只是临时副本；写回失败时保留，以免丢失数据
+        if self.config.bundle.is_some() && saved.is_ok() {
+            for handle in self.tables.read().recover().values() {
+                handle.write().recover().discard_changes();
+            }
+            let _ = std::fs::remove_dir_all(&self.config.data_dir);
+        }
+    }
+}
+
+/// 集群模式下把`build`加入的写操作作为一个事务复制
+fn replicate(cluster: &Cluster, build: impl FnOnce(&mut Transaction)) -> Result<()> {
+    let mut tx = Transaction::new();
+    build(&mut tx);
+    cluster.replicate(tx.into_ops())
+}
+
+/// 把要恢复的文件写入暂存目录，全部落盘后改名为`RESTORE_DIR`提交；调用者持有数据目录的排他锁
+fn stage_restore(data_dir: &Path, files: &[backup::ArchiveFile]) -> Result<()> {
+    let staging = data_dir.join(RESTORE_STAGING_DIR);
+    // 上次未提交的暂存内容直接丢弃
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    let staged = staging.join("files");
+    std::fs::create_dir_all(&staged)?;
+    for (name, content) in files {
+        let mut file = File::create(staged.join(name))?;
+        file.write_all(content)?;
+        file.sync_all()?;
+    }
+    let names: Vec<&String> = files.iter().map(|(name, _)| name).collect();
+    let mut file = File::create(staging.join("NAMES"))?;
+    file.write_all(&bincode::serialize(&names)?)?;
+    file.sync_all()?;
+    storage::sync_dir(&staged)?;
+    storage::sync_dir(&staging)?;
+    std::fs::rename(&staging, data_dir.join(RESTORE_DIR))?;
+    storage::sync_dir(data_dir)
+}
+
+/// 用已提交的恢复内容替换数据目录中的文件，没有时直接返回；中途中断后可以重复执行
+///
+/// 删除数据目录中备份没有的表文件，再把暂存的文件逐个改名到数据目录中，最后删除恢复目录。
+fn finish_restore(data_dir: &Path) -> Result<()> {
+    let dir = data_dir.join(RESTORE_DIR);
+    if !dir.exists() {
+        return Ok(());
+    }
+    let names: Vec<String> = bincode::deserialize(&std::fs::read(dir.join("NAMES"))?)?;
+    for entry in std::fs::read_dir(data_dir)? {
+        let path = entry?.path();
+        let restored = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| names.iter().any(|name| name == n));
+        let owned = path.is_file()
+            && path.extension().is_some_and(|ext| {
+                ext == "db"
+                    || ext == "stats"
+                    || ext == META_EXTENSION
+                    || ext == PLAINTEXT_EXTENSION
+                    || ext == DICTIONARY_EXTENSION
+                    || ext == BLOB_EXTENSION
+            });
+        if owned && !restored {
+            std::fs::remove_file(path)?;
+        }
+    }
+    for name in &names {
+        check_file_name(name)?;
+        let staged = dir.join("files").join(name);
+        if staged.exists() {
+            std::fs::rename(staged, data_dir.join(name))?;
+        }
+    }
+    storage::sync_dir(data_dir)?;
+    std::fs::remove_dir_all(&dir)?;
+    storage::sync_dir(data_dir)
+}
+
+/// 备份或单文件中的文件名只能是数据目录下的单个文件，防止写到目录之外
+fn check_file_name(name: &str) -> Result<()> {
+    if Path::new(name).file_name().and_then(|n| n.to_str()) != Some(name) {
+        return Err(DatabaseError::DataFormat(format!("文件名无效: {}", name)));
+    }
+    Ok(())
+}
+
+/// 在`deadline`之前取得表的写锁
+///
+/// 等待会形成循环等待（如当前线程或它等待的线程持有该表的锁）时立即返回`Deadlock`，
+/// 锁只是被占用到超时时返回`LockTimeout`。
+fn lock_before<'a>(name: &str, handle: &'a TableHandle, deadline: Instant) -> Result<Held<RwLockWriteGuard<'a, Table>>> {
+    let wait = TableLocks::global().lock_before(lock_key(handle), deadline, || match handle.try_write() {
+        Ok(guard) => Ok(Some(guard)),
+        Err(TryLockError::Poisoned(_)) => Err(poisoned(name)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+    })?;
+    match wait {
+        Wait::Acquired(guard) => Ok(guard),
+        Wait::Deadlock => Err(DatabaseError::Deadlock(format!("等待表 {} 的锁会形成循环等待", name))),
+        Wait::TimedOut => Err(DatabaseError::LockTimeout(format!("等待表 {} 的锁超时", name))),
+    }
+}
+
+/// 获取表的读锁，表在修改时发生过panic则返回`Internal`
+fn read_lock<'a>(name: &str, handle: &'a TableHandle) -> Result<Held<RwLockReadGuard<'a, Table>>> {
+    TableLocks::global().lock(lock_key(handle), false, || handle.read().map_err(|_| poisoned(name)))
+}
+
+/// 获取表的写锁，表在修改时发生过panic则返回`Internal`
+fn write_lock<'a>(name: &str, handle: &'a TableHandle) -> Result<Held<RwLockWriteGuard<'a, Table>>> {
+    TableLocks::global().lock(lock_key(handle), true, || handle.write().map_err(|_| poisoned(name)))
+}
+
+/// 在`TableLocks`中标识表锁
+fn lock_key(handle: &TableHandle) -> usize {
+    Arc::as_ptr(handle) as usize
+}
+
+fn poisoned(name: &str) -> DatabaseError {
+    DatabaseError::Internal(format!("修改表 {} 时发生panic，内存中的表可能不完整，重新打开数据库后可从磁盘恢复", name))
+}
+
+/// 当前的Unix时间（秒）
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
+
+fn not_time_series(table: &str) -> DatabaseError {
+    DatabaseError::NotPermitted(format!("{} 不是时间序列表", table))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::AssertUnwindSafe;
+    use crate::testing::{config_in, open, open_with, reopen, temp_config, temp_dir};
+    use crate::changes::ChangeKind;
+    use crate::index::IndexKind;
+    use crate::{plaintext, storage, Autosave, Comparator, Quota, QuotaPolicy, SortOrder};
+
+    #[test]
+    fn test_forget_subject() {
+        let (_dir, db) = open_with("forget", Config {
+            encryption_key: Some(Crypto::generate_key()),
+            ..Config::default()
+        });
+        let email = Value::String("alice@example.com".to_string());
+        let id = db
+            .insert(
+                "users",
+                IndexMap::from([
+                    ("name".to_string(), Value::String("alice".to_string())),
+                    ("email".to_string(), db.seal("user-1", &email).unwrap()),
+                ]),
+            )
+            .unwrap();
+
+        let record = db.find_by_id("users", &id).unwrap().unwrap();
+        assert_eq!(db.reveal(&record).unwrap().data["email"], email);
+
+        // 确定性加密的字段可以按等值查询，范围查询被拒绝
+        let ssn = db.seal_deterministic("ssn", &Value::String("123-45-6789".to_string())).unwrap();
+        db.patch("users", &id, &[UpdateOp::Set("ssn".to_string(), ssn.clone())]).unwrap();
+        let lookup = db.seal_deterministic("ssn", &Value::String("123-45-6789".to_string())).unwrap();
+        assert_eq!(lookup, ssn);
+        assert_eq!(db.query("users", &Query::eq("ssn", lookup.clone())).unwrap().len(), 1);
+        assert!(db.query("users", &Query::gt("ssn", lookup)).is_err());
+        assert_eq!(db.unseal(&ssn).unwrap(), Some(Value::String("123-45-6789".to_string())));
+
+        assert!(db.forget("user-1").unwrap());
+        let revealed = db.reveal(&record).unwrap();
+        assert_eq!(revealed.data["email"], Value::Null);
+        assert_eq!(revealed.data["name"], Value::String("alice".to_string()));
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_merge_strategies() {
+        let (_into_dir, into) = open("merge-into");
+        let (_from_dir, from) = open("merge-from");
+        let name = |n: &str| IndexMap::from([("name".to_string(), Value::String(n.to_string()))]);
+
+        let shared = from.insert("users", name("old")).unwrap();
+        let mut stale = (*from.find_by_id("users", &shared).unwrap().unwrap()).clone();
+        stale.data = name("older");
+        stale.updated_at -= 10;
+        into.create_table("users").unwrap();
+        into.write_table("users", |t| {
+            t.restore(Arc::new(stale));
+            Ok(())
+        })
+        .unwrap();
+        from.insert("users", name("new")).unwrap();
+
+        assert!(into.merge_from(&from, MergeStrategy::Error).is_err());
+        assert_eq!(into.count("users").unwrap(), 1);
+
+        let report = into.merge_from(&from, MergeStrategy::Skip).unwrap();
+        assert_eq!(report, MergeReport { inserted: 1, replaced: 0, skipped: 1 });
+
+        let report = into.merge_from(&from, MergeStrategy::Newest).unwrap();
+        assert_eq!(report, MergeReport { inserted: 0, replaced: 1, skipped: 1 });
+        assert_eq!(into.find_by_id("users", &shared).unwrap().unwrap().data, name("old"));
+
+        drop((into, from));
+    }
+
+    #[test]
+    fn test_poisoned_table() {
+        let (_dir, db) = open("poisoned");
+        let name = |n: &str| IndexMap::from([("name".to_string(), Value::String(n.to_string()))]);
+        db.insert("users", name("alice")).unwrap();
+        db.insert("orders", name("book")).unwrap();
+
+        let panicked = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            db.write_table("users", |_| -> Result<()> { panic!("修改到一半") })
+        }));
+        assert!(panicked.is_err());
+        assert!(matches!(db.count("users"), Err(DatabaseError::Internal(_))));
+        assert!(matches!(db.insert("users", name("bob")), Err(DatabaseError::Internal(_))));
+        // 其他表不受影响，损坏的表也可以删除
+        assert_eq!(db.count("orders").unwrap(), 1);
+        db.insert("orders", name("pen")).unwrap();
+        assert!(matches!(db.save_all(), Err(DatabaseError::Internal(_))));
+        db.drop_table("users").unwrap();
+        db.save_all().unwrap();
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_default_order() {
+        let (_dir, db) = open("default-order");
+        for n in 0..20 {
+            db.insert("users", IndexMap::from([("n".to_string(), Value::Int(n))])).unwrap();
+        }
+        let ids = |records: Vec<Arc<Record>>| records.iter().map(|r| r.id.clone()).collect::<Vec<_>>();
+        let all = db.find_all("users").unwrap();
+        assert!(all.windows(2).all(|pair| pair[0].default_order(&pair[1]) == std::cmp::Ordering::Less));
+        // 同一秒内插入的记录按ID排列，与哈希表的遍历顺序无关
+        assert_eq!(ids(db.query("users", &Query::new()).unwrap()), ids(all.clone()));
+        assert_eq!(ids(db.find_where("users", |_| true).unwrap()), ids(all.clone()));
+        assert_eq!(ids(db.read_transaction().find_all("users").unwrap()), ids(all));
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_table_info() {
+        let (_dir, db) = open("table-info");
+        let name = |n: &str| IndexMap::from([("name".to_string(), Value::String(n.to_string()))]);
+        db.create_table("users").unwrap();
+        let info = db.table_info("users").unwrap();
+        assert_eq!((info.modified_at, info.saved_at, info.dirty, info.pending_changes), (None, None, false, 0));
+
+        let id = db.insert("users", name("alice")).unwrap();
+        db.update("users", &id, name("alice2")).unwrap();
+        let info = db.table_info("users").unwrap();
+        assert!(info.dirty && info.modified_at.is_some());
+        assert_eq!((info.records, info.pending_changes, info.saved_at), (1, 2, None));
+
+        db.save_all().unwrap();
+        let saved = db.table_info("users").unwrap();
+        assert!(!saved.dirty && saved.saved_at >= info.modified_at);
+        assert_eq!(saved.pending_changes, 0);
+        assert!(db.table_info("missing").is_err());
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_atomic_save() {
+        let (dir, config) = temp_config("atomic", Config {
+            sync_dir: true,
+            ..Config::default()
+        });
+        let name = |n: &str| IndexMap::from([("name".to_string(), Value::String(n.to_string()))]);
+        let db = SimpleDB::new(config.clone()).unwrap();
+        db.insert("users", name("alice")).unwrap();
+        db.save_all().unwrap();
+        assert!(!dir.path().join("users.db.tmp").exists());
+        drop(db);
+
+        // 写临时文件时崩溃：原表文件完整，留下的临时文件不影响打开，由repair清理
+        std::fs::write(dir.path().join("users.db.tmp"), b"half a table").unwrap();
+        let db = SimpleDB::new(config.clone()).unwrap();
+        assert_eq!(db.count("users").unwrap(), 1);
+        assert_eq!(db.list_tables(), vec!["users".to_string()]);
+        drop(db);
+        assert_eq!(SimpleDB::repair(&config).unwrap().removed_temp, vec!["users.db.tmp"]);
+    }
+
+    #[test]
+    fn test_split_table_files() {
+        let (dir, config) = temp_config("split", Config {
+            max_file_size: 4096,
+            ..Config::default()
+        });
+        let line = |n: usize| IndexMap::from([("line".to_string(), Value::String(format!("{:0>100}", n)))]);
+        let db = SimpleDB::new(config.clone()).unwrap();
+        let ids: Vec<String> = (0..200).map(|n| db.insert("logs", line(n)).unwrap()).collect();
+        db.save_all().unwrap();
+        let parts = |table: &str| -> Vec<String> {
+            let mut names: Vec<String> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .filter(|name| storage::parse_part_file_name(name).is_some_and(|(t, ..)| t == table))
+                .collect();
+            names.sort();
+            names
+        };
+        let split = parts("logs");
+        assert!(split.len() >= 4, "{:?}", split);
+        for name in &split {
+            assert_eq!(storage::parse_part_file_name(name).unwrap().1, 1);
+            let size = std::fs::metadata(dir.path().join(name)).unwrap().len();
+            assert!(size <= 4096 + 256, "{}", size);
+        }
+        // 每次保存写出新的一代，旧一代在提交后删除
+        db.update("logs", &ids[0], line(1000)).unwrap();
+        db.save_all().unwrap();
+        assert!(parts("logs").iter().all(|name| storage::parse_part_file_name(name).unwrap().1 == 2));
+        db.clone_table("logs", "logs_copy").unwrap();
+        assert!(db.create_table("logs.1.2").is_err());
+        drop(db);
+
+        // 保存中断：新一代的文件写了一半、部分清单没有替换时仍读出上一代；其他表名中的数字不被误认为部分文件
+        std::fs::write(dir.path().join(storage::part_file_name("logs", 3, 0)), b"half a part").unwrap();
+        let db = SimpleDB::new(config.clone()).unwrap();
+        db.insert("data.2024", line(0)).unwrap();
+        drop(db);
+        let db = SimpleDB::new(config.clone()).unwrap();
+        let mut tables = db.list_tables();
+        tables.sort();
+        assert_eq!(tables, vec!["data.2024", "logs", "logs_copy"]);
+        assert_eq!((db.count("logs").unwrap(), db.count("logs_copy").unwrap()), (200, 200));
+        assert_eq!(db.count("data.2024").unwrap(), 1);
+        assert_eq!(db.find_by_id("logs", &ids[0]).unwrap().unwrap().data, line(1000));
+
+        // 备份中的部分文件和部分清单一致，恢复后读出同样的记录
+        let backups = temp_dir("backups");
+        let archive = backups.path().join("data.bak");
+        db.backup(&archive).unwrap();
+
+        // 记录变少后不再拆分，部分清单和所有部分文件被删除
+        for id in &ids[10..] {
+            db.delete("logs", id).unwrap();
+        }
+        db.save_all().unwrap();
+        assert!(parts("logs").is_empty());
+        assert!(!dir.path().join("logs.parts").exists());
+        assert!(db.verify(false).unwrap().is_ok());
+        db.drop_table("logs_copy").unwrap();
+        assert!(parts("logs_copy").is_empty());
+        assert!(!dir.path().join("logs_copy.parts").exists());
+        drop(db);
+        assert_eq!(SimpleDB::new(config.clone()).unwrap().count("logs").unwrap(), 10);
+
+        SimpleDB::restore(&Config { allow_unsigned_backup: true, ..config.clone() }, &archive).unwrap();
+        let db = SimpleDB::new(config.clone()).unwrap();
+        assert_eq!(db.count("logs").unwrap(), 200);
+        assert!(db.verify(false).unwrap().is_ok());
+        drop(db);
+    }
+
+    #[test]
+    fn test_conditional_writes() {
+        let (_dir, db) = open("conditional");
+        let status = |s: &str| IndexMap::from([("status".to_string(), Value::String(s.to_string()))]);
+        let pending = Value::String("pending".to_string());
+        let order = db.insert("orders", status("pending")).unwrap();
+
+        db.update_if("orders", &order, "status", &pending, status("cancelled")).unwrap();
+        // 第二次取消时状态已不是pending
+        let again = db.update_if("orders", &order, "status", &pending, status("cancelled"));
+        assert!(matches!(again, Err(DatabaseError::PreconditionFailed(_))));
+        assert!(matches!(
+            db.patch_if("orders", &order, "paid", &Value::Bool(true), &[UpdateOp::Unset("status".to_string())]),
+            Err(DatabaseError::PreconditionFailed(_))
+        ));
+        assert!(matches!(db.delete_if("orders", "missing", "status", &pending), Err(DatabaseError::RecordNotFound(_))));
+
+        db.delete_if("orders", &order, "id", &Value::String(order.clone())).unwrap();
+        assert_eq!(db.count("orders").unwrap(), 0);
+
+        // 事务中的字段校验失败时整个事务回滚，为插入创建的表也被移除
+        let order = db.insert("orders", status("shipped")).unwrap();
+        let mut tx = Transaction::new();
+        tx.insert("shipments", status("pending"));
+        tx.require_field("orders", &order, "status", pending);
+        tx.delete("orders", &order);
+        assert!(db.commit(tx).is_err());
+        assert_eq!(db.count("orders").unwrap(), 1);
+        assert!(!db.list_tables().contains(&"shipments".to_string()));
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_cascade_update_value() {
+        let (_dir, db) = open("cascade");
+        let string = |s: &str| Value::String(s.to_string());
+        let row = |field: &str, value: Value| IndexMap::from([(field.to_string(), value)]);
+        let (digital, electronics) = (string("数码"), string("电子产品"));
+        let category = db.insert("categories", row("name", digital.clone())).unwrap();
+        db.insert("categories", row("name", string("图书"))).unwrap();
+        for name in ["数码", "数码", "图书"] {
+            db.insert("products", row("category", string(name))).unwrap();
+        }
+        let banner = db.insert("banners", row("category", digital.clone())).unwrap();
+        // 按ID引用的记录不需要修改
+        let pinned = db.insert("pins", row("category", Value::reference("categories", &category))).unwrap();
+        let referencing = [("products", "category"), ("banners", "category")];
+
+        assert_eq!(db.cascade_update_value("categories", "name", &digital, &electronics, &referencing).unwrap(), 4);
+        assert_eq!(db.find_by_id("categories", &category).unwrap().unwrap().data["name"], electronics);
+        assert_eq!(db.query("products", &Query::eq("category", electronics.clone())).unwrap().len(), 2);
+        assert!(db.query("products", &Query::eq("category", digital.clone())).unwrap().is_empty());
+        assert_eq!(db.find_by_id("banners", &banner).unwrap().unwrap().data["category"], electronics);
+        let pin = db.find_by_id("pins", &pinned).unwrap().unwrap();
+        let Value::Object(resolved) = &db.resolve(&pin, 1).unwrap().data["category"] else { panic!("引用未解析") };
+        assert_eq!(resolved["name"], electronics);
+
+        // 任一张表中有一条记录写入失败时所有表都不生效
+        let camera = db.insert("products", row("category", digital.clone())).unwrap();
+        db.insert("banners", row("category", digital.clone())).unwrap();
+        db.update("categories", &category, row("name", digital.clone())).unwrap();
+        db.set_validator("banners", |data| match data.get("category") {
+            Some(Value::String(c)) if c != "数码" => Err(vec![FieldError::new("category", "已锁定")]),
+            _ => Ok(()),
+        })
+        .unwrap();
+        assert!(matches!(
+            db.cascade_update_value("categories", "name", &digital, &electronics, &referencing),
+            Err(DatabaseError::Validation(_))
+        ));
+        assert_eq!(db.find_by_id("categories", &category).unwrap().unwrap().data["name"], digital);
+        assert_eq!(db.find_by_id("products", &camera).unwrap().unwrap().data["category"], digital);
+        assert_eq!(db.query("banners", &Query::eq("category", digital)).unwrap().len(), 1);
+        drop(db);
+    }
+
+    #[test]
+    fn test_strict_table_mode() {
+        let (dir, db) = open("strict");
+        let user = |name: Value, age: Value| IndexMap::from([("name".to_string(), name), ("age".to_string(), age)]);
+        let alice = db.insert("users", user(Value::String("alice".to_string()), Value::Int(30))).unwrap();
+        let bob = db.insert("users", user(Value::String("bob".to_string()), Value::String("三十".to_string()))).unwrap();
+        db.set_field_type("users", "name", Some(FieldType::String)).unwrap();
+        db.set_field_type("users", "age", Some(FieldType::Int)).unwrap();
+
+        // 已有记录不符合声明时不能切换，错误列出不符合的记录
+        let Err(DatabaseError::Validation(errors)) = db.set_table_mode("users", TableMode::Strict) else {
+            panic!("切换应当失败");
+        };
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains(&bob), "{:?}", errors);
+        assert_eq!(db.table_mode("users").unwrap(), TableMode::Flexible);
+
+        db.update("users", &bob, user(Value::String("bob".to_string()), Value::Null)).unwrap();
+        db.set_table_mode("users", TableMode::Strict).unwrap();
+        let mut extra = user(Value::String("carol".to_string()), Value::Int(20));
+        extra.insert("nick".to_string(), Value::String("c".to_string()));
+        assert!(matches!(db.insert("users", extra), Err(DatabaseError::Validation(_))));
+        assert!(db.update("users", &alice, user(Value::Int(1), Value::Int(30))).is_err());
+        // 严格模式下修改声明同样检查已有的记录
+        assert!(db.set_field_type("users", "age", Some(FieldType::String)).is_err());
+        db.save_all().unwrap();
+        drop(db);
+
+        let db = reopen(&dir);
+        assert_eq!(db.table_mode("users").unwrap(), TableMode::Strict);
+        assert_eq!(db.field_types("users").unwrap()["age"], FieldType::Int);
+        db.set_table_mode("users", TableMode::Flexible).unwrap();
+        db.update("users", &alice, user(Value::Int(1), Value::Int(30))).unwrap();
+
+        // 声明和模式一起修改：记录不符合时两者都不修改
+        let nick = BTreeMap::from([("nick".to_string(), Some(FieldType::String))]);
+        assert!(db.set_table_schema("users", &nick, Some(TableMode::Strict)).is_err());
+        assert!(!db.field_types("users").unwrap().contains_key("nick"));
+        assert_eq!(db.table_mode("users").unwrap(), TableMode::Flexible);
+        db.update("users", &alice, user(Value::String("alice".to_string()), Value::Int(30))).unwrap();
+        db.set_table_schema("users", &nick, Some(TableMode::Strict)).unwrap();
+
+        // 合并进来的记录同样受严格模式约束，有不符合的记录时什么也不合并
+        let (_other_dir, other) = open("strict-other");
+        other.insert("users", user(Value::String("dave".to_string()), Value::Int(40))).unwrap();
+        other.insert("users", user(Value::String("erin".to_string()), Value::Float(1.5))).unwrap();
+        assert!(matches!(db.merge_from(&other, MergeStrategy::Skip), Err(DatabaseError::Validation(_))));
+        assert_eq!(db.count("users").unwrap(), 2);
+        drop(other);
+        drop(db);
+    }
+
+    #[test]
+    fn test_backup_restore() {
+        let seed = backup::generate_signing_key();
+        let (dir, config) = temp_config("backup", Config {
+            backup_signing_key: Some(seed.clone()),
+            ..Config::default()
+        });
+        let backups = temp_dir("backups");
+        let archive = backups.path().join("data.bak");
+        let name = |n: &str| IndexMap::from([("name".to_string(), Value::String(n.to_string()))]);
+
+        let db = SimpleDB::new(config.clone()).unwrap();
+        let id = db.insert("users", name("alice")).unwrap();
+        // 未保存的表文件和清单
+        assert_eq!(db.backup(&archive).unwrap(), 2);
+        db.insert("orders", name("later")).unwrap();
+        db.delete("users", &id).unwrap();
+        drop(db);
+
+        // 恢复只需要公钥；没有公钥或公钥不匹配时拒绝，数据目录保持不变
+        let restoring = |key: &[u8]| Config {
+            backup_signing_key: None,
+            backup_verifying_key: Some(backup::public_key(key).unwrap()),
+            ..config.clone()
+        };
+        assert!(SimpleDB::restore(&restoring(&backup::generate_signing_key()), &archive).is_err());
+        assert!(SimpleDB::restore(&Config { allow_unsigned_backup: true, ..config.clone() }, &archive).is_err());
+        assert!(dir.path().join("orders.db").exists());
+
+        // 数据库打开时不能恢复
+        let db = SimpleDB::new(config.clone()).unwrap();
+        assert!(matches!(SimpleDB::restore(&restoring(&seed), &archive), Err(DatabaseError::LockTimeout(_))));
+        drop(db);
+
+        SimpleDB::restore(&restoring(&seed), &archive).unwrap();
+        let db = SimpleDB::new(config.clone()).unwrap();
+        assert_eq!(db.list_tables(), vec!["users".to_string()]);
+        assert!(db.find_by_id("users", &id).unwrap().is_some());
+        assert!(db.verify(true).unwrap().is_ok());
+        db.insert("orders", name("later")).unwrap();
+        drop(db);
+
+        // 恢复提交后、替换完之前中断：下次打开数据库时完成替换
+        let files = backup::read_archive(&archive, Some(&backup::verifying_key(&backup::public_key(&seed).unwrap()).unwrap()), false)
+            .unwrap();
+        stage_restore(dir.path(), &files).unwrap();
+        std::fs::rename(dir.path().join(RESTORE_DIR).join("files").join("users.db"), dir.path().join("users.db")).unwrap();
+        let db = SimpleDB::new(config).unwrap();
+        assert!(!dir.path().join(RESTORE_DIR).exists());
+        assert_eq!(db.list_tables(), vec!["users".to_string()]);
+        assert!(db.verify(true).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_table_config_overrides() {
+        let secret_key = Crypto::generate_key();
+        let (dir, config) = temp_config("table-config", Config {
+            tables: HashMap::from([
+                (
+                    "logs".to_string(),
+                    TableConfig {
+                        compress: Some(true),
+                        autosave: Some(Autosave::EveryChanges(2)),
+                        ..TableConfig::default()
+                    },
+                ),
+                (
+                    "secrets".to_string(),
+                    TableConfig {
+                        encryption_key_id: Some("vault".to_string()),
+                        autosave: Some(Autosave::EveryWrite),
+                        ..TableConfig::default()
+                    },
+                ),
+            ]),
+            table_keys: HashMap::from([("vault".to_string(), secret_key.clone())]),
+            ..Config::default()
+        });
+        let line = |n: i64| IndexMap::from([("line".to_string(), Value::Int(n))]);
+
+        let db = SimpleDB::new(config.clone()).unwrap();
+        db.insert("secrets", line(1)).unwrap();
+        // 每次写入后立即保存，且只能用表指定的密钥解密
+        let secrets = dir.path().join("secrets.db");
+        assert_eq!(storage::read_table_file(&secrets, Some(&Crypto::new(&secret_key).unwrap())).unwrap().len(), 1);
+        assert!(storage::read_table_file(&secrets, None).is_err());
+
+        db.insert("logs", line(1)).unwrap();
+        assert!(!dir.path().join("logs.db").exists());
+        db.insert("logs", line(2)).unwrap();
+        let header = format::Header::parse(&std::fs::read(dir.path().join("logs.db")).unwrap()).unwrap().unwrap();
+        assert!(header.is_compressed());
+        // 没有覆盖设置的表沿用全局配置
+        db.insert("users", line(1)).unwrap();
+        assert!(!dir.path().join("users.db").exists());
+        assert_eq!(db.table_config("users"), TableConfig::default());
+        assert!(db.verify(true).unwrap().is_ok());
+        drop(db);
+
+        let db = SimpleDB::new(config.clone()).unwrap();
+        assert_eq!(db.find_all("secrets").unwrap().len(), 1);
+        drop(db);
+
+        // 引用不存在的密钥
+        let missing = Config {
+            table_keys: HashMap::new(),
+            ..config
+        };
+        assert!(matches!(SimpleDB::new(missing), Err(DatabaseError::Config(_))));
+    }
+
+    #[test]
+    fn test_table_quota() {
+        let quota = |policy| TableConfig {
+            quota: Some(Quota {
+                max_records: Some(3),
+                max_bytes: None,
+                policy,
+            }),
+            ..TableConfig::default()
+        };
+        let (dir, db) = open_with("quota", Config {
+            tables: HashMap::from([
+                ("audit".to_string(), quota(QuotaPolicy::EvictOldest)),
+                ("users".to_string(), quota(QuotaPolicy::Reject)),
+            ]),
+            ..Config::default()
+        });
+        let line = |n: i64| IndexMap::from([("line".to_string(), Value::Int(n))]);
+
+        let ids: Vec<String> = (0..5).map(|n| db.insert("audit", line(n)).unwrap()).collect();
+        // 最早插入的两条被淘汰
+        assert_eq!(db.count("audit").unwrap(), 3);
+        assert!(db.find_by_id("audit", &ids[1]).unwrap().is_none());
+        assert!(db.find_by_id("audit", &ids[2]).unwrap().is_some());
+
+        // 事务失败时淘汰不撤销，仍然发布淘汰记录的删除事件
+        let mut changes = db.change_feed().subscribe(None).receiver;
+        let mut tx = Transaction::new();
+        tx.insert("audit", line(5));
+        tx.delete("audit", "missing");
+        assert!(db.commit(tx).is_err());
+        assert!(db.find_by_id("audit", &ids[2]).unwrap().is_none());
+        let evicted = changes.try_recv().unwrap();
+        assert_eq!((evicted.kind, evicted.id.as_str()), (ChangeKind::Delete, ids[2].as_str()));
+        assert!(changes.try_recv().is_err());
+
+        for n in 0..3 {
+            db.insert("users", line(n)).unwrap();
+        }
+        assert!(matches!(db.insert("users", line(3)), Err(DatabaseError::QuotaExceeded(_))));
+        assert_eq!(db.count("users").unwrap(), 3);
+
+        // 字节配额：更新使记录变大时同样受限
+        let mut table = Table::new("bytes".to_string(), dir.path(), None).unwrap();
+        let id = table.insert(Record::new(line(0))).unwrap();
+        table.set_quota(Some(Quota {
+            max_bytes: Some(table.data_size() + 8),
+            ..Quota::default()
+        }));
+        let big = IndexMap::from([("line".to_string(), Value::String("x".repeat(64)))]);
+        assert!(matches!(table.update(&id, big), Err(DatabaseError::QuotaExceeded(_))));
+        table.update(&id, line(1)).unwrap();
+        table.discard_changes();
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_capped_table() {
+        let (dir, db) = open("capped");
+        let line = |n: i64| IndexMap::from([("line".to_string(), Value::Int(n))]);
+        db.create_capped_table("metrics", Cap::records(3)).unwrap();
+        let ids: Vec<String> = (0..5).map(|n| db.insert("metrics", line(n)).unwrap()).collect();
+        assert_eq!(db.count("metrics").unwrap(), 3);
+        let latest: Vec<String> = db.find_latest("metrics", 2).unwrap().iter().map(|r| r.id.clone()).collect();
+        assert_eq!(latest, vec![ids[4].clone(), ids[3].clone()]);
+
+        db.insert("users", line(0)).unwrap();
+        assert!(db.create_capped_table("users", Cap::records(1)).is_err());
+        drop(db);
+
+        // 上限随元数据文件保存，重新打开后仍然生效
+        let db = reopen(&dir);
+        db.insert("metrics", line(5)).unwrap();
+        assert_eq!(db.count("metrics").unwrap(), 3);
+        db.create_capped_table("metrics", Cap::records(1)).unwrap();
+        assert_eq!(db.find_all("metrics").unwrap()[0].data["line"], Value::Int(5));
+
+        db.drop_table("metrics").unwrap();
+        assert!(!dir.path().join("metrics.meta").exists());
+        drop(db);
+    }
+
+    #[test]
+    fn test_time_series_table() {
+        let (dir, db) = open("timeseries");
+        let point = |ts: i64, value: i64| {
+            IndexMap::from([("ts".to_string(), Value::Int(ts)), ("cpu".to_string(), Value::Int(value))])
+        };
+        db.create_time_series_table("cpu", TimeSeries::new("ts", 100)).unwrap();
+        for (ts, value) in [(10, 1), (50, 3), (120, 5), (250, 7)] {
+            db.insert("cpu", point(ts, value)).unwrap();
+        }
+        assert!(db.insert("cpu", IndexMap::from([("cpu".to_string(), Value::Int(0))])).is_err());
+        db.save_all().unwrap();
+        for name in ["cpu@0.db", "cpu@100.db", "cpu@200.db"] {
+            assert!(dir.path().join(name).exists(), "{}", name);
+        }
+
+        let between: Vec<Value> = db.find_between("cpu", 50, 250).unwrap().iter().map(|r| r.data["ts"].clone()).collect();
+        assert_eq!(between, vec![Value::Int(50), Value::Int(120)]);
+        let averages = db.downsample("cpu", 0, 300, 100, "cpu", Aggregate::Avg).unwrap();
+        assert_eq!(averages, vec![(0, Value::Float(2.0)), (100, Value::Float(5.0)), (200, Value::Float(7.0))]);
+
+        // 过期的整段直接删除段文件
+        assert_eq!(db.expire_before("cpu", 100).unwrap(), 2);
+        db.save_all().unwrap();
+        assert!(!dir.path().join("cpu@0.db").exists());
+        assert!(db.verify(true).unwrap().is_ok());
+        drop(db);
+
+        let db = reopen(&dir);
+        assert_eq!(db.list_tables(), vec!["cpu".to_string()]);
+        assert_eq!(db.find_between("cpu", i64::MIN, i64::MAX).unwrap().len(), 2);
+        db.drop_table("cpu").unwrap();
+        assert!(!dir.path().join("cpu@100.db").exists());
+        drop(db);
+    }
+
+    #[test]
+    fn test_record_ttl() {
+        let (dir, db) = open("ttl");
+        let session = |user: &str, expires_at: i64| {
+            IndexMap::from([("user".to_string(), Value::String(user.to_string())), ("expires_at".to_string(), Value::Int(expires_at))])
+        };
+        db.insert("sessions", session("alice", unix_now() - 10)).unwrap();
+        db.insert("sessions", session("bob", unix_now() + 3600)).unwrap();
+        db.insert("sessions", IndexMap::from([("user".to_string(), Value::String("carol".to_string()))])).unwrap();
+        assert!(db.insert_with_ttl("sessions", IndexMap::new(), Duration::from_secs(60)).is_err());
+
+        db.set_ttl_field("sessions", Some("expires_at")).unwrap();
+        let dave = db.insert_with_ttl("sessions", IndexMap::new(), Duration::ZERO).unwrap();
+        assert_eq!(db.sweep_expired().unwrap(), 2);
+        assert_eq!(db.count("sessions").unwrap(), 2);
+        assert!(db.find_by_id("sessions", &dave).unwrap().is_none());
+
+        // 改为已过期的时间后在下次清理时删除
+        let bob = db.find_by_field("sessions", "user", &Value::String("bob".to_string())).unwrap()[0].id.clone();
+        db.update("sessions", &bob, session("bob", 0)).unwrap();
+        drop(db);
+
+        let db = reopen(&dir);
+        assert_eq!(db.sweep_expired().unwrap(), 1);
+        assert_eq!(db.count("sessions").unwrap(), 1);
+        drop(db);
+    }
+
+    #[test]
+    fn test_collation() {
+        let (dir, db) = open("collation");
+        for name in ["file10", "file2", "File1"] {
+            db.insert("files", IndexMap::from([("name".to_string(), Value::String(name.to_string()))])).unwrap();
+        }
+        let names = |db: &SimpleDB, query: &Query| -> Vec<String> {
+            db.query("files", query)
+                .unwrap()
+                .iter()
+                .map(|r| r.data["name"].as_string().unwrap().to_string())
+                .collect()
+        };
+        let sorted = Query::new().order_by(vec![("name".to_string(), SortOrder::Asc)]);
+        assert_eq!(names(&db, &sorted), ["File1", "file10", "file2"]);
+
+        assert!(db.set_collation("files", Some("icu")).is_err());
+        db.set_collation("files", Some("natural")).unwrap();
+        assert_eq!(names(&db, &sorted), ["File1", "file2", "file10"]);
+        let after_file2 = sorted.clone().filter(Condition::gt("name", Value::String("file2".to_string())));
+        assert_eq!(names(&db, &after_file2), ["file10"]);
+        drop(db);
+
+        // 排序规则随元数据保存；自定义的规则没有注册时无法打开
+        let config = config_in(&dir);
+        let db = SimpleDB::new(config.clone()).unwrap();
+        assert_eq!(names(&db, &sorted), ["File1", "file2", "file10"]);
+        drop(db);
+        fn by_length(a: &str, b: &str) -> std::cmp::Ordering {
+            a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+        }
+        let db = SimpleDB::new(Config {
+            collations: HashMap::from([("length".to_string(), by_length as Comparator)]),
+            ..config.clone()
+        })
+        .unwrap();
+        db.set_collation("files", Some("length")).unwrap();
+        drop(db);
+        assert!(SimpleDB::new(config).is_err());
+    }
+
+    #[test]
+    fn test_read_transaction() {
+        let (_dir, db) = open("readtx");
+        let amount = |n: i64| IndexMap::from([("amount".to_string(), Value::Int(n))]);
+        let first = db.insert("orders", amount(10)).unwrap();
+        db.insert("orders", amount(20)).unwrap();
+
+        let tx = db.read_transaction();
+        db.insert("orders", amount(30)).unwrap();
+        db.delete("orders", &first).unwrap();
+        db.insert("refunds", amount(5)).unwrap();
+
+        // 快照之后的写入对只读事务不可见
+        let sorted = Query::new().order_by(vec![("amount".to_string(), SortOrder::Desc)]);
+        let amounts: Vec<Value> = tx.query("orders", &sorted).unwrap().iter().map(|r| r.data["amount"].clone()).collect();
+        assert_eq!(amounts, vec![Value::Int(20), Value::Int(10)]);
+        assert!(tx.find_by_id("orders", &first).unwrap().is_some());
+        assert_eq!(tx.count("orders").unwrap(), 2);
+        assert!(matches!(tx.count("refunds"), Err(DatabaseError::TableNotFound(_))));
+        assert_eq!(db.count("orders").unwrap(), 2);
+        drop(db);
+    }
+
+    #[test]
+    fn test_parallel_scan_matches_sequential() {
+        let dir = temp_dir("parallel_scan");
+        let mut table = Table::new("events".to_string(), dir.path(), None).unwrap();
+        // 超过并行扫描的阈值
+        for n in 0..12_000i64 {
+            let mut data = IndexMap::from([("n".to_string(), Value::Int(n)), ("group".to_string(), Value::Int(n % 7))]);
+            if n % 3 == 0 {
+                data.insert("tag".to_string(), Value::String(format!("t{}", n % 5)));
+            }
+            table.insert(Record::new(data)).unwrap();
+        }
+        let queries = [
+            Query::new(),
+            Query::eq("group", Value::Int(3)),
+            Query::new().filter(Condition::gte("n", Value::Int(9_000))).filter(Condition::ne("group", Value::Int(0))),
+            Query::eq("tag", Value::String("t2".to_string())).order_by([("n", SortOrder::Desc)]).offset(10).limit(50),
+            Query::gt("n", Value::Int(100)).order_by([("group", SortOrder::Asc), ("n", SortOrder::Desc)]).limit(500),
+        ];
+        let run = |table: &Table| -> Vec<Vec<String>> {
+            queries.iter().map(|query| table.query(query).iter().map(|r| r.id.clone()).collect()).collect()
+        };
+
+        let sequential = run(&table);
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        table.set_scan_pool(Some(Arc::new(pool)));
+        assert_eq!(run(&table), sequential);
+        assert_eq!(sequential[0].len(), 12_000);
+        assert_eq!(sequential[3].len(), 50);
+        table.discard_changes();
+    }
+
+    #[test]
+    fn test_writers_on_different_tables() {
+        let (_dir, db) = open("per_table_lock");
+        let row = |n: i64| IndexMap::from([("n".to_string(), Value::Int(n))]);
+        db.insert("a", row(0)).unwrap();
+        let db = &db;
+        let tables = ["b", "c", "d", "e"];
+        let (entered_tx, entered) = std::sync::mpsc::channel();
+        let (done_tx, done) = std::sync::mpsc::channel::<()>();
+        std::thread::scope(|scope| {
+            // 在a表的写锁中等待其他表的写入完成；全局锁会让它们互相等待
+            let holder = scope.spawn(move || {
+                db.transform("a", |_| {
+                    entered_tx.send(()).unwrap();
+                    done.recv_timeout(Duration::from_secs(10)).expect("其他表的写入被a表的锁阻塞");
+                    None
+                })
+            });
+            entered.recv().unwrap();
+            let writers: Vec<_> = tables
+                .iter()
+                .map(|table| {
+                    scope.spawn(move || {
+                        for n in 0..25 {
+                            db.insert(table, row(n)).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done_tx.send(()).unwrap();
+            assert_eq!(holder.join().unwrap().unwrap(), 0);
+        });
+        for table in tables {
+            assert_eq!(db.count(table).unwrap(), 25);
+        }
+    }
+
+    #[test]
+    fn test_query_cache_invalidation() {
+        let (_dir, db) = open_with("query_cache", Config {
+            query_cache_size: 8,
+            ..Config::default()
+        });
+        let status = |s: &str| IndexMap::from([("status".to_string(), Value::String(s.to_string()))]);
+        let active = Query::eq("status", Value::String("active".to_string()));
+        let first = db.insert("users", status("active")).unwrap();
+        let scans = || db.table_counters("users").unwrap().scans;
+        // 创建时间相同的记录按ID排列，按集合比较
+        let ids = || -> BTreeSet<String> { db.query("users", &active).unwrap().iter().map(|r| r.id.clone()).collect() };
+
+        assert_eq!(ids(), BTreeSet::from([first.clone()]));
+        // 表没有写入时直接返回缓存的结果，不再扫描
+        let cached = scans();
+        assert_eq!(ids(), BTreeSet::from([first.clone()]));
+        assert_eq!(scans(), cached);
+
+        // 插入、更新、删除和事务都使缓存失效
+        let second = db.insert("users", status("active")).unwrap();
+        assert_eq!(ids(), BTreeSet::from([first.clone(), second.clone()]));
+        assert_eq!(scans(), cached + 1);
+        db.update("users", &first, status("banned")).unwrap();
+        assert_eq!(ids(), BTreeSet::from([second.clone()]));
+        db.delete("users", &second).unwrap();
+        assert!(ids().is_empty());
+        let mut tx = Transaction::new();
+        let third = tx.insert("users", status("active"));
+        db.commit(tx).unwrap();
+        assert_eq!(ids(), BTreeSet::from([third]));
+        // 写入其他表不影响本表的缓存
+        let cached = scans();
+        db.insert("orders", status("active")).unwrap();
+        ids();
+        assert_eq!(scans(), cached);
+        drop(db);
+    }
+
+    #[test]
+    fn test_rename_and_remove_field() {
+        let (dir, db) = open("rename_field");
+        let string = |s: &str| Value::String(s.to_string());
+        let alice = db
+            .insert("users", IndexMap::from([("e_mail".to_string(), string("a@x.cn")), ("legacy_flag".to_string(), Value::Bool(true))]))
+            .unwrap();
+        db.insert("users", IndexMap::from([("e_mail".to_string(), string("b@x.cn"))])).unwrap();
+        db.insert("users", IndexMap::from([("name".to_string(), string("carol"))])).unwrap();
+        db.create_index("users", "email").unwrap();
+
+        // 没有该字段的记录不算变化
+        assert_eq!(db.rename_field("users", "e_mail", "email").unwrap(), 2);
+        assert_eq!(db.rename_field("users", "e_mail", "email").unwrap(), 0);
+        assert_eq!(db.remove_field("users", "legacy_flag").unwrap(), 1);
+        // 索引随改名后的字段更新
+        let found = db.query("users", &Query::eq("email", string("a@x.cn")).use_index("email")).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, alice);
+        db.save_all().unwrap();
+        drop(db);
+
+        let db = reopen(&dir);
+        let records = db.find_all("users").unwrap();
+        assert!(records.iter().all(|r| !r.data.contains_key("e_mail") && !r.data.contains_key("legacy_flag")));
+        assert_eq!(records.iter().filter(|r| r.data.contains_key("email")).count(), 2);
+        drop(db);
+    }
+
+    #[test]
+    fn test_commit_publishes_after_success() {
+        let (_dir, db) = open("commit_changes");
+        let balance = |n: i64| IndexMap::from([("balance".to_string(), Value::Int(n))]);
+        let account = db.insert("accounts", balance(100)).unwrap();
+        db.insert("audit", IndexMap::new()).unwrap();
+        let mut changes = db.change_feed().subscribe(None).receiver;
+
+        // 最后一个操作失败，已应用的插入和更新被撤销，订阅者收不到任何事件
+        let mut tx = Transaction::new();
+        tx.insert("audit", IndexMap::new());
+        tx.update("accounts", &account, balance(0));
+        tx.delete("accounts", "missing");
+        assert!(db.commit(tx).is_err());
+        assert_eq!(db.find_by_id("accounts", &account).unwrap().unwrap().data, balance(100));
+        assert!(changes.try_recv().is_err());
+
+        // 成功的事务按操作顺序发布事件
+        let mut tx = Transaction::new();
+        let audit = tx.insert("audit", IndexMap::new());
+        tx.update("accounts", &account, balance(0));
+        db.commit(tx).unwrap();
+        let insert = changes.try_recv().unwrap();
+        assert_eq!((insert.table.as_str(), insert.kind, insert.id.as_str()), ("audit", ChangeKind::Insert, audit.as_str()));
+        let update = changes.try_recv().unwrap();
+        assert_eq!(update.kind, ChangeKind::Update);
+        assert_eq!(update.record.as_ref().unwrap().data, balance(0));
+        assert!(changes.try_recv().is_err());
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_commit_reports_deadlock() {
+        let (_dir, db) = open_with("deadlock", Config {
+            lock_timeout: Duration::from_millis(50),
+            ..Config::default()
+        });
+        let account = db.insert("accounts", IndexMap::from([("balance".to_string(), Value::Int(100))])).unwrap();
+        db.insert("audit", IndexMap::new()).unwrap();
+
+        // 在持有accounts读锁的回调中提交涉及accounts的事务，以前会永远等待
+        let result = db.find_where("accounts", |_| {
+            let mut tx = Transaction::new();
+            tx.insert("audit", IndexMap::new());
+            tx.update("accounts", &account, IndexMap::from([("balance".to_string(), Value::Int(0))]));
+            matches!(db.commit(tx), Err(DatabaseError::Deadlock(_)))
+        });
+        assert_eq!(result.unwrap().len(), 1);
+        assert_eq!(db.count("audit").unwrap(), 1);
+        assert_eq!(db.find_by_id("accounts", &account).unwrap().unwrap().data["balance"], Value::Int(100));
+
+        // 锁只是被其他线程占用时不是死锁：超时返回LockTimeout，释放得早则等到后提交
+        let zero = IndexMap::from([("balance".to_string(), Value::Int(0))]);
+        for (millis, times_out) in [(200, true), (10, false)] {
+            let (held, release) = std::sync::mpsc::channel();
+            std::thread::scope(|scope| {
+                scope.spawn(|| {
+                    db.write_table("accounts", |_| {
+                        held.send(()).unwrap();
+                        std::thread::sleep(Duration::from_millis(millis));
+                        Ok(())
+                    })
+                    .unwrap();
+                });
+                release.recv().unwrap();
+                let mut tx = Transaction::new();
+                tx.update("accounts", &account, zero.clone());
+                match db.commit(tx) {
+                    Err(DatabaseError::LockTimeout(_)) => assert!(times_out),
+                    result => assert!(result.is_ok() && !times_out, "{:?}", result),
+                }
+            });
+        }
+        assert_eq!(db.find_by_id("accounts", &account).unwrap().unwrap().data["balance"], Value::Int(0));
+        drop(db);
+    }
+
+    #[test]
+    fn test_with_retry() {
+        let (_dir, db) = open("retry");
+        let counter = db.insert("counters", IndexMap::from([("n".to_string(), Value::Int(0))])).unwrap();
+
+        // 读取之后、提交之前被其他写入修改，第一次提交因ETag不一致失败，重试后成功
+        let mut attempts = 0;
+        let n = db
+            .with_retry(|tx| {
+                attempts += 1;
+                let current = db.find_by_id("counters", &counter)?.unwrap();
+                if attempts == 1 {
+                    db.patch("counters", &counter, &[UpdateOp::Set("n".to_string(), Value::Int(10))])?;
+                }
+                let n = current.data["n"].as_int().unwrap() + 1;
+                tx.require_etag("counters", &counter, &current.etag());
+                tx.update("counters", &counter, IndexMap::from([("n".to_string(), Value::Int(n))]));
+                Ok(n)
+            })
+            .unwrap();
+        assert_eq!((attempts, n), (2, 11));
+        assert_eq!(db.find_by_id("counters", &counter).unwrap().unwrap().data["n"], Value::Int(11));
+
+        // 其他错误不重试
+        let mut attempts = 0;
+        let result = db.with_retry(|tx| {
+            attempts += 1;
+            tx.delete("counters", "missing");
+            Ok(())
+        });
+        assert!(matches!(result, Err(DatabaseError::RecordNotFound(_))));
+        assert_eq!(attempts, 1);
+        drop(db);
+    }
+
+    #[test]
+    fn test_prepared_query() {
+        let (dir, db) = open("prepared");
+        for (name, age) in [("张三", 17), ("李四", 30), ("王五", 45)] {
+            let data = IndexMap::from([
+                ("name".to_string(), Value::String(name.to_string())),
+                ("age".to_string(), Value::Int(age)),
+            ]);
+            db.insert("users", data).unwrap();
+        }
+        db.create_index("users", "name").unwrap();
+
+        let adults = db.prepare("adults", "users", Query::gt("age", crate::param("min"))).unwrap();
+        assert_eq!(adults.params.iter().collect::<Vec<_>>(), ["min"]);
+        let by_name = db.prepare("by_name", "users", Query::eq("name", crate::param("name"))).unwrap();
+        assert_eq!(by_name.query.hint, IndexHint::Use("name".to_string()));
+
+        let bindings = IndexMap::from([("min".to_string(), Value::Int(18))]);
+        assert_eq!(db.execute_prepared("adults", &bindings).unwrap().len(), 2);
+        assert!(matches!(db.execute_prepared("adults", &IndexMap::new()), Err(DatabaseError::InvalidQuery(_))));
+        assert!(matches!(db.execute_prepared("missing", &bindings), Err(DatabaseError::InvalidQuery(_))));
+        // 未绑定的查询不能直接执行
+        assert!(db.query("users", &adults.query).is_err());
+
+        // 重新打开后预备查询仍然可用
+        drop(db);
+        let db = reopen(&dir);
+        assert_eq!(db.prepared_queries(), ["adults", "by_name"]);
+        let bindings = IndexMap::from([("name".to_string(), Value::String("李四".to_string()))]);
+        assert_eq!(db.execute_prepared("by_name", &bindings).unwrap()[0].data["age"], Value::Int(30));
+        assert!(db.unprepare("adults").unwrap());
+        assert!(db.unprepare("by_name").unwrap());
+        assert!(!dir.path().join(PREPARED_FILE).exists());
+        drop(db);
+    }
+
+    #[test]
+    fn test_row_level_security() {
+        let (dir, db) = open("policy");
+        let note = |owner: &str| {
+            IndexMap::from([
+                ("owner_id".to_string(), Value::String(owner.to_string())),
+                ("text".to_string(), Value::String(format!("{}的笔记", owner))),
+            ])
+        };
+        let user = |sub: &str| Claims::from([("sub".to_string(), Value::String(sub.to_string()))]);
+        db.create_table("notes").unwrap();
+        db.set_policy("notes", Some(Policy::new(vec![Condition::eq("owner_id", crate::claim("sub"))])))
+            .unwrap();
+
+        let alice = db.as_caller(user("alice"));
+        let bob = db.as_caller(user("bob"));
+        let mine = alice.insert("notes", note("alice")).unwrap();
+        let theirs = bob.insert("notes", note("bob")).unwrap();
+        assert!(matches!(alice.insert("notes", note("bob")), Err(DatabaseError::NotPermitted(_))));
+
+        assert_eq!(alice.find_all("notes").unwrap().len(), 1);
+        assert!(alice.find_by_id("notes", &theirs).unwrap().is_none());
+        assert!(matches!(alice.delete("notes", &theirs, None), Err(DatabaseError::RecordNotFound(_))));
+        // 不能把自己的记录改成别人的
+        let ops = [UpdateOp::Set("owner_id".to_string(), Value::String("bob".to_string()))];
+        assert!(matches!(alice.patch("notes", &mine, &ops, None), Err(DatabaseError::NotPermitted(_))));
+        alice.update("notes", &mine, note("alice"), None).unwrap();
+
+        // 缺少策略引用的声明时什么也看不到，管理员不受限制
+        assert!(db.as_caller(Claims::new()).find_all("notes").unwrap().is_empty());
+        let admin = db.as_caller(Claims::from([("admin".to_string(), Value::Bool(true))]));
+        assert_eq!(admin.find_all("notes").unwrap().len(), 2);
+
+        // 策略随表的元数据保存
+        drop((alice, bob, admin));
+        drop(db);
+        let db = reopen(&dir);
+        let results = db.as_caller(user("bob")).query("notes", &Query::new()).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, theirs);
+        drop(db);
+    }
+
+    #[test]
+    fn test_tenants() {
+        let (_dir, db) = open_with("tenants", Config {
+            tenant_quotas: HashMap::from([(
+                "acme".to_string(),
+                Quota {
+                    max_records: Some(2),
+                    max_bytes: None,
+                    policy: QuotaPolicy::Reject,
+                },
+            )]),
+            max_open_tenants: 1,
+            ..Config::default()
+        });
+        let user = |name: &str| IndexMap::from([("name".to_string(), Value::String(name.to_string()))]);
+
+        let acme = db.with_tenant("acme").unwrap();
+        let globex = db.with_tenant("globex").unwrap();
+        acme.insert("users", user("张三")).unwrap();
+        acme.insert("orders", user("李四")).unwrap();
+        globex.insert("users", user("王五")).unwrap();
+        assert!(db.list_tables().is_empty());
+        assert_eq!(globex.find_all("users").unwrap()[0].data["name"], Value::String("王五".to_string()));
+
+        // 配额按租户所有表合计
+        assert!(matches!(acme.insert("users", user("赵六")), Err(DatabaseError::QuotaExceeded(_))));
+        assert_eq!(acme.usage().map(|(records, _)| records), Some(2));
+        globex.insert("users", user("赵六")).unwrap();
+        assert!(db.with_tenant("../acme").is_err());
+        assert_eq!(db.tenants().unwrap(), ["acme", "globex"]);
+
+        let backups = temp_dir("backups");
+        let archive = backups.path().join("acme.bak");
+        db.export_tenant("acme", &archive).unwrap();
+        let (_restored, config) = temp_config("restored", Config {
+            allow_unsigned_backup: true,
+            ..Config::default()
+        });
+        SimpleDB::restore(&config, &archive).unwrap();
+        assert_eq!(SimpleDB::new(config).unwrap().count("orders").unwrap(), 1);
+
+        assert!(matches!(db.drop_tenant("acme"), Err(DatabaseError::NotPermitted(_))));
+        drop(acme);
+        db.drop_tenant("acme").unwrap();
+        assert_eq!(db.tenants().unwrap(), ["globex"]);
+
+        // 超出缓存上限时关闭最久未用的空闲分区，仍在使用的分区保留
+        let initech = db.with_tenant("initech").unwrap();
+        assert_eq!(db.open_tenants(), ["globex", "initech"]);
+        drop(globex);
+        db.with_tenant("umbrella").unwrap();
+        assert_eq!(db.open_tenants(), ["initech", "umbrella"]);
+        drop(initech);
+        assert_eq!(db.with_tenant("globex").unwrap().count("users").unwrap(), 2);
+        assert_eq!(db.open_tenants(), ["globex"]);
+    }
+
+    #[test]
+    fn test_security_report() {
+        let (dir, db) = open("security");
+        db.insert("users", IndexMap::from([("name".to_string(), Value::String("张三".to_string()))])).unwrap();
+        db.save_all().unwrap();
+        drop(db);
+
+        // 启用加密之前写出的表文件仍是明文，直到下次保存
+        let key = Crypto::generate_key();
+        let db = SimpleDB::new(Config {
+            encryption_key: Some(key.clone()),
+            table_keys: HashMap::from([("vault".to_string(), Crypto::generate_key())]),
+            tables: HashMap::from([(
+                "secrets".to_string(),
+                TableConfig {
+                    encryption_key_id: Some("vault".to_string()),
+                    ..TableConfig::default()
+                },
+            )]),
+            ..config_in(&dir)
+        })
+        .unwrap();
+        let email = db.seal("user-1", &Value::String("a@example.com".to_string())).unwrap();
+        let ssn = db.seal_deterministic("ssn", &Value::String("123".to_string())).unwrap();
+        db.insert("secrets", IndexMap::from([("email".to_string(), email), ("ssn".to_string(), ssn)])).unwrap();
+        db.save_all().unwrap();
+
+        let report = db.security_report().unwrap();
+        let users = &report.tables[1];
+        assert_eq!((users.table.as_str(), users.encrypted, users.key_id.as_deref()), ("users", true, None));
+        assert_eq!(report.unmigrated_files().collect::<Vec<_>>(), ["users.db", "users.stats"]);
+        let secrets = &report.tables[0];
+        assert_eq!(secrets.key_id.as_deref(), Some("vault"));
+        assert_eq!(secrets.sealed_fields.iter().collect::<Vec<_>>(), ["email"]);
+        assert_eq!(secrets.deterministic_fields.iter().collect::<Vec<_>>(), ["ssn"]);
+        let plaintext: Vec<&str> = report.plaintext_files().collect();
+        assert!(plaintext.contains(&"users.db") && plaintext.contains(&MANIFEST_FILE));
+        assert!(!plaintext.contains(&"secrets.db") && !plaintext.contains(&KEYRING_FILE));
+
+        // 修改后重新保存即按配置加密
+        db.update("users", &db.find_all("users").unwrap()[0].id, IndexMap::new()).unwrap();
+        db.save_all().unwrap();
+        assert_eq!(db.security_report().unwrap().unmigrated_files().count(), 0);
+        drop(db);
+    }
+
+    #[test]
+    fn test_plaintext_sidecar() {
+        let (dir, db) = open_with("plaintext", Config {
+            encryption_key: Some(Crypto::generate_key()),
+            tables: HashMap::from([(
+                "orders".to_string(),
+                TableConfig {
+                    plaintext_fields: vec!["created_at".to_string(), "status".to_string()],
+                    ..TableConfig::default()
+                },
+            )]),
+            ..Config::default()
+        });
+        for (status, card) in [("paid", "4111"), ("open", "5500")] {
+            db.insert(
+                "orders",
+                IndexMap::from([
+                    ("status".to_string(), Value::String(status.to_string())),
+                    ("card".to_string(), Value::String(card.to_string())),
+                ]),
+            )
+            .unwrap();
+        }
+        db.insert("users", IndexMap::from([("name".to_string(), Value::String("张三".to_string()))])).unwrap();
+        db.save_all().unwrap();
+
+        // 不需要密钥就能列出记录、按明文字段和创建时间过滤，加密的字段不在其中
+        let paid = plaintext::read(dir.path(), "orders", &Query::eq("status", Value::String("paid".to_string()))).unwrap();
+        assert_eq!(paid.len(), 1);
+        assert!(!paid[0].data.contains_key("card"));
+        let recent = plaintext::read(dir.path(), "orders", &Query::gt("created_at", Value::Int(0))).unwrap();
+        assert_eq!(recent.len(), 2);
+        // 没有选择明文字段的表不写旁路文件
+        assert!(plaintext::read(dir.path(), "users", &Query::new()).is_err());
+
+        let report = db.security_report().unwrap();
+        assert_eq!(report.tables[0].plaintext_fields, ["created_at", "status"]);
+        assert!(report.plaintext_files().any(|name| name == "orders.plain"));
+        assert_eq!(report.unmigrated_files().count(), 0);
+
+        db.drop_table("orders").unwrap();
+        assert!(!dir.path().join("orders.plain").exists());
+        drop(db);
+    }
+
+    #[test]
+    fn test_value_compression() {
+        let (dir, db) = open("compression");
+        let message = |i: usize| {
+            format!("GET /api/orders/{} 200 user=u{} agent=Mozilla/5.0 (X11; Linux x86_64) latency={}ms", i, i % 7, i % 13)
+        };
+        let mut ids = Vec::new();
+        for i in 0..200 {
+            let data = IndexMap::from([("message".to_string(), Value::String(message(i)))]);
+            ids.push(db.insert("logs", data).unwrap());
+        }
+        db.set_value_compression("logs", Some(64)).unwrap();
+        db.create_index("logs", "message").unwrap();
+        assert!(db.train_compression_dictionary("logs").unwrap() > 0);
+        assert!(dir.path().join("logs.dict").exists());
+        db.save_all().unwrap();
+
+        // 表文件中保存压缩后的值，读取、查询和索引都看到原值
+        let stored = storage::read_table_file(&dir.path().join("logs.db"), None).unwrap();
+        assert!(matches!(stored[&ids[5]].data["message"], Value::Compressed { .. }));
+        assert_eq!(db.find_by_id("logs", &ids[5]).unwrap().unwrap().data["message"], Value::String(message(5)));
+        let found = db.query("logs", &Query::eq("message", Value::String(message(7)))).unwrap();
+        assert_eq!(found.len(), 1);
+        db.update("logs", &ids[7], IndexMap::from([("message".to_string(), Value::String(message(1000)))])).unwrap();
+        assert!(db.query("logs", &Query::eq("message", Value::String(message(7)))).unwrap().is_empty());
+        drop(db);
+
+        // 重新打开时按元数据和字典文件解压；关闭压缩后字典文件删除
+        let db = reopen(&dir);
+        assert_eq!(db.find_by_id("logs", &ids[7]).unwrap().unwrap().data["message"], Value::String(message(1000)));
+        db.set_value_compression("logs", None).unwrap();
+        assert!(!dir.path().join("logs.dict").exists());
+        db.save_all().unwrap();
+        let stored = storage::read_table_file(&dir.path().join("logs.db"), None).unwrap();
+        assert_eq!(stored[&ids[5]].data["message"], Value::String(message(5)));
+        assert!(db.train_compression_dictionary("logs").is_err());
+        drop(db);
+    }
+
+    #[test]
+    fn test_blob_dedup() {
+        let (dir, config) = temp_config("blobs", Config {
+            encryption_key: Some(Crypto::generate_key()),
+            blob_threshold: Some(64),
+            ..Config::default()
+        });
+        let blob_files = || {
+            std::fs::read_dir(&dir)
+                .unwrap()
+                .filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "blob"))
+                .count()
+        };
+        let attachment = Value::Bytes((0..=255).collect());
+        let db = SimpleDB::new(config.clone()).unwrap();
+        let mut ids = Vec::new();
+        for table in ["mails", "mails", "tickets"] {
+            let data = IndexMap::from([("attachment".to_string(), attachment.clone())]);
+            ids.push(db.insert(table, data).unwrap());
+        }
+        db.insert("mails", IndexMap::from([("attachment".to_string(), Value::Bytes(vec![1; 10]))])).unwrap();
+        assert_eq!(db.blob_usage(), BlobUsage { blobs: 1, references: 3, bytes: 256 });
+        assert_eq!(blob_files(), 1);
+        assert_eq!(db.find_by_id("tickets", &ids[2]).unwrap().unwrap().data["attachment"], attachment);
+        assert_eq!(db.query("mails", &Query::eq("attachment", attachment.clone())).unwrap().len(), 2);
+        db.save_all().unwrap();
+        drop(db);
+
+        // 重新打开时按表中的引用重新计数；最后一个引用删除、所有表保存后blob文件才删除
+        let db = SimpleDB::new(config.clone()).unwrap();
+        assert_eq!(db.blob_usage().references, 3);
+        db.delete("mails", &ids[0]).unwrap();
+        db.drop_table("tickets").unwrap();
+        db.update("mails", &ids[1], IndexMap::new()).unwrap();
+        assert_eq!(db.blob_usage(), BlobUsage::default());
+        assert_eq!(blob_files(), 1);
+        db.save_all().unwrap();
+        assert_eq!(blob_files(), 0);
+        drop(db);
+    }
+
+    #[test]
+    fn test_otlp_export() {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        // 充当追踪接收端：接收一次导出请求，返回请求行和请求体
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let collector = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let (mut request_line, mut length) = (String::new(), 0);
+            reader.read_line(&mut request_line).unwrap();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+            (request_line, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+        });
+
+        let (_dir, db) = open_with("otlp", Config {
+            otlp_endpoint: Some(endpoint),
+            service_name: "orders-service".to_string(),
+            ..Config::default()
+        });
+        let parent = crate::TraceContext::parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        parent.scope(|| {
+            db.insert("orders", IndexMap::from([("total".to_string(), Value::Int(5))])).unwrap();
+            assert_eq!(db.find_all("orders").unwrap().len(), 1);
+        });
+        assert!(db.find_by_id("missing", "1").is_err());
+        assert!(db.in_trace("00-bad", || db.count("orders")).is_err());
+        assert_eq!(db.flush_traces().unwrap(), 3);
+
+        let (request_line, body) = collector.join().unwrap();
+        assert!(request_line.starts_with("POST /v1/traces "));
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(resource["resource"]["attributes"][0]["value"]["stringValue"], "orders-service");
+        let spans = resource["scopeSpans"][0]["spans"].as_array().unwrap();
+        let names: Vec<&str> = spans.iter().map(|span| span["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["insert orders", "find_all orders", "find_by_id missing"]);
+        // 调用方上下文中的操作属于调用方的追踪，之外的操作开始新的追踪
+        for span in &spans[..2] {
+            assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+            assert_eq!(span["parentSpanId"], "00f067aa0ba902b7");
+        }
+        assert_ne!(spans[2]["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(spans[2]["status"]["code"], 2);
+        let rows = spans[1]["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|attribute| attribute["key"] == "db.response.returned_rows")
+            .unwrap();
+        assert_eq!(rows["value"]["intValue"], "1");
+        drop(db);
+    }
+
+    #[test]
+    fn test_read_only_reload() {
+        let (_dir, config) = temp_config("replica", Config {
+            ..Config::default()
+        });
+        let writer = SimpleDB::new(config.clone()).unwrap();
+        writer.insert("users", IndexMap::from([("name".to_string(), Value::String("a".to_string()))])).unwrap();
+        writer.save_all().unwrap();
+
+        let replica = SimpleDB::new(Config { read_only: true, ..config }).unwrap();
+        assert_eq!(replica.count("users").unwrap(), 1);
+        assert!(matches!(replica.insert("users", IndexMap::new()), Err(DatabaseError::NotPermitted(_))));
+        assert!(matches!(replica.drop_table("users"), Err(DatabaseError::NotPermitted(_))));
+        assert!(replica.reload_changed().unwrap().is_empty());
+
+        // 写入进程保存后，副本重新加载变化的表、加载新表、移除已删除的表
+        writer.insert("users", IndexMap::new()).unwrap();
+        writer.insert("orders", IndexMap::new()).unwrap();
+        writer.save_all().unwrap();
+        assert_eq!(replica.reload_changed().unwrap(), ["orders", "users"]);
+        assert_eq!(replica.count("users").unwrap(), 2);
+        writer.drop_table("orders").unwrap();
+        assert_eq!(replica.reload_changed().unwrap(), ["orders"]);
+        assert!(replica.count("orders").is_err());
+        drop(replica);
+        drop(writer);
+    }
+
+    #[test]
+    fn test_load_report_for_damaged_table() {
+        let (dir, db) = open("damaged");
+        let line = |n: i64| IndexMap::from([("line".to_string(), Value::Int(n))]);
+        for n in 0..3 {
+            db.insert("logs", line(n)).unwrap();
+        }
+        db.insert("users", line(0)).unwrap();
+        drop(db);
+
+        // 改动第二个记录帧的内容
+        let path = dir.path().join("logs.db");
+        let mut content = std::fs::read(&path).unwrap();
+        let second = content
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| *w == format::FRAME_MAGIC)
+            .nth(1)
+            .unwrap()
+            .0;
+        content[second + format::FRAME_HEADER_LEN] ^= 0xff;
+        std::fs::write(&path, content).unwrap();
+
+        let db = reopen(&dir);
+        let report = db.load_report();
+        assert_eq!(report.damaged.keys().collect::<Vec<_>>(), vec!["logs"]);
+        assert_eq!(report.damaged["logs"].len(), 1);
+        assert_eq!(db.count("logs").unwrap(), 2);
+        assert_eq!(db.count("users").unwrap(), 1);
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_repair() {
+        let (dir, db) = open("repair");
+        let config = db.config().clone();
+        let line = |n: i64| IndexMap::from([("line".to_string(), Value::Int(n))]);
+        for n in 0..3 {
+            db.insert("logs", line(n)).unwrap();
+        }
+        drop(db);
+
+        // 写入中断：截掉最后一个记录帧的末尾；另有文件头损坏的表文件和未写完的临时文件
+        let path = dir.path().join("logs.db");
+        let content = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &content[..content.len() - 2]).unwrap();
+        let mut broken = content.clone();
+        broken[8] = 0xff;
+        std::fs::write(dir.path().join("broken.db"), broken).unwrap();
+        std::fs::write(dir.path().join("users.meta.tmp"), "{").unwrap();
+
+        let report = SimpleDB::repair(&config).unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.repaired.len(), 1);
+        assert_eq!((report.repaired[0].recovered, report.repaired[0].lost.len()), (2, 1));
+        assert_eq!(report.quarantined.len(), 1);
+        assert_eq!(report.removed_temp, vec!["users.meta.tmp"]);
+        assert_eq!(std::fs::read_dir(dir.path().join(repair::QUARANTINE_DIR)).unwrap().count(), 2);
+
+        let db = SimpleDB::new(config.clone()).unwrap();
+        assert!(db.load_report().is_clean());
+        assert_eq!(db.list_tables(), vec!["logs".to_string()]);
+        assert_eq!(db.count("logs").unwrap(), 2);
+        assert!(db.verify(true).unwrap().is_ok());
+        drop(db);
+        assert!(SimpleDB::repair(&config).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_repair_state_files() {
+        let (dir, db) = open("repair_state");
+        let config = db.config().clone();
+        db.insert("users", IndexMap::from([("name".to_string(), Value::String("a".to_string()))]))
+            .unwrap();
+        // 数据库打开时不能修复
+        assert!(matches!(SimpleDB::repair(&config), Err(DatabaseError::LockTimeout(_))));
+        drop(db);
+
+        std::fs::write(dir.path().join(SCHEDULE_FILE), statefile::encode(crate::schedule::SCHEDULE_MAGIC, &[0xff; 3]))
+            .unwrap();
+        std::fs::write(dir.path().join("notes.tmp"), "not ours").unwrap();
+        let report = SimpleDB::repair(&config).unwrap();
+        assert_eq!(report.quarantined.len(), 1);
+        assert_eq!(report.quarantined[0].0, SCHEDULE_FILE);
+        assert!(report.removed_temp.is_empty());
+        assert!(dir.path().join("notes.tmp").exists());
+
+        let db = SimpleDB::new(config.clone()).unwrap();
+        assert_eq!(db.count("users").unwrap(), 1);
+        assert!(db.scheduled_jobs().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_bundle_mode() {
+        let dir = temp_dir("bundle");
+        let path = dir.path().join("data.sdb");
+        let config = Config {
+            bundle: Some(path.to_string_lossy().into_owned()),
+            ..Config::default()
+        };
+        let name = |n: &str| IndexMap::from([("name".to_string(), Value::String(n.to_string()))]);
+
+        let db = SimpleDB::new(config.clone()).unwrap();
+        let work_dir = PathBuf::from(&db.config.data_dir);
+        let id = db.insert("users", name("alice")).unwrap();
+        db.create_index("users", "name").unwrap();
+        drop(db);
+        // 关闭后只剩单文件，工作目录被删除
+        assert!(path.exists());
+        assert!(!work_dir.exists());
+
+        let db = SimpleDB::new(config).unwrap();
+        assert_eq!(db.list_tables(), vec!["users".to_string()]);
+        assert!(db.find_by_id("users", &id).unwrap().is_some());
+        assert!(db.verify(true).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_multikey_index() {
+        let (_dir, db) = open("multikey");
+        let tags = |tags: &[&str]| Value::Array(tags.iter().map(|t| Value::String(t.to_string())).collect());
+        let a = db.insert("articles", IndexMap::from([("tags".to_string(), tags(&["rust", "db"]))])).unwrap();
+        let b = db.insert("articles", IndexMap::from([("tags".to_string(), tags(&["go"]))])).unwrap();
+        let c = db.insert("articles", IndexMap::from([("tags".to_string(), Value::String("rust".to_string()))])).unwrap();
+        let rust = Value::String("rust".to_string());
+
+        // 无索引时扫描，结果与多键索引一致
+        assert_eq!(db.find_by_field("articles", "tags", &rust).unwrap().len(), 2);
+        db.create_index("articles", "tags").unwrap();
+        assert!(db.list_indexes("articles").unwrap()[0].multikey);
+        assert_eq!(db.find_by_field("articles", "tags", &rust).unwrap().len(), 2);
+        assert_eq!(db.find_by_field("articles", "tags", &tags(&["go"])).unwrap()[0].id, b);
+
+        // 更新后元素键随之变化
+        db.update("articles", &a, IndexMap::from([("tags".to_string(), tags(&["db"]))])).unwrap();
+        db.update("articles", &b, IndexMap::from([("tags".to_string(), tags(&["go", "rust"]))])).unwrap();
+        let mut ids: Vec<String> = db.find_by_field("articles", "tags", &rust).unwrap().iter().map(|r| r.id.clone()).collect();
+        ids.sort();
+        let mut expected = vec![b, c];
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_partial_index() {
+        let (_dir, db) = open("partial");
+        let mut ids = Vec::new();
+        for i in 0..20 {
+            let data = IndexMap::from([
+                ("email".to_string(), Value::String(format!("u{}@example.com", i))),
+                ("active".to_string(), Value::Bool(i < 5)),
+            ]);
+            ids.push(db.insert("users", data).unwrap());
+        }
+        let active = Condition::eq("active", Value::Bool(true));
+        db.create_partial_index("users", "email", vec![active.clone()]).unwrap();
+        let info = &db.list_indexes("users").unwrap()[0];
+        assert_eq!((info.distinct_keys, info.filter.clone()), (Some(5), vec![active.clone()]));
+
+        // 查询条件包含过滤条件时才使用部分索引
+        let email = Condition::eq("email", Value::String("u2@example.com".to_string()));
+        let query = Query::new().filter(email.clone()).filter(active);
+        assert_eq!(db.explain("users", &query).unwrap().index.as_deref(), Some("email"));
+        assert_eq!(db.query("users", &query).unwrap().len(), 1);
+        let inactive = Query::new().filter(Condition::eq("email", Value::String("u9@example.com".to_string())));
+        assert_eq!(db.explain("users", &inactive).unwrap().index, None);
+        assert_eq!(db.query("users", &inactive).unwrap().len(), 1);
+
+        // 记录不再满足过滤条件时移出索引
+        db.patch("users", &ids[2], &[UpdateOp::Set("active".to_string(), Value::Bool(false))]).unwrap();
+        assert_eq!(db.list_indexes("users").unwrap()[0].distinct_keys, Some(4));
+        assert!(db.query("users", &query).unwrap().is_empty());
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_indexes_survive_reopen() {
+        let (dir, db) = open("reopen-index");
+        let place = |lat: f64| IndexMap::from([
+            ("location".to_string(), Value::GeoPoint { lat, lon: 116.4 }),
+            ("code".to_string(), Value::Int((lat * 10.0) as i64)),
+        ]);
+        db.insert("places", place(39.9)).unwrap();
+        db.insert("places", place(31.2)).unwrap();
+        db.create_geo_index("places", "location").unwrap();
+        db.create_unique_index("places", &["code"]).unwrap();
+        db.create_index("places", "tag").unwrap();
+        assert!(db.drop_index("places", "tag").unwrap());
+        db.save_all().unwrap();
+        drop(db);
+
+        let db = reopen(&dir);
+        let kinds: Vec<_> = db.list_indexes("places").unwrap().into_iter().map(|i| (i.kind, i.field)).collect();
+        assert_eq!(kinds.len(), 2);
+        assert!(kinds.contains(&(IndexKind::Geo, "location".to_string())));
+        assert!(kinds.contains(&(IndexKind::Unique, "code".to_string())));
+        let beijing = Value::GeoPoint { lat: 39.9, lon: 116.4 };
+        assert_eq!(db.find_near("places", "location", &beijing, 1000.0).unwrap().len(), 1);
+        assert!(matches!(db.insert("places", place(39.9)), Err(DatabaseError::DuplicateKey(_))));
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_if_match_etags() {
+        let (_dir, db) = open("if-match");
+        let id = db.insert("users", IndexMap::from([("n".to_string(), Value::Int(1))])).unwrap();
+        let etag = db.find_by_id("users", &id).unwrap().unwrap().etag();
+        let set = |n: i64| [UpdateOp::Set("n".to_string(), Value::Int(n))];
+
+        // 与If-Match头的比较规则一致：弱校验前缀、多个候选和`*`
+        db.patch_if_match("users", &id, &format!("W/{}", etag), &set(2)).unwrap();
+        let etag = db.find_by_id("users", &id).unwrap().unwrap().etag();
+        db.patch_if_match("users", &id, &format!("\"stale\", {}", etag), &set(3)).unwrap();
+        db.patch_if_match("users", &id, "*", &set(4)).unwrap();
+        assert!(matches!(
+            db.patch_if_match("users", &id, &etag, &set(5)),
+            Err(DatabaseError::PreconditionFailed(_))
+        ));
+
+        // ETag不随进程变化
+        let record = Record {
+            id: "r1".to_string(),
+            data: IndexMap::from([("n".to_string(), Value::Int(1))]),
+            created_at: 0,
+            updated_at: 0x10,
+        };
+        assert_eq!(record.etag(), "\"10-4e500995d38adb6e\"");
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_compound_unique_index() {
+        let (_dir, db) = open("unique");
+        let order = |user: i64, product: i64| {
+            IndexMap::from([
+                ("user_id".to_string(), Value::Int(user)),
+                ("product_id".to_string(), Value::Int(product)),
+            ])
+        };
+        db.insert("orders", order(1, 1)).unwrap();
+        let second = db.insert("orders", order(1, 2)).unwrap();
+        db.create_unique_index("orders", &["user_id", "product_id"]).unwrap();
+        assert_eq!(db.list_indexes("orders").unwrap()[0].field, "user_id,product_id");
+
+        assert!(matches!(db.insert("orders", order(1, 1)), Err(DatabaseError::DuplicateKey(_))));
+        db.insert("orders", order(2, 1)).unwrap();
+        // 缺少字段的记录不受约束
+        let partial = IndexMap::from([("user_id".to_string(), Value::Int(1))]);
+        db.insert("orders", partial.clone()).unwrap();
+        db.insert("orders", partial).unwrap();
+
+        // 更新为已存在的组合时失败且记录不变；更新为自身的值不算冲突
+        assert!(db.update("orders", &second, order(2, 1)).is_err());
+        assert_eq!(db.find_by_id("orders", &second).unwrap().unwrap().data, order(1, 2));
+        db.update("orders", &second, order(1, 2)).unwrap();
+        assert!(db.patch_all("orders", &[UpdateOp::Set("product_id".to_string(), Value::Int(9))]).is_err());
+
+        // 已有重复时不能创建
+        assert!(db.create_unique_index("orders", &["user_id"]).is_err());
+        assert!(db.drop_index("orders", "user_id,product_id").unwrap());
+        db.insert("orders", order(1, 1)).unwrap();
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_background_index_build() {
+        let (_dir, db) = open("background");
+        let mut ids = Vec::new();
+        for i in 0..5000 {
+            let data = IndexMap::from([("team".to_string(), Value::Int(i % 10))]);
+            ids.push(db.insert("users", data).unwrap());
+        }
+        let build = db.create_index_background("users", "team", Vec::new()).unwrap();
+
+        // 构建期间的写入在完成时补到索引中
+        db.delete("users", &ids[0]).unwrap();
+        db.update("users", &ids[1], IndexMap::from([("team".to_string(), Value::Int(0))])).unwrap();
+        db.insert("users", IndexMap::from([("team".to_string(), Value::Int(0))])).unwrap();
+        build.join().unwrap().unwrap();
+
+        assert!(db.index_builds("users").unwrap().is_empty());
+        let team = Value::Int(0);
+        assert_eq!(db.list_indexes("users").unwrap()[0].field, "team");
+        assert_eq!(db.find_by_field("users", "team", &team).unwrap().len(), 501);
+        let scanned = db.find_where("users", |r| r.data.get("team") == Some(&team)).unwrap();
+        assert_eq!(scanned.len(), 501);
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_clone_table() {
+        let (dir, db) = open("clone");
+        for i in 0..10 {
+            db.insert("users", IndexMap::from([("team".to_string(), Value::Int(i % 2))])).unwrap();
+        }
+        db.create_index("users", "team").unwrap();
+        db.clone_table("users", "staging").unwrap();
+        assert!(matches!(db.clone_table("users", "staging"), Err(DatabaseError::DuplicateKey(_))));
+
+        // 副本带有索引，修改副本不影响源表
+        assert_eq!(db.list_indexes("staging").unwrap()[0].field, "team");
+        db.insert("staging", IndexMap::from([("team".to_string(), Value::Int(0))])).unwrap();
+        assert_eq!(db.find_by_field("staging", "team", &Value::Int(0)).unwrap().len(), 6);
+        assert_eq!(db.count("users").unwrap(), 10);
+        assert!(db.verify(false).unwrap().is_ok());
+
+        drop(db);
+        let db = reopen(&dir);
+        assert_eq!(db.count("staging").unwrap(), 11);
+        drop(db);
+    }
+
+    #[test]
+    fn test_truncate_keeps_indexes() {
+        let (_dir, db) = open("truncate");
+        for i in 0..10 {
+            db.insert("users", IndexMap::from([("team".to_string(), Value::Int(i % 2))])).unwrap();
+        }
+        db.create_index("users", "team").unwrap();
+        db.create_unique_index("users", &["email"]).unwrap();
+        db.analyze("users").unwrap();
+
+        assert_eq!(db.truncate("users").unwrap(), 10);
+        assert_eq!(db.count("users").unwrap(), 0);
+        assert_eq!(db.list_indexes("users").unwrap().len(), 2);
+        assert!(db.table_stats("users").unwrap().is_none());
+        assert!(db.verify(false).unwrap().is_ok());
+
+        // 索引仍在维护新写入的记录
+        db.insert("users", IndexMap::from([("team".to_string(), Value::Int(1))])).unwrap();
+        assert_eq!(db.find_by_field("users", "team", &Value::Int(1)).unwrap().len(), 1);
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_destroy_keeps_foreign_files() {
+        let (dir, db) = open("destroy");
+        db.insert("users", IndexMap::from([("name".to_string(), Value::String("alice".to_string()))])).unwrap();
+        db.analyze("users").unwrap();
+        db.prepare("by_name", "users", Query::eq("name", Value::Param("name".to_string()))).unwrap();
+        drop(db);
+        // 共享目录中与数据库无关的文件，包括同样以.db结尾的文件和与状态文件同名的文件
+        std::fs::write(dir.path().join("notes.txt"), "keep me").unwrap();
+        std::fs::write(dir.path().join("other.db"), "SQLite format 3").unwrap();
+        std::fs::write(dir.path().join("other.stats"), "").unwrap();
+        std::fs::write(dir.path().join(SYNC_FILE), "not ours").unwrap();
+
+        let config = config_in(&dir);
+        let report = SimpleDB::destroy(&config).unwrap();
+        assert_eq!(report.removed, vec!["LOCK", "MANIFEST", "PREPARED", "users.db", "users.stats"]);
+        assert_eq!(report.kept, vec!["SYNC", "notes.txt", "other.db", "other.stats"]);
+        assert!(dir.path().join("notes.txt").exists());
+
+        for file in report.kept {
+            std::fs::remove_file(dir.path().join(file)).unwrap();
+        }
+        assert!(SimpleDB::destroy(&config).unwrap().removed.is_empty());
+        assert!(!dir.path().exists());
+    }
+
+    #[test]
+    fn test_transform() {
+        let (_dir, db) = open("transform");
+        let phone = |p: &str| IndexMap::from([("phone".to_string(), Value::String(p.to_string()))]);
+        let a = db.insert("users", phone("138-0000-0001")).unwrap();
+        let b = db.insert("users", phone("13800000002")).unwrap();
+        db.create_unique_index("users", &["phone"]).unwrap();
+        let mut changes = db.change_feed().subscribe(None).receiver;
+
+        // 去掉分隔符；已经规范的记录不算变化
+        let normalize = |r: &Record| {
+            let phone = r.data.get("phone")?.as_string()?.replace('-', "");
+            Some(IndexMap::from([("phone".to_string(), Value::String(phone))]))
+        };
+        assert_eq!(db.transform("users", normalize).unwrap(), 1);
+        assert_eq!(db.find_by_id("users", &a).unwrap().unwrap().data, phone("13800000001"));
+        assert_eq!(changes.try_recv().unwrap().id, a);
+        assert!(changes.try_recv().is_err());
+
+        // 两条记录互换号码不违反唯一约束；映射成相同号码则整表不变
+        let swapped = db
+            .transform("users", |r| {
+                let other = if r.id == a { "13800000002" } else { "13800000001" };
+                Some(phone(other))
+            })
+            .unwrap();
+        assert_eq!(swapped, 2);
+        assert!(db.transform("users", |_| Some(phone("1"))).is_err());
+        assert_eq!(db.find_by_id("users", &b).unwrap().unwrap().data, phone("13800000001"));
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_resolve_references() {
+        let (_dir, db) = open("resolve");
+        let name = |n: &str| ("name".to_string(), Value::String(n.to_string()));
+        let team = db.insert("teams", IndexMap::from([name("infra")])).unwrap();
+        let alice = db
+            .insert("users", IndexMap::from([name("alice"), ("team".to_string(), Value::reference("teams", &team))]))
+            .unwrap();
+        // 团队的负责人反过来引用成员，形成循环
+        db.update("teams", &team, IndexMap::from([name("infra"), ("lead".to_string(), Value::reference("users", &alice))]))
+            .unwrap();
+        let record = db.find_by_id("users", &alice).unwrap().unwrap();
+
+        let resolved = db.resolve(&record, 5).unwrap();
+        let Some(Value::Object(team_object)) = resolved.data.get("team") else {
+            panic!("引用未展开: {:?}", resolved.data);
+        };
+        assert_eq!(team_object.get("id"), Some(&Value::String(team.clone())));
+        let Some(Value::Object(lead)) = team_object.get("lead") else {
+            panic!("第二层引用未展开: {:?}", team_object);
+        };
+        // 团队已在展开路径上，不再展开
+        assert_eq!(lead.get("team"), Some(&Value::reference("teams", &team)));
+
+        assert_eq!(db.resolve(&record, 0).unwrap().data, record.data);
+        let mut dangling = record.as_ref().clone();
+        dangling.data.insert("team".to_string(), Value::reference("teams", "missing"));
+        assert_eq!(db.resolve(&dangling, 1).unwrap().data, dangling.data);
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_event_table_projection() {
+        let (_dir, db) = open("events");
+        db.create_event_table("ledger").unwrap();
+        let event = |amount: i64| IndexMap::from([("amount".to_string(), Value::Int(amount))]);
+        fn balance(total: i64, event: &Record) -> i64 {
+            total + event.data["amount"].as_int().unwrap_or(0)
+        }
+
+        let first = db.insert("ledger", event(100)).unwrap();
+        db.insert("ledger", event(-30)).unwrap();
+        assert_eq!(first, "00000000000000000001");
+        assert!(matches!(db.update("ledger", &first, event(0)), Err(DatabaseError::NotPermitted(_))));
+        assert!(matches!(db.delete("ledger", &first), Err(DatabaseError::NotPermitted(_))));
+        assert!(db.truncate("ledger").is_err());
+        assert_eq!(db.project("ledger", balance).unwrap(), 70);
+
+        // 从检查点继续归约新追加的事件
+        db.insert("ledger", event(5)).unwrap();
+        assert_eq!(db.project("ledger", balance).unwrap(), 75);
+        let count: fn(usize, &Record) -> usize = |n, _| n + 1;
+        assert_eq!(db.project("ledger", count).unwrap(), 3);
+
+        // 重新打开后仍是事件表，序号接着分配
+        let config = db.config().clone();
+        drop(db);
+        let db = SimpleDB::new(config).unwrap();
+        assert!(db.delete("ledger", &first).is_err());
+        assert_eq!(db.insert("ledger", event(1)).unwrap(), "00000000000000000004");
+        assert_eq!(db.project("ledger", balance).unwrap(), 76);
+
+        db.insert("users", event(1)).unwrap();
+        assert!(db.project("users", balance).is_err());
+        assert!(db.create_event_table("users").is_err());
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_cost_based_index_choice() {
+        let (_dir, db) = open("plan");
+        for i in 0..100 {
+            let status = if i < 90 { "active" } else { "banned" };
+            let data = IndexMap::from([
+                ("status".to_string(), Value::String(status.to_string())),
+                ("team".to_string(), Value::Int(i % 20)),
+            ]);
+            db.insert("users", data).unwrap();
+        }
+        db.create_index("users", "status").unwrap();
+        db.create_index("users", "team").unwrap();
+
+        let active = Condition::eq("status", Value::String("active".to_string()));
+        let banned = Condition::eq("status", Value::String("banned".to_string()));
+        let team = Condition::eq("team", Value::Int(3));
+
+        // 选择候选最少的索引
+        let plan = db.explain("users", &Query::new().filter(active.clone()).filter(team.clone())).unwrap();
+        assert_eq!((plan.index.as_deref(), plan.estimated_scanned), (Some("team"), 5));
+        // 索引几乎覆盖整张表时不如直接扫描
+        let plan = db.explain("users", &Query::new().filter(active.clone())).unwrap();
+        assert_eq!(plan.index, None);
+        assert_eq!(db.explain("users", &Query::new().filter(banned)).unwrap().index.as_deref(), Some("status"));
+
+        // 提示覆盖自动选择，结果不变
+        let query = Query::new().filter(active).filter(team);
+        let hinted = query.clone().use_index("status");
+        assert_eq!(db.explain("users", &hinted).unwrap().index.as_deref(), Some("status"));
+        assert_eq!(db.explain("users", &query.clone().force_scan()).unwrap().index, None);
+        let ids = |query: &Query| {
+            let mut ids: Vec<String> = db.query("users", query).unwrap().iter().map(|r| r.id.clone()).collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids(&hinted).len(), 5);
+        assert_eq!(ids(&hinted), ids(&query));
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_json_engine_reopen() {
+        let (dir, config) = temp_config("json_engine", Config {
+            engine: format::Engine::Json,
+            ..Config::default()
+        });
+        let person = |name: &str, age: i64| {
+            IndexMap::from([
+                ("name".to_string(), Value::String(name.to_string())),
+                ("age".to_string(), Value::Int(age)),
+                ("tags".to_string(), Value::Array(vec![Value::String("a".to_string()), Value::Bool(true)])),
+            ])
+        };
+        let data = |db: &SimpleDB| -> BTreeMap<String, IndexMap<String, Value>> {
+            let records = db.query("users", &Query::new()).unwrap();
+            records.iter().map(|record| (record.id.clone(), record.data.clone())).collect()
+        };
+
+        let db = SimpleDB::new(config.clone()).unwrap();
+        db.insert("users", person("张三", 25)).unwrap();
+        db.insert("users", person("李四", 30)).unwrap();
+        db.save_all().unwrap();
+        let mut expected = data(&db);
+        drop(db);
+
+        // 表文件是格式化的JSON，手工编辑后重新打开能读到修改
+        let path = dir.path().join("users.db");
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(format::is_json(content.as_bytes()) && content.contains("\n  "), "{}", content);
+        std::fs::write(&path, content.replace("张三", "张三丰")).unwrap();
+        for record in expected.values_mut() {
+            if record["name"] == Value::String("张三".to_string()) {
+                record.insert("name".to_string(), Value::String("张三丰".to_string()));
+            }
+        }
+        let db = SimpleDB::new(config).unwrap();
+        assert_eq!(data(&db), expected);
+        db.insert("users", person("王五", 35)).unwrap();
+        db.save_all().unwrap();
+        let expected = data(&db);
+        drop(db);
+        assert!(format::is_json(&std::fs::read(&path).unwrap()));
+
+        // 改用默认引擎打开时自动识别JSON文件，保存后写为带文件头的二进制格式
+        let db = reopen(&dir);
+        assert_eq!(data(&db), expected);
+        db.delete("users", expected.keys().next().unwrap()).unwrap();
+        db.save_all().unwrap();
+        let expected = data(&db);
+        drop(db);
+        assert!(format::read_header(&path).unwrap().is_some());
+        assert_eq!(data(&reopen(&dir)), expected);
+    }
+}
@@ -0,0 +1,182 @@
+//! ISO-8601日期时间与`Value::Timestamp`（Unix纪元以来的毫秒数，UTC）之间的转换
+
+/// Unix纪元以来的天数对应的（年, 月, 日）
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// （年, 月, 日）对应的Unix纪元以来的天数
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// 解析ISO-8601日期时间，返回Unix纪元以来的毫秒数
+///
+/// 接受`2024-05-01`、`2024-05-01T08:30`、`2024-05-01T08:30:00.123+08:00`等形式，日期和时间之间也可以用空格；
+/// 没有时区时按UTC处理，毫秒以下的小数截断。
+pub fn parse(text: &str) -> Option<i64> {
+    let text = text.trim();
+    let digits = |s: &str| (!s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())).then(|| s.parse::<i64>().ok()).flatten();
+    let (date, time) = match text.find(['T', 't', ' ']) {
+        Some(at) => (&text[..at], Some(&text[at + 1..])),
+        None => (text, None),
+    };
+
+    let mut parts = date.split('-');
+    let (year, month, day) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(y), Some(m), Some(d), None) if y.len() == 4 && m.len() == 2 && d.len() == 2 => (digits(y)?, digits(m)? as u32, digits(d)? as u32),
+        _ => return None,
+    };
+    if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+        return None;
+    }
+    let mut millis = days_from_civil(year, month, day) * 86_400_000;
+
+    let Some(time) = time else { return Some(millis) };
+    let (clock, offset) = match time.find(['Z', 'z', '+', '-']) {
+        Some(at) => (&time[..at], Some(&time[at..])),
+        None => (time, None),
+    };
+    let (clock, fraction) = match clock.split_once(['.', ',']) {
+        Some((clock, fraction)) => (clock, Some(fraction)),
+        None => (clock, None),
+    };
+    let fields: Vec<&str> = clock.split(':').collect();
+    if !(2..=3).contains(&fields.len()) || fields.iter().any(|f| f.len() != 2) || (fraction.is_some() && fields.len() != 3) {
+        return None;
+    }
+    let (hour, minute) = (digits(fields[0])?, digits(fields[1])?);
+    let second = fields.get(2).map_or(Some(0), |s| digits(s))?;
+    if hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    millis += (hour * 3600 + minute * 60 + second) * 1000;
+    if let Some(fraction) = fraction {
+        digits(fraction)?;
+        let padded = format!("{:0<3}", &fraction[..fraction.len().min(3)]);
+        millis += padded.parse::<i64>().ok()?;
+    }
+
+    match offset {
+        None | Some("Z" | "z") => {}
+        Some(offset) => {
+            let sign = if offset.starts_with('-') { 1 } else { -1 };
+            let offset = &offset[1..];
+            let (hours, minutes) = match offset.len() {
+                2 => (offset, "00"),
+                4 => offset.split_at(2),
+                5 if &offset[2..3] == ":" => (&offset[..2], &offset[3..]),
+                _ => return None,
+            };
+            let (hours, minutes) = (digits(hours)?, digits(minutes)?);
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            millis += sign * (hours * 60 + minutes) * 60_000;
+        }
+    }
+    Some(millis)
+}
+
+/// 格式化为UTC的ISO-8601日期时间，如`2024-05-01T00:30:00Z`，有毫秒时带三位小数
+pub fn format(millis: i64) -> String {
+    let (days, rest) = (millis.div_euclid(86_400_000), millis.rem_euclid(86_400_000));
+    let (year, month, day) = civil_from_days(days);
+    let (seconds, millis) = (rest / 1000, rest % 1000);
+    let clock = format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if millis == 0 {
+        format!("{:04}-{:02}-{:02}T{}Z", year, month, day, clock)
+    } else {
+        format!("{:04}-{:02}-{:02}T{}.{:03}Z", year, month, day, clock, millis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{open, request, serve};
+    use crate::schema::FieldType;
+    use crate::storage::Value;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_and_format() {
+        let noon = days_from_civil(2024, 5, 1) * 86_400_000 + 12 * 3_600_000;
+        assert_eq!(parse("2024-05-01T12:00:00Z"), Some(noon));
+        assert_eq!(parse("2024-05-01 12:00"), Some(noon));
+        assert_eq!(parse("2024-05-01T20:00:00+08:00"), Some(noon));
+        assert_eq!(parse("2024-05-01T07:30:00-0430"), Some(noon));
+        assert_eq!(parse("2024-05-01T12:00:00.1234Z"), Some(noon + 123));
+        assert_eq!(parse("2024-05-01"), Some(noon - 12 * 3_600_000));
+        assert_eq!(parse("1969-12-31T23:59:59.5Z"), Some(-500));
+        for invalid in ["2023-02-29", "2024-13-01", "2024-5-1", "2024-05-01T24:00", "2024-05-01T12", "2024-05-01T12:00+8", "张三"] {
+            assert_eq!(parse(invalid), None, "{}", invalid);
+        }
+
+        assert_eq!(format(noon), "2024-05-01T12:00:00Z");
+        assert_eq!(format(-500), "1969-12-31T23:59:59.500Z");
+        assert_eq!(parse(&format(noon + 7)), Some(noon + 7));
+    }
+
+    #[tokio::test]
+    async fn test_datetime_fields_over_http() {
+        let (_dir, db) = open("datetime");
+        let db = Arc::new(db);
+        let (address, serving) = serve(Arc::clone(&db));
+
+        let response = request(address, "PUT /api/tables/orders/schema HTTP/1.1", r#"{"types": {"ordered_at": "datetime"}}"#).await;
+        assert!(response.contains(r#""ordered_at":"datetime""#), "{}", response);
+        for body in [
+            r#"{"table": "orders", "data": {"no": 1, "ordered_at": "2024-05-01T09:00:00+08:00"}}"#,
+            r#"{"table": "orders", "data": {"no": 2, "ordered_at": "2024-05-01T12:00:00Z"}}"#,
+            r#"{"table": "orders", "data": {"no": 3, "ordered_at": "2024-04-30"}}"#,
+        ] {
+            request(address, "POST /api/insert HTTP/1.1", body).await;
+        }
+        let orders = db.find_all("orders").unwrap();
+        let first = orders.iter().find(|order| order.data["no"] == Value::Int(1)).unwrap();
+        assert_eq!(first.data["ordered_at"], Value::Timestamp(parse("2024-05-01T01:00:00Z").unwrap()));
+
+        // 不同时区的字符串按时间先后比较，返回时统一为UTC
+        let response = request(
+            address,
+            "GET /api/find HTTP/1.1",
+            r#"{"table": "orders", "query": {"ordered_at": {"$gt": "2024-05-01T08:00:00+08:00"}}}"#,
+        )
+        .await;
+        assert!(response.contains(r#""ordered_at":"2024-05-01T01:00:00Z""#), "{}", response);
+        assert!(response.contains(r#""no":2"#), "{}", response);
+        assert!(!response.contains(r#""no":3"#), "{}", response);
+
+        assert_eq!(db.field_types("orders").unwrap()["ordered_at"], FieldType::DateTime);
+        db.set_field_type("orders", "ordered_at", None).unwrap();
+        assert!(db.field_types("orders").unwrap().is_empty());
+        serving.abort();
+        let _ = serving.await;
+        drop(db);
+    }
+}
@@ -0,0 +1,79 @@
+//! 数据目录的文件锁
+//!
+//! 打开的数据库（包括只读打开）持有数据目录中`LOCK`文件的共享锁，多个进程可以同时打开同一目录；
+//! 修复等要求数据库未打开的操作持有排他锁，数据库已在任何进程中打开时立即失败而不是等待。
+//! 锁随文件句柄释放，进程崩溃后不会残留。
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::{DatabaseError, Result};
+
+/// 锁文件名
+pub const LOCK_FILE: &str = "LOCK";
+
+/// `LOCK`文件的内容，供`SimpleDB::destroy`识别
+pub const LOCK_MAGIC: &[u8; 8] = b"SDBLOCK1";
+
+/// 持有期间数据目录保持加锁
+#[derive(Debug)]
+pub struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// 打开数据库时取得的共享锁，数据目录正在修复时失败
+    pub fn shared(data_dir: &Path) -> Result<DirLock> {
+        let file = open(data_dir)?;
+        match file.try_lock_shared() {
+            Ok(()) => Ok(DirLock { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(DatabaseError::LockTimeout(format!(
+                "数据目录 {} 正在被修复或恢复",
+                data_dir.display()
+            ))),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+
+    /// 要求数据库未打开的操作取得的排他锁，数据库已打开时失败
+    pub fn exclusive(data_dir: &Path) -> Result<DirLock> {
+        let file = open(data_dir)?;
+        match file.try_lock() {
+            Ok(()) => Ok(DirLock { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(DatabaseError::LockTimeout(format!(
+                "数据目录 {} 中的数据库已打开，要先关闭",
+                data_dir.display()
+            ))),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
+
+fn open(data_dir: &Path) -> Result<File> {
+    let mut file = OpenOptions::new().create(true).truncate(false).read(true).write(true).open(data_dir.join(LOCK_FILE))?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(LOCK_MAGIC)?;
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_dir_lock() {
+        let dir = temp_dir("dirlock");
+        let first = DirLock::shared(dir.path()).unwrap();
+        let second = DirLock::shared(dir.path()).unwrap();
+        assert!(matches!(DirLock::exclusive(dir.path()), Err(DatabaseError::LockTimeout(_))));
+        drop((first, second));
+
+        let exclusive = DirLock::exclusive(dir.path()).unwrap();
+        assert!(matches!(DirLock::shared(dir.path()), Err(DatabaseError::LockTimeout(_))));
+        drop(exclusive);
+        assert!(crate::statefile::is_state_file(&dir.path().join(LOCK_FILE)).unwrap());
+    }
+}
@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::validate::FieldError;
+
 #[derive(Error, Debug)]
 pub enum DatabaseError {
     #[error("IO错误: {0}")]
@@ -25,6 +27,58 @@ pub enum DatabaseError {
 
     #[error("数据格式错误: {0}")]
     DataFormat(String),
+
+    #[error("前置条件不满足: {0}")]
+    PreconditionFailed(String),
+
+    #[error("无效的查询: {0}")]
+    InvalidQuery(String),
+
+    #[error("不允许的操作: {0}")]
+    NotPermitted(String),
+
+    #[error("同步失败: {0}")]
+    Sync(String),
+
+    #[error("超出配额: {0}")]
+    QuotaExceeded(String),
+
+    #[error("检测到死锁: {0}")]
+    Deadlock(String),
+
+    #[error("等待锁超时: {0}")]
+    LockTimeout(String),
+
+    #[error("不是主节点: {0}")]
+    NotLeader(String),
+
+    #[error("集群错误: {0}")]
+    Cluster(String),
+
+    #[error("发布变更失败: {0}")]
+    Publish(String),
+
+    #[error("导出追踪数据失败: {0}")]
+    Telemetry(String),
+
+    #[error("数值溢出: {0}")]
+    Overflow(String),
+
+    #[error("记录校验失败: {}", crate::validate::describe(.0))]
+    Validation(Vec<FieldError>),
+
+    #[error("内部错误: {0}")]
+    Internal(String),
+}
+
+impl DatabaseError {
+    /// 是否为并发写入造成的冲突：记录的ETag已被他人修改、等待锁会形成死锁或等待超时；重新读取后重试通常能成功
+    pub fn is_conflict(&self) -> bool {
+        matches!(
+            self,
+            DatabaseError::PreconditionFailed(_) | DatabaseError::Deadlock(_) | DatabaseError::LockTimeout(_)
+        )
+    }
 }
 
 pub type Result<T> = std::result::Result<T, DatabaseError>; 
\ No newline at end of file
@@ -0,0 +1,858 @@
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::codec::{Bincode, Cbor, Codec, Json, MessagePack, Records};
+use crate::crypto::{Cipher, Crypto};
+use crate::error::{DatabaseError, Result};
+use crate::storage::Record;
+
+/// 表文件开头的魔数
+pub const MAGIC: &[u8; 8] = b"SIMPLEDB";
+
+/// 当前写入的文件格式版本
+///
+/// 版本0是没有文件头的旧格式：bincode序列化的记录，配置了密钥时整体用AES-GCM加密。
+/// 版本1的载荷是整体序列化的记录映射；版本2起载荷按记录分帧，个别记录损坏时其余记录仍可读出。
+/// 版本3起加密的载荷按`CHUNK_SIZE`分块加密，一处损坏只丢失所在的块。
+pub const CURRENT_VERSION: u16 = 3;
+
+/// 加密的载荷（压缩后）每块的明文大小，最后一块可以更小
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// 每块密文比明文多出的nonce和认证标签
+const CHUNK_OVERHEAD: usize = 12 + 16;
+
+/// 每个记录帧开头的标记，帧损坏时据此找到下一帧
+pub const FRAME_MAGIC: &[u8; 4] = b"SREC";
+
+/// 帧头长度：标记、记录长度（u32小端）、记录的CRC32（u32小端）
+pub const FRAME_HEADER_LEN: usize = 12;
+
+/// 文件头长度：魔数、版本（u16小端）、标志、存储引擎
+pub const HEADER_LEN: usize = 12;
+
+/// 标志位：载荷经过AES-GCM加密
+pub const FLAG_ENCRYPTED: u8 = 1;
+/// 标志位：载荷经过zlib压缩（先压缩后加密）
+pub const FLAG_COMPRESSED: u8 = 2;
+/// 标志位：事件表，记录只能追加
+pub const FLAG_APPEND_ONLY: u8 = 4;
+/// 标志位：载荷用AES-GCM-SIV而不是AES-GCM加密
+pub const FLAG_GCM_SIV: u8 = 8;
+
+/// 存储引擎，决定记录的序列化方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Engine {
+    #[default]
+    Bincode,
+    MessagePack,
+    Cbor,
+    /// 格式化的JSON，未加密且未压缩时不写文件头，可以直接查看和手工编辑
+    Json,
+}
+
+impl Engine {
+    fn id(self) -> u8 {
+        match self {
+            Engine::Bincode => 0,
+            Engine::MessagePack => 1,
+            Engine::Cbor => 2,
+            Engine::Json => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(Engine::Bincode),
+            1 => Ok(Engine::MessagePack),
+            2 => Ok(Engine::Cbor),
+            3 => Ok(Engine::Json),
+            other => Err(DatabaseError::DataFormat(format!("不支持的存储引擎: {}", other))),
+        }
+    }
+
+    /// 引擎对应的编码
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            Engine::Bincode => &Bincode,
+            Engine::MessagePack => &MessagePack,
+            Engine::Cbor => &Cbor,
+            Engine::Json => &Json,
+        }
+    }
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "bincode" => Ok(Engine::Bincode),
+            "messagepack" | "msgpack" => Ok(Engine::MessagePack),
+            "cbor" => Ok(Engine::Cbor),
+            "json" => Ok(Engine::Json),
+            other => Err(format!("不支持的存储引擎: {}（可选 bincode、messagepack、cbor、json）", other)),
+        }
+    }
+}
+
+/// 写入表文件的选项
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileOptions {
+    pub engine: Engine,
+    /// 是否用zlib压缩
+    pub compress: bool,
+    /// 是否为只能追加记录的事件表；需要文件头记录，JSON存储也会写出文件头
+    pub append_only: bool,
+}
+
+/// 表文件头
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u16,
+    pub flags: u8,
+    pub engine: Engine,
+}
+
+impl Header {
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+
+    pub fn is_append_only(&self) -> bool {
+        self.flags & FLAG_APPEND_ONLY != 0
+    }
+
+    /// 加密载荷使用的算法
+    pub fn cipher(&self) -> Cipher {
+        if self.flags & FLAG_GCM_SIV != 0 {
+            Cipher::Aes256GcmSiv
+        } else {
+            Cipher::Aes256Gcm
+        }
+    }
+
+    /// 以相同的方式写回时的选项
+    pub fn options(&self) -> FileOptions {
+        FileOptions {
+            engine: self.engine,
+            compress: self.is_compressed(),
+            append_only: self.is_append_only(),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[..8].copy_from_slice(MAGIC);
+        bytes[8..10].copy_from_slice(&self.version.to_le_bytes());
+        bytes[10] = self.flags;
+        bytes[11] = self.engine.id();
+        bytes
+    }
+
+    /// 解析文件头，没有魔数时返回None（版本0的旧文件）
+    pub fn parse(content: &[u8]) -> Result<Option<Self>> {
+        if content.len() < HEADER_LEN || &content[..8] != MAGIC {
+            return Ok(None);
+        }
+        let version = u16::from_le_bytes([content[8], content[9]]);
+        if version == 0 || version > CURRENT_VERSION {
+            return Err(DatabaseError::DataFormat(format!(
+                "不支持的文件格式版本 {}，当前版本最高支持 {}",
+                version, CURRENT_VERSION
+            )));
+        }
+        Ok(Some(Self {
+            version,
+            flags: content[10],
+            engine: Engine::from_id(content[11])?,
+        }))
+    }
+}
+
+/// 表文件中无法读取而被跳过的一段
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Damage {
+    /// 在（解密、解压后的）载荷中的偏移
+    pub offset: usize,
+    /// 跳过的字节数
+    pub len: usize,
+    pub reason: String,
+}
+
+/// 解码后的表文件
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Decoded {
+    pub records: Records,
+    /// 文件原来的格式版本
+    pub version: u16,
+    /// 跳过的损坏部分，为空表示文件完好
+    pub damage: Vec<Damage>,
+    /// 按文件头得出的写入选项，以相同的方式写回
+    pub options: FileOptions,
+}
+
+/// 文件是否像没有文件头的JSON表文件
+pub fn is_json(content: &[u8]) -> bool {
+    content.trim_ascii_start().starts_with(b"{")
+}
+
+/// 文件是否是本数据库写出的表文件：带有文件头，或是没有文件头但能解析为记录的JSON
+///
+/// 只读取文件头，以`{`开头的旧版本JSON文件才读取全部内容解析。
+/// 没有文件头的旧版本bincode文件无法与其他文件可靠地区分，不被识别。
+pub fn is_table_file(path: &Path) -> Result<bool> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    std::fs::File::open(path)?.take(HEADER_LEN as u64).read_to_end(&mut header)?;
+    if matches!(Header::parse(&header), Ok(Some(_))) {
+        return Ok(true);
+    }
+    if !is_json(&header) {
+        return Ok(false);
+    }
+    Ok(Json.decode(&std::fs::read(path)?).is_ok())
+}
+
+/// 只读取文件头，没有文件头时为None
+pub fn read_header(path: &Path) -> Result<Option<Header>> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    std::fs::File::open(path)?.take(HEADER_LEN as u64).read_to_end(&mut header)?;
+    Header::parse(&header)
+}
+
+/// 编码表文件内容：文件头加上（压缩、加密后的）记录
+pub fn encode(records: &Records, crypto: Option<&Crypto>, options: FileOptions) -> Result<Vec<u8>> {
+    if options.engine == Engine::Json && crypto.is_none() && !options.compress && !options.append_only {
+        return Json.encode(records);
+    }
+    let header = Header {
+        version: CURRENT_VERSION,
+        flags: if crypto.is_some() { FLAG_ENCRYPTED } else { 0 }
+            | if options.compress { FLAG_COMPRESSED } else { 0 }
+            | if options.append_only { FLAG_APPEND_ONLY } else { 0 }
+            | if crypto.is_some_and(|c| c.cipher() == Cipher::Aes256GcmSiv) { FLAG_GCM_SIV } else { 0 },
+        engine: options.engine,
+    };
+    let codec = options.engine.codec();
+    let mut payload = Vec::new();
+    for record in records.values() {
+        let body = codec.encode_record(record)?;
+        payload.extend_from_slice(FRAME_MAGIC);
+        payload.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        payload.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+        payload.extend(body);
+    }
+    if options.compress {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&payload)?;
+        payload = encoder.finish()?;
+    }
+    let header = header.to_bytes();
+    if let Some(crypto) = crypto {
+        payload = encrypt_chunks(&payload, crypto, &header)?;
+    }
+
+    let mut content = header.to_vec();
+    content.extend(payload);
+    Ok(content)
+}
+
+/// 第`index`块的附加认证数据：文件头、块序号和是否为最后一块，块被调换、删除或文件在块边界被截断时无法通过认证
+fn chunk_aad(header: &[u8], index: usize, last: bool) -> Vec<u8> {
+    let mut aad = header[..HEADER_LEN].to_vec();
+    aad.extend_from_slice(&(index as u64).to_le_bytes());
+    aad.push(last as u8);
+    aad
+}
+
+/// 把载荷分块加密；空载荷也写出一个空块，使整个载荷被截断时能够发现
+fn encrypt_chunks(payload: &[u8], crypto: &Crypto, header: &[u8]) -> Result<Vec<u8>> {
+    let count = payload.len().div_ceil(CHUNK_SIZE).max(1);
+    let mut content = Vec::with_capacity(payload.len() + count * CHUNK_OVERHEAD);
+    for index in 0..count {
+        let chunk = &payload[(index * CHUNK_SIZE).min(payload.len())..((index + 1) * CHUNK_SIZE).min(payload.len())];
+        content.extend(crypto.encrypt_with_aad(chunk, &chunk_aad(header, index, index + 1 == count))?);
+    }
+    Ok(content)
+}
+
+/// 逐块解密载荷的读取器，内存中只保留当前块和下一块；无法解密的块以零填充并记为损坏
+struct ChunkReader<'a, R> {
+    inner: R,
+    crypto: &'a Crypto,
+    header: Header,
+    /// 已读入、尚未解密的下一块，用来判断当前块是否为最后一块
+    next: Option<Vec<u8>>,
+    plaintext: Vec<u8>,
+    pos: usize,
+    /// 已处理的块数和其中能解密的块数
+    chunks: usize,
+    decrypted: usize,
+    /// 已解密出的载荷字节数
+    offset: usize,
+    damage: Vec<Damage>,
+}
+
+impl<'a, R: Read> ChunkReader<'a, R> {
+    fn new(inner: R, crypto: &'a Crypto, header: Header) -> Self {
+        Self {
+            inner,
+            crypto,
+            header,
+            next: None,
+            plaintext: Vec::new(),
+            pos: 0,
+            chunks: 0,
+            decrypted: 0,
+            offset: 0,
+            damage: Vec::new(),
+        }
+    }
+
+    /// 读入一整块密文，到达末尾时为None
+    fn read_chunk(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE + CHUNK_OVERHEAD);
+        (&mut self.inner).take((CHUNK_SIZE + CHUNK_OVERHEAD) as u64).read_to_end(&mut chunk)?;
+        Ok((!chunk.is_empty()).then_some(chunk))
+    }
+
+    /// 解密下一块放入缓冲区，没有更多的块时返回false
+    fn next_chunk(&mut self) -> std::io::Result<bool> {
+        let chunk = match self.next.take() {
+            Some(chunk) => chunk,
+            None => match self.read_chunk()? {
+                Some(chunk) => chunk,
+                None => return Ok(false),
+            },
+        };
+        self.next = self.read_chunk()?;
+        let (index, last) = (self.chunks, self.next.is_none());
+        self.chunks += 1;
+        let cipher = self.header.cipher();
+        let header = self.header.to_bytes();
+        let mut result = self.crypto.decrypt_with_aad(cipher, &chunk, &chunk_aad(&header, index, last));
+        // 文件在块边界被截断时，现在的最后一块写入时不是最后一块
+        let mut truncated = false;
+        if result.is_err() && last && chunk.len() == CHUNK_SIZE + CHUNK_OVERHEAD {
+            if let Ok(plaintext) = self.crypto.decrypt_with_aad(cipher, &chunk, &chunk_aad(&header, index, false)) {
+                result = Ok(plaintext);
+                truncated = true;
+            }
+        }
+        self.plaintext = match result {
+            Ok(plaintext) => {
+                self.decrypted += 1;
+                if truncated {
+                    self.damage.push(Damage {
+                        offset: self.offset + plaintext.len(),
+                        len: 0,
+                        reason: "加密的载荷被截断，之后的内容丢失".to_string(),
+                    });
+                }
+                plaintext
+            }
+            Err(e) => {
+                let len = chunk.len().saturating_sub(CHUNK_OVERHEAD);
+                self.damage.push(Damage {
+                    offset: self.offset,
+                    len,
+                    reason: format!("第 {} 块无法解密: {}", index, e),
+                });
+                vec![0; len]
+            }
+        };
+        self.offset += self.plaintext.len();
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for ChunkReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.plaintext.len() {
+            if !self.next_chunk()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.plaintext.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plaintext[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// 从载荷流中逐帧读出记录，缓冲区只保留尚未解析的内容
+struct FrameReader<R> {
+    source: R,
+    /// 载荷是否经过压缩，解压失败时之前解压出的内容仍可读出
+    compressed: bool,
+    buffer: Vec<u8>,
+    /// 缓冲区开头在载荷中的偏移
+    base: usize,
+    eof: bool,
+    /// 解压失败的位置
+    damage: Vec<Damage>,
+}
+
+impl<R: Read> FrameReader<R> {
+    fn new(source: R, compressed: bool) -> Self {
+        Self {
+            source,
+            compressed,
+            buffer: Vec::new(),
+            base: 0,
+            eof: false,
+            damage: Vec::new(),
+        }
+    }
+
+    /// 再读入一段内容，到达末尾时设置`eof`
+    fn fill(&mut self) -> Result<()> {
+        let len = self.buffer.len();
+        self.buffer.resize(len + CHUNK_SIZE, 0);
+        let result = loop {
+            match self.source.read(&mut self.buffer[len..]) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                result => break result,
+            }
+        };
+        match result {
+            Ok(n) => {
+                self.buffer.truncate(len + n);
+                self.eof = n == 0;
+            }
+            Err(e) if self.compressed && matches!(e.kind(), std::io::ErrorKind::InvalidData | std::io::ErrorKind::InvalidInput) => {
+                self.buffer.truncate(len);
+                self.eof = true;
+                self.damage.push(Damage {
+                    offset: self.base + len,
+                    len: 0,
+                    reason: format!("解压失败，之后的内容丢失: {}", e),
+                });
+            }
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+
+    /// 读出所有记录，返回记录、跳过的帧和解压失败的位置
+    fn read_all(mut self, codec: &dyn Codec) -> Result<(Records, Vec<Damage>, Vec<Damage>)> {
+        let mut records = Records::new();
+        let mut damage = Vec::new();
+        let mut pos = 0;
+        loop {
+            // 读够一个完整的帧：先读够帧头，帧头给出长度后再读够记录
+            loop {
+                let available = &self.buffer[pos..];
+                let needed = match available.get(..FRAME_HEADER_LEN) {
+                    Some(header) if &header[..4] == FRAME_MAGIC => {
+                        FRAME_HEADER_LEN + u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize
+                    }
+                    _ => FRAME_HEADER_LEN,
+                };
+                if self.eof || available.len() >= needed {
+                    break;
+                }
+                self.fill()?;
+            }
+            if pos == self.buffer.len() {
+                break;
+            }
+            match read_frame(&self.buffer, pos, codec) {
+                Ok((record, next)) => {
+                    records.insert(record.id.clone(), std::sync::Arc::new(record));
+                    pos = next;
+                }
+                Err(reason) => {
+                    let mut from = pos + 1;
+                    let next = loop {
+                        let found = find_frame(&self.buffer, from);
+                        if found < self.buffer.len() || self.eof {
+                            break found;
+                        }
+                        // 帧标记可能跨越两次读入的边界
+                        from = self.buffer.len().saturating_sub(FRAME_MAGIC.len() - 1).max(pos + 1);
+                        self.fill()?;
+                    };
+                    damage.push(Damage {
+                        offset: self.base + pos,
+                        len: next - pos,
+                        reason,
+                    });
+                    pos = next;
+                }
+            }
+            if pos >= CHUNK_SIZE {
+                self.buffer.drain(..pos);
+                self.base += pos;
+                pos = 0;
+            }
+        }
+        Ok((records, damage, self.damage))
+    }
+}
+
+/// 从（解密后的）载荷流中读出记录，按需解压；返回记录、跳过的帧和解压失败的位置
+fn read_payload(source: impl Read, compressed: bool, codec: &dyn Codec) -> Result<(Records, Vec<Damage>, Vec<Damage>)> {
+    if compressed {
+        FrameReader::new(ZlibDecoder::new(source), true).read_all(codec)
+    } else {
+        FrameReader::new(source, false).read_all(codec)
+    }
+}
+
+/// 解码表文件内容，返回记录和文件原来的格式版本；有任何损坏时返回错误
+///
+/// 旧版本的文件在这里迁移为当前的内存表示，调用方据此决定是否以当前格式重写文件。
+pub fn decode(content: &[u8], crypto: Option<&Crypto>) -> Result<(Records, u16)> {
+    read(content, crypto)
+}
+
+/// 与`decode`相同，从`reader`中流式读取，见`read_salvaging`
+pub fn read(reader: impl Read, crypto: Option<&Crypto>) -> Result<(Records, u16)> {
+    let decoded = read_salvaging(reader, crypto)?;
+    if let Some(first) = decoded.damage.first() {
+        return Err(DatabaseError::DataFormat(format!(
+            "表文件有 {} 处损坏，第一处在偏移 {}: {}",
+            decoded.damage.len(),
+            first.offset,
+            first.reason
+        )));
+    }
+    Ok((decoded.records, decoded.version))
+}
+
+/// 解码表文件内容，跳过无法读取的记录帧，尽量读出其余记录
+///
+/// 只有按记录分帧的文件（版本2起）能部分读出：损坏的帧被跳过，压缩数据损坏时保留损坏之前的记录。
+/// 分块加密的文件（版本3起）跳过无法解密的块，其中的记录丢失。
+/// 文件头损坏、所有块都无法解密、整体加密的旧版本文件解密失败或旧版本文件损坏时仍返回错误。
+pub fn decode_salvaging(content: &[u8], crypto: Option<&Crypto>) -> Result<Decoded> {
+    read_salvaging(content, crypto)
+}
+
+/// 与`decode_salvaging`相同，从`reader`中流式读取
+///
+/// 当前格式的文件逐块解密、解压并逐帧解析，内存中只保留一块密文和尚未解析的一段载荷，不会先读入整个文件；
+/// 没有文件头、版本1和整体加密的版本2文件只能整体解码。
+pub fn read_salvaging(mut reader: impl Read, crypto: Option<&Crypto>) -> Result<Decoded> {
+    let mut head = Vec::with_capacity(HEADER_LEN);
+    (&mut reader).take(HEADER_LEN as u64).read_to_end(&mut head)?;
+    let header = match Header::parse(&head)? {
+        Some(header) if header.version >= 3 || (header.version == 2 && !header.is_encrypted()) => header,
+        _ => {
+            reader.read_to_end(&mut head)?;
+            return decode_whole(&head, crypto);
+        }
+    };
+    let codec = header.engine.codec();
+    let (records, damage) = match (header.is_encrypted(), crypto) {
+        (true, Some(crypto)) => {
+            let mut chunks = ChunkReader::new(reader, crypto, header);
+            let (records, mut damage, stream_damage) = read_payload(&mut chunks, header.is_compressed(), codec)?;
+            if chunks.chunks == 0 {
+                chunks.damage.push(Damage {
+                    offset: 0,
+                    len: 0,
+                    reason: "加密的载荷为空（写入中断）".to_string(),
+                });
+            } else if chunks.decrypted == 0 {
+                return Err(DatabaseError::Encryption("解密失败: 所有块都无法解密，密钥可能不正确".to_string()));
+            }
+            damage.extend(chunks.damage);
+            damage.extend(stream_damage);
+            (records, damage)
+        }
+        (true, None) => return Err(DatabaseError::Encryption("表文件已加密，需要提供密钥".to_string())),
+        // 未加密的文件在配置密钥后仍可读取，下次保存时加密
+        (false, _) => {
+            let (records, mut damage, stream_damage) = read_payload(reader, header.is_compressed(), codec)?;
+            damage.extend(stream_damage);
+            (records, damage)
+        }
+    };
+    Ok(Decoded {
+        records,
+        version: header.version,
+        damage,
+        options: header.options(),
+    })
+}
+
+/// 整体解码没有文件头、版本1和整体加密的版本2文件
+fn decode_whole(content: &[u8], crypto: Option<&Crypto>) -> Result<Decoded> {
+    if content.is_empty() {
+        return Ok(Decoded {
+            version: CURRENT_VERSION,
+            ..Decoded::default()
+        });
+    }
+    let Some(header) = Header::parse(content)? else {
+        // 没有文件头的JSON文件；旧格式的bincode不可能是合法的JSON
+        if is_json(content) {
+            if let Ok(records) = Json.decode(content) {
+                return Ok(Decoded {
+                    records,
+                    version: CURRENT_VERSION,
+                    damage: Vec::new(),
+                    options: FileOptions {
+                        engine: Engine::Json,
+                        ..FileOptions::default()
+                    },
+                });
+            }
+        }
+        let data = match crypto {
+            Some(crypto) => crypto.decrypt(content)?,
+            None => content.to_vec(),
+        };
+        return Ok(Decoded {
+            records: Bincode.decode(&data)?,
+            version: 0,
+            damage: Vec::new(),
+            options: FileOptions::default(),
+        });
+    };
+
+    let payload = match (header.is_encrypted(), crypto) {
+        (true, Some(crypto)) => crypto.decrypt_with(header.cipher(), &content[HEADER_LEN..])?,
+        (true, None) => return Err(DatabaseError::Encryption("表文件已加密，需要提供密钥".to_string())),
+        (false, _) => content[HEADER_LEN..].to_vec(),
+    };
+    let codec = header.engine.codec();
+    if header.version >= 2 {
+        let (records, mut damage, stream_damage) = read_payload(&payload[..], header.is_compressed(), codec)?;
+        damage.extend(stream_damage);
+        return Ok(Decoded {
+            records,
+            version: header.version,
+            damage,
+            options: header.options(),
+        });
+    }
+    let payload = if header.is_compressed() {
+        let mut decompressed = Vec::new();
+        ZlibDecoder::new(&payload[..])
+            .read_to_end(&mut decompressed)
+            .map_err(|e| DatabaseError::DataFormat(format!("解压表文件失败: {}", e)))?;
+        decompressed
+    } else {
+        payload
+    };
+    Ok(Decoded {
+        records: codec.decode(&payload)?,
+        version: header.version,
+        damage: Vec::new(),
+        options: header.options(),
+    })
+}
+
+/// 读取从`pos`开始的一个记录帧，返回记录和下一帧的位置
+fn read_frame(payload: &[u8], pos: usize, codec: &dyn Codec) -> std::result::Result<(Record, usize), String> {
+    let header = payload
+        .get(pos..pos + FRAME_HEADER_LEN)
+        .ok_or_else(|| "帧头不完整（写入中断）".to_string())?;
+    if &header[..4] != FRAME_MAGIC {
+        return Err("缺少帧标记".to_string());
+    }
+    let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    let start = pos + FRAME_HEADER_LEN;
+    let body = payload
+        .get(start..start.saturating_add(len))
+        .ok_or_else(|| "记录超出文件末尾（写入中断）".to_string())?;
+    if crc32fast::hash(body) != crc {
+        return Err("记录的校验值不符".to_string());
+    }
+    let record = codec.decode_record(body).map_err(|e| format!("无法解码记录: {}", e))?;
+    Ok((record, start + len))
+}
+
+/// 从`from`起下一个帧标记的位置，没有时为载荷末尾
+fn find_frame(payload: &[u8], from: usize) -> usize {
+    payload
+        .get(from..)
+        .and_then(|rest| rest.windows(FRAME_MAGIC.len()).position(|w| w == FRAME_MAGIC))
+        .map_or(payload.len(), |offset| from + offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::IndexMap;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_reads_legacy_and_current_formats() {
+        let key = Crypto::generate_key();
+        let crypto = Crypto::new(&key).unwrap();
+        let record = Record {
+            id: "1".to_string(),
+            data: IndexMap::new(),
+            created_at: 1,
+            updated_at: 1,
+        };
+        let records = HashMap::from([("1".to_string(), Arc::new(record))]);
+
+        // 没有文件头的旧格式
+        let legacy = crypto.encrypt(&bincode::serialize(&records).unwrap()).unwrap();
+        assert_eq!(decode(&legacy, Some(&crypto)).unwrap(), (records.clone(), 0));
+
+        let options = FileOptions {
+            engine: Engine::Cbor,
+            compress: true,
+            append_only: false,
+        };
+        let content = encode(&records, Some(&crypto), options).unwrap();
+        let header = Header::parse(&content).unwrap().unwrap();
+        assert!(header.is_encrypted() && header.is_compressed());
+        assert_eq!(header.engine, Engine::Cbor);
+        assert_eq!(decode(&content, Some(&crypto)).unwrap(), (records.clone(), CURRENT_VERSION));
+        assert!(decode(&content, None).is_err());
+
+        // 文件头记录加密算法，配置的算法不同也能读取
+        let siv = Crypto::with_cipher(&key, Cipher::Aes256GcmSiv).unwrap();
+        let content = encode(&records, Some(&siv), options).unwrap();
+        assert_eq!(Header::parse(&content).unwrap().unwrap().cipher(), Cipher::Aes256GcmSiv);
+        assert_eq!(decode(&content, Some(&crypto)).unwrap(), (records.clone(), CURRENT_VERSION));
+
+        let json = encode(&records, None, FileOptions { engine: Engine::Json, ..Default::default() }).unwrap();
+        assert!(json.starts_with(b"{"));
+        assert_eq!(decode(&json, None).unwrap(), (records.clone(), CURRENT_VERSION));
+
+        let mut future = encode(&records, None, FileOptions::default()).unwrap();
+        future[8] = 99;
+        assert!(decode(&future, None).is_err());
+    }
+
+    #[test]
+    fn test_salvages_damaged_frames() {
+        let records: Records = (0..3)
+            .map(|n| {
+                let record = Record {
+                    id: n.to_string(),
+                    data: IndexMap::new(),
+                    created_at: n,
+                    updated_at: n,
+                };
+                (record.id.clone(), Arc::new(record))
+            })
+            .collect();
+        let content = encode(&records, None, FileOptions::default()).unwrap();
+        let frame_len = (content.len() - HEADER_LEN) / 3;
+
+        // 第二帧的内容被改动：跳过这一帧
+        let mut damaged = content.clone();
+        damaged[HEADER_LEN + frame_len + FRAME_HEADER_LEN] ^= 0xff;
+        let decoded = decode_salvaging(&damaged, None).unwrap();
+        assert_eq!(decoded.records.len(), 2);
+        assert_eq!(decoded.damage.len(), 1);
+        assert_eq!(decoded.damage[0].offset, frame_len);
+        assert_eq!(decoded.damage[0].len, frame_len);
+        assert!(decode(&damaged, None).is_err());
+
+        // 写入中断留下的不完整末尾
+        let decoded = decode_salvaging(&content[..content.len() - 1], None).unwrap();
+        assert_eq!((decoded.records.len(), decoded.damage.len()), (2, 1));
+    }
+
+    #[test]
+    fn test_chunked_encryption() {
+        let crypto = Crypto::new(&Crypto::generate_key()).unwrap();
+        let records: Records = (0..300)
+            .map(|n| {
+                let record = Record {
+                    id: format!("{:03}", n),
+                    data: IndexMap::from([("text".to_string(), crate::storage::Value::String("x".repeat(1000)))]),
+                    created_at: n,
+                    updated_at: n,
+                };
+                (record.id.clone(), Arc::new(record))
+            })
+            .collect();
+        let content = encode(&records, Some(&crypto), FileOptions::default()).unwrap();
+        let chunk_len = CHUNK_SIZE + CHUNK_OVERHEAD;
+        assert_eq!((content.len() - HEADER_LEN).div_ceil(chunk_len), 5);
+        assert_eq!(decode(&content, Some(&crypto)).unwrap().0.len(), 300);
+
+        // 第二块中的一位被改动：只丢失这一块中的记录
+        let mut damaged = content.clone();
+        damaged[HEADER_LEN + chunk_len + 100] ^= 1;
+        let decoded = decode_salvaging(&damaged, Some(&crypto)).unwrap();
+        assert!(decoded.records.len() > 200 && decoded.records.len() < 300);
+        assert_eq!(decoded.damage.iter().filter(|d| d.reason.contains("第 1 块")).count(), 1);
+
+        // 调换块的顺序、在块边界截断都能发现
+        let mut swapped = content[..HEADER_LEN].to_vec();
+        swapped.extend_from_slice(&content[HEADER_LEN + chunk_len..HEADER_LEN + 2 * chunk_len]);
+        swapped.extend_from_slice(&content[HEADER_LEN..HEADER_LEN + chunk_len]);
+        swapped.extend_from_slice(&content[HEADER_LEN + 2 * chunk_len..]);
+        assert!(decode(&swapped, Some(&crypto)).is_err());
+        let truncated = decode_salvaging(&content[..HEADER_LEN + 2 * chunk_len], Some(&crypto)).unwrap();
+        assert!(truncated.damage.iter().any(|d| d.reason.contains("截断")));
+
+        let other = Crypto::new(&Crypto::generate_key()).unwrap();
+        assert!(matches!(decode_salvaging(&content, Some(&other)), Err(DatabaseError::Encryption(_))));
+    }
+
+    /// 每次最多读出1000字节的读取器
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(1000);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_reads_chunks_from_reader() {
+        let crypto = Crypto::new(&Crypto::generate_key()).unwrap();
+        let records: Records = (0..300u64)
+            .map(|n| {
+                let text: String = (0..200).map(|i| format!("{:x}", n.wrapping_mul(2654435761).wrapping_add(i * 40503) % 65521)).collect();
+                let record = Record {
+                    id: format!("{:03}", n),
+                    data: IndexMap::from([("text".to_string(), crate::storage::Value::String(text))]),
+                    created_at: n,
+                    updated_at: n,
+                };
+                (record.id.clone(), Arc::new(record))
+            })
+            .collect();
+        for (crypto, compress) in [(Some(&crypto), true), (Some(&crypto), false), (None, true)] {
+            let options = FileOptions {
+                compress,
+                ..FileOptions::default()
+            };
+            let content = encode(&records, crypto, options).unwrap();
+            assert!(content.len() > 2 * CHUNK_SIZE);
+            let (read_records, version) = read(Trickle(&content), crypto).unwrap();
+            assert_eq!(version, CURRENT_VERSION);
+            assert_eq!(read_records, records);
+            let decoded = read_salvaging(Trickle(&content), crypto).unwrap();
+            assert!(decoded.damage.is_empty());
+            assert_eq!(decoded.options.compress, compress);
+        }
+
+        // 从读取器读到的损坏与整块解码时相同
+        let content = encode(&records, Some(&crypto), FileOptions::default()).unwrap();
+        let mut damaged = content.clone();
+        damaged[HEADER_LEN + CHUNK_SIZE + CHUNK_OVERHEAD + 100] ^= 1;
+        let streamed = read_salvaging(Trickle(&damaged), Some(&crypto)).unwrap();
+        let whole = decode_salvaging(&damaged, Some(&crypto)).unwrap();
+        assert_eq!(streamed.records, whole.records);
+        assert_eq!(streamed.damage.len(), whole.damage.len());
+    }
+}
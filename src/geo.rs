@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, HashSet};
+
+/// 地球平均半径（米）
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+
+/// geohash使用的base32字符表
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// 索引中保存的geohash精度（约4.8m x 4.8m）
+const INDEX_PRECISION: usize = 9;
+
+/// 计算两点之间的大圆距离（米）
+pub fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let d_phi = (lat2 - lat1).to_radians();
+    let d_lambda = (lon2 - lon1).to_radians();
+
+    let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().min(1.0).asin()
+}
+
+/// 将坐标编码为指定精度的geohash
+pub fn encode(lat: f64, lon: f64, precision: usize) -> String {
+    let (mut lat_range, mut lon_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    let mut bits = 0u8;
+    let mut bit_count = 0;
+    let mut even = true;
+
+    while hash.len() < precision {
+        let (range, value): (&mut (f64, f64), f64) = if even {
+            (&mut lon_range, lon)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        bits <<= 1;
+        if value >= mid {
+            bits |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even = !even;
+
+        bit_count += 1;
+        if bit_count == 5 {
+            hash.push(BASE32[bits as usize] as char);
+            bits = 0;
+            bit_count = 0;
+        }
+    }
+
+    hash
+}
+
+/// 指定精度下单个geohash格子的尺寸（纬度跨度, 经度跨度），单位为度
+fn cell_size(precision: usize) -> (f64, f64) {
+    let total_bits = precision * 5;
+    let lon_bits = total_bits.div_ceil(2);
+    let lat_bits = total_bits / 2;
+    (180.0 / (1u64 << lat_bits) as f64, 360.0 / (1u64 << lon_bits) as f64)
+}
+
+/// 选择格子边长不小于半径的最大精度，返回None表示半径过大无法用格子覆盖
+fn precision_for_radius(lat: f64, radius_m: f64) -> Option<usize> {
+    let meters_per_degree = EARTH_RADIUS_M.to_radians();
+    (1..=INDEX_PRECISION).rev().find(|&p| {
+        let (lat_deg, lon_deg) = cell_size(p);
+        let height = lat_deg * meters_per_degree;
+        let width = lon_deg * meters_per_degree * lat.to_radians().cos().abs();
+        height >= radius_m && width >= radius_m
+    })
+}
+
+/// 空间索引，按geohash前缀组织记录ID
+#[derive(Debug, Default, Clone)]
+pub struct GeoIndex {
+    cells: BTreeMap<String, HashSet<String>>,
+}
+
+impl GeoIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 添加一个点
+    pub fn insert(&mut self, id: &str, lat: f64, lon: f64) {
+        self.cells
+            .entry(encode(lat, lon, INDEX_PRECISION))
+            .or_default()
+            .insert(id.to_string());
+    }
+
+    /// 移除一个点
+    pub fn remove(&mut self, id: &str, lat: f64, lon: f64) {
+        let hash = encode(lat, lon, INDEX_PRECISION);
+        if let Some(ids) = self.cells.get_mut(&hash) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.cells.remove(&hash);
+            }
+        }
+    }
+
+    /// 返回可能落在半径内的候选ID（需再按实际距离过滤）
+    ///
+    /// 半径超出最粗精度格子时返回None，调用方应退回全表扫描。
+    pub fn candidates(&self, lat: f64, lon: f64, radius_m: f64) -> Option<HashSet<String>> {
+        let precision = precision_for_radius(lat, radius_m)?;
+        let (lat_deg, lon_deg) = cell_size(precision);
+
+        let mut prefixes = HashSet::new();
+        for d_lat in [-1.0, 0.0, 1.0] {
+            for d_lon in [-1.0, 0.0, 1.0] {
+                let n_lat = (lat + d_lat * lat_deg).clamp(-90.0, 90.0);
+                let mut n_lon = lon + d_lon * lon_deg;
+                if n_lon >= 180.0 {
+                    n_lon -= 360.0;
+                } else if n_lon < -180.0 {
+                    n_lon += 360.0;
+                }
+                prefixes.insert(encode(n_lat, n_lon, precision));
+            }
+        }
+
+        let mut result = HashSet::new();
+        for prefix in prefixes {
+            for (_, ids) in self
+                .cells
+                .range(prefix.clone()..)
+                .take_while(|(hash, _)| hash.starts_with(&prefix))
+            {
+                result.extend(ids.iter().cloned());
+            }
+        }
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_and_radius() {
+        assert_eq!(encode(57.64911, 10.40744, 11), "u4pruydqqvj");
+
+        // 天安门 -> 故宫北门 约1km
+        let d = haversine_distance(39.9087, 116.3975, 39.9200, 116.3970);
+        assert!((d - 1257.0).abs() < 20.0);
+
+        let mut index = GeoIndex::new();
+        index.insert("a", 39.9087, 116.3975);
+        index.insert("b", 31.2304, 121.4737);
+        let near = index.candidates(39.9200, 116.3970, 5_000.0).unwrap();
+        assert!(near.contains("a"));
+        assert!(!near.contains("b"));
+    }
+}
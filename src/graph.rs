@@ -0,0 +1,228 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+use crate::database::SimpleDB;
+use crate::error::{DatabaseError, Result};
+use crate::storage::{Record, Value};
+
+/// 图中节点的标识：(表名, 记录ID)
+pub type NodeKey = (String, String);
+
+/// 遍历顺序
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Traversal {
+    /// 广度优先，节点按与起点的距离由近到远排列
+    #[default]
+    BreadthFirst,
+    /// 深度优先，节点按先序排列
+    DepthFirst,
+}
+
+/// 遍历到的一条记录
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub table: String,
+    pub record: Arc<Record>,
+    /// 与起点之间最少的边数
+    pub depth: usize,
+}
+
+impl Node {
+    fn key(&self) -> NodeKey {
+        (self.table.clone(), self.record.id.clone())
+    }
+}
+
+/// 由边字段连接的两条记录
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Edge {
+    pub from: NodeKey,
+    pub to: NodeKey,
+}
+
+/// 从起点可达的子图
+#[derive(Debug, Clone, Default)]
+pub struct Subgraph {
+    /// 按遍历顺序排列，第一个是起点
+    pub nodes: Vec<Node>,
+    /// 节点之间的边，包括指向已访问节点的边（环）
+    pub edges: Vec<Edge>,
+}
+
+impl Subgraph {
+    pub fn contains(&self, table: &str, id: &str) -> bool {
+        self.nodes.iter().any(|n| n.table == table && n.record.id == id)
+    }
+}
+
+/// 边字段值指向的记录：引用指向其所在的表，字符串视为同一张表中的记录ID，数组中的每个元素各是一条边
+fn targets(table: &str, value: &Value, out: &mut Vec<NodeKey>) {
+    match value {
+        Value::Ref { table, id } => out.push((table.clone(), id.clone())),
+        Value::String(id) => out.push((table.to_string(), id.clone())),
+        Value::Array(items) => items.iter().for_each(|item| targets(table, item, out)),
+        _ => {}
+    }
+}
+
+struct Walker<'a> {
+    db: &'a SimpleDB,
+    edge_field: &'a str,
+    max_depth: usize,
+    graph: Subgraph,
+    positions: HashMap<NodeKey, usize>,
+    seen_edges: HashSet<Edge>,
+}
+
+impl Walker<'_> {
+    /// 节点的边字段指向的记录；已达到最大深度的节点不再展开
+    fn neighbours(&self, index: usize) -> Vec<NodeKey> {
+        let node = &self.graph.nodes[index];
+        let mut keys = Vec::new();
+        if node.depth < self.max_depth {
+            if let Some(value) = node.record.data.get(self.edge_field) {
+                targets(&node.table, value, &mut keys);
+            }
+        }
+        keys
+    }
+
+    /// 记录从`from`到`key`的边，返回需要继续展开的节点：新发现的节点，或经由更短路径到达的已有节点
+    fn visit(&mut self, from: usize, key: NodeKey) -> Result<Option<usize>> {
+        let depth = self.graph.nodes[from].depth + 1;
+        let (target, expand) = match self.positions.get(&key) {
+            Some(&target) => {
+                let shorter = self.graph.nodes[target].depth > depth;
+                if shorter {
+                    self.graph.nodes[target].depth = depth;
+                }
+                (target, shorter)
+            }
+            None => {
+                // 指向不存在的记录的边忽略
+                let Some(record) = self.db.find_by_id(&key.0, &key.1)? else {
+                    return Ok(None);
+                };
+                self.graph.nodes.push(Node {
+                    table: key.0.clone(),
+                    record,
+                    depth,
+                });
+                self.positions.insert(key.clone(), self.graph.nodes.len() - 1);
+                (self.graph.nodes.len() - 1, true)
+            }
+        };
+        let edge = Edge {
+            from: self.graph.nodes[from].key(),
+            to: key,
+        };
+        if self.seen_edges.insert(edge.clone()) {
+            self.graph.edges.push(edge);
+        }
+        Ok(expand.then_some(target))
+    }
+
+    fn breadth_first(&mut self) -> Result<()> {
+        let mut queue = VecDeque::from([0]);
+        while let Some(index) = queue.pop_front() {
+            for key in self.neighbours(index) {
+                queue.extend(self.visit(index, key)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn depth_first(&mut self, index: usize) -> Result<()> {
+        for key in self.neighbours(index) {
+            if let Some(next) = self.visit(index, key)? {
+                self.depth_first(next)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 从`table`表中ID为`id`的记录出发，沿`edge_field`字段最多走`max_depth`步
+pub(crate) fn traverse(
+    db: &SimpleDB,
+    table: &str,
+    id: &str,
+    edge_field: &str,
+    max_depth: usize,
+    order: Traversal,
+) -> Result<Subgraph> {
+    let record = db
+        .find_by_id(table, id)?
+        .ok_or_else(|| DatabaseError::RecordNotFound(id.to_string()))?;
+    let mut walker = Walker {
+        db,
+        edge_field,
+        max_depth,
+        graph: Subgraph::default(),
+        positions: HashMap::from([((table.to_string(), id.to_string()), 0)]),
+        seen_edges: HashSet::new(),
+    };
+    walker.graph.nodes.push(Node {
+        table: table.to_string(),
+        record,
+        depth: 0,
+    });
+
+    match order {
+        Traversal::BreadthFirst => walker.breadth_first()?,
+        Traversal::DepthFirst => walker.depth_first(0)?,
+    }
+    Ok(walker.graph)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::open;
+    use indexmap::IndexMap;
+
+    #[test]
+    fn test_traverse_org_chart() {
+        let (_dir, db) = open("graph");
+        let person = |name: &str, reports: Vec<Value>| {
+            IndexMap::from([
+                ("name".to_string(), Value::String(name.to_string())),
+                ("reports".to_string(), Value::Array(reports)),
+            ])
+        };
+        // ceo -> (cto, cfo)，cto -> dev -> ceo 形成环，另有一条指向不存在记录的边
+        let dev = db.insert("people", person("dev", vec![])).unwrap();
+        let cto = db.insert("people", person("cto", vec![Value::String(dev.clone())])).unwrap();
+        let cfo = db.insert("people", person("cfo", vec![Value::reference("people", "gone")])).unwrap();
+        let ceo = db
+            .insert("people", person("ceo", vec![Value::String(cto.clone()), Value::reference("people", &cfo)]))
+            .unwrap();
+        db.update("people", &dev, person("dev", vec![Value::String(ceo.clone())])).unwrap();
+
+        let names = |graph: &Subgraph| -> Vec<(String, usize)> {
+            graph
+                .nodes
+                .iter()
+                .map(|n| (n.record.data["name"].as_string().unwrap().to_string(), n.depth))
+                .collect()
+        };
+        let owned = |v: &[(&str, usize)]| v.iter().map(|(n, d)| (n.to_string(), *d)).collect::<Vec<_>>();
+
+        let graph = db.traverse("people", &ceo, "reports", 1).unwrap();
+        assert_eq!(names(&graph), owned(&[("ceo", 0), ("cto", 1), ("cfo", 1)]));
+        assert_eq!(graph.edges.len(), 2);
+
+        let graph = db.traverse("people", &ceo, "reports", 10).unwrap();
+        assert_eq!(names(&graph), owned(&[("ceo", 0), ("cto", 1), ("cfo", 1), ("dev", 2)]));
+        // 回到起点的边保留，但不会重复访问
+        let people = |id: &str| ("people".to_string(), id.to_string());
+        assert!(graph.edges.contains(&Edge { from: people(&dev), to: people(&ceo) }));
+        assert_eq!(graph.edges.len(), 4);
+
+        let graph = db.traverse_with("people", &ceo, "reports", 10, Traversal::DepthFirst).unwrap();
+        assert_eq!(names(&graph), owned(&[("ceo", 0), ("cto", 1), ("dev", 2), ("cfo", 1)]));
+        assert!(db.traverse("people", "missing", "reports", 1).is_err());
+
+        drop(db);
+    }
+}
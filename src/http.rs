@@ -0,0 +1,526 @@
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use std::collections::HashMap;
+use std::io::Write;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// 请求头的最大长度
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// 默认的请求体最大长度，流式导入不受限制
+pub const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// 响应体达到该大小才进行压缩
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// 解析后的HTTP请求
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    /// 不含查询字符串的路径
+    pub path: String,
+    /// 解码后的查询字符串参数
+    pub query: HashMap<String, String>,
+    /// 请求头，键统一为小写
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// 按名称（不区分大小写）获取请求头
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+
+    /// 获取查询字符串参数
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(String::as_str)
+    }
+
+    /// 以文本形式获取请求体
+    pub fn body_str(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
+
+    /// 解析请求行和请求头
+    fn parse_head(head: &str) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+        let mut parts = lines.next()?.split_whitespace();
+        let method = parts.next()?.to_string();
+        let target = parts.next()?;
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, parse_query_string(query)),
+            None => (target, HashMap::new()),
+        };
+
+        let headers = lines
+            .filter_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                Some((name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            })
+            .collect();
+
+        Some(Self {
+            method,
+            path: path.to_string(),
+            query,
+            headers,
+            body: Vec::new(),
+        })
+    }
+}
+
+/// 从连接中读取一个完整的HTTP请求，按`Content-Length`或分块传输读取请求体
+///
+/// 连接在收到任何数据前关闭时返回`Ok(None)`；请求体超过`DEFAULT_MAX_BODY_SIZE`时返回错误，见[`is_too_large`]。
+pub async fn read_request<R: AsyncRead + Unpin>(stream: &mut R) -> std::io::Result<Option<HttpRequest>> {
+    let Some((mut request, buffered)) = read_head(stream).await? else {
+        return Ok(None);
+    };
+    request.body = BodyReader::new(&request, stream, buffered).read_to_end(DEFAULT_MAX_BODY_SIZE).await?;
+    Ok(Some(request))
+}
+
+/// 只读取请求行和请求头，同时返回已读入缓冲区的请求体开头部分
+///
+/// 请求体随后可以用[`BodyReader`]边接收边处理。
+pub async fn read_head<R: AsyncRead + Unpin>(stream: &mut R) -> std::io::Result<Option<(HttpRequest, Vec<u8>)>> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    let head_end = loop {
+        if let Some(pos) = find_subslice(&buffer, b"\r\n\r\n") {
+            break pos;
+        }
+        if buffer.len() > MAX_HEAD_SIZE {
+            return Err(invalid_data("请求头过长"));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return if buffer.is_empty() {
+                Ok(None)
+            } else {
+                Err(invalid_data("请求不完整"))
+            };
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..head_end]).into_owned();
+    let request = HttpRequest::parse_head(&head).ok_or_else(|| invalid_data("无效的请求格式"))?;
+    let body = buffer.split_off(head_end + 4);
+    Ok(Some((request, body)))
+}
+
+/// 请求体的分帧方式
+#[derive(Debug, Clone, Copy)]
+enum Framing {
+    /// 剩余的`Content-Length`字节数
+    Length(usize),
+    /// 分块传输，当前分块剩余的字节数
+    Chunked(usize),
+    Done,
+}
+
+/// 增量读取请求体，支持`Content-Length`和`Transfer-Encoding: chunked`
+pub struct BodyReader<'a, R> {
+    stream: &'a mut R,
+    buffer: Vec<u8>,
+    framing: Framing,
+}
+
+impl<'a, R: AsyncRead + Unpin> BodyReader<'a, R> {
+    /// `buffered`是读取请求头时已经收到的请求体字节
+    pub fn new(request: &HttpRequest, stream: &'a mut R, buffered: Vec<u8>) -> Self {
+        let chunked = request
+            .header("transfer-encoding")
+            .is_some_and(|v| v.to_ascii_lowercase().contains("chunked"));
+        let framing = if chunked {
+            Framing::Chunked(0)
+        } else {
+            let length = request
+                .header("content-length")
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(0);
+            Framing::Length(length)
+        };
+        Self {
+            stream,
+            buffer: buffered,
+            framing,
+        }
+    }
+
+    /// 读取下一段请求体数据，请求体结束时返回`None`
+    pub async fn next_chunk(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.framing {
+                Framing::Done | Framing::Length(0) => {
+                    self.framing = Framing::Done;
+                    return Ok(None);
+                }
+                Framing::Length(remaining) => {
+                    let data = self.take_up_to(remaining).await?;
+                    self.framing = Framing::Length(remaining - data.len());
+                    return Ok(Some(data));
+                }
+                Framing::Chunked(0) => {
+                    // 分块大小行；分块数据之后的CRLF会被读成空行
+                    let line_end = loop {
+                        if let Some(pos) = find_subslice(&self.buffer, b"\r\n") {
+                            break pos;
+                        }
+                        if self.buffer.len() > MAX_HEAD_SIZE {
+                            return Err(invalid_data("无效的分块大小"));
+                        }
+                        self.fill().await?;
+                    };
+                    let line: Vec<u8> = self.buffer.drain(..line_end + 2).collect();
+                    let line = String::from_utf8_lossy(&line[..line_end]);
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    if size.is_empty() {
+                        continue;
+                    }
+                    let size = usize::from_str_radix(size, 16).map_err(|_| invalid_data("无效的分块大小"))?;
+                    self.framing = if size == 0 { Framing::Done } else { Framing::Chunked(size) };
+                }
+                Framing::Chunked(remaining) => {
+                    let data = self.take_up_to(remaining).await?;
+                    self.framing = Framing::Chunked(remaining - data.len());
+                    return Ok(Some(data));
+                }
+            }
+        }
+    }
+
+    /// 读取完整的请求体，超过`limit`字节时停止读取并返回错误，见[`is_too_large`]
+    ///
+    /// `Content-Length`已经超过`limit`时不读取任何请求体。
+    pub async fn read_to_end(mut self, limit: usize) -> std::io::Result<Vec<u8>> {
+        let too_large = || std::io::Error::new(std::io::ErrorKind::FileTooLarge, format!("请求体超过 {} 字节", limit));
+        if matches!(self.framing, Framing::Length(length) if length > limit) {
+            return Err(too_large());
+        }
+        let mut body = Vec::new();
+        while let Some(data) = self.next_chunk().await? {
+            if body.len() + data.len() > limit {
+                return Err(too_large());
+            }
+            body.extend(data);
+        }
+        Ok(body)
+    }
+
+    async fn take_up_to(&mut self, limit: usize) -> std::io::Result<Vec<u8>> {
+        if self.buffer.is_empty() {
+            self.fill().await?;
+        }
+        let n = limit.min(self.buffer.len());
+        Ok(self.buffer.drain(..n).collect())
+    }
+
+    async fn fill(&mut self) -> std::io::Result<()> {
+        let mut chunk = [0u8; 8192];
+        let n = self.stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(invalid_data("请求体不完整"));
+        }
+        self.buffer.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+}
+
+/// 读取请求体的错误是否因为请求体过大，应以413回应
+pub fn is_too_large(error: &std::io::Error) -> bool {
+    error.kind() == std::io::ErrorKind::FileTooLarge
+}
+
+/// 解析`a=1&b=2`形式的查询字符串
+fn parse_query_string(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect()
+}
+
+/// `Content-Type`或`Accept`中的一项是否为`media`，忽略参数和大小写
+pub fn is_media_type(value: Option<&str>, media: &str) -> bool {
+    value.is_some_and(|value| value.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(media))
+}
+
+/// URL百分号编码，只保留字母、数字和`-_.~`
+pub fn percent_encode(input: &str) -> String {
+    input
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// URL百分号解码，`+`解码为空格
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => output.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(byte) => {
+                        output.push(byte);
+                        i += 2;
+                    }
+                    None => output.push(b'%'),
+                }
+            }
+            byte => output.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// 状态码对应的原因短语
+pub fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        409 => "Conflict",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        507 => "Insufficient Storage",
+        _ => "",
+    }
+}
+
+/// 判断`If-Match`/`If-None-Match`头是否与给定ETag匹配，`*`匹配任何ETag
+///
+/// 比较时忽略弱校验前缀`W/`。
+pub fn etag_matches(header: &str, etag: &str) -> bool {
+    header.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+/// 响应内容编码
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    /// 根据`Accept-Encoding`选择编码，gzip优先
+    ///
+    /// `q=0`的项表示拒绝该编码；`*`只作用于没有单独列出的编码，`gzip;q=0, *`因此选择deflate。
+    pub fn negotiate(accept_encoding: Option<&str>) -> Self {
+        let codings: Vec<(String, bool)> = accept_encoding
+            .unwrap_or("")
+            .split(',')
+            .filter_map(|item| {
+                let mut params = item.split(';');
+                let name = params.next()?.trim().to_ascii_lowercase();
+                if name.is_empty() {
+                    return None;
+                }
+                let rejected = params.any(|p| {
+                    p.trim()
+                        .strip_prefix("q=")
+                        .and_then(|q| q.trim().parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+                });
+                Some((name, !rejected))
+            })
+            .collect();
+        let accepts = |coding: &str| {
+            let listed = |name: &str| codings.iter().find(|(n, _)| n == name).map(|(_, accepted)| *accepted);
+            listed(coding).or_else(|| listed("*")).unwrap_or(false)
+        };
+
+        if accepts("gzip") {
+            ContentEncoding::Gzip
+        } else if accepts("deflate") {
+            ContentEncoding::Deflate
+        } else {
+            ContentEncoding::Identity
+        }
+    }
+
+    /// `Content-Encoding`响应头的值
+    pub fn header_value(&self) -> Option<&'static str> {
+        match self {
+            ContentEncoding::Identity => None,
+            ContentEncoding::Gzip => Some("gzip"),
+            ContentEncoding::Deflate => Some("deflate"),
+        }
+    }
+
+    /// 一次性压缩完整的响应体
+    pub fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut encoder = StreamEncoder::new(*self);
+        let mut output = encoder.write(data)?;
+        output.extend(encoder.finish()?);
+        Ok(output)
+    }
+}
+
+/// 增量压缩器，用于分块传输的流式响应
+pub enum StreamEncoder {
+    Identity,
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl StreamEncoder {
+    pub fn new(encoding: ContentEncoding) -> Self {
+        match encoding {
+            ContentEncoding::Identity => StreamEncoder::Identity,
+            ContentEncoding::Gzip => StreamEncoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            ContentEncoding::Deflate => StreamEncoder::Deflate(ZlibEncoder::new(Vec::new(), Compression::default())),
+        }
+    }
+
+    /// 写入数据，返回当前已可输出的压缩字节
+    pub fn write(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            StreamEncoder::Identity => Ok(data.to_vec()),
+            StreamEncoder::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            StreamEncoder::Deflate(encoder) => {
+                encoder.write_all(data)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    /// 结束压缩，返回剩余的字节
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            StreamEncoder::Identity => Ok(Vec::new()),
+            StreamEncoder::Gzip(encoder) => encoder.finish(),
+            StreamEncoder::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{open, serve};
+
+    #[tokio::test]
+    async fn test_chunked_request_body() {
+        let raw: &[u8] = b"POST /api/tables/t/import HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            7\r\n{\"a\":1}\r\n8\r\n\n{\"a\":2}\r\n0\r\n\r\n";
+        let mut stream = raw;
+        let request = read_request(&mut stream).await.unwrap().unwrap();
+        assert_eq!(request.body_str(), "{\"a\":1}\n{\"a\":2}");
+    }
+
+    #[tokio::test]
+    async fn test_body_size_limit() {
+        let raw: &[u8] = b"POST /api/insert HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789";
+        let mut stream = raw;
+        let (request, buffered) = read_head(&mut stream).await.unwrap().unwrap();
+        let error = BodyReader::new(&request, &mut stream, buffered).read_to_end(9).await.unwrap_err();
+        assert!(is_too_large(&error));
+
+        // 分块传输没有预先声明长度，读到超过限制时停止
+        let raw: &[u8] = b"POST /api/insert HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n01234\r\n5\r\n56789\r\n0\r\n\r\n";
+        let mut stream = raw;
+        let (request, buffered) = read_head(&mut stream).await.unwrap().unwrap();
+        let error = BodyReader::new(&request, &mut stream, buffered).read_to_end(9).await.unwrap_err();
+        assert!(is_too_large(&error));
+    }
+
+    #[test]
+    fn test_accept_encoding() {
+        let negotiate = |header: &str| ContentEncoding::negotiate(Some(header));
+        assert_eq!(negotiate("gzip, deflate"), ContentEncoding::Gzip);
+        assert_eq!(negotiate("gzip;q=0, *"), ContentEncoding::Deflate);
+        assert_eq!(negotiate("gzip;q=0, deflate;q=0, *"), ContentEncoding::Identity);
+        assert_eq!(negotiate("*;q=0, deflate"), ContentEncoding::Deflate);
+        assert_eq!(negotiate("*"), ContentEncoding::Gzip);
+        assert_eq!(negotiate("identity"), ContentEncoding::Identity);
+        assert_eq!(ContentEncoding::negotiate(None), ContentEncoding::Identity);
+    }
+
+    #[test]
+    fn test_query_string() {
+        let request = HttpRequest::parse_head("GET /api/tables/t/export?format=csv&query=%7B%22a%22%3A1%7D&x=a+b HTTP/1.1").unwrap();
+        assert_eq!(request.path, "/api/tables/t/export");
+        assert_eq!(request.query_param("format"), Some("csv"));
+        assert_eq!(request.query_param("query"), Some("{\"a\":1}"));
+        assert_eq!(request.query_param("x"), Some("a b"));
+        assert_eq!(percent_decode(&percent_encode("{\"a\": \"张三\"}")), "{\"a\": \"张三\"}");
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_negotiation() {
+        use std::sync::Arc;
+
+        let (_dir, db) = open("ndjson");
+        let db = Arc::new(db);
+        let (address, serving) = serve(Arc::clone(&db));
+        let request = |head: &'static str, body: &'static str| async move {
+            let response = crate::testing::request(address, head, body).await;
+            response.split_once("\r\n\r\n").map(|(head, body)| (head.to_string(), body.to_string())).unwrap()
+        };
+
+        // 逐行插入，逐行返回ID或失败原因
+        let (head, body) = request(
+            "POST /api/insert?table=users HTTP/1.1\r\nContent-Type: application/x-ndjson\r\nAccept: application/x-ndjson",
+            "{\"name\":\"张三\"}\nnot json\n{\"name\":\"李四\"}\n",
+        )
+        .await;
+        assert!(head.contains("Content-Type: application/x-ndjson"), "{}", head);
+        let lines: Vec<serde_json::Value> = body.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0]["id"].is_string() && lines[1]["error"].is_string() && lines[2]["id"].is_string());
+        assert_eq!(db.count("users").unwrap(), 2);
+
+        // 不接受NDJSON时返回汇总
+        let (_, body) = request("POST /api/insert?table=users HTTP/1.1\r\nContent-Type: application/x-ndjson", "{\"name\":\"王五\"}").await;
+        assert!(body.contains(r#""inserted":1"#), "{}", body);
+
+        let (head, body) = request(
+            "GET /api/find HTTP/1.1\r\nAccept: application/json, application/x-ndjson",
+            r#"{"table":"users","limit":2}"#,
+        )
+        .await;
+        assert!(head.contains("X-Next-Token: "), "{}", head);
+        assert!(head.contains("X-Total-Count: 3"), "{}", head);
+        // 分块传输的每一块是一行记录
+        assert_eq!(body.matches(r#""name":"#).count(), 2, "{}", body);
+        serving.abort();
+        let _ = serving.await;
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::lock::Recover;
+
+/// 幂等键默认的保留时长
+pub const DEFAULT_IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// 幂等键对应的请求状态
+enum Entry<T> {
+    /// 首次请求仍在处理
+    Pending { fingerprint: u64, expires_at: Instant },
+    /// 已完成，保存原始结果
+    Completed { fingerprint: u64, expires_at: Instant, outcome: T },
+}
+
+impl<T> Entry<T> {
+    fn expires_at(&self) -> Instant {
+        match self {
+            Entry::Pending { expires_at, .. } | Entry::Completed { expires_at, .. } => *expires_at,
+        }
+    }
+
+    fn fingerprint(&self) -> u64 {
+        match self {
+            Entry::Pending { fingerprint, .. } | Entry::Completed { fingerprint, .. } => *fingerprint,
+        }
+    }
+}
+
+/// 开始处理带幂等键的请求时的判定结果
+pub enum Attempt<'a, T: Clone> {
+    /// 首次出现的键，处理完成后调用`Reservation::complete`保存结果
+    New(Reservation<'a, T>),
+    /// 重试已完成的请求，返回原始结果
+    Replay(T),
+    /// 相同的键仍在处理中
+    InProgress,
+    /// 相同的键被用于不同的请求
+    Mismatch,
+}
+
+/// 记录带幂等键的写请求结果，在保留时长内对重试返回原始结果
+pub struct IdempotencyCache<T> {
+    window: Duration,
+    entries: Mutex<HashMap<String, Entry<T>>>,
+}
+
+impl<T: Clone> IdempotencyCache<T> {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 登记一次请求，`fingerprint`用于识别同一个键是否对应同一个请求
+    pub fn begin(&self, key: &str, fingerprint: u64) -> Attempt<'_, T> {
+        let mut entries = self.entries.lock().recover();
+        let now = Instant::now();
+        entries.retain(|_, entry| entry.expires_at() > now);
+
+        match entries.get(key) {
+            Some(entry) if entry.fingerprint() != fingerprint => Attempt::Mismatch,
+            Some(Entry::Pending { .. }) => Attempt::InProgress,
+            Some(Entry::Completed { outcome, .. }) => Attempt::Replay(outcome.clone()),
+            None => {
+                entries.insert(
+                    key.to_string(),
+                    Entry::Pending {
+                        fingerprint,
+                        expires_at: now + self.window,
+                    },
+                );
+                Attempt::New(Reservation {
+                    cache: self,
+                    key: key.to_string(),
+                    fingerprint,
+                    completed: false,
+                })
+            }
+        }
+    }
+}
+
+/// 首次请求对幂等键的占用
+///
+/// 没有调用`complete`就被丢弃时（处理时panic或连接中断）删除处理中的记录，重试会重新执行请求，
+/// 而不是在保留时长内一直得到“正在处理中”。
+pub struct Reservation<'a, T: Clone> {
+    cache: &'a IdempotencyCache<T>,
+    key: String,
+    fingerprint: u64,
+    completed: bool,
+}
+
+impl<T: Clone> Reservation<'_, T> {
+    /// 保存请求的处理结果
+    pub fn complete(mut self, outcome: T) {
+        self.completed = true;
+        self.cache.entries.lock().recover().insert(
+            std::mem::take(&mut self.key),
+            Entry::Completed {
+                fingerprint: self.fingerprint,
+                expires_at: Instant::now() + self.cache.window,
+                outcome,
+            },
+        );
+    }
+}
+
+impl<T: Clone> Drop for Reservation<'_, T> {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        let mut entries = self.cache.entries.lock().recover();
+        if matches!(entries.get(&self.key), Some(Entry::Pending { fingerprint, .. }) if *fingerprint == self.fingerprint) {
+            entries.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_and_mismatch() {
+        let cache = IdempotencyCache::new(DEFAULT_IDEMPOTENCY_WINDOW);
+        let Attempt::New(reservation) = cache.begin("k", 1) else {
+            panic!("首次请求应为New");
+        };
+        assert!(matches!(cache.begin("k", 1), Attempt::InProgress));
+        reservation.complete("created");
+        assert!(matches!(cache.begin("k", 1), Attempt::Replay("created")));
+        assert!(matches!(cache.begin("k", 2), Attempt::Mismatch));
+    }
+
+    #[test]
+    fn test_abandoned_request() {
+        let cache = IdempotencyCache::<&str>::new(DEFAULT_IDEMPOTENCY_WINDOW);
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _reservation = cache.begin("k", 1);
+            panic!("处理请求时panic");
+        }));
+        assert!(panicked.is_err());
+        // 处理中的记录已删除，重试重新执行
+        assert!(matches!(cache.begin("k", 1), Attempt::New(_)));
+    }
+}
@@ -0,0 +1,241 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+use crate::query::Condition;
+use crate::storage::{Record, Value};
+
+/// 索引类型
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexKind {
+    /// 等值索引
+    #[default]
+    Hash,
+    /// 地理位置索引
+    Geo,
+    /// 单字段或多字段唯一索引
+    Unique,
+}
+
+/// 索引定义
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IndexInfo {
+    /// 索引的字段，多字段唯一索引为逗号连接的字段名
+    pub field: String,
+    pub kind: IndexKind,
+    /// 不同键的数量，仅等值索引和唯一索引提供
+    pub distinct_keys: Option<usize>,
+    /// 是否索引了数组的元素
+    pub multikey: bool,
+    /// 部分索引的过滤条件，为空时索引所有记录
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub filter: Vec<Condition>,
+}
+
+/// 后台创建中的索引的进度
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IndexBuildProgress {
+    pub field: String,
+    /// 已处理的快照记录数
+    pub processed: usize,
+    /// 快照中的记录总数
+    pub total: usize,
+}
+
+/// 字段等值索引，按值的序列化字节组织记录ID
+///
+/// 与`Value`的相等语义一致：`Int(1)`与`Float(1.0)`视为不同的键。
+/// 数组值除了整体作为一个键，每个元素也各作为一个键（多键索引），用于查找包含某个元素的记录。
+#[derive(Debug, Default, Clone)]
+pub struct FieldIndex {
+    entries: HashMap<Vec<u8>, HashSet<String>>,
+    multikey: bool,
+    /// 部分索引只收录满足全部条件的记录
+    filter: Vec<Condition>,
+}
+
+impl FieldIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 计算索引键，`-0.0`与`0.0`、不同位模式的NaN各对应同一个键
+    pub fn key(value: &Value) -> Vec<u8> {
+        bincode::serialize(&normalized(value)).unwrap_or_default()
+    }
+
+    /// 值本身及数组的每个元素对应的键
+    fn keys(value: &Value) -> Vec<Vec<u8>> {
+        let mut keys = vec![Self::key(value)];
+        if let Value::Array(items) = value {
+            keys.extend(items.iter().map(Self::key));
+        }
+        keys
+    }
+
+    pub fn insert(&mut self, id: &str, value: &Value) {
+        if matches!(value, Value::Array(_)) {
+            self.multikey = true;
+        }
+        for key in Self::keys(value) {
+            self.entries.entry(key).or_default().insert(id.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, id: &str, value: &Value) {
+        for key in Self::keys(value) {
+            if let Some(ids) = self.entries.get_mut(&key) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.entries.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// 只收录满足`filter`中全部条件的记录的部分索引
+    pub fn partial(filter: Vec<Condition>) -> Self {
+        Self {
+            filter,
+            ..Self::default()
+        }
+    }
+
+    pub fn filter(&self) -> &[Condition] {
+        &self.filter
+    }
+
+    /// 记录是否应被收录
+    pub fn covers(&self, record: &Record) -> bool {
+        self.filter.iter().all(|c| c.matches(record))
+    }
+
+    /// 查询条件是否包含部分索引的全部过滤条件，只有这样索引中才有所有可能的结果
+    pub fn usable_for(&self, conditions: &[Condition]) -> bool {
+        self.filter.iter().all(|f| conditions.contains(f))
+    }
+
+    /// 移除所有键，保留过滤条件
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.multikey = false;
+    }
+
+    /// 是否索引过数组值
+    pub fn is_multikey(&self) -> bool {
+        self.multikey
+    }
+
+    /// 查找等于给定值或数组中包含给定值的记录ID
+    pub fn lookup(&self, value: &Value) -> Option<&HashSet<String>> {
+        self.entries.get(&Self::key(value))
+    }
+
+    /// 不同键的数量
+    pub fn cardinality(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// 收录记录在`field`上的值，部分索引跳过不满足过滤条件的记录
+    pub fn add_record(&mut self, field: &str, record: &Record) {
+        if let Some(value) = record.data.get(field).filter(|_| self.covers(record)) {
+            self.insert(&record.id, value);
+        }
+    }
+
+    /// 移除由`add_record`收录的记录
+    pub fn remove_record(&mut self, field: &str, record: &Record) {
+        if let Some(value) = record.data.get(field).filter(|_| self.covers(record)) {
+            self.remove(&record.id, value);
+        }
+    }
+
+    /// 由现有记录构建索引，`filter`不为空时只收录满足条件的记录
+    pub fn build<'a>(field: &str, filter: Vec<Condition>, records: impl Iterator<Item = &'a Record>) -> Self {
+        let mut index = Self::partial(filter);
+        for record in records {
+            index.add_record(field, record);
+        }
+        index
+    }
+}
+
+/// 与`==`一致的索引键用值：`-0.0`换成`0.0`，NaN换成标准的NaN
+fn normalized(value: &Value) -> Cow<'_, Value> {
+    match value {
+        Value::Float(f) if *f == 0.0 => Cow::Owned(Value::Float(0.0)),
+        Value::Float(f) if f.is_nan() => Cow::Owned(Value::Float(f64::NAN)),
+        _ => Cow::Borrowed(value),
+    }
+}
+
+/// 多字段唯一索引：同一组字段值最多对应一条记录
+///
+/// 缺少任一字段或其值为null的记录不受约束，与SQL中NULL不参与唯一性比较一致。
+#[derive(Debug, Default, Clone)]
+pub struct UniqueIndex {
+    fields: Vec<String>,
+    entries: HashMap<Vec<u8>, String>,
+}
+
+impl UniqueIndex {
+    pub fn new(fields: Vec<String>) -> Self {
+        Self {
+            fields,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// 索引名称：以逗号连接的字段名
+    pub fn name(&self) -> String {
+        self.fields.join(",")
+    }
+
+    /// 记录的组合键，有字段缺失或为null时返回None
+    fn key(&self, record: &Record) -> Option<Vec<u8>> {
+        let values: Option<Vec<Cow<Value>>> = self
+            .fields
+            .iter()
+            .map(|f| record.data.get(f).filter(|v| **v != Value::Null).map(normalized))
+            .collect();
+        bincode::serialize(&values?).ok()
+    }
+
+    /// 检查记录能否加入索引，组合键已被其他记录占用时返回该记录的ID
+    pub fn conflict(&self, record: &Record) -> Option<&str> {
+        let owner = self.entries.get(&self.key(record)?)?;
+        (owner != &record.id).then_some(owner.as_str())
+    }
+
+    pub fn insert(&mut self, record: &Record) {
+        if let Some(key) = self.key(record) {
+            self.entries.insert(key, record.id.clone());
+        }
+    }
+
+    pub fn remove(&mut self, record: &Record) {
+        if let Some(key) = self.key(record) {
+            if self.entries.get(&key) == Some(&record.id) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// 不同组合键的数量
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::crypto::Crypto;
+use crate::error::Result;
+use crate::lock::Recover;
+use crate::statefile;
+
+/// 密钥文件名，扩展名不是`.db`，不会被当作表加载
+pub const KEYRING_FILE: &str = "subject_keys.keys";
+
+/// 密钥文件开头的魔数
+pub const KEYRING_MAGIC: &[u8; 8] = b"SDBKEYR1";
+
+/// 按主体（如用户ID）保存的数据密钥
+///
+/// 每个数据密钥用主密钥加密后保存，新增和销毁密钥时立即持久地写回磁盘（见`statefile`）。
+/// 销毁某个主体的密钥后，用它加密的字段就无法再解密，不需要改写任何表文件。
+/// 注意：销毁前的备份中仍保留着旧的密钥文件。
+pub struct Keyring {
+    path: PathBuf,
+    master: Crypto,
+    /// 主体ID -> 被主密钥加密的数据密钥
+    wrapped: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl Keyring {
+    /// 打开数据目录中的密钥文件，不存在时为空
+    pub fn open(data_dir: &Path, master: Crypto) -> Result<Self> {
+        let path = data_dir.join(KEYRING_FILE);
+        // 数据密钥已经被主密钥加密，文件本身不再加密
+        let wrapped = statefile::load(&path, KEYRING_MAGIC, None)?.unwrap_or_default();
+        Ok(Self {
+            path,
+            master,
+            wrapped: Mutex::new(wrapped),
+        })
+    }
+
+    /// 内容是否是密钥文件，只检查开头的魔数
+    pub fn is_keyring(content: &[u8]) -> bool {
+        content.starts_with(KEYRING_MAGIC)
+    }
+
+    /// 取主体的数据密钥，不存在时生成一个
+    pub fn key_for(&self, subject: &str) -> Result<Crypto> {
+        let mut wrapped = self.wrapped.lock().recover();
+        if let Some(key) = wrapped.get(subject) {
+            return Crypto::new(&self.master.decrypt(key)?);
+        }
+        let key = Crypto::generate_key();
+        wrapped.insert(subject.to_string(), self.master.encrypt(&key)?);
+        // 密钥落盘之后才能用它加密数据，否则崩溃后数据将无法解密
+        self.persist(&wrapped)?;
+        Crypto::new(&key)
+    }
+
+    /// 取已有的数据密钥，主体已被遗忘或从未加密过数据时返回None
+    pub fn existing_key(&self, subject: &str) -> Result<Option<Crypto>> {
+        match self.wrapped.lock().recover().get(subject) {
+            Some(key) => Ok(Some(Crypto::new(&self.master.decrypt(key)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// 销毁主体的数据密钥，返回密钥是否存在
+    pub fn forget(&self, subject: &str) -> Result<bool> {
+        let mut wrapped = self.wrapped.lock().recover();
+        if wrapped.remove(subject).is_none() {
+            return Ok(false);
+        }
+        self.persist(&wrapped)?;
+        Ok(true)
+    }
+
+    fn persist(&self, wrapped: &HashMap<String, Vec<u8>>) -> Result<()> {
+        statefile::store(&self.path, KEYRING_MAGIC, wrapped, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_forget_survives_reopen() {
+        let dir = temp_dir("keyring");
+        let master = Crypto::new(&Crypto::generate_key()).unwrap();
+
+        let keyring = Keyring::open(dir.path(), master.clone()).unwrap();
+        let sealed = keyring.key_for("alice").unwrap().encrypt(b"secret").unwrap();
+        keyring.key_for("bob").unwrap();
+
+        let reopened = Keyring::open(dir.path(), master.clone()).unwrap();
+        let key = reopened.existing_key("alice").unwrap().unwrap();
+        assert!(Keyring::is_keyring(&std::fs::read(dir.path().join(KEYRING_FILE)).unwrap()));
+        assert!(!dir.path().join(format!("{}.tmp", KEYRING_FILE)).exists());
+        assert_eq!(key.decrypt(&sealed).unwrap(), b"secret");
+
+        assert!(reopened.forget("alice").unwrap());
+        assert!(!reopened.forget("alice").unwrap());
+        let reopened = Keyring::open(dir.path(), master).unwrap();
+        assert!(reopened.existing_key("alice").unwrap().is_none());
+        assert!(reopened.existing_key("bob").unwrap().is_some());
+
+    }
+}
@@ -1,12 +1,102 @@
 pub mod storage;
 pub mod crypto;
 pub mod database;
+pub mod datetime;
 pub mod api;
 pub mod error;
+pub mod audit;
+pub mod backup;
+pub mod blob;
+pub mod bundle;
+pub mod cache;
+pub mod cdc;
+pub mod changes;
+pub mod cluster;
+pub mod codec;
+pub mod collation;
+pub mod compress;
+pub mod counters;
+pub mod dirlock;
+pub mod format;
+pub mod geo;
+pub mod graph;
+pub mod http;
+pub mod idempotency;
+pub mod index;
+pub mod keyring;
+pub mod lock;
+pub mod manifest;
+pub mod mapping;
+pub mod meta;
+pub mod output;
+pub mod pgwire;
+pub mod pipeline;
+pub mod plaintext;
+pub mod policy;
+pub mod prepared;
+pub mod projection;
+pub mod query;
+pub mod quota;
+pub mod repair;
+pub mod schedule;
+pub mod schema;
+pub mod security;
+pub mod session;
+pub mod siv;
+pub mod sql;
+pub mod statefile;
+pub mod stats;
+pub mod sync;
+pub mod system;
+pub mod telemetry;
+#[cfg(test)]
+mod testing;
+pub mod timeseries;
+pub mod transaction;
+pub mod update;
+pub mod validate;
+#[cfg(feature = "admin-ui")]
+pub mod ui;
+#[cfg(unix)]
+pub mod upgrade;
+pub mod vector;
 
-pub use database::SimpleDB;
+pub use audit::{AccessEntry, AccessLog};
+pub use blob::BlobUsage;
+pub use cdc::{CdcConfig, CdcSink, CdcStatus, CdcTarget};
+pub use cluster::{Cluster, ClusterConfig, ClusterStatus, Role};
+pub use collation::{Collation, Comparator};
+pub use counters::Counters;
+pub use crypto::Cipher;
+pub use database::{DestroyReport, LoadReport, MergeReport, MergeStrategy, SimpleDB};
 pub use error::DatabaseError;
-pub use storage::{Record, Table, Value};
+pub use format::{Damage, Engine};
+pub use graph::{Subgraph, Traversal};
+pub use mapping::{Cast, FieldMapping, FieldRule};
+pub use meta::Cap;
+pub use pipeline::{Accumulator, Pipeline, Stage};
+pub use policy::{claim, Caller, Claims, Policy};
+pub use prepared::{param, PreparedQuery};
+pub use projection::Reducer;
+pub use query::{Condition, Cursor, IndexHint, Operator, Query, QueryPlan, SortOrder, SortStrategy};
+pub use quota::{Quota, QuotaPolicy};
+pub use repair::RepairReport;
+pub use schedule::{Cron, Job, ScheduledJob};
+pub use schema::{FieldType, TableMode};
+pub use security::{FileSecurity, SecurityReport, TableSecurity};
+pub use sql::{Aggregate, OverflowPolicy};
+pub use storage::{Record, Table, TableInfo, Value};
+pub use sync::{Conflict, SyncReport};
+pub use telemetry::TraceContext;
+pub use timeseries::TimeSeries;
+pub use transaction::{ReadTransaction, Transaction, WriteOp};
+pub use update::{PopEnd, UpdateOp};
+pub use validate::FieldError;
+/// 记录的字段按插入顺序保存在`IndexMap`中
+pub use indexmap::IndexMap;
+
+use std::collections::HashMap;
+use std::time::Duration;
 
 /// 数据库配置
 #[derive(Debug, Clone)]
@@ -14,6 +104,56 @@ pub struct Config {
     pub data_dir: String,
     pub encryption_key: Option<Vec<u8>>,
     pub max_file_size: usize,
+    /// 每张表缓存的查询结果条数，0表示不缓存
+    pub query_cache_size: usize,
+    /// 大表非索引扫描使用的线程数，1表示串行，0表示使用CPU核数
+    pub query_threads: usize,
+    /// 变更流日志保留的事件数，决定断线后能补发多少事件
+    pub change_log_size: usize,
+    /// 备份签名密钥（32字节Ed25519种子），配置后备份带签名
+    pub backup_signing_key: Option<Vec<u8>>,
+    /// 恢复时校验备份签名的公钥（32字节），见`backup::public_key`；恢复的机器不需要签名密钥
+    pub backup_verifying_key: Option<Vec<u8>>,
+    /// 没有配置`backup_verifying_key`时是否允许恢复未签名的备份，默认拒绝
+    pub allow_unsigned_backup: bool,
+    /// 保存表文件时是否用zlib压缩
+    pub compress_tables: bool,
+    /// 不小于该字节数的`Bytes`值存入所有表共享的blob存储，相同的内容只保存一份；None表示不存入新的blob
+    pub blob_threshold: Option<usize>,
+    /// 写入表文件使用的存储引擎，读取时按文件头自动识别
+    pub engine: Engine,
+    /// 加密表文件等使用的算法，读取时按文件头自动识别
+    pub cipher: Cipher,
+    /// 在线备份接口写入备份文件的目录，None时把备份内容直接返回给调用方
+    pub backup_dir: Option<String>,
+    /// 单文件模式：整个数据库保存在这个`.sdb`文件中，打开期间在临时工作目录中读写，保存时整体写回
+    pub bundle: Option<String>,
+    /// 按表名覆盖上面的全局设置
+    pub tables: HashMap<String, TableConfig>,
+    /// 供`TableConfig::encryption_key_id`引用的其他表文件加密密钥，按密钥ID组织
+    pub table_keys: HashMap<String, Vec<u8>>,
+    /// 内置排序规则之外可供表使用的字符串比较函数，按名称组织；表使用的排序规则必须在打开数据库时注册
+    pub collations: HashMap<String, Comparator>,
+    /// 事务等待被占用的表锁的最长时间，超过时返回`LockTimeout`而不是一直等待；会形成循环等待时立即返回`Deadlock`
+    pub lock_timeout: Duration,
+    /// 调用方令牌的签名密钥（32字节），配置后HTTP API要求`Authorization: Bearer <令牌>`并按令牌应用行级安全策略
+    pub auth_key: Option<Vec<u8>>,
+    /// 整个数据库（所有表合计）的记录数和字节数配额，超出时总是拒绝写入
+    pub quota: Option<Quota>,
+    /// 按租户名组织的租户配额，作为`SimpleDB::with_tenant`打开的租户分区的`quota`
+    pub tenant_quotas: HashMap<String, Quota>,
+    /// `SimpleDB::with_tenant`缓存的租户分区数的上限，超出时关闭最久未用的空闲分区
+    pub max_open_tenants: usize,
+    /// OTLP/HTTP追踪接收端，如`http://localhost:4318`；配置后数据库和HTTP API的操作以span导出
+    pub otlp_endpoint: Option<String>,
+    /// 导出的追踪数据中的`service.name`
+    pub service_name: String,
+    /// SQL、聚合管道和降采样中整数`SUM`的结果超出i64范围时的处理方式，默认返回`Float`
+    pub aggregate_overflow: OverflowPolicy,
+    /// 保存表后fsync数据目录：表文件总是先写临时文件再改名覆盖，开启后改名在断电后也能保留，代价是每次保存多一次fsync
+    pub sync_dir: bool,
+    /// 只读打开：拒绝所有写入，不保存、不清理数据目录，可以用`SimpleDB::reload_changed`加载其他进程保存的修改
+    pub read_only: bool,
 }
 
 impl Default for Config {
@@ -22,6 +162,60 @@ impl Default for Config {
             data_dir: "./data".to_string(),
             encryption_key: None,
             max_file_size: 1024 * 1024 * 10, // 10MB
+            query_cache_size: 0,
+            query_threads: 1,
+            change_log_size: 10_000,
+            backup_signing_key: None,
+            backup_verifying_key: None,
+            allow_unsigned_backup: false,
+            compress_tables: false,
+            blob_threshold: None,
+            engine: Engine::default(),
+            cipher: Cipher::default(),
+            backup_dir: None,
+            bundle: None,
+            tables: HashMap::new(),
+            table_keys: HashMap::new(),
+            collations: HashMap::new(),
+            lock_timeout: Duration::from_secs(10),
+            auth_key: None,
+            quota: None,
+            tenant_quotas: HashMap::new(),
+            max_open_tenants: 64,
+            otlp_endpoint: None,
+            service_name: "simpledb".to_string(),
+            aggregate_overflow: OverflowPolicy::default(),
+            sync_dir: false,
+            read_only: false,
         }
     }
-} 
\ No newline at end of file
+}
+
+/// 单张表覆盖全局配置的设置，为None的项沿用`Config`中的设置
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableConfig {
+    /// 保存表文件时是否用zlib压缩，覆盖`compress_tables`
+    pub compress: Option<bool>,
+    /// 加密表文件的密钥在`Config::table_keys`中的ID，覆盖`encryption_key`
+    pub encryption_key_id: Option<String>,
+    pub autosave: Option<Autosave>,
+    /// 表文件的大小上限，覆盖`max_file_size`
+    pub max_file_size: Option<usize>,
+    /// 表的记录数和字节数配额
+    pub quota: Option<Quota>,
+    /// 加密的表另外以明文写入`<表名>.plain`旁路文件的字段，不需要密钥就能列出记录、按这些字段过滤；
+    /// 可以包含`id`、`created_at`和`updated_at`。默认为空，不写旁路文件
+    pub plaintext_fields: Vec<String>,
+}
+
+/// 表的修改何时写入磁盘
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Autosave {
+    /// 只在`save_all`和关闭数据库时保存
+    #[default]
+    OnClose,
+    /// 每次写操作后立即保存
+    EveryWrite,
+    /// 未保存的修改累计达到这么多条时保存
+    EveryChanges(usize),
+}
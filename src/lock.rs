@@ -0,0 +1,223 @@
+//! 锁被毒化时的处理
+//!
+//! 持有锁的线程panic后标准库的锁会被“毒化”，之后每次加锁都返回错误；直接`unwrap`会让一个请求中的panic
+//! 变成之后所有请求的panic。缓存、会话表、计数器这类每次修改都保持一致的结构继续使用其中的数据，
+//! 表的锁被毒化时则返回`DatabaseError::Internal`，见`SimpleDB::read_table`。
+//!
+//! 表锁的持有和等待关系登记在`TableLocks`中，用于事务发现真正的死锁。
+
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, LazyLock, LockResult, Mutex, PoisonError};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+
+/// 忽略毒化，取得锁保护的数据
+pub(crate) trait Recover<G> {
+    fn recover(self) -> G;
+}
+
+impl<G> Recover<G> for LockResult<G> {
+    fn recover(self) -> G {
+        self.unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// 表锁的持有与等待关系
+///
+/// 通过`SimpleDB`的加锁函数取得的表锁都在这里登记持有者，等待中的线程登记所等的表。
+/// 事务等待表锁前沿“等待的表 → 持有者 → 持有者等待的表”查找，回到自己时说明形成了循环等待（死锁）；
+/// 只是锁被占用时则等待释放的通知，直到超时。有事务在等待写锁时，新的读锁让它先取得，避免写入被持续的读取饿死。
+pub(crate) struct TableLocks {
+    state: Mutex<LockState>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct LockState {
+    /// 表锁（以表句柄的地址标识）→ 持有它的线程，同一线程多次持有时出现多次
+    holders: HashMap<usize, Vec<ThreadId>>,
+    /// 线程 → 正在等待的表锁
+    waiting: HashMap<ThreadId, usize>,
+    /// 表锁 → 等待写锁的事务数
+    writers: HashMap<usize, usize>,
+}
+
+impl LockState {
+    fn hold(&mut self, key: usize, thread: ThreadId) {
+        self.holders.entry(key).or_default().push(thread);
+    }
+
+    fn holds(&self, key: usize, thread: ThreadId) -> bool {
+        self.holders.get(&key).is_some_and(|holders| holders.contains(&thread))
+    }
+
+    /// `thread`等待`key`是否会形成循环等待
+    fn closes_cycle(&self, thread: ThreadId, key: usize) -> bool {
+        let mut pending = vec![key];
+        let mut visited = HashSet::new();
+        while let Some(key) = pending.pop() {
+            if !visited.insert(key) {
+                continue;
+            }
+            for holder in self.holders.get(&key).into_iter().flatten() {
+                if *holder == thread {
+                    return true;
+                }
+                pending.extend(self.waiting.get(holder));
+            }
+        }
+        false
+    }
+}
+
+/// 等待表锁的结果
+pub(crate) enum Wait<G> {
+    Acquired(Held<G>),
+    /// 继续等待会形成循环等待
+    Deadlock,
+    TimedOut,
+}
+
+/// 未登记的持有者（如直接对句柄加锁）释放时没有通知，等待的线程最多隔这么久重新尝试一次
+const RECHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+static TABLE_LOCKS: LazyLock<TableLocks> = LazyLock::new(|| TableLocks {
+    state: Mutex::new(LockState::default()),
+    released: Condvar::new(),
+});
+
+impl TableLocks {
+    pub(crate) fn global() -> &'static TableLocks {
+        &TABLE_LOCKS
+    }
+
+    /// 以阻塞方式加锁并登记：等待期间登记为等待者，供其他线程检查循环等待
+    ///
+    /// 读锁（`write`为false）在有事务等待写锁时先让写锁取得，当前线程已持有该锁时除外。
+    pub(crate) fn lock<G, E>(&self, key: usize, write: bool, lock: impl FnOnce() -> Result<G, E>) -> Result<Held<G>, E> {
+        let thread = std::thread::current().id();
+        {
+            let mut state = self.state.lock().recover();
+            if !write {
+                while state.writers.get(&key).is_some_and(|n| *n > 0) && !state.holds(key, thread) {
+                    state = self.released.wait_timeout(state, RECHECK_INTERVAL).recover().0;
+                }
+            }
+            state.waiting.insert(thread, key);
+        }
+        self.released.notify_all();
+        let result = lock();
+        let mut state = self.state.lock().recover();
+        state.waiting.remove(&thread);
+        let guard = result?;
+        state.hold(key, thread);
+        Ok(Held { guard: Some(guard), key })
+    }
+
+    /// 在`deadline`之前取得锁：`try_lock`取不到锁（返回None）时等待释放，会形成循环等待时立即返回
+    pub(crate) fn lock_before<G, E>(
+        &self,
+        key: usize,
+        deadline: Instant,
+        mut try_lock: impl FnMut() -> Result<Option<G>, E>,
+    ) -> Result<Wait<G>, E> {
+        let thread = std::thread::current().id();
+        let mut state = self.state.lock().recover();
+        *state.writers.entry(key).or_default() += 1;
+        let outcome = loop {
+            match try_lock() {
+                Ok(Some(guard)) => {
+                    state.hold(key, thread);
+                    break Ok(Wait::Acquired(Held { guard: Some(guard), key }));
+                }
+                Ok(None) => {}
+                Err(e) => break Err(e),
+            }
+            state.waiting.insert(thread, key);
+            if state.closes_cycle(thread, key) {
+                break Ok(Wait::Deadlock);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break Ok(Wait::TimedOut);
+            }
+            state = self.released.wait_timeout(state, (deadline - now).min(RECHECK_INTERVAL)).recover().0;
+        };
+        state.waiting.remove(&thread);
+        if let Some(n) = state.writers.get_mut(&key) {
+            *n -= 1;
+            if *n == 0 {
+                state.writers.remove(&key);
+            }
+        }
+        drop(state);
+        // 让等在写锁之后的读取继续
+        self.released.notify_all();
+        outcome
+    }
+
+    fn release(&self, key: usize) {
+        let thread = std::thread::current().id();
+        let mut state = self.state.lock().recover();
+        if let Some(holders) = state.holders.get_mut(&key) {
+            if let Some(pos) = holders.iter().position(|holder| *holder == thread) {
+                holders.swap_remove(pos);
+            }
+            if holders.is_empty() {
+                state.holders.remove(&key);
+            }
+        }
+        drop(state);
+        self.released.notify_all();
+    }
+}
+
+/// 已登记的表锁，释放时注销并通知等待的线程
+pub(crate) struct Held<G> {
+    guard: Option<G>,
+    key: usize,
+}
+
+impl<G: Deref> Deref for Held<G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &G::Target {
+        self.guard.as_ref().expect("锁在释放前一直持有")
+    }
+}
+
+impl<G: DerefMut> DerefMut for Held<G> {
+    fn deref_mut(&mut self) -> &mut G::Target {
+        self.guard.as_mut().expect("锁在释放前一直持有")
+    }
+}
+
+impl<G> Drop for Held<G> {
+    fn drop(&mut self) {
+        // 先释放锁再通知，被唤醒的线程重试时能取得
+        drop(self.guard.take());
+        TableLocks::global().release(self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cycle_detection() {
+        let other = std::thread::spawn(|| std::thread::current().id()).join().unwrap();
+        let me = std::thread::current().id();
+        let mut state = LockState::default();
+        // other持有表1并等待表2
+        state.hold(1, other);
+        state.waiting.insert(other, 2);
+        assert!(!state.closes_cycle(me, 1));
+        // 当前线程持有表2后再等待表1就形成循环
+        state.hold(2, me);
+        assert!(state.closes_cycle(me, 1));
+        // 等待自己持有的表
+        assert!(state.closes_cycle(me, 2));
+    }
+}
@@ -1,8 +1,18 @@
+use indexmap::IndexMap;
 use clap::{Parser, Subcommand};
-use simpledb::{Config, SimpleDB, Value};
+use simpledb::{AccessLog, CdcConfig, CdcSink, CdcTarget, Cipher, Cluster, ClusterConfig, Config, Engine, MergeStrategy, Query, SimpleDB, Value};
 use simpledb::api::DatabaseServer;
-use simpledb::crypto::Crypto;
-use std::collections::HashMap;
+use simpledb::output::{self, OutputFormat};
+use simpledb::pgwire::PgServer;
+use simpledb::backup;
+use simpledb::plaintext;
+use simpledb::policy;
+use simpledb::crypto::{self, Crypto};
+use simpledb::storage;
+#[cfg(unix)]
+use simpledb::upgrade;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Parser)]
 #[command(name = "simpledb")]
@@ -12,6 +22,8 @@ struct Cli {
     command: Commands,
 }
 
+// 只在启动时解析一次，不必为变体大小装箱
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// 启动数据库服务器
@@ -21,17 +33,125 @@ enum Commands {
         
         #[arg(short, long, default_value = "./data")]
         data_dir: String,
+
+        /// 同时启动PostgreSQL协议只读前端（实验性）的端口
+        #[arg(long)]
+        pg_port: Option<u16>,
         
         #[arg(short, long)]
         encrypted: bool,
+
+        /// 加密时的密钥文件，不存在时生成新密钥并以口令保护写入该文件
+        #[arg(long, default_value = "simpledb.key")]
+        key_file: PathBuf,
+
+        /// 加密算法：aes-gcm，或nonce重复时更安全的aes-gcm-siv；已有的文件按文件头自动识别
+        #[arg(long, default_value = "aes-gcm")]
+        cipher: Cipher,
+
+        /// 表文件的存储引擎：bincode、messagepack、cbor或json（便于查看和手工编辑）
+        #[arg(long, default_value = "bincode")]
+        engine: Engine,
+
+        /// 在线备份接口写入备份文件的目录，不指定时备份内容直接返回给调用方
+        #[arg(long)]
+        backup_dir: Option<String>,
+
+        /// 单文件数据库（.sdb）路径，指定时忽略数据目录，所有表都保存在这一个文件中
+        #[arg(long)]
+        bundle: Option<String>,
+
+        /// 令牌签名密钥文件，指定时API要求令牌并按令牌应用表的行级安全策略
+        #[arg(long)]
+        auth_key_file: Option<PathBuf>,
+
+        /// OTLP/HTTP追踪接收端，如http://localhost:4318，指定时导出数据库和API操作的span
+        #[arg(long)]
+        otlp_endpoint: Option<String>,
+
+        /// 把每个API请求记录到__access_log表
+        #[arg(long)]
+        access_log: bool,
+
+        /// 同时写入该审计日志文件（JSON Lines），每10MB轮转，保留5个旧文件
+        #[arg(long)]
+        access_log_file: Option<PathBuf>,
+
+        /// 成功请求的审计抽样比例，被拒绝和失败的请求总是记录
+        #[arg(long, default_value = "1.0")]
+        access_log_sample_rate: f64,
+
+        /// 停止或平滑升级时等待进行中的请求完成的秒数
+        #[arg(long, default_value = "30")]
+        drain_timeout: u64,
+
+        /// 请求体的最大字节数，超过时返回413；流式导入不受限制
+        #[arg(long, default_value_t = simpledb::http::DEFAULT_MAX_BODY_SIZE)]
+        max_body_size: usize,
+
+        /// 以只读方式打开数据目录：拒绝所有写入，不保存也不清理任何文件
+        #[arg(long)]
+        read_only: bool,
+
+        /// 作为另一个进程的数据目录的只读副本提供查询，表文件被重写后自动重新加载；需要同时指定--read-only
+        #[arg(long, requires = "read_only")]
+        watch_dir: Option<String>,
+
+        /// API监听的地址，集群中的节点要能互相访问
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+
+        /// 以集群模式运行，本节点的ID；写入经过Raft复制到其他节点
+        #[arg(long, conflicts_with = "read_only")]
+        node_id: Option<String>,
+
+        /// 集群中的其他节点，格式为`ID=http://主机:端口`，可以重复
+        #[arg(long = "peer", requires = "node_id", value_parser = parse_peer)]
+        peers: Vec<(String, String)>,
+
+        /// 把变更事件发布到这些Kafka代理（`主机:端口`，逗号分隔）
+        #[arg(long, value_delimiter = ',')]
+        cdc_kafka: Vec<String>,
+
+        /// 把变更事件发布到该NATS服务器（`主机:端口`）
+        #[arg(long, conflicts_with = "cdc_kafka")]
+        cdc_nats: Option<String>,
+
+        /// 变更事件的主题名，`{table}`替换为表名
+        #[arg(long, default_value = "simpledb.{table}")]
+        cdc_topic: String,
     },
     /// 创建示例数据库
     Demo {
         #[arg(short, long, default_value = "./demo_data")]
         data_dir: String,
     },
+    /// 签发API调用方令牌
+    Token {
+        /// 令牌签名密钥文件，与服务器的--auth-key-file相同
+        #[arg(long)]
+        key_file: PathBuf,
+
+        /// 令牌中的声明，JSON格式，如 {"sub":"alice"}
+        #[arg(long)]
+        claims: String,
+    },
+    /// 生成或保护密钥文件
+    Key {
+        #[command(subcommand)]
+        operation: KeyOperation,
+    },
+    /// 单独加密或解密一个表文件
+    Crypt {
+        #[command(subcommand)]
+        operation: CryptOperation,
+    },
     /// 数据库操作
     Db {
+        /// 输出格式：table、json、jsonl或csv
+        #[arg(short, long, global = true, default_value = "table")]
+        output: OutputFormat,
+
         #[command(subcommand)]
         operation: DbOperation,
     },
@@ -54,6 +174,20 @@ enum DbOperation {
         
         #[arg(short, long)]
         id: Option<String>,
+
+        /// 只读取加密表的明文旁路文件，不需要密钥，结果只含明文保存的字段
+        #[arg(long)]
+        plaintext: bool,
+    },
+    /// 绑定参数执行预备查询
+    Query {
+        /// 预备查询的名称
+        #[arg(short, long)]
+        name: String,
+
+        /// 参数绑定，JSON格式
+        #[arg(short, long, default_value = "{}")]
+        params: String,
     },
     /// 更新记录
     Update {
@@ -76,6 +210,151 @@ enum DbOperation {
     },
     /// 列出所有表
     Tables,
+    /// 报告每张表的加密密钥、字段级加密的字段，以及数据目录中未加密的文件
+    Security {
+        /// 数据目录加密时的密钥文件
+        #[arg(long)]
+        key_file: Option<PathBuf>,
+    },
+    /// 扫描全部记录，报告字段、类型、覆盖率和是否可为空
+    Schema {
+        #[arg(short, long)]
+        table: String,
+    },
+    /// 删除表中的所有记录，保留表和索引定义
+    Truncate {
+        #[arg(short, long)]
+        table: String,
+
+        /// 不询问确认
+        #[arg(long)]
+        yes: bool,
+    },
+    /// 修复损坏的数据目录：读出损坏表文件中完好的记录并写回，无法读取的文件移入quarantine目录，重建清单；数据库须未运行
+    Repair {
+        /// 数据目录加密时的密钥文件
+        #[arg(long)]
+        key_file: Option<PathBuf>,
+    },
+    /// 检查表文件是否在数据库之外被修改
+    Verify {
+        /// 同时解密并反序列化每个文件
+        #[arg(long)]
+        deep: bool,
+
+        /// 数据目录加密时的密钥文件，用于校验MAC和解密
+        #[arg(long)]
+        key_file: Option<PathBuf>,
+    },
+    /// 把数据目录打包为备份文件
+    Backup {
+        /// 备份文件路径
+        #[arg(long = "out")]
+        path: PathBuf,
+
+        /// 备份签名密钥文件：32字节Ed25519种子或其十六进制文本
+        #[arg(long)]
+        signing_key: Option<PathBuf>,
+    },
+    /// 把数据目录导出为单文件数据库（.sdb）
+    Bundle {
+        /// 单文件数据库路径
+        #[arg(long = "out")]
+        path: PathBuf,
+    },
+    /// 校验备份文件后用它替换数据目录的内容
+    Restore {
+        /// 备份文件路径
+        #[arg(long = "in")]
+        path: PathBuf,
+
+        /// 备份校验公钥文件：32字节公钥或其十六进制文本，由`key backup-public`生成
+        #[arg(long)]
+        verify_key: Option<PathBuf>,
+
+        /// 允许恢复未签名的备份，默认拒绝
+        #[arg(long)]
+        allow_unsigned: bool,
+    },
+    /// 删除数据目录中属于数据库的文件，保留无法识别的文件
+    Destroy {
+        /// 不询问确认
+        #[arg(long)]
+        yes: bool,
+    },
+    /// 按记录ID合并另一个数据目录
+    Merge {
+        /// 合并到的数据目录
+        #[arg(long)]
+        into: String,
+
+        /// 被合并的数据目录
+        #[arg(long)]
+        from: String,
+
+        /// 记录冲突时的处理方式：newest、skip或error
+        #[arg(long, default_value = "newest")]
+        strategy: MergeStrategy,
+    },
+    /// 与运行中的服务器双向同步，并发修改的记录保留较晚的修改
+    Sync {
+        /// 服务器地址，如 http://192.168.1.10:8080
+        remote: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeyOperation {
+    /// 生成新密钥，以口令保护写入文件
+    Generate {
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// 把原始密钥文件（32字节或十六进制文本）转换为受口令保护的密钥文件
+    Protect {
+        #[arg(long = "in")]
+        input: PathBuf,
+
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// 由备份签名密钥文件导出恢复时校验签名用的公钥（十六进制文本）
+    BackupPublic {
+        #[arg(long = "in")]
+        input: PathBuf,
+
+        #[arg(long)]
+        out: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum CryptOperation {
+    /// 加密明文表文件
+    Encrypt {
+        #[command(flatten)]
+        files: CryptFiles,
+    },
+    /// 解密表文件
+    Decrypt {
+        #[command(flatten)]
+        files: CryptFiles,
+    },
+}
+
+#[derive(clap::Args)]
+struct CryptFiles {
+    /// 密钥文件：受口令保护的密钥文件、32字节原始密钥或其十六进制文本
+    #[arg(long)]
+    key_file: PathBuf,
+
+    /// 输入的表文件
+    #[arg(long = "in")]
+    input: PathBuf,
+
+    /// 输出的表文件，可以与输入相同
+    #[arg(long = "out")]
+    output: PathBuf,
 }
 
 #[tokio::main]
@@ -83,28 +362,144 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Server { port, data_dir, encrypted } => {
+        Commands::Server { port, data_dir, pg_port, encrypted, key_file, cipher, engine, backup_dir, bundle, auth_key_file, otlp_endpoint, access_log, access_log_file, access_log_sample_rate, drain_timeout, max_body_size, read_only, watch_dir, host, node_id, peers, cdc_kafka, cdc_nats, cdc_topic } => {
             println!("正在启动数据库服务器...");
             
+            let auth_key = auth_key_file.as_deref().map(load_key).transpose()?;
+            let watching = watch_dir.is_some();
+            let data_dir = watch_dir.unwrap_or(data_dir);
             let config = if encrypted {
-                let key = Crypto::generate_key();
-                println!("生成的加密密钥（请保存）: {:?}", hex::encode(&key));
+                let key = if key_file.exists() {
+                    load_key(&key_file)?
+                } else {
+                    let key = Crypto::generate_key();
+                    Crypto::export_key(&key, &key_file, &new_passphrase()?)?;
+                    println!("已生成加密密钥，以口令保护保存在 {}（请妥善保管）", key_file.display());
+                    key
+                };
                 Config {
                     data_dir,
                     encryption_key: Some(key),
+                    cipher,
                     max_file_size: 1024 * 1024 * 10,
+                    engine,
+                    backup_dir,
+                    bundle,
+                    auth_key,
+                    otlp_endpoint,
+                    read_only,
+                    ..Config::default()
                 }
             } else {
                 Config {
                     data_dir,
                     encryption_key: None,
                     max_file_size: 1024 * 1024 * 10,
+                    engine,
+                    backup_dir,
+                    bundle,
+                    auth_key,
+                    otlp_endpoint,
+                    read_only,
+                    ..Config::default()
                 }
             };
             
-            let db = SimpleDB::new(config)?;
-            let server = DatabaseServer::new(db, port);
-            server.start().await?;
+            let db = Arc::new(SimpleDB::new(config)?);
+            for (table, damage) in db.load_report().damaged {
+                eprintln!("警告: 表 {} 有 {} 处损坏，已跳过无法读取的记录", table, damage.len());
+                for d in damage {
+                    eprintln!("  偏移 {}，{} 字节: {}", d.offset, d.len, d.reason);
+                }
+            }
+            if let Some(node_id) = node_id {
+                println!("以集群节点 {} 运行，其他节点: {}", node_id, peers.len());
+                Cluster::start(&db, ClusterConfig::new(node_id, peers.into_iter().collect()))?;
+            }
+            let target = match cdc_nats {
+                Some(address) => Some(CdcTarget::Nats { address }),
+                None if !cdc_kafka.is_empty() => Some(CdcTarget::Kafka { brokers: cdc_kafka }),
+                None => None,
+            };
+            // 服务器停止后才释放，发布完剩余的事件
+            let _cdc = match target {
+                Some(target) => {
+                    println!("变更事件发布到 {:?}", target);
+                    Some(CdcSink::start(&db, CdcConfig { topic: cdc_topic, ..CdcConfig::new(target) })?)
+                }
+                None => None,
+            };
+            // 每秒清理一次过期的记录（只访问到期的记录）并运行到期的定时任务
+            let sweeper = Arc::clone(&db);
+            let stop_sweeper = Arc::new(tokio::sync::Notify::new());
+            let stopped = Arc::clone(&stop_sweeper);
+            let sweeper = tokio::spawn(async move {
+                let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = stopped.notified() => break,
+                    }
+                    let db = Arc::clone(&sweeper);
+                    if let Ok(Err(e)) = tokio::task::spawn_blocking(move || db.sweep_expired()).await {
+                        eprintln!("清理过期记录失败: {}", e);
+                    }
+                    let db = Arc::clone(&sweeper);
+                    match tokio::task::spawn_blocking(move || db.run_due_jobs()).await {
+                        Ok(Ok(results)) => {
+                            for (name, result) in results {
+                                if let Err(e) = result {
+                                    eprintln!("定时任务 {} 失败: {}", name, e);
+                                }
+                            }
+                        }
+                        Ok(Err(e)) => eprintln!("运行定时任务失败: {}", e),
+                        Err(_) => {}
+                    }
+                }
+            });
+            if watching {
+                // 每秒检查一次其他进程保存的表文件
+                let replica = Arc::clone(&db);
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+                    loop {
+                        interval.tick().await;
+                        let db = Arc::clone(&replica);
+                        if let Ok(Err(e)) = tokio::task::spawn_blocking(move || db.reload_changed()).await {
+                            eprintln!("重新加载表文件失败: {}", e);
+                        }
+                    }
+                });
+            }
+            if let Some(pg_port) = pg_port {
+                let pg = PgServer::new(Arc::clone(&db), pg_port);
+                tokio::spawn(async move {
+                    if let Err(e) = pg.start().await {
+                        eprintln!("PostgreSQL协议前端启动失败: {}", e);
+                    }
+                });
+            }
+            let mut server = DatabaseServer::with_shared(Arc::clone(&db), port)
+                .with_drain_timeout(std::time::Duration::from_secs(drain_timeout))
+                .with_max_body_size(max_body_size);
+            #[cfg(unix)]
+            let inherited = upgrade::inherited_listener()?;
+            #[cfg(not(unix))]
+            let inherited = None;
+            match inherited {
+                Some(listener) => server = server.with_listener(listener),
+                None if host != "127.0.0.1" => server = server.with_listener(std::net::TcpListener::bind((host.as_str(), port))?),
+                None => {}
+            }
+            if access_log || access_log_file.is_some() {
+                let mut log = AccessLog::new().with_table(access_log).with_sample_rate(access_log_sample_rate);
+                if let Some(path) = access_log_file {
+                    log = log.with_file(path, 10 * 1024 * 1024, 5);
+                }
+                server = server.with_access_log(log);
+            }
+            serve(server, db, (stop_sweeper, sweeper)).await?;
         }
         
         Commands::Demo { data_dir } => {
@@ -114,10 +509,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 data_dir,
                 encryption_key: Some(Crypto::generate_key()),
                 max_file_size: 1024 * 1024,
+                ..Config::default()
             };
             
-            let mut db = SimpleDB::new(config)?;
-            create_demo_data(&mut db)?;
+            let db = SimpleDB::new(config)?;
+            create_demo_data(&db)?;
             
             println!("示例数据库创建完成！");
             println!("包含以下表和数据：");
@@ -140,57 +536,361 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
         
-        Commands::Db { operation } => {
-            let config = Config::default();
-            let mut db = SimpleDB::new(config)?;
+        Commands::Token { key_file, claims } => {
+            let claims: IndexMap<String, serde_json::Value> = serde_json::from_str(&claims)?;
+            let token = policy::issue_token(&load_key(&key_file)?, &convert_json_to_value(claims).into_iter().collect())?;
+            println!("{}", token);
+        }
+
+        Commands::Key { operation } => {
+            let (key, out) = match operation {
+                KeyOperation::Generate { out } => (Crypto::generate_key(), out),
+                KeyOperation::Protect { input, out } => (crypto::read_key_file(&input)?, out),
+                KeyOperation::BackupPublic { input, out } => {
+                    let public = backup::public_key(&load_key(&input)?)?;
+                    std::fs::write(&out, hex::encode(public))?;
+                    println!("备份校验公钥已写入 {}", out.display());
+                    return Ok(());
+                }
+            };
+            Crypto::new(&key)?;
+            Crypto::export_key(&key, &out, &new_passphrase()?)?;
+            println!("密钥已以口令保护写入 {}", out.display());
+        }
+
+        Commands::Crypt { operation } => {
+            crypt(operation)?;
+        }
+
+        Commands::Db { output: format, operation } => {
+            let config = match &operation {
+                DbOperation::Merge { into, .. } => Config {
+                    data_dir: into.clone(),
+                    ..Config::default()
+                },
+                DbOperation::Verify { key_file: Some(key_file), .. }
+                | DbOperation::Repair { key_file: Some(key_file) }
+                | DbOperation::Security { key_file: Some(key_file) } => Config {
+                    encryption_key: Some(load_key(key_file)?),
+                    ..Config::default()
+                },
+                DbOperation::Backup { signing_key: Some(key_file), .. } => Config {
+                    backup_signing_key: Some(load_key(key_file)?),
+                    ..Config::default()
+                },
+                DbOperation::Restore { verify_key, allow_unsigned, .. } => Config {
+                    backup_verifying_key: verify_key.as_deref().map(crypto::read_key_file).transpose()?,
+                    allow_unsigned_backup: *allow_unsigned,
+                    ..Config::default()
+                },
+                _ => Config::default(),
+            };
+
+            // 恢复会替换数据目录中的文件，必须在打开数据库之前进行
+            if let DbOperation::Restore { path, .. } = &operation {
+                let restored = SimpleDB::restore(&config, path)?;
+                if format == OutputFormat::Table {
+                    println!("已从 {} 恢复 {} 个文件", path.display(), restored);
+                } else {
+                    print!("{}", output::render_rows(&["files"], &[vec![Value::Int(restored as i64)]], format));
+                }
+                return Ok(());
+            }
+            // 读取明文旁路文件不需要密钥，也不打开数据库
+            if let DbOperation::Find { table, id, plaintext: true } = &operation {
+                let records: Vec<Arc<storage::Record>> = plaintext::read(Path::new(&config.data_dir), table, &Query::new())?
+                    .into_iter()
+                    .filter(|record| id.as_ref().is_none_or(|id| &record.id == id))
+                    .map(Arc::new)
+                    .collect();
+                print!("{}", output::render_records(&records, format));
+                if format == OutputFormat::Table {
+                    println!("({} 条记录)", records.len());
+                }
+                return Ok(());
+            }
+            // 销毁同样只能在数据库未打开时进行
+            if let DbOperation::Destroy { yes } = &operation {
+                if !yes && !confirm(&format!("确定要删除数据目录 {} 中的数据库文件吗？", config.data_dir))? {
+                    println!("已取消");
+                    return Ok(());
+                }
+                let report = SimpleDB::destroy(&config)?;
+                if format == OutputFormat::Table {
+                    println!("已删除 {} 个文件", report.removed.len());
+                    for name in &report.kept {
+                        println!("保留无法识别的文件: {}", name);
+                    }
+                } else {
+                    let rows: Vec<Vec<Value>> = report
+                        .removed
+                        .iter()
+                        .map(|name| vec![Value::String(name.clone()), Value::Bool(true)])
+                        .chain(report.kept.iter().map(|name| vec![Value::String(name.clone()), Value::Bool(false)]))
+                        .collect();
+                    print!("{}", output::render_rows(&["file", "removed"], &rows, format));
+                }
+                return Ok(());
+            }
+            // 修复同样只能在数据库未打开时进行
+            if let DbOperation::Repair { .. } = &operation {
+                let report = SimpleDB::repair(&config)?;
+                if format == OutputFormat::Table {
+                    println!("检查了 {} 个表文件", report.checked);
+                    for name in &report.removed_temp {
+                        println!("删除未写完的临时文件: {}", name);
+                    }
+                    for table in &report.repaired {
+                        println!("表 {}: 恢复 {} 条记录，丢失 {} 处损坏", table.table, table.recovered, table.lost.len());
+                        for damage in &table.lost {
+                            println!("  偏移 {}，{} 字节: {}", damage.offset, damage.len, damage.reason);
+                        }
+                    }
+                    for (name, reason) in &report.quarantined {
+                        println!("无法读取，已移入隔离目录: {}（{}）", name, reason);
+                    }
+                    if report.is_clean() {
+                        println!("数据目录完好，已重建清单");
+                    }
+                } else {
+                    let rows: Vec<Vec<Value>> = report
+                        .repaired
+                        .iter()
+                        .map(|t| {
+                            vec![
+                                Value::String(t.table.clone()),
+                                Value::String("repaired".to_string()),
+                                Value::Int(t.recovered as i64),
+                                Value::Int(t.lost.len() as i64),
+                            ]
+                        })
+                        .chain(report.quarantined.iter().map(|(name, _)| {
+                            vec![Value::String(name.clone()), Value::String("quarantined".to_string()), Value::Int(0), Value::Null]
+                        }))
+                        .collect();
+                    print!("{}", output::render_rows(&["file", "action", "recovered", "lost"], &rows, format));
+                }
+                return Ok(());
+            }
+            let db = SimpleDB::new(config)?;
             
             match operation {
                 DbOperation::Insert { table, data } => {
-                    let json_data: HashMap<String, serde_json::Value> = serde_json::from_str(&data)?;
-                    let converted_data = convert_json_to_value(json_data);
+                    let json_data: IndexMap<String, serde_json::Value> = serde_json::from_str(&data)?;
+                    let converted_data = with_field_types(&db, &table, convert_json_to_value(json_data));
                     let id = db.insert(&table, converted_data)?;
-                    println!("记录插入成功，ID: {}", id);
+                    if format == OutputFormat::Table {
+                        println!("记录插入成功，ID: {}", id);
+                    } else {
+                        print!("{}", output::render_rows(&["id"], &[vec![Value::String(id)]], format));
+                    }
                 }
                 
-                DbOperation::Find { table, id } => {
-                    if let Some(id) = id {
-                        if let Some(record) = db.find_by_id(&table, &id)? {
-                            print_record(record);
-                        } else {
-                            println!("记录不存在");
-                        }
+                DbOperation::Find { table, id, .. } => {
+                    let records = match id {
+                        Some(id) => db.find_by_id(&table, &id)?.into_iter().collect(),
+                        None => db.find_all(&table)?,
+                    };
+                    if format == OutputFormat::Table && records.is_empty() {
+                        println!("记录不存在");
                     } else {
-                        let records = db.find_all(&table)?;
-                        println!("找到 {} 条记录:", records.len());
-                        for record in records {
-                            print_record(record);
-                            println!("---");
+                        print!("{}", output::render_records(&records, format));
+                        if format == OutputFormat::Table {
+                            println!("({} 条记录)", records.len());
                         }
                     }
                 }
                 
+                DbOperation::Query { name, params } => {
+                    let json_params: IndexMap<String, serde_json::Value> = serde_json::from_str(&params)?;
+                    let records = db.execute_prepared(&name, &convert_json_to_value(json_params))?;
+                    print!("{}", output::render_records(&records, format));
+                    if format == OutputFormat::Table {
+                        println!("({} 条记录)", records.len());
+                    }
+                }
+
                 DbOperation::Update { table, id, data } => {
-                    let json_data: HashMap<String, serde_json::Value> = serde_json::from_str(&data)?;
-                    let converted_data = convert_json_to_value(json_data);
+                    let json_data: IndexMap<String, serde_json::Value> = serde_json::from_str(&data)?;
+                    let converted_data = with_field_types(&db, &table, convert_json_to_value(json_data));
                     db.update(&table, &id, converted_data)?;
-                    println!("记录更新成功");
+                    if format == OutputFormat::Table {
+                        println!("记录更新成功");
+                    } else {
+                        print!("{}", output::render_rows(&["id"], &[vec![Value::String(id)]], format));
+                    }
                 }
                 
                 DbOperation::Delete { table, id } => {
                     db.delete(&table, &id)?;
-                    println!("记录删除成功");
+                    if format == OutputFormat::Table {
+                        println!("记录删除成功");
+                    } else {
+                        print!("{}", output::render_rows(&["id"], &[vec![Value::String(id)]], format));
+                    }
                 }
                 
+                DbOperation::Security { .. } => {
+                    let report = db.security_report()?;
+                    let fields = |fields: &std::collections::BTreeSet<String>| {
+                        Value::String(fields.iter().cloned().collect::<Vec<_>>().join(","))
+                    };
+                    let rows: Vec<Vec<Value>> = report
+                        .tables
+                        .iter()
+                        .map(|t| {
+                            let key = match (&t.key_id, t.encrypted) {
+                                (Some(id), _) => id.clone(),
+                                (None, true) => "master".to_string(),
+                                (None, false) => "none".to_string(),
+                            };
+                            vec![
+                                Value::String(t.table.clone()),
+                                Value::String(key),
+                                fields(&t.sealed_fields),
+                                fields(&t.deterministic_fields),
+                                Value::String(t.unmigrated_files.join(",")),
+                            ]
+                        })
+                        .collect();
+                    print!(
+                        "{}",
+                        output::render_rows(&["table", "key", "sealed", "deterministic", "unmigrated"], &rows, format)
+                    );
+                    if format == OutputFormat::Table {
+                        println!("未加密的文件: {}", report.plaintext_files().collect::<Vec<_>>().join(", "));
+                    }
+                }
+
                 DbOperation::Tables => {
                     let tables = db.list_tables();
-                    if tables.is_empty() {
+                    if format == OutputFormat::Table && tables.is_empty() {
                         println!("没有找到任何表");
                     } else {
-                        println!("数据库中的表:");
+                        let mut rows = Vec::new();
                         for table in tables {
                             let count = db.count(&table)?;
-                            println!("  - {}: {} 条记录", table, count);
+                            rows.push(vec![Value::String(table), Value::Int(count as i64)]);
                         }
+                        print!("{}", output::render_rows(&["table", "records"], &rows, format));
+                    }
+                }
+
+                DbOperation::Schema { table } => {
+                    let schema = db.infer_schema(&table)?;
+                    let rows: Vec<Vec<Value>> = schema
+                        .fields
+                        .iter()
+                        .map(|field| {
+                            let types: Vec<String> =
+                                field.types.iter().map(|(name, count)| format!("{}({})", name, count)).collect();
+                            let coverage = if format == OutputFormat::Table {
+                                Value::String(format!("{:.1}%", field.coverage * 100.0))
+                            } else {
+                                Value::Float(field.coverage)
+                            };
+                            vec![
+                                Value::String(field.name.clone()),
+                                Value::String(types.join(", ")),
+                                coverage,
+                                Value::Bool(field.nullable),
+                            ]
+                        })
+                        .collect();
+                    print!("{}", output::render_rows(&["field", "types", "coverage", "nullable"], &rows, format));
+                    if format == OutputFormat::Table {
+                        println!("({} 条记录，{} 个字段)", schema.total, schema.fields.len());
+                    }
+                }
+
+                DbOperation::Truncate { table, yes } => {
+                    let count = db.count(&table)?;
+                    if !yes && !confirm(&format!("确定要删除表 {} 中的全部 {} 条记录吗？", table, count))? {
+                        println!("已取消");
+                        return Ok(());
+                    }
+                    let removed = db.truncate(&table)?;
+                    if format == OutputFormat::Table {
+                        println!("已清空表 {}，删除了 {} 条记录", table, removed);
+                    } else {
+                        print!("{}", output::render_rows(&["removed"], &[vec![Value::Int(removed as i64)]], format));
+                    }
+                }
+
+                DbOperation::Verify { deep, .. } => {
+                    let report = db.verify(deep)?;
+                    if format == OutputFormat::Table {
+                        for (file, problem) in &report.problems {
+                            println!("{}: {}", file, problem);
+                        }
+                        println!("检查了 {} 个文件，发现 {} 个问题", report.checked, report.problems.len());
+                    } else {
+                        let rows: Vec<Vec<Value>> = report
+                            .problems
+                            .iter()
+                            .map(|(file, problem)| vec![Value::String(file.clone()), Value::String(problem.to_string())])
+                            .collect();
+                        print!("{}", output::render_rows(&["file", "problem"], &rows, format));
+                    }
+                    if !report.is_ok() {
+                        std::process::exit(1);
+                    }
+                }
+
+                DbOperation::Backup { path, .. } => {
+                    let files = db.backup(&path)?;
+                    if format == OutputFormat::Table {
+                        println!("已备份 {} 个文件到 {}", files, path.display());
+                    } else {
+                        print!("{}", output::render_rows(&["files"], &[vec![Value::Int(files as i64)]], format));
+                    }
+                }
+
+                DbOperation::Bundle { path } => {
+                    let files = db.export_bundle(&path)?;
+                    if format == OutputFormat::Table {
+                        println!("已把 {} 个文件导出到单文件数据库 {}", files, path.display());
+                    } else {
+                        print!("{}", output::render_rows(&["files"], &[vec![Value::Int(files as i64)]], format));
+                    }
+                }
+
+                DbOperation::Restore { .. } | DbOperation::Destroy { .. } | DbOperation::Repair { .. } => {
+                    unreachable!("恢复、销毁和修复在打开数据库之前处理")
+                }
+
+                DbOperation::Merge { from, strategy, .. } => {
+                    if !std::path::Path::new(&from).is_dir() {
+                        return Err(format!("数据目录不存在: {}", from).into());
+                    }
+                    let source = SimpleDB::new(Config {
+                        data_dir: from,
+                        ..Config::default()
+                    })?;
+                    let report = db.merge_from(&source, strategy)?;
+                    db.save_all()?;
+                    if format == OutputFormat::Table {
+                        println!(
+                            "合并完成：新增 {} 条，替换 {} 条，跳过 {} 条",
+                            report.inserted, report.replaced, report.skipped
+                        );
+                    } else {
+                        let row = [report.inserted, report.replaced, report.skipped].map(|n| Value::Int(n as i64));
+                        print!("{}", output::render_rows(&["inserted", "replaced", "skipped"], &[row.to_vec()], format));
+                    }
+                }
+
+                DbOperation::Sync { remote } => {
+                    let report = db.sync_with(&remote)?;
+                    db.save_all()?;
+                    if format == OutputFormat::Table {
+                        println!(
+                            "同步完成：取回 {} 条，发送 {} 条，合并冲突 {} 条",
+                            report.pulled, report.pushed, report.conflicts
+                        );
+                    } else {
+                        let row = [report.pulled, report.pushed, report.conflicts].map(|n| Value::Int(n as i64));
+                        print!("{}", output::render_rows(&["pulled", "pushed", "conflicts"], &[row.to_vec()], format));
                     }
                 }
             }
@@ -200,21 +900,150 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-fn create_demo_data(db: &mut SimpleDB) -> Result<(), Box<dyn std::error::Error>> {
+/// 运行服务器直到收到信号：SIGTERM或Ctrl-C时处理完进行中的请求、保存后退出；
+/// SIGUSR2时同样停止，再以相同的命令行启动继承监听套接字的新进程，实现不中断连接的重启
+#[cfg(unix)]
+async fn serve(
+    server: DatabaseServer,
+    db: Arc<SimpleDB>,
+    (stop_sweeper, sweeper): (Arc<tokio::sync::Notify>, tokio::task::JoinHandle<()>),
+) -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut upgrade = signal(SignalKind::user_defined2())?;
+    let mut upgrading = false;
+    let listener = server
+        .serve_until(async {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+                _ = upgrade.recv() => upgrading = true,
+            }
+        })
+        .await?;
+    // 等正在进行的清理结束后停止后台清理，之后不再修改数据
+    stop_sweeper.notify_one();
+    let _ = sweeper.await;
+    db.save_all()?;
+    let _ = db.flush_traces();
+    if upgrading {
+        let child = upgrade::spawn_successor(&listener)?;
+        println!("已把监听套接字交给新进程 {}", child.id());
+        // 不再运行析构函数，新进程接管后当前进程不能再写数据目录
+        std::process::exit(0);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn serve(
+    server: DatabaseServer,
+    _db: Arc<SimpleDB>,
+    _sweeper: (Arc<tokio::sync::Notify>, tokio::task::JoinHandle<()>),
+) -> Result<(), Box<dyn std::error::Error>> {
+    server
+        .serve_until(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+    Ok(())
+}
+
+/// 解析`ID=http://主机:端口`形式的集群节点
+fn parse_peer(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((id, url)) if !id.is_empty() && url.starts_with("http://") => Ok((id.to_string(), url.to_string())),
+        _ => Err(format!("节点应为ID=http://主机:端口: {}", value)),
+    }
+}
+
+/// 在终端询问确认，输入y或yes时返回true
+fn confirm(prompt: &str) -> std::io::Result<bool> {
+    use std::io::Write;
+    print!("{} [y/N] ", prompt);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// 加密或解密单个表文件
+fn crypt(operation: CryptOperation) -> Result<(), Box<dyn std::error::Error>> {
+    let (files, encrypt) = match operation {
+        CryptOperation::Encrypt { files } => (files, true),
+        CryptOperation::Decrypt { files } => (files, false),
+    };
+    let crypto = Crypto::new(&load_key(&files.key_file)?)?;
+    // 先完整读出再写入，输出与输入相同时也是安全的
+    let (from, to) = if encrypt { (None, Some(&crypto)) } else { (Some(&crypto), None) };
+    let records = storage::read_table_file(&files.input, from)?;
+    storage::write_table_file(&files.output, &records, to, Default::default())?;
+    println!(
+        "已{} {} 条记录: {} -> {}",
+        if encrypt { "加密" } else { "解密" },
+        records.len(),
+        files.input.display(),
+        files.output.display()
+    );
+    Ok(())
+}
+
+/// 读取密钥文件，受口令保护时询问口令
+fn load_key(path: &Path) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if std::fs::read(path)?.starts_with(crypto::KEY_FILE_MAGIC) {
+        let passphrase = passphrase(&format!("请输入密钥文件 {} 的口令: ", path.display()))?;
+        return Ok(Crypto::import_key(path, &passphrase)?);
+    }
+    Ok(crypto::read_key_file(path)?)
+}
+
+/// 为新的密钥文件设置口令，在终端输入时需要输入两次
+fn new_passphrase() -> Result<String, Box<dyn std::error::Error>> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let passphrase = passphrase("请设置密钥文件的口令: ")?;
+    if passphrase.is_empty() {
+        return Err("口令不能为空".into());
+    }
+    if passphrase != self::passphrase("请再次输入口令: ")? {
+        return Err("两次输入的口令不一致".into());
+    }
+    Ok(passphrase)
+}
+
+/// 保存密钥文件口令的环境变量，设置时不再在终端询问
+const PASSPHRASE_ENV: &str = "SIMPLEDB_PASSPHRASE";
+
+/// 从环境变量读取口令，未设置时在终端询问
+fn passphrase(prompt: &str) -> std::io::Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    use std::io::Write;
+    eprint!("{}", prompt);
+    std::io::stderr().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn create_demo_data(db: &SimpleDB) -> Result<(), Box<dyn std::error::Error>> {
     // 创建用户表
-    let mut user1 = HashMap::new();
+    let mut user1 = IndexMap::new();
     user1.insert("name".to_string(), Value::String("张三".to_string()));
     user1.insert("age".to_string(), Value::Int(25));
     user1.insert("email".to_string(), Value::String("zhangsan@example.com".to_string()));
     user1.insert("active".to_string(), Value::Bool(true));
     
-    let mut user2 = HashMap::new();
+    let mut user2 = IndexMap::new();
     user2.insert("name".to_string(), Value::String("李四".to_string()));
     user2.insert("age".to_string(), Value::Int(30));
     user2.insert("email".to_string(), Value::String("lisi@example.com".to_string()));
     user2.insert("active".to_string(), Value::Bool(false));
     
-    let mut user3 = HashMap::new();
+    let mut user3 = IndexMap::new();
     user3.insert("name".to_string(), Value::String("王五".to_string()));
     user3.insert("age".to_string(), Value::Int(28));
     user3.insert("email".to_string(), Value::String("wangwu@example.com".to_string()));
@@ -225,19 +1054,19 @@ fn create_demo_data(db: &mut SimpleDB) -> Result<(), Box<dyn std::error::Error>>
     db.insert("users", user3)?;
 
     // 创建产品表
-    let mut product1 = HashMap::new();
+    let mut product1 = IndexMap::new();
     product1.insert("name".to_string(), Value::String("笔记本电脑".to_string()));
     product1.insert("price".to_string(), Value::Float(5999.99));
     product1.insert("category".to_string(), Value::String("电子产品".to_string()));
     product1.insert("in_stock".to_string(), Value::Bool(true));
     
-    let mut product2 = HashMap::new();
+    let mut product2 = IndexMap::new();
     product2.insert("name".to_string(), Value::String("智能手机".to_string()));
     product2.insert("price".to_string(), Value::Float(2999.50));
     product2.insert("category".to_string(), Value::String("电子产品".to_string()));
     product2.insert("in_stock".to_string(), Value::Bool(true));
     
-    let mut product3 = HashMap::new();
+    let mut product3 = IndexMap::new();
     product3.insert("name".to_string(), Value::String("咖啡机".to_string()));
     product3.insert("price".to_string(), Value::Float(899.00));
     product3.insert("category".to_string(), Value::String("家电".to_string()));
@@ -248,7 +1077,7 @@ fn create_demo_data(db: &mut SimpleDB) -> Result<(), Box<dyn std::error::Error>>
     db.insert("products", product3)?;
 
     // 创建订单表
-    let mut order1 = HashMap::new();
+    let mut order1 = IndexMap::new();
     order1.insert("user_name".to_string(), Value::String("张三".to_string()));
     order1.insert("product_name".to_string(), Value::String("笔记本电脑".to_string()));
     order1.insert("quantity".to_string(), Value::Int(1));
@@ -261,7 +1090,21 @@ fn create_demo_data(db: &mut SimpleDB) -> Result<(), Box<dyn std::error::Error>>
     Ok(())
 }
 
-fn convert_json_to_value(json_map: HashMap<String, serde_json::Value>) -> HashMap<String, Value> {
+/// 按表中声明的字段类型转换数据，如把datetime字段的ISO-8601字符串转换为时间戳
+fn with_field_types(db: &SimpleDB, table: &str, data: IndexMap<String, Value>) -> IndexMap<String, Value> {
+    let types = db.field_types(table).unwrap_or_default();
+    data.into_iter()
+        .map(|(field, value)| match types.get(&field) {
+            Some(field_type) => {
+                let value = field_type.convert(value);
+                (field, value)
+            }
+            None => (field, value),
+        })
+        .collect()
+}
+
+fn convert_json_to_value(json_map: IndexMap<String, serde_json::Value>) -> IndexMap<String, Value> {
     json_map
         .into_iter()
         .map(|(k, v)| {
@@ -285,12 +1128,56 @@ fn convert_json_to_value(json_map: HashMap<String, serde_json::Value>) -> HashMa
         .collect()
 }
 
-fn print_record(record: &simpledb::storage::Record) {
-    println!("记录 ID: {}", record.id);
-    println!("创建时间: {}", record.created_at);
-    println!("更新时间: {}", record.updated_at);
-    println!("数据:");
-    for (key, value) in &record.data {
-        println!("  {}: {:?}", key, value);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 按命令行参数执行`crypt`子命令
+    fn run_crypt(args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        match Cli::try_parse_from(["simpledb", "crypt"].iter().chain(args))?.command {
+            Commands::Crypt { operation } => crypt(operation),
+            _ => unreachable!(),
+        }
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_crypt_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Crypto::generate_key();
+        let config = Config {
+            data_dir: dir.path().to_str().unwrap().to_string(),
+            encryption_key: Some(key.clone()),
+            ..Config::default()
+        };
+        let db = SimpleDB::new(config.clone()).unwrap();
+        for n in 0..10 {
+            db.insert("users", IndexMap::from([("n".to_string(), Value::Int(n))])).unwrap();
+        }
+        db.save_all().unwrap();
+        let expected = db.query("users", &Query::new()).unwrap();
+        drop(db);
+
+        let key_file = dir.path().join("key.hex");
+        std::fs::write(&key_file, hex::encode(&key)).unwrap();
+        let other_key = dir.path().join("other.hex");
+        std::fs::write(&other_key, hex::encode(Crypto::generate_key())).unwrap();
+        let table = dir.path().join("users.db");
+        let plain = dir.path().join("users.plain.db");
+        let path = |path: &Path| path.to_str().unwrap().to_string();
+
+        // 解密后的文件不用密钥就能读出同样的记录
+        assert!(run_crypt(&["decrypt", "--key-file", &path(&other_key), "--in", &path(&table), "--out", &path(&plain)]).is_err());
+        assert!(!plain.exists());
+        run_crypt(&["decrypt", "--key-file", &path(&key_file), "--in", &path(&table), "--out", &path(&plain)]).unwrap();
+        let records = storage::read_table_file(&plain, None).unwrap();
+        assert_eq!(records.len(), 10);
+        assert!(expected.iter().all(|record| records[&record.id] == *record));
+
+        // 原地重新加密后数据库用原来的密钥照常打开
+        run_crypt(&["encrypt", "--key-file", &path(&key_file), "--in", &path(&plain), "--out", &path(&table)]).unwrap();
+        run_crypt(&["encrypt", "--key-file", &path(&key_file), "--in", &path(&plain), "--out", &path(&plain)]).unwrap();
+        assert!(storage::read_table_file(&plain, None).is_err());
+        let db = SimpleDB::new(config).unwrap();
+        assert_eq!(db.query("users", &Query::new()).unwrap(), expected);
+    }
+}
@@ -0,0 +1,261 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::crypto::Crypto;
+use crate::error::Result;
+use crate::lock::Recover;
+use crate::siv::Siv;
+use crate::statefile;
+use crate::storage;
+
+/// 清单文件名
+pub const MANIFEST_FILE: &str = "MANIFEST";
+
+/// 清单文件开头的魔数
+pub const MANIFEST_MAGIC: &[u8; 8] = b"SDBMANI1";
+
+/// 计算文件MAC时使用的域
+const MAC_DOMAIN: &[u8] = b"simpledb-manifest";
+
+/// 表文件内容的校验值
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Checksum {
+    /// 未配置密钥时只能发现意外损坏
+    Crc32(u32),
+    /// 由主密钥派生的AES-CMAC，不知道密钥就无法伪造
+    Mac([u8; 16]),
+}
+
+/// 清单中一个表文件的登记信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub size: u64,
+    pub checksum: Checksum,
+}
+
+/// 校验发现的问题
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// 清单中登记了，但文件不存在
+    Missing,
+    /// 文件存在，但不在清单中
+    Untracked,
+    SizeMismatch { expected: u64, actual: u64 },
+    ChecksumMismatch,
+    /// 文件以MAC登记，但没有提供主密钥
+    Unverifiable,
+    /// 深度校验时解密（认证标签）或反序列化失败
+    Unreadable(String),
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Missing => write!(f, "文件缺失"),
+            Problem::Untracked => write!(f, "文件不在清单中"),
+            Problem::SizeMismatch { expected, actual } => {
+                write!(f, "文件大小不符，清单中为 {} 字节，实际为 {} 字节", expected, actual)
+            }
+            Problem::ChecksumMismatch => write!(f, "校验值不符，文件可能在数据库之外被修改"),
+            Problem::Unverifiable => write!(f, "需要主密钥才能校验"),
+            Problem::Unreadable(reason) => write!(f, "无法读取: {}", reason),
+        }
+    }
+}
+
+/// 校验结果
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// 检查过的文件数
+    pub checked: usize,
+    /// (文件名, 问题)
+    pub problems: Vec<(String, Problem)>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// 表文件清单：每次保存表时记录文件大小和校验值，用于发现在数据库之外被修改的文件
+#[derive(Debug)]
+pub struct Manifest {
+    path: PathBuf,
+    mac: Option<Siv>,
+    entries: Mutex<BTreeMap<String, FileEntry>>,
+}
+
+impl Manifest {
+    /// 打开数据目录中的清单，`mac`为None时使用CRC32
+    ///
+    /// 目录中还没有清单时（旧版本创建的数据目录），以现有的表文件初始化。
+    pub fn open(data_dir: &Path, mac: Option<Siv>) -> Result<Self> {
+        let path = data_dir.join(MANIFEST_FILE);
+        let manifest = Self {
+            path,
+            mac,
+            entries: Mutex::new(BTreeMap::new()),
+        };
+        if let Some(entries) = statefile::load(&manifest.path, MANIFEST_MAGIC, None)? {
+            *manifest.entries.lock().recover() = entries;
+        } else {
+            let mut entries = manifest.entries.lock().recover();
+            for (name, path) in table_files(data_dir)? {
+                entries.insert(name, manifest.entry(&std::fs::read(path)?));
+            }
+            manifest.persist(&entries)?;
+        }
+        Ok(manifest)
+    }
+
+    fn checksum(&self, content: &[u8]) -> Checksum {
+        match &self.mac {
+            Some(siv) => Checksum::Mac(siv.mac(MAC_DOMAIN, content)),
+            None => Checksum::Crc32(crc32fast::hash(content)),
+        }
+    }
+
+    fn entry(&self, content: &[u8]) -> FileEntry {
+        FileEntry {
+            size: content.len() as u64,
+            checksum: self.checksum(content),
+        }
+    }
+
+    /// 登记刚写入的表文件内容
+    pub fn record(&self, file_name: &str, content: &[u8]) -> Result<()> {
+        let mut entries = self.entries.lock().recover();
+        entries.insert(file_name.to_string(), self.entry(content));
+        self.persist(&entries)
+    }
+
+    /// 复制表文件后把原文件的登记复制给新文件
+    pub fn copy(&self, from: &str, to: &str) -> Result<()> {
+        let mut entries = self.entries.lock().recover();
+        if let Some(entry) = entries.get(from).copied() {
+            entries.insert(to.to_string(), entry);
+            self.persist(&entries)?;
+        }
+        Ok(())
+    }
+
+    /// 删除表文件后移除登记
+    pub fn remove(&self, file_name: &str) -> Result<()> {
+        let mut entries = self.entries.lock().recover();
+        if entries.remove(file_name).is_some() {
+            self.persist(&entries)?;
+        }
+        Ok(())
+    }
+
+    /// 以给定的文件内容替换登记、去掉`removed`中的文件后编码清单，不写入磁盘；用于与内存中的表快照一起备份
+    pub fn encode_with(&self, files: &[(String, Vec<u8>)], removed: &[String]) -> Result<Vec<u8>> {
+        let mut entries = self.entries.lock().recover().clone();
+        for name in removed {
+            entries.remove(name);
+        }
+        for (name, content) in files {
+            entries.insert(name.clone(), self.entry(content));
+        }
+        Ok(statefile::encode(MANIFEST_MAGIC, &bincode::serialize(&entries)?))
+    }
+
+    fn persist(&self, entries: &BTreeMap<String, FileEntry>) -> Result<()> {
+        statefile::store(&self.path, MANIFEST_MAGIC, entries, None)
+    }
+
+    /// 对照清单检查目录中的表文件；`deep`为true时还会解密（校验认证标签）并反序列化每个文件
+    pub fn verify(&self, data_dir: &Path, crypto: &dyn Fn(&str) -> Option<Crypto>, deep: bool) -> Result<VerifyReport> {
+        let entries = self.entries.lock().recover().clone();
+        let files: BTreeMap<String, PathBuf> = table_files(data_dir)?.into_iter().collect();
+        let mut report = VerifyReport::default();
+
+        for name in entries.keys().filter(|name| !files.contains_key(*name)) {
+            report.problems.push((name.clone(), Problem::Missing));
+        }
+        for (name, path) in files {
+            report.checked += 1;
+            let content = std::fs::read(&path)?;
+            match entries.get(&name) {
+                None => report.problems.push((name.clone(), Problem::Untracked)),
+                Some(entry) if entry.size != content.len() as u64 => report.problems.push((
+                    name.clone(),
+                    Problem::SizeMismatch {
+                        expected: entry.size,
+                        actual: content.len() as u64,
+                    },
+                )),
+                Some(entry) => {
+                    // 按登记时的算法比较，配置密钥之前登记的文件仍按CRC32校验
+                    let matches = match (entry.checksum, &self.mac) {
+                        (Checksum::Crc32(crc), _) => crc32fast::hash(&content) == crc,
+                        (Checksum::Mac(mac), Some(siv)) => siv.mac(MAC_DOMAIN, &content) == mac,
+                        (Checksum::Mac(_), None) => {
+                            report.problems.push((name.clone(), Problem::Unverifiable));
+                            true
+                        }
+                    };
+                    if !matches {
+                        report.problems.push((name.clone(), Problem::ChecksumMismatch));
+                    }
+                }
+            }
+            if deep {
+                if let Err(e) = storage::read_table_file(&path, crypto(&name).as_ref()) {
+                    report.problems.push((name, Problem::Unreadable(e.to_string())));
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// 数据目录中的表文件：(文件名, 路径)
+fn table_files(data_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(data_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "db") {
+            if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                files.push((name.to_string(), path.clone()));
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_detects_outside_modification() {
+        let dir = temp_dir("manifest");
+        let mac = Siv::derive(&Crypto::generate_key()).unwrap();
+        let manifest = Manifest::open(dir.path(), Some(mac.clone())).unwrap();
+
+        let content = storage::write_table_file(&dir.path().join("users.db"), &Default::default(), None, Default::default()).unwrap();
+        manifest.record("users.db", &content).unwrap();
+        assert!(manifest.verify(dir.path(), &|_| None, true).unwrap().is_ok());
+
+        // 在数据库之外改写文件并伪造一个同样大小的内容
+        let mut tampered = content.clone();
+        tampered[0] ^= 1;
+        std::fs::write(dir.path().join("users.db"), &tampered).unwrap();
+        std::fs::write(dir.path().join("extra.db"), b"").unwrap();
+
+        let report = Manifest::open(dir.path(), Some(mac)).unwrap().verify(dir.path(), &|_| None, Default::default()).unwrap();
+        assert_eq!(
+            report.problems,
+            vec![
+                ("extra.db".to_string(), Problem::Untracked),
+                ("users.db".to_string(), Problem::ChecksumMismatch),
+            ]
+        );
+
+    }
+}
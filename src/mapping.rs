@@ -0,0 +1,216 @@
+//! 导入导出的字段映射：重命名、类型转换、默认值和丢弃字段
+//!
+//! 列名与表结构不一致的CSV或JSONL不需要先用脚本预处理，导出时也可以按下游的要求改写字段。
+
+use serde::{Deserialize, Serialize};
+use indexmap::IndexMap;
+use std::collections::BTreeMap;
+
+use crate::api::DatabaseServer;
+use crate::error::{DatabaseError, Result};
+use crate::storage::Value;
+
+/// 字段值转换成的类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Cast {
+    String,
+    Int,
+    Float,
+    /// `true`/`false`、`1`/`0`、`yes`/`no`，不区分大小写
+    Bool,
+}
+
+impl Cast {
+    fn name(self) -> &'static str {
+        match self {
+            Cast::String => "string",
+            Cast::Int => "int",
+            Cast::Float => "float",
+            Cast::Bool => "bool",
+        }
+    }
+
+    /// 转换一个值，null保持不变
+    pub fn apply(self, value: Value) -> Result<Value> {
+        let converted = match (self, &value) {
+            (_, Value::Null) => Some(Value::Null),
+            (Cast::String, Value::String(_)) | (Cast::Int, Value::Int(_)) | (Cast::Float, Value::Float(_)) | (Cast::Bool, Value::Bool(_)) => {
+                Some(value.clone())
+            }
+            (Cast::String, Value::Int(_) | Value::Float(_) | Value::Bool(_)) => {
+                Some(Value::String(DatabaseServer::value_to_json(&value).to_string()))
+            }
+            (Cast::Int, Value::Float(f)) if f.fract() == 0.0 && f.abs() < i64::MAX as f64 => Some(Value::Int(*f as i64)),
+            (Cast::Int, Value::Bool(b)) => Some(Value::Int(i64::from(*b))),
+            (Cast::Int, Value::String(s)) => s.trim().parse().ok().map(Value::Int),
+            (Cast::Float, Value::Int(i)) => Some(Value::Float(*i as f64)),
+            (Cast::Float, Value::String(s)) => s.trim().parse().ok().filter(|f: &f64| f.is_finite()).map(Value::Float),
+            (Cast::Bool, Value::Int(0)) => Some(Value::Bool(false)),
+            (Cast::Bool, Value::Int(1)) => Some(Value::Bool(true)),
+            (Cast::Bool, Value::String(s)) => match s.trim().to_ascii_lowercase().as_str() {
+                "true" | "1" | "yes" => Some(Value::Bool(true)),
+                "false" | "0" | "no" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            _ => None,
+        };
+        converted.ok_or_else(|| {
+            DatabaseError::DataFormat(format!("无法把 {} 转换为{}", DatabaseServer::value_to_json(&value), self.name()))
+        })
+    }
+}
+
+/// 一个字段的映射规则
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FieldRule {
+    /// 改用的字段名
+    pub rename: Option<String>,
+    pub cast: Option<Cast>,
+    /// 字段缺失、为null或为空字符串时使用的值，同样经过类型转换
+    pub default: Option<serde_json::Value>,
+    /// 丢弃该字段
+    pub drop: bool,
+}
+
+/// 声明式的字段映射，如`{"fields": {"Full Name": {"rename": "name"}, "Age": {"rename": "age", "cast": "int"}}}`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FieldMapping {
+    /// 按源字段名（导入时为CSV列名或JSON键，导出时为表中的字段名）配置的规则
+    pub fields: BTreeMap<String, FieldRule>,
+    /// 丢弃没有配置规则的字段
+    pub drop_unmapped: bool,
+}
+
+impl FieldMapping {
+    /// 从JSON解析映射
+    pub fn parse(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(|e| DatabaseError::DataFormat(format!("字段映射无效: {}", e)))
+    }
+
+    /// 按映射改写一条记录的数据，字段保持原来的顺序，缺失而有默认值的字段排在最后；
+    /// 有规则的字段优先于同名的未映射字段
+    pub fn apply(&self, data: IndexMap<String, Value>) -> Result<IndexMap<String, Value>> {
+        let mut mapped = IndexMap::with_capacity(data.len());
+        for (field, value) in data {
+            match self.fields.get(&field) {
+                Some(rule) => {
+                    if let Some(value) = Self::map_value(&field, rule, Some(value))? {
+                        mapped.insert(rule.rename.clone().unwrap_or(field), value);
+                    }
+                }
+                None if self.drop_unmapped => {}
+                None => {
+                    mapped.entry(field).or_insert(value);
+                }
+            }
+        }
+        for (field, rule) in &self.fields {
+            let target = rule.rename.as_ref().unwrap_or(field);
+            if !mapped.contains_key(target) {
+                if let Some(value) = Self::map_value(field, rule, None)? {
+                    mapped.insert(target.clone(), value);
+                }
+            }
+        }
+        Ok(mapped)
+    }
+
+    /// 按规则转换一个字段的值，丢弃的字段和缺失且没有默认值的字段返回None
+    fn map_value(field: &str, rule: &FieldRule, value: Option<Value>) -> Result<Option<Value>> {
+        if rule.drop {
+            return Ok(None);
+        }
+        let value = match value {
+            Some(Value::Null) | None => None,
+            Some(Value::String(s)) if s.is_empty() => None,
+            value => value,
+        };
+        let Some(value) = value.or_else(|| rule.default.clone().map(DatabaseServer::convert_json_value)) else {
+            return Ok(None);
+        };
+        match rule.cast {
+            Some(cast) => cast
+                .apply(value)
+                .map(Some)
+                .map_err(|e| DatabaseError::DataFormat(format!("字段 {}: {}", field, e))),
+            None => Ok(Some(value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{open, request, serve};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_field_mapping() {
+        let mapping = FieldMapping::parse(
+            r#"{"fields": {
+                "Full Name": {"rename": "name"},
+                "Age": {"rename": "age", "cast": "int"},
+                "Active": {"rename": "active", "cast": "bool", "default": "no"},
+                "Notes": {"drop": true}
+            }}"#,
+        )
+        .unwrap();
+        let row = |age: &str| {
+            IndexMap::from([
+                ("Full Name".to_string(), Value::String("张三".to_string())),
+                ("Age".to_string(), Value::String(age.to_string())),
+                ("Notes".to_string(), Value::String("内部备注".to_string())),
+                ("city".to_string(), Value::String("北京".to_string())),
+            ])
+        };
+        let data = mapping.apply(row(" 30 ")).unwrap();
+        assert_eq!(
+            data,
+            IndexMap::from([
+                ("name".to_string(), Value::String("张三".to_string())),
+                ("age".to_string(), Value::Int(30)),
+                ("active".to_string(), Value::Bool(false)),
+                ("city".to_string(), Value::String("北京".to_string())),
+            ])
+        );
+        assert!(mapping.apply(row("三十")).unwrap_err().to_string().contains("Age"));
+        let strict = FieldMapping {
+            drop_unmapped: true,
+            ..mapping
+        };
+        assert!(!strict.apply(row("30")).unwrap().contains_key("city"));
+        assert!(FieldMapping::parse(r#"{"fields": {"a": {"rename": "b", "typo": 1}}}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_import_and_export_with_mapping() {
+        let (_dir, db) = open("mapping");
+        let db = Arc::new(db);
+        let (address, serving) = serve(Arc::clone(&db));
+        let encode = |json: &str| json.bytes().map(|b| format!("%{:02X}", b)).collect::<String>();
+
+        let mapping = encode(r#"{"fields": {"Full Name": {"rename": "name"}, "Age": {"rename": "age", "cast": "int"}}}"#);
+        let csv = "Full Name,Age,City\r\n张三,30,北京\r\n\"李, 四\",4x,上海\r\n王五,,\"上\n\"\"海\"\"\"\r\n";
+        let response = request(address, &format!("POST /api/tables/users/import?format=csv&mapping={} HTTP/1.1", mapping), csv).await;
+        // 第3行的年龄无法转换为整数
+        assert!(response.contains(r#""inserted":2"#), "{}", response);
+        assert!(response.contains(r#""line":3"#), "{}", response);
+        let users = db.find_all("users").unwrap();
+        let zhang = users.iter().find(|user| user.data["name"] == Value::String("张三".to_string())).unwrap();
+        assert_eq!((&zhang.data["age"], &zhang.data["City"]), (&Value::Int(30), &Value::String("北京".to_string())));
+        // 空单元格视为缺失的字段，引号中可以有逗号、引号和换行
+        let wang = users.iter().find(|user| user.data["name"] == Value::String("王五".to_string())).unwrap();
+        assert_eq!(wang.data.len(), 2);
+        assert_eq!(wang.data["City"], Value::String("上\n\"海\"".to_string()));
+
+        let mapping = encode(r#"{"fields": {"name": {"rename": "姓名"}}, "drop_unmapped": true}"#);
+        let response = request(address, &format!("GET /api/tables/users/export?mapping={} HTTP/1.1", mapping), "").await;
+        assert!(response.contains(r#""姓名":"张三""#), "{}", response);
+        assert!(!response.contains("age"), "{}", response);
+        serving.abort();
+        let _ = serving.await;
+    }
+}
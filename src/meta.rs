@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::error::{DatabaseError, Result};
+use crate::index::IndexKind;
+use crate::policy::Policy;
+use crate::query::Condition;
+use crate::quota::{Quota, QuotaPolicy};
+use crate::schema::{FieldType, TableMode};
+use crate::timeseries::TimeSeries;
+
+/// 表元数据文件的扩展名；扩展名不是`.db`，不会被当作表加载
+pub const META_EXTENSION: &str = "meta";
+
+/// 随表保存的元数据，以JSON保存在表文件旁的`<表名>.meta`中
+///
+/// 新增的字段都带默认值，旧版本写出的元数据文件仍可读取。
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TableMeta {
+    /// 固定大小表的上限
+    pub capped: Option<Cap>,
+    /// 时间序列表的时间戳字段和分段跨度
+    pub time_series: Option<TimeSeries>,
+    /// 保存记录过期时间（Unix秒）的字段
+    pub ttl_field: Option<String>,
+    /// 字符串排序规则的名称，None为按字节比较
+    pub collation: Option<String>,
+    /// 行级安全策略
+    pub policy: Option<Policy>,
+    /// 不小于该字节数的字符串和字节串值压缩保存，None为不压缩
+    pub value_compression: Option<usize>,
+    /// 按字段声明的类型
+    pub field_types: BTreeMap<String, FieldType>,
+    /// 严格模式下只能写入声明了类型的字段
+    pub mode: TableMode,
+    /// 索引定义，索引本身在打开表时按定义重建
+    pub indexes: Vec<IndexDef>,
+}
+
+/// 一个索引的定义
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexDef {
+    pub kind: IndexKind,
+    /// 索引的字段，多字段唯一索引为逗号连接的字段名
+    pub field: String,
+    /// 部分索引的过滤条件，为空时索引所有记录
+    #[serde(default)]
+    pub filter: Vec<Condition>,
+}
+
+impl IndexDef {
+    pub fn new(kind: IndexKind, field: impl Into<String>, filter: Vec<Condition>) -> Self {
+        Self {
+            kind,
+            field: field.into(),
+            filter,
+        }
+    }
+}
+
+/// 固定大小表的上限，插入时超出则自动删除最早插入的记录，为None的项不限制
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cap {
+    pub max_records: Option<usize>,
+    /// 按记录序列化后的大小累计
+    pub max_bytes: Option<usize>,
+}
+
+impl Cap {
+    pub fn records(max_records: usize) -> Self {
+        Self {
+            max_records: Some(max_records),
+            max_bytes: None,
+        }
+    }
+
+    pub fn bytes(max_bytes: usize) -> Self {
+        Self {
+            max_records: None,
+            max_bytes: Some(max_bytes),
+        }
+    }
+
+    /// 等价的配额：超出时淘汰最早的记录
+    pub fn quota(&self) -> Quota {
+        Quota {
+            max_records: self.max_records,
+            max_bytes: self.max_bytes,
+            policy: QuotaPolicy::EvictOldest,
+        }
+    }
+}
+
+impl TableMeta {
+    /// 读取元数据文件，不存在时为默认值
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read(path)?;
+        serde_json::from_slice(&content)
+            .map_err(|e| DatabaseError::DataFormat(format!("表元数据无效: {}", e)))
+    }
+
+    /// 写入元数据文件，全部为默认值时删除文件
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if *self == Self::default() {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+        let content = serde_json::to_vec_pretty(self)
+            .map_err(|e| DatabaseError::DataFormat(format!("无法写入表元数据: {}", e)))?;
+        let tmp = path.with_extension("meta.tmp");
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::api::DatabaseServer;
+use crate::datetime;
+use crate::storage::{Record, Value};
+
+/// 命令行输出格式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// 按列对齐的表格
+    #[default]
+    Table,
+    /// JSON数组
+    Json,
+    /// 每行一个JSON对象
+    Jsonl,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" | "ndjson" => Ok(OutputFormat::Jsonl),
+            "csv" => Ok(OutputFormat::Csv),
+            other => Err(format!("不支持的输出格式: {}（可选 table、json、jsonl、csv）", other)),
+        }
+    }
+}
+
+/// 输出记录列表，JSON格式与HTTP API一致，CSV格式与导出接口一致
+pub fn render_records(records: &[Arc<Record>], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Json => {
+            let records: Vec<serde_json::Value> = records.iter().map(|r| DatabaseServer::convert_record_to_json(r)).collect();
+            let mut out = serde_json::to_string_pretty(&records).unwrap_or_default();
+            out.push('\n');
+            out
+        }
+        OutputFormat::Jsonl => records
+            .iter()
+            .map(|r| format!("{}\n", DatabaseServer::convert_record_to_json(r)))
+            .collect(),
+        OutputFormat::Csv => {
+            let columns = DatabaseServer::csv_columns(records);
+            let header = DatabaseServer::csv_line(
+                ["id", "created_at", "updated_at"].into_iter().chain(columns.iter().map(String::as_str)),
+            );
+            std::iter::once(header)
+                .chain(records.iter().map(|r| DatabaseServer::csv_record(r, &columns)))
+                .collect()
+        }
+        OutputFormat::Table => {
+            let fields = DatabaseServer::csv_columns(records);
+            let columns: Vec<&str> = ["id", "created_at", "updated_at"]
+                .into_iter()
+                .chain(fields.iter().map(String::as_str))
+                .collect();
+            let rows: Vec<Vec<String>> = records
+                .iter()
+                .map(|record| {
+                    [record.id.clone(), record.created_at.to_string(), record.updated_at.to_string()]
+                        .into_iter()
+                        .chain(fields.iter().map(|f| cell(record.data.get(f).unwrap_or(&Value::Null))))
+                        .collect()
+                })
+                .collect();
+            table(&columns, &rows)
+        }
+    }
+}
+
+/// 输出任意行列数据，如表列表或写操作的结果
+pub fn render_rows(columns: &[&str], rows: &[Vec<Value>], format: OutputFormat) -> String {
+    let object = |row: &Vec<Value>| -> serde_json::Value {
+        columns
+            .iter()
+            .zip(row)
+            .map(|(column, value)| (column.to_string(), DatabaseServer::value_to_json(value)))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    };
+    match format {
+        OutputFormat::Json => {
+            let rows: Vec<serde_json::Value> = rows.iter().map(object).collect();
+            let mut out = serde_json::to_string_pretty(&rows).unwrap_or_default();
+            out.push('\n');
+            out
+        }
+        OutputFormat::Jsonl => rows.iter().map(|row| format!("{}\n", object(row))).collect(),
+        OutputFormat::Csv => std::iter::once(DatabaseServer::csv_line(columns.iter().copied()))
+            .chain(rows.iter().map(|row| {
+                let cells: Vec<String> = row.iter().map(cell).collect();
+                DatabaseServer::csv_line(cells.iter().map(String::as_str))
+            }))
+            .collect(),
+        OutputFormat::Table => {
+            let rows: Vec<Vec<String>> = rows.iter().map(|row| row.iter().map(cell).collect()).collect();
+            table(columns, &rows)
+        }
+    }
+}
+
+/// 单元格中的文本：字符串原样输出，NULL为空，复杂类型输出JSON
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Timestamp(millis) => datetime::format(*millis),
+        other => DatabaseServer::value_to_json(other).to_string(),
+    }
+}
+
+/// 渲染按列对齐的表格
+fn table(columns: &[&str], rows: &[Vec<String>]) -> String {
+    // 换行会破坏对齐，以转义形式显示
+    let rows: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| row.iter().map(|c| c.replace('\r', "\\r").replace('\n', "\\n")).collect())
+        .collect();
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            rows.iter()
+                .map(|row| display_width(&row[i]))
+                .chain(std::iter::once(display_width(column)))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let line = |cells: &mut dyn Iterator<Item = &str>| -> String {
+        let padded: Vec<String> = cells
+            .zip(&widths)
+            .map(|(cell, width)| format!("{}{}", cell, " ".repeat(width - display_width(cell))))
+            .collect();
+        format!("{}\n", padded.join(" | ").trim_end())
+    };
+
+    let mut out = line(&mut columns.iter().copied());
+    out.push_str(&widths.iter().map(|w| "-".repeat(*w)).collect::<Vec<_>>().join("-+-"));
+    out.push('\n');
+    for row in &rows {
+        out.push_str(&line(&mut row.iter().map(String::as_str)));
+    }
+    out
+}
+
+/// 终端中的显示宽度，中日韩文字和全角符号占两列
+fn display_width(s: &str) -> usize {
+    s.chars()
+        .map(|c| match c as u32 {
+            0x1100..=0x115F
+            | 0x2E80..=0x303E
+            | 0x3041..=0x33FF
+            | 0x3400..=0x4DBF
+            | 0x4E00..=0x9FFF
+            | 0xA000..=0xA4CF
+            | 0xAC00..=0xD7A3
+            | 0xF900..=0xFAFF
+            | 0xFE30..=0xFE4F
+            | 0xFF00..=0xFF60
+            | 0xFFE0..=0xFFE6
+            | 0x20000..=0x3FFFD => 2,
+            _ => 1,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_alignment() {
+        let rows = vec![
+            vec![Value::String("张三".to_string()), Value::Int(25)],
+            vec![Value::String("bob".to_string()), Value::Null],
+        ];
+        let out = render_rows(&["name", "age"], &rows, OutputFormat::Table);
+        assert_eq!(out, "name | age\n-----+----\n张三 | 25\nbob  |\n");
+
+        let out = render_rows(&["name", "age"], &rows, OutputFormat::Csv);
+        assert_eq!(out, "name,age\r\n张三,25\r\nbob,\r\n");
+        assert_eq!("jsonl".parse::<OutputFormat>(), Ok(OutputFormat::Jsonl));
+    }
+}
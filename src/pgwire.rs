@@ -0,0 +1,563 @@
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::TcpListener;
+
+use crate::api::DatabaseServer;
+use crate::database::SimpleDB;
+use crate::datetime;
+use crate::error::{DatabaseError, Result};
+use crate::schema::FieldType;
+use crate::sql::{SqlColumn, SqlResult};
+use crate::storage::Value;
+
+/// SSL协商请求的协议号
+const SSL_REQUEST_CODE: i32 = 80877103;
+/// 协议版本3.0
+const PROTOCOL_VERSION: i32 = 196608;
+/// 单条消息的最大长度
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// 列类型的OID
+const INT8_OID: i32 = 20;
+const FLOAT8_OID: i32 = 701;
+const BOOL_OID: i32 = 16;
+const TEXT_OID: i32 = 25;
+const TIMESTAMPTZ_OID: i32 = 1184;
+
+/// 实验性的PostgreSQL协议只读前端
+///
+/// 实现v3协议的简单查询和扩展查询流程（不支持绑定参数，支持Execute的最大行数），只接受SELECT，
+/// 所有结果以文本格式返回，Bind要求二进制格式时返回错误，不做身份验证，仅供本机的BI工具连接。
+pub struct PgServer {
+    db: Arc<SimpleDB>,
+    port: u16,
+}
+
+/// 扩展查询流程中已解析、待执行的语句
+#[derive(Default)]
+struct Portal {
+    sql: String,
+    /// Describe返回的列，执行结果按这些列返回
+    columns: Option<Vec<SqlColumn>>,
+    /// 达到Execute的最大行数而暂停时尚未返回的行
+    pending: Option<Vec<Vec<Value>>>,
+}
+
+impl PgServer {
+    pub fn new(db: Arc<SimpleDB>, port: u16) -> Self {
+        Self { db, port }
+    }
+
+    /// 启动监听
+    pub async fn start(&self) -> Result<()> {
+        let listener = TcpListener::bind(format!("127.0.0.1:{}", self.port))
+            .await
+            .map_err(DatabaseError::Io)?;
+        println!("PostgreSQL协议前端（实验性，只读）监听端口: {}", self.port);
+
+        loop {
+            if let Ok((stream, _)) = listener.accept().await {
+                let db = Arc::clone(&self.db);
+                tokio::spawn(async move {
+                    let (reader, writer) = tokio::io::split(stream);
+                    let _ = Self::serve(&db, reader, BufWriter::new(writer)).await;
+                });
+            }
+        }
+    }
+
+    /// 处理一个连接
+    async fn serve<R, W>(db: &SimpleDB, mut reader: R, mut writer: W) -> std::io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        // 启动阶段：拒绝SSL，接受任何用户与数据库名
+        loop {
+            let len = reader.read_i32().await? as usize;
+            if !(8..=MAX_MESSAGE_SIZE).contains(&len) {
+                return Err(invalid_data("无效的启动消息"));
+            }
+            let mut body = vec![0u8; len - 4];
+            reader.read_exact(&mut body).await?;
+            let code = i32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+            if code == SSL_REQUEST_CODE {
+                writer.write_all(b"N").await?;
+                writer.flush().await?;
+                continue;
+            }
+            if code != PROTOCOL_VERSION {
+                return Err(invalid_data("不支持的协议版本"));
+            }
+            break;
+        }
+
+        writer.write_all(&message(b'R', &0i32.to_be_bytes())).await?;
+        for (name, value) in [
+            ("server_version", "14.0 (SimpleDB)"),
+            ("server_encoding", "UTF8"),
+            ("client_encoding", "UTF8"),
+            ("DateStyle", "ISO, MDY"),
+            ("integer_datetimes", "on"),
+            ("standard_conforming_strings", "on"),
+        ] {
+            let mut body = cstring(name);
+            body.extend(cstring(value));
+            writer.write_all(&message(b'S', &body)).await?;
+        }
+        writer.write_all(&message(b'K', &[0u8; 8])).await?;
+        writer.write_all(&ready_for_query()).await?;
+        writer.flush().await?;
+
+        let mut portal = Portal::default();
+        // 扩展查询出错后忽略后续消息，直到Sync
+        let mut skip_until_sync = false;
+        loop {
+            let tag = match reader.read_u8().await {
+                Ok(tag) => tag,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let len = reader.read_i32().await? as usize;
+            if !(4..=MAX_MESSAGE_SIZE).contains(&len) {
+                return Err(invalid_data("无效的消息长度"));
+            }
+            let mut body = vec![0u8; len - 4];
+            reader.read_exact(&mut body).await?;
+
+            if skip_until_sync && tag != b'S' {
+                continue;
+            }
+            match tag {
+                // 简单查询
+                b'Q' => {
+                    let sql = read_cstring(&body);
+                    match Self::run(db, &sql) {
+                        Ok(Some(result)) => {
+                            writer.write_all(&row_description(&result_columns(&result))).await?;
+                            Self::write_rows(&mut writer, &result.rows).await?;
+                            writer.write_all(&select_complete(result.rows.len())).await?;
+                        }
+                        Ok(None) => writer.write_all(&command_complete(&sql)).await?,
+                        Err(e) => writer.write_all(&error_response(&e)).await?,
+                    }
+                    writer.write_all(&ready_for_query()).await?;
+                    writer.flush().await?;
+                }
+                // Parse：只记录语句文本
+                b'P' => {
+                    let mut parts = body.split(|&b| b == 0);
+                    parts.next();
+                    portal = Portal {
+                        sql: parts.next().map(|s| String::from_utf8_lossy(s).into_owned()).unwrap_or_default(),
+                        columns: None,
+                        pending: None,
+                    };
+                    writer.write_all(&message(b'1', &[])).await?;
+                }
+                // Bind：不支持参数，结果只支持文本格式
+                b'B' => {
+                    let mut rest = body.splitn(3, |&b| b == 0).nth(2).unwrap_or(&[]);
+                    let format_count = take_i16(&mut rest) as usize;
+                    rest = rest.get(format_count * 2..).unwrap_or(&[]);
+                    let error = if take_i16(&mut rest) != 0 {
+                        Some("不支持绑定参数")
+                    } else if (0..take_i16(&mut rest)).any(|_| take_i16(&mut rest) != 0) {
+                        Some("结果只支持文本格式")
+                    } else {
+                        None
+                    };
+                    if let Some(error) = error {
+                        writer.write_all(&error_response(&DatabaseError::InvalidQuery(error.to_string()))).await?;
+                        skip_until_sync = true;
+                        continue;
+                    }
+                    portal.pending = None;
+                    writer.write_all(&message(b'2', &[])).await?;
+                }
+                // Describe：只规划查询以获得列信息，不执行
+                b'D' => match Self::describe(db, &portal.sql) {
+                    Ok(Some(columns)) => {
+                        if body.first() == Some(&b'S') {
+                            writer.write_all(&message(b't', &0i16.to_be_bytes())).await?;
+                        }
+                        writer.write_all(&row_description(&planned_columns(&columns))).await?;
+                        portal.columns = Some(columns);
+                    }
+                    Ok(None) => writer.write_all(&message(b'n', &[])).await?,
+                    Err(e) => {
+                        writer.write_all(&error_response(&e)).await?;
+                        skip_until_sync = true;
+                    }
+                },
+                // Execute：最大行数不为0时最多返回这么多行，其余的行留给下一次Execute
+                b'E' => {
+                    let mut rest = body.splitn(2, |&b| b == 0).nth(1).unwrap_or(&[]);
+                    let max_rows = take_i32(&mut rest).max(0) as usize;
+                    let rows = match (portal.pending.take(), &portal.columns) {
+                        (Some(rows), _) => Ok(Some(rows)),
+                        (None, Some(columns)) => Self::run(db, &portal.sql).map(|result| result.map(|r| r.conform(columns).rows)),
+                        (None, None) => Self::run(db, &portal.sql).map(|result| result.map(|r| r.rows)),
+                    };
+                    match rows {
+                        Ok(Some(mut rows)) if max_rows > 0 && rows.len() > max_rows => {
+                            let rest = rows.split_off(max_rows);
+                            Self::write_rows(&mut writer, &rows).await?;
+                            writer.write_all(&message(b's', &[])).await?;
+                            portal.pending = Some(rest);
+                        }
+                        Ok(Some(rows)) => {
+                            Self::write_rows(&mut writer, &rows).await?;
+                            writer.write_all(&select_complete(rows.len())).await?;
+                        }
+                        Ok(None) => writer.write_all(&command_complete(&portal.sql)).await?,
+                        Err(e) => {
+                            writer.write_all(&error_response(&e)).await?;
+                            skip_until_sync = true;
+                        }
+                    }
+                }
+                // Close
+                b'C' => writer.write_all(&message(b'3', &[])).await?,
+                // Sync
+                b'S' => {
+                    skip_until_sync = false;
+                    writer.write_all(&ready_for_query()).await?;
+                    writer.flush().await?;
+                }
+                // Flush
+                b'H' => writer.flush().await?,
+                // Terminate
+                b'X' => return Ok(()),
+                _ => {
+                    let e = DatabaseError::InvalidQuery(format!("不支持的消息类型: {}", tag as char));
+                    writer.write_all(&error_response(&e)).await?;
+                    writer.write_all(&ready_for_query()).await?;
+                    writer.flush().await?;
+                }
+            }
+        }
+    }
+
+    /// 执行一条语句，会话控制语句（SET、BEGIN等）直接确认并返回None
+    fn run(db: &SimpleDB, sql: &str) -> Result<Option<SqlResult>> {
+        match is_session_command(sql) {
+            true => Ok(None),
+            false => db.sql(sql).map(Some),
+        }
+    }
+
+    /// 规划一条语句的结果列，会话控制语句没有结果，返回None
+    fn describe(db: &SimpleDB, sql: &str) -> Result<Option<Vec<SqlColumn>>> {
+        match is_session_command(sql) {
+            true => Ok(None),
+            false => db.describe_sql(sql).map(Some),
+        }
+    }
+
+    async fn write_rows<W: AsyncWrite + Unpin>(writer: &mut W, rows: &[Vec<Value>]) -> std::io::Result<()> {
+        for row in rows {
+            let mut body = (row.len() as i16).to_be_bytes().to_vec();
+            for value in row {
+                match text_value(value) {
+                    Some(text) => {
+                        body.extend((text.len() as i32).to_be_bytes());
+                        body.extend(text.as_bytes());
+                    }
+                    None => body.extend((-1i32).to_be_bytes()),
+                }
+            }
+            writer.write_all(&message(b'D', &body)).await?;
+        }
+        Ok(())
+    }
+}
+
+/// 构造一条后端消息：类型字节 + 长度 + 内容
+fn message(tag: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 5);
+    out.push(tag);
+    out.extend(((body.len() + 4) as i32).to_be_bytes());
+    out.extend(body);
+    out
+}
+
+fn cstring(s: &str) -> Vec<u8> {
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+fn read_cstring(body: &[u8]) -> String {
+    let end = body.iter().position(|&b| b == 0).unwrap_or(body.len());
+    String::from_utf8_lossy(&body[..end]).into_owned()
+}
+
+fn take_i16(bytes: &mut &[u8]) -> i16 {
+    match bytes {
+        [a, b, rest @ ..] => {
+            let value = i16::from_be_bytes([*a, *b]);
+            *bytes = rest;
+            value
+        }
+        _ => 0,
+    }
+}
+
+/// SET、BEGIN等会话控制语句和空语句
+fn is_session_command(sql: &str) -> bool {
+    let first = sql.split_whitespace().next().unwrap_or("").to_ascii_uppercase();
+    matches!(
+        first.trim_end_matches(';'),
+        "SET" | "BEGIN" | "COMMIT" | "ROLLBACK" | "DISCARD" | "DEALLOCATE" | ""
+    )
+}
+
+fn take_i32(bytes: &mut &[u8]) -> i32 {
+    match bytes {
+        [a, b, c, d, rest @ ..] => {
+            let value = i32::from_be_bytes([*a, *b, *c, *d]);
+            *bytes = rest;
+            value
+        }
+        _ => 0,
+    }
+}
+
+fn select_complete(rows: usize) -> Vec<u8> {
+    message(b'C', &cstring(&format!("SELECT {}", rows)))
+}
+
+fn ready_for_query() -> Vec<u8> {
+    message(b'Z', b"I")
+}
+
+fn command_complete(sql: &str) -> Vec<u8> {
+    let tag = sql.split_whitespace().next().unwrap_or("").trim_end_matches(';').to_ascii_uppercase();
+    if tag.is_empty() {
+        return message(b'I', &[]);
+    }
+    message(b'C', &cstring(&tag))
+}
+
+fn error_response(error: &DatabaseError) -> Vec<u8> {
+    let code = match error {
+        DatabaseError::TableNotFound(_) => "42P01",
+        DatabaseError::InvalidQuery(_) => "42601",
+        _ => "XX000",
+    };
+    let mut body = Vec::new();
+    for (field, value) in [(b'S', "ERROR"), (b'C', code), (b'M', &error.to_string())] {
+        body.push(field);
+        body.extend(cstring(value));
+    }
+    body.push(0);
+    message(b'E', &body)
+}
+
+/// 简单查询的列类型：列中所有非NULL值类型一致时使用对应的数值或布尔类型，否则为文本
+fn result_columns(result: &SqlResult) -> Vec<(&str, i32)> {
+    let mut columns = Vec::new();
+    for (i, column) in result.columns.iter().enumerate() {
+        let mut oids = result.rows.iter().filter_map(|row| match &row[i] {
+            Value::Null => None,
+            Value::Int(_) => Some(INT8_OID),
+            Value::Float(_) => Some(FLOAT8_OID),
+            Value::Bool(_) => Some(BOOL_OID),
+            Value::Timestamp(_) => Some(TIMESTAMPTZ_OID),
+            _ => Some(TEXT_OID),
+        });
+        let first = oids.next().unwrap_or(TEXT_OID);
+        columns.push((column.as_str(), if oids.all(|oid| oid == first) { first } else { TEXT_OID }));
+    }
+    columns
+}
+
+/// 扩展查询的列类型：执行前能确定类型的列使用对应的类型，否则为文本
+fn planned_columns(columns: &[SqlColumn]) -> Vec<(&str, i32)> {
+    columns
+        .iter()
+        .map(|column| {
+            let oid = match column.field_type {
+                Some(FieldType::Int) => INT8_OID,
+                Some(FieldType::Float) => FLOAT8_OID,
+                Some(FieldType::Bool) => BOOL_OID,
+                Some(FieldType::DateTime) => TIMESTAMPTZ_OID,
+                _ => TEXT_OID,
+            };
+            (column.name.as_str(), oid)
+        })
+        .collect()
+}
+
+fn row_description(columns: &[(&str, i32)]) -> Vec<u8> {
+    let mut body = (columns.len() as i16).to_be_bytes().to_vec();
+    for (column, oid) in columns {
+        body.extend(cstring(column));
+        body.extend(0i32.to_be_bytes()); // 表OID
+        body.extend(0i16.to_be_bytes()); // 列序号
+        body.extend(oid.to_be_bytes());
+        body.extend((-1i16).to_be_bytes()); // 类型长度
+        body.extend((-1i32).to_be_bytes()); // 类型修饰
+        body.extend(0i16.to_be_bytes()); // 文本格式
+    }
+    message(b'T', &body)
+}
+
+/// 值的文本表示，NULL返回None
+fn text_value(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::Bool(b) => Some(if *b { "t" } else { "f" }.to_string()),
+        Value::Int(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        Value::String(s) => Some(s.clone()),
+        Value::Bytes(bytes) => Some(format!("\\x{}", hex::encode(bytes))),
+        Value::GeoPoint { lat, lon } => Some(format!("({},{})", lat, lon)),
+        Value::Vector(v) => Some(format!("{:?}", v)),
+        Value::Timestamp(millis) => Some(datetime::format(*millis).replacen('T', " ", 1).replace('Z', "+00")),
+        Value::Array(_) | Value::Object(_) | Value::Sealed { .. } | Value::Deterministic { .. } | Value::Ref { .. }
+        | Value::Param(_) | Value::Compressed { .. } | Value::Blob { .. } => Some(DatabaseServer::value_to_json(value).to_string()),
+    }
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::open;
+    use indexmap::IndexMap;
+    use tokio::net::TcpStream;
+
+    /// 连接一个只处理一个连接的前端并完成启动
+    async fn connect(db: Arc<SimpleDB>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, writer) = tokio::io::split(stream);
+            let _ = PgServer::serve(&db, reader, BufWriter::new(writer)).await;
+        });
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut body = PROTOCOL_VERSION.to_be_bytes().to_vec();
+        body.extend(cstring("user"));
+        body.extend(cstring("test"));
+        body.push(0);
+        stream.write_all(&((body.len() + 4) as i32).to_be_bytes()).await.unwrap();
+        stream.write_all(&body).await.unwrap();
+        assert_eq!(tags(&until_ready(&mut stream).await).first(), Some(&b'R'));
+        stream
+    }
+
+    /// 读取后端消息直到ReadyForQuery
+    async fn until_ready(stream: &mut TcpStream) -> Vec<(u8, Vec<u8>)> {
+        let mut messages = Vec::new();
+        loop {
+            let tag = stream.read_u8().await.unwrap();
+            let len = stream.read_i32().await.unwrap() as usize;
+            let mut body = vec![0u8; len - 4];
+            stream.read_exact(&mut body).await.unwrap();
+            messages.push((tag, body));
+            if tag == b'Z' {
+                return messages;
+            }
+        }
+    }
+
+    fn tags(messages: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        messages.iter().map(|(tag, _)| *tag).collect()
+    }
+
+    /// DataRow中各列的文本值
+    fn data_rows(messages: &[(u8, Vec<u8>)]) -> Vec<Vec<Option<String>>> {
+        messages
+            .iter()
+            .filter(|(tag, _)| *tag == b'D')
+            .map(|(_, body)| {
+                let mut rest = &body[..];
+                (0..take_i16(&mut rest))
+                    .map(|_| match take_i32(&mut rest) {
+                        -1 => None,
+                        len => {
+                            let (text, tail) = rest.split_at(len as usize);
+                            rest = tail;
+                            Some(String::from_utf8(text.to_vec()).unwrap())
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    async fn send(stream: &mut TcpStream, tag: u8, body: &[u8]) {
+        stream.write_all(&message(tag, body)).await.unwrap();
+    }
+
+    /// Bind消息：无参数，`formats`为结果列的格式代码
+    fn bind(formats: &[i16]) -> Vec<u8> {
+        let mut body = vec![0, 0, 0, 0, 0, 0];
+        body.extend((formats.len() as i16).to_be_bytes());
+        for format in formats {
+            body.extend(format.to_be_bytes());
+        }
+        body
+    }
+
+    fn execute(max_rows: i32) -> Vec<u8> {
+        let mut body = cstring("");
+        body.extend(max_rows.to_be_bytes());
+        body
+    }
+
+    #[tokio::test]
+    async fn test_simple_and_extended_query() {
+        let (_dir, db) = open("pgwire");
+        for (name, age) in [("alice", 30), ("bob", 25), ("carol", 35)] {
+            let data = IndexMap::from([
+                ("name".to_string(), Value::String(name.to_string())),
+                ("age".to_string(), Value::Int(age)),
+            ]);
+            db.insert("users", data).unwrap();
+        }
+        let mut stream = connect(Arc::new(db)).await;
+
+        // 简单查询
+        send(&mut stream, b'Q', &cstring("SELECT name, age FROM users ORDER BY age")).await;
+        let messages = until_ready(&mut stream).await;
+        assert_eq!(tags(&messages), b"TDDDCZ");
+        assert_eq!(data_rows(&messages)[0], vec![Some("bob".to_string()), Some("25".to_string())]);
+        send(&mut stream, b'Q', &cstring("SET extra_float_digits = 3")).await;
+        assert_eq!(tags(&until_ready(&mut stream).await), b"CZ");
+        send(&mut stream, b'Q', &cstring("SELECT name FROM missing")).await;
+        assert_eq!(tags(&until_ready(&mut stream).await), b"EZ");
+
+        // 扩展查询：按最大行数分两次取回结果
+        let mut parse = cstring("");
+        parse.extend(cstring("SELECT name FROM users ORDER BY age"));
+        parse.extend(0i16.to_be_bytes());
+        send(&mut stream, b'P', &parse).await;
+        send(&mut stream, b'B', &bind(&[0])).await;
+        send(&mut stream, b'D', b"P\0").await;
+        send(&mut stream, b'E', &execute(2)).await;
+        send(&mut stream, b'E', &execute(0)).await;
+        send(&mut stream, b'S', &[]).await;
+        let messages = until_ready(&mut stream).await;
+        assert_eq!(tags(&messages), b"12TDDsDCZ");
+        let names: Vec<_> = data_rows(&messages).into_iter().map(|row| row[0].clone().unwrap()).collect();
+        assert_eq!(names, vec!["bob", "alice", "carol"]);
+
+        // 要求二进制格式的结果时返回错误，忽略到Sync为止的消息
+        send(&mut stream, b'P', &parse).await;
+        send(&mut stream, b'B', &bind(&[1])).await;
+        send(&mut stream, b'E', &execute(0)).await;
+        send(&mut stream, b'S', &[]).await;
+        let messages = until_ready(&mut stream).await;
+        assert_eq!(tags(&messages), b"1EZ");
+        assert!(String::from_utf8_lossy(&messages[1].1).contains("文本格式"));
+
+        send(&mut stream, b'X', &[]).await;
+    }
+}
@@ -0,0 +1,256 @@
+use indexmap::IndexMap;
+use std::collections::HashMap;
+
+use crate::database::SimpleDB;
+use crate::error::{DatabaseError, Result};
+use crate::index::FieldIndex;
+use crate::query::{compare_field, Condition, Query, SortOrder};
+use crate::sql::{Aggregate, OverflowPolicy};
+use crate::storage::Value;
+use crate::system;
+
+/// 管道中流动的文档：记录的字段加上系统字段`id`、`created_at`和`updated_at`，或上一阶段的输出
+pub type Document = IndexMap<String, Value>;
+
+/// 分组阶段中的一个聚合输出
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accumulator {
+    /// 输出字段名
+    pub name: String,
+    pub func: Aggregate,
+    /// 聚合的字段，None表示统计文档数
+    pub field: Option<String>,
+}
+
+impl Accumulator {
+    pub fn new(name: &str, func: Aggregate, field: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            func,
+            field: Some(field.to_string()),
+        }
+    }
+
+    /// 统计每组的文档数
+    pub fn count(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            func: Aggregate::Count,
+            field: None,
+        }
+    }
+}
+
+/// 聚合管道的一个阶段
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stage {
+    /// 只保留满足全部条件的文档
+    Match(Vec<Condition>),
+    /// 只输出列出的字段：(输出名, 来源字段)
+    Project(Vec<(String, String)>),
+    /// 按字段分组，输出分组字段和各聚合结果；`by`为空时整体作为一组
+    Group { by: Vec<String>, accumulators: Vec<Accumulator> },
+    Sort(Vec<(String, SortOrder)>),
+    Skip(usize),
+    Limit(usize),
+}
+
+/// 多阶段聚合管道，依次执行各阶段
+///
+/// 开头的`Match`阶段合并为一个查询交给查询引擎，可以利用索引；之后的阶段在内存中处理。
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pipeline {
+    pub stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stage(mut self, stage: Stage) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    pub fn filter(self, condition: Condition) -> Self {
+        self.stage(Stage::Match(vec![condition]))
+    }
+
+    /// 只保留列出的字段
+    pub fn project<'a>(self, fields: impl IntoIterator<Item = &'a str>) -> Self {
+        self.stage(Stage::Project(fields.into_iter().map(|f| (f.to_string(), f.to_string())).collect()))
+    }
+
+    pub fn group<'a>(self, by: impl IntoIterator<Item = &'a str>, accumulators: Vec<Accumulator>) -> Self {
+        self.stage(Stage::Group {
+            by: by.into_iter().map(str::to_string).collect(),
+            accumulators,
+        })
+    }
+
+    pub fn sort(self, field: &str, order: SortOrder) -> Self {
+        self.stage(Stage::Sort(vec![(field.to_string(), order)]))
+    }
+
+    pub fn skip(self, n: usize) -> Self {
+        self.stage(Stage::Skip(n))
+    }
+
+    pub fn limit(self, n: usize) -> Self {
+        self.stage(Stage::Limit(n))
+    }
+
+    /// 在表上执行管道
+    pub fn execute(&self, db: &SimpleDB, table: &str) -> Result<Vec<Document>> {
+        let leading = self.stages.iter().take_while(|s| matches!(s, Stage::Match(_))).count();
+        let mut query = Query::new();
+        for stage in &self.stages[..leading] {
+            if let Stage::Match(conditions) = stage {
+                query.conditions.extend(conditions.iter().cloned());
+            }
+        }
+        let mut documents: Vec<Document> = db
+            .query(table, &query)?
+            .iter()
+            .map(|record| {
+                let mut document = record.data.clone();
+                for field in system::SYSTEM_FIELDS {
+                    document.extend(record.field(field).map(|value| (field.to_string(), value.into_owned())));
+                }
+                document
+            })
+            .collect();
+
+        for stage in &self.stages[leading..] {
+            documents = apply(stage, documents, db.config().aggregate_overflow)?;
+        }
+        Ok(documents)
+    }
+}
+
+fn apply(stage: &Stage, mut documents: Vec<Document>, overflow: OverflowPolicy) -> Result<Vec<Document>> {
+    match stage {
+        Stage::Match(conditions) => {
+            documents.retain(|doc| conditions.iter().all(|c| c.matches_value(doc.get(&c.field))));
+        }
+        Stage::Project(fields) => {
+            for doc in documents.iter_mut() {
+                *doc = fields
+                    .iter()
+                    .filter_map(|(name, source)| Some((name.clone(), doc.get(source)?.clone())))
+                    .collect();
+            }
+        }
+        Stage::Group { by, accumulators } => {
+            if accumulators.iter().any(|a| by.contains(&a.name)) {
+                return Err(DatabaseError::InvalidQuery("聚合输出不能与分组字段同名".to_string()));
+            }
+            // 按分组键首次出现的顺序输出
+            let mut groups: Vec<(Vec<Value>, Vec<&Document>)> = Vec::new();
+            let mut positions: HashMap<Vec<u8>, usize> = HashMap::new();
+            for doc in &documents {
+                let key: Vec<Value> = by.iter().map(|f| doc.get(f).cloned().unwrap_or(Value::Null)).collect();
+                let position = *positions.entry(FieldIndex::key(&Value::Array(key.clone()))).or_insert_with(|| {
+                    groups.push((key, Vec::new()));
+                    groups.len() - 1
+                });
+                groups[position].1.push(doc);
+            }
+            if groups.is_empty() && by.is_empty() {
+                groups.push((Vec::new(), Vec::new()));
+            }
+
+            return groups
+                .into_iter()
+                .map(|(key, members)| {
+                    let mut output: Document = by.iter().cloned().zip(key).collect();
+                    for acc in accumulators {
+                        let values = acc.field.as_ref().map(|field| {
+                            members.iter().map(|doc| doc.get(field).cloned().unwrap_or(Value::Null)).collect()
+                        });
+                        output.insert(acc.name.clone(), acc.func.apply(members.len(), values, overflow)?);
+                    }
+                    Ok(output)
+                })
+                .collect();
+        }
+        Stage::Sort(order_by) => {
+            documents.sort_by(|a, b| {
+                order_by
+                    .iter()
+                    .map(|(field, order)| {
+                        let ordering = compare_field(a.get(field), b.get(field));
+                        match order {
+                            SortOrder::Asc => ordering,
+                            SortOrder::Desc => ordering.reverse(),
+                        }
+                    })
+                    .find(|o| o.is_ne())
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+        }
+        Stage::Skip(n) => {
+            documents.drain(..(*n).min(documents.len()));
+        }
+        Stage::Limit(n) => documents.truncate(*n),
+    }
+    Ok(documents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_sort_limit() {
+        let order = |status: &str, amount: i64| {
+            IndexMap::from([
+                ("status".to_string(), Value::String(status.to_string())),
+                ("amount".to_string(), Value::Int(amount)),
+            ])
+        };
+        let documents = vec![order("paid", 10), order("open", 5), order("paid", 30), order("void", 1)];
+        let stages = [
+            Stage::Match(vec![Condition::gt("amount", Value::Int(2))]),
+            Stage::Group {
+                by: vec!["status".to_string()],
+                accumulators: vec![Accumulator::new("total", Aggregate::Sum, "amount"), Accumulator::count("n")],
+            },
+            Stage::Sort(vec![("total".to_string(), SortOrder::Desc)]),
+            Stage::Limit(1),
+        ];
+        let result = stages.iter().try_fold(documents, |docs, stage| apply(stage, docs, OverflowPolicy::Widen)).unwrap();
+        assert_eq!(
+            result,
+            vec![IndexMap::from([
+                ("status".to_string(), Value::String("paid".to_string())),
+                ("total".to_string(), Value::Int(40)),
+                ("n".to_string(), Value::Int(2)),
+            ])]
+        );
+
+        // 没有文档时不分组的聚合仍输出一行
+        let empty = apply(&Stage::Group { by: Vec::new(), accumulators: vec![Accumulator::count("n")] }, Vec::new(), OverflowPolicy::Widen);
+        assert_eq!(empty.unwrap(), vec![IndexMap::from([("n".to_string(), Value::Int(0))])]);
+    }
+
+    #[test]
+    fn test_sum_overflow() {
+        let counter = |n: i64| IndexMap::from([("n".to_string(), Value::Int(n))]);
+        let group = Stage::Group {
+            by: Vec::new(),
+            accumulators: vec![Accumulator::new("total", Aggregate::Sum, "n"), Accumulator::new("mean", Aggregate::Avg, "n")],
+        };
+        let sum = |documents: Vec<Document>, overflow| apply(&group, documents, overflow).map(|result| result[0].clone());
+
+        // 中间结果超出i64范围但最终结果没有超出时仍得到精确的整数
+        let back_in_range = vec![counter(i64::MAX), counter(i64::MAX), counter(-i64::MAX)];
+        let result = sum(back_in_range, OverflowPolicy::Error).unwrap();
+        assert_eq!((&result["total"], &result["mean"]), (&Value::Int(i64::MAX), &Value::Float(i64::MAX as f64 / 3.0)));
+
+        let overflowing = vec![counter(i64::MAX), counter(1)];
+        assert_eq!(sum(overflowing.clone(), OverflowPolicy::Widen).unwrap()["total"], Value::Float(i64::MAX as f64 + 1.0));
+        assert!(matches!(sum(overflowing, OverflowPolicy::Error), Err(DatabaseError::Overflow(_))));
+    }
+}
@@ -0,0 +1,77 @@
+use indexmap::IndexMap;
+use std::path::Path;
+
+use crate::error::{DatabaseError, Result};
+use crate::query::Query;
+use crate::storage::{self, Record, Value};
+use crate::system;
+
+/// 加密表的明文旁路文件的扩展名
+pub const PLAINTEXT_EXTENSION: &str = "plain";
+
+/// 明文旁路文件中保存的记录：ID，以及`fields`中列出的字段
+///
+/// `created_at`和`updated_at`是系统字段，列出时保留记录的创建和修改时间，可以按它们过滤；
+/// 未列出的时间为0。
+pub(crate) fn project(record: &Record, fields: &[String]) -> Record {
+    let listed = |field: &str| fields.iter().any(|f| f == field);
+    let data: IndexMap<String, Value> = fields
+        .iter()
+        .filter(|field| !system::is_system_field(field))
+        .filter_map(|field| Some((field.clone(), record.data.get(field)?.clone())))
+        .collect();
+    Record {
+        id: record.id.clone(),
+        data,
+        created_at: if listed(system::CREATED_AT) { record.created_at } else { 0 },
+        updated_at: if listed(system::UPDATED_AT) { record.updated_at } else { 0 },
+    }
+}
+
+/// 不需要密钥，从表的明文旁路文件中读出满足`query`的记录，只含`TableConfig::plaintext_fields`中的字段
+///
+/// 支持条件、排序和分页，不支持游标；表没有旁路文件时返回`TableNotFound`。
+pub fn read(data_dir: &Path, table: &str, query: &Query) -> Result<Vec<Record>> {
+    if query.after.is_some() {
+        return Err(DatabaseError::InvalidQuery("明文旁路文件不支持游标分页".to_string()));
+    }
+    query.validate()?;
+    let path = data_dir.join(format!("{}.{}", table, PLAINTEXT_EXTENSION));
+    if !path.exists() {
+        return Err(DatabaseError::TableNotFound(format!("{}（没有明文旁路文件）", table)));
+    }
+    let mut records: Vec<Record> = storage::read_table_file(&path, None)?
+        .into_values()
+        .map(|record| (*record).clone())
+        .filter(|record| query.matches(record))
+        .collect();
+    records.sort_by(|a, b| query.compare(a, b).then_with(|| a.id.cmp(&b.id)));
+    Ok(records
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit.unwrap_or(usize::MAX))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_project() {
+        let record = Record {
+            id: "1".to_string(),
+            data: IndexMap::from([
+                ("status".to_string(), Value::String("paid".to_string())),
+                ("card".to_string(), Value::String("4111".to_string())),
+            ]),
+            created_at: 100,
+            updated_at: 200,
+        };
+        let fields = ["id", "created_at", "status", "missing"].map(String::from);
+        let projected = project(&record, &fields);
+        assert_eq!(projected.id, "1");
+        assert_eq!((projected.created_at, projected.updated_at), (100, 0));
+        assert_eq!(projected.data, IndexMap::from([("status".to_string(), Value::String("paid".to_string()))]));
+    }
+}
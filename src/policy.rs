@@ -0,0 +1,312 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use indexmap::IndexMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::api::DatabaseServer;
+use crate::database::SimpleDB;
+use crate::error::{DatabaseError, Result};
+use crate::query::{Condition, Query, QueryPlan};
+use crate::siv::Siv;
+use crate::storage::{Record, Table, Value};
+use crate::update::{self, UpdateOp};
+
+/// 调用方令牌中的声明，如`sub`（用户ID）、`exp`（过期时间，Unix秒）、`admin`
+pub type Claims = HashMap<String, Value>;
+
+/// 令牌签名的域，与其他用途的MAC区分
+const TOKEN_DOMAIN: &[u8] = b"simpledb-token";
+
+/// 行级安全策略中引用调用方声明`name`的值，如`Condition::eq("owner_id", claim("sub"))`
+pub fn claim(name: &str) -> Value {
+    Value::Param(name.to_string())
+}
+
+/// 表的行级安全策略：调用方只能读写满足全部条件的记录，条件值可以用`claim`引用调用方的声明
+///
+/// 插入和更新后的记录也必须满足条件，调用方不能把记录改成自己看不到的样子。
+/// 条件引用的声明在令牌中不存在时，调用方看不到任何记录。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Policy {
+    pub conditions: Vec<Condition>,
+}
+
+impl Policy {
+    pub fn new(conditions: Vec<Condition>) -> Self {
+        Self { conditions }
+    }
+
+    /// 用调用方的声明替换条件中的引用，缺少声明时为None
+    pub fn bind(&self, claims: &Claims) -> Option<Vec<Condition>> {
+        self.conditions
+            .iter()
+            .map(|condition| match &condition.value {
+                Value::Param(name) => Some(Condition {
+                    value: claims.get(name)?.clone(),
+                    ..condition.clone()
+                }),
+                _ => Some(condition.clone()),
+            })
+            .collect()
+    }
+}
+
+/// 签发令牌：声明以JSON编码，附上用`key`计算的消息认证码
+pub fn issue_token(key: &[u8], claims: &Claims) -> Result<String> {
+    let payload: serde_json::Map<String, serde_json::Value> = claims
+        .iter()
+        .map(|(name, value)| (name.clone(), DatabaseServer::value_to_json(value)))
+        .collect();
+    let payload = serde_json::to_vec(&payload).map_err(|e| DatabaseError::DataFormat(e.to_string()))?;
+    let mac = Siv::derive(key)?.mac(TOKEN_DOMAIN, &payload);
+    Ok(format!("{}.{}", URL_SAFE_NO_PAD.encode(&payload), URL_SAFE_NO_PAD.encode(mac)))
+}
+
+/// 校验令牌并取出声明；签名不符或`exp`已过时返回`NotPermitted`
+pub fn verify_token(key: &[u8], token: &str) -> Result<Claims> {
+    let invalid = || DatabaseError::NotPermitted("令牌无效".to_string());
+    let (payload, mac) = token.trim().split_once('.').ok_or_else(invalid)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| invalid())?;
+    let mac = URL_SAFE_NO_PAD.decode(mac).map_err(|_| invalid())?;
+    let expected = Siv::derive(key)?.mac(TOKEN_DOMAIN, &payload);
+    // 常量时间比较
+    if mac.len() != expected.len() || expected.iter().zip(&mac).fold(0u8, |acc, (a, b)| acc | (a ^ b)) != 0 {
+        return Err(invalid());
+    }
+    let payload: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(&payload).map_err(|_| invalid())?;
+    let claims: Claims = payload
+        .into_iter()
+        .map(|(name, value)| (name, DatabaseServer::convert_json_value(value)))
+        .collect();
+    if let Some(exp) = claims.get("exp") {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        if exp.as_int().is_none_or(|exp| exp <= now) {
+            return Err(DatabaseError::NotPermitted("令牌已过期".to_string()));
+        }
+    }
+    Ok(claims)
+}
+
+/// 调用方在一张表上的可见范围
+enum Scope {
+    All,
+    Where(Vec<Condition>),
+    Nothing,
+}
+
+impl Scope {
+    fn of(table: &Table, caller: &Caller) -> Self {
+        if caller.is_admin() {
+            return Scope::All;
+        }
+        match table.policy() {
+            None => Scope::All,
+            Some(policy) => match policy.bind(&caller.claims) {
+                Some(conditions) => Scope::Where(conditions),
+                None => Scope::Nothing,
+            },
+        }
+    }
+
+    fn allows(&self, record: &Record) -> bool {
+        match self {
+            Scope::All => true,
+            Scope::Where(conditions) => conditions.iter().all(|c| c.matches(record)),
+            Scope::Nothing => false,
+        }
+    }
+
+    /// 加上策略条件的查询，看不到任何记录时为None
+    fn restrict(&self, query: &Query) -> Option<Query> {
+        match self {
+            Scope::All => Some(query.clone()),
+            Scope::Where(conditions) => Some(conditions.iter().cloned().fold(query.clone(), Query::filter)),
+            Scope::Nothing => None,
+        }
+    }
+}
+
+/// 以某个调用方的身份访问数据库，所有读写都受表的行级安全策略限制，由`SimpleDB::as_caller`创建
+///
+/// 声明`admin`为true的调用方不受策略限制。调用方看不到的记录如同不存在：
+/// 按ID查找返回None，更新和删除返回`RecordNotFound`；写入的记录不满足策略时返回`NotPermitted`。
+/// 检查和写入在同一次加锁中完成。
+pub struct Caller<'a> {
+    db: &'a SimpleDB,
+    claims: Claims,
+}
+
+impl<'a> Caller<'a> {
+    pub(crate) fn new(db: &'a SimpleDB, claims: Claims) -> Self {
+        Self { db, claims }
+    }
+
+    pub fn claims(&self) -> &Claims {
+        &self.claims
+    }
+
+    /// 不受行级安全策略限制的管理员
+    pub fn is_admin(&self) -> bool {
+        self.claims.get("admin") == Some(&Value::Bool(true))
+    }
+
+    pub fn find_by_id(&self, table_name: &str, id: &str) -> Result<Option<Arc<Record>>> {
+        self.db.read_table(table_name, |table| {
+            let scope = Scope::of(table, self);
+            table.find_by_id(id).filter(|record| scope.allows(record))
+        })
+    }
+
+    pub fn find_all(&self, table_name: &str) -> Result<Vec<Arc<Record>>> {
+        self.query(table_name, &Query::new())
+    }
+
+    pub fn query(&self, table_name: &str, query: &Query) -> Result<Vec<Arc<Record>>> {
+        query.validate()?;
+        self.db.read_table(table_name, |table| match Scope::of(table, self).restrict(query) {
+            Some(query) => table.query(&query),
+            None => Vec::new(),
+        })
+    }
+
+    /// 同`query`，同时返回分页前调用方可见的满足条件的记录数
+    pub fn query_with_total(&self, table_name: &str, query: &Query) -> Result<(Vec<Arc<Record>>, usize)> {
+        query.validate()?;
+        self.db.read_table(table_name, |table| match Scope::of(table, self).restrict(query) {
+            Some(query) => table.query_with_total(&query),
+            None => (Vec::new(), 0),
+        })
+    }
+
+    /// 加上策略条件后的执行计划
+    pub fn explain(&self, table_name: &str, query: &Query) -> Result<QueryPlan> {
+        query.validate()?;
+        self.db.read_table(table_name, |table| {
+            let scope = Scope::of(table, self);
+            table.plan(&scope.restrict(query).unwrap_or_else(|| query.clone()))
+        })
+    }
+
+    /// 绑定参数执行预备查询，结果只包含调用方可见的记录
+    pub fn execute_prepared(&self, name: &str, bindings: &IndexMap<String, Value>) -> Result<Vec<Arc<Record>>> {
+        let prepared = self.db.prepared_query(name)?;
+        self.query(&prepared.table, &prepared.bind(bindings)?)
+    }
+
+    pub fn insert(&self, table_name: &str, data: IndexMap<String, Value>) -> Result<String> {
+        self.db.check_local_write()?;
+        if self.db.get_table(table_name).is_err() {
+            self.db.create_table(table_name)?;
+        }
+        let record = Record::new(data);
+        self.db.write_table(table_name, |table| {
+            if !Scope::of(table, self).allows(&record) {
+                return Err(self.denied(table_name));
+            }
+            table.insert(record)
+        })
+    }
+
+    /// 同`insert`，返回插入后的完整记录
+    pub fn insert_returning(&self, table_name: &str, data: IndexMap<String, Value>) -> Result<Arc<Record>> {
+        self.db.check_local_write()?;
+        if self.db.get_table(table_name).is_err() {
+            self.db.create_table(table_name)?;
+        }
+        let record = Record::new(data);
+        self.db.write_table(table_name, |table| {
+            if !Scope::of(table, self).allows(&record) {
+                return Err(self.denied(table_name));
+            }
+            let id = table.insert(record)?;
+            table.find_by_id(&id).ok_or(DatabaseError::RecordNotFound(id))
+        })
+    }
+
+    /// 替换记录的数据；`if_match`不为None时还要求记录的ETag与之一致
+    pub fn update(&self, table_name: &str, id: &str, data: IndexMap<String, Value>, if_match: Option<&str>) -> Result<()> {
+        self.write(table_name, id, if_match, |_| Ok(data))
+    }
+
+    /// 局部更新记录；`if_match`不为None时还要求记录的ETag与之一致
+    pub fn patch(&self, table_name: &str, id: &str, ops: &[UpdateOp], if_match: Option<&str>) -> Result<()> {
+        self.write(table_name, id, if_match, |current| update::apply_all(current, ops))
+    }
+
+    /// 删除记录；`if_match`不为None时还要求记录的ETag与之一致
+    pub fn delete(&self, table_name: &str, id: &str, if_match: Option<&str>) -> Result<()> {
+        self.db.check_local_write()?;
+        self.db.write_table(table_name, |table| {
+            self.check_visible(table, id, if_match)?;
+            table.delete(id)
+        })
+    }
+
+    /// 在写锁内检查原记录可见、新数据满足策略后更新
+    fn write(
+        &self,
+        table_name: &str,
+        id: &str,
+        if_match: Option<&str>,
+        f: impl FnOnce(&IndexMap<String, Value>) -> Result<IndexMap<String, Value>>,
+    ) -> Result<()> {
+        self.db.check_local_write()?;
+        self.db.write_table(table_name, |table| {
+            let current = self.check_visible(table, id, if_match)?;
+            let data = f(&current.data)?;
+            let updated = Record {
+                data,
+                ..(*current).clone()
+            };
+            if !Scope::of(table, self).allows(&updated) {
+                return Err(self.denied(table_name));
+            }
+            table.update(id, updated.data)
+        })
+    }
+
+    fn check_visible(&self, table: &Table, id: &str, if_match: Option<&str>) -> Result<Arc<Record>> {
+        let record = table
+            .find_by_id(id)
+            .filter(|record| Scope::of(table, self).allows(record))
+            .ok_or_else(|| DatabaseError::RecordNotFound(id.to_string()))?;
+        if let Some(etag) = if_match {
+            SimpleDB::check_etag(table, id, etag)?;
+        }
+        Ok(record)
+    }
+
+    fn denied(&self, table_name: &str) -> DatabaseError {
+        DatabaseError::NotPermitted(format!("记录不满足表 {} 的行级安全策略", table_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens() {
+        let key = [7u8; 32];
+        let claims = Claims::from([
+            ("sub".to_string(), Value::String("alice".to_string())),
+            ("exp".to_string(), Value::Int(i64::MAX)),
+        ]);
+        let token = issue_token(&key, &claims).unwrap();
+        assert_eq!(verify_token(&key, &token).unwrap(), claims);
+        assert!(verify_token(&[8u8; 32], &token).is_err());
+
+        // 篡改声明后签名不再有效
+        let (_, mac) = token.split_once('.').unwrap();
+        let forged = format!("{}.{}", URL_SAFE_NO_PAD.encode(br#"{"sub":"bob"}"#), mac);
+        assert!(verify_token(&key, &forged).is_err());
+
+        let expired = issue_token(&key, &Claims::from([("exp".to_string(), Value::Int(1))])).unwrap();
+        assert!(matches!(verify_token(&key, &expired), Err(DatabaseError::NotPermitted(_))));
+    }
+}
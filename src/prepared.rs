@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use indexmap::IndexMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::crypto::Crypto;
+use crate::error::{DatabaseError, Result};
+use crate::lock::Recover;
+use crate::statefile;
+use crate::query::Query;
+use crate::storage::Value;
+
+/// 数据目录中保存预备查询的文件
+pub const PREPARED_FILE: &str = "PREPARED";
+
+/// 预备查询文件开头的魔数
+pub const PREPARED_MAGIC: &[u8; 8] = b"SDBPREP1";
+
+/// 预备查询中名为`name`的参数占位符，执行时替换为绑定的值
+pub fn param(name: &str) -> Value {
+    Value::Param(name.to_string())
+}
+
+/// 预备查询：带参数占位符的查询，准备时校验并选定执行计划，执行时只需绑定参数
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PreparedQuery {
+    pub table: String,
+    /// 查询模板，索引提示已固定为准备时选定的计划
+    pub query: Query,
+    /// 模板中的参数名
+    pub params: BTreeSet<String>,
+}
+
+impl PreparedQuery {
+    /// 把参数替换为绑定的值，得到可执行的查询；缺少参数或有未知参数时返回`InvalidQuery`
+    pub fn bind(&self, bindings: &IndexMap<String, Value>) -> Result<Query> {
+        if let Some(unknown) = bindings.keys().find(|name| !self.params.contains(*name)) {
+            return Err(DatabaseError::InvalidQuery(format!("未知参数 {}", unknown)));
+        }
+        let mut query = self.query.clone();
+        for condition in &mut query.conditions {
+            if let Value::Param(name) = &condition.value {
+                condition.value = bindings
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| DatabaseError::InvalidQuery(format!("缺少参数 {}", name)))?;
+            }
+        }
+        query.validate()?;
+        Ok(query)
+    }
+}
+
+/// 已注册的预备查询，按名称组织，修改后立即写入`PREPARED`文件；配置了主密钥时文件加密
+pub(crate) struct PreparedQueries {
+    path: PathBuf,
+    crypto: Option<Crypto>,
+    queries: Mutex<BTreeMap<String, PreparedQuery>>,
+}
+
+impl PreparedQueries {
+    pub(crate) fn open(data_dir: &Path, crypto: Option<Crypto>) -> Result<Self> {
+        let path = data_dir.join(PREPARED_FILE);
+        let queries = statefile::load(&path, PREPARED_MAGIC, crypto.as_ref())?.unwrap_or_default();
+        Ok(Self {
+            path,
+            crypto,
+            queries: Mutex::new(queries),
+        })
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<PreparedQuery> {
+        self.queries.lock().recover().get(name).cloned()
+    }
+
+    pub(crate) fn names(&self) -> Vec<String> {
+        self.queries.lock().recover().keys().cloned().collect()
+    }
+
+    /// 注册或替换同名的预备查询
+    pub(crate) fn insert(&self, name: &str, query: PreparedQuery) -> Result<()> {
+        let mut queries = self.queries.lock().recover();
+        queries.insert(name.to_string(), query);
+        self.persist(&queries)
+    }
+
+    pub(crate) fn remove(&self, name: &str) -> Result<bool> {
+        let mut queries = self.queries.lock().recover();
+        if queries.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.persist(&queries)?;
+        Ok(true)
+    }
+
+    fn persist(&self, queries: &BTreeMap<String, PreparedQuery>) -> Result<()> {
+        if queries.is_empty() {
+            if self.path.exists() {
+                std::fs::remove_file(&self.path)?;
+            }
+            return Ok(());
+        }
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // 查询条件中可能有敏感的常量，与表数据一样加密
+        statefile::store(&self.path, PREPARED_MAGIC, queries, self.crypto.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::Condition;
+
+    #[test]
+    fn test_bind_params() {
+        let prepared = PreparedQuery {
+            table: "users".to_string(),
+            query: Query::gt("age", param("min")).filter(Condition::eq("city", param("city"))),
+            params: BTreeSet::from(["min".to_string(), "city".to_string()]),
+        };
+        let bindings = IndexMap::from([
+            ("min".to_string(), Value::Int(18)),
+            ("city".to_string(), Value::String("北京".to_string())),
+        ]);
+        let query = prepared.bind(&bindings).unwrap();
+        assert_eq!(query.conditions[0].value, Value::Int(18));
+        assert_eq!(query.conditions[1].value, Value::String("北京".to_string()));
+
+        assert!(prepared.bind(&IndexMap::from([("min".to_string(), Value::Int(18))])).is_err());
+        let mut extra = bindings.clone();
+        extra.insert("max".to_string(), Value::Int(60));
+        assert!(prepared.bind(&extra).is_err());
+        // 未绑定的查询不能直接执行
+        assert!(prepared.query.validate().is_err());
+    }
+}
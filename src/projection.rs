@@ -0,0 +1,61 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::lock::Recover;
+use crate::storage::Record;
+
+/// 事件表投影的归约函数：由当前状态和下一个事件得到新状态
+pub type Reducer<S> = fn(S, &Record) -> S;
+
+/// 投影检查点：已归约到的最后一个事件和此时的状态
+struct Checkpoint {
+    /// 最后一个已归约事件的ID
+    last: String,
+    /// 已归约的事件数，用于发现检查点之前的事件发生了变化
+    folded: usize,
+    state: Arc<dyn Any + Send + Sync>,
+}
+
+/// (表名, 状态类型, 归约函数地址)
+type Key = (String, TypeId, usize);
+
+/// 各事件表投影的检查点缓存，再次投影时只归约检查点之后追加的事件
+#[derive(Default)]
+pub(crate) struct Projections {
+    checkpoints: Mutex<HashMap<Key, Checkpoint>>,
+}
+
+fn key<S: 'static>(table: &str, reducer: Reducer<S>) -> Key {
+    (table.to_string(), TypeId::of::<S>(), reducer as usize)
+}
+
+impl Projections {
+    /// 取出检查点：(最后一个已归约事件的ID, 已归约的事件数, 状态)
+    pub(crate) fn get<S>(&self, table: &str, reducer: Reducer<S>) -> Option<(String, usize, S)>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let checkpoints = self.checkpoints.lock().recover();
+        let checkpoint = checkpoints.get(&key(table, reducer))?;
+        let state = checkpoint.state.downcast_ref::<S>()?.clone();
+        Some((checkpoint.last.clone(), checkpoint.folded, state))
+    }
+
+    pub(crate) fn put<S>(&self, table: &str, reducer: Reducer<S>, last: String, folded: usize, state: S)
+    where
+        S: Send + Sync + 'static,
+    {
+        let checkpoint = Checkpoint {
+            last,
+            folded,
+            state: Arc::new(state),
+        };
+        self.checkpoints.lock().recover().insert(key(table, reducer), checkpoint);
+    }
+
+    /// 丢弃表的所有检查点，表被删除时调用
+    pub(crate) fn invalidate(&self, table: &str) {
+        self.checkpoints.lock().recover().retain(|(name, _, _), _| name != table);
+    }
+}
@@ -0,0 +1,625 @@
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::borrow::{Borrow, Cow};
+use std::cmp::Ordering;
+
+use crate::collation::{self, Comparator};
+use crate::error::{DatabaseError, Result};
+use crate::storage::{Record, Value};
+
+/// 排序方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// 比较运算符
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operator {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+/// 单个字段上的过滤条件
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Condition {
+    pub field: String,
+    pub op: Operator,
+    pub value: Value,
+}
+
+impl Condition {
+    pub fn new(field: &str, op: Operator, value: Value) -> Self {
+        Self {
+            field: field.to_string(),
+            op,
+            value,
+        }
+    }
+
+    pub fn eq(field: &str, value: Value) -> Self {
+        Self::new(field, Operator::Eq, value)
+    }
+
+    pub fn ne(field: &str, value: Value) -> Self {
+        Self::new(field, Operator::Ne, value)
+    }
+
+    pub fn gt(field: &str, value: Value) -> Self {
+        Self::new(field, Operator::Gt, value)
+    }
+
+    pub fn gte(field: &str, value: Value) -> Self {
+        Self::new(field, Operator::Gte, value)
+    }
+
+    pub fn lt(field: &str, value: Value) -> Self {
+        Self::new(field, Operator::Lt, value)
+    }
+
+    pub fn lte(field: &str, value: Value) -> Self {
+        Self::new(field, Operator::Lte, value)
+    }
+
+    /// 判断记录是否满足条件，字段缺失时只有`Ne`成立；字段可以是系统字段
+    pub fn matches(&self, record: &Record) -> bool {
+        self.matches_value(record.field(&self.field).as_deref())
+    }
+
+    /// 判断字段值是否满足条件，None表示字段缺失
+    pub fn matches_value(&self, actual: Option<&Value>) -> bool {
+        self.matches_value_with(actual, collation::binary)
+    }
+
+    /// 同`matches_value`，范围比较中的字符串按`strings`比较；等值比较始终要求完全相同
+    pub fn matches_value_with(&self, actual: Option<&Value>, strings: Comparator) -> bool {
+        let actual = match actual {
+            Some(value) => value,
+            None => return self.op == Operator::Ne,
+        };
+        match self.op {
+            Operator::Eq => actual == &self.value,
+            Operator::Ne => actual != &self.value,
+            op => {
+                let ordering = match compare_values_with(actual, &self.value, strings) {
+                    Some(ordering) => ordering,
+                    None => return false,
+                };
+                match op {
+                    Operator::Gt => ordering == Ordering::Greater,
+                    Operator::Gte => ordering != Ordering::Less,
+                    Operator::Lt => ordering == Ordering::Less,
+                    Operator::Lte => ordering != Ordering::Greater,
+                    Operator::Eq | Operator::Ne => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+/// 索引提示，覆盖查询计划对索引的自动选择
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IndexHint {
+    /// 按代价自动选择
+    #[default]
+    Auto,
+    /// 使用指定字段上的索引；该字段没有索引或没有等值条件时退回全表扫描
+    Use(String),
+    /// 不使用索引
+    Scan,
+}
+
+/// 查询构建器：过滤条件（逻辑与）、排序和分页
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Query {
+    pub conditions: Vec<Condition>,
+    pub order_by: Vec<(String, SortOrder)>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+    /// 只返回排在该位置之后的记录
+    pub after: Option<Cursor>,
+    pub hint: IndexHint,
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn eq(field: &str, value: Value) -> Self {
+        Self::new().filter(Condition::eq(field, value))
+    }
+
+    pub fn gt(field: &str, value: Value) -> Self {
+        Self::new().filter(Condition::gt(field, value))
+    }
+
+    pub fn lt(field: &str, value: Value) -> Self {
+        Self::new().filter(Condition::lt(field, value))
+    }
+
+    /// 追加过滤条件
+    pub fn filter(mut self, condition: Condition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+
+    /// 设置排序字段，按给定顺序依次比较，最后以记录ID兜底保证顺序稳定
+    pub fn order_by<I, S>(mut self, fields: I) -> Self
+    where
+        I: IntoIterator<Item = (S, SortOrder)>,
+        S: Into<String>,
+    {
+        self.order_by = fields.into_iter().map(|(f, o)| (f.into(), o)).collect();
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// 强制使用指定字段上的索引
+    pub fn use_index(mut self, field: &str) -> Self {
+        self.hint = IndexHint::Use(field.to_string());
+        self
+    }
+
+    /// 强制全表扫描
+    pub fn force_scan(mut self) -> Self {
+        self.hint = IndexHint::Scan;
+        self
+    }
+
+    /// 从游标位置之后继续分页
+    pub fn after(mut self, cursor: Cursor) -> Self {
+        self.after = Some(cursor);
+        self
+    }
+
+    /// 生成指向给定记录的游标，作为下一页的起点
+    pub fn cursor_for(&self, record: &Record) -> Cursor {
+        Cursor {
+            order_by: self.order_by.clone(),
+            values: self
+                .order_by
+                .iter()
+                .map(|(field, _)| record.field(field).map(Cow::into_owned))
+                .collect(),
+            id: record.id.clone(),
+        }
+    }
+
+    /// 检查查询是否合法：参数都已绑定，且加密值只做等值比较
+    pub fn validate(&self) -> Result<()> {
+        if let Some(name) = self.params().next() {
+            return Err(DatabaseError::InvalidQuery(format!("参数 {} 未绑定", name)));
+        }
+        self.validate_template()
+    }
+
+    /// 检查预备查询的模板是否合法，允许有参数占位符：加密值的大小顺序没有意义，只能做等值比较
+    pub fn validate_template(&self) -> Result<()> {
+        for condition in &self.conditions {
+            if condition.value.is_encrypted() && !matches!(condition.op, Operator::Eq | Operator::Ne) {
+                return Err(DatabaseError::InvalidQuery(format!(
+                    "加密字段 {} 只支持等值比较，不支持范围查询",
+                    condition.field
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// 条件中的参数占位符名称
+    pub fn params(&self) -> impl Iterator<Item = &str> {
+        self.conditions.iter().filter_map(|c| match &c.value {
+            Value::Param(name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// 判断记录是否满足所有条件
+    pub fn matches(&self, record: &Record) -> bool {
+        self.matches_with(record, collation::binary)
+    }
+
+    /// 同`matches`，范围条件中的字符串按`strings`比较
+    pub fn matches_with(&self, record: &Record, strings: Comparator) -> bool {
+        self.conditions
+            .iter()
+            .all(|c| c.matches_value_with(record.field(&c.field).as_deref(), strings))
+    }
+
+    /// 按`order_by`比较两条记录
+    pub fn compare(&self, a: &Record, b: &Record) -> Ordering {
+        self.compare_with(a, b, collation::binary)
+    }
+
+    /// 同`compare`，字符串按`strings`比较
+    pub fn compare_with(&self, a: &Record, b: &Record, strings: Comparator) -> Ordering {
+        self.compare_keys(|field| a.field(field), |field| b.field(field), strings)
+            .then_with(|| a.id.cmp(&b.id))
+    }
+
+    /// 比较记录与游标位置
+    fn compare_to_cursor(&self, record: &Record, cursor: &Cursor, strings: Comparator) -> Ordering {
+        let cursor_value = |field: &str| {
+            let i = self.order_by.iter().position(|(f, _)| f == field)?;
+            cursor.values.get(i)?.as_ref().map(Cow::Borrowed)
+        };
+        self.compare_keys(|field| record.field(field), cursor_value, strings)
+            .then_with(|| record.id.as_str().cmp(&cursor.id))
+    }
+
+    fn compare_keys<'a, 'b>(
+        &self,
+        a: impl Fn(&str) -> Option<Cow<'a, Value>>,
+        b: impl Fn(&str) -> Option<Cow<'b, Value>>,
+        strings: Comparator,
+    ) -> Ordering {
+        for (field, order) in &self.order_by {
+            let ordering = compare_field_with(a(field).as_deref(), b(field).as_deref(), strings);
+            let ordering = match order {
+                SortOrder::Asc => ordering,
+                SortOrder::Desc => ordering.reverse(),
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    }
+
+    /// 根据排序与分页参数选择排序策略
+    ///
+    /// 分页（`limit`或游标）时即使没有`order_by`也按ID排序，保证各页互不重叠。
+    pub fn sort_strategy(&self) -> SortStrategy {
+        match self.limit {
+            _ if self.order_by.is_empty() && self.limit.is_none() && self.after.is_none() => SortStrategy::None,
+            Some(limit) => SortStrategy::TopK(self.offset.saturating_add(limit)),
+            None => SortStrategy::InMemory,
+        }
+    }
+
+    /// 对已过滤的结果排序并应用分页
+    pub fn finish<R: Borrow<Record>>(&self, records: Vec<R>) -> Vec<R> {
+        self.finish_with(records, collation::binary)
+    }
+
+    /// 同`finish`，字符串按`strings`排序
+    pub fn finish_with<R: Borrow<Record>>(&self, mut records: Vec<R>, strings: Comparator) -> Vec<R> {
+        if let Some(cursor) = &self.after {
+            records.retain(|r| self.compare_to_cursor(r.borrow(), cursor, strings) == Ordering::Greater);
+        }
+        let compare = |a: &R, b: &R| self.compare_with(a.borrow(), b.borrow(), strings);
+        match self.sort_strategy() {
+            SortStrategy::None => records.sort_by(|a, b| a.borrow().default_order(b.borrow())),
+            SortStrategy::InMemory => records.sort_by(compare),
+            SortStrategy::TopK(k) => {
+                // 只需前k条时先做部分选择，再对这k条排序
+                if k < records.len() {
+                    if k == 0 {
+                        records.clear();
+                    } else {
+                        records.select_nth_unstable_by(k - 1, compare);
+                        records.truncate(k);
+                    }
+                }
+                records.sort_by(compare);
+            }
+        }
+        let limit = self.limit.unwrap_or(usize::MAX);
+        records.into_iter().skip(self.offset).take(limit).collect()
+    }
+}
+
+/// 分页游标：上一页最后一条记录的排序键和ID
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub order_by: Vec<(String, SortOrder)>,
+    pub values: Vec<Option<Value>>,
+    pub id: String,
+}
+
+impl Cursor {
+    /// 编码为不透明的URL安全令牌
+    pub fn encode(&self) -> String {
+        let bytes = bincode::serialize(self).unwrap_or_default();
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    /// 解码令牌，并校验其与查询的排序方式一致
+    pub fn decode(token: &str, query: &Query) -> Result<Self> {
+        let invalid = || DatabaseError::DataFormat("无效的分页令牌".to_string());
+        let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(token)
+            .map_err(|_| invalid())?;
+        let cursor: Cursor = bincode::deserialize(&bytes).map_err(|_| invalid())?;
+        if cursor.order_by != query.order_by {
+            return Err(DatabaseError::DataFormat("分页令牌与查询的排序方式不匹配".to_string()));
+        }
+        Ok(cursor)
+    }
+}
+
+/// 排序策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum SortStrategy {
+    /// 没有指定排序，按记录的默认顺序（创建时间，相同时按ID）排列
+    None,
+    /// 对全部匹配结果完整排序
+    InMemory,
+    /// 仅选出前k条再排序
+    TopK(usize),
+}
+
+/// 查询执行计划，由`explain`返回
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueryPlan {
+    /// 使用的索引字段，None表示全表扫描
+    pub index: Option<String>,
+    /// 预计需要检查的记录数
+    pub estimated_scanned: usize,
+    /// 按统计信息估计的结果记录数（分页之前），还没有统计信息时为None
+    pub estimated_rows: Option<usize>,
+    /// 表中的记录总数
+    pub total_records: usize,
+    pub sort: SortStrategy,
+}
+
+/// 比较同类的标量值（null、布尔、数值、时间戳、字符串、字节串），类型不兼容时返回None
+///
+/// 范围条件和统计信息用它比较，顺序与`total_cmp_with`一致。
+pub fn compare_values(a: &Value, b: &Value) -> Option<Ordering> {
+    compare_values_with(a, b, collation::binary)
+}
+
+/// 同`compare_values`，字符串按`strings`比较
+pub fn compare_values_with(a: &Value, b: &Value, strings: Comparator) -> Option<Ordering> {
+    let scalar = |value: &Value| {
+        matches!(
+            value,
+            Value::Null | Value::Bool(_) | Value::Int(_) | Value::Float(_) | Value::Timestamp(_) | Value::String(_) | Value::Bytes(_)
+        )
+    };
+    (scalar(a) && scalar(b) && rank(a) == rank(b)).then(|| total_cmp_with(a, b, strings))
+}
+
+/// 值的全序，排序、范围条件、统计信息和分页游标都按此比较
+///
+/// - 不同类型按 null < 布尔 < 数值 < 时间戳 < 字符串 < 字节串 < 数组 < 对象 < 其他类型 排列；
+/// - `Int`与`Float`按数学上的大小比较，不经过有损的浮点转换；`-0.0`等于`0.0`，NaN大于所有数值且等于自身；
+/// - 字符串按`strings`比较，字节串、数组和对象逐个元素比较，前缀较小。
+///
+/// 顺序相等不代表`==`成立：等值条件和索引仍要求类型相同，如`Int(1)`不等于`Float(1.0)`。
+pub fn total_cmp_with(a: &Value, b: &Value, strings: Comparator) -> Ordering {
+    let elements = |x: &[Value], y: &[Value]| {
+        x.iter()
+            .zip(y)
+            .map(|(a, b)| total_cmp_with(a, b, strings))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| x.len().cmp(&y.len()))
+    };
+    match (a, b) {
+        (Value::Bool(x), Value::Bool(y)) => x.cmp(y),
+        (Value::Int(x), Value::Int(y)) => x.cmp(y),
+        (Value::Int(x), Value::Float(y)) => compare_int_float(*x, *y),
+        (Value::Float(x), Value::Int(y)) => compare_int_float(*y, *x).reverse(),
+        (Value::Float(x), Value::Float(y)) => compare_floats(*x, *y),
+        (Value::Timestamp(x), Value::Timestamp(y)) => x.cmp(y),
+        (Value::String(x), Value::String(y)) => strings(x, y),
+        (Value::Bytes(x), Value::Bytes(y)) => x.cmp(y),
+        (Value::Array(x), Value::Array(y)) => elements(x, y),
+        (Value::Object(x), Value::Object(y)) => x
+            .iter()
+            .zip(y)
+            .map(|((k1, v1), (k2, v2))| strings(k1, k2).then_with(|| total_cmp_with(v1, v2, strings)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| x.len().cmp(&y.len())),
+        (Value::GeoPoint { lat: a1, lon: o1 }, Value::GeoPoint { lat: a2, lon: o2 }) => {
+            compare_floats(*a1, *a2).then_with(|| compare_floats(*o1, *o2))
+        }
+        (Value::Vector(x), Value::Vector(y)) => x
+            .iter()
+            .zip(y)
+            .map(|(a, b)| compare_floats(f64::from(*a), f64::from(*b)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| x.len().cmp(&y.len())),
+        (Value::Ref { table: t1, id: i1 }, Value::Ref { table: t2, id: i2 }) => (t1, i1).cmp(&(t2, i2)),
+        (Value::Sealed { subject: s1, data: d1 }, Value::Sealed { subject: s2, data: d2 }) => (s1, d1).cmp(&(s2, d2)),
+        (Value::Deterministic { domain: s1, data: d1 }, Value::Deterministic { domain: s2, data: d2 }) => {
+            (s1, d1).cmp(&(s2, d2))
+        }
+        (Value::Param(x), Value::Param(y)) => x.cmp(y),
+        (Value::Compressed { binary: b1, data: d1 }, Value::Compressed { binary: b2, data: d2 }) => (b1, d1).cmp(&(b2, d2)),
+        (Value::Blob { id: i1, size: s1 }, Value::Blob { id: i2, size: s2 }) => (i1, s1).cmp(&(i2, s2)),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// 类型在全序中的位置，`Int`与`Float`同属数值
+fn rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Value::Int(_) | Value::Float(_) => 2,
+        Value::Timestamp(_) => 3,
+        Value::String(_) => 4,
+        Value::Bytes(_) => 5,
+        Value::Array(_) => 6,
+        Value::Object(_) => 7,
+        Value::GeoPoint { .. } => 8,
+        Value::Vector(_) => 9,
+        Value::Ref { .. } => 10,
+        Value::Sealed { .. } => 11,
+        Value::Deterministic { .. } => 12,
+        Value::Param(_) => 13,
+        Value::Compressed { .. } => 14,
+        Value::Blob { .. } => 15,
+    }
+}
+
+/// NaN大于其他浮点数且等于自身，`-0.0`等于`0.0`
+fn compare_floats(x: f64, y: f64) -> Ordering {
+    match (x.is_nan(), y.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+    }
+}
+
+/// 精确比较整数和浮点数；超过2^53的整数转换为f64会丢失精度，因此比较整数部分和小数部分
+fn compare_int_float(x: i64, y: f64) -> Ordering {
+    const TWO_POW_63: f64 = 9_223_372_036_854_775_808.0;
+    if y.is_nan() || y >= TWO_POW_63 {
+        return Ordering::Less;
+    }
+    if y < -TWO_POW_63 {
+        return Ordering::Greater;
+    }
+    let whole = y.trunc();
+    x.cmp(&(whole as i64)).then_with(|| 0.0.partial_cmp(&(y - whole)).unwrap_or(Ordering::Equal))
+}
+
+/// 排序用比较：缺失字段最小，其余按`total_cmp_with`
+pub(crate) fn compare_field(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    compare_field_with(a, b, collation::binary)
+}
+
+pub(crate) fn compare_field_with(a: Option<&Value>, b: Option<&Value>, strings: Comparator) -> Ordering {
+    match (a, b) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Less,
+        (Some(_), None) => Ordering::Greater,
+        (Some(x), Some(y)) => total_cmp_with(x, y, strings),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::IndexMap;
+
+    fn product(category: &str, price: f64) -> Record {
+        let mut data = IndexMap::new();
+        data.insert("category".to_string(), Value::String(category.to_string()));
+        data.insert("price".to_string(), Value::Float(price));
+        Record::new(data)
+    }
+
+    #[test]
+    fn test_total_order() {
+        let big = 1i64 << 53;
+        let ordered = [
+            Value::Null,
+            Value::Bool(false),
+            Value::Bool(true),
+            Value::Float(f64::NEG_INFINITY),
+            Value::Int(i64::MIN),
+            Value::Float(-0.5),
+            Value::Int(0),
+            Value::Float(0.5),
+            Value::Int(big),
+            Value::Int(big + 1),
+            Value::Float(big as f64 + 2.0),
+            Value::Int(i64::MAX),
+            Value::Float(f64::INFINITY),
+            Value::Float(f64::NAN),
+            Value::Timestamp(0),
+            Value::String("a".to_string()),
+            Value::Bytes(vec![0]),
+            Value::Array(vec![Value::Int(1)]),
+            Value::Array(vec![Value::Int(1), Value::Int(0)]),
+        ];
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                assert_eq!(a.total_cmp(b), i.cmp(&j), "{:?} {:?}", a, b);
+            }
+        }
+        assert_eq!(Value::Float(-0.0).total_cmp(&Value::Int(0)), Ordering::Equal);
+        assert_eq!(Value::Float(f64::NAN).total_cmp(&Value::Float(-f64::NAN)), Ordering::Equal);
+        // 索引与等值条件一致：-0.0 == 0.0
+        let key = crate::index::FieldIndex::key;
+        assert_eq!(key(&Value::Float(-0.0)), key(&Value::Float(0.0)));
+
+        // 范围条件只比较同类的值，NaN排在所有数值之后
+        let nan = Record::new(IndexMap::from([("x".to_string(), Value::Float(f64::NAN))]));
+        assert!(Query::gt("x", Value::Float(f64::INFINITY)).matches(&nan));
+        assert!(!Query::lt("x", Value::Int(0)).matches(&nan));
+        assert!(!Query::gt("x", Value::String("a".to_string())).matches(&nan));
+
+        // 混合类型的字段排序结果与输入顺序无关
+        let mut records: Vec<Record> = ordered
+            .iter()
+            .rev()
+            .map(|value| Record::new(IndexMap::from([("x".to_string(), value.clone())])))
+            .collect();
+        records.push(Record::new(IndexMap::new()));
+        let sorted = Query::new().order_by([("x", SortOrder::Asc)]).finish(records);
+        assert!(!sorted[0].data.contains_key("x"));
+        for (record, expected) in sorted[1..].iter().zip(&ordered) {
+            assert_eq!(record.data["x"].total_cmp(expected), Ordering::Equal);
+        }
+    }
+
+    #[test]
+    fn test_multi_field_order() {
+        let records = [
+            product("家电", 899.0),
+            product("电子产品", 2999.5),
+            product("家电", 1999.0),
+            product("电子产品", 5999.99),
+        ];
+        let query = Query::new()
+            .filter(Condition::gt("price", Value::Int(1000)))
+            .order_by([("category", SortOrder::Asc), ("price", SortOrder::Desc)]);
+
+        let matched: Vec<&Record> = records.iter().filter(|r| query.matches(r)).collect();
+        let prices: Vec<Value> = query
+            .finish(matched)
+            .iter()
+            .map(|r| r.data["price"].clone())
+            .collect();
+        assert_eq!(
+            prices,
+            vec![Value::Float(1999.0), Value::Float(5999.99), Value::Float(2999.5)]
+        );
+    }
+
+    #[test]
+    fn test_cursor_paging_survives_inserts() {
+        let mut records = vec![
+            product("家电", 100.0),
+            product("家电", 200.0),
+            product("家电", 300.0),
+            product("家电", 400.0),
+        ];
+        let query = Query::new().order_by([("price", SortOrder::Asc)]).limit(2);
+
+        let first = query.finish(records.iter().collect::<Vec<_>>());
+        let token = query.cursor_for(first[1]).encode();
+
+        // 翻页之间插入一条排在当前页之前的记录
+        records.push(product("家电", 50.0));
+
+        let cursor = Cursor::decode(&token, &query).unwrap();
+        let next = query.clone().after(cursor).finish(records.iter().collect::<Vec<_>>());
+        let prices: Vec<Value> = next.iter().map(|r| r.data["price"].clone()).collect();
+        assert_eq!(prices, vec![Value::Float(300.0), Value::Float(400.0)]);
+
+        assert!(Cursor::decode(&token, &Query::new()).is_err());
+    }
+}
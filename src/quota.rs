@@ -0,0 +1,170 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use crate::lock::Recover;
+use crate::storage::Record;
+
+/// 表的存储配额，记录数和字节数分别限制，为None的项不限制
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub max_records: Option<usize>,
+    /// 按记录序列化后的大小累计，与表文件的实际大小大致相当
+    pub max_bytes: Option<usize>,
+    pub policy: QuotaPolicy,
+}
+
+/// 写入会超出配额时的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuotaPolicy {
+    /// 拒绝写入，返回`QuotaExceeded`
+    #[default]
+    Reject,
+    /// 按插入顺序淘汰最早的记录，直到写入后不超出配额
+    EvictOldest,
+}
+
+impl Quota {
+    /// 表中有`records`条记录、共`bytes`字节时是否超出配额
+    pub fn exceeded(&self, records: usize, bytes: usize) -> bool {
+        self.max_records.is_some_and(|max| records > max) || self.max_bytes.is_some_and(|max| bytes > max)
+    }
+}
+
+/// 整个数据库（所有表合计）的配额和各表的用量，见`Config::quota`
+///
+/// 每张表在每次修改后记下自己的记录数和字节数。写入前在同一把锁内检查并预先记下写入后的用量，
+/// 不同的表并发写入时后检查的一方能看到先通过检查的写入，合计不会超出配额。
+#[derive(Debug)]
+pub(crate) struct Usage {
+    quota: Quota,
+    tables: Mutex<HashMap<String, (usize, usize)>>,
+}
+
+impl Usage {
+    pub(crate) fn new(quota: Quota) -> Self {
+        Self {
+            quota,
+            tables: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 记下表当前的记录数和字节数
+    pub(crate) fn record(&self, table: &str, records: usize, bytes: usize) {
+        self.tables.lock().recover().insert(table.to_string(), (records, bytes));
+    }
+
+    pub(crate) fn remove(&self, table: &str) {
+        self.tables.lock().recover().remove(table);
+    }
+
+    /// 所有表合计的记录数和字节数
+    pub(crate) fn total(&self) -> (usize, usize) {
+        let tables = self.tables.lock().recover();
+        tables.values().fold((0, 0), |(r, b), (records, bytes)| (r + records, b + bytes))
+    }
+
+    /// 表`table`写入后有`records`条记录、共`bytes`字节：整个数据库不超出配额时记下这一用量并返回true，否则不做修改
+    ///
+    /// 写入最终没有进行时调用者要用`record`改回表实际的用量。
+    pub(crate) fn reserve(&self, table: &str, records: usize, bytes: usize) -> bool {
+        let mut tables = self.tables.lock().recover();
+        let (total_records, total_bytes) = tables
+            .iter()
+            .filter(|(name, _)| name.as_str() != table)
+            .fold((records, bytes), |(r, b), (_, (records, bytes))| (r + records, b + bytes));
+        if self.quota.exceeded(total_records, total_bytes) {
+            return false;
+        }
+        tables.insert(table.to_string(), (records, bytes));
+        true
+    }
+}
+
+/// 记录计入配额的字节数
+pub fn record_size(record: &Record) -> usize {
+    bincode::serialized_size(record).map_or(0, |size| size as usize)
+}
+
+/// 记录的插入顺序，用于按先进先出淘汰
+///
+/// 只保存在内存中；加载表文件时按创建时间（相同时按ID）重建。
+#[derive(Debug, Default)]
+pub(crate) struct InsertionOrder {
+    next: u64,
+    order: BTreeMap<u64, String>,
+    positions: HashMap<String, u64>,
+}
+
+impl InsertionOrder {
+    pub(crate) fn rebuild<'a>(records: impl Iterator<Item = &'a Record>) -> Self {
+        let mut records: Vec<&Record> = records.collect();
+        records.sort_by(|a, b| a.default_order(b));
+        let mut order = Self::default();
+        for record in records {
+            order.push(&record.id);
+        }
+        order
+    }
+
+    /// 记到最后，即最新插入
+    pub(crate) fn push(&mut self, id: &str) {
+        self.order.insert(self.next, id.to_string());
+        self.positions.insert(id.to_string(), self.next);
+        self.next += 1;
+    }
+
+    pub(crate) fn remove(&mut self, id: &str) {
+        if let Some(position) = self.positions.remove(id) {
+            self.order.remove(&position);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.order.clear();
+        self.positions.clear();
+    }
+
+    /// 由旧到新排列的记录ID
+    pub(crate) fn oldest_first(&self) -> impl DoubleEndedIterator<Item = &str> {
+        self.order.values().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::IndexMap;
+
+    #[test]
+    fn test_insertion_order() {
+        let record = |id: &str, created_at: u64| Record {
+            id: id.to_string(),
+            data: IndexMap::new(),
+            created_at,
+            updated_at: created_at,
+        };
+        let records = [record("b", 1), record("c", 2), record("a", 1)];
+        let mut order = InsertionOrder::rebuild(records.iter());
+        assert_eq!(order.oldest_first().collect::<Vec<_>>(), ["a", "b", "c"]);
+
+        order.remove("b");
+        order.push("b");
+        assert_eq!(order.oldest_first().collect::<Vec<_>>(), ["a", "c", "b"]);
+
+        let quota = Quota {
+            max_records: Some(2),
+            ..Quota::default()
+        };
+        assert!(!quota.exceeded(2, usize::MAX));
+        assert!(quota.exceeded(3, 0));
+
+        // 先通过检查的写入已计入合计，另一张表随后的写入按此检查
+        let usage = Usage::new(quota);
+        assert!(usage.reserve("a", 1, 10));
+        assert!(usage.reserve("b", 1, 10));
+        assert!(!usage.reserve("a", 2, 20));
+        assert_eq!(usage.total(), (2, 20));
+        assert!(usage.reserve("b", 0, 0));
+        assert!(usage.reserve("a", 2, 20));
+    }
+}
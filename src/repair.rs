@@ -0,0 +1,267 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::cdc::{self, CDC_FILE};
+use crate::changes::{ChangeFeed, CHANGES_FILE};
+use crate::cluster::{self, RAFT_FILE, RAFT_LOG_FILE};
+use crate::compress::DICTIONARY_EXTENSION;
+use crate::crypto::Crypto;
+use crate::dirlock::DirLock;
+use crate::error::{DatabaseError, Result};
+use crate::format::{self, Damage};
+use crate::keyring::{Keyring, KEYRING_FILE};
+use crate::manifest::{Manifest, MANIFEST_FILE};
+use crate::meta::META_EXTENSION;
+use crate::plaintext::PLAINTEXT_EXTENSION;
+use crate::prepared::{PreparedQueries, PREPARED_FILE};
+use crate::schedule::{Schedules, SCHEDULE_FILE};
+use crate::siv::Siv;
+use crate::storage::{self, PARTS_EXTENSION};
+use crate::sync::{SyncState, SYNC_FILE};
+use crate::timeseries;
+use crate::Config;
+
+/// 数据目录中存放无法读取的文件的子目录
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// 修复了的表
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepairedTable {
+    pub table: String,
+    /// 读出并写回的记录数
+    pub recovered: usize,
+    /// 跳过而丢失的部分
+    pub lost: Vec<Damage>,
+}
+
+/// 修复结果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// 检查过的表文件数
+    pub checked: usize,
+    pub repaired: Vec<RepairedTable>,
+    /// 整个无法读取而移入隔离目录的文件：(文件名, 原因)
+    pub quarantined: Vec<(String, String)>,
+    /// 删除的数据库写到一半的临时文件，其他`.tmp`文件保留
+    pub removed_temp: Vec<String>,
+}
+
+impl RepairReport {
+    /// 数据目录原本就完好，没有做任何修改（清单总会重建）
+    pub fn is_clean(&self) -> bool {
+        self.repaired.is_empty() && self.quarantined.is_empty() && self.removed_temp.is_empty()
+    }
+}
+
+/// 加密表文件使用的密钥：表指定的密钥，否则为主密钥
+fn table_crypto(config: &Config, table: &str) -> Result<Option<Crypto>> {
+    let id = config.tables.get(table).and_then(|t| t.encryption_key_id.as_ref());
+    let key = match id {
+        Some(id) => Some(
+            config
+                .table_keys
+                .get(id)
+                .ok_or_else(|| DatabaseError::Config(format!("表 {} 使用的密钥 {} 不存在", table, id)))?,
+        ),
+        None => config.encryption_key.as_ref(),
+    };
+    key.map(|key| Crypto::with_cipher(key, config.cipher)).transpose()
+}
+
+/// 数据库写出的临时文件：表文件、附属文件、状态文件和对象文件名加上`.tmp`；其他`.tmp`文件不属于数据库
+fn is_own_temp(name: &str) -> bool {
+    let Some(stem) = name.strip_suffix(".tmp") else {
+        return false;
+    };
+    let state_files = [
+        MANIFEST_FILE,
+        KEYRING_FILE,
+        PREPARED_FILE,
+        SCHEDULE_FILE,
+        SYNC_FILE,
+        CDC_FILE,
+        CHANGES_FILE,
+        RAFT_FILE,
+        RAFT_LOG_FILE,
+    ];
+    let extensions = ["db", "stats", META_EXTENSION, PLAINTEXT_EXTENSION, DICTIONARY_EXTENSION, PARTS_EXTENSION];
+    // 对象文件`<SHA-256>.blob`的临时文件是`<SHA-256>.tmp`
+    let blob = stem.len() == 64 && stem.bytes().all(|b| b.is_ascii_hexdigit());
+    state_files.contains(&stem)
+        || blob
+        || Path::new(stem).extension().and_then(|ext| ext.to_str()).is_some_and(|ext| extensions.contains(&ext))
+}
+
+/// 隔离目录中尚未使用的文件名：原文件名后加上时间戳，不覆盖之前隔离的文件
+fn quarantine_path(data_dir: &Path, path: &Path) -> Result<PathBuf> {
+    let dir = data_dir.join(QUARANTINE_DIR);
+    std::fs::create_dir_all(&dir)?;
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut target = dir.join(format!("{}.{}", name, timestamp));
+    let mut n = 1;
+    while target.exists() {
+        target = dir.join(format!("{}.{}-{}", name, timestamp, n));
+        n += 1;
+    }
+    Ok(target)
+}
+
+/// 把文件移入隔离目录，改名在断电后也保留
+fn quarantine(data_dir: &Path, path: &Path) -> Result<PathBuf> {
+    let target = quarantine_path(data_dir, path)?;
+    std::fs::rename(path, &target)?;
+    storage::sync_dir(&data_dir.join(QUARANTINE_DIR))?;
+    storage::sync_dir(data_dir)?;
+    Ok(target)
+}
+
+/// 把文件复制到隔离目录并落盘，原文件保留，随后才能替换原文件
+fn preserve(data_dir: &Path, path: &Path) -> Result<PathBuf> {
+    let target = quarantine_path(data_dir, path)?;
+    std::fs::copy(path, &target)?;
+    File::open(&target)?.sync_all()?;
+    storage::sync_dir(&data_dir.join(QUARANTINE_DIR))?;
+    Ok(target)
+}
+
+/// 检查表文件以外的状态文件，返回无法读取的文件和原因；变更日志和Raft日志打开时截掉末尾写到一半的记录
+fn check_state_files(config: &Config, data_dir: &Path) -> Result<Vec<(&'static str, DatabaseError)>> {
+    let crypto = config
+        .encryption_key
+        .as_ref()
+        .map(|key| Crypto::with_cipher(key, config.cipher))
+        .transpose()?;
+    let mut damaged = Vec::new();
+    let mut check = |name, result: Result<()>| {
+        if let Err(e) = result {
+            damaged.push((name, e));
+        }
+    };
+    check(PREPARED_FILE, PreparedQueries::open(data_dir, crypto.clone()).map(drop));
+    check(SCHEDULE_FILE, Schedules::open(data_dir, crypto.clone()).map(drop));
+    check(SYNC_FILE, SyncState::open(data_dir, crypto.clone()).map(drop));
+    check(CDC_FILE, cdc::saved_offset(data_dir, crypto.clone()).map(drop));
+    check(CHANGES_FILE, ChangeFeed::open(data_dir, config.change_log_size, crypto.clone(), false).map(drop));
+    // 密钥文件只在配置了主密钥时使用
+    if let Some(master) = &crypto {
+        check(KEYRING_FILE, Keyring::open(data_dir, master.clone()).map(drop));
+    }
+    damaged.extend(cluster::check_files(data_dir, crypto.as_ref()));
+    Ok(damaged)
+}
+
+/// 数据目录中是否有加密的表文件
+fn has_encrypted_table(data_dir: &Path) -> Result<bool> {
+    for entry in std::fs::read_dir(data_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "db")
+            && format::read_header(&path).is_ok_and(|h| h.is_some_and(|h| h.is_encrypted()))
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// 修复数据目录，数据库必须未打开：修复期间持有数据目录的排他锁，数据库在任何进程中打开时立即失败
+///
+/// - 删除数据库写到一半的临时文件，其他`.tmp`文件保留；
+/// - 逐个读取表文件，跳过损坏的记录帧（包括写入中断留下的不完整末尾），原文件复制到隔离目录并落盘后，
+///   把读出的记录写入临时文件再改名替换原文件；
+/// - 整个无法读取的表文件（文件头损坏、解密失败等）移入隔离目录；
+/// - 读不出的状态文件移入隔离目录；变更日志和Raft日志末尾写到一半的记录被截掉（数据库没有单独的预写日志）。
+///   无法解密的状态文件多半是密钥错误，此时中止修复而不隔离；
+/// - 按修复后的表文件重建清单。索引只在内存中，打开数据库后按需重建。
+pub fn repair(config: &Config) -> Result<RepairReport> {
+    let data_dir = Path::new(&config.data_dir);
+    let mut report = RepairReport::default();
+    if !data_dir.exists() {
+        return Ok(report);
+    }
+    let _lock = DirLock::exclusive(data_dir)?;
+
+    let damaged = check_state_files(config, data_dir)?;
+    if let Some((name, e)) = damaged.iter().find(|(_, e)| matches!(e, DatabaseError::Encryption(_))) {
+        return Err(DatabaseError::Encryption(format!("状态文件 {} 无法解密，请检查密钥: {}", name, e)));
+    }
+    // 没有密钥时加密的状态文件读不出，同样不能当作损坏的文件隔离
+    if !damaged.is_empty() && config.encryption_key.is_none() && has_encrypted_table(data_dir)? {
+        return Err(DatabaseError::Encryption("数据目录已加密，修复需要提供密钥".to_string()));
+    }
+    for (name, e) in damaged {
+        let path = data_dir.join(name);
+        if path.exists() {
+            quarantine(data_dir, &path)?;
+            report.quarantined.push((name.to_string(), e.to_string()));
+        }
+    }
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(data_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+
+    for path in files {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("tmp") if is_own_temp(&name) => {
+                std::fs::remove_file(&path)?;
+                report.removed_temp.push(name);
+            }
+            Some("db") => {
+                report.checked += 1;
+                let table = timeseries::table_of_file(&name);
+                let crypto = table_crypto(config, table)?;
+                // 没有密钥时不能把加密的表当作损坏的文件隔离
+                if crypto.is_none() && format::read_header(&path).is_ok_and(|h| h.is_some_and(|h| h.is_encrypted())) {
+                    return Err(DatabaseError::Encryption(format!("表文件 {} 已加密，修复需要提供密钥", name)));
+                }
+                let reader = BufReader::new(File::open(&path)?);
+                let decoded = match format::read_salvaging(reader, crypto.as_ref()) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        quarantine(data_dir, &path)?;
+                        // 统计信息、元数据、明文旁路和压缩字典文件不再有对应的表文件（段文件没有这些附属文件）
+                        for extension in ["stats", META_EXTENSION, PLAINTEXT_EXTENSION, DICTIONARY_EXTENSION] {
+                            let sidecar = path.with_extension(extension);
+                            if sidecar.exists() {
+                                quarantine(data_dir, &sidecar)?;
+                            }
+                        }
+                        report.quarantined.push((name, e.to_string()));
+                        continue;
+                    }
+                };
+                if decoded.damage.is_empty() {
+                    continue;
+                }
+                preserve(data_dir, &path)?;
+                storage::write_table_file(&path, &decoded.records, crypto.as_ref(), decoded.options)?;
+                storage::sync_dir(data_dir)?;
+                report.repaired.push(RepairedTable {
+                    table: table.to_string(),
+                    recovered: decoded.records.len(),
+                    lost: decoded.damage,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    let manifest = data_dir.join(MANIFEST_FILE);
+    if manifest.exists() {
+        std::fs::remove_file(&manifest)?;
+    }
+    let mac = config.encryption_key.as_deref().map(Siv::derive).transpose()?;
+    Manifest::open(data_dir, mac)?;
+    Ok(report)
+}
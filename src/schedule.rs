@@ -0,0 +1,396 @@
+//! 定时任务：按cron表达式定期清理过期记录、重写表文件、备份和刷新统计信息，取代外部的cron脚本
+//!
+//! 计划保存在数据目录的`SCHEDULE`文件中，重启后继续生效。任务由`SimpleDB::run_due_jobs`运行，
+//! 服务器每秒调用一次；停机期间错过的任务在启动后运行一次，之后按计划继续。
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::crypto::Crypto;
+use crate::database::SimpleDB;
+use crate::datetime::{civil_from_days, days_from_civil};
+use crate::error::{DatabaseError, Result};
+use crate::statefile;
+
+/// 保存定时任务的文件名
+pub const SCHEDULE_FILE: &str = "SCHEDULE";
+
+/// 定时任务文件开头的魔数
+pub const SCHEDULE_MAGIC: &[u8; 8] = b"SDBSCHD1";
+
+/// 查找下次运行时间的范围，覆盖只在2月29日运行的计划
+const SEARCH_DAYS: i64 = 8 * 366;
+
+/// 五个字段（分 时 日 月 周）的cron表达式，按UTC时间计算
+///
+/// 每个字段支持`*`、数字、范围`1-5`、列表`1,15`和步长`*/10`、`8-18/2`，周日为0或7；
+/// 日和周都不是`*`时满足其一即可。也可以用`@hourly`、`@daily`、`@weekly`、`@monthly`和`@yearly`。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(expression: &str) -> Result<Cron> {
+        let expanded = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let invalid = |reason: &str| DatabaseError::Config(format!("无效的cron表达式 {:?}: {}", expression, reason));
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let &[minutes, hours, days, months, weekdays] = fields.as_slice() else {
+            return Err(invalid("应有分、时、日、月、周五个字段"));
+        };
+        let mut weekday_bits = parse_field(weekdays, 0, 7).map_err(|e| invalid(&e))?;
+        // 7也表示周日
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits = (weekday_bits & !(1 << 7)) | 1;
+        }
+        Ok(Cron {
+            expression: expression.trim().to_string(),
+            minutes: parse_field(minutes, 0, 59).map_err(|e| invalid(&e))?,
+            hours: parse_field(hours, 0, 23).map_err(|e| invalid(&e))?,
+            days: parse_field(days, 1, 31).map_err(|e| invalid(&e))?,
+            months: parse_field(months, 1, 12).map_err(|e| invalid(&e))?,
+            weekdays: weekday_bits,
+            any_day: days.starts_with('*'),
+            any_weekday: weekdays.starts_with('*'),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.expression
+    }
+
+    fn matches_day(&self, day: u32, weekday: u32) -> bool {
+        let day = self.days & (1 << day) != 0;
+        let weekday = self.weekdays & (1 << weekday) != 0;
+        if self.any_day || self.any_weekday {
+            day && weekday
+        } else {
+            day || weekday
+        }
+    }
+
+    /// `time`（Unix秒）之后的下一次运行时间，表达式永远不会满足（如`0 0 31 2 *`）时为None
+    pub fn next_after(&self, time: i64) -> Option<i64> {
+        let mut time = (time.div_euclid(60) + 1) * 60;
+        let limit = time + SEARCH_DAYS * 86400;
+        while time < limit {
+            let days = time.div_euclid(86400);
+            let (year, month, day) = civil_from_days(days);
+            if self.months & (1 << month) == 0 {
+                time = days_from_civil(if month == 12 { year + 1 } else { year }, month % 12 + 1, 1) * 86400;
+                continue;
+            }
+            // 1970-01-01是周四
+            let weekday = (days + 4).rem_euclid(7) as u32;
+            if !self.matches_day(day, weekday) {
+                time = (days + 1) * 86400;
+                continue;
+            }
+            let seconds = time.rem_euclid(86400);
+            if self.hours & (1 << (seconds / 3600)) == 0 {
+                time = days * 86400 + (seconds / 3600 + 1) * 3600;
+                continue;
+            }
+            if self.minutes & (1 << (seconds % 3600 / 60)) == 0 {
+                time += 60;
+                continue;
+            }
+            return Some(time);
+        }
+        None
+    }
+}
+
+/// 解析一个字段，返回取值的位图
+fn parse_field(field: &str, min: u32, max: u32) -> std::result::Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<u32>().ok().filter(|&s| s > 0).ok_or(format!("无效的步长 {}", step))?)),
+            None => (part, None),
+        };
+        let number = |s: &str| s.parse::<u32>().ok().filter(|n| (min..=max).contains(n)).ok_or(format!("{} 不在{}到{}之间", s, min, max));
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (number(start)?, number(end)?),
+            // `5/15`表示从5开始每15个
+            None if step.is_some() => (number(range)?, max),
+            None => {
+                let n = number(range)?;
+                (n, n)
+            }
+        };
+        if start > end {
+            return Err(format!("范围 {} 的起点大于终点", range));
+        }
+        for n in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+/// 定时运行的任务
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Job {
+    /// 删除所有表中已过期的记录（`sweep_expired`）
+    SweepExpired,
+    /// 按当前设置重写所有表文件并清理不再引用的blob文件（`compact`）
+    Compact,
+    /// 把备份写入`dir`下的`backup-<Unix秒>.sdbbak`，只保留最近的`keep`个，为0时全部保留
+    Backup { dir: String, keep: usize },
+    /// 重新收集所有表的统计信息（`analyze`）
+    RefreshStats,
+    /// 运行以`SimpleDB::on_job`注册的同名处理函数
+    Custom(String),
+}
+
+impl Job {
+    pub(crate) fn run(&self, db: &SimpleDB, handler: Option<JobHandler>) -> Result<()> {
+        match self {
+            Job::SweepExpired => db.sweep_expired().map(drop),
+            Job::Compact => db.compact().map(drop),
+            Job::Backup { dir, keep } => backup(db, Path::new(dir), *keep),
+            Job::RefreshStats => {
+                for table in db.list_tables() {
+                    db.analyze(&table)?;
+                }
+                Ok(())
+            }
+            Job::Custom(name) => match handler {
+                Some(handler) => handler(db),
+                None => Err(DatabaseError::Config(format!("没有注册任务 {} 的处理函数", name))),
+            },
+        }
+    }
+}
+
+fn backup(db: &SimpleDB, dir: &Path, keep: usize) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    db.backup(&dir.join(format!("backup-{}.sdbbak", timestamp)))?;
+    if keep == 0 {
+        return Ok(());
+    }
+    let mut backups: Vec<(u64, PathBuf)> = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let timestamp = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("backup-")?.strip_suffix(".sdbbak")?.parse().ok());
+        if let Some(timestamp) = timestamp {
+            backups.push((timestamp, path));
+        }
+    }
+    backups.sort();
+    for (_, path) in &backups[..backups.len().saturating_sub(keep)] {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// `Job::Custom`的处理函数
+pub type JobHandler = Arc<dyn Fn(&SimpleDB) -> Result<()> + Send + Sync>;
+
+/// 一个定时任务的计划和最近一次运行的结果
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledJob {
+    pub name: String,
+    pub cron: String,
+    pub job: Job,
+    /// 下次运行的Unix时间（秒）
+    pub next_run: i64,
+    pub last_run: Option<i64>,
+    /// 最近一次运行失败的原因，成功时为None
+    pub last_error: Option<String>,
+}
+
+/// 数据目录中的定时任务
+pub(crate) struct Schedules {
+    path: PathBuf,
+    crypto: Option<Crypto>,
+    jobs: BTreeMap<String, ScheduledJob>,
+    handlers: HashMap<String, JobHandler>,
+}
+
+impl Schedules {
+    pub(crate) fn open(data_dir: &Path, crypto: Option<Crypto>) -> Result<Self> {
+        let path = data_dir.join(SCHEDULE_FILE);
+        let jobs = statefile::load(&path, SCHEDULE_MAGIC, crypto.as_ref())?.unwrap_or_default();
+        Ok(Self {
+            path,
+            crypto,
+            jobs,
+            handlers: HashMap::new(),
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        // 任务中有表名和备份目录，随表数据加密
+        statefile::store(&self.path, SCHEDULE_MAGIC, &self.jobs, self.crypto.as_ref())
+    }
+
+    /// 添加或替换任务，下次运行时间从`now`算起
+    pub(crate) fn insert(&mut self, name: &str, cron: &str, job: Job, now: i64) -> Result<()> {
+        let cron = Cron::parse(cron)?;
+        let next_run = cron
+            .next_after(now)
+            .ok_or_else(|| DatabaseError::Config(format!("cron表达式 {} 永远不会满足", cron.as_str())))?;
+        self.jobs.insert(
+            name.to_string(),
+            ScheduledJob {
+                name: name.to_string(),
+                cron: cron.as_str().to_string(),
+                job,
+                next_run,
+                last_run: None,
+                last_error: None,
+            },
+        );
+        self.save()
+    }
+
+    pub(crate) fn remove(&mut self, name: &str) -> Result<bool> {
+        if self.jobs.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    pub(crate) fn jobs(&self) -> Vec<ScheduledJob> {
+        self.jobs.values().cloned().collect()
+    }
+
+    pub(crate) fn set_handler(&mut self, name: &str, handler: JobHandler) {
+        self.handlers.insert(name.to_string(), handler);
+    }
+
+    /// 取出到期的任务并推迟到下一次运行时间，运行期间再次检查不会重复运行
+    pub(crate) fn take_due(&mut self, now: i64) -> Result<Vec<(String, Job, Option<JobHandler>)>> {
+        let mut due = Vec::new();
+        for job in self.jobs.values_mut().filter(|job| job.next_run <= now) {
+            // 表达式在保存时已经检查过
+            job.next_run = Cron::parse(&job.cron)?.next_after(now).unwrap_or(i64::MAX);
+            let handler = match &job.job {
+                Job::Custom(name) => self.handlers.get(name).cloned(),
+                _ => None,
+            };
+            due.push((job.name.clone(), job.job.clone(), handler));
+        }
+        if !due.is_empty() {
+            self.save()?;
+        }
+        Ok(due)
+    }
+
+    /// 记录任务运行的结果
+    pub(crate) fn finish(&mut self, name: &str, time: i64, error: Option<String>) -> Result<()> {
+        let Some(job) = self.jobs.get_mut(name) else {
+            return Ok(());
+        };
+        job.last_run = Some(time);
+        job.last_error = error;
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+    use indexmap::IndexMap;
+    use crate::storage::Value;
+    use crate::Config;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_cron_next_after() {
+        // 2024-02-28 23:59:30 UTC，周三
+        let time = days_from_civil(2024, 2, 28) * 86400 + 23 * 3600 + 59 * 60 + 30;
+        let next = |expression: &str| Cron::parse(expression).unwrap().next_after(time).map(|t| {
+            let (year, month, day) = civil_from_days(t.div_euclid(86400));
+            (year, month, day, t.rem_euclid(86400) / 3600, t.rem_euclid(3600) / 60)
+        });
+        assert_eq!(next("0 3 * * *"), Some((2024, 2, 29, 3, 0)));
+        assert_eq!(next("*/15 * * * *"), Some((2024, 2, 29, 0, 0)));
+        assert_eq!(next("30 8-18/2 * * 1-5"), Some((2024, 2, 29, 8, 30)));
+        assert_eq!(next("0 0 29 2 *"), Some((2024, 2, 29, 0, 0)));
+        assert_eq!(next("0 0 1 * *"), Some((2024, 3, 1, 0, 0)));
+        assert_eq!(next("@weekly"), Some((2024, 3, 3, 0, 0)));
+        // 日和周都指定时满足其一即可
+        assert_eq!(next("0 12 15 * 5"), Some((2024, 3, 1, 12, 0)));
+        assert_eq!(next("0 0 10 * 0,7"), Some((2024, 3, 3, 0, 0)));
+        assert_eq!(next("0 0 31 2 *"), None);
+        for invalid in ["0 3 * *", "60 * * * *", "*/0 * * * *", "5-1 * * * *", "a * * * *"] {
+            assert!(Cron::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_scheduled_jobs() {
+        let dir = temp_dir("schedule");
+        let config = Config {
+            data_dir: dir.path().join("data").to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+        let backups = dir.path().join("backups");
+        let db = SimpleDB::new(config.clone()).unwrap();
+        db.insert("users", IndexMap::from([("name".to_string(), Value::String("张三".to_string()))])).unwrap();
+        let backup = Job::Backup {
+            dir: backups.to_string_lossy().into_owned(),
+            keep: 1,
+        };
+        db.schedule("backup", "0 3 * * *", backup).unwrap();
+        db.schedule("report", "*/5 * * * *", Job::Custom("report".to_string())).unwrap();
+        assert!(db.schedule("never", "0 0 30 2 *", Job::Compact).is_err());
+        drop(db);
+
+        // 计划在重启后继续生效，处理函数要重新注册
+        let db = SimpleDB::new(config).unwrap();
+        let jobs = db.scheduled_jobs().unwrap();
+        assert_eq!(jobs.iter().map(|job| job.name.as_str()).collect::<Vec<_>>(), ["backup", "report"]);
+        let due = jobs[0].next_run.max(jobs[1].next_run);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&runs);
+        db.on_job("report", move |db| {
+            assert_eq!(db.count("users")?, 1);
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        let ran = db.run_jobs_due_at(due).unwrap();
+        assert_eq!(ran.iter().map(|(name, result)| (name.as_str(), result.is_ok())).collect::<Vec<_>>(), [("backup", true), ("report", true)]);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(std::fs::read_dir(&backups).unwrap().count(), 1);
+        // 已运行的任务推迟到下一次
+        assert!(db.run_jobs_due_at(due).unwrap().is_empty());
+        let jobs = db.scheduled_jobs().unwrap();
+        assert_eq!(jobs[0].next_run, due + 86400);
+        assert_eq!((jobs[1].last_run, jobs[1].next_run), (Some(due), due + 300));
+
+        assert!(db.unschedule("report").unwrap());
+        assert!(!db.unschedule("report").unwrap());
+        drop(db);
+    }
+}
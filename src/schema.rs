@@ -0,0 +1,190 @@
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::datetime;
+use crate::storage::{Record, Value};
+use crate::validate::FieldError;
+
+/// 在表结构中为字段声明的类型，保存在表的元数据中，见`SimpleDB::set_field_type`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    Bool,
+    Int,
+    /// 浮点数，也接受整数
+    Float,
+    String,
+    Bytes,
+    Array,
+    Object,
+    /// ISO-8601日期时间，API收到的字符串保存为`Value::Timestamp`，范围查询按时间先后比较
+    DateTime,
+}
+
+/// 表的结构模式，保存在表的元数据中，见`SimpleDB::set_table_mode`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TableMode {
+    /// 字段不受限制，声明的类型只用于转换客户端发送的值
+    #[default]
+    Flexible,
+    /// 只能写入声明了类型的字段，值必须符合声明的类型；null和加密值不检查类型
+    Strict,
+}
+
+impl FieldType {
+    /// 把客户端发送的值转换为声明的类型，数组逐个元素转换；无法转换的值保持不变
+    pub fn convert(self, value: Value) -> Value {
+        match (self, value) {
+            (FieldType::DateTime, Value::String(s)) => datetime::parse(&s).map_or(Value::String(s), Value::Timestamp),
+            (_, Value::Array(items)) => Value::Array(items.into_iter().map(|item| self.convert(item)).collect()),
+            (_, value) => value,
+        }
+    }
+
+    /// 值是否符合声明的类型，压缩保存和放入blob存储的值按原来的类型判断
+    pub fn accepts(self, value: &Value) -> bool {
+        matches!(
+            (self, value),
+            (_, Value::Null | Value::Sealed { .. } | Value::Deterministic { .. })
+                | (FieldType::Bool, Value::Bool(_))
+                | (FieldType::Int, Value::Int(_))
+                | (FieldType::Float, Value::Float(_) | Value::Int(_))
+                | (FieldType::String, Value::String(_) | Value::Compressed { binary: false, .. })
+                | (FieldType::Bytes, Value::Bytes(_) | Value::Compressed { binary: true, .. } | Value::Blob { .. })
+                | (FieldType::Array, Value::Array(_))
+                | (FieldType::Object, Value::Object(_))
+                | (FieldType::DateTime, Value::Timestamp(_))
+        )
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FieldType::Bool => "bool",
+            FieldType::Int => "int",
+            FieldType::Float => "float",
+            FieldType::String => "string",
+            FieldType::Bytes => "bytes",
+            FieldType::Array => "array",
+            FieldType::Object => "object",
+            FieldType::DateTime => "datetime",
+        }
+    }
+}
+
+/// 按严格模式检查数据：没有声明的字段和不符合声明类型的值各产生一个字段错误
+pub(crate) fn check_strict(types: &BTreeMap<String, FieldType>, data: &IndexMap<String, Value>) -> Vec<FieldError> {
+    data.iter()
+        .filter_map(|(field, value)| match types.get(field) {
+            None => Some(FieldError::new(field, "严格模式的表没有声明该字段")),
+            Some(field_type) if !field_type.accepts(value) => Some(FieldError::new(
+                field,
+                format!("应为{}，实际为{}", field_type.name(), value.type_name()),
+            )),
+            Some(_) => None,
+        })
+        .collect()
+}
+
+/// 从记录中推断出的字段信息
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldInfo {
+    pub name: String,
+    /// 观察到的值类型及出现次数
+    pub types: BTreeMap<String, usize>,
+    /// 含有该字段的记录占样本的比例（0~1）
+    pub coverage: f64,
+    /// 是否有记录缺少该字段或值为null
+    pub nullable: bool,
+}
+
+/// 基于样本推断的表结构
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SchemaSample {
+    /// 参与推断的记录数
+    pub sampled: usize,
+    /// 表中的记录总数
+    pub total: usize,
+    /// 按字段名排序
+    pub fields: Vec<FieldInfo>,
+}
+
+impl SchemaSample {
+    /// 由记录样本推断字段类型与覆盖率
+    pub fn infer<'a>(records: impl Iterator<Item = &'a Record>, total: usize) -> Self {
+        let mut fields: BTreeMap<&str, BTreeMap<String, usize>> = BTreeMap::new();
+        let mut sampled = 0;
+        for record in records {
+            sampled += 1;
+            for (name, value) in &record.data {
+                *fields.entry(name).or_default().entry(value.type_name().to_string()).or_default() += 1;
+            }
+        }
+
+        let fields = fields
+            .into_iter()
+            .map(|(name, types)| {
+                let present: usize = types.values().sum();
+                FieldInfo {
+                    name: name.to_string(),
+                    nullable: present < sampled || types.contains_key("null"),
+                    types,
+                    coverage: present as f64 / sampled as f64,
+                }
+            })
+            .collect();
+        Self { sampled, total, fields }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Value;
+    use indexmap::IndexMap;
+
+    #[test]
+    fn test_convert_datetime() {
+        let convert = |s: &str| FieldType::DateTime.convert(Value::String(s.to_string()));
+        assert_eq!(convert("1970-01-01T00:00:01.5Z"), Value::Timestamp(1500));
+        assert_eq!(convert("明天"), Value::String("明天".to_string()));
+        assert_eq!(
+            FieldType::DateTime.convert(Value::Array(vec![Value::String("1970-01-02".to_string()), Value::Int(1)])),
+            Value::Array(vec![Value::Timestamp(86_400_000), Value::Int(1)])
+        );
+    }
+
+    #[test]
+    fn test_check_strict() {
+        let types = BTreeMap::from([("age".to_string(), FieldType::Int), ("score".to_string(), FieldType::Float)]);
+        let data = IndexMap::from([
+            ("age".to_string(), Value::String("30".to_string())),
+            ("score".to_string(), Value::Int(9)),
+            ("nick".to_string(), Value::Null),
+        ]);
+        let fields: Vec<String> = check_strict(&types, &data).into_iter().map(|e| e.field).collect();
+        assert_eq!(fields, ["age", "nick"]);
+        assert!(FieldType::String.accepts(&Value::Compressed { binary: false, data: Vec::new() }));
+        assert!(!FieldType::String.accepts(&Value::Compressed { binary: true, data: Vec::new() }));
+    }
+
+    #[test]
+    fn test_infer_types_and_coverage() {
+        let records: Vec<Record> = [Value::Int(1), Value::String("x".to_string())]
+            .into_iter()
+            .map(|v| Record::new(IndexMap::from([("a".to_string(), v.clone()), ("b".to_string(), v)])))
+            .chain(std::iter::once(Record::new(IndexMap::from([("b".to_string(), Value::Null)]))))
+            .collect();
+        let schema = SchemaSample::infer(records.iter(), 10);
+        assert_eq!(schema.sampled, 3);
+        assert_eq!(schema.total, 10);
+        assert_eq!(schema.fields.len(), 2);
+        assert_eq!(schema.fields[0].types, BTreeMap::from([("int".to_string(), 1), ("string".to_string(), 1)]));
+        assert!((schema.fields[0].coverage - 2.0 / 3.0).abs() < 1e-9);
+        assert!(schema.fields[0].nullable);
+        // 字段都存在，但有一条为null
+        assert_eq!(schema.fields[1].coverage, 1.0);
+        assert!(schema.fields[1].nullable);
+    }
+}
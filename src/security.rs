@@ -0,0 +1,49 @@
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+/// 数据目录的静态加密情况，由`SimpleDB::security_report`生成
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SecurityReport {
+    /// 按表名排序
+    pub tables: Vec<TableSecurity>,
+    /// 数据目录中的文件（不含子目录），按文件名排序
+    pub files: Vec<FileSecurity>,
+}
+
+impl SecurityReport {
+    /// 内容未加密的文件
+    pub fn plaintext_files(&self) -> impl Iterator<Item = &str> {
+        self.files.iter().filter(|f| !f.encrypted).map(|f| f.name.as_str())
+    }
+
+    /// 磁盘上的加密状态与配置不一致、下次保存时才会按配置重写的文件
+    pub fn unmigrated_files(&self) -> impl Iterator<Item = &str> {
+        self.tables.iter().flat_map(|t| t.unmigrated_files.iter().map(String::as_str))
+    }
+}
+
+/// 一张表的加密情况
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TableSecurity {
+    pub table: String,
+    /// 表文件是否配置为加密
+    pub encrypted: bool,
+    /// 表文件使用的`Config::table_keys`中的密钥ID，None且`encrypted`为true时使用主密钥
+    pub key_id: Option<String>,
+    /// 以明文写入旁路文件的字段（`TableConfig::plaintext_fields`），只对加密的表有效
+    pub plaintext_fields: Vec<String>,
+    /// 含有按主体加密的值（`db.seal`）的字段
+    pub sealed_fields: BTreeSet<String>,
+    /// 含有确定性加密的值（`db.seal_deterministic`）的字段
+    pub deterministic_fields: BTreeSet<String>,
+    /// 磁盘上的加密状态与配置不一致的文件，如启用加密之前写出、之后还没有重新保存的明文文件
+    pub unmigrated_files: Vec<String>,
+}
+
+/// 数据目录中一个文件的加密情况
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileSecurity {
+    pub name: String,
+    /// 内容是否加密；主体密钥文件中的密钥由主密钥加密，视为加密
+    pub encrypted: bool,
+}
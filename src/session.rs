@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::lock::Recover;
+use crate::transaction::Transaction;
+
+/// HTTP事务默认的空闲超时
+pub const DEFAULT_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Session {
+    tx: Transaction,
+    expires_at: Instant,
+}
+
+/// 服务器端保存的未提交事务
+///
+/// 每次访问都会顺延超时；超时的事务视为已回滚，其中的操作不会生效。
+pub struct TransactionSessions {
+    timeout: Duration,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl TransactionSessions {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// 开始新事务，返回事务ID
+    pub fn begin(&self) -> String {
+        let id = Uuid::new_v4().to_string();
+        let mut sessions = self.sessions.lock().recover();
+        Self::purge_expired(&mut sessions);
+        sessions.insert(
+            id.clone(),
+            Session {
+                tx: Transaction::new(),
+                expires_at: Instant::now() + self.timeout,
+            },
+        );
+        id
+    }
+
+    /// 在未超时的事务上执行操作，事务不存在时返回None
+    pub fn with<T>(&self, id: &str, f: impl FnOnce(&mut Transaction) -> T) -> Option<T> {
+        let mut sessions = self.sessions.lock().recover();
+        Self::purge_expired(&mut sessions);
+        let session = sessions.get_mut(id)?;
+        session.expires_at = Instant::now() + self.timeout;
+        Some(f(&mut session.tx))
+    }
+
+    /// 取出事务用于提交或回滚
+    pub fn take(&self, id: &str) -> Option<Transaction> {
+        let mut sessions = self.sessions.lock().recover();
+        Self::purge_expired(&mut sessions);
+        sessions.remove(id).map(|session| session.tx)
+    }
+
+    fn purge_expired(sessions: &mut HashMap<String, Session>) {
+        let now = Instant::now();
+        sessions.retain(|_, session| session.expires_at > now);
+    }
+}
@@ -0,0 +1,186 @@
+use aes::cipher::consts::U16;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockEncrypt, BlockSizeUser, KeyInit};
+use aes::Aes256;
+
+use crate::error::{DatabaseError, Result};
+
+type Block = [u8; 16];
+
+/// 派生子密钥用的标签，每个16字节
+const DERIVE_LABELS: [&[u8; 16]; 4] = [b"simpledb-siv-m-0", b"simpledb-siv-m-1", b"simpledb-siv-c-0", b"simpledb-siv-c-1"];
+
+/// 确定性认证加密（RFC 5297 AES-SIV）
+///
+/// 相同的明文和关联数据总是得到相同的密文，因此加密后的值仍能做等值比较和索引，
+/// 代价是泄露了哪些值彼此相等；密文的大小顺序与明文无关，无法做范围查询。
+#[derive(Clone)]
+pub struct Siv<C = Aes256> {
+    mac: C,
+    ctr: C,
+    /// CMAC的两个子密钥
+    k1: Block,
+    k2: Block,
+}
+
+impl<C> std::fmt::Debug for Siv<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Siv").field("key", &"<AES-SIV key>").finish()
+    }
+}
+
+impl Siv<Aes256> {
+    /// 从32字节主密钥派生确定性加密所需的两把密钥
+    pub fn derive(master_key: &[u8]) -> Result<Self> {
+        let prf = Aes256::new_from_slice(master_key)
+            .map_err(|_| DatabaseError::Encryption("密钥长度必须为32字节".to_string()))?;
+        let key: Vec<u8> = DERIVE_LABELS.iter().flat_map(|label| encrypt_block(&prf, **label)).collect();
+        Self::new(&key)
+    }
+}
+
+impl<C: BlockEncrypt + BlockSizeUser<BlockSize = U16> + KeyInit> Siv<C> {
+    /// 密钥为两把分组密钥拼接：前一半用于S2V，后一半用于CTR加密
+    pub fn new(key: &[u8]) -> Result<Self> {
+        let invalid = || DatabaseError::Encryption("SIV密钥长度无效".to_string());
+        if !key.len().is_multiple_of(2) {
+            return Err(invalid());
+        }
+        let (mac_key, ctr_key) = key.split_at(key.len() / 2);
+        let mac = C::new_from_slice(mac_key).map_err(|_| invalid())?;
+        let ctr = C::new_from_slice(ctr_key).map_err(|_| invalid())?;
+        let k1 = dbl(encrypt_block(&mac, [0; 16]));
+        let k2 = dbl(k1);
+        Ok(Self { mac, ctr, k1, k2 })
+    }
+
+    /// 加密，输出为16字节合成IV加上密文
+    pub fn encrypt(&self, associated_data: &[&[u8]], plaintext: &[u8]) -> Vec<u8> {
+        let iv = self.s2v(associated_data, plaintext);
+        let mut out = Vec::with_capacity(16 + plaintext.len());
+        out.extend_from_slice(&iv);
+        out.extend_from_slice(plaintext);
+        self.apply_ctr(iv, &mut out[16..]);
+        out
+    }
+
+    /// 解密并校验，关联数据必须与加密时一致
+    pub fn decrypt(&self, associated_data: &[&[u8]], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        if ciphertext.len() < 16 {
+            return Err(DatabaseError::Encryption("SIV密文太短，至少需要16字节".to_string()));
+        }
+        let (iv, body) = ciphertext.split_at(16);
+        let iv: Block = iv.try_into().expect("长度已检查");
+        let mut plaintext = body.to_vec();
+        self.apply_ctr(iv, &mut plaintext);
+
+        let expected = self.s2v(associated_data, &plaintext);
+        // 常量时间比较
+        if expected.iter().zip(iv).fold(0u8, |acc, (a, b)| acc | (a ^ b)) != 0 {
+            return Err(DatabaseError::Encryption("解密失败: SIV校验不通过".to_string()));
+        }
+        Ok(plaintext)
+    }
+
+    /// 对`data`计算16字节的消息认证码，`domain`用于区分不同用途
+    pub fn mac(&self, domain: &[u8], data: &[u8]) -> Block {
+        self.s2v(&[domain], data)
+    }
+
+    /// RFC 4493 AES-CMAC
+    fn cmac(&self, message: &[u8]) -> Block {
+        let blocks = message.len().div_ceil(16).max(1);
+        let mut x = [0u8; 16];
+        for chunk in message.chunks(16).take(blocks - 1) {
+            x = encrypt_block(&self.mac, xor(x, chunk.try_into().expect("完整的分组")));
+        }
+        let last = &message[(blocks - 1) * 16..];
+        let last = if last.len() == 16 {
+            xor(last.try_into().expect("完整的分组"), self.k1)
+        } else {
+            xor(pad(last), self.k2)
+        };
+        encrypt_block(&self.mac, xor(x, last))
+    }
+
+    /// RFC 5297 S2V：由关联数据和明文计算合成IV
+    fn s2v(&self, associated_data: &[&[u8]], plaintext: &[u8]) -> Block {
+        let mut d = self.cmac(&[0u8; 16]);
+        for data in associated_data {
+            d = xor(dbl(d), self.cmac(data));
+        }
+        if plaintext.len() >= 16 {
+            let mut t = plaintext.to_vec();
+            let tail = t.len() - 16;
+            for (byte, mask) in t[tail..].iter_mut().zip(d) {
+                *byte ^= mask;
+            }
+            self.cmac(&t)
+        } else {
+            self.cmac(&xor(dbl(d), pad(plaintext)))
+        }
+    }
+
+    /// 以合成IV（清除第63和31位）为初始计数器的CTR模式
+    fn apply_ctr(&self, iv: Block, data: &mut [u8]) {
+        let mut q = iv;
+        q[8] &= 0x7f;
+        q[12] &= 0x7f;
+        let mut counter = u128::from_be_bytes(q);
+        for chunk in data.chunks_mut(16) {
+            let keystream = encrypt_block(&self.ctr, counter.to_be_bytes());
+            for (byte, key) in chunk.iter_mut().zip(keystream) {
+                *byte ^= key;
+            }
+            counter = counter.wrapping_add(1);
+        }
+    }
+}
+
+fn encrypt_block<C: BlockEncrypt + BlockSizeUser<BlockSize = U16>>(cipher: &C, block: Block) -> Block {
+    let mut block = GenericArray::from(block);
+    cipher.encrypt_block(&mut block);
+    block.into()
+}
+
+/// GF(2^128)上乘以x
+fn dbl(block: Block) -> Block {
+    let value = u128::from_be_bytes(block);
+    let carry = if value >> 127 == 1 { 0x87 } else { 0 };
+    ((value << 1) ^ carry).to_be_bytes()
+}
+
+fn xor(a: Block, b: Block) -> Block {
+    (u128::from_be_bytes(a) ^ u128::from_be_bytes(b)).to_be_bytes()
+}
+
+/// 不足16字节时补一个0x80再补零
+fn pad(data: &[u8]) -> Block {
+    let mut block = [0u8; 16];
+    block[..data.len()].copy_from_slice(data);
+    block[data.len()] = 0x80;
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::Aes128;
+
+    #[test]
+    fn test_rfc5297_vector() {
+        // RFC 5297 附录A.1
+        let key = hex::decode("fffefdfcfbfaf9f8f7f6f5f4f3f2f1f0f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff").unwrap();
+        let ad = hex::decode("101112131415161718191a1b1c1d1e1f2021222324252627").unwrap();
+        let plaintext = hex::decode("112233445566778899aabbccddee").unwrap();
+        let siv = Siv::<Aes128>::new(&key).unwrap();
+
+        let ciphertext = siv.encrypt(&[&ad], &plaintext);
+        assert_eq!(
+            hex::encode(&ciphertext),
+            "85632d07c6e8f37f950acd320a2ecc9340c02b9690c4dc04daef7f6afe5c"
+        );
+        assert_eq!(siv.decrypt(&[&ad], &ciphertext).unwrap(), plaintext);
+        assert!(siv.decrypt(&[b"other"], &ciphertext).is_err());
+    }
+}
@@ -0,0 +1,1105 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+
+use crate::database::SimpleDB;
+use crate::error::{DatabaseError, Result};
+use crate::index::FieldIndex;
+use crate::query::{compare_field, compare_values, Condition, Operator, Query, SortOrder};
+use crate::schema::{FieldType, TableMode};
+use crate::storage::{Record, Value};
+
+/// 解析后的SELECT语句
+#[derive(Debug, Clone, PartialEq)]
+pub struct Select {
+    pub projection: Vec<SelectItem>,
+    pub from: TableRef,
+    pub joins: Vec<Join>,
+    /// WHERE子句中以AND连接的条件
+    pub conditions: Vec<Predicate>,
+    pub group_by: Vec<ColumnRef>,
+    pub order_by: Vec<(ColumnRef, SortOrder)>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+/// 列引用，可带表名或别名限定
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnRef {
+    pub table: Option<String>,
+    pub name: String,
+}
+
+impl ColumnRef {
+    pub fn new(name: &str) -> Self {
+        Self {
+            table: None,
+            name: name.to_string(),
+        }
+    }
+
+    pub fn qualified(table: &str, name: &str) -> Self {
+        Self {
+            table: Some(table.to_string()),
+            name: name.to_string(),
+        }
+    }
+}
+
+/// FROM或JOIN中的表
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRef {
+    pub name: String,
+    pub alias: Option<String>,
+}
+
+impl TableRef {
+    /// 列引用中使用的名字
+    pub fn binding(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinKind {
+    Inner,
+    Left,
+}
+
+/// `JOIN table ON a.col = b.col`，只支持等值连接
+#[derive(Debug, Clone, PartialEq)]
+pub struct Join {
+    pub kind: JoinKind,
+    pub table: TableRef,
+    pub left: ColumnRef,
+    pub right: ColumnRef,
+}
+
+/// `列 运算符 字面量`形式的过滤条件
+#[derive(Debug, Clone, PartialEq)]
+pub struct Predicate {
+    pub column: ColumnRef,
+    pub op: Operator,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+/// 整数的`SUM`超出i64范围时的处理方式，见`Config::aggregate_overflow`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// 以i128精确累加，结果超出i64范围时返回最接近的`Float`
+    #[default]
+    Widen,
+    /// 返回`DatabaseError::Overflow`
+    Error,
+}
+
+impl Aggregate {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "COUNT" => Some(Aggregate::Count),
+            "SUM" => Some(Aggregate::Sum),
+            "AVG" => Some(Aggregate::Avg),
+            "MIN" => Some(Aggregate::Min),
+            "MAX" => Some(Aggregate::Max),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Aggregate::Count => "count",
+            Aggregate::Sum => "sum",
+            Aggregate::Avg => "avg",
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+        }
+    }
+
+    /// 对一组值求聚合，忽略NULL；`COUNT(*)`传入None
+    ///
+    /// 整数以i128累加，不会回绕；只有整数的`SUM`超出i64范围时按`overflow`处理。
+    pub(crate) fn apply(&self, rows: usize, values: Option<Vec<Value>>, overflow: OverflowPolicy) -> Result<Value> {
+        let Some(values) = values else {
+            return Ok(Value::Int(rows as i64));
+        };
+        let values: Vec<Value> = values.into_iter().filter(|v| !matches!(v, Value::Null)).collect();
+        let sum = || {
+            let (mut ints, mut floats, mut count, mut any_float) = (0i128, 0.0, 0usize, false);
+            for value in &values {
+                match value {
+                    Value::Int(i) => ints += i128::from(*i),
+                    Value::Float(f) => {
+                        floats += f;
+                        any_float = true;
+                    }
+                    _ => continue,
+                }
+                count += 1;
+            }
+            (ints, floats, count, any_float)
+        };
+        Ok(match self {
+            Aggregate::Count => Value::Int(values.len() as i64),
+            Aggregate::Sum => match sum() {
+                (_, _, 0, _) => Value::Null,
+                (ints, floats, _, true) => Value::Float(ints as f64 + floats),
+                (ints, _, _, false) => match (i64::try_from(ints), overflow) {
+                    (Ok(total), _) => Value::Int(total),
+                    (Err(_), OverflowPolicy::Widen) => Value::Float(ints as f64),
+                    (Err(_), OverflowPolicy::Error) => {
+                        return Err(DatabaseError::Overflow(format!("SUM的结果 {} 超出64位整数的范围", ints)));
+                    }
+                },
+            },
+            Aggregate::Avg => match sum() {
+                (_, _, 0, _) => Value::Null,
+                (ints, floats, count, _) => Value::Float((ints as f64 + floats) / count as f64),
+            },
+            Aggregate::Min | Aggregate::Max => {
+                let wanted = if *self == Aggregate::Min { Ordering::Less } else { Ordering::Greater };
+                values.into_iter().fold(Value::Null, |best, value| {
+                    if matches!(best, Value::Null) || compare_values(&value, &best) == Some(wanted) {
+                        value
+                    } else {
+                        best
+                    }
+                })
+            }
+        })
+    }
+}
+
+/// 选择列表中的一项
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectItem {
+    /// `*`：`id`加上所有出现过的字段，有连接时按`表.列`展开
+    Wildcard,
+    Column { column: ColumnRef, alias: Option<String> },
+    /// `column`为None表示`COUNT(*)`
+    Aggregate {
+        func: Aggregate,
+        column: Option<ColumnRef>,
+        alias: Option<String>,
+    },
+}
+
+/// SELECT的执行结果
+#[derive(Debug, Clone, PartialEq)]
+pub struct SqlResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+/// 规划查询得到的结果列
+#[derive(Debug, Clone, PartialEq)]
+pub struct SqlColumn {
+    pub name: String,
+    /// 执行前能确定的值类型：`id`、`COUNT`和严格模式的表中声明了类型的字段，其他为None
+    pub field_type: Option<FieldType>,
+}
+
+impl SqlResult {
+    /// 按`Select::describe`规划的列重新排列结果，结果中没有的列为NULL
+    pub fn conform(self, columns: &[SqlColumn]) -> SqlResult {
+        let positions: Vec<Option<usize>> = columns.iter().map(|c| self.columns.iter().position(|name| *name == c.name)).collect();
+        SqlResult {
+            columns: columns.iter().map(|c| c.name.clone()).collect(),
+            rows: self
+                .rows
+                .into_iter()
+                .map(|row| positions.iter().map(|p| p.map_or(Value::Null, |i| row[i].clone())).collect())
+                .collect(),
+        }
+    }
+}
+
+/// 连接后的一行：每张表对应一条记录，LEFT JOIN未匹配时为None
+type Row = Vec<Option<Arc<Record>>>;
+
+/// 解析到具体表的列：None表示未限定的列，在连接结果中按表的顺序查找
+#[derive(Debug, Clone)]
+struct Bound {
+    source: Option<usize>,
+    name: String,
+}
+
+impl Bound {
+    fn value(&self, row: &Row) -> Value {
+        match self.source {
+            Some(i) => row[i].as_ref().map_or(Value::Null, |r| column_value(r, &self.name)),
+            None => row
+                .iter()
+                .flatten()
+                .find_map(|r| r.field(&self.name).map(Cow::into_owned))
+                .unwrap_or(Value::Null),
+        }
+    }
+}
+
+/// 排序键的来源：聚合输出列或源表中的列
+enum SortKey {
+    /// 选择列表中聚合项的位置
+    Output(usize),
+    Source(Bound),
+}
+
+impl Select {
+    /// 执行查询，`id`列对应记录ID
+    ///
+    /// 没有连接和聚合时，条件、排序和分页下推给查询引擎以利用索引；
+    /// 否则先按条件扫描主表，再在内存中完成连接、分组、排序和分页。
+    pub fn execute(&self, db: &SimpleDB) -> Result<SqlResult> {
+        let tables: Vec<&TableRef> = std::iter::once(&self.from).chain(self.joins.iter().map(|j| &j.table)).collect();
+        let aggregated = !self.group_by.is_empty()
+            || self.projection.iter().any(|item| matches!(item, SelectItem::Aggregate { .. }));
+
+        // 只涉及主表数据字段的条件可以直接交给查询引擎
+        let mut pushed = Vec::new();
+        let mut remaining = Vec::new();
+        for predicate in &self.conditions {
+            let bound = self.bind(&tables, &predicate.column)?;
+            let on_base = bound.source == Some(0) || (bound.source.is_none() && self.joins.is_empty());
+            let condition = Condition::new(&bound.name, predicate.op, predicate.value.clone());
+            if on_base {
+                pushed.push(condition);
+            } else {
+                remaining.push((bound, condition));
+            }
+        }
+
+        // ORDER BY可以引用输出列名（含别名），否则按源表中的列排序
+        let output_names: Vec<Option<String>> = self.projection.iter().map(Self::output_name).collect();
+        let mut sort_keys = Vec::new();
+        for (column, order) in &self.order_by {
+            let item = match column.table {
+                None => output_names.iter().position(|name| name.as_deref() == Some(column.name.as_str())),
+                Some(_) => None,
+            };
+            let key = match item.map(|i| (i, &self.projection[i])) {
+                Some((_, SelectItem::Column { column, .. })) => SortKey::Source(self.bind(&tables, column)?),
+                Some((i, _)) => SortKey::Output(i),
+                None => SortKey::Source(self.bind(&tables, column)?),
+            };
+            sort_keys.push((key, *order));
+        }
+
+        // 简单的单表查询：排序和分页也交给查询引擎
+        let simple = self.joins.is_empty()
+            && !aggregated
+            && remaining.is_empty()
+            && sort_keys.iter().all(|(key, _)| matches!(key, SortKey::Source(_)));
+        let mut query = Query::new();
+        for condition in pushed {
+            query = query.filter(condition);
+        }
+        if simple {
+            query = query
+                .order_by(
+                    sort_keys
+                        .iter()
+                        .filter_map(|(key, order)| match key {
+                            SortKey::Source(bound) => Some((bound.name.clone(), *order)),
+                            SortKey::Output(_) => None,
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .offset(self.offset);
+            if let Some(limit) = self.limit {
+                query = query.limit(limit);
+            }
+        }
+        let mut base = db.query(&self.from.name, &query)?;
+        if !simple {
+            // 固定初始顺序，保证结果稳定
+            base.sort_by(|a, b| a.id.cmp(&b.id));
+        }
+        let mut rows: Vec<Row> = base.into_iter().map(|record| vec![Some(record)]).collect();
+
+        for (i, join) in self.joins.iter().enumerate() {
+            rows = self.join(db, &tables, i + 1, join, rows)?;
+        }
+        rows.retain(|row| remaining.iter().all(|(bound, condition)| condition.matches_value(Some(&bound.value(row)))));
+
+        let (columns, mut output) = if aggregated {
+            self.aggregate(&tables, rows, &sort_keys, db.config().aggregate_overflow)?
+        } else {
+            self.project(&tables, rows, &sort_keys)?
+        };
+
+        if !simple {
+            if !sort_keys.is_empty() {
+                output.sort_by(|(_, a), (_, b)| {
+                    for ((x, y), (_, order)) in a.iter().zip(b).zip(&sort_keys) {
+                        let ordering = compare_field(non_null(x), non_null(y));
+                        let ordering = match order {
+                            SortOrder::Asc => ordering,
+                            SortOrder::Desc => ordering.reverse(),
+                        };
+                        if ordering != Ordering::Equal {
+                            return ordering;
+                        }
+                    }
+                    Ordering::Equal
+                });
+            }
+            let end = self.limit.map_or(output.len(), |limit| self.offset.saturating_add(limit));
+            output = output.into_iter().take(end).skip(self.offset).collect();
+        }
+
+        Ok(SqlResult {
+            columns,
+            rows: output.into_iter().map(|(row, _)| row).collect(),
+        })
+    }
+
+    /// 只规划查询、不执行，返回结果的列
+    ///
+    /// 检查表和列引用；`*`展开为`id`加上表中出现过或声明了类型的所有字段，
+    /// 比执行结果多出的列（没有匹配的记录含有该字段）在`conform`之后为NULL。
+    pub fn describe(&self, db: &SimpleDB) -> Result<Vec<SqlColumn>> {
+        let tables: Vec<&TableRef> = std::iter::once(&self.from).chain(self.joins.iter().map(|j| &j.table)).collect();
+        let aggregated = !self.group_by.is_empty()
+            || self.projection.iter().any(|item| matches!(item, SelectItem::Aggregate { .. }));
+        if aggregated && self.projection.contains(&SelectItem::Wildcard) {
+            return Err(DatabaseError::InvalidQuery("聚合查询不支持 *".to_string()));
+        }
+        // 严格模式的表中字段的值必须符合声明的类型
+        let mut declared = Vec::new();
+        for table in &tables {
+            declared.push(match db.table_mode(&table.name)? {
+                TableMode::Strict => db.field_types(&table.name)?,
+                TableMode::Flexible => BTreeMap::new(),
+            });
+        }
+        let field_type = |bound: &Bound| match (bound.source, bound.name.as_str()) {
+            (_, "id") => Some(FieldType::String),
+            (Some(i), name) => declared[i].get(name).copied(),
+            (None, _) => None,
+        };
+        for predicate in &self.conditions {
+            self.bind(&tables, &predicate.column)?;
+        }
+        for column in &self.group_by {
+            self.bind(&tables, column)?;
+        }
+
+        let mut columns = Vec::new();
+        for item in &self.projection {
+            match item {
+                SelectItem::Wildcard => {
+                    for (i, table) in tables.iter().enumerate() {
+                        let mut fields: BTreeSet<String> = db.infer_schema(&table.name)?.fields.into_iter().map(|f| f.name).collect();
+                        fields.extend(db.field_types(&table.name)?.into_keys());
+                        for name in std::iter::once("id".to_string()).chain(fields) {
+                            let bound = Bound { source: Some(i), name };
+                            columns.push(SqlColumn {
+                                name: if tables.len() == 1 {
+                                    bound.name.clone()
+                                } else {
+                                    format!("{}.{}", table.binding(), bound.name)
+                                },
+                                field_type: field_type(&bound),
+                            });
+                        }
+                    }
+                }
+                SelectItem::Column { column, .. } => columns.push(SqlColumn {
+                    name: Self::output_name(item).unwrap_or_default(),
+                    field_type: field_type(&self.bind(&tables, column)?),
+                }),
+                SelectItem::Aggregate { func, column, .. } => {
+                    if let Some(column) = column {
+                        self.bind(&tables, column)?;
+                    }
+                    columns.push(SqlColumn {
+                        name: Self::output_name(item).unwrap_or_default(),
+                        field_type: (*func == Aggregate::Count).then_some(FieldType::Int),
+                    });
+                }
+            }
+        }
+        Ok(columns)
+    }
+
+    /// 解析列引用属于哪张表
+    fn bind(&self, tables: &[&TableRef], column: &ColumnRef) -> Result<Bound> {
+        let source = match &column.table {
+            Some(qualifier) => Some(
+                tables
+                    .iter()
+                    .position(|t| t.binding() == qualifier)
+                    .ok_or_else(|| DatabaseError::InvalidQuery(format!("未知的表或别名: {}", qualifier)))?,
+            ),
+            None if tables.len() == 1 => Some(0),
+            None => None,
+        };
+        Ok(Bound {
+            source,
+            name: column.name.clone(),
+        })
+    }
+
+    fn output_name(item: &SelectItem) -> Option<String> {
+        match item {
+            SelectItem::Wildcard => None,
+            SelectItem::Column { column, alias } => Some(alias.clone().unwrap_or_else(|| column.name.clone())),
+            SelectItem::Aggregate { func, alias, .. } => Some(alias.clone().unwrap_or_else(|| func.name().to_string())),
+        }
+    }
+
+    /// 等值哈希连接，`index`为被连接表在行中的位置
+    fn join(&self, db: &SimpleDB, tables: &[&TableRef], index: usize, join: &Join, rows: Vec<Row>) -> Result<Vec<Row>> {
+        let left = self.bind(&tables[..=index], &join.left)?;
+        let right = self.bind(&tables[..=index], &join.right)?;
+        let (outer, inner) = match (left.source, right.source) {
+            (_, Some(i)) if i == index => (left, right),
+            (Some(i), _) if i == index => (right, left),
+            _ => {
+                return Err(DatabaseError::InvalidQuery(format!(
+                    "JOIN条件必须用限定列引用被连接的表: {}",
+                    join.table.binding()
+                )))
+            }
+        };
+
+        let mut lookup: HashMap<Vec<u8>, Vec<Arc<Record>>> = HashMap::new();
+        let mut records = db.find_all(&join.table.name)?;
+        records.sort_by(|a, b| a.id.cmp(&b.id));
+        for record in records {
+            let value = column_value(&record, &inner.name);
+            if !matches!(value, Value::Null) {
+                lookup.entry(FieldIndex::key(&value)).or_default().push(record);
+            }
+        }
+
+        let mut joined = Vec::new();
+        for row in rows {
+            let value = outer.value(&row);
+            let matches = if matches!(value, Value::Null) {
+                None
+            } else {
+                lookup.get(&FieldIndex::key(&value))
+            };
+            match matches {
+                Some(records) => {
+                    for record in records {
+                        let mut row = row.clone();
+                        row.push(Some(Arc::clone(record)));
+                        joined.push(row);
+                    }
+                }
+                None if join.kind == JoinKind::Left => {
+                    let mut row = row;
+                    row.push(None);
+                    joined.push(row);
+                }
+                None => {}
+            }
+        }
+        Ok(joined)
+    }
+
+    fn sort_values(sort_keys: &[(SortKey, SortOrder)], output: &[Value], row: &Row) -> Vec<Value> {
+        sort_keys
+            .iter()
+            .map(|(key, _)| match key {
+                SortKey::Output(i) => output[*i].clone(),
+                SortKey::Source(bound) => bound.value(row),
+            })
+            .collect()
+    }
+
+    /// 不带聚合的投影，返回列名和(输出行, 排序键)
+    #[allow(clippy::type_complexity)]
+    fn project(&self, tables: &[&TableRef], rows: Vec<Row>, sort_keys: &[(SortKey, SortOrder)]) -> Result<(Vec<String>, Vec<(Vec<Value>, Vec<Value>)>)> {
+        let mut columns = Vec::new();
+        let mut bindings = Vec::new();
+        for item in &self.projection {
+            match item {
+                SelectItem::Wildcard => {
+                    for (i, table) in tables.iter().enumerate() {
+                        let fields: BTreeSet<&String> = rows
+                            .iter()
+                            .filter_map(|row| row[i].as_ref())
+                            .flat_map(|r| r.data.keys())
+                            .collect();
+                        for name in std::iter::once("id").chain(fields.into_iter().map(String::as_str)) {
+                            columns.push(if tables.len() == 1 {
+                                name.to_string()
+                            } else {
+                                format!("{}.{}", table.binding(), name)
+                            });
+                            bindings.push(Bound {
+                                source: Some(i),
+                                name: name.to_string(),
+                            });
+                        }
+                    }
+                }
+                SelectItem::Column { column, .. } => {
+                    columns.push(Self::output_name(item).unwrap_or_default());
+                    bindings.push(self.bind(tables, column)?);
+                }
+                SelectItem::Aggregate { .. } => unreachable!("聚合查询走aggregate"),
+            }
+        }
+
+        let output = rows
+            .iter()
+            .map(|row| {
+                let values: Vec<Value> = bindings.iter().map(|b| b.value(row)).collect();
+                let keys = Self::sort_values(sort_keys, &values, row);
+                (values, keys)
+            })
+            .collect();
+        Ok((columns, output))
+    }
+
+    /// 分组聚合，没有GROUP BY时整个结果为一组
+    #[allow(clippy::type_complexity)]
+    fn aggregate(
+        &self,
+        tables: &[&TableRef],
+        rows: Vec<Row>,
+        sort_keys: &[(SortKey, SortOrder)],
+        overflow: OverflowPolicy,
+    ) -> Result<(Vec<String>, Vec<(Vec<Value>, Vec<Value>)>)> {
+        if self.projection.contains(&SelectItem::Wildcard) {
+            return Err(DatabaseError::InvalidQuery("聚合查询不支持 *".to_string()));
+        }
+        let group_by = self.group_by.iter().map(|c| self.bind(tables, c)).collect::<Result<Vec<_>>>()?;
+
+        let mut groups: Vec<Vec<Row>> = Vec::new();
+        let mut positions: HashMap<Vec<u8>, usize> = HashMap::new();
+        for row in rows {
+            let key: Vec<Value> = group_by.iter().map(|b| b.value(&row)).collect();
+            let key = bincode::serialize(&key).unwrap_or_default();
+            let position = *positions.entry(key).or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+            groups[position].push(row);
+        }
+        if groups.is_empty() && group_by.is_empty() {
+            groups.push(Vec::new());
+        }
+
+        let columns = self.projection.iter().filter_map(Self::output_name).collect();
+        let mut output = Vec::new();
+        for group in groups {
+            // 普通列取组内第一行的值
+            let first = group.first().cloned().unwrap_or_else(|| vec![None; tables.len()]);
+            let mut values = Vec::new();
+            for item in &self.projection {
+                values.push(match item {
+                    SelectItem::Column { column, .. } => self.bind(tables, column)?.value(&first),
+                    SelectItem::Aggregate { func, column, .. } => {
+                        let inputs = match column {
+                            Some(column) => {
+                                let bound = self.bind(tables, column)?;
+                                Some(group.iter().map(|row| bound.value(row)).collect())
+                            }
+                            None => None,
+                        };
+                        func.apply(group.len(), inputs, overflow)?
+                    }
+                    SelectItem::Wildcard => unreachable!(),
+                });
+            }
+            let keys = Self::sort_values(sort_keys, &values, &first);
+            output.push((values, keys));
+        }
+        Ok((columns, output))
+    }
+}
+
+/// 排序时把NULL视为缺失，排在最前
+fn non_null(value: &Value) -> Option<&Value> {
+    match value {
+        Value::Null => None,
+        other => Some(other),
+    }
+}
+
+/// 取记录中某一列的值，可以是系统字段，缺失的字段为NULL
+fn column_value(record: &Record, column: &str) -> Value {
+    record.field(column).map_or(Value::Null, Cow::into_owned)
+}
+
+/// 解析SELECT语句
+///
+/// 支持`SELECT * | expr [AS alias], ... FROM table [alias]
+/// [[INNER | LEFT [OUTER]] JOIN table [alias] ON a.col = b.col ...]
+/// [WHERE col op literal [AND ...]] [GROUP BY col, ...]
+/// [ORDER BY col [ASC|DESC], ...] [LIMIT n] [OFFSET n]`，
+/// 其中expr为列或`COUNT(*)`、`COUNT/SUM/AVG/MIN/MAX(col)`。
+pub fn parse(sql: &str) -> Result<Select> {
+    let mut parser = Parser {
+        tokens: tokenize(sql)?,
+        pos: 0,
+    };
+    let select = parser.select()?;
+    parser.accept_symbol(";");
+    if let Some(token) = parser.peek() {
+        return Err(syntax_error(&format!("多余的内容: {}", token)));
+    }
+    Ok(select)
+}
+
+fn syntax_error(message: &str) -> DatabaseError {
+    DatabaseError::InvalidQuery(format!("SQL语法错误: {}", message))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Number(String),
+    Symbol(String),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Ident(s) | Token::Number(s) | Token::Symbol(s) => write!(f, "{}", s),
+            Token::Str(s) => write!(f, "'{}'", s),
+        }
+    }
+}
+
+fn tokenize(sql: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c == '"' {
+            // 带引号的标识符
+            let end = chars[i + 1..]
+                .iter()
+                .position(|&ch| ch == '"')
+                .ok_or_else(|| syntax_error("未闭合的标识符引号"))?;
+            tokens.push(Token::Ident(chars[i + 1..i + 1 + end].iter().collect()));
+            i += end + 2;
+        } else if c == '\'' {
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(syntax_error("未闭合的字符串")),
+                    Some('\'') if chars.get(i + 1) == Some(&'\'') => {
+                        value.push('\'');
+                        i += 2;
+                    }
+                    Some('\'') => {
+                        i += 1;
+                        break;
+                    }
+                    Some(&ch) => {
+                        value.push(ch);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(Token::Str(value));
+        } else if c.is_ascii_digit() || (c == '-' && chars.get(i + 1).is_some_and(|ch| ch.is_ascii_digit())) {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push(Token::Number(chars[start..i].iter().collect()));
+        } else {
+            let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            let symbol = if ["<=", ">=", "<>", "!="].contains(&two.as_str()) {
+                two
+            } else if "=<>*,();.".contains(c) {
+                c.to_string()
+            } else {
+                return Err(syntax_error(&format!("无法识别的字符: {}", c)));
+            };
+            i += symbol.len();
+            tokens.push(Token::Symbol(symbol));
+        }
+    }
+    Ok(tokens)
+}
+
+/// 不能用作无AS别名的关键字
+const KEYWORDS: [&str; 13] = [
+    "FROM", "WHERE", "JOIN", "INNER", "LEFT", "ON", "GROUP", "ORDER", "LIMIT", "OFFSET", "AND", "OR", "AS",
+];
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn accept_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        if self.accept_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(syntax_error(&format!("缺少 {}", keyword)))
+        }
+    }
+
+    fn accept_symbol(&mut self, symbol: &str) -> bool {
+        match self.peek() {
+            Some(Token::Symbol(s)) if s == symbol => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Ident(name)) => Ok(name),
+            Some(other) => Err(syntax_error(&format!("应为标识符，实际为 {}", other))),
+            None => Err(syntax_error("语句不完整")),
+        }
+    }
+
+    fn integer(&mut self) -> Result<usize> {
+        match self.next() {
+            Some(Token::Number(n)) => n.parse().map_err(|_| syntax_error(&format!("应为非负整数: {}", n))),
+            _ => Err(syntax_error("应为非负整数")),
+        }
+    }
+
+    /// 下一个标识符不是关键字时作为别名
+    fn alias(&mut self) -> Result<Option<String>> {
+        if self.accept_keyword("AS") {
+            return self.identifier().map(Some);
+        }
+        match self.peek() {
+            Some(Token::Ident(word)) if !KEYWORDS.iter().any(|k| word.eq_ignore_ascii_case(k)) => self.identifier().map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn column(&mut self) -> Result<ColumnRef> {
+        let first = self.identifier()?;
+        if self.accept_symbol(".") {
+            Ok(ColumnRef::qualified(&first, &self.identifier()?))
+        } else {
+            Ok(ColumnRef::new(&first))
+        }
+    }
+
+    fn table(&mut self) -> Result<TableRef> {
+        let name = self.identifier()?;
+        let alias = self.alias()?;
+        Ok(TableRef { name, alias })
+    }
+
+    fn select_item(&mut self) -> Result<SelectItem> {
+        if self.accept_symbol("*") {
+            return Ok(SelectItem::Wildcard);
+        }
+        let func = match (self.peek(), self.tokens.get(self.pos + 1)) {
+            (Some(Token::Ident(name)), Some(Token::Symbol(s))) if s == "(" => Some(
+                Aggregate::from_name(name).ok_or_else(|| syntax_error(&format!("不支持的函数: {}", name)))?,
+            ),
+            _ => None,
+        };
+        let Some(func) = func else {
+            let column = self.column()?;
+            return Ok(SelectItem::Column {
+                column,
+                alias: self.alias()?,
+            });
+        };
+        self.pos += 2;
+        let column = if self.accept_symbol("*") {
+            if func != Aggregate::Count {
+                return Err(syntax_error("只有COUNT支持 *"));
+            }
+            None
+        } else {
+            Some(self.column()?)
+        };
+        if !self.accept_symbol(")") {
+            return Err(syntax_error("缺少 )"));
+        }
+        Ok(SelectItem::Aggregate {
+            func,
+            column,
+            alias: self.alias()?,
+        })
+    }
+
+    fn select(&mut self) -> Result<Select> {
+        self.expect_keyword("SELECT")?;
+        let mut projection = vec![self.select_item()?];
+        while self.accept_symbol(",") {
+            projection.push(self.select_item()?);
+        }
+
+        self.expect_keyword("FROM")?;
+        let from = self.table()?;
+
+        let mut joins = Vec::new();
+        loop {
+            let kind = if self.accept_keyword("LEFT") {
+                self.accept_keyword("OUTER");
+                self.expect_keyword("JOIN")?;
+                JoinKind::Left
+            } else if self.accept_keyword("INNER") {
+                self.expect_keyword("JOIN")?;
+                JoinKind::Inner
+            } else if self.accept_keyword("JOIN") {
+                JoinKind::Inner
+            } else {
+                break;
+            };
+            let table = self.table()?;
+            self.expect_keyword("ON")?;
+            let left = self.column()?;
+            if !self.accept_symbol("=") {
+                return Err(syntax_error("JOIN条件只支持等值比较"));
+            }
+            let right = self.column()?;
+            joins.push(Join { kind, table, left, right });
+        }
+
+        let mut conditions = Vec::new();
+        if self.accept_keyword("WHERE") {
+            conditions.push(self.condition()?);
+            while self.accept_keyword("AND") {
+                conditions.push(self.condition()?);
+            }
+            if self.accept_keyword("OR") {
+                return Err(syntax_error("暂不支持OR条件"));
+            }
+        }
+
+        let mut group_by = Vec::new();
+        if self.accept_keyword("GROUP") {
+            self.expect_keyword("BY")?;
+            group_by.push(self.column()?);
+            while self.accept_symbol(",") {
+                group_by.push(self.column()?);
+            }
+        }
+
+        let mut order_by = Vec::new();
+        if self.accept_keyword("ORDER") {
+            self.expect_keyword("BY")?;
+            loop {
+                let column = self.column()?;
+                let order = if self.accept_keyword("DESC") {
+                    SortOrder::Desc
+                } else {
+                    self.accept_keyword("ASC");
+                    SortOrder::Asc
+                };
+                order_by.push((column, order));
+                if !self.accept_symbol(",") {
+                    break;
+                }
+            }
+        }
+
+        let limit = if self.accept_keyword("LIMIT") { Some(self.integer()?) } else { None };
+        let offset = if self.accept_keyword("OFFSET") { self.integer()? } else { 0 };
+
+        Ok(Select {
+            projection,
+            from,
+            joins,
+            conditions,
+            group_by,
+            order_by,
+            limit,
+            offset,
+        })
+    }
+
+    fn condition(&mut self) -> Result<Predicate> {
+        let column = self.column()?;
+        let op = match self.next() {
+            Some(Token::Symbol(s)) => match s.as_str() {
+                "=" => Operator::Eq,
+                "!=" | "<>" => Operator::Ne,
+                ">" => Operator::Gt,
+                ">=" => Operator::Gte,
+                "<" => Operator::Lt,
+                "<=" => Operator::Lte,
+                other => return Err(syntax_error(&format!("不支持的比较运算符: {}", other))),
+            },
+            _ => return Err(syntax_error("缺少比较运算符")),
+        };
+        Ok(Predicate {
+            column,
+            op,
+            value: self.literal()?,
+        })
+    }
+
+    fn literal(&mut self) -> Result<Value> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(Value::String(s)),
+            Some(Token::Number(n)) => match n.parse::<i64>() {
+                Ok(i) => Ok(Value::Int(i)),
+                Err(_) => n
+                    .parse::<f64>()
+                    .map(Value::Float)
+                    .map_err(|_| syntax_error(&format!("无效的数字: {}", n))),
+            },
+            Some(Token::Ident(word)) if word.eq_ignore_ascii_case("TRUE") => Ok(Value::Bool(true)),
+            Some(Token::Ident(word)) if word.eq_ignore_ascii_case("FALSE") => Ok(Value::Bool(false)),
+            Some(Token::Ident(word)) if word.eq_ignore_ascii_case("NULL") => Ok(Value::Null),
+            Some(other) => Err(syntax_error(&format!("应为字面量，实际为 {}", other))),
+            None => Err(syntax_error("语句不完整")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::open;
+    use indexmap::IndexMap;
+
+    #[test]
+    fn test_parse_select() {
+        let select = parse(
+            "select name, \"age\" from users where age >= 18 and name <> 'O''Brien' order by age desc, name limit 10 offset 5;",
+        )
+        .unwrap();
+        assert_eq!(
+            select.projection,
+            vec![
+                SelectItem::Column { column: ColumnRef::new("name"), alias: None },
+                SelectItem::Column { column: ColumnRef::new("age"), alias: None },
+            ]
+        );
+        assert_eq!(select.from.name, "users");
+        assert_eq!(
+            select.conditions,
+            vec![
+                Predicate { column: ColumnRef::new("age"), op: Operator::Gte, value: Value::Int(18) },
+                Predicate { column: ColumnRef::new("name"), op: Operator::Ne, value: Value::String("O'Brien".to_string()) },
+            ]
+        );
+        assert_eq!(
+            select.order_by,
+            vec![(ColumnRef::new("age"), SortOrder::Desc), (ColumnRef::new("name"), SortOrder::Asc)]
+        );
+        assert_eq!((select.limit, select.offset), (Some(10), 5));
+
+        assert!(parse("SELECT * FROM t WHERE a = 1 OR b = 2").is_err());
+        assert!(parse("SELECT * FROM").is_err());
+        assert!(parse("SELECT lower(a) FROM t").is_err());
+    }
+
+    #[test]
+    fn test_join_and_group_by() {
+        let (_dir, db) = open("sql");
+        let mut ids = Vec::new();
+        for name in ["alice", "bob", "carol"] {
+            let mut data = IndexMap::new();
+            data.insert("name".to_string(), Value::String(name.to_string()));
+            ids.push(db.insert("users", data).unwrap());
+        }
+        for (user, amount) in [(0, 10), (0, 5), (1, 7)] {
+            let mut data = IndexMap::new();
+            data.insert("user_id".to_string(), Value::String(ids[user].clone()));
+            data.insert("amount".to_string(), Value::Int(amount));
+            db.insert("orders", data).unwrap();
+        }
+
+        let result = db
+            .sql(
+                "SELECT u.name, COUNT(o.id) AS n, SUM(o.amount) AS total FROM users u \
+                 LEFT JOIN orders o ON o.user_id = u.id GROUP BY u.name ORDER BY total DESC",
+            )
+            .unwrap();
+        assert_eq!(result.columns, vec!["name", "n", "total"]);
+        assert_eq!(
+            result.rows,
+            vec![
+                vec![Value::String("alice".to_string()), Value::Int(2), Value::Int(15)],
+                vec![Value::String("bob".to_string()), Value::Int(1), Value::Int(7)],
+                vec![Value::String("carol".to_string()), Value::Int(0), Value::Null],
+            ]
+        );
+
+        let result = db.sql("SELECT COUNT(*), MAX(amount) FROM orders WHERE amount < 10").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::Int(2), Value::Int(7)]]);
+
+        drop(db);
+    }
+
+    #[test]
+    fn test_describe() {
+        let (_dir, db) = open("sql_describe");
+        for amount in [10, 5] {
+            db.insert("orders", IndexMap::from([("amount".to_string(), Value::Int(amount))])).unwrap();
+        }
+        db.set_field_type("orders", "amount", Some(FieldType::Int)).unwrap();
+        db.set_table_mode("orders", TableMode::Strict).unwrap();
+        db.insert("notes", IndexMap::from([("text".to_string(), Value::String("x".to_string()))])).unwrap();
+
+        let column = |name: &str, field_type: Option<FieldType>| SqlColumn { name: name.to_string(), field_type };
+        assert_eq!(
+            db.describe_sql("SELECT amount AS a, COUNT(*), MAX(amount) FROM orders GROUP BY amount").unwrap(),
+            vec![column("a", Some(FieldType::Int)), column("count", Some(FieldType::Int)), column("max", None)]
+        );
+        // 宽松模式的表中字段类型不确定
+        let columns = db.describe_sql("SELECT * FROM notes WHERE text = 'y'").unwrap();
+        assert_eq!(columns, vec![column("id", Some(FieldType::String)), column("text", None)]);
+        assert!(db.describe_sql("SELECT o.amount FROM orders WHERE x.a = 1").is_err());
+        assert!(db.describe_sql("SELECT *, COUNT(*) FROM orders").is_err());
+
+        // 没有匹配的记录时执行结果只有id列，按规划的列返回
+        let result = db.sql("SELECT * FROM notes WHERE text = 'y'").unwrap();
+        assert_eq!(result.columns, vec!["id"]);
+        let result = db.sql("SELECT * FROM notes").unwrap().conform(&columns);
+        assert_eq!(result.columns, vec!["id", "text"]);
+        assert_eq!(result.rows[0][1], Value::String("x".to_string()));
+
+        drop(db);
+    }
+}
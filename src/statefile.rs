@@ -0,0 +1,189 @@
+//! 数据目录中表文件以外的状态文件：主体密钥、同步状态、预备查询、集群、CDC位置、变更日志、定时任务和拆分的表的部分清单等
+//!
+//! 每个状态文件以8字节的魔数开头，`SimpleDB::destroy`只读文件头就能确认文件属于数据库。
+//! 写入时先写临时文件并fsync，改名后再fsync数据目录，返回后内容在崩溃和断电后都能保留；
+//! 写到一半崩溃时原文件保持完整。加密的状态文件只加密魔数之后的内容。
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::crypto::Crypto;
+use crate::error::Result;
+use crate::storage;
+
+/// 魔数的长度
+pub const MAGIC_LEN: usize = 8;
+
+/// 数据库写出的各种状态文件的魔数
+const MAGICS: [&[u8; MAGIC_LEN]; 12] = [
+    crate::manifest::MANIFEST_MAGIC,
+    crate::keyring::KEYRING_MAGIC,
+    crate::prepared::PREPARED_MAGIC,
+    crate::schedule::SCHEDULE_MAGIC,
+    crate::sync::SYNC_MAGIC,
+    crate::cdc::CDC_MAGIC,
+    crate::cluster::RAFT_MAGIC,
+    crate::cluster::LEGACY_RAFT_MAGIC,
+    crate::cluster::RAFT_LOG_MAGIC,
+    crate::storage::PARTS_MAGIC,
+    crate::changes::CHANGES_MAGIC,
+    crate::dirlock::LOCK_MAGIC,
+];
+
+/// 读取文件开头的`MAGIC_LEN`字节，文件更短时为None
+fn read_header(path: &Path) -> Result<Option<[u8; MAGIC_LEN]>> {
+    let mut header = [0u8; MAGIC_LEN];
+    let mut file = File::open(path)?;
+    let mut read = 0;
+    while read < MAGIC_LEN {
+        match file.read(&mut header[read..])? {
+            0 => return Ok(None),
+            n => read += n,
+        }
+    }
+    Ok(Some(header))
+}
+
+/// 文件是否以`magic`开头，只读取文件头
+pub fn has_magic(path: &Path, magic: &[u8; MAGIC_LEN]) -> Result<bool> {
+    Ok(read_header(path)?.as_ref() == Some(magic))
+}
+
+/// 文件是否是数据库写出的状态文件（与文件名无关），只读取文件头
+pub fn is_state_file(path: &Path) -> Result<bool> {
+    Ok(read_header(path)?.is_some_and(|header| MAGICS.contains(&&header)))
+}
+
+/// 去掉任一状态文件魔数后的内容，没有魔数时原样返回
+pub(crate) fn strip_magic(content: &[u8]) -> &[u8] {
+    MAGICS.iter().find_map(|magic| content.strip_prefix(magic.as_slice())).unwrap_or(content)
+}
+
+/// 在内容前加上魔数，不写入磁盘；用于备份中的状态文件
+pub(crate) fn encode(magic: &[u8; MAGIC_LEN], content: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(MAGIC_LEN + content.len());
+    encoded.extend_from_slice(magic);
+    encoded.extend_from_slice(content);
+    encoded
+}
+
+/// 去掉魔数后的内容；没有魔数的是加上魔数之前的版本写出的文件，整个作为内容
+pub(crate) fn decode<'a>(magic: &[u8; MAGIC_LEN], content: &'a [u8]) -> &'a [u8] {
+    content.strip_prefix(magic.as_slice()).unwrap_or(content)
+}
+
+/// 持久地写入文件：临时文件fsync后改名，再fsync所在目录
+pub(crate) fn write_durable(path: &Path, content: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = OpenOptions::new().create(true).write(true).truncate(true).open(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp, path)?;
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => storage::sync_dir(dir),
+        _ => Ok(()),
+    }
+}
+
+/// 追加写入的状态文件中的一条记录：4字节小端的长度和内容
+pub(crate) fn frame(content: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + content.len());
+    frame.extend_from_slice(&(content.len() as u32).to_le_bytes());
+    frame.extend_from_slice(content);
+    frame
+}
+
+/// 追加写入的状态文件的开头：魔数和8字节小端的起始值（如第一条记录的序号）
+pub(crate) fn frames_header(magic: &[u8; MAGIC_LEN], start: u64) -> Vec<u8> {
+    encode(magic, &start.to_le_bytes())
+}
+
+/// 读出追加写入的状态文件：起始值、各条记录的内容和完整记录结束的位置；
+/// 末尾写到一半的记录（写入时崩溃）被忽略，调用者应从结束位置截断后再追加
+pub(crate) fn read_frames(path: &Path, magic: &[u8; MAGIC_LEN]) -> Result<(u64, Vec<Vec<u8>>, u64)> {
+    let content = std::fs::read(path)?;
+    let invalid = || crate::error::DatabaseError::DataFormat(format!("{} 的文件头无效", path.display()));
+    let (start, mut rest) = content
+        .strip_prefix(magic.as_slice())
+        .and_then(|content| content.split_first_chunk::<8>())
+        .ok_or_else(invalid)?;
+    let mut frames = Vec::new();
+    while let Some((len, tail)) = rest.split_first_chunk::<4>() {
+        let len = u32::from_le_bytes(*len) as usize;
+        let Some(frame) = tail.get(..len) else { break };
+        frames.push(frame.to_vec());
+        rest = &tail[len..];
+    }
+    Ok((u64::from_le_bytes(*start), frames, (content.len() - rest.len()) as u64))
+}
+
+/// 追加写入的状态文件中的记录是否都用`crypto`加密；用于安全报告，没有记录时视为加密
+pub(crate) fn frames_encrypted(path: &Path, magic: &[u8; MAGIC_LEN], crypto: Option<&Crypto>) -> bool {
+    let Some(crypto) = crypto else { return false };
+    read_frames(path, magic).is_ok_and(|(_, frames, _)| frames.iter().all(|frame| crypto.decrypt(frame).is_ok()))
+}
+
+/// 读取状态文件，不存在时为None
+pub(crate) fn load<T: DeserializeOwned>(path: &Path, magic: &[u8; MAGIC_LEN], crypto: Option<&Crypto>) -> Result<Option<T>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = std::fs::read(path)?;
+    let content = decode(magic, &content);
+    let value = match crypto {
+        Some(crypto) => bincode::deserialize(&crypto.decrypt(content)?)?,
+        None => bincode::deserialize(content)?,
+    };
+    Ok(Some(value))
+}
+
+/// 编码（配置了密钥时加密）后持久地写入状态文件
+pub(crate) fn store<T: Serialize>(path: &Path, magic: &[u8; MAGIC_LEN], value: &T, crypto: Option<&Crypto>) -> Result<()> {
+    let mut content = bincode::serialize(value)?;
+    if let Some(crypto) = crypto {
+        content = crypto.encrypt(&content)?;
+    }
+    write_durable(path, &encode(magic, &content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_state_file() {
+        let dir = temp_dir("statefile");
+        let path = dir.path().join("STATE");
+        let magic = b"SDBTEST1";
+        let crypto = Crypto::new(&Crypto::generate_key()).unwrap();
+        let value = BTreeMap::from([("a".to_string(), 1u64)]);
+
+        assert_eq!(load::<BTreeMap<String, u64>>(&path, magic, None).unwrap(), None);
+        store(&path, magic, &value, Some(&crypto)).unwrap();
+        assert!(has_magic(&path, magic).unwrap());
+        assert!(!has_magic(&path, b"SDBTEST2").unwrap());
+        assert!(!dir.path().join("STATE.tmp").exists());
+        assert_eq!(load(&path, magic, Some(&crypto)).unwrap(), Some(value.clone()));
+
+        // 加上魔数之前写出的文件仍可读取，但不被识别为状态文件
+        std::fs::write(&path, bincode::serialize(&value).unwrap()).unwrap();
+        assert_eq!(load(&path, magic, None).unwrap(), Some(value));
+        assert!(!has_magic(&path, magic).unwrap());
+
+        // 按魔数而不是文件名识别状态文件
+        let other = dir.path().join("notes");
+        store(&other, crate::sync::SYNC_MAGIC, &1u64, None).unwrap();
+        assert!(is_state_file(&other).unwrap());
+        assert_eq!(strip_magic(&std::fs::read(&other).unwrap()), bincode::serialize(&1u64).unwrap());
+        std::fs::write(&other, b"SDB").unwrap();
+        assert!(!is_state_file(&other).unwrap());
+    }
+}
@@ -0,0 +1,243 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::index::FieldIndex;
+use crate::query::{compare_values, Condition, Operator};
+use crate::storage::{Record, Value};
+
+/// 数值直方图的桶数
+const HISTOGRAM_BUCKETS: usize = 10;
+
+/// 保留的最常见值个数
+const MOST_COMMON: usize = 5;
+
+/// 无法用直方图估计时，范围条件的默认选择率
+const DEFAULT_RANGE_SELECTIVITY: f64 = 1.0 / 3.0;
+
+/// 自上次统计以来修改的记录超过记录数的该比例时，保存表时重新统计
+pub const STALE_RATIO: f64 = 0.1;
+
+/// 单个字段的统计信息
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldStats {
+    /// 值不为null的记录数
+    pub present: usize,
+    /// 缺少该字段或值为null的记录数
+    pub nulls: usize,
+    /// 不同的非null值的数量
+    pub distinct: usize,
+    pub min: Option<Value>,
+    pub max: Option<Value>,
+    /// 出现次数最多的值及其次数，按次数降序
+    pub most_common: Vec<(Value, usize)>,
+    /// 数值的等深直方图边界，相邻边界之间的值数量大致相同；没有数值时为空
+    pub histogram: Vec<f64>,
+}
+
+impl FieldStats {
+    /// 缺少该字段或值为null的记录占比
+    pub fn null_ratio(&self) -> f64 {
+        ratio(self.nulls, self.present + self.nulls)
+    }
+
+    /// 非null值中等于`value`的比例
+    fn eq_fraction(&self, value: &Value) -> f64 {
+        if let Some((_, count)) = self.most_common.iter().find(|(v, _)| v == value) {
+            return ratio(*count, self.present);
+        }
+        // 不在最常见值中时，假设其余的值均匀分布
+        let common: usize = self.most_common.iter().map(|(_, count)| count).sum();
+        let others = self.distinct.saturating_sub(self.most_common.len());
+        if others == 0 {
+            return 0.0;
+        }
+        ratio(self.present - common, self.present) / others as f64
+    }
+
+    /// 非null数值中小于`x`的比例，由直方图线性插值
+    fn below_fraction(&self, x: f64) -> Option<f64> {
+        let bounds = &self.histogram;
+        let (first, last) = (*bounds.first()?, *bounds.last()?);
+        if x <= first {
+            return Some(0.0);
+        }
+        if x > last {
+            return Some(1.0);
+        }
+        let buckets = (bounds.len() - 1) as f64;
+        let i = bounds.partition_point(|b| *b < x).saturating_sub(1);
+        let (low, high) = (bounds[i], bounds[i + 1]);
+        let within = if high > low { (x - low) / (high - low) } else { 0.0 };
+        Some((i as f64 + within) / buckets)
+    }
+}
+
+/// 表的统计信息，用于估计查询条件的选择率
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableStats {
+    /// 统计时的记录数
+    pub rows: usize,
+    /// 统计时间（Unix秒）
+    pub analyzed_at: u64,
+    pub fields: BTreeMap<String, FieldStats>,
+}
+
+impl TableStats {
+    /// 扫描记录收集统计信息
+    pub fn collect<'a>(records: impl Iterator<Item = &'a Record>) -> Self {
+        #[derive(Default)]
+        struct Collector<'a> {
+            present: usize,
+            counts: HashMap<Vec<u8>, (&'a Value, usize)>,
+            numbers: Vec<f64>,
+            min: Option<&'a Value>,
+            max: Option<&'a Value>,
+        }
+
+        let mut rows = 0;
+        let mut collectors: BTreeMap<&str, Collector> = BTreeMap::new();
+        for record in records {
+            rows += 1;
+            for (field, value) in &record.data {
+                if *value == Value::Null {
+                    continue;
+                }
+                let c = collectors.entry(field).or_default();
+                c.present += 1;
+                c.counts.entry(FieldIndex::key(value)).or_insert((value, 0)).1 += 1;
+                if let Some(x) = as_number(value) {
+                    c.numbers.push(x);
+                }
+                if c.min.is_none_or(|min| compare_values(value, min) == Some(Ordering::Less)) {
+                    c.min = Some(value);
+                }
+                if c.max.is_none_or(|max| compare_values(value, max) == Some(Ordering::Greater)) {
+                    c.max = Some(value);
+                }
+            }
+        }
+
+        let fields = collectors
+            .into_iter()
+            .map(|(field, mut c)| {
+                let distinct = c.counts.len();
+                let mut most_common: Vec<(&Value, usize)> = c.counts.into_values().collect();
+                // 次数相同时按值的序列化字节排序，使结果稳定
+                most_common.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| FieldIndex::key(a.0).cmp(&FieldIndex::key(b.0))));
+                most_common.truncate(MOST_COMMON);
+
+                c.numbers.retain(|x| !x.is_nan());
+                c.numbers.sort_by(f64::total_cmp);
+                let histogram = if c.numbers.is_empty() {
+                    Vec::new()
+                } else {
+                    let last = c.numbers.len() - 1;
+                    (0..=HISTOGRAM_BUCKETS)
+                        .map(|i| c.numbers[i * last / HISTOGRAM_BUCKETS])
+                        .collect()
+                };
+
+                let stats = FieldStats {
+                    present: c.present,
+                    nulls: rows - c.present,
+                    distinct,
+                    min: c.min.cloned(),
+                    max: c.max.cloned(),
+                    most_common: most_common.into_iter().map(|(v, n)| (v.clone(), n)).collect(),
+                    histogram,
+                };
+                (field.to_string(), stats)
+            })
+            .collect();
+
+        let analyzed_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Self { rows, analyzed_at, fields }
+    }
+
+    /// 估计满足条件的记录占比（0~1）
+    pub fn selectivity(&self, condition: &Condition) -> f64 {
+        let Some(field) = self.fields.get(&condition.field) else {
+            // 从未出现过的字段：只有 != 和 = null 能匹配
+            return match (condition.op, &condition.value) {
+                (Operator::Eq, Value::Null) | (Operator::Ne, _) => 1.0,
+                _ => 0.0,
+            };
+        };
+        let present = ratio(field.present, self.rows);
+        match (condition.op, &condition.value) {
+            (Operator::Eq, Value::Null) => field.null_ratio(),
+            (Operator::Ne, Value::Null) => 1.0 - field.null_ratio(),
+            (Operator::Eq, value) => present * field.eq_fraction(value),
+            (Operator::Ne, value) => 1.0 - present * field.eq_fraction(value),
+            (op, value) => {
+                let below = as_number(value).and_then(|x| field.below_fraction(x));
+                let fraction = match (op, below) {
+                    (Operator::Lt | Operator::Lte, Some(below)) => below,
+                    (_, Some(below)) => 1.0 - below,
+                    (_, None) => DEFAULT_RANGE_SELECTIVITY,
+                };
+                present * fraction
+            }
+        }
+    }
+
+    /// 估计`rows`条记录中满足所有条件的记录数，假设各条件相互独立
+    pub fn estimate_rows(&self, conditions: &[Condition], rows: usize) -> usize {
+        let selectivity: f64 = conditions.iter().map(|c| self.selectivity(c).clamp(0.0, 1.0)).product();
+        (selectivity * rows as f64).round() as usize
+    }
+}
+
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Int(i) | Value::Timestamp(i) => Some(*i as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+fn ratio(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::IndexMap;
+
+    #[test]
+    fn test_estimates_follow_data_shape() {
+        // 900条status为active，100条为其他10种值；age均匀分布在0~99
+        let records: Vec<Record> = (0..1000)
+            .map(|i| {
+                let status = if i < 900 { "active".to_string() } else { format!("s{}", i % 10) };
+                let mut data = IndexMap::from([("status".to_string(), Value::String(status))]);
+                if i % 4 != 0 {
+                    data.insert("age".to_string(), Value::Int(i % 100));
+                }
+                Record::new(data)
+            })
+            .collect();
+        let stats = TableStats::collect(records.iter());
+
+        let age = &stats.fields["age"];
+        assert_eq!((age.present, age.nulls, age.distinct), (750, 250, 75));
+        assert_eq!((age.min.clone(), age.max.clone()), (Some(Value::Int(1)), Some(Value::Int(99))));
+        assert!((age.null_ratio() - 0.25).abs() < 1e-9);
+
+        let estimate = |c: Condition| stats.estimate_rows(&[c], 1000);
+        assert_eq!(estimate(Condition::eq("status", Value::String("active".to_string()))), 900);
+        assert_eq!(estimate(Condition::eq("status", Value::String("s3".to_string()))), 10);
+        assert_eq!(estimate(Condition::eq("missing", Value::Int(1))), 0);
+        let young = estimate(Condition::lt("age", Value::Int(50)));
+        assert!((340..=410).contains(&young), "{}", young);
+    }
+}
@@ -1,24 +1,61 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use indexmap::IndexMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+use std::sync::{Arc, Mutex};
 use uuid::Uuid;
 
+use crate::blob::BlobStore;
+use crate::cache::QueryCache;
+use crate::changes::{ChangeFeed, ChangeKind};
 use crate::crypto::Crypto;
 use crate::error::{DatabaseError, Result};
+use crate::format::{self, Damage, FileOptions};
+use crate::geo::{self, GeoIndex};
+use crate::codec::Records;
+use crate::collation::{self, Collation, Comparator};
+use crate::compress::{self, ValueCodec, DICTIONARY_EXTENSION};
+use crate::counters::{Counters, TableCounters};
+use crate::validate::{FieldError, Validator};
+use crate::index::{FieldIndex, IndexBuildProgress, IndexInfo, IndexKind, UniqueIndex};
+use crate::manifest::Manifest;
+use crate::meta::{Cap, IndexDef, TableMeta, META_EXTENSION};
+use crate::plaintext::{self, PLAINTEXT_EXTENSION};
+use crate::policy::Policy;
+use crate::query::{Condition, IndexHint, Operator, Query, QueryPlan};
+use crate::quota::{self, InsertionOrder, Quota, QuotaPolicy, Usage};
+use crate::schema::{self, FieldType, SchemaSample, TableMode};
+use crate::statefile;
+use crate::sql::{Aggregate, OverflowPolicy};
+use crate::system;
+use crate::stats::{self, TableStats};
+use crate::timeseries::{self, TimeSeries, Timeline};
+use crate::update::{self, UpdateOp};
+use crate::vector::Metric;
+use crate::Autosave;
+
+/// 暂存待发布的变更事件：类型、记录ID和变更后的记录
+pub(crate) type PendingChange = (ChangeKind, String, Option<Arc<Record>>);
 
 /// 数据记录
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Record {
     pub id: String,
-    pub data: HashMap<String, Value>,
+    pub data: IndexMap<String, Value>,
     pub created_at: u64,
     pub updated_at: u64,
 }
 
 impl Record {
-    pub fn new(data: HashMap<String, Value>) -> Self {
+    pub fn new(data: IndexMap<String, Value>) -> Self {
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
@@ -32,13 +69,46 @@ impl Record {
         }
     }
 
-    pub fn update(&mut self, data: HashMap<String, Value>) {
+    /// 字段的值，包括系统字段`id`、`created_at`和`updated_at`，见`system::field`
+    pub fn field(&self, name: &str) -> Option<Cow<'_, Value>> {
+        system::field(self, name)
+    }
+
+    /// 默认顺序：按创建时间，相同时按ID；没有指定排序的查询结果按此排列
+    pub fn default_order(&self, other: &Record) -> std::cmp::Ordering {
+        self.created_at.cmp(&other.created_at).then_with(|| self.id.cmp(&other.id))
+    }
+
+    pub fn update(&mut self, data: IndexMap<String, Value>) {
         self.data = data;
         self.updated_at = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs();
     }
+
+    /// 记录的实体标签（ETag），更新时间或内容变化时随之改变
+    ///
+    /// 内容用SHA-256哈希，同一记录在不同版本、不同节点上得到相同的ETag。
+    pub fn etag(&self) -> String {
+        let mut hasher = Sha256::new();
+        // 各部分带上长度，避免拼接后相同
+        let mut feed = |bytes: &[u8]| {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        };
+        feed(self.id.as_bytes());
+        // 按字段名排序后再哈希，避免字段顺序影响结果
+        let mut fields: Vec<_> = self.data.iter().collect();
+        fields.sort_by(|a, b| a.0.cmp(b.0));
+        for (key, value) in fields {
+            feed(key.as_bytes());
+            feed(&bincode::serialize(value).unwrap_or_default());
+        }
+        let digest = hasher.finalize();
+        let hash = u64::from_be_bytes(digest[..8].try_into().expect("SHA-256摘要长于8字节"));
+        format!("\"{:x}-{:016x}\"", self.updated_at, hash)
+    }
 }
 
 /// 支持的数据类型
@@ -51,10 +121,59 @@ pub enum Value {
     String(String),
     Bytes(Vec<u8>),
     Array(Vec<Value>),
-    Object(HashMap<String, Value>),
+    Object(IndexMap<String, Value>),
+    GeoPoint { lat: f64, lon: f64 },
+    Vector(Vec<f32>),
+    /// 用主体数据密钥加密的值，见`SimpleDB::seal`
+    Sealed { subject: String, data: Vec<u8> },
+    /// 确定性加密的值，见`SimpleDB::seal_deterministic`；相同的域和明文得到相同的密文
+    Deterministic { domain: String, data: Vec<u8> },
+    /// 对另一条记录的引用，见`SimpleDB::resolve`
+    Ref { table: String, id: String },
+    /// 预备查询中的参数占位符，执行时替换为绑定的值，见`SimpleDB::prepare`
+    Param(String),
+    /// 以zstd压缩保存的字符串（`binary`为false）或字节串，由表在读取时解压，见`SimpleDB::set_value_compression`
+    Compressed { binary: bool, data: Vec<u8> },
+    /// 共享blob存储中`size`字节的字节串，由表在读取时换回`Bytes`，见`Config::blob_threshold`
+    Blob { id: String, size: u64 },
+    /// Unix纪元以来的毫秒数（UTC），API中以ISO-8601字符串表示，见`FieldType::DateTime`
+    Timestamp(i64),
 }
 
 impl Value {
+    /// 按值的全序比较，字符串按字节比较，见`query::total_cmp_with`
+    pub fn total_cmp(&self, other: &Value) -> std::cmp::Ordering {
+        crate::query::total_cmp_with(self, other, crate::collation::binary)
+    }
+
+    /// 是否为加密值，加密值只支持等值比较
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, Value::Sealed { .. } | Value::Deterministic { .. })
+    }
+
+    /// 值的类型名称
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::String(_) => "string",
+            Value::Bytes(_) => "bytes",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+            Value::GeoPoint { .. } => "geo_point",
+            Value::Vector(_) => "vector",
+            Value::Sealed { .. } => "sealed",
+            Value::Deterministic { .. } => "deterministic",
+            Value::Ref { .. } => "ref",
+            Value::Param(_) => "param",
+            Value::Compressed { .. } => "compressed",
+            Value::Blob { .. } => "blob",
+            Value::Timestamp(_) => "timestamp",
+        }
+    }
+
     pub fn as_string(&self) -> Option<&str> {
         match self {
             Value::String(s) => Some(s),
@@ -68,18 +187,128 @@ impl Value {
             _ => None,
         }
     }
+
+    pub fn as_geo_point(&self) -> Option<(f64, f64)> {
+        match self {
+            Value::GeoPoint { lat, lon } => Some((*lat, *lon)),
+            _ => None,
+        }
+    }
+
+    /// 引用`table`表中ID为`id`的记录
+    pub fn reference(table: &str, id: &str) -> Self {
+        Value::Ref {
+            table: table.to_string(),
+            id: id.to_string(),
+        }
+    }
+
+    /// 引用的(表名, 记录ID)
+    pub fn as_reference(&self) -> Option<(&str, &str)> {
+        match self {
+            Value::Ref { table, id } => Some((table, id)),
+            _ => None,
+        }
+    }
+
+    pub fn as_vector(&self) -> Option<&[f32]> {
+        match self {
+            Value::Vector(v) => Some(v),
+            _ => None,
+        }
+    }
 }
 
+/// 记录数达到该值时，非索引扫描才会并行执行
+const PARALLEL_SCAN_THRESHOLD: usize = 10_000;
+
+/// 通过索引取一条记录的代价，以顺序扫描一条记录为单位
+const INDEX_FETCH_COST: f64 = 4.0;
+
 /// 表结构
 #[derive(Debug)]
 pub struct Table {
     pub name: String,
     pub file_path: PathBuf,
     pub crypto: Option<Crypto>,
-    records: HashMap<String, Record>,
+    records: HashMap<String, Arc<Record>>,
+    indexes: HashMap<String, FieldIndex>,
+    geo_indexes: HashMap<String, GeoIndex>,
+    /// 唯一索引，按索引名称（逗号连接的字段名）组织
+    unique_indexes: HashMap<String, UniqueIndex>,
+    /// 后台创建中的等值索引，按字段组织
+    index_builds: HashMap<String, IndexBuild>,
+    query_cache: Option<Mutex<QueryCache>>,
+    scan_pool: Option<Arc<ThreadPool>>,
+    changes: Option<Arc<ChangeFeed>>,
+    /// 事务进行中暂存的变更事件，为None时直接发布
+    deferred: Option<Vec<PendingChange>>,
+    manifest: Option<Arc<Manifest>>,
+    /// 保存时使用的存储引擎和压缩方式
+    options: FileOptions,
+    /// 查询优化用的统计信息，与表文件一起保存
+    stats: Option<TableStats>,
+    /// 自上次统计以来修改的记录数
+    modified_since_analyze: usize,
+    /// 事件表中下一个事件的序号
+    next_event: u64,
+    autosave: Autosave,
+    /// 自上次保存以来修改的记录数
+    unsaved: usize,
+    /// 表文件的大小上限
+    max_file_size: usize,
+    quota: Option<Quota>,
+    /// 整个数据库的配额和用量，所有表共享
+    usage: Option<Arc<Usage>>,
+    /// 所有记录计入配额的字节数
+    bytes: usize,
+    /// 加密时另外以明文写入旁路文件的字段，为空时不写旁路文件
+    plaintext_fields: Vec<String>,
+    /// 按`meta.value_compression`压缩较大的值，内存中的记录保存压缩后的值
+    codec: Option<ValueCodec>,
+    /// 数据库共享的blob存储，较大的字节串只保存引用
+    blobs: Option<Arc<BlobStore>>,
+    /// 记录的插入顺序，超出配额时按它淘汰
+    insertion: InsertionOrder,
+    meta: TableMeta,
+    /// 时间序列表按时间戳排列的记录
+    timeline: Timeline,
+    /// 时间序列表中自上次保存以来修改过的段
+    dirty_segments: BTreeSet<i64>,
+    /// 按过期时间排列的记录，只包含过期时间字段为整数的记录
+    expiry: Timeline,
+    /// 排序和范围查询中比较字符串的函数
+    collation: Comparator,
+    /// 加载时跳过的损坏部分
+    damage: Vec<Damage>,
+    /// 最近一次修改记录的时间，加载时为表文件的修改时间
+    modified_at: Option<SystemTime>,
+    /// 最近一次保存的时间，加载时为表文件的修改时间
+    saved_at: Option<SystemTime>,
+    /// 自打开以来的读写计数
+    counters: TableCounters,
+    /// `SimpleDB::set_validator`注册的校验函数
+    validator: Option<Validator>,
+    /// 保存后是否fsync表文件所在的目录
+    sync_dir: bool,
     is_dirty: bool,
 }
 
+/// 表的修改与保存状态，见`SimpleDB::table_info`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableInfo {
+    pub name: String,
+    pub records: usize,
+    /// 最近一次修改记录的时间，新建后还没有修改过时为None
+    pub modified_at: Option<SystemTime>,
+    /// 最近一次保存的时间，还没有保存过时为None
+    pub saved_at: Option<SystemTime>,
+    /// 是否有未保存的修改，包括表设置的修改
+    pub dirty: bool,
+    /// 自上次保存以来修改的记录数，`Autosave::EveryChanges`按它决定何时保存
+    pub pending_changes: usize,
+}
+
 impl Table {
     /// 创建新表
     pub fn new(name: String, data_dir: &Path, crypto: Option<Crypto>) -> Result<Self> {
@@ -90,8 +319,44 @@ impl Table {
             file_path,
             crypto,
             records: HashMap::new(),
+            indexes: HashMap::new(),
+            geo_indexes: HashMap::new(),
+            unique_indexes: HashMap::new(),
+            index_builds: HashMap::new(),
+            query_cache: None,
+            scan_pool: None,
+            changes: None,
+            deferred: None,
+            manifest: None,
+            options: FileOptions::default(),
+            stats: None,
+            modified_since_analyze: 0,
+            next_event: 1,
+            autosave: Autosave::default(),
+            unsaved: 0,
+            max_file_size: usize::MAX,
+            quota: None,
+            usage: None,
+            bytes: 0,
+            plaintext_fields: Vec::new(),
+            codec: None,
+            blobs: None,
+            insertion: InsertionOrder::default(),
+            meta: TableMeta::default(),
+            timeline: Timeline::default(),
+            dirty_segments: BTreeSet::new(),
+            expiry: Timeline::default(),
+            collation: collation::binary,
+            damage: Vec::new(),
+            modified_at: None,
+            saved_at: None,
+            counters: TableCounters::default(),
+            validator: None,
+            sync_dir: false,
             is_dirty: false,
         };
+        table.meta = TableMeta::load(&table.meta_path())?;
+        table.codec = table.load_codec()?;
 
         // 如果文件存在，加载数据
         if table.file_path.exists() {
@@ -101,125 +366,1973 @@ impl Table {
         Ok(table)
     }
 
-    /// 插入记录
-    pub fn insert(&mut self, record: Record) -> Result<String> {
-        if self.records.contains_key(&record.id) {
-            return Err(DatabaseError::DuplicateKey(record.id));
+    /// 表的修改与保存状态
+    pub fn info(&self) -> TableInfo {
+        TableInfo {
+            name: self.name.clone(),
+            records: self.records.len(),
+            modified_at: self.modified_at,
+            saved_at: self.saved_at,
+            dirty: self.is_dirty,
+            pending_changes: self.unsaved,
         }
+    }
 
-        let id = record.id.clone();
-        self.records.insert(id.clone(), record);
-        self.is_dirty = true;
+    /// 自打开以来的读写计数
+    pub fn counters(&self) -> Counters {
+        self.counters.snapshot()
+    }
 
-        Ok(id)
+    /// 保存后是否fsync表文件所在的目录，见`Config::sync_dir`
+    pub fn set_sync_dir(&mut self, sync_dir: bool) {
+        self.sync_dir = sync_dir;
     }
 
-    /// 根据ID查找记录
-    pub fn find_by_id(&self, id: &str) -> Option<&Record> {
-        self.records.get(id)
+    /// 设置写入前调用的校验函数，None时取消
+    pub fn set_validator(&mut self, validator: Option<Validator>) {
+        self.validator = validator;
     }
 
-    /// 更新记录
-    pub fn update(&mut self, id: &str, data: HashMap<String, Value>) -> Result<()> {
-        match self.records.get_mut(id) {
-            Some(record) => {
-                record.update(data);
-                self.is_dirty = true;
-                Ok(())
-            }
-            None => Err(DatabaseError::RecordNotFound(id.to_string())),
+    /// 启用查询结果缓存，`capacity`为0时关闭
+    pub fn set_query_cache(&mut self, capacity: usize) {
+        self.query_cache = (capacity > 0).then(|| Mutex::new(QueryCache::new(capacity)));
+    }
+
+    /// 设置用于并行扫描的线程池，None表示始终串行扫描
+    pub fn set_scan_pool(&mut self, pool: Option<Arc<ThreadPool>>) {
+        self.scan_pool = pool;
+    }
+
+    /// 设置发布记录变更的变更流
+    pub fn set_change_feed(&mut self, feed: Option<Arc<ChangeFeed>>) {
+        self.changes = feed;
+    }
+
+    /// 设置保存时登记文件校验值的清单
+    pub fn set_manifest(&mut self, manifest: Option<Arc<Manifest>>) {
+        self.manifest = manifest;
+    }
+
+    /// 设置共享的blob存储，并为已加载的记录中的引用计数
+    pub(crate) fn set_blob_store(&mut self, blobs: Arc<BlobStore>) {
+        for record in self.records.values() {
+            blobs.retain(record);
         }
+        self.blobs = Some(blobs);
     }
 
-    /// 删除记录
-    pub fn delete(&mut self, id: &str) -> Result<()> {
-        match self.records.remove(id) {
-            Some(_) => {
-                self.is_dirty = true;
-                Ok(())
+    /// 表被删除时释放所有记录对blob的引用
+    pub(crate) fn release_blobs(&self) {
+        if let Some(blobs) = &self.blobs {
+            for record in self.records.values() {
+                blobs.release(record);
             }
-            None => Err(DatabaseError::RecordNotFound(id.to_string())),
         }
     }
 
-    /// 查询所有记录
-    pub fn find_all(&self) -> Vec<&Record> {
-        self.records.values().collect()
+    /// 设置保存时使用的存储引擎和压缩方式，已有文件与设置不同时下次保存会按新设置重写
+    ///
+    /// 是否为事件表是表本身的属性，不随这里的设置改变，见`set_append_only`。
+    pub fn set_file_options(&mut self, mut options: FileOptions) {
+        options.append_only = self.options.append_only;
+        if self.options != options {
+            self.options = options;
+            self.is_dirty |= self.file_path.exists();
+        }
     }
 
-    /// 根据条件查询记录
-    pub fn find_where<F>(&self, predicate: F) -> Vec<&Record>
-    where
-        F: Fn(&Record) -> bool,
-    {
-        self.records.values().filter(|r| predicate(r)).collect()
+    /// 设置修改何时写入磁盘
+    pub fn set_autosave(&mut self, autosave: Autosave) {
+        self.autosave = autosave;
     }
 
-    /// 保存到文件
-    pub fn save(&mut self) -> Result<()> {
-        if !self.is_dirty {
-            return Ok(());
+    /// 按自动保存策略，在写操作之后保存
+    pub(crate) fn autosave(&mut self) -> Result<()> {
+        match self.autosave {
+            Autosave::OnClose => Ok(()),
+            Autosave::EveryWrite => self.save(),
+            Autosave::EveryChanges(n) if self.unsaved >= n => self.save(),
+            Autosave::EveryChanges(_) => Ok(()),
+        }
+    }
+
+    pub fn set_max_file_size(&mut self, max_file_size: usize) {
+        self.max_file_size = max_file_size;
+    }
+
+    /// 表文件的大小上限，超出时拆分为多个文件，见`part_file_name`
+    pub fn max_file_size(&self) -> usize {
+        self.max_file_size
+    }
+
+    /// 设置存储配额，None表示不限制；已超出配额的表不会立即淘汰记录，只限制之后的写入
+    ///
+    /// 固定大小表始终按其上限淘汰记录，不受这里的设置影响。
+    pub fn set_quota(&mut self, quota: Option<Quota>) {
+        self.quota = quota;
+    }
+
+    /// 设置加密时以明文写入旁路文件的字段，下次保存时生效
+    pub fn set_plaintext_fields(&mut self, fields: Vec<String>) {
+        self.plaintext_fields = fields;
+    }
+
+    /// 明文旁路文件的路径
+    pub fn plaintext_path(&self) -> PathBuf {
+        self.file_path.with_extension(PLAINTEXT_EXTENSION)
+    }
+
+    /// 压缩字典文件的路径
+    pub fn dictionary_path(&self) -> PathBuf {
+        self.file_path.with_extension(DICTIONARY_EXTENSION)
+    }
+
+    /// 按元数据和字典文件创建值压缩器，未启用值压缩时为None
+    fn load_codec(&self) -> Result<Option<ValueCodec>> {
+        let Some(threshold) = self.meta.value_compression else {
+            return Ok(None);
+        };
+        let path = self.dictionary_path();
+        let dictionary = if path.exists() {
+            let content = std::fs::read(&path)?;
+            Some(match &self.crypto {
+                Some(crypto) => crypto.decrypt(&content)?,
+                None => content,
+            })
+        } else {
+            None
+        };
+        Ok(Some(ValueCodec::new(threshold, dictionary)))
+    }
+
+    /// 值压缩的阈值，None为不压缩
+    pub fn value_compression(&self) -> Option<usize> {
+        self.meta.value_compression
+    }
+
+    /// 是否使用训练出的压缩字典
+    pub fn has_compression_dictionary(&self) -> bool {
+        self.codec.as_ref().is_some_and(|codec| codec.dictionary().is_some())
+    }
+
+    /// 设置值压缩的阈值并立即写入元数据文件，None表示不压缩；现有记录随即按新设置重新压缩
+    ///
+    /// 不小于阈值的字符串和字节串值以zstd压缩后保存在内存和表文件中，读取时透明解压。
+    /// 关闭压缩时删除已训练的字典。
+    pub fn set_value_compression(&mut self, threshold: Option<usize>) -> Result<()> {
+        let dictionary = self.codec.as_ref().and_then(ValueCodec::dictionary).map(<[u8]>::to_vec);
+        self.meta.value_compression = threshold;
+        self.meta.save(&self.meta_path())?;
+        if threshold.is_none() {
+            self.write_dictionary(None)?;
         }
+        self.recode(threshold.map(|threshold| ValueCodec::new(threshold, dictionary)));
+        Ok(())
+    }
 
-        // 确保目录存在
-        if let Some(parent) = self.file_path.parent() {
+    /// 用表中不小于阈值的字符串和字节串值训练压缩字典，写入字典文件并用它重新压缩现有记录，返回字典的字节数
+    ///
+    /// 未启用值压缩或样本不足时返回`InvalidQuery`。
+    pub fn train_compression_dictionary(&mut self) -> Result<usize> {
+        let Some(threshold) = self.meta.value_compression else {
+            return Err(DatabaseError::InvalidQuery(format!("表 {} 没有启用值压缩", self.name)));
+        };
+        let records: Vec<Cow<Record>> = self.records.values().map(|r| self.expanded_ref(r)).collect();
+        let dictionary = compress::train_dictionary(threshold, records.iter().map(|r| &**r))?;
+        drop(records);
+        self.write_dictionary(Some(&dictionary))?;
+        let size = dictionary.len();
+        self.recode(Some(ValueCodec::new(threshold, Some(dictionary))));
+        Ok(size)
+    }
+
+    /// 写入压缩字典文件，None时删除；字典含有表中数据的片段，与表数据一样加密
+    fn write_dictionary(&self, dictionary: Option<&[u8]>) -> Result<()> {
+        let path = self.dictionary_path();
+        let Some(dictionary) = dictionary else {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            return Ok(());
+        };
+        let content = match &self.crypto {
+            Some(crypto) => crypto.encrypt(dictionary)?,
+            None => dictionary.to_vec(),
+        };
+        if let Some(parent) = path.parent() {
             std::fs::create_dir_all(parent)?;
         }
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+
+    /// 换用新的值压缩器：用旧的压缩器解压现有记录后重新压缩
+    fn recode(&mut self, codec: Option<ValueCodec>) {
+        let old = std::mem::replace(&mut self.codec, codec);
+        let records: Records = self
+            .records
+            .iter()
+            .map(|(id, record)| {
+                let mut record = match &old {
+                    Some(old) => old.expand(record).into_owned(),
+                    None => (**record).clone(),
+                };
+                record.data = self.compressed(record.data);
+                (id.clone(), Arc::new(record))
+            })
+            .collect();
+        self.records = records;
+        self.bytes = self.records.values().map(|r| quota::record_size(r)).sum();
+        if let Some(series) = &self.meta.time_series {
+            self.dirty_segments.extend(self.timeline.buckets(series));
+        }
+        self.mark_dirty();
+    }
 
-        // 序列化数据
-        let data = bincode::serialize(&self.records)?;
+    /// 按表的设置压缩数据中较大的值
+    fn compressed(&self, data: IndexMap<String, Value>) -> IndexMap<String, Value> {
+        match &self.codec {
+            Some(codec) => codec.compress(data),
+            None => data,
+        }
+    }
 
-        // 加密（如果启用）
-        let final_data = match &self.crypto {
-            Some(crypto) => crypto.encrypt(&data)?,
+    /// 数据存入表中的形式：较大的字节串换成blob引用并计数，较长的值再压缩
+    ///
+    /// 加密的表只在blob文件同样加密时使用blob存储。
+    fn stored(&self, data: IndexMap<String, Value>) -> IndexMap<String, Value> {
+        let data = match self.blobs.as_ref().filter(|blobs| self.crypto.is_none() || blobs.is_encrypted()) {
+            Some(blobs) => blobs.store(data),
             None => data,
         };
+        self.compressed(data)
+    }
 
-        // 写入文件
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&self.file_path)?;
-        
-        let mut writer = BufWriter::new(file);
-        writer.write_all(&final_data)?;
-        writer.flush()?;
+    /// 解压记录中的压缩值并换回blob引用的字节串，返回给调用方或用于比较和建索引；没有这些值时不复制
+    fn expanded_ref<'a>(&self, record: &'a Record) -> Cow<'a, Record> {
+        let record = match &self.codec {
+            Some(codec) => codec.expand(record),
+            None => Cow::Borrowed(record),
+        };
+        match &self.blobs {
+            Some(blobs) => blobs.resolve(record),
+            None => record,
+        }
+    }
 
-        self.is_dirty = false;
+    fn expanded(&self, record: &Arc<Record>) -> Arc<Record> {
+        match self.expanded_ref(record) {
+            Cow::Borrowed(_) => Arc::clone(record),
+            Cow::Owned(record) => Arc::new(record),
+        }
+    }
+
+    /// 元数据文件的路径
+    pub fn meta_path(&self) -> PathBuf {
+        self.file_path.with_extension(META_EXTENSION)
+    }
+
+    pub fn meta(&self) -> &TableMeta {
+        &self.meta
+    }
+
+    /// 设为固定大小表并立即写入元数据文件，None表示取消；超出新上限的最早记录立即删除
+    pub fn set_capped(&mut self, cap: Option<Cap>) -> Result<()> {
+        self.meta.capped = cap;
+        self.meta.save(&self.meta_path())?;
+        // 元数据只随表文件加载，没有表文件时要写出
+        self.is_dirty = true;
+        self.make_room(self.records.len(), self.bytes, |_| false)
+    }
+
+    /// 设为时间序列表并立即写入元数据文件，所有记录都必须有整数的时间戳字段
+    ///
+    /// 之后记录按时间戳分段保存在`<表名>@<段起始时间>.db`中，保存时只重写修改过的段。
+    pub fn set_time_series(&mut self, series: TimeSeries) -> Result<()> {
+        if series.bucket <= 0 {
+            return Err(DatabaseError::InvalidQuery(format!("时间序列表 {} 的分段跨度必须大于0", self.name)));
+        }
+        for record in self.records.values() {
+            if series.timestamp(&record.data).is_none() {
+                return Err(missing_timestamp(&self.name, &series.field));
+            }
+        }
+        self.timeline.clear();
+        for record in self.records.values() {
+            let timestamp = series.timestamp(&record.data).unwrap_or_default();
+            self.timeline.insert(timestamp, &record.id);
+            self.dirty_segments.insert(series.bucket_of(timestamp));
+        }
+        self.meta.time_series = Some(series);
+        self.meta.save(&self.meta_path())?;
+        // 原来的表文件中的记录移到段文件中
+        self.is_dirty = true;
         Ok(())
     }
 
-    /// 从文件加载
-    fn load(&mut self) -> Result<()> {
-        let file = File::open(&self.file_path)?;
-        let mut reader = BufReader::new(file);
-        
-        // 读取所有数据
-        let mut buffer = Vec::new();
-        reader.read_to_end(&mut buffer)?;
+    pub fn time_series(&self) -> Option<&TimeSeries> {
+        self.meta.time_series.as_ref()
+    }
 
-        if buffer.is_empty() {
-            return Ok(());
+    /// 设置保存记录过期时间（Unix秒）的字段并立即写入元数据文件，None表示取消
+    ///
+    /// 该字段为整数的记录到期后由`sweep_expired`删除，其他记录不会过期。
+    pub fn set_ttl_field(&mut self, field: Option<&str>) -> Result<()> {
+        self.check_mutable()?;
+        self.meta.ttl_field = field.map(str::to_string);
+        self.meta.save(&self.meta_path())?;
+        // 元数据只随表文件加载，没有表文件时要写出
+        self.is_dirty = true;
+        self.rebuild_expiry();
+        Ok(())
+    }
+
+    pub fn ttl_field(&self) -> Option<&str> {
+        self.meta.ttl_field.as_deref()
+    }
+
+    fn expires_at(&self, record: &Record) -> Option<i64> {
+        let field = self.meta.ttl_field.as_ref()?;
+        record.data.get(field).and_then(Value::as_int)
+    }
+
+    fn rebuild_expiry(&mut self) {
+        let mut expiry = Timeline::default();
+        for record in self.records.values() {
+            if let Some(expires_at) = self.expires_at(record) {
+                expiry.insert(expires_at, &record.id);
+            }
+        }
+        self.expiry = expiry;
+    }
+
+    /// 最早的过期时间，没有会过期的记录时为None
+    pub fn next_expiry(&self) -> Option<i64> {
+        self.expiry.first()
+    }
+
+    /// 删除过期时间不晚于`now`的记录，返回删除的记录数；只访问到期的记录，不扫描全表
+    pub fn sweep_expired(&mut self, now: i64) -> Result<usize> {
+        let expired: Vec<String> = self
+            .expiry
+            .range(i64::MIN, now.saturating_add(1))
+            .map(|(_, id)| id.to_string())
+            .collect();
+        for id in &expired {
+            self.remove(id)?;
+        }
+        Ok(expired.len())
+    }
+
+    /// 设置排序和范围查询中字符串的排序规则并立即写入元数据文件，None表示按字节比较
+    ///
+    /// 元数据中只保存排序规则的名称，重新打开时由数据库按名称找回比较函数，见`use_collation`。
+    pub fn set_collation(&mut self, collation: Option<Collation>) -> Result<()> {
+        self.meta.collation = collation.as_ref().map(|c| c.name.clone());
+        self.meta.save(&self.meta_path())?;
+        // 元数据只随表文件加载，没有表文件时要写出
+        self.is_dirty = true;
+        self.use_collation(collation.map_or(collation::binary, |c| c.compare));
+        Ok(())
+    }
+
+    /// 设置行级安全策略，None表示取消
+    pub fn set_policy(&mut self, policy: Option<Policy>) -> Result<()> {
+        self.meta.policy = policy;
+        self.meta.save(&self.meta_path())?;
+        // 元数据只随表文件加载，没有表文件时要写出
+        self.is_dirty = true;
+        Ok(())
+    }
+
+    /// 声明字段的类型，None表示取消声明；宽松模式下已有的记录不受影响，
+    /// 严格模式下已有的记录必须符合修改后的声明，否则不修改并返回`Validation`错误
+    pub fn set_field_type(&mut self, field: &str, field_type: Option<FieldType>) -> Result<()> {
+        self.set_schema(&BTreeMap::from([(field.to_string(), field_type)]), None)
+    }
+
+    /// 一起修改字段声明（None取消声明）和结构模式（None不变）
+    ///
+    /// 修改后为严格模式时先按修改后的声明检查所有记录，有不符合的记录时什么也不修改，返回`Validation`错误。
+    pub fn set_schema(&mut self, changes: &BTreeMap<String, Option<FieldType>>, mode: Option<TableMode>) -> Result<()> {
+        let mut types = self.meta.field_types.clone();
+        for (field, field_type) in changes {
+            match field_type {
+                Some(field_type) => types.insert(field.clone(), *field_type),
+                None => types.remove(field),
+            };
+        }
+        let mode = mode.unwrap_or(self.meta.mode);
+        if mode == TableMode::Strict {
+            self.check_records(&types)?;
+        }
+        self.meta.field_types = types;
+        self.meta.mode = mode;
+        self.meta.save(&self.meta_path())?;
+        // 元数据只随表文件加载，没有表文件时要写出
+        self.is_dirty = true;
+        Ok(())
+    }
+
+    /// 按字段声明的类型
+    pub fn field_types(&self) -> &BTreeMap<String, FieldType> {
+        &self.meta.field_types
+    }
+
+    /// 切换表的结构模式；切换为严格模式前检查所有已有的记录，有不符合的记录时不切换并返回`Validation`错误
+    pub fn set_mode(&mut self, mode: TableMode) -> Result<()> {
+        self.set_schema(&BTreeMap::new(), Some(mode))
+    }
+
+    /// 表的结构模式
+    pub fn mode(&self) -> TableMode {
+        self.meta.mode
+    }
+
+    /// 按严格模式检查所有记录，字段错误的信息前加上记录ID，按ID排列
+    fn check_records(&self, types: &BTreeMap<String, FieldType>) -> Result<()> {
+        let mut records: Vec<&Arc<Record>> = self.records.values().collect();
+        records.sort_by(|a, b| a.id.cmp(&b.id));
+        let errors: Vec<FieldError> = records
+            .into_iter()
+            .flat_map(|record| {
+                schema::check_strict(types, &record.data)
+                    .into_iter()
+                    .map(|e| FieldError::new(e.field, format!("记录 {}: {}", record.id, e.message)))
+            })
+            .collect();
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(DatabaseError::Validation(errors)),
+        }
+    }
+
+    /// 行级安全策略
+    pub fn policy(&self) -> Option<&Policy> {
+        self.meta.policy.as_ref()
+    }
+
+    /// 使用元数据中记录的排序规则对应的比较函数，不修改元数据
+    pub(crate) fn use_collation(&mut self, compare: Comparator) {
+        self.collation = compare;
+        self.clear_query_cache();
+    }
+
+    /// 排序规则的名称，None为按字节比较
+    pub fn collation(&self) -> Option<&str> {
+        self.meta.collation.as_deref()
+    }
+
+    /// 写入前检查数据：不能使用系统字段名，时间序列表必须有整数的时间戳，严格模式的表只能写入声明的字段，
+    /// 最后调用注册的校验函数
+    fn check_data(&self, data: &IndexMap<String, Value>) -> Result<()> {
+        system::check_data(data)?;
+        if let Some(series) = &self.meta.time_series {
+            if series.timestamp(data).is_none() {
+                return Err(missing_timestamp(&self.name, &series.field));
+            }
+        }
+        if self.meta.mode == TableMode::Strict {
+            let errors = schema::check_strict(&self.meta.field_types, data);
+            if !errors.is_empty() {
+                return Err(DatabaseError::Validation(errors));
+            }
         }
+        match &self.validator {
+            Some(validator) => validator.validate(data).map_err(DatabaseError::Validation),
+            None => Ok(()),
+        }
+    }
+
+    /// 生效的配额：固定大小表的上限优先于配置的配额
+    fn effective_quota(&self) -> Option<Quota> {
+        self.meta.capped.map(|cap| cap.quota()).or(self.quota)
+    }
+
+    /// 写入前是否要检查配额：表有配额，或整个数据库有配额
+    fn has_quota(&self) -> bool {
+        self.effective_quota().is_some() || self.usage.is_some()
+    }
+
+    /// 参与整个数据库的配额，记下当前的用量
+    pub(crate) fn set_usage(&mut self, usage: Option<Arc<Usage>>) {
+        if let Some(usage) = &usage {
+            usage.record(&self.name, self.records.len(), self.bytes);
+        }
+        self.usage = usage;
+    }
+
+    /// 所有记录计入配额的字节数
+    pub fn data_size(&self) -> usize {
+        self.bytes
+    }
+
+    /// 确保写入后表中有`records`条记录、共`bytes`字节时不超出配额
+    ///
+    /// 按配额的策略拒绝写入，或由旧到新淘汰`keep`返回false的记录；即使淘汰所有能淘汰的记录仍超出时拒绝，不淘汰任何记录。
+    /// 整个数据库有配额时同时预先记下写入后的用量，见`Usage::reserve`；返回错误时改回表实际的用量。
+    fn make_room(&mut self, records: usize, bytes: usize, keep: impl Fn(&str) -> bool) -> Result<()> {
+        // 整个数据库的配额总是拒绝写入，不淘汰记录
+        if let Some(usage) = &self.usage {
+            if !usage.reserve(&self.name, records, bytes) {
+                return Err(DatabaseError::QuotaExceeded(format!("数据库超出配额，无法写入表 {}", self.name)));
+            }
+        }
+        let result = self.make_table_room(records, bytes, keep);
+        if result.is_err() {
+            if let Some(usage) = &self.usage {
+                usage.record(&self.name, self.records.len(), self.bytes);
+            }
+        }
+        result
+    }
 
-        // 解密（如果启用）
-        let data = match &self.crypto {
-            Some(crypto) => crypto.decrypt(&buffer)?,
-            None => buffer,
+    /// 按表的配额腾出空间，见`make_room`
+    fn make_table_room(&mut self, records: usize, bytes: usize, keep: impl Fn(&str) -> bool) -> Result<()> {
+        let Some(quota) = self.effective_quota() else {
+            return Ok(());
         };
+        if !quota.exceeded(records, bytes) {
+            return Ok(());
+        }
+        let exceeded = || DatabaseError::QuotaExceeded(format!("表 {} 超出配额", self.name));
+        if quota.policy == QuotaPolicy::Reject {
+            return Err(exceeded());
+        }
 
-        // 反序列化
-        self.records = bincode::deserialize(&data)?;
-        self.is_dirty = false;
+        let (mut records, mut bytes) = (records, bytes);
+        let mut victims = Vec::new();
+        for id in self.insertion.oldest_first().filter(|id| !keep(id)) {
+            if !quota.exceeded(records, bytes) {
+                break;
+            }
+            records -= 1;
+            bytes = bytes.saturating_sub(quota::record_size(&self.records[id]));
+            victims.push(id.to_string());
+        }
+        if quota.exceeded(records, bytes) {
+            return Err(exceeded());
+        }
+        // 淘汰不随事务撤销，删除事件总是直接发布
+        let deferred = self.deferred.take();
+        let result = victims.iter().try_for_each(|id| self.remove(id));
+        self.deferred = deferred;
+        result
+    }
+
+    /// 设为只能追加记录的事件表，或取消
+    pub fn set_append_only(&mut self, append_only: bool) {
+        if self.options.append_only != append_only {
+            self.options.append_only = append_only;
+            self.is_dirty = true;
+        }
+    }
+
+    /// 是否为事件表
+    pub fn is_append_only(&self) -> bool {
+        self.options.append_only
+    }
 
+    /// 事件表拒绝修改和删除记录
+    fn check_mutable(&self) -> Result<()> {
+        if self.options.append_only {
+            return Err(DatabaseError::NotPermitted(format!("{} 是事件表，记录只能追加", self.name)));
+        }
         Ok(())
     }
 
-    /// 获取记录数量
-    pub fn count(&self) -> usize {
-        self.records.len()
+    /// 事件表中序号大于`after`的事件，按序号排列；同时返回序号不大于`after`的事件数
+    pub fn events_after(&self, after: &str) -> (usize, Vec<Arc<Record>>) {
+        let mut earlier = 0;
+        let mut events = Vec::new();
+        for (id, record) in &self.records {
+            if id.as_str() > after {
+                events.push(self.expanded(record));
+            } else {
+                earlier += 1;
+            }
+        }
+        events.sort_by(|a, b| a.id.cmp(&b.id));
+        (earlier, events)
+    }
+
+    /// 放弃未保存的修改，表被删除时避免析构时又把文件写回去
+    pub(crate) fn discard_changes(&mut self) {
+        self.is_dirty = false;
+        self.unsaved = 0;
+        self.dirty_segments.clear();
+    }
+
+    /// 统计信息文件的路径，扩展名不是`.db`，不会被当作表加载
+    pub fn stats_path(&self) -> PathBuf {
+        self.file_path.with_extension("stats")
+    }
+
+    /// 最近一次收集的统计信息
+    pub fn stats(&self) -> Option<&TableStats> {
+        self.stats.as_ref()
+    }
+
+    /// 重新收集统计信息并保存
+    pub fn analyze(&mut self) -> Result<&TableStats> {
+        let records: Vec<Cow<Record>> = self.records.values().map(|r| self.expanded_ref(r)).collect();
+        let stats = TableStats::collect(records.iter().map(|r| &**r));
+        let mut content = bincode::serialize(&stats)?;
+        if let Some(crypto) = &self.crypto {
+            // 统计信息含有字段的最值和常见值，与表数据一样需要加密
+            content = crypto.encrypt(&content)?;
+        }
+        std::fs::write(self.stats_path(), content)?;
+        self.modified_since_analyze = 0;
+        Ok(self.stats.insert(stats))
+    }
+
+    /// 统计信息缺失或自上次统计以来修改过多
+    fn stats_stale(&self) -> bool {
+        match &self.stats {
+            None => !self.records.is_empty(),
+            Some(stats) => self.modified_since_analyze as f64 > stats.rows.max(1) as f64 * stats::STALE_RATIO,
+        }
+    }
+
+    /// 表文件名（不含目录）
+    pub fn file_name(&self) -> String {
+        format!("{}.db", self.name)
+    }
+
+    fn publish(&mut self, kind: ChangeKind, id: &str, record: Option<Arc<Record>>) {
+        match &mut self.deferred {
+            Some(deferred) => deferred.push((kind, id.to_string(), record)),
+            None => self.publish_change((kind, id.to_string(), record)),
+        }
+    }
+
+    /// 发布一个暂存的变更事件
+    pub(crate) fn publish_change(&self, (kind, id, record): PendingChange) {
+        if let Some(feed) = &self.changes {
+            feed.publish(&self.name, kind, &id, record.map(|record| self.expanded(&record)));
+        }
+    }
+
+    /// 之后的变更事件先暂存，由`take_deferred`取出；事务提交成功后才发布，撤销的操作不产生事件
+    pub(crate) fn defer_changes(&mut self) {
+        self.deferred.get_or_insert_with(Vec::new);
+    }
+
+    /// 取出暂存的变更事件，`resume`为true时恢复直接发布
+    pub(crate) fn take_deferred(&mut self, resume: bool) -> Vec<PendingChange> {
+        match resume {
+            true => self.deferred.take().unwrap_or_default(),
+            false => self.deferred.as_mut().map(std::mem::take).unwrap_or_default(),
+        }
+    }
+
+    /// 标记表已修改并使查询缓存失效
+    fn mark_dirty(&mut self) {
+        self.is_dirty = true;
+        self.modified_at = Some(SystemTime::now());
+        self.counters.write();
+        self.unsaved += 1;
+        self.modified_since_analyze += 1;
+        self.clear_query_cache();
+        if let Some(usage) = &self.usage {
+            usage.record(&self.name, self.records.len(), self.bytes);
+        }
+    }
+
+    fn clear_query_cache(&self) {
+        if let Some(cache) = &self.query_cache {
+            if let Ok(mut cache) = cache.lock() {
+                cache.clear();
+            }
+        }
+    }
+
+    /// 插入记录
+    ///
+    /// 事件表忽略记录原来的ID，按追加顺序分配定长的序号作为ID，ID的字典序即事件的先后顺序。
+    pub fn insert(&mut self, mut record: Record) -> Result<String> {
+        if self.options.append_only {
+            record.id = event_id(self.next_event);
+            self.next_event += 1;
+        }
+        if self.records.contains_key(&record.id) {
+            return Err(DatabaseError::DuplicateKey(record.id));
+        }
+        self.check_data(&record.data)?;
+        self.check_unique(&record)?;
+        if self.has_quota() {
+            let size = quota::record_size(&Record {
+                data: self.compressed(record.data.clone()),
+                ..record.clone()
+            });
+            self.make_room(self.records.len() + 1, self.bytes + size, |id| id == record.id)?;
+        }
+        record.data = self.stored(record.data);
+
+        let id = record.id.clone();
+        self.index_record(&record);
+        self.insertion.push(&id);
+        let record = Arc::new(record);
+        self.records.insert(id.clone(), Arc::clone(&record));
+        self.mark_dirty();
+        self.publish(ChangeKind::Insert, &id, Some(record));
+
+        Ok(id)
+    }
+
+    /// 根据ID查找记录
+    pub fn find_by_id(&self, id: &str) -> Option<Arc<Record>> {
+        self.counters.read();
+        self.records.get(id).map(|record| self.expanded(record))
+    }
+
+    /// 更新记录
+    pub fn update(&mut self, id: &str, data: IndexMap<String, Value>) -> Result<()> {
+        self.check_mutable()?;
+        self.check_data(&data)?;
+        if !self.unique_indexes.is_empty() {
+            let current = self
+                .records
+                .get(id)
+                .ok_or_else(|| DatabaseError::RecordNotFound(id.to_string()))?;
+            self.check_unique(&Record {
+                id: id.to_string(),
+                data: data.clone(),
+                created_at: current.created_at,
+                updated_at: current.updated_at,
+            })?;
+        }
+        if self.has_quota() {
+            self.check_quota(&[(id.to_string(), data.clone())])?;
+        }
+        self.replace_data(id, data)
+    }
+
+    /// 替换记录的数据前检查配额，必要时淘汰其他记录
+    fn check_quota(&mut self, changes: &[(String, IndexMap<String, Value>)]) -> Result<()> {
+        let mut bytes = self.bytes;
+        for (id, data) in changes {
+            let current = self
+                .records
+                .get(id)
+                .ok_or_else(|| DatabaseError::RecordNotFound(id.to_string()))?;
+            let updated = Record {
+                data: self.compressed(data.clone()),
+                ..(**current).clone()
+            };
+            bytes = (bytes + quota::record_size(&updated)).saturating_sub(quota::record_size(current));
+        }
+        let changed: HashSet<&str> = changes.iter().map(|(id, _)| id.as_str()).collect();
+        self.make_room(self.records.len(), bytes, |id| changed.contains(id))
+    }
+
+    /// 替换记录的数据，不检查唯一约束
+    fn replace_data(&mut self, id: &str, data: IndexMap<String, Value>) -> Result<()> {
+        match self.records.remove(id) {
+            Some(mut record) => {
+                let data = self.stored(data);
+                self.unindex_record(&record);
+                // 写时复制：仍被调用方持有的旧句柄保持不变
+                Arc::make_mut(&mut record).update(data);
+                self.index_record(&record);
+                self.records.insert(id.to_string(), Arc::clone(&record));
+                self.mark_dirty();
+                self.publish(ChangeKind::Update, id, Some(record));
+                Ok(())
+            }
+            None => Err(DatabaseError::RecordNotFound(id.to_string())),
+        }
+    }
+
+    /// 把记录的修改时间改为`updated_at`，用于按主节点的时间重放集群中的写入；只在刚写入该记录后调用
+    pub(crate) fn set_updated_at(&mut self, id: &str, updated_at: u64) {
+        if let Some(record) = self.records.get_mut(id) {
+            Arc::make_mut(record).updated_at = updated_at;
+        }
+    }
+
+    /// 按顺序应用局部更新操作
+    pub fn patch(&mut self, id: &str, ops: &[UpdateOp]) -> Result<()> {
+        let record = self
+            .records
+            .get(id)
+            .ok_or_else(|| DatabaseError::RecordNotFound(id.to_string()))?;
+        let data = update::apply_all(&self.expanded_ref(record).data, ops)?;
+        self.update(id, data)
+    }
+
+    /// 对所有记录应用同一组更新操作，返回实际发生变化的记录数
+    ///
+    /// 先计算全部结果再写入，任一记录失败时整表保持不变。
+    pub fn patch_all(&mut self, ops: &[UpdateOp]) -> Result<usize> {
+        let mut changes = Vec::new();
+        for record in self.records.values() {
+            let record = self.expanded_ref(record);
+            let data = update::apply_all(&record.data, ops)?;
+            if data != record.data {
+                changes.push((record.id.clone(), data));
+            }
+        }
+        self.apply_changes(changes)
+    }
+
+    /// 对每条记录调用`f`，返回`Some`时用其结果替换记录的数据，返回实际发生变化的记录数
+    ///
+    /// 与`patch_all`一样先计算全部结果再写入，违反唯一约束时整表保持不变；每条变更都发布到变更流。
+    pub fn transform<F>(&mut self, mut f: F) -> Result<usize>
+    where
+        F: FnMut(&Record) -> Option<IndexMap<String, Value>>,
+    {
+        let changes: Vec<(String, IndexMap<String, Value>)> = self
+            .records
+            .values()
+            .filter_map(|record| {
+                let record = self.expanded_ref(record);
+                f(&record).filter(|data| *data != record.data).map(|data| (record.id.clone(), data))
+            })
+            .collect();
+        self.apply_changes(changes)
+    }
+
+    /// 批量替换记录的数据，写入前按全部替换后的结果检查唯一约束，记录之间互换值不算冲突
+    fn apply_changes(&mut self, changes: Vec<(String, IndexMap<String, Value>)>) -> Result<usize> {
+        if !changes.is_empty() {
+            self.check_mutable()?;
+        }
+        for (_, data) in &changes {
+            self.check_data(data)?;
+        }
+        for index in self.unique_indexes.values() {
+            let mut index = index.clone();
+            for (id, _) in &changes {
+                index.remove(&self.expanded_ref(&self.records[id]));
+            }
+            for (id, data) in &changes {
+                let record = Record {
+                    data: data.clone(),
+                    ..(*self.records[id]).clone()
+                };
+                if let Some(owner) = index.conflict(&record) {
+                    return Err(unique_violation(&index, owner));
+                }
+                index.insert(&record);
+            }
+        }
+        if self.has_quota() {
+            self.check_quota(&changes)?;
+        }
+
+        let changed = changes.len();
+        for (id, data) in changes {
+            self.replace_data(&id, data)?;
+        }
+        Ok(changed)
+    }
+
+    /// 删除记录
+    pub fn delete(&mut self, id: &str) -> Result<()> {
+        self.check_mutable()?;
+        self.remove(id)
+    }
+
+    /// 删除记录，不检查是否为事件表；用于撤销未提交的插入
+    pub(crate) fn remove(&mut self, id: &str) -> Result<()> {
+        match self.records.remove(id) {
+            Some(record) => {
+                self.unindex_record(&record);
+                self.insertion.remove(id);
+                self.mark_dirty();
+                self.publish(ChangeKind::Delete, id, None);
+                Ok(())
+            }
+            None => Err(DatabaseError::RecordNotFound(id.to_string())),
+        }
+    }
+
+    /// 删除所有记录并把表文件重写为空表，保留索引定义；返回删除的记录数
+    pub fn truncate(&mut self) -> Result<usize> {
+        self.check_mutable()?;
+        let removed = self.records.len();
+        for build in self.index_builds.values_mut() {
+            build.changed.extend(self.records.keys().cloned());
+        }
+        self.release_blobs();
+        self.records.clear();
+        self.insertion.clear();
+        if let Some(series) = &self.meta.time_series {
+            self.dirty_segments.extend(self.timeline.buckets(series));
+        }
+        self.timeline.clear();
+        self.expiry.clear();
+        self.bytes = 0;
+        for index in self.indexes.values_mut() {
+            index.clear();
+        }
+        for index in self.geo_indexes.values_mut() {
+            *index = GeoIndex::new();
+        }
+        for index in self.unique_indexes.values_mut() {
+            index.clear();
+        }
+        if self.stats_path().exists() {
+            std::fs::remove_file(self.stats_path())?;
+        }
+        self.stats = None;
+
+        self.mark_dirty();
+        self.save()?;
+        self.modified_since_analyze = 0;
+        self.publish(ChangeKind::Truncate, "", None);
+        Ok(removed)
+    }
+
+    /// 写入来自其他数据库的记录版本（合并、同步），保留其ID和时间戳
+    ///
+    /// 与插入一样先检查数据：严格模式的表拒绝不符合声明的记录，注册的校验函数同样生效。
+    pub(crate) fn restore_checked(&mut self, record: Arc<Record>) -> Result<()> {
+        self.check_data(&record.data)?;
+        self.restore(record);
+        Ok(())
+    }
+
+    /// 写入前对数据的检查，不写入；用于在修改之前检查一批记录
+    pub(crate) fn check_incoming(&self, data: &IndexMap<String, Value>) -> Result<()> {
+        self.check_data(data)
+    }
+
+    /// 将记录恢复为给定版本，用于撤销已应用的写操作
+    pub(crate) fn restore(&mut self, mut record: Arc<Record>) {
+        if self.codec.is_some() || self.blobs.is_some() {
+            let data = self.stored(record.data.clone());
+            Arc::make_mut(&mut record).data = data;
+        }
+        let kind = match self.records.remove(&record.id) {
+            Some(current) => {
+                self.unindex_record(&current);
+                ChangeKind::Update
+            }
+            None => {
+                self.insertion.push(&record.id);
+                ChangeKind::Insert
+            }
+        };
+        self.index_record(&record);
+        let id = record.id.clone();
+        self.records.insert(id.clone(), Arc::clone(&record));
+        self.mark_dirty();
+        self.publish(kind, &id, Some(record));
+    }
+
+    /// 最近插入的`n`条记录，由新到旧排列
+    ///
+    /// 插入顺序只保存在内存中，重新打开表后按创建时间排列，同一秒内创建的记录按ID排列。
+    pub fn find_latest(&self, n: usize) -> Vec<Arc<Record>> {
+        self.counters.read();
+        self.insertion
+            .oldest_first()
+            .rev()
+            .take(n)
+            .filter_map(|id| self.records.get(id))
+            .map(|record| self.expanded(record))
+            .collect()
+    }
+
+    /// 时间序列表中时间戳在`[from, to)`内的记录，按时间先后排列；不是时间序列表时为空
+    pub fn find_between(&self, from: i64, to: i64) -> Vec<Arc<Record>> {
+        self.counters.read();
+        self.timeline
+            .range(from, to)
+            .filter_map(|(_, id)| self.records.get(id))
+            .map(|record| self.expanded(record))
+            .collect()
+    }
+
+    /// 时间序列表中时间戳在`[from, to)`内的记录按`interval`分组，对每组的`field`求聚合
+    ///
+    /// 返回(组的起始时间, 聚合值)，按时间先后排列，没有记录的组不出现。
+    pub fn downsample(
+        &self,
+        from: i64,
+        to: i64,
+        interval: i64,
+        field: &str,
+        aggregate: Aggregate,
+        overflow: OverflowPolicy,
+    ) -> Result<Vec<(i64, Value)>> {
+        if interval <= 0 {
+            return Err(DatabaseError::InvalidQuery("降采样的间隔必须大于0".to_string()));
+        }
+        let points = self
+            .timeline
+            .range(from, to)
+            .filter_map(|(timestamp, id)| self.records.get(id).map(|record| (timestamp, record.as_ref())));
+        timeseries::downsample(points, interval, field, aggregate, overflow)
+    }
+
+    /// 删除时间序列表中时间戳早于`before`的记录，返回删除的记录数
+    ///
+    /// 整段过期的段文件在保存时直接删除，不需要重写。
+    pub fn expire_before(&mut self, before: i64) -> Result<usize> {
+        self.check_mutable()?;
+        let expired: Vec<String> = self.timeline.range(i64::MIN, before).map(|(_, id)| id.to_string()).collect();
+        for id in &expired {
+            self.remove(id)?;
+        }
+        Ok(expired.len())
+    }
+
+    /// 查询所有记录，按默认顺序（创建时间，相同时按ID）排列
+    pub fn find_all(&self) -> Vec<Arc<Record>> {
+        self.find_where(|_| true)
+    }
+
+    /// 根据条件查询记录，按默认顺序排列
+    pub fn find_where<F>(&self, predicate: F) -> Vec<Arc<Record>>
+    where
+        F: Fn(&Record) -> bool,
+    {
+        self.counters.read();
+        self.filter_records(predicate)
+    }
+
+    /// 遍历全部记录，返回满足条件的记录，按默认顺序排列
+    fn filter_records<F>(&self, predicate: F) -> Vec<Arc<Record>>
+    where
+        F: Fn(&Record) -> bool,
+    {
+        self.counters.scan();
+        let mut records: Vec<Arc<Record>> = self.records.values().map(|r| self.expanded(r)).filter(|r| predicate(r)).collect();
+        records.sort_by(|a, b| a.default_order(b));
+        records
+    }
+
+    /// 查询字段等于`value`或数组字段包含`value`的记录，有索引时不扫描全表
+    pub fn find_by_field(&self, field: &str, value: &Value) -> Vec<Arc<Record>> {
+        let matches = |record: &Record| match record.data.get(field) {
+            Some(v) if v == value => true,
+            Some(Value::Array(items)) => items.contains(value),
+            _ => false,
+        };
+        self.counters.read();
+        match self.indexes.get(field).filter(|index| index.filter().is_empty()) {
+            Some(index) => {
+                self.counters.index_hit();
+                index
+                    .lookup(value)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|id| self.records.get(id))
+                    .map(|r| self.expanded(r))
+                    .filter(|r| matches(r))
+                    .collect()
+            }
+            None => {
+                self.counters.index_miss();
+                self.filter_records(matches)
+            }
+        }
+    }
+
+    /// 全表扫描，大表且配置了线程池时并行过滤
+    fn scan(&self, query: &Query) -> Vec<Arc<Record>> {
+        match &self.scan_pool {
+            Some(pool) if self.records.len() >= PARALLEL_SCAN_THRESHOLD => pool.install(|| {
+                self.counters.scan();
+                self.records
+                    .par_iter()
+                    .map(|(_, r)| self.expanded(r))
+                    .filter(|r| query.matches_with(r, self.collation))
+                    .collect()
+            }),
+            _ => self.filter_records(|r| query.matches_with(r, self.collation)),
+        }
+    }
+
+    /// 在指定字段上创建空间索引，定义保存在元数据中
+    pub fn create_geo_index(&mut self, field: &str) -> Result<()> {
+        self.build_geo_index(field);
+        self.define_index(IndexDef::new(IndexKind::Geo, field, Vec::new()))
+    }
+
+    fn build_geo_index(&mut self, field: &str) {
+        let mut index = GeoIndex::new();
+        for record in self.records.values() {
+            if let Some((lat, lon)) = record.data.get(field).and_then(Value::as_geo_point) {
+                index.insert(&record.id, lat, lon);
+            }
+        }
+        self.geo_indexes.insert(field.to_string(), index);
+    }
+
+    /// 查询指定字段距离给定点不超过`radius_m`米的记录，按距离由近到远排序
+    pub fn find_near(&self, field: &str, lat: f64, lon: f64, radius_m: f64) -> Vec<(Arc<Record>, f64)> {
+        self.counters.read();
+        let candidates: Box<dyn Iterator<Item = &Arc<Record>>> = match self
+            .geo_indexes
+            .get(field)
+            .and_then(|index| index.candidates(lat, lon, radius_m))
+        {
+            Some(ids) => {
+                self.counters.index_hit();
+                Box::new(ids.into_iter().filter_map(|id| self.records.get(&id)))
+            }
+            None => {
+                self.counters.index_miss();
+                self.counters.scan();
+                Box::new(self.records.values())
+            }
+        };
+
+        let mut result: Vec<(Arc<Record>, f64)> = candidates
+            .filter_map(|record| {
+                let (p_lat, p_lon) = record.data.get(field)?.as_geo_point()?;
+                let distance = geo::haversine_distance(lat, lon, p_lat, p_lon);
+                (distance <= radius_m).then(|| (self.expanded(record), distance))
+            })
+            .collect();
+        result.sort_by(|a, b| a.1.total_cmp(&b.1));
+        result
+    }
+
+    /// 返回与查询向量最相似的`k`条记录及其相似度，按相似度由高到低排序
+    ///
+    /// 目前为暴力扫描，字段缺失或维度不一致的记录会被跳过。
+    pub fn find_similar(&self, field: &str, query: &[f32], k: usize, metric: Metric) -> Vec<(Arc<Record>, f32)> {
+        self.counters.read();
+        self.counters.scan();
+        let mut scored: Vec<(Arc<Record>, f32)> = self
+            .records
+            .values()
+            .filter_map(|record| {
+                let vector = record.data.get(field)?.as_vector()?;
+                Some((self.expanded(record), metric.similarity(query, vector)?))
+            })
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.truncate(k);
+        scored
+    }
+
+    /// 将记录加入所有索引，并计入表的字节数
+    fn index_record(&mut self, record: &Record) {
+        self.bytes += quota::record_size(record);
+        let expanded = self.expanded_ref(record);
+        let record: &Record = &expanded;
+        if let Some(series) = &self.meta.time_series {
+            if let Some(timestamp) = series.timestamp(&record.data) {
+                self.timeline.insert(timestamp, &record.id);
+                self.dirty_segments.insert(series.bucket_of(timestamp));
+            }
+        }
+        if let Some(expires_at) = self.expires_at(record) {
+            self.expiry.insert(expires_at, &record.id);
+        }
+        for (field, index) in self.indexes.iter_mut() {
+            index.add_record(field, record);
+        }
+        for build in self.index_builds.values_mut() {
+            build.changed.insert(record.id.clone());
+        }
+        for (field, index) in self.geo_indexes.iter_mut() {
+            if let Some((lat, lon)) = record.data.get(field).and_then(Value::as_geo_point) {
+                index.insert(&record.id, lat, lon);
+            }
+        }
+        for index in self.unique_indexes.values_mut() {
+            index.insert(record);
+        }
+    }
+
+    /// 检查记录是否违反唯一约束
+    fn check_unique(&self, record: &Record) -> Result<()> {
+        for index in self.unique_indexes.values() {
+            if let Some(owner) = index.conflict(record) {
+                return Err(unique_violation(index, owner));
+            }
+        }
+        Ok(())
+    }
+
+    /// 将记录从所有索引中移除，并从表的字节数中扣除
+    fn unindex_record(&mut self, record: &Record) {
+        self.bytes = self.bytes.saturating_sub(quota::record_size(record));
+        let expanded = self.expanded_ref(record);
+        // 先取出blob的内容再释放引用，引用数降为0时内容随之释放
+        if let Some(blobs) = &self.blobs {
+            blobs.release(record);
+        }
+        let record: &Record = &expanded;
+        if let Some(series) = &self.meta.time_series {
+            if let Some(timestamp) = series.timestamp(&record.data) {
+                self.timeline.remove(timestamp, &record.id);
+                self.dirty_segments.insert(series.bucket_of(timestamp));
+            }
+        }
+        if let Some(expires_at) = self.expires_at(record) {
+            self.expiry.remove(expires_at, &record.id);
+        }
+        for (field, index) in self.indexes.iter_mut() {
+            index.remove_record(field, record);
+        }
+        for build in self.index_builds.values_mut() {
+            build.changed.insert(record.id.clone());
+        }
+        for (field, index) in self.geo_indexes.iter_mut() {
+            if let Some((lat, lon)) = record.data.get(field).and_then(Value::as_geo_point) {
+                index.remove(&record.id, lat, lon);
+            }
+        }
+        for index in self.unique_indexes.values_mut() {
+            index.remove(record);
+        }
+    }
+
+    /// 在指定字段上创建等值索引
+    pub fn create_index(&mut self, field: &str) -> Result<()> {
+        self.create_partial_index(field, Vec::new())
+    }
+
+    /// 在指定字段上创建只收录满足`filter`的记录的部分索引，定义保存在元数据中
+    ///
+    /// 只有查询条件中包含全部过滤条件时，查询计划才会使用该索引。
+    pub fn create_partial_index(&mut self, field: &str, filter: Vec<Condition>) -> Result<()> {
+        self.build_partial_index(field, filter.clone());
+        self.define_index(IndexDef::new(IndexKind::Hash, field, filter))
+    }
+
+    fn build_partial_index(&mut self, field: &str, filter: Vec<Condition>) {
+        let records: Vec<Cow<Record>> = self.records.values().map(|r| self.expanded_ref(r)).collect();
+        let index = FieldIndex::build(field, filter, records.iter().map(|r| &**r));
+        self.indexes.insert(field.to_string(), index);
+    }
+
+    /// 登记索引定义并写入元数据，同一字段上同类的定义被替换
+    fn define_index(&mut self, def: IndexDef) -> Result<()> {
+        self.meta.indexes.retain(|d| (d.kind, &d.field) != (def.kind, &def.field));
+        self.meta.indexes.push(def);
+        self.meta.save(&self.meta_path())?;
+        // 元数据只随表文件加载，没有表文件时要写出
+        self.is_dirty = true;
+        Ok(())
+    }
+
+    /// 按元数据中的定义重建索引，打开表时调用
+    pub(crate) fn rebuild_indexes(&mut self) -> Result<()> {
+        for def in self.meta.indexes.clone() {
+            match def.kind {
+                IndexKind::Hash => self.build_partial_index(&def.field, def.filter),
+                IndexKind::Geo => self.build_geo_index(&def.field),
+                IndexKind::Unique => self.build_unique_index(def.field.split(',').map(str::to_string).collect())?,
+            }
+        }
+        Ok(())
+    }
+
+    /// 复制另一张表（记录相同）的全部索引和索引定义
+    pub(crate) fn copy_indexes_from(&mut self, other: &Table) -> Result<()> {
+        self.indexes = other.indexes.clone();
+        self.geo_indexes = other.geo_indexes.clone();
+        self.unique_indexes = other.unique_indexes.clone();
+        if self.meta.indexes != other.meta.indexes {
+            self.meta.indexes = other.meta.indexes.clone();
+            self.meta.save(&self.meta_path())?;
+            self.is_dirty = true;
+        }
+        Ok(())
+    }
+
+    /// 开始在后台创建`field`上的等值索引，返回记录快照和用于报告进度的计数器
+    ///
+    /// 之后被写入的记录ID会被记下，由`finish_index_build`补到索引中。
+    pub(crate) fn begin_index_build(&mut self, field: &str) -> Result<(Records, Arc<AtomicUsize>)> {
+        if self.index_builds.contains_key(field) {
+            return Err(DatabaseError::InvalidQuery(format!("字段 {} 上的索引正在后台创建", field)));
+        }
+        let processed = Arc::new(AtomicUsize::new(0));
+        let build = IndexBuild {
+            total: self.records.len(),
+            processed: Arc::clone(&processed),
+            changed: HashSet::new(),
+        };
+        self.index_builds.insert(field.to_string(), build);
+        Ok((self.expanded_records(), processed))
+    }
+
+    /// 将在快照上构建好的索引补上快照之后的修改并启用，定义保存在元数据中
+    pub(crate) fn finish_index_build(&mut self, field: &str, mut index: FieldIndex, snapshot: &Records) -> Result<()> {
+        let Some(build) = self.index_builds.remove(field) else {
+            return Ok(());
+        };
+        for id in build.changed {
+            if let Some(old) = snapshot.get(&id) {
+                index.remove_record(field, old);
+            }
+            if let Some(current) = self.records.get(&id) {
+                index.add_record(field, &self.expanded_ref(current));
+            }
+        }
+        let filter = index.filter().to_vec();
+        self.indexes.insert(field.to_string(), index);
+        self.define_index(IndexDef::new(IndexKind::Hash, field, filter))
+    }
+
+    /// 后台创建中的索引及其进度，按字段名排序
+    pub fn index_builds(&self) -> Vec<IndexBuildProgress> {
+        let mut builds: Vec<IndexBuildProgress> = self
+            .index_builds
+            .iter()
+            .map(|(field, build)| IndexBuildProgress {
+                field: field.clone(),
+                processed: build.processed.load(Ordering::Relaxed),
+                total: build.total,
+            })
+            .collect();
+        builds.sort_by(|a, b| a.field.cmp(&b.field));
+        builds
+    }
+
+    /// 在一个或多个字段上创建唯一索引，之后插入和更新不能产生重复的组合值
+    ///
+    /// 现有记录中已有重复时返回错误，不创建索引。
+    pub fn create_unique_index(&mut self, fields: Vec<String>) -> Result<()> {
+        let name = fields.join(",");
+        self.build_unique_index(fields)?;
+        self.define_index(IndexDef::new(IndexKind::Unique, name, Vec::new()))
+    }
+
+    fn build_unique_index(&mut self, fields: Vec<String>) -> Result<()> {
+        if fields.is_empty() {
+            return Err(DatabaseError::InvalidQuery("唯一索引至少需要一个字段".to_string()));
+        }
+        let mut index = UniqueIndex::new(fields);
+        for record in self.records.values() {
+            let record = self.expanded_ref(record);
+            if let Some(owner) = index.conflict(&record) {
+                return Err(unique_violation(&index, owner));
+            }
+            index.insert(&record);
+        }
+        self.unique_indexes.insert(index.name(), index);
+        Ok(())
+    }
+
+    /// 删除字段上的等值索引和地理位置索引，或该名称的唯一索引，连同元数据中的定义；返回索引是否存在
+    pub fn drop_index(&mut self, field: &str) -> Result<bool> {
+        let hash = self.indexes.remove(field).is_some();
+        let geo = self.geo_indexes.remove(field).is_some();
+        let unique = self.unique_indexes.remove(field).is_some();
+        if self.meta.indexes.iter().any(|d| d.field == field) {
+            self.meta.indexes.retain(|d| d.field != field);
+            self.meta.save(&self.meta_path())?;
+            self.is_dirty = true;
+        }
+        Ok(hash || geo || unique)
+    }
+
+    /// 已建立等值索引的字段
+    pub fn index_fields(&self) -> Vec<String> {
+        self.indexes.keys().cloned().collect()
+    }
+
+    /// 所有索引的定义，按字段名排序
+    pub fn indexes(&self) -> Vec<IndexInfo> {
+        let hash = self.indexes.iter().map(|(field, index)| IndexInfo {
+            field: field.clone(),
+            kind: IndexKind::Hash,
+            distinct_keys: Some(index.cardinality()),
+            multikey: index.is_multikey(),
+            filter: index.filter().to_vec(),
+        });
+        let geo = self.geo_indexes.keys().map(|field| IndexInfo {
+            field: field.clone(),
+            kind: IndexKind::Geo,
+            distinct_keys: None,
+            multikey: false,
+            filter: Vec::new(),
+        });
+        let unique = self.unique_indexes.iter().map(|(name, index)| IndexInfo {
+            field: name.clone(),
+            kind: IndexKind::Unique,
+            distinct_keys: Some(index.len()),
+            multikey: false,
+            filter: Vec::new(),
+        });
+        let mut indexes: Vec<IndexInfo> = hash.chain(geo).chain(unique).collect();
+        indexes.sort_by(|a, b| a.field.cmp(&b.field));
+        indexes
+    }
+
+    /// 取最多`sample_size`条记录推断字段类型与覆盖率
+    pub fn sample_schema(&self, sample_size: usize) -> SchemaSample {
+        let records: Vec<Cow<Record>> = self.records.values().take(sample_size).map(|r| self.expanded_ref(r)).collect();
+        SchemaSample::infer(
+            records.iter().map(|r| &**r),
+            self.records.len(),
+        )
+    }
+
+    /// 为查询选择执行计划
+    ///
+    /// 每个可用的等值索引都能精确给出候选记录数，选择候选最少的一个；通过索引逐条取记录比顺序扫描慢，
+    /// 候选数乘以`INDEX_FETCH_COST`不少于记录总数时改为全表扫描。`query.hint`可以覆盖自动选择。
+    pub fn plan(&self, query: &Query) -> QueryPlan {
+        let candidates = query.conditions.iter().filter(|c| c.op == Operator::Eq).filter_map(|c| {
+            let index = self.indexes.get(&c.field).filter(|index| index.usable_for(&query.conditions))?;
+            let hits = index.lookup(&c.value).map_or(0, |ids| ids.len());
+            Some((c.field.clone(), hits))
+        });
+        let best = match &query.hint {
+            IndexHint::Auto => candidates
+                .min_by_key(|(_, hits)| *hits)
+                .filter(|(_, hits)| (*hits as f64) * INDEX_FETCH_COST < self.records.len() as f64),
+            IndexHint::Use(field) => candidates.filter(|(f, _)| f == field).min_by_key(|(_, hits)| *hits),
+            IndexHint::Scan => None,
+        };
+
+        let (index, estimated_scanned) = match best {
+            Some((field, hits)) => (Some(field), hits),
+            None => (None, self.records.len()),
+        };
+        QueryPlan {
+            index,
+            estimated_scanned,
+            estimated_rows: self
+                .stats
+                .as_ref()
+                .map(|stats| stats.estimate_rows(&query.conditions, self.records.len())),
+            total_records: self.records.len(),
+            sort: query.sort_strategy(),
+        }
+    }
+
+    /// 执行查询：过滤、排序并分页，启用缓存时优先返回缓存结果
+    pub fn query(&self, query: &Query) -> Vec<Arc<Record>> {
+        self.counters.read();
+        let cache = match &self.query_cache {
+            Some(cache) => cache,
+            None => return self.execute(query),
+        };
+
+        let key = QueryCache::key(query);
+        if let Ok(mut cache) = cache.lock() {
+            if let Some(ids) = cache.get(&key) {
+                return ids.iter().filter_map(|id| self.records.get(id)).map(|r| self.expanded(r)).collect();
+            }
+        }
+
+        let records = self.execute(query);
+        if let Ok(mut cache) = cache.lock() {
+            cache.put(key, records.iter().map(|r| r.id.clone()).collect());
+        }
+        records
+    }
+
+    /// 执行查询，同时返回分页前满足条件的记录数；只扫描一次，不使用查询缓存
+    pub fn query_with_total(&self, query: &Query) -> (Vec<Arc<Record>>, usize) {
+        self.counters.read();
+        let matched = self.matching(query);
+        let total = matched.len();
+        (query.finish_with(matched, self.collation), total)
+    }
+
+    /// 按执行计划执行查询
+    fn execute(&self, query: &Query) -> Vec<Arc<Record>> {
+        query.finish_with(self.matching(query), self.collation)
+    }
+
+    /// 按执行计划找出满足条件的记录，未排序、未分页
+    fn matching(&self, query: &Query) -> Vec<Arc<Record>> {
+        let plan = self.plan(query);
+        let indexed = plan.index.as_ref().and_then(|field| {
+            let condition = query
+                .conditions
+                .iter()
+                .find(|c| &c.field == field && c.op == Operator::Eq)?;
+            Some(self.indexes.get(field)?.lookup(&condition.value))
+        });
+
+        match indexed {
+            Some(_) => self.counters.index_hit(),
+            None if !query.conditions.is_empty() => self.counters.index_miss(),
+            None => {}
+        }
+        match indexed {
+            Some(Some(ids)) => ids
+                .iter()
+                .filter_map(|id| self.records.get(id))
+                .map(|r| self.expanded(r))
+                .filter(|r| query.matches_with(r, self.collation))
+                .collect(),
+            Some(None) => Vec::new(),
+            None => match &self.stats {
+                // 有统计信息时先检查选择率最低的条件，使不匹配的记录尽早被排除
+                Some(stats) if query.conditions.len() > 1 => {
+                    let mut ordered = query.clone();
+                    ordered
+                        .conditions
+                        .sort_by(|a, b| stats.selectivity(a).total_cmp(&stats.selectivity(b)));
+                    self.scan(&ordered)
+                }
+                _ => self.scan(query),
+            },
+        }
+    }
+
+    /// 当前记录的快照，编码后即为保存时写入的各个文件；只复制记录指针，很快
+    ///
+    /// 时间序列表的表文件为空，记录按段分到各个段文件中；其他表超出`max_file_size`时按`parts`拆分，
+    /// 拆分的部分文件总是第1代，与快照中的部分清单一致。
+    pub(crate) fn snapshot(&self) -> FileSnapshot {
+        let mut parts = None;
+        let files = match &self.meta.time_series {
+            Some(series) => {
+                let mut files = vec![(self.file_name(), Records::new())];
+                for bucket in self.timeline.buckets(series) {
+                    files.push((timeseries::segment_file_name(&self.name, bucket), self.segment_records(series, bucket)));
+                }
+                files
+            }
+            None => {
+                let mut split = self.parts();
+                if split.len() == 1 {
+                    vec![(self.file_name(), split.remove(0))]
+                } else {
+                    let set = PartSet { generation: 1, count: split.len() };
+                    parts = Some(set);
+                    std::iter::once((self.file_name(), Records::new()))
+                        .chain(split.into_iter().enumerate().map(|(part, records)| (part_file_name(&self.name, set.generation, part), records)))
+                        .collect()
+                }
+            }
+        };
+        FileSnapshot {
+            files,
+            parts,
+            crypto: self.crypto.clone(),
+            options: self.options,
+        }
+    }
+
+    /// 按`max_file_size`把记录分成若干部分，每部分编码后大致不超过上限；单条记录超过上限时独占一部分
+    ///
+    /// 按记录序列化后的大小累计，并按整表编码后的实际大小折算压缩和加密的影响。
+    /// 记录按默认顺序分配，较早创建的记录在前面的部分。
+    fn parts(&self) -> Vec<Records> {
+        // 帧头等开销按每条记录64字节估计，压缩只会更小，明显不超过上限时不必编码
+        let estimate = self.bytes + 64 * self.records.len();
+        if estimate <= self.max_file_size {
+            return vec![self.records.clone()];
+        }
+        let encoded = format::encode(&self.records, self.crypto.as_ref(), self.options).map_or(estimate, |content| content.len());
+        if encoded <= self.max_file_size {
+            return vec![self.records.clone()];
+        }
+        let budget = ((self.max_file_size as f64) * (self.bytes as f64) / (encoded as f64)) as usize;
+        let mut records: Vec<&Arc<Record>> = self.records.values().collect();
+        records.sort_by(|a, b| a.default_order(b));
+        let mut parts = vec![Records::new()];
+        let mut used = 0;
+        for record in records {
+            let size = quota::record_size(record);
+            if used > 0 && used + size > budget {
+                parts.push(Records::new());
+                used = 0;
+            }
+            if let Some(part) = parts.last_mut() {
+                part.insert(record.id.clone(), Arc::clone(record));
+            }
+            used += size;
+        }
+        parts
+    }
+
+    /// 当前记录和字符串比较函数的快照，供只读事务查询；只复制记录指针
+    pub(crate) fn view(&self) -> (Records, Comparator) {
+        (self.expanded_records(), self.collation)
+    }
+
+    /// 解压后的全部记录；没有压缩值的记录只复制指针
+    fn expanded_records(&self) -> Records {
+        self.records.iter().map(|(id, record)| (id.clone(), self.expanded(record))).collect()
+    }
+
+    /// 时间序列表中一个段的记录
+    fn segment_records(&self, series: &TimeSeries, bucket: i64) -> Records {
+        self.timeline
+            .range(bucket, bucket.saturating_add(series.bucket))
+            .filter_map(|(_, id)| self.records.get(id).map(|record| (id.to_string(), Arc::clone(record))))
+            .collect()
+    }
+
+    /// 磁盘上属于本表的段文件
+    pub fn segment_files(&self) -> Result<Vec<PathBuf>> {
+        let Some(dir) = self.file_path.parent().filter(|dir| dir.exists()) else {
+            return Ok(Vec::new());
+        };
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            if timeseries::parse_segment_file_name(name).is_some_and(|(table, _)| table == self.name) {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// 部分清单文件的路径，只有拆分的表才有，见`PartSet`
+    pub fn parts_path(&self) -> PathBuf {
+        self.file_path.with_extension(PARTS_EXTENSION)
+    }
+
+    /// 磁盘上的部分清单，表没有拆分时为None
+    fn part_set(&self) -> Result<Option<PartSet>> {
+        statefile::load(&self.parts_path(), PARTS_MAGIC, None)
+    }
+
+    /// 磁盘上本表按`max_file_size`拆分出的文件，包括不属于当前一代的文件，按代和序号排列
+    pub fn part_files(&self) -> Result<Vec<PathBuf>> {
+        let Some(dir) = self.file_path.parent().filter(|dir| dir.exists()) else {
+            return Ok(Vec::new());
+        };
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            if let Some((_, generation, part)) = parse_part_file_name(name).filter(|(table, ..)| *table == self.name) {
+                files.push((generation, part, path));
+            }
+        }
+        files.sort();
+        Ok(files.into_iter().map(|(.., path)| path).collect())
+    }
+
+    /// 保存修改过的段，没有记录的段删除其文件
+    fn save_segments(&mut self) -> Result<()> {
+        let Some(series) = &self.meta.time_series else {
+            return Ok(());
+        };
+        for &bucket in &self.dirty_segments {
+            let name = timeseries::segment_file_name(&self.name, bucket);
+            let path = self.file_path.with_file_name(&name);
+            let records = self.segment_records(series, bucket);
+            if records.is_empty() {
+                if path.exists() {
+                    std::fs::remove_file(&path)?;
+                }
+                if let Some(manifest) = &self.manifest {
+                    manifest.remove(&name)?;
+                }
+                continue;
+            }
+            let content = write_table_file(&path, &records, self.crypto.as_ref(), self.options)?;
+            self.counters.bytes_written(content.len());
+            if let Some(manifest) = &self.manifest {
+                manifest.record(&name, &content)?;
+            }
+        }
+        self.dirty_segments.clear();
+        Ok(())
+    }
+
+    /// 不论是否修改过都重写表文件，时间序列表重写所有段
+    pub fn rewrite(&mut self) -> Result<()> {
+        if let Some(series) = &self.meta.time_series {
+            self.dirty_segments.extend(self.timeline.buckets(series));
+        }
+        self.is_dirty = true;
+        self.save()
+    }
+
+    /// 保存到文件
+    pub fn save(&mut self) -> Result<()> {
+        if !self.is_dirty {
+            return Ok(());
+        }
+
+        // 先持久化修改的变更事件，已保存的修改的事件不会因崩溃丢失
+        if let Some(feed) = &self.changes {
+            feed.sync()?;
+        }
+        self.save_segments()?;
+        match self.meta.time_series {
+            Some(_) => self.write_file(&self.file_name(), &Records::new())?,
+            None => self.save_parts()?,
+        }
+        self.save_plaintext()?;
+        if self.sync_dir {
+            if let Some(dir) = self.file_path.parent() {
+                sync_dir(dir)?;
+            }
+        }
+        self.is_dirty = false;
+        self.unsaved = 0;
+        self.saved_at = Some(SystemTime::now());
+        if self.stats_stale() {
+            self.analyze()?;
+        }
+        Ok(())
+    }
+
+    /// 写出数据目录中的一个表文件并登记到清单
+    fn write_file(&self, name: &str, records: &Records) -> Result<()> {
+        let content = write_table_file(&self.file_path.with_file_name(name), records, self.crypto.as_ref(), self.options)?;
+        self.counters.bytes_written(content.len());
+        if let Some(manifest) = &self.manifest {
+            manifest.record(name, &content)?;
+        }
+        Ok(())
+    }
+
+    /// 按`parts`写出记录：不拆分时写表文件本身；拆分时先写出新一代的全部部分文件，
+    /// 再替换部分清单提交，之后删除其他代的部分文件。任何一步中断时磁盘上仍是完整的旧版本或新版本。
+    fn save_parts(&mut self) -> Result<()> {
+        let mut parts = self.parts();
+        let current = self.part_set()?;
+        let dir = self.file_path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let set = if parts.len() == 1 {
+            self.write_file(&self.file_name(), &parts.remove(0))?;
+            // 删除部分清单即提交，之前表文件中的记录不会被读取
+            if current.is_some() {
+                std::fs::remove_file(self.parts_path())?;
+                sync_dir(&dir)?;
+            }
+            None
+        } else {
+            let set = PartSet {
+                generation: current.map_or(1, |set| set.generation + 1),
+                count: parts.len(),
+            };
+            for (part, records) in parts.iter().enumerate() {
+                self.write_file(&part_file_name(&self.name, set.generation, part), records)?;
+            }
+            sync_dir(&dir)?;
+            statefile::store(&self.parts_path(), PARTS_MAGIC, &set, None)?;
+            // 表文件只用来发现表，记录都在部分文件中
+            self.write_file(&self.file_name(), &Records::new())?;
+            Some(set)
+        };
+        for path in self.part_files()? {
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            let current = parse_part_file_name(name).is_some_and(|(_, generation, _)| set.is_some_and(|set| set.generation == generation));
+            if !current {
+                std::fs::remove_file(&path)?;
+                if let Some(manifest) = &self.manifest {
+                    manifest.remove(name)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 加密的表按`plaintext_fields`写出明文旁路文件；不加密或没有选定字段时删除旧的旁路文件
+    fn save_plaintext(&self) -> Result<()> {
+        let path = self.plaintext_path();
+        if self.crypto.is_none() || self.plaintext_fields.is_empty() {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+        let records = self
+            .records
+            .values()
+            .map(|record| (record.id.clone(), Arc::new(plaintext::project(&self.expanded_ref(record), &self.plaintext_fields))))
+            .collect();
+        write_table_file(&path, &records, None, FileOptions::default())?;
+        Ok(())
+    }
+
+    /// 从文件加载
+    fn load(&mut self) -> Result<()> {
+        let file_time = std::fs::metadata(&self.file_path)?.modified().ok();
+        self.modified_at = file_time;
+        self.saved_at = file_time;
+        let decoded = format::read_salvaging(BufReader::new(File::open(&self.file_path)?), self.crypto.as_ref())?;
+        let version = decoded.version;
+        self.options = decoded.options;
+        self.records = decoded.records;
+        self.damage = decoded.damage;
+        // 拆分的表只读取部分清单指向的这一代文件，其他代是写到一半或尚未删除的文件
+        if let Some(set) = self.part_set()? {
+            self.records.clear();
+            for part in 0..set.count {
+                self.load_file(&self.file_path.with_file_name(part_file_name(&self.name, set.generation, part)))?;
+            }
+        }
+        if let Some(series) = self.meta.time_series.clone() {
+            for path in self.segment_files()? {
+                self.load_file(&path)?;
+            }
+            self.timeline.clear();
+            for record in self.records.values() {
+                if let Some(timestamp) = series.timestamp(&record.data) {
+                    self.timeline.insert(timestamp, &record.id);
+                }
+            }
+        }
+        self.bytes = self.records.values().map(|r| quota::record_size(r)).sum();
+        self.rebuild_expiry();
+        self.insertion = InsertionOrder::rebuild(self.records.values().map(|r| r.as_ref()));
+        self.next_event = self.records.keys().filter_map(|id| id.parse::<u64>().ok()).max().unwrap_or(0) + 1;
+        // 旧格式的文件在下次保存时以当前格式重写
+        self.is_dirty = version < format::CURRENT_VERSION;
+        // 统计信息只用于估计，无法读取时当作没有
+        self.stats = std::fs::read(self.stats_path()).ok().and_then(|content| {
+            let content = match &self.crypto {
+                Some(crypto) => crypto.decrypt(&content).ok()?,
+                None => content,
+            };
+            bincode::deserialize(&content).ok()
+        });
+        Ok(())
+    }
+
+    /// 把段文件或拆分出的文件中的记录并入表中，损坏部分标注所在的文件
+    fn load_file(&mut self, path: &Path) -> Result<()> {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string();
+        let decoded = format::read_salvaging(BufReader::new(File::open(path)?), self.crypto.as_ref())?;
+        self.records.extend(decoded.records);
+        self.damage.extend(decoded.damage.into_iter().map(|damage| Damage {
+            reason: format!("{}: {}", name, damage.reason),
+            ..damage
+        }));
+        Ok(())
+    }
+
+    /// 加载表文件时跳过的损坏部分，为空表示文件完好
+    ///
+    /// 表文件不会因此被自动重写；下次保存时写出的文件只包含读出的记录，损坏部分随之丢弃。
+    pub fn damage(&self) -> &[Damage] {
+        &self.damage
+    }
+
+    /// 获取记录数量
+    pub fn count(&self) -> usize {
+        self.records.len()
+    }
+}
+
+/// 后台创建中的索引
+#[derive(Debug)]
+struct IndexBuild {
+    /// 快照中的记录数
+    total: usize,
+    processed: Arc<AtomicUsize>,
+    /// 快照之后被写入的记录ID
+    changed: HashSet<String>,
+}
+
+/// 事件的ID：补零到20位的序号，能容纳任意u64
+fn event_id(sequence: u64) -> String {
+    format!("{:020}", sequence)
+}
+
+/// 违反唯一约束的错误
+fn unique_violation(index: &UniqueIndex, owner: &str) -> DatabaseError {
+    DatabaseError::DuplicateKey(format!("唯一索引 ({}) 上的值已被记录 {} 使用", index.name(), owner))
+}
+
+/// 时间序列表的记录缺少时间戳的错误
+fn missing_timestamp(table: &str, field: &str) -> DatabaseError {
+    DatabaseError::InvalidQuery(format!("时间序列表 {} 的记录必须有整数字段 {}", table, field))
+}
+
+/// 读取表文件中的记录，`crypto`为None表示文件未加密；旧版本的文件格式会自动迁移
+pub fn read_table_file(path: &Path, crypto: Option<&Crypto>) -> Result<HashMap<String, Arc<Record>>> {
+    Ok(format::read(BufReader::new(File::open(path)?), crypto)?.0)
+}
+
+/// 以当前格式把记录写入表文件，`crypto`不为None时加密；返回写入的文件内容
+///
+/// 先写入同目录下的`<文件名>.tmp`并fsync，再改名覆盖原文件：写入中途崩溃时原文件保持完整，
+/// 留下的临时文件由`SimpleDB::repair`清理。改名本身要在断电后也保留，还需要`sync_dir`。
+pub fn write_table_file(
+    path: &Path,
+    records: &HashMap<String, Arc<Record>>,
+    crypto: Option<&Crypto>,
+    options: FileOptions,
+) -> Result<Vec<u8>> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let content = format::encode(records, crypto, options)?;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let file = OpenOptions::new().create(true).write(true).truncate(true).open(&tmp)?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(&content)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+    std::fs::rename(&tmp, path)?;
+    Ok(content)
+}
+
+/// 部分清单文件的扩展名
+pub const PARTS_EXTENSION: &str = "parts";
+
+/// 部分清单文件开头的魔数
+pub const PARTS_MAGIC: &[u8; 8] = b"SDBPART1";
+
+/// 按`max_file_size`拆分的表当前使用的一代部分文件，保存在`<表名>.parts`中
+///
+/// 每次保存拆分的表都写出新一代的部分文件，全部落盘后持久地替换部分清单，替换即提交。
+/// 拆分的表的表文件（`<表名>.db`）不含记录，只用来发现表。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartSet {
+    pub generation: u64,
+    /// 部分文件的个数，序号从0开始
+    pub count: usize,
+}
+
+/// 表在某一时刻要写出的文件，见`Table::snapshot`
+pub(crate) struct FileSnapshot {
+    /// 文件名和其中的记录
+    pub files: Vec<(String, Records)>,
+    /// 拆分时的部分清单
+    pub parts: Option<PartSet>,
+    pub crypto: Option<Crypto>,
+    pub options: FileOptions,
+}
+
+/// 表按`max_file_size`拆分后第`generation`代第`part`部分的文件名：`<表名>.<代>.<序号>.db`
+///
+/// 加载时同一代的所有部分合并为一张表；拆分只适用于普通表，时间序列表的记录按段分到段文件中。
+pub fn part_file_name(table: &str, generation: u64, part: usize) -> String {
+    format!("{}.{}.{}.db", table, generation, part)
+}
+
+/// 从`<表名>.<代>.<序号>.db`解析出表名、代和序号，不符合这一格式时为None
+///
+/// 符合格式的文件只有在所属的表有部分清单时才是拆分出的文件，见`is_part_file`。
+pub fn parse_part_file_name(name: &str) -> Option<(&str, u64, usize)> {
+    let (rest, part) = name.strip_suffix(".db")?.rsplit_once('.')?;
+    let (table, generation) = rest.rsplit_once('.')?;
+    let numeric = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if table.is_empty() || !numeric(generation) || !numeric(part) {
+        return None;
+    }
+    Some((table, generation.parse().ok()?, part.parse().ok()?))
+}
+
+/// `dir`中名为`name`的文件是否是某张拆分的表的部分文件（不论是否属于当前一代）
+///
+/// 没有部分清单的表不会有部分文件，因此旧版本中名为`data.2024.db`等的表不会被误认。
+pub fn is_part_file(dir: &Path, name: &str) -> bool {
+    parse_part_file_name(name).is_some_and(|(table, ..)| dir.join(format!("{}.{}", table, PARTS_EXTENSION)).exists())
+}
+
+/// fsync目录，使其中新建、改名的目录项在断电后也能保留；只在Unix上有效，其他平台直接返回
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    if cfg!(unix) {
+        File::open(dir)?.sync_all()?;
     }
+    Ok(())
 }
 
 impl Drop for Table {
@@ -0,0 +1,484 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::crypto::Crypto;
+use crate::database::SimpleDB;
+use crate::error::{DatabaseError, Result};
+use crate::statefile;
+use crate::storage::Record;
+
+/// 同步状态文件名
+pub const SYNC_FILE: &str = "SYNC";
+
+/// 同步状态文件开头的魔数
+pub const SYNC_MAGIC: &[u8; 8] = b"SDBSYNC1";
+
+/// 同步请求的连接和读写超时
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 记录的版本向量：节点ID → 该节点修改这条记录的次数
+pub type VersionVector = BTreeMap<String, u64>;
+
+/// 版本a相对于版本b的先后关系
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Equal,
+    Before,
+    After,
+    /// 两边各自修改过，互不包含
+    Concurrent,
+}
+
+pub fn compare(a: &VersionVector, b: &VersionVector) -> Causality {
+    let nodes: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+    let (mut before, mut after) = (false, false);
+    for node in nodes {
+        let (x, y) = (a.get(node).copied().unwrap_or(0), b.get(node).copied().unwrap_or(0));
+        before |= x < y;
+        after |= x > y;
+    }
+    match (before, after) {
+        (false, false) => Causality::Equal,
+        (true, false) => Causality::Before,
+        (false, true) => Causality::After,
+        (true, true) => Causality::Concurrent,
+    }
+}
+
+/// 合并两个版本向量：每个节点取较大的计数
+fn join(a: &mut VersionVector, b: &VersionVector) {
+    for (node, count) in b {
+        let entry = a.entry(node.clone()).or_default();
+        *entry = (*entry).max(*count);
+    }
+}
+
+/// 一条记录的版本
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Digest {
+    pub table: String,
+    pub id: String,
+    pub vector: VersionVector,
+}
+
+/// 同步时交换的一条记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncEntry {
+    pub table: String,
+    pub id: String,
+    pub vector: VersionVector,
+    /// None表示记录已被删除
+    pub record: Option<Record>,
+    /// 最后修改或删除的时间（Unix秒）
+    pub time: u64,
+}
+
+/// 服务器对同步请求的答复：服务器上所有记录的版本，以及客户端没有或不是最新的记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncOffer {
+    pub node: String,
+    pub versions: Vec<Digest>,
+    pub entries: Vec<SyncEntry>,
+}
+
+/// 两边并发修改了同一条记录
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub table: String,
+    pub id: String,
+    /// 本地的版本，None表示已在本地删除
+    pub local: Option<Record>,
+    pub remote: Option<Record>,
+    /// 两边最后修改或删除的时间（Unix秒）
+    pub local_time: u64,
+    pub remote_time: u64,
+}
+
+impl Conflict {
+    /// 后写者胜出：保留修改或删除时间较晚的一边，时间相同时保留本地版本
+    pub fn last_writer_wins(&self) -> Option<Record> {
+        if self.remote_time > self.local_time {
+            self.remote.clone()
+        } else {
+            self.local.clone()
+        }
+    }
+}
+
+/// 同步结果统计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// 从远端取回并写入本地的记录数（含删除）
+    pub pulled: usize,
+    /// 发送给远端的记录数（含删除）
+    pub pushed: usize,
+    /// 两边并发修改、经过合并处理的记录数
+    pub conflicts: usize,
+}
+
+/// 本节点所知的一条记录的版本
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Tracked {
+    vector: VersionVector,
+    /// 记下版本时记录的ETag，None表示已删除
+    etag: Option<String>,
+    time: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Persisted {
+    node: String,
+    records: BTreeMap<String, BTreeMap<String, Tracked>>,
+}
+
+/// 本节点的同步状态：节点ID和每条记录的版本向量，保存在数据目录的`SYNC`文件中
+///
+/// 版本在同步时才更新：记录的ETag与上次不同即视为本节点修改过一次，
+/// 因此两次同步之间的多次修改只算一个版本。事件表的ID是各节点本地的序号，不参与同步。
+#[derive(Debug)]
+pub(crate) struct SyncState {
+    path: PathBuf,
+    crypto: Option<Crypto>,
+    node: String,
+    records: BTreeMap<String, BTreeMap<String, Tracked>>,
+}
+
+impl SyncState {
+    /// 读取数据目录中的同步状态，没有时生成新的节点ID
+    pub(crate) fn open(data_dir: &Path, crypto: Option<Crypto>) -> Result<Self> {
+        let path = data_dir.join(SYNC_FILE);
+        let persisted = statefile::load(&path, SYNC_MAGIC, crypto.as_ref())?.unwrap_or_else(|| Persisted {
+            node: uuid::Uuid::new_v4().to_string(),
+            records: BTreeMap::new(),
+        });
+        Ok(Self {
+            path,
+            crypto,
+            node: persisted.node,
+            records: persisted.records,
+        })
+    }
+
+    pub(crate) fn node(&self) -> &str {
+        &self.node
+    }
+
+    fn save(&self) -> Result<()> {
+        let persisted = Persisted {
+            node: self.node.clone(),
+            records: self.records.clone(),
+        };
+        // 状态中有表名和记录ID，与统计信息一样随表数据加密
+        statefile::store(&self.path, SYNC_MAGIC, &persisted, self.crypto.as_ref())
+    }
+
+    /// 对照当前的记录更新版本：新增或修改过的记录、被删除的记录各记为本节点的一次修改
+    fn refresh(&mut self, db: &SimpleDB) -> Result<()> {
+        let now = now();
+        let tables = db.sync_tables();
+        for name in &tables {
+            let tracked = self.records.entry(name.clone()).or_default();
+            let mut present = BTreeSet::new();
+            for record in db.find_all(name)? {
+                let etag = record.etag();
+                let entry = tracked.entry(record.id.clone()).or_default();
+                if entry.etag.as_ref() != Some(&etag) {
+                    *entry.vector.entry(self.node.clone()).or_default() += 1;
+                    entry.etag = Some(etag);
+                    entry.time = record.updated_at;
+                }
+                present.insert(record.id.clone());
+            }
+            for (id, entry) in tracked.iter_mut() {
+                if entry.etag.is_some() && !present.contains(id) {
+                    *entry.vector.entry(self.node.clone()).or_default() += 1;
+                    entry.etag = None;
+                    entry.time = now;
+                }
+            }
+        }
+        // 整张表被删除时其中的记录都算删除
+        let existing = db.list_tables();
+        for (_, tracked) in self.records.iter_mut().filter(|(name, _)| !existing.contains(name)) {
+            for entry in tracked.values_mut().filter(|entry| entry.etag.is_some()) {
+                *entry.vector.entry(self.node.clone()).or_default() += 1;
+                entry.etag = None;
+                entry.time = now;
+            }
+        }
+        Ok(())
+    }
+
+    fn digests(&self) -> Vec<Digest> {
+        self.records
+            .iter()
+            .flat_map(|(table, tracked)| {
+                tracked.iter().map(|(id, entry)| Digest {
+                    table: table.clone(),
+                    id: id.clone(),
+                    vector: entry.vector.clone(),
+                })
+            })
+            .collect()
+    }
+
+    fn entry(&self, db: &SimpleDB, table: &str, id: &str, tracked: &Tracked) -> SyncEntry {
+        let record = match tracked.etag {
+            Some(_) => db.find_by_id(table, id).ok().flatten().map(|r| (*r).clone()),
+            None => None,
+        };
+        SyncEntry {
+            table: table.to_string(),
+            id: id.to_string(),
+            vector: tracked.vector.clone(),
+            record,
+            time: tracked.time,
+        }
+    }
+
+    /// 本节点上版本不早于`versions`的记录（对方没有、比对方新或与对方并发）
+    fn newer_than(&self, db: &SimpleDB, versions: &[Digest]) -> Vec<SyncEntry> {
+        let known: HashMap<(&str, &str), &VersionVector> =
+            versions.iter().map(|d| ((d.table.as_str(), d.id.as_str()), &d.vector)).collect();
+        let mut entries = Vec::new();
+        for (table, tracked) in &self.records {
+            for (id, entry) in tracked {
+                let newer = match known.get(&(table.as_str(), id.as_str())) {
+                    Some(vector) => matches!(compare(&entry.vector, vector), Causality::After | Causality::Concurrent),
+                    None => true,
+                };
+                if newer {
+                    entries.push(self.entry(db, table, id, entry));
+                }
+            }
+        }
+        entries
+    }
+
+    /// 服务器端：更新版本后返回客户端需要的记录
+    pub(crate) fn offer(&mut self, db: &SimpleDB, versions: &[Digest]) -> Result<SyncOffer> {
+        self.refresh(db)?;
+        self.save()?;
+        Ok(SyncOffer {
+            node: self.node.clone(),
+            versions: self.digests(),
+            entries: self.newer_than(db, versions),
+        })
+    }
+
+    /// 写入对方发来的记录，返回(写入的记录数, 冲突数)
+    ///
+    /// 对方的版本较新时直接写入；并发修改时由`resolve`决定结果，版本取两边的合并再记一次本节点的修改。
+    pub(crate) fn accept<F>(&mut self, db: &SimpleDB, entries: Vec<SyncEntry>, mut resolve: F) -> Result<(usize, usize)>
+    where
+        F: FnMut(&Conflict) -> Option<Record>,
+    {
+        // 本地同名的事件表不接收同步的记录
+        let tables = db.sync_tables();
+        let event_tables: Vec<String> = db.list_tables().into_iter().filter(|t| !tables.contains(t)).collect();
+        let (mut applied, mut conflicts) = (0, 0);
+        for entry in entries {
+            if event_tables.contains(&entry.table) {
+                continue;
+            }
+            let tracked = self.records.entry(entry.table.clone()).or_default().entry(entry.id.clone()).or_default();
+            let (record, vector, time) = match compare(&tracked.vector, &entry.vector) {
+                Causality::Equal | Causality::After => continue,
+                Causality::Before => (entry.record, entry.vector, entry.time),
+                Causality::Concurrent => {
+                    let local = match tracked.etag {
+                        Some(_) => db.find_by_id(&entry.table, &entry.id).ok().flatten().map(|r| (*r).clone()),
+                        None => None,
+                    };
+                    let conflict = Conflict {
+                        table: entry.table.clone(),
+                        id: entry.id.clone(),
+                        local,
+                        remote: entry.record,
+                        local_time: tracked.time,
+                        remote_time: entry.time,
+                    };
+                    let mut record = resolve(&conflict);
+                    if let Some(record) = &mut record {
+                        record.id = entry.id.clone();
+                    }
+                    let mut vector = tracked.vector.clone();
+                    join(&mut vector, &entry.vector);
+                    *vector.entry(self.node.clone()).or_default() += 1;
+                    let time = record.as_ref().map_or_else(now, |r| r.updated_at);
+                    conflicts += 1;
+                    (record, vector, time)
+                }
+            };
+            tracked.etag = record.as_ref().map(Record::etag);
+            tracked.vector = vector;
+            tracked.time = time;
+            db.apply_synced(&entry.table, &entry.id, record)?;
+            applied += 1;
+        }
+        self.save()?;
+        Ok((applied, conflicts))
+    }
+
+    /// 客户端：经`send(路径, 请求体)`与服务器交换记录
+    pub(crate) fn sync<S, F>(&mut self, db: &SimpleDB, mut send: S, resolve: F) -> Result<SyncReport>
+    where
+        S: FnMut(&str, serde_json::Value) -> Result<serde_json::Value>,
+        F: FnMut(&Conflict) -> Option<Record>,
+    {
+        self.refresh(db)?;
+        let offer = send("/api/sync", serde_json::json!({"node": self.node, "versions": self.digests()}))?;
+        let offer: SyncOffer = serde_json::from_value(offer).map_err(|e| sync_error(format!("无效的答复: {}", e)))?;
+        if offer.node == self.node {
+            return Err(sync_error("不能与自己同步".to_string()));
+        }
+        let (pulled, conflicts) = self.accept(db, offer.entries, resolve)?;
+
+        let entries = self.newer_than(db, &offer.versions);
+        let pushed = entries.len();
+        if pushed > 0 {
+            send("/api/sync/push", serde_json::json!({"node": self.node, "entries": entries}))?;
+        }
+        Ok(SyncReport { pulled, pushed, conflicts })
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn sync_error(message: String) -> DatabaseError {
+    DatabaseError::Sync(message)
+}
+
+/// 向`http://主机:端口[/前缀]`形式的服务器地址发送JSON请求，返回响应中的`data`
+///
+/// 服务器每个连接只处理一个请求，响应读到连接关闭为止。
+pub(crate) fn post(base_url: &str, path: &str, body: &serde_json::Value) -> Result<serde_json::Value> {
+    post_with(base_url, path, body, None, TIMEOUT)
+}
+
+/// 同`post`，附带`Authorization: Bearer <令牌>`请求头，连接和读写以`timeout`为限
+pub(crate) fn post_with(
+    base_url: &str,
+    path: &str,
+    body: &serde_json::Value,
+    token: Option<&str>,
+    timeout: Duration,
+) -> Result<serde_json::Value> {
+    let rest = base_url
+        .strip_prefix("http://")
+        .ok_or_else(|| sync_error(format!("只支持http://地址: {}", base_url)))?;
+    let (authority, prefix) = match rest.split_once('/') {
+        Some((authority, prefix)) => (authority, format!("/{}", prefix.trim_end_matches('/'))),
+        None => (rest, String::new()),
+    };
+    let address = if authority.contains(':') { authority.to_string() } else { format!("{}:80", authority) };
+
+    let socket_address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| sync_error(format!("无法解析地址: {}", address)))?;
+    let mut stream = TcpStream::connect_timeout(&socket_address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let body = serde_json::to_vec(body).map_err(|e| sync_error(e.to_string()))?;
+    let authorization = token.map(|token| format!("Authorization: Bearer {}\r\n", token)).unwrap_or_default();
+    let head = format!(
+        "POST {}{} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        prefix,
+        path,
+        authority,
+        body.len(),
+        authorization
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&body)?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| sync_error("响应不完整".to_string()))?;
+    let status = head.split_whitespace().nth(1).unwrap_or_default();
+    let mut reply: serde_json::Value = serde_json::from_str(body)
+        .map_err(|_| sync_error(format!("服务器返回 {}: {}", status, body.trim())))?;
+    if reply["success"] != true {
+        return Err(sync_error(reply["error"].as_str().unwrap_or(status).to_string()));
+    }
+    Ok(reply["data"].take())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::IndexMap;
+    use crate::storage::Value;
+    use crate::testing::open;
+
+    /// 不经过网络，直接调用服务器端的两个步骤，请求和答复都经过JSON往返
+    fn sync<F>(client: &SimpleDB, server: &SimpleDB, resolve: F) -> SyncReport
+    where
+        F: FnMut(&Conflict) -> Option<Record>,
+    {
+        let mut state = SyncState::open(Path::new(&client.config().data_dir), None).unwrap();
+        let send = |path: &str, body: serde_json::Value| -> Result<serde_json::Value> {
+            match path {
+                "/api/sync" => {
+                    let versions: Vec<Digest> = serde_json::from_value(body["versions"].clone()).unwrap();
+                    Ok(serde_json::json!(server.sync_offer(&versions)?))
+                }
+                _ => {
+                    let entries: Vec<SyncEntry> = serde_json::from_value(body["entries"].clone()).unwrap();
+                    Ok(serde_json::json!({"applied": server.sync_accept(entries)?}))
+                }
+            }
+        };
+        state.sync(client, send, resolve).unwrap()
+    }
+
+    #[test]
+    fn test_two_way_sync() {
+        let (_laptop_dir, laptop) = open("laptop");
+        let (_server_dir, server) = open("server");
+        let note = |text: &str| IndexMap::from([("text".to_string(), Value::String(text.to_string()))]);
+        let text = |db: &SimpleDB, id: &str| db.find_by_id("notes", id).unwrap().map(|r| r.data["text"].clone());
+
+        let a = laptop.insert("notes", note("a")).unwrap();
+        let b = server.insert("notes", note("b")).unwrap();
+        let report = sync(&laptop, &server, Conflict::last_writer_wins);
+        assert_eq!((report.pulled, report.pushed, report.conflicts), (1, 1, 0));
+        assert_eq!(text(&server, &a), Some(Value::String("a".to_string())));
+        assert_eq!(text(&laptop, &b), Some(Value::String("b".to_string())));
+
+        // 已同步的记录不再传输
+        assert_eq!(sync(&laptop, &server, Conflict::last_writer_wins), SyncReport::default());
+
+        // 离线期间一边删除、两边并发修改同一条记录
+        laptop.delete("notes", &b).unwrap();
+        laptop.update("notes", &a, note("laptop")).unwrap();
+        server.update("notes", &a, note("server")).unwrap();
+        let merge = |conflict: &Conflict| {
+            let join = |r: &Option<Record>| r.as_ref().and_then(|r| r.data["text"].as_string().map(str::to_string));
+            let mut record = conflict.local.clone()?;
+            let merged = format!("{}+{}", join(&conflict.local)?, join(&conflict.remote)?);
+            record.data.insert("text".to_string(), Value::String(merged));
+            Some(record)
+        };
+        let report = sync(&laptop, &server, merge);
+        assert_eq!(report.conflicts, 1);
+        assert_eq!(text(&server, &b), None);
+        assert_eq!(text(&laptop, &a), Some(Value::String("laptop+server".to_string())));
+        assert_eq!(text(&server, &a), text(&laptop, &a));
+
+        drop((laptop, server));
+    }
+}
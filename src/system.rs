@@ -0,0 +1,84 @@
+//! 系统字段：记录的`id`、`created_at`和`updated_at`
+//!
+//! 系统字段由数据库维护，保存在`Record`本身而不是`Record::data`中。查询条件、排序、游标分页和SQL
+//! 可以像普通字段一样使用它们：`id`为字符串，`created_at`和`updated_at`为Unix秒整数。
+//! 用户数据不能使用这些字段名，否则写入时返回`DataFormat`错误。
+
+use indexmap::IndexMap;
+use std::borrow::Cow;
+
+use crate::error::{DatabaseError, Result};
+use crate::storage::{Record, Value};
+
+/// 记录ID
+pub const ID: &str = "id";
+/// 创建时间（Unix秒）
+pub const CREATED_AT: &str = "created_at";
+/// 最后修改时间（Unix秒）
+pub const UPDATED_AT: &str = "updated_at";
+/// 全部系统字段
+pub const SYSTEM_FIELDS: [&str; 3] = [ID, CREATED_AT, UPDATED_AT];
+
+/// 是否为系统字段
+pub fn is_system_field(field: &str) -> bool {
+    SYSTEM_FIELDS.contains(&field)
+}
+
+/// 记录中字段的值，系统字段按其类型给出，其余字段取自记录的数据
+pub fn field<'a>(record: &'a Record, field: &str) -> Option<Cow<'a, Value>> {
+    match field {
+        ID => Some(Cow::Owned(Value::String(record.id.clone()))),
+        CREATED_AT => Some(Cow::Owned(Value::Int(record.created_at as i64))),
+        UPDATED_AT => Some(Cow::Owned(Value::Int(record.updated_at as i64))),
+        _ => record.data.get(field).map(Cow::Borrowed),
+    }
+}
+
+/// 检查写入的数据没有使用系统字段名
+pub(crate) fn check_data(data: &IndexMap<String, Value>) -> Result<()> {
+    match data.keys().find(|field| is_system_field(field)) {
+        Some(field) => Err(DatabaseError::DataFormat(format!("{} 是系统字段，不能出现在记录的数据中", field))),
+        None => Ok(()),
+    }
+}
+
+/// 检查索引的字段不是系统字段；系统字段不在记录的数据中，不能建立索引
+pub(crate) fn check_indexable(field: &str) -> Result<()> {
+    match is_system_field(field) {
+        true => Err(DatabaseError::InvalidQuery(format!("不能在系统字段 {} 上创建索引", field))),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::open;
+    use crate::query::{Condition, Query, SortOrder};
+
+    #[test]
+    fn test_system_fields() {
+        let (_dir, db) = open("system");
+        let name = |n: &str| IndexMap::from([("name".to_string(), Value::String(n.to_string()))]);
+        let alice = db.insert("users", name("alice")).unwrap();
+        let bob = db.insert("users", name("bob")).unwrap();
+
+        let shadowing = IndexMap::from([("created_at".to_string(), Value::Int(0))]);
+        assert!(matches!(db.insert("users", shadowing.clone()), Err(DatabaseError::DataFormat(_))));
+        assert!(matches!(db.update("users", &alice, shadowing), Err(DatabaseError::DataFormat(_))));
+        assert!(db.create_index("users", ID).is_err());
+
+        // 系统字段可以像普通字段一样用于条件和排序
+        let found = db.query("users", &Query::eq(ID, Value::String(bob.clone()))).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].data["name"], Value::String("bob".to_string()));
+        let recent = db.query("users", &Query::new().filter(Condition::gte(CREATED_AT, Value::Int(1)))).unwrap();
+        assert_eq!(recent.len(), 2);
+        let by_id = db.query("users", &Query::new().order_by([(ID, SortOrder::Desc)])).unwrap();
+        assert!(by_id[0].id > by_id[1].id);
+
+        let sql = db.sql(&format!("SELECT name FROM users WHERE id = '{}'", alice)).unwrap();
+        assert_eq!(sql.rows, vec![vec![Value::String("alice".to_string())]]);
+        drop(db);
+    }
+}
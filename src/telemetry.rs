@@ -0,0 +1,404 @@
+use std::cell::Cell;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::{DatabaseError, Result};
+use crate::lock::Recover;
+
+/// 攒够这么多span就立即导出
+const BATCH_SIZE: usize = 512;
+
+/// 后台导出的最长间隔
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 导出请求连接、发送和读取响应各自的超时
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 未导出的span上限，导出跟不上时丢弃新的span，不占用无限的内存
+const MAX_QUEUE: usize = 16 * BATCH_SIZE;
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+thread_local! {
+    /// `scope`设置的同步上下文，不随异步任务在线程间移动
+    static THREAD_CURRENT: Cell<Option<TraceContext>> = const { Cell::new(None) };
+}
+
+/// 追踪上下文：所在的追踪和当前span，在其中开始的span成为它的子span
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+}
+
+impl TraceContext {
+    /// 解析W3C `traceparent`请求头，如`00-<32位十六进制>-<16位十六进制>-01`
+    pub fn parse_traceparent(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let (version, trace_id, span_id, _flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version.len() != 2 || version == "ff" {
+            return None;
+        }
+        let context = Self {
+            trace_id: hex::decode(trace_id).ok()?.try_into().ok()?,
+            span_id: hex::decode(span_id).ok()?.try_into().ok()?,
+        };
+        // 全为0的ID无效
+        (context.trace_id != [0; 16] && context.span_id != [0; 8]).then_some(context)
+    }
+
+    /// W3C `traceparent`格式，供调用方把追踪继续传给下游
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-01", hex::encode(self.trace_id), hex::encode(self.span_id))
+    }
+
+    /// 当前所在的追踪上下文：当前线程上`scope`设置的上下文优先，其次是异步任务的`in_scope`
+    pub fn current() -> Option<Self> {
+        THREAD_CURRENT.get().or_else(|| CURRENT.try_with(|context| *context).ok())
+    }
+
+    /// 在当前线程上以该上下文执行`f`，期间数据库操作的span都成为它的子span
+    ///
+    /// 上下文不会自动传到其他线程；把工作交给其他线程（如`spawn_blocking`）时，先取`current()`再在那里调用`scope`。
+    pub fn scope<T>(self, f: impl FnOnce() -> T) -> T {
+        /// `f`结束或panic时恢复外层的上下文
+        struct Restore(Option<TraceContext>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                THREAD_CURRENT.set(self.0);
+            }
+        }
+        let _restore = Restore(THREAD_CURRENT.replace(Some(self)));
+        f()
+    }
+
+    /// 异步版本的`scope`
+    pub async fn in_scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+}
+
+impl FromStr for TraceContext {
+    type Err = DatabaseError;
+
+    /// 解析W3C `traceparent`，见`parse_traceparent`
+    fn from_str(header: &str) -> Result<Self> {
+        Self::parse_traceparent(header).ok_or_else(|| DatabaseError::InvalidQuery(format!("无效的traceparent: {}", header)))
+    }
+}
+
+/// span的属性值
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+}
+
+/// OTLP中的span类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+}
+
+/// 一次操作的span：开始时由`Tracer::start`创建，结束时交给`Tracer::finish`排队导出
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub context: TraceContext,
+    pub parent_span_id: Option<[u8; 8]>,
+    pub name: String,
+    pub kind: SpanKind,
+    start: SystemTime,
+    end: SystemTime,
+    pub attributes: Vec<(&'static str, AttributeValue)>,
+    /// 操作失败时的错误信息
+    pub error: Option<String>,
+}
+
+impl Span {
+    pub fn set(&mut self, key: &'static str, value: impl Into<AttributeValue>) {
+        self.attributes.push((key, value.into()));
+    }
+
+    /// 持续时间，未结束时为0
+    pub fn duration(&self) -> Duration {
+        self.end.duration_since(self.start).unwrap_or_default()
+    }
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::String(value.to_string())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue::String(value)
+    }
+}
+
+impl From<usize> for AttributeValue {
+    fn from(value: usize) -> Self {
+        AttributeValue::Int(i64::try_from(value).unwrap_or(i64::MAX))
+    }
+}
+
+impl From<u16> for AttributeValue {
+    fn from(value: u16) -> Self {
+        AttributeValue::Int(value.into())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    spans: Vec<Span>,
+    closed: bool,
+}
+
+/// 收集span并以OTLP/HTTP（JSON编码）导出到`Config::otlp_endpoint`
+///
+/// 后台线程每5秒或攒够一批时导出一次，导出失败的批次丢弃，不影响数据库操作；关闭数据库时导出剩余的span。
+#[derive(Debug)]
+pub struct Tracer {
+    shared: Arc<(Mutex<Queue>, Condvar)>,
+    exporter: Mutex<Option<JoinHandle<()>>>,
+    endpoint: Arc<str>,
+    service_name: Arc<str>,
+}
+
+impl Tracer {
+    pub fn new(endpoint: &str, service_name: &str) -> Self {
+        let shared = Arc::new((Mutex::new(Queue::default()), Condvar::new()));
+        let endpoint: Arc<str> = endpoint.trim_end_matches('/').into();
+        let service_name: Arc<str> = service_name.into();
+        let exporter = {
+            let (shared, endpoint, service_name) = (Arc::clone(&shared), Arc::clone(&endpoint), Arc::clone(&service_name));
+            std::thread::Builder::new()
+                .name("simpledb-otlp".to_string())
+                .spawn(move || loop {
+                    let (queue, wake) = &*shared;
+                    let mut state = queue.lock().recover();
+                    if state.spans.len() < BATCH_SIZE && !state.closed {
+                        state = wake.wait_timeout(state, EXPORT_INTERVAL).recover().0;
+                    }
+                    let spans = std::mem::take(&mut state.spans);
+                    let closed = state.closed;
+                    drop(state);
+                    if !spans.is_empty() {
+                        let _ = export(&endpoint, &service_name, &spans);
+                    }
+                    if closed {
+                        return;
+                    }
+                })
+                .ok()
+        };
+        Self {
+            shared,
+            exporter: Mutex::new(exporter),
+            endpoint,
+            service_name,
+        }
+    }
+
+    /// 开始一个span，父span取自当前的追踪上下文，没有时开始新的追踪
+    pub fn start(&self, name: String, kind: SpanKind) -> Span {
+        self.start_with_parent(name, kind, TraceContext::current())
+    }
+
+    /// 开始以`parent`（如请求头中的`traceparent`）为父span的span
+    pub fn start_with_parent(&self, name: String, kind: SpanKind, parent: Option<TraceContext>) -> Span {
+        let now = SystemTime::now();
+        Span {
+            context: TraceContext {
+                trace_id: parent.map_or_else(rand::random, |parent| parent.trace_id),
+                span_id: rand::random(),
+            },
+            parent_span_id: parent.map(|parent| parent.span_id),
+            name,
+            kind,
+            start: now,
+            end: now,
+            attributes: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// 结束span并排队等待导出
+    pub fn finish(&self, mut span: Span) {
+        span.end = SystemTime::now();
+        let (queue, wake) = &*self.shared;
+        let mut state = queue.lock().recover();
+        if state.spans.len() >= MAX_QUEUE {
+            return;
+        }
+        state.spans.push(span);
+        if state.spans.len() >= BATCH_SIZE {
+            wake.notify_one();
+        }
+    }
+
+    /// 立即在当前线程导出排队的span，返回导出的个数
+    pub fn flush(&self) -> Result<usize> {
+        let spans = std::mem::take(&mut self.shared.0.lock().recover().spans);
+        if !spans.is_empty() {
+            export(&self.endpoint, &self.service_name, &spans)?;
+        }
+        Ok(spans.len())
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        let (queue, wake) = &*self.shared;
+        queue.lock().recover().closed = true;
+        wake.notify_one();
+        if let Some(exporter) = self.exporter.lock().recover().take() {
+            let _ = exporter.join();
+        }
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+fn attribute_json(key: &str, value: &AttributeValue) -> serde_json::Value {
+    // OTLP/JSON中64位整数编码为字符串
+    let value = match value {
+        AttributeValue::String(s) => serde_json::json!({"stringValue": s}),
+        AttributeValue::Int(i) => serde_json::json!({"intValue": i.to_string()}),
+    };
+    serde_json::json!({"key": key, "value": value})
+}
+
+/// 按OTLP/JSON编码一批span
+pub fn encode(service_name: &str, spans: &[Span]) -> serde_json::Value {
+    let spans: Vec<serde_json::Value> = spans
+        .iter()
+        .map(|span| {
+            let mut json = serde_json::json!({
+                "traceId": hex::encode(span.context.trace_id),
+                "spanId": hex::encode(span.context.span_id),
+                "name": span.name,
+                "kind": span.kind as u8,
+                "startTimeUnixNano": unix_nanos(span.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": span.attributes.iter().map(|(key, value)| attribute_json(key, value)).collect::<Vec<_>>(),
+                "status": match &span.error {
+                    Some(message) => serde_json::json!({"code": 2, "message": message}),
+                    None => serde_json::json!({"code": 0}),
+                },
+            });
+            if let Some(parent) = span.parent_span_id {
+                json["parentSpanId"] = hex::encode(parent).into();
+            }
+            json
+        })
+        .collect();
+    serde_json::json!({
+        "resourceSpans": [{
+            "resource": {"attributes": [attribute_json("service.name", &AttributeValue::from(service_name))]},
+            "scopeSpans": [{"scope": {"name": "simpledb"}, "spans": spans}],
+        }]
+    })
+}
+
+/// 把span以OTLP/HTTP发送到`http://主机:端口[/前缀]`形式的接收端的`/v1/traces`
+fn export(endpoint: &str, service_name: &str, spans: &[Span]) -> Result<()> {
+    let error = DatabaseError::Telemetry;
+    let rest = endpoint
+        .strip_prefix("http://")
+        .ok_or_else(|| error(format!("只支持http://地址: {}", endpoint)))?;
+    let (authority, prefix) = match rest.split_once('/') {
+        Some((authority, prefix)) => (authority, format!("/{}", prefix)),
+        None => (rest, String::new()),
+    };
+    let address = if authority.contains(':') { authority.to_string() } else { format!("{}:80", authority) };
+
+    let body = serde_json::to_vec(&encode(service_name, spans)).map_err(|e| error(e.to_string()))?;
+    let mut stream = connect(&address).map_err(|e| error(format!("连接 {} 失败: {}", address, e)))?;
+    stream.set_read_timeout(Some(EXPORT_TIMEOUT)).map_err(|e| error(e.to_string()))?;
+    stream.set_write_timeout(Some(EXPORT_TIMEOUT)).map_err(|e| error(e.to_string()))?;
+    let head = format!(
+        "POST {}/v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        prefix,
+        authority,
+        body.len()
+    );
+    let mut response = Vec::new();
+    stream
+        .write_all(head.as_bytes())
+        .and_then(|()| stream.write_all(&body))
+        .and_then(|()| stream.read_to_end(&mut response))
+        .map_err(|e| error(e.to_string()))?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response.split_whitespace().nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        return Err(error(format!("接收端返回 {}", status)));
+    }
+    Ok(())
+}
+
+/// 依次尝试地址解析出的每个地址，每次连接最多等待`EXPORT_TIMEOUT`
+fn connect(address: &str) -> std::io::Result<TcpStream> {
+    let mut last = None;
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, EXPORT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(last.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "地址没有解析出任何结果")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traceparent() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::parse_traceparent(header).unwrap();
+        assert_eq!(context.traceparent(), header);
+        assert!(TraceContext::parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(TraceContext::parse_traceparent("00-4bf92f35-00f067aa0ba902b7-01").is_none());
+
+        // 在上下文中开始的span属于同一追踪，父span为上下文中的span
+        let tracer = Tracer::new("http://127.0.0.1:9", "test");
+        assert!(TraceContext::current().is_none());
+        let span = context.scope(|| tracer.start("insert users".to_string(), SpanKind::Internal));
+        assert_eq!(span.context.trace_id, context.trace_id);
+        assert_eq!(span.parent_span_id, Some(context.span_id));
+        let json = encode("test", &[span]);
+        assert_eq!(json["resourceSpans"][0]["scopeSpans"][0]["spans"][0]["parentSpanId"], "00f067aa0ba902b7");
+
+        // 同步上下文随线程，嵌套的scope结束后恢复外层上下文
+        let inner: TraceContext = "00-4bf92f3577b34da6a3ce929d0e0e4736-1111111111111111-01".parse().unwrap();
+        context.scope(|| {
+            inner.scope(|| assert_eq!(TraceContext::current(), Some(inner)));
+            assert_eq!(TraceContext::current(), Some(context));
+            assert!(std::thread::spawn(TraceContext::current).join().unwrap().is_none());
+        });
+        assert!(TraceContext::current().is_none());
+        assert!("00-bad".parse::<TraceContext>().is_err());
+    }
+
+    #[test]
+    fn test_export_failure() {
+        // 没有监听的端口：连接失败报告为追踪导出错误，而不是配置错误
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let span = Tracer::new("http://127.0.0.1:9", "test").start("op".to_string(), SpanKind::Internal);
+        assert!(matches!(export(&endpoint, "test", &[span]), Err(DatabaseError::Telemetry(_))));
+    }
+}
@@ -0,0 +1,85 @@
+//! 测试共用的临时数据目录：目录由返回的`TempDir`持有，离开作用域时删除，测试失败时也不会遗留
+//!
+//! 以及在随机端口上启动HTTP服务器、发送原始HTTP请求的辅助函数。
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task::JoinHandle;
+
+use crate::api::DatabaseServer;
+use crate::database::SimpleDB;
+use crate::error::Result;
+use crate::Config;
+
+/// 在临时目录中以默认配置打开数据库，`name`只用作目录名前缀
+pub(crate) fn open(name: &str) -> (TempDir, SimpleDB) {
+    open_with(name, Config::default())
+}
+
+/// 同`open`，但使用`config`中`data_dir`以外的设置
+pub(crate) fn open_with(name: &str, config: Config) -> (TempDir, SimpleDB) {
+    let (dir, config) = temp_config(name, config);
+    (dir, SimpleDB::new(config).unwrap())
+}
+
+/// 以临时目录为数据目录的配置，保留`config`中的其他设置；用于需要重新打开或在打开前操作数据目录的测试
+pub(crate) fn temp_config(name: &str, config: Config) -> (TempDir, Config) {
+    let dir = temp_dir(name);
+    let config = Config {
+        data_dir: config_in(&dir).data_dir,
+        ..config
+    };
+    (dir, config)
+}
+
+/// 空的临时目录，用于备份文件等不属于数据目录的文件
+pub(crate) fn temp_dir(name: &str) -> TempDir {
+    tempfile::Builder::new().prefix(&format!("simpledb-{}-", name)).tempdir().unwrap()
+}
+
+/// 以`dir`为数据目录的默认配置
+pub(crate) fn config_in(dir: &TempDir) -> Config {
+    Config {
+        data_dir: dir.path().to_string_lossy().into_owned(),
+        ..Config::default()
+    }
+}
+
+/// 以默认配置重新打开`dir`中的数据库
+pub(crate) fn reopen(dir: &TempDir) -> SimpleDB {
+    SimpleDB::new(config_in(dir)).unwrap()
+}
+
+/// 在随机端口上为`db`启动HTTP服务器，返回监听地址和服务任务；测试结束时`abort`服务任务
+pub(crate) fn serve(db: Arc<SimpleDB>) -> (SocketAddr, JoinHandle<Result<()>>) {
+    serve_with(DatabaseServer::with_shared(db, 0))
+}
+
+/// 同`serve`，但启动已经配置好的服务器，例如加了中间件的服务器
+pub(crate) fn serve_with(server: DatabaseServer) -> (SocketAddr, JoinHandle<Result<()>>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = server.with_listener(listener);
+    (address, tokio::spawn(async move { server.start().await }))
+}
+
+/// 发送一个HTTP请求并读完整个响应
+///
+/// `head`是请求行，后面可以跟以`\r\n`分隔的请求头；`Content-Length`和`Connection: close`自动加上。
+pub(crate) async fn request(address: SocketAddr, head: &str, body: &str) -> String {
+    let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    let request = format!("{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", head, body.len(), body);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    String::from_utf8(response).unwrap()
+}
+
+/// 把响应体解析为JSON
+pub(crate) fn json_body(response: &str) -> serde_json::Value {
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    serde_json::from_str(body).unwrap()
+}
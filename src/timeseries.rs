@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use indexmap::IndexMap;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::error::Result;
+use crate::sql::{Aggregate, OverflowPolicy};
+use crate::storage::{self, Record, Value};
+
+/// 时间序列表的设置，随表的元数据保存
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeSeries {
+    /// 必填的时间戳字段，值为整数，单位由使用者决定（如Unix毫秒）
+    pub field: String,
+    /// 每个段覆盖的时间跨度，与时间戳的单位相同
+    pub bucket: i64,
+}
+
+impl TimeSeries {
+    pub fn new(field: &str, bucket: i64) -> Self {
+        Self {
+            field: field.to_string(),
+            bucket,
+        }
+    }
+
+    /// 记录的时间戳，缺少字段或不是整数时为None
+    pub fn timestamp(&self, data: &IndexMap<String, Value>) -> Option<i64> {
+        data.get(&self.field).and_then(Value::as_int)
+    }
+
+    /// 时间戳所在段的起始时间
+    pub fn bucket_of(&self, timestamp: i64) -> i64 {
+        timestamp.saturating_sub(timestamp.rem_euclid(self.bucket))
+    }
+}
+
+/// 段文件名：`<表名>@<段起始时间>.db`
+pub fn segment_file_name(table: &str, bucket: i64) -> String {
+    format!("{}@{}.db", table, bucket)
+}
+
+/// 从段文件名解析出表名和段起始时间，不是段文件时为None
+pub fn parse_segment_file_name(name: &str) -> Option<(&str, i64)> {
+    let (table, bucket) = name.strip_suffix(".db")?.rsplit_once('@')?;
+    Some((table, bucket.parse().ok()?))
+}
+
+/// 表文件、段文件或按大小拆分出的文件所属的表
+pub fn table_of_file(name: &str) -> &str {
+    match parse_segment_file_name(name).or_else(|| storage::parse_part_file_name(name).map(|(table, ..)| (table, 0))) {
+        Some((table, _)) => table,
+        None => name.trim_end_matches(".db"),
+    }
+}
+
+/// 按时间戳排列的记录ID，只保存在内存中，加载时重建
+#[derive(Debug, Default)]
+pub(crate) struct Timeline {
+    entries: BTreeSet<(i64, String)>,
+}
+
+impl Timeline {
+    pub(crate) fn insert(&mut self, timestamp: i64, id: &str) {
+        self.entries.insert((timestamp, id.to_string()));
+    }
+
+    pub(crate) fn remove(&mut self, timestamp: i64, id: &str) {
+        self.entries.remove(&(timestamp, id.to_string()));
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// 最早的时间戳
+    pub(crate) fn first(&self) -> Option<i64> {
+        self.entries.first().map(|(timestamp, _)| *timestamp)
+    }
+
+    /// 时间戳在`[from, to)`内的记录ID，按时间先后排列
+    pub(crate) fn range(&self, from: i64, to: i64) -> impl Iterator<Item = (i64, &str)> {
+        let start = (from, String::new());
+        self.entries
+            .range(start..)
+            .take_while(move |(timestamp, _)| *timestamp < to)
+            .map(|(timestamp, id)| (*timestamp, id.as_str()))
+    }
+
+    /// 所有记录所在的段
+    pub(crate) fn buckets(&self, series: &TimeSeries) -> BTreeSet<i64> {
+        self.entries.iter().map(|(timestamp, _)| series.bucket_of(*timestamp)).collect()
+    }
+}
+
+/// 按时间先后排列的记录按`interval`分组，对每组记录的`field`求聚合，返回(组的起始时间, 聚合值)
+pub(crate) fn downsample<'a>(
+    points: impl Iterator<Item = (i64, &'a Record)>,
+    interval: i64,
+    field: &str,
+    aggregate: Aggregate,
+    overflow: OverflowPolicy,
+) -> Result<Vec<(i64, Value)>> {
+    let grouping = TimeSeries::new(field, interval);
+    let mut groups: BTreeMap<i64, Vec<Value>> = BTreeMap::new();
+    for (timestamp, record) in points {
+        let value = record.data.get(field).cloned().unwrap_or(Value::Null);
+        groups.entry(grouping.bucket_of(timestamp)).or_default().push(value);
+    }
+    groups
+        .into_iter()
+        .map(|(start, values)| Ok((start, aggregate.apply(values.len(), Some(values), overflow)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segments_and_downsample() {
+        let series = TimeSeries::new("ts", 60);
+        assert_eq!(series.bucket_of(125), 120);
+        assert_eq!(series.bucket_of(-1), -60);
+        assert_eq!(parse_segment_file_name(&segment_file_name("cpu", -60)), Some(("cpu", -60)));
+        assert_eq!(parse_segment_file_name("cpu.db"), None);
+        assert_eq!(parse_segment_file_name("user@example.db"), None);
+        assert_eq!(table_of_file("cpu@120.db"), "cpu");
+
+        let record = |value: i64| Record::new(IndexMap::from([("v".to_string(), Value::Int(value))]));
+        let records = [record(1), record(3), record(10)];
+        let points = [(0, &records[0]), (5, &records[1]), (12, &records[2])];
+        assert_eq!(
+            downsample(points.into_iter(), 10, "v", Aggregate::Sum, OverflowPolicy::Widen).unwrap(),
+            vec![(0, Value::Int(4)), (10, Value::Int(10))]
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use indexmap::IndexMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::codec::Records;
+use crate::collation::Comparator;
+use crate::error::{DatabaseError, Result};
+use crate::query::Query;
+use crate::storage::{Record, Value};
+use crate::update::UpdateOp;
+
+/// `SimpleDB::with_retry`在冲突后最多重试的次数
+pub const MAX_RETRIES: u32 = 8;
+
+/// 第`attempt`次（从0开始）重试前的等待时间：上限从5毫秒起每次翻倍、最多500毫秒，在0到上限之间随机，
+/// 避免同时冲突的请求再次同时重试
+pub fn retry_delay(attempt: u32) -> Duration {
+    let cap = Duration::from_millis(5).saturating_mul(1 << attempt.min(16)).min(Duration::from_millis(500));
+    cap.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+}
+
+/// 事务中的一项写操作
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WriteOp {
+    /// 插入记录，ID在加入事务时预先分配
+    Insert {
+        table: String,
+        id: String,
+        data: IndexMap<String, Value>,
+    },
+    Update {
+        table: String,
+        id: String,
+        data: IndexMap<String, Value>,
+    },
+    Patch {
+        table: String,
+        id: String,
+        ops: Vec<UpdateOp>,
+    },
+    Delete {
+        table: String,
+        id: String,
+    },
+    /// 提交时要求记录的ETag仍为给定值
+    CheckEtag {
+        table: String,
+        id: String,
+        etag: String,
+    },
+    /// 提交时要求记录的字段仍等于给定值
+    CheckField {
+        table: String,
+        id: String,
+        field: String,
+        expected: Value,
+    },
+}
+
+impl WriteOp {
+    /// 操作涉及的表
+    pub fn table(&self) -> &str {
+        match self {
+            WriteOp::Insert { table, .. }
+            | WriteOp::Update { table, .. }
+            | WriteOp::Patch { table, .. }
+            | WriteOp::Delete { table, .. }
+            | WriteOp::CheckEtag { table, .. }
+            | WriteOp::CheckField { table, .. } => table,
+        }
+    }
+}
+
+/// 待提交的事务
+///
+/// 写操作先在内存中累积，由`SimpleDB::commit`按加入顺序原子地应用；
+/// 提交前数据库中的数据不受影响。
+#[derive(Debug, Clone, Default)]
+pub struct Transaction {
+    ops: Vec<WriteOp>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 加入插入操作，返回新记录将使用的ID
+    pub fn insert(&mut self, table: &str, data: IndexMap<String, Value>) -> String {
+        let id = Uuid::new_v4().to_string();
+        self.ops.push(WriteOp::Insert {
+            table: table.to_string(),
+            id: id.clone(),
+            data,
+        });
+        id
+    }
+
+    pub fn update(&mut self, table: &str, id: &str, data: IndexMap<String, Value>) {
+        self.ops.push(WriteOp::Update {
+            table: table.to_string(),
+            id: id.to_string(),
+            data,
+        });
+    }
+
+    pub fn patch(&mut self, table: &str, id: &str, ops: Vec<UpdateOp>) {
+        self.ops.push(WriteOp::Patch {
+            table: table.to_string(),
+            id: id.to_string(),
+            ops,
+        });
+    }
+
+    pub fn delete(&mut self, table: &str, id: &str) {
+        self.ops.push(WriteOp::Delete {
+            table: table.to_string(),
+            id: id.to_string(),
+        });
+    }
+
+    /// 提交时校验记录的ETag，不一致则整个事务失败
+    pub fn require_etag(&mut self, table: &str, id: &str, etag: &str) {
+        self.ops.push(WriteOp::CheckEtag {
+            table: table.to_string(),
+            id: id.to_string(),
+            etag: etag.to_string(),
+        });
+    }
+
+    /// 提交时校验记录的字段仍等于`expected`，不相等或字段缺失则整个事务失败
+    pub fn require_field(&mut self, table: &str, id: &str, field: &str, expected: Value) {
+        self.ops.push(WriteOp::CheckField {
+            table: table.to_string(),
+            id: id.to_string(),
+            field: field.to_string(),
+            expected,
+        });
+    }
+
+    pub fn ops(&self) -> &[WriteOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<WriteOp> {
+        self.ops
+    }
+}
+
+/// 只读事务：所有表在同一时刻的快照，其中的多次查询看到同一份数据（可重复读）
+///
+/// 由`SimpleDB::read_transaction`创建。快照只复制记录指针，之后数据库中的写入不影响快照；
+/// 快照中的查询不使用索引，快照存在期间被替换或删除的记录占用的内存要等快照释放后才回收。
+#[derive(Debug, Clone, Default)]
+pub struct ReadTransaction {
+    tables: HashMap<String, (Records, Comparator)>,
+}
+
+impl ReadTransaction {
+    pub(crate) fn new(tables: HashMap<String, (Records, Comparator)>) -> Self {
+        Self { tables }
+    }
+
+    fn table(&self, name: &str) -> Result<&(Records, Comparator)> {
+        self.tables.get(name).ok_or_else(|| DatabaseError::TableNotFound(name.to_string()))
+    }
+
+    /// 快照中的表
+    pub fn list_tables(&self) -> Vec<String> {
+        self.tables.keys().cloned().collect()
+    }
+
+    pub fn find_by_id(&self, table: &str, id: &str) -> Result<Option<Arc<Record>>> {
+        Ok(self.table(table)?.0.get(id).cloned())
+    }
+
+    pub fn find_all(&self, table: &str) -> Result<Vec<Arc<Record>>> {
+        self.find_where(table, |_| true)
+    }
+
+    /// 与`SimpleDB::find_where`相同，按默认顺序排列
+    pub fn find_where<F>(&self, table: &str, predicate: F) -> Result<Vec<Arc<Record>>>
+    where
+        F: Fn(&Record) -> bool,
+    {
+        let mut records: Vec<Arc<Record>> = self.table(table)?.0.values().filter(|r| predicate(r)).cloned().collect();
+        records.sort_by(|a, b| a.default_order(b));
+        Ok(records)
+    }
+
+    /// 按查询过滤、排序并分页，字符串按表的排序规则比较
+    pub fn query(&self, table: &str, query: &Query) -> Result<Vec<Arc<Record>>> {
+        query.validate()?;
+        let (records, collation) = self.table(table)?;
+        let matched = records.values().filter(|r| query.matches_with(r, *collation)).cloned().collect();
+        Ok(query.finish_with(matched, *collation))
+    }
+
+    pub fn count(&self, table: &str) -> Result<usize> {
+        Ok(self.table(table)?.0.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::open;
+    use crate::DatabaseError;
+
+    #[test]
+    fn test_failed_commit_rolls_back() {
+        let (_dir, db) = open("tx");
+        let id = db.insert("accounts", IndexMap::from([("balance".to_string(), Value::Int(100))])).unwrap();
+
+        let mut tx = Transaction::new();
+        tx.patch("accounts", &id, vec![UpdateOp::Set("balance".to_string(), Value::Int(0))]);
+        tx.insert("accounts", IndexMap::new());
+        tx.delete("accounts", "missing");
+        assert!(matches!(db.commit(tx), Err(DatabaseError::RecordNotFound(_))));
+
+        assert_eq!(db.count("accounts").unwrap(), 1);
+        let record = db.find_by_id("accounts", &id).unwrap().unwrap();
+        assert_eq!(record.data["balance"], Value::Int(100));
+
+        drop(db);
+    }
+}
@@ -0,0 +1,75 @@
+//! 内置的管理界面，启用`admin-ui`特性时由服务器在`/ui`提供
+//!
+//! 界面是一个静态页面，通过现有的HTTP API列出表、分页浏览记录、执行SQL查询和查看统计信息；
+//! 服务器启用认证时在页面上填入令牌，请求以`Authorization: Bearer`发送。
+
+/// 管理界面的页面
+pub const INDEX_HTML: &str = include_str!("ui/index.html");
+
+/// 是否为管理界面的路径
+pub fn is_ui_path(path: &str) -> bool {
+    matches!(path, "/ui" | "/ui/" | "/ui/index.html")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::Value;
+    use crate::testing::{json_body, open, request, serve};
+    use crate::IndexMap;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_admin_ui() {
+        let (_dir, db) = open("ui");
+        let (address, serving) = serve(Arc::new(db));
+        let response = request(address, "GET /ui HTTP/1.1", "").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("text/html") && response.contains("SimpleDB 管理界面"), "{}", response);
+        serving.abort();
+        let _ = serving.await;
+    }
+
+    /// 页面依赖的接口：表列表、表信息、按页浏览、查询框和统计信息，请求与页面中发出的一致
+    #[tokio::test]
+    async fn test_ui_endpoints() {
+        let (_dir, db) = open("ui_endpoints");
+        for n in 0..60 {
+            let name = Value::String(format!("user{}", n));
+            db.insert("users", IndexMap::from([("name".to_string(), name), ("age".to_string(), Value::Int(n))])).unwrap();
+        }
+        db.create_table("orders").unwrap();
+        let (address, serving) = serve(Arc::new(db));
+        let get = |path: &str| format!("GET {} HTTP/1.1", path);
+        let sql = |query: &str| serde_json::json!({ "query": query }).to_string();
+
+        let tables = json_body(&request(address, &get("/api/tables"), "").await);
+        let mut names: Vec<&str> = tables["data"].as_array().unwrap().iter().map(|name| name.as_str().unwrap()).collect();
+        names.sort();
+        assert_eq!(names, ["orders", "users"]);
+
+        let info = json_body(&request(address, &get("/api/tables/users/info"), "").await);
+        assert_eq!(info["data"]["records"], 60);
+        assert!(info["data"]["dirty"].is_boolean(), "{}", info);
+
+        // 第二页只剩10条
+        let page = json_body(&request(address, "POST /api/sql HTTP/1.1", &sql("SELECT * FROM users LIMIT 50 OFFSET 50")).await);
+        assert!(page["data"]["columns"].as_array().unwrap().contains(&"age".into()), "{}", page);
+        assert_eq!(page["data"]["rows"].as_array().unwrap().len(), 10);
+
+        let result = json_body(&request(address, "POST /api/sql HTTP/1.1", &sql("SELECT name FROM users WHERE age >= 58")).await);
+        assert_eq!(result["data"]["columns"], serde_json::json!(["name"]));
+        assert_eq!(result["data"]["rows"].as_array().unwrap().len(), 2);
+        // 页面把失败的查询显示为错误信息
+        let failed = json_body(&request(address, "POST /api/sql HTTP/1.1", &sql("DELETE FROM users")).await);
+        assert_eq!(failed["success"], false);
+        assert!(failed["error"].is_string() || failed["message"].is_string(), "{}", failed);
+
+        let stats = json_body(&request(address, &get("/api/tables/users/stats"), "").await);
+        let age = &stats["data"]["fields"]["age"];
+        assert_eq!((&age["present"], &age["min"], &age["max"]), (&60.into(), &0.into(), &59.into()));
+        assert!(age["null_ratio"].is_number() && age["distinct"].is_number(), "{}", stats);
+        assert!(stats["data"]["counters"].is_object(), "{}", stats);
+        serving.abort();
+        let _ = serving.await;
+    }
+}
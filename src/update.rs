@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use indexmap::IndexMap;
+
+use crate::error::{DatabaseError, Result};
+use crate::storage::Value;
+
+/// 数组弹出方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PopEnd {
+    First,
+    Last,
+}
+
+/// 局部更新操作，由`patch`按顺序应用到记录上
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum UpdateOp {
+    /// 设置字段值
+    Set(String, Value),
+    /// 向数组末尾追加元素，字段不存在时创建数组
+    Push(String, Value),
+    /// 移除数组中所有等于给定值的元素
+    Pull(String, Value),
+    /// 元素不存在时才追加
+    AddToSet(String, Value),
+    /// 移除数组的第一个或最后一个元素
+    Pop(String, PopEnd),
+    /// 删除字段
+    Unset(String),
+    /// 重命名字段，目标字段已存在时被覆盖
+    Rename(String, String),
+}
+
+impl UpdateOp {
+    /// 将操作应用到记录数据上
+    pub fn apply(&self, data: &mut IndexMap<String, Value>) -> Result<()> {
+        match self {
+            UpdateOp::Set(field, value) => {
+                data.insert(field.clone(), value.clone());
+            }
+            UpdateOp::Push(field, value) => {
+                array_entry(data, field)?.push(value.clone());
+            }
+            UpdateOp::AddToSet(field, value) => {
+                let items = array_entry(data, field)?;
+                if !items.contains(value) {
+                    items.push(value.clone());
+                }
+            }
+            UpdateOp::Pull(field, value) => {
+                if let Some(items) = existing_array(data, field)? {
+                    items.retain(|item| item != value);
+                }
+            }
+            UpdateOp::Pop(field, end) => {
+                if let Some(items) = existing_array(data, field)? {
+                    if !items.is_empty() {
+                        match end {
+                            PopEnd::First => {
+                                items.remove(0);
+                            }
+                            PopEnd::Last => {
+                                items.pop();
+                            }
+                        }
+                    }
+                }
+            }
+            UpdateOp::Unset(field) => {
+                data.shift_remove(field);
+            }
+            // 改名后的字段留在原来的位置
+            UpdateOp::Rename(from, to) => {
+                if let Some((index, _, value)) = data.shift_remove_full(from) {
+                    data.shift_remove(to);
+                    data.shift_insert(index.min(data.len()), to.clone(), value);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 获取数组字段，不存在时创建空数组
+fn array_entry<'a>(data: &'a mut IndexMap<String, Value>, field: &str) -> Result<&'a mut Vec<Value>> {
+    match data.entry(field.to_string()).or_insert_with(|| Value::Array(Vec::new())) {
+        Value::Array(items) => Ok(items),
+        _ => Err(not_an_array(field)),
+    }
+}
+
+/// 获取已存在的数组字段，字段不存在时返回None
+fn existing_array<'a>(data: &'a mut IndexMap<String, Value>, field: &str) -> Result<Option<&'a mut Vec<Value>>> {
+    match data.get_mut(field) {
+        None => Ok(None),
+        Some(Value::Array(items)) => Ok(Some(items)),
+        Some(_) => Err(not_an_array(field)),
+    }
+}
+
+fn not_an_array(field: &str) -> DatabaseError {
+    DatabaseError::DataFormat(format!("字段 {} 不是数组", field))
+}
+
+/// 按顺序应用一组更新操作，任一操作失败时原数据保持不变
+pub fn apply_all(data: &IndexMap<String, Value>, ops: &[UpdateOp]) -> Result<IndexMap<String, Value>> {
+    let mut updated = data.clone();
+    for op in ops {
+        op.apply(&mut updated)?;
+    }
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_array_operators() {
+        let tag = |s: &str| Value::String(s.to_string());
+        let data = IndexMap::new();
+        let ops = vec![
+            UpdateOp::Push("tags".to_string(), tag("rust")),
+            UpdateOp::AddToSet("tags".to_string(), tag("db")),
+            UpdateOp::AddToSet("tags".to_string(), tag("rust")),
+            UpdateOp::Push("tags".to_string(), tag("tmp")),
+            UpdateOp::Pop("tags".to_string(), PopEnd::Last),
+            UpdateOp::Push("tags".to_string(), tag("old")),
+            UpdateOp::Pull("tags".to_string(), tag("old")),
+        ];
+        let updated = apply_all(&data, &ops).unwrap();
+        assert_eq!(updated["tags"], Value::Array(vec![tag("rust"), tag("db")]));
+
+        let renamed = apply_all(
+            &updated,
+            &[
+                UpdateOp::Rename("tags".to_string(), "labels".to_string()),
+                UpdateOp::Unset("missing".to_string()),
+            ],
+        )
+        .unwrap();
+        assert!(!renamed.contains_key("tags"));
+        assert_eq!(renamed["labels"], updated["tags"]);
+
+        // 重命名的字段保持原来的位置
+        let mut ordered = IndexMap::new();
+        for field in ["a", "b", "c"] {
+            ordered.insert(field.to_string(), Value::Int(1));
+        }
+        let renamed = apply_all(&ordered, &[UpdateOp::Rename("b".to_string(), "z".to_string())]).unwrap();
+        assert_eq!(renamed.keys().collect::<Vec<_>>(), ["a", "z", "c"]);
+
+        let mut scalar = IndexMap::new();
+        scalar.insert("tags".to_string(), Value::Int(1));
+        assert!(apply_all(&scalar, &ops).is_err());
+    }
+}
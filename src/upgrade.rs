@@ -0,0 +1,107 @@
+//! 平滑升级：新进程继承旧进程的监听套接字，部署期间客户端不会遇到连接被拒绝或重置
+//!
+//! 旧进程收到升级信号后停止接受新连接，等待进行中的请求完成并保存所有表（`DatabaseServer::serve_until`），
+//! 然后以相同的命令行启动新进程并把监听套接字传给它，自己退出。新进程加载已保存的数据后从同一个套接字继续接受连接，
+//! 交接期间到达的连接在内核中排队。两个进程不会同时读写数据目录。
+
+use std::net::TcpListener;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::process::{Child, Command};
+
+use socket2::SockRef;
+
+use crate::error::{DatabaseError, Result};
+
+/// 传递继承的监听套接字文件描述符的环境变量
+pub const LISTEN_FD_ENV: &str = "SIMPLEDB_LISTEN_FD";
+
+/// 从上一个进程继承的监听套接字，不是由平滑升级启动时为None
+pub fn inherited_listener() -> Result<Option<TcpListener>> {
+    let Some(value) = std::env::var_os(LISTEN_FD_ENV) else {
+        return Ok(None);
+    };
+    // 只使用一次，之后启动的进程不应再继承
+    std::env::remove_var(LISTEN_FD_ENV);
+    let fd: RawFd = value
+        .to_str()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| DatabaseError::Config(format!("无效的{}: {:?}", LISTEN_FD_ENV, value)))?;
+    // SAFETY: 文件描述符由上一个进程专门为此传入，此后只由这个TcpListener拥有
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    // 确认确实是监听中的套接字
+    listener.local_addr()?;
+    Ok(Some(listener))
+}
+
+/// 以当前进程的命令行启动继承`listener`的新进程
+///
+/// 调用前应已停止服务并保存所有表，启动后当前进程应立即退出，不再写数据目录。
+pub fn spawn_successor(listener: &TcpListener) -> Result<Child> {
+    // 监听套接字默认在exec时关闭，新进程要继承它
+    SockRef::from(listener).set_cloexec(false)?;
+    // 按启动时的路径（argv[0]）而不是当前可执行文件启动，替换后的新版本才会生效
+    let mut args = std::env::args_os();
+    let program = match args.next() {
+        Some(program) => program,
+        None => std::env::current_exe()?.into_os_string(),
+    };
+    let child = Command::new(program)
+        .args(args)
+        .env(LISTEN_FD_ENV, listener.as_raw_fd().to_string())
+        .spawn();
+    SockRef::from(listener).set_cloexec(true)?;
+    Ok(child?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::DatabaseServer;
+    use crate::testing::temp_config;
+    use crate::{Config, SimpleDB};
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_listener_handoff() {
+        let (_dir, config) = temp_config("upgrade", Config::default());
+        let request = |address| {
+            let mut stream = std::net::TcpStream::connect(address).unwrap();
+            stream
+                .write_all(b"POST /api/insert HTTP/1.1\r\nContent-Length: 31\r\n\r\n{\"table\":\"t\",\"data\":{\"n\":1}}   ")
+                .unwrap();
+            stream
+        };
+        let read = |mut stream: std::net::TcpStream| {
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let old = DatabaseServer::new(SimpleDB::new(config.clone()).unwrap(), 0).with_listener(listener);
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(async move {
+            old.serve_until(async {
+                let _ = stopped.await;
+            })
+            .await
+        });
+        let first = tokio::task::spawn_blocking(move || read(request(address))).await.unwrap();
+        assert!(first.starts_with("HTTP/1.1 200"));
+
+        // 旧服务停止后套接字仍在监听，交接期间的连接排队等待新服务处理
+        stop.send(()).unwrap();
+        let listener = serving.await.unwrap().unwrap();
+        let queued = request(address);
+        let db = Arc::new(SimpleDB::new(config).unwrap());
+        let new = DatabaseServer::with_shared(Arc::clone(&db), 0).with_listener(listener);
+        let serving = tokio::spawn(async move { new.start().await });
+        let second = tokio::task::spawn_blocking(move || read(queued)).await.unwrap();
+        assert!(second.starts_with("HTTP/1.1 200"));
+        // 新进程加载了旧进程停止时保存的数据
+        assert_eq!(db.count("t").unwrap(), 2);
+        serving.abort();
+        let _ = serving.await;
+    }
+}
@@ -0,0 +1,98 @@
+//! 按表注册的记录校验函数
+//!
+//! 声明式的字段类型无法表达的业务规则（如“结束日期不早于开始日期”）可以写成校验函数，
+//! 在插入、更新和局部更新写入之前对记录的数据调用；返回的字段错误组成`DatabaseError::Validation`，
+//! HTTP API中为422，响应的`data.errors`列出每个字段的错误。校验函数只保存在内存中，每次打开数据库后重新注册。
+
+use indexmap::IndexMap;
+use serde::Serialize;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::storage::Value;
+
+/// 校验失败的一个字段
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+type ValidateFn = dyn Fn(&IndexMap<String, Value>) -> Result<(), Vec<FieldError>> + Send + Sync;
+
+/// 记录校验函数，参数为将要写入的数据
+#[derive(Clone)]
+pub struct Validator(Arc<ValidateFn>);
+
+impl Validator {
+    pub fn new(validate: impl Fn(&IndexMap<String, Value>) -> Result<(), Vec<FieldError>> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(validate))
+    }
+
+    pub fn validate(&self, data: &IndexMap<String, Value>) -> Result<(), Vec<FieldError>> {
+        (self.0)(data)
+    }
+}
+
+impl fmt::Debug for Validator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Validator")
+    }
+}
+
+/// 错误信息中的字段错误列表，如`age: 必须为正数; email: 缺少@`
+pub(crate) fn describe(errors: &[FieldError]) -> String {
+    errors
+        .iter()
+        .map(|e| format!("{}: {}", e.field, e.message))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::open;
+    use crate::error::DatabaseError;
+    use crate::update::UpdateOp;
+
+    #[test]
+    fn test_validator() {
+        let (_dir, db) = open("validate");
+        // 表还不存在时注册，创建后生效
+        db.set_validator("bookings", |data| {
+            let day = |field: &str| data.get(field).and_then(Value::as_int);
+            match (day("start"), day("end")) {
+                (Some(start), Some(end)) if end < start => Err(vec![FieldError::new("end", "不能早于start")]),
+                (Some(_), Some(_)) => Ok(()),
+                _ => Err(vec![FieldError::new("start", "start和end都是必填的整数")]),
+            }
+        })
+        .unwrap();
+        let booking = |start: i64, end: i64| {
+            IndexMap::from([("start".to_string(), Value::Int(start)), ("end".to_string(), Value::Int(end))])
+        };
+
+        let id = db.insert("bookings", booking(1, 3)).unwrap();
+        match db.insert("bookings", booking(5, 2)) {
+            Err(DatabaseError::Validation(errors)) => assert_eq!(errors, vec![FieldError::new("end", "不能早于start")]),
+            other => panic!("{:?}", other),
+        }
+        let patched = db.patch("bookings", &id, &[UpdateOp::Unset("end".to_string())]);
+        assert!(patched.unwrap_err().to_string().contains("start: start和end都是必填的整数"));
+        assert_eq!(db.find_by_id("bookings", &id).unwrap().unwrap().data, booking(1, 3));
+
+        db.clear_validator("bookings").unwrap();
+        db.insert("bookings", booking(5, 2)).unwrap();
+        drop(db);
+    }
+}
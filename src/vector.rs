@@ -0,0 +1,38 @@
+/// 向量相似度度量方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Metric {
+    /// 余弦相似度，取值范围[-1, 1]
+    #[default]
+    Cosine,
+    /// 点积，适用于已归一化的向量
+    DotProduct,
+}
+
+impl Metric {
+    /// 计算两个向量的相似度，维度不一致时返回None
+    pub fn similarity(&self, a: &[f32], b: &[f32]) -> Option<f32> {
+        match self {
+            Metric::Cosine => cosine_similarity(a, b),
+            Metric::DotProduct => dot_product(a, b),
+        }
+    }
+}
+
+/// 点积
+pub fn dot_product(a: &[f32], b: &[f32]) -> Option<f32> {
+    if a.len() != b.len() {
+        return None;
+    }
+    Some(a.iter().zip(b).map(|(x, y)| x * y).sum())
+}
+
+/// 余弦相似度，任一向量为零向量时返回None
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> Option<f32> {
+    let dot = dot_product(a, b)?;
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return None;
+    }
+    Some(dot / (norm_a * norm_b))
+}
@@ -1,6 +1,7 @@
-use simpledb::{Config, SimpleDB, Value};
+#![allow(unused_variables, clippy::approx_constant)]
+
+use simpledb::{Config, IndexMap, SimpleDB, Value};
 use simpledb::crypto::Crypto;
-use std::collections::HashMap;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -41,12 +42,13 @@ async fn test_basic_crud() -> Result<(), Box<dyn std::error::Error>> {
         data_dir: "./test_basic".to_string(),
         encryption_key: None,
         max_file_size: 1024 * 1024,
+        ..Config::default()
     };
 
-    let mut db = SimpleDB::new(config)?;
+    let db = SimpleDB::new(config)?;
 
     // 创建用户数据
-    let mut user_data = HashMap::new();
+    let mut user_data = IndexMap::new();
     user_data.insert("name".to_string(), Value::String("测试用户".to_string()));
     user_data.insert("age".to_string(), Value::Int(25));
     user_data.insert("active".to_string(), Value::Bool(true));
@@ -61,7 +63,7 @@ async fn test_basic_crud() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     // 更新数据
-    let mut update_data = HashMap::new();
+    let mut update_data = IndexMap::new();
     update_data.insert("name".to_string(), Value::String("测试用户".to_string()));
     update_data.insert("age".to_string(), Value::Int(26));
     update_data.insert("active".to_string(), Value::Bool(true));
@@ -81,12 +83,13 @@ async fn test_encryption() -> Result<(), Box<dyn std::error::Error>> {
         data_dir: "./test_encrypted".to_string(),
         encryption_key: Some(key.clone()),
         max_file_size: 1024 * 1024,
+        ..Config::default()
     };
 
-    let mut db = SimpleDB::new(config)?;
+    let db = SimpleDB::new(config)?;
 
     // 插入敏感数据
-    let mut sensitive_data = HashMap::new();
+    let mut sensitive_data = IndexMap::new();
     sensitive_data.insert("password".to_string(), Value::String("secret123".to_string()));
     sensitive_data.insert("credit_card".to_string(), Value::String("1234-5678-9012-3456".to_string()));
 
@@ -102,6 +105,7 @@ async fn test_encryption() -> Result<(), Box<dyn std::error::Error>> {
         data_dir: "./test_encrypted".to_string(),
         encryption_key: Some(key),
         max_file_size: 1024 * 1024,
+        ..Config::default()
     };
     let db2 = SimpleDB::new(config2)?;
     
@@ -115,6 +119,7 @@ async fn test_encryption() -> Result<(), Box<dyn std::error::Error>> {
         data_dir: "./test_encrypted".to_string(),
         encryption_key: Some(wrong_key),
         max_file_size: 1024 * 1024,
+        ..Config::default()
     };
     
     match SimpleDB::new(wrong_config) {
@@ -130,13 +135,14 @@ async fn test_persistence() -> Result<(), Box<dyn std::error::Error>> {
         data_dir: "./test_persistence".to_string(),
         encryption_key: None,
         max_file_size: 1024 * 1024,
+        ..Config::default()
     };
 
     // 第一次创建数据库并插入数据
     {
-        let mut db = SimpleDB::new(config.clone())?;
+        let db = SimpleDB::new(config.clone())?;
         
-        let mut data = HashMap::new();
+        let mut data = IndexMap::new();
         data.insert("persistent_data".to_string(), Value::String("这条数据应该持久保存".to_string()));
         
         db.insert("persistence_test", data)?;
@@ -165,11 +171,12 @@ async fn test_data_types() -> Result<(), Box<dyn std::error::Error>> {
         data_dir: "./test_types".to_string(),
         encryption_key: None,
         max_file_size: 1024 * 1024,
+        ..Config::default()
     };
 
-    let mut db = SimpleDB::new(config)?;
+    let db = SimpleDB::new(config)?;
 
-    let mut type_data = HashMap::new();
+    let mut type_data = IndexMap::new();
     type_data.insert("null_value".to_string(), Value::Null);
     type_data.insert("bool_value".to_string(), Value::Bool(true));
     type_data.insert("int_value".to_string(), Value::Int(42));
@@ -199,13 +206,14 @@ async fn test_conditional_queries() -> Result<(), Box<dyn std::error::Error>> {
         data_dir: "./test_queries".to_string(),
         encryption_key: None,
         max_file_size: 1024 * 1024,
+        ..Config::default()
     };
 
-    let mut db = SimpleDB::new(config)?;
+    let db = SimpleDB::new(config)?;
 
     // 插入多个用户
     for i in 1..=5 {
-        let mut user_data = HashMap::new();
+        let mut user_data = IndexMap::new();
         user_data.insert("name".to_string(), Value::String(format!("用户{}", i)));
         user_data.insert("age".to_string(), Value::Int(20 + i));
         user_data.insert("active".to_string(), Value::Bool(i % 2 == 0));